@@ -6,17 +6,20 @@ use std::io::Stdin;
 use std::mem::zeroed;
 use std::os::unix::io::RawFd;
 
+use libc::ioctl;
 use libc::isatty;
 use libc::read;
 use libc::tcgetattr;
 use libc::tcsetattr;
 use libc::termios;
+use libc::winsize;
 use libc::ECHO;
 use libc::ICANON;
 use libc::ISIG;
 use libc::O_NONBLOCK;
 use libc::STDIN_FILENO;
 use libc::TCSANOW;
+use libc::TIOCGWINSZ;
 
 use crate::errno::Result;
 use crate::errno_result;
@@ -106,6 +109,21 @@ pub unsafe trait Terminal {
             clear_fd_flags(self.tty_fd(), O_NONBLOCK)
         }
     }
+
+    /// Gets the size of this terminal's window as `(columns, rows)`.
+    fn window_size(&self) -> Result<(u16, u16)> {
+        // SAFETY:
+        // Safe because winsize is plain old data that the ioctl overwrites.
+        let mut ws: winsize = unsafe { zeroed() };
+        // SAFETY:
+        // Safe because the ioctl only writes to the extent of winsize and we check the return
+        // result.
+        let ret = unsafe { ioctl(self.tty_fd(), TIOCGWINSZ, &mut ws as *mut _) };
+        if ret < 0 {
+            return errno_result();
+        }
+        Ok((ws.ws_col, ws.ws_row))
+    }
 }
 
 // # SAFETY:
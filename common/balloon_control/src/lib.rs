@@ -31,6 +31,11 @@ pub enum BalloonTubeCommand {
         refresh_threshold: u32,
         report_threshold: u32,
     },
+    // Ask the guest to hint its free pages, whose contents are discarded. The guest doesn't reuse
+    // the hinted pages until FreePageHintDone is sent.
+    FreePageHint,
+    // Let the guest reuse the pages hinted since the last FreePageHint.
+    FreePageHintDone,
 }
 
 // BalloonStats holds stats returned from the stats_queue.
@@ -88,4 +93,8 @@ pub enum BalloonTubeResult {
         /// size of the balloon in bytes.
         balloon_actual: u64,
     },
+    FreePagesHinted {
+        /// size of the hinted pages in bytes.
+        num_bytes: u64,
+    },
 }
@@ -23,6 +23,7 @@ mod pci;
 mod pflash;
 pub mod pl030;
 pub mod pmc_virt;
+mod ratelimit;
 mod serial;
 pub mod serial_device;
 mod suspendable;
@@ -123,6 +124,7 @@ pub use self::pflash::Pflash;
 pub use self::pflash::PflashParameters;
 pub use self::pl030::Pl030;
 pub use self::pmc_virt::VirtualPmc;
+pub use self::ratelimit::Ratelimit;
 pub use self::serial::Serial;
 pub use self::serial_device::Error as SerialError;
 pub use self::serial_device::SerialDevice;
@@ -236,6 +236,14 @@ impl PciRoot {
         self.root_bus.clone()
     }
 
+    /// Get the debug labels of the devices, by address
+    pub fn device_labels(&self) -> BTreeMap<PciAddress, String> {
+        self.devices
+            .iter()
+            .map(|(address, device)| (*address, device.lock().debug_label()))
+            .collect()
+    }
+
     /// Get the ACPI path to a PCI device
     pub fn acpi_path(&self, address: &PciAddress) -> Option<String> {
         if let Some(device) = self.devices.get(address) {
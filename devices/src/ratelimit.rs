@@ -4,8 +4,6 @@
 
 //! Utility file to provide a ratelimit object
 
-#![cfg_attr(not(any(target_os = "android", target_os = "linux")), allow(dead_code))]
-
 use std::cmp;
 use std::time::Duration;
 use std::time::Instant;
@@ -20,6 +18,12 @@ pub struct Ratelimit {
     dispatched: u64,
 }
 
+impl Default for Ratelimit {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Ratelimit {
     pub fn new() -> Self {
         Ratelimit {
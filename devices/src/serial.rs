@@ -79,7 +79,7 @@ const DEFAULT_MODEM_CONTROL: u8 = MCR_OUT2_BIT;
 const DEFAULT_MODEM_STATUS: u8 = MSR_DSR_BIT | MSR_CTS_BIT | MSR_DCD_BIT;
 const DEFAULT_BAUD_DIVISOR: u16 = 12; // 9600 bps
 
-const TIMESTAMP_PREFIX_FMT: &str = "[ %F %T%.9f ]: ";
+pub(crate) const TIMESTAMP_PREFIX_FMT: &str = "[ %F %T%.9f ]: ";
 
 /// Emulates serial COM ports commonly seen on x86 I/O ports 0x3f8/0x2f8/0x3e8/0x2e8.
 ///
@@ -12,6 +12,7 @@ use std::io::stdout;
 #[cfg(unix)]
 use std::os::unix::net::UnixStream;
 use std::path::PathBuf;
+use std::str::FromStr;
 
 use base::error;
 use base::open_file_or_duplicate;
@@ -30,6 +31,11 @@ use serde::Serialize;
 use serde_keyvalue::FromKeyValues;
 use thiserror::Error as ThisError;
 
+mod tee;
+
+pub use self::tee::console_history;
+pub use self::tee::ConsoleHistory;
+use self::tee::TeeWriter;
 pub use crate::sys::serial_device::SerialDevice;
 use crate::sys::serial_device::*;
 use crate::PciAddress;
@@ -47,6 +53,8 @@ pub enum Error {
     FileCreate(std::io::Error, PathBuf),
     #[error("Unable to open file '{1}': {0}")]
     FileOpen(std::io::Error, PathBuf),
+    #[error("Unable to create output history: {0}")]
+    HistoryCreate(std::io::Error),
     #[error("Invalid serial config specified: {0}")]
     InvalidConfig(String),
     #[error("Serial device path '{0} is invalid")]
@@ -57,6 +65,8 @@ pub enum Error {
     InvalidSerialType(String),
     #[error("Serial device type file requires a path")]
     PathRequired,
+    #[error("Unable to create pseudo-terminal: {0}")]
+    PtyCreate(std::io::Error),
     #[error("Failed to connect to socket: {0}")]
     SocketConnect(std::io::Error),
     #[error("Failed to create unbound socket: {0}")]
@@ -89,6 +99,12 @@ pub enum SerialType {
     // Use the same Unix domain socket for input and output.
     #[cfg(unix)]
     UnixStream,
+    // Allocate a pseudo-terminal for input and output.
+    #[cfg(unix)]
+    Pty,
+    // Serve input and output to a client connecting to a local TCP port.
+    #[cfg(unix)]
+    Tcp,
 }
 
 impl Default for SerialType {
@@ -107,6 +123,10 @@ impl Display for SerialType {
             SerialType::SystemSerialType => SYSTEM_SERIAL_TYPE_NAME.to_string(),
             #[cfg(unix)]
             SerialType::UnixStream => "UnixStream".to_string(),
+            #[cfg(unix)]
+            SerialType::Pty => "Pty".to_string(),
+            #[cfg(unix)]
+            SerialType::Tcp => "Tcp".to_string(),
         };
 
         write!(f, "{}", s)
@@ -146,10 +166,27 @@ impl Display for SerialHardware {
     }
 }
 
+impl FromStr for SerialHardware {
+    type Err = Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "serial" => Ok(SerialHardware::Serial),
+            "virtio-console" | "legacy-virtio-console" => Ok(SerialHardware::VirtioConsole),
+            "debugcon" => Ok(SerialHardware::Debugcon),
+            _ => Err(Error::InvalidSerialHardware(s.to_string())),
+        }
+    }
+}
+
 fn serial_parameters_default_num() -> u8 {
     1
 }
 
+fn serial_parameters_default_tee_history_size() -> usize {
+    64 * 1024
+}
+
 fn serial_parameters_default_debugcon_port() -> u16 {
     // Default to the port OVMF expects.
     0x402
@@ -168,6 +205,9 @@ pub struct SerialParameters {
     /// This flag can be used only when `type_` is `UnixStream`.
     #[cfg(unix)]
     pub input_unix_stream: bool,
+    /// Local TCP port to listen on. Required when `type_` is `Tcp`.
+    #[cfg(unix)]
+    pub tcp_port: Option<u16>,
     #[serde(default = "serial_parameters_default_num")]
     pub num: u8,
     pub console: bool,
@@ -182,6 +222,12 @@ pub struct SerialParameters {
     pub debugcon_port: u16,
     pub pci_address: Option<PciAddress>,
     pub max_queue_sizes: Option<Vec<u16>>,
+    /// File to write a timestamped copy of the output to. The copy is also kept in a history that
+    /// can be retrieved through the control socket.
+    pub tee: Option<PathBuf>,
+    /// Number of bytes of the timestamped copy of the output kept in the history.
+    #[serde(default = "serial_parameters_default_tee_history_size")]
+    pub tee_history_size: usize,
 }
 
 /// Temporary structure containing the parameters of a serial port for easy passing to
@@ -196,6 +242,23 @@ pub struct SerialOptions {
 }
 
 impl SerialParameters {
+    /// Wraps `output` to also copy it to the tee of the device, if any.
+    pub(crate) fn tee_output(
+        &self,
+        output: Option<Box<dyn io::Write + Send>>,
+        keep_rds: &mut Vec<RawDescriptor>,
+    ) -> std::result::Result<Option<Box<dyn io::Write + Send>>, Error> {
+        let Some(path) = &self.tee else {
+            return Ok(output);
+        };
+        let file = open_file_or_duplicate(path, OpenOptions::new().append(true).create(true))
+            .map_err(|e| Error::FileCreate(e.into(), path.clone()))?;
+        keep_rds.push(file.as_raw_descriptor());
+        let history = ConsoleHistory::new(self.tee_history_size).map_err(Error::HistoryCreate)?;
+        tee::register_history(self.hardware, self.num, history.clone());
+        Ok(Some(Box::new(TeeWriter::new(output, Some(file), history))))
+    }
+
     /// Helper function to create a serial device from the defined parameters.
     ///
     /// # Arguments
@@ -230,6 +293,16 @@ impl SerialParameters {
             return create_unix_stream_serial_device(self, protection_type, evt, keep_rds);
         }
 
+        // Pseudo-terminals and TCP clients are used for both output and input.
+        #[cfg(unix)]
+        if matches!(self.type_, SerialType::Pty | SerialType::Tcp)
+            && (self.input.is_some() || self.stdin)
+        {
+            return Err(Error::InvalidConfig(
+                "input and stdin can't be passed when type=pty or type=tcp".to_string(),
+            ));
+        }
+
         let input: Option<Box<dyn SerialInput>> = if let Some(input_path) = &self.input {
             let input_path = input_path.as_path();
 
@@ -290,7 +363,16 @@ impl SerialParameters {
                 keep_rds.push(output.as_raw_descriptor());
                 (Some(Box::new(output)), None)
             }
+            #[cfg(unix)]
+            SerialType::Pty => {
+                return create_pty_serial_device(self, protection_type, evt, keep_rds);
+            }
+            #[cfg(unix)]
+            SerialType::Tcp => {
+                return create_tcp_serial_device(self, protection_type, evt, keep_rds);
+            }
         };
+        let output = self.tee_output(output, keep_rds)?;
         Ok(T::new(
             protection_type,
             evt,
@@ -333,6 +415,8 @@ mod tests {
                 input: None,
                 #[cfg(unix)]
                 input_unix_stream: false,
+                #[cfg(unix)]
+                tcp_port: None,
                 num: 1,
                 console: false,
                 earlycon: false,
@@ -341,6 +425,8 @@ mod tests {
                 debugcon_port: 0x402,
                 pci_address: None,
                 max_queue_sizes: None,
+                tee: None,
+                tee_history_size: 65536,
             }
         );
 
@@ -363,6 +449,10 @@ mod tests {
         {
             let params = from_serial_arg("type=unix-stream").unwrap();
             assert_eq!(params.type_, SerialType::UnixStream);
+            let params = from_serial_arg("type=pty").unwrap();
+            assert_eq!(params.type_, SerialType::Pty);
+            let params = from_serial_arg("type=tcp").unwrap();
+            assert_eq!(params.type_, SerialType::Tcp);
         }
         let params = from_serial_arg("type=foobar");
         assert!(params.is_err());
@@ -376,6 +466,15 @@ mod tests {
         assert_eq!(params.hardware, SerialHardware::Debugcon);
         let params = from_serial_arg("hardware=foobar");
         assert!(params.is_err());
+        assert_eq!(
+            "virtio-console".parse::<SerialHardware>().unwrap(),
+            SerialHardware::VirtioConsole
+        );
+        assert_eq!(
+            "legacy-virtio-console".parse::<SerialHardware>().unwrap(),
+            SerialHardware::VirtioConsole
+        );
+        assert!("foobar".parse::<SerialHardware>().is_err());
 
         // path parameter
         let params = from_serial_arg("path=/test/path").unwrap();
@@ -400,6 +499,12 @@ mod tests {
             assert!(!params.input_unix_stream);
             let params = from_serial_arg("input-unix-stream=foobar");
             assert!(params.is_err());
+
+            // tcp-port parameter
+            let params = from_serial_arg("tcp-port=4555").unwrap();
+            assert_eq!(params.tcp_port, Some(4555));
+            let params = from_serial_arg("tcp-port=foobar");
+            assert!(params.is_err());
         }
 
         // console parameter
@@ -452,6 +557,13 @@ mod tests {
         let params = from_serial_arg("debugcon_port=1026").unwrap();
         assert_eq!(params.debugcon_port, 1026);
 
+        // tee parameters
+        let params = from_serial_arg("tee=/some/log,tee-history-size=4096").unwrap();
+        assert_eq!(params.tee, Some("/some/log".into()));
+        assert_eq!(params.tee_history_size, 4096);
+        let params = from_serial_arg("tee");
+        assert!(params.is_err());
+
         // all together
         let params = from_serial_arg("type=stdout,path=/some/path,hardware=virtio-console,num=5,earlycon,console,stdin,input=/some/input,out_timestamp,debugcon_port=12,pci-address=00:0e.0,max-queue-sizes=[1,2]").unwrap();
         assert_eq!(
@@ -464,6 +576,8 @@ mod tests {
                 input: Some("/some/input".into()),
                 #[cfg(unix)]
                 input_unix_stream: false,
+                #[cfg(unix)]
+                tcp_port: None,
                 num: 5,
                 console: true,
                 earlycon: true,
@@ -476,6 +590,8 @@ mod tests {
                     func: 0
                 }),
                 max_queue_sizes: Some(vec![1, 2]),
+                tee: None,
+                tee_history_size: 65536,
             }
         );
 
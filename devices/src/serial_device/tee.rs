@@ -0,0 +1,228 @@
+// Copyright 2025 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Timestamped copy of the output of a serial device, written to a file and kept in a ring buffer.
+//!
+//! The ring buffer is shared memory created before the device is moved to its sandbox, so that the
+//! main process can read the history of devices running in other processes.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::sync::atomic::fence;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use base::MappedRegion;
+use base::MemoryMapping;
+use base::MemoryMappingBuilder;
+use base::SharedMemory;
+use sync::Mutex;
+
+use crate::serial::TIMESTAMP_PREFIX_FMT;
+use crate::serial_device::SerialHardware;
+
+// Offsets of the counters at the start of the shared memory, followed by the data.
+const RESERVED_OFFSET: usize = 0;
+const WRITTEN_OFFSET: usize = 8;
+const DATA_OFFSET: usize = 64;
+
+/// Ring buffer keeping the last output of a serial device.
+///
+/// Cloned handles refer to the same buffer. There must be a single writer, but any number of
+/// readers.
+#[derive(Clone)]
+pub struct ConsoleHistory {
+    mapping: Arc<MemoryMapping>,
+    capacity: usize,
+}
+
+impl ConsoleHistory {
+    /// Creates an empty history keeping the last `capacity` bytes.
+    pub fn new(capacity: usize) -> io::Result<ConsoleHistory> {
+        let shm = SharedMemory::new("console_history", (DATA_OFFSET + capacity) as u64)?;
+        let mapping = MemoryMappingBuilder::new(DATA_OFFSET + capacity)
+            .from_shared_memory(&shm)
+            .build()
+            .map_err(io::Error::other)?;
+        Ok(ConsoleHistory {
+            mapping: Arc::new(mapping),
+            capacity,
+        })
+    }
+
+    // Counters of the bytes ever written. `reserved` is increased before the data is written and
+    // `written` after, so that readers can tell which data was overwritten while they copied it.
+    fn counter(&self, offset: usize) -> &AtomicU64 {
+        // SAFETY:
+        // Safe because the offset is within the mapping, aligned, and only accessed atomically.
+        unsafe { &*(self.mapping.as_ptr().add(offset) as *const AtomicU64) }
+    }
+
+    fn append(&self, data: &[u8]) {
+        if self.capacity == 0 {
+            return;
+        }
+        let start = self.counter(WRITTEN_OFFSET).load(Ordering::Relaxed);
+        let end = start + data.len() as u64;
+        self.counter(RESERVED_OFFSET).store(end, Ordering::Relaxed);
+        fence(Ordering::Release);
+
+        // Only the last `capacity` bytes would remain.
+        let skip = data.len().saturating_sub(self.capacity);
+        let mut pos = ((start + skip as u64) % self.capacity as u64) as usize;
+        let mut data = &data[skip..];
+        while !data.is_empty() {
+            let len = data.len().min(self.capacity - pos);
+            // Cannot fail since the range is within the mapping.
+            let _ = self.mapping.write_slice(&data[..len], DATA_OFFSET + pos);
+            data = &data[len..];
+            pos = 0;
+        }
+
+        self.counter(WRITTEN_OFFSET).store(end, Ordering::Release);
+    }
+
+    /// Returns the bytes currently in the history, oldest first.
+    pub fn contents(&self) -> Vec<u8> {
+        let mut buf = vec![0u8; self.capacity];
+        loop {
+            let written = self.counter(WRITTEN_OFFSET).load(Ordering::Acquire);
+            let _ = self.mapping.read_slice(&mut buf, DATA_OFFSET);
+            fence(Ordering::Acquire);
+            let reserved = self.counter(RESERVED_OFFSET).load(Ordering::Relaxed);
+
+            // Bytes older than `reserved - capacity` may have been overwritten during the copy.
+            let oldest = reserved.saturating_sub(self.capacity as u64);
+            if oldest > written {
+                // The writer lapped the copy, try again.
+                continue;
+            }
+            let len = (written - oldest) as usize;
+            if len == 0 {
+                return Vec::new();
+            }
+            let pos = (oldest % self.capacity as u64) as usize;
+            let mut contents = Vec::with_capacity(len);
+            let first = len.min(self.capacity - pos);
+            contents.extend_from_slice(&buf[pos..pos + first]);
+            contents.extend_from_slice(&buf[..len - first]);
+            return contents;
+        }
+    }
+}
+
+/// Writes to the output of a serial device and a timestamped copy of it to a file and a history.
+pub struct TeeWriter {
+    output: Option<Box<dyn Write + Send>>,
+    file: Option<File>,
+    history: ConsoleHistory,
+    last_write_was_newline: bool,
+}
+
+impl TeeWriter {
+    pub fn new(
+        output: Option<Box<dyn Write + Send>>,
+        file: Option<File>,
+        history: ConsoleHistory,
+    ) -> TeeWriter {
+        TeeWriter {
+            output,
+            file,
+            history,
+            last_write_was_newline: true,
+        }
+    }
+
+    fn write_copy(&mut self, data: &[u8]) {
+        if let Some(file) = self.file.as_mut() {
+            // The copy is best effort, the output of the device must not fail because of it.
+            let _ = file.write_all(data);
+        }
+        self.history.append(data);
+    }
+}
+
+impl Write for TeeWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = match self.output.as_mut() {
+            Some(output) => output.write(buf)?,
+            None => buf.len(),
+        };
+
+        let mut copy = Vec::with_capacity(len);
+        for line in buf[..len].split_inclusive(|&b| b == b'\n') {
+            if self.last_write_was_newline {
+                write!(copy, "{}", chrono::Utc::now().format(TIMESTAMP_PREFIX_FMT))?;
+            }
+            copy.extend_from_slice(line);
+            self.last_write_was_newline = line.ends_with(b"\n");
+        }
+        self.write_copy(&copy);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if let Some(file) = self.file.as_mut() {
+            let _ = file.flush();
+        }
+        match self.output.as_mut() {
+            Some(output) => output.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+// Histories of the serial devices created by this process, so that the main process can serve
+// them on request.
+static HISTORIES: Mutex<BTreeMap<(SerialHardware, u8), ConsoleHistory>> =
+    Mutex::new(BTreeMap::new());
+
+pub(crate) fn register_history(hardware: SerialHardware, num: u8, history: ConsoleHistory) {
+    HISTORIES.lock().insert((hardware, num), history);
+}
+
+/// Returns the output history of serial device `num` of type `hardware`, if it has a tee.
+pub fn console_history(hardware: SerialHardware, num: u8) -> Option<ConsoleHistory> {
+    HISTORIES.lock().get(&(hardware, num)).cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn history_wraps_around() {
+        let history = ConsoleHistory::new(8).unwrap();
+        assert!(history.contents().is_empty());
+
+        history.append(b"abc");
+        assert_eq!(history.contents(), b"abc");
+        history.append(b"defgh");
+        assert_eq!(history.contents(), b"abcdefgh");
+        history.append(b"ij");
+        assert_eq!(history.contents(), b"cdefghij");
+        history.append(b"0123456789");
+        assert_eq!(history.contents(), b"23456789");
+
+        // Clones share the buffer.
+        assert_eq!(history.clone().contents(), b"23456789");
+    }
+
+    #[test]
+    fn tee_writer_timestamps() {
+        let history = ConsoleHistory::new(4096).unwrap();
+        let mut tee = TeeWriter::new(None, None, history.clone());
+        tee.write_all(b"first\nsec").unwrap();
+        tee.write_all(b"ond\n").unwrap();
+
+        let contents = String::from_utf8(history.contents()).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("[ ") && lines[0].ends_with(" ]: first"));
+        assert!(lines[1].starts_with("[ ") && lines[1].ends_with(" ]: second"));
+    }
+}
@@ -2,6 +2,9 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+mod pty;
+mod tcp;
+
 use std::borrow::Cow;
 use std::fs::OpenOptions;
 use std::io;
@@ -24,6 +27,8 @@ use base::RawDescriptor;
 use base::ReadNotifier;
 use hypervisor::ProtectionType;
 
+use self::pty::Pty;
+use self::tcp::TcpSerial;
 use crate::serial_device::Error;
 use crate::serial_device::SerialInput;
 use crate::serial_device::SerialOptions;
@@ -219,6 +224,7 @@ pub(crate) fn create_system_type_serial_device<T: SerialDevice>(
             }
             keep_rds.push(sock.as_raw_descriptor());
             let output: Option<Box<dyn Write + Send>> = Some(Box::new(WriteSocket::new(sock)));
+            let output = param.tee_output(output, keep_rds)?;
             Ok(T::new(
                 protection_type,
                 evt,
@@ -245,20 +251,98 @@ pub(crate) fn create_unix_stream_serial_device<T: SerialDevice>(
     let output = input.try_clone().map_err(Error::CloneUnixStream)?;
     keep_rds.push(input.as_raw_descriptor());
     keep_rds.push(output.as_raw_descriptor());
+    let output = param.tee_output(Some(Box::new(output)), keep_rds)?;
+
+    Ok(T::new(
+        protection_type,
+        evt,
+        Some(Box::new(input)),
+        output,
+        None,
+        serial_options(param),
+        keep_rds.to_vec(),
+    ))
+}
+
+fn serial_options(param: &SerialParameters) -> SerialOptions {
+    SerialOptions {
+        name: param.name.clone(),
+        out_timestamp: param.out_timestamp,
+        console: param.console,
+        pci_address: param.pci_address,
+        max_queue_sizes: param.max_queue_sizes.clone(),
+    }
+}
+
+/// Creates a serial device that uses a newly allocated pseudo-terminal for both input and output.
+///
+/// The path of the terminal is logged, and `param.path` is made a symbolic link to it if given.
+pub(crate) fn create_pty_serial_device<T: SerialDevice>(
+    param: &SerialParameters,
+    protection_type: ProtectionType,
+    evt: Event,
+    keep_rds: &mut Vec<RawDescriptor>,
+) -> std::result::Result<T, Error> {
+    let pty = Pty::new().map_err(Error::PtyCreate)?;
+    info!(
+        "serial device {} ({}) attached to {}",
+        param.num,
+        param.hardware,
+        pty.path().display()
+    );
+    if let Some(link) = &param.path {
+        // Replace the link left by a previous instance, but not other files.
+        if link.is_symlink() {
+            std::fs::remove_file(link).map_err(|e| Error::FileCreate(e, link.clone()))?;
+        }
+        std::os::unix::fs::symlink(pty.path(), link)
+            .map_err(|e| Error::FileCreate(e, link.clone()))?;
+    }
+    let (input, output) = pty.into_input_output().map_err(Error::PtyCreate)?;
+    keep_rds.push(input.get_read_notifier().as_raw_descriptor());
+    keep_rds.extend(output.as_raw_descriptors());
+    let output = param.tee_output(Some(Box::new(output)), keep_rds)?;
+
+    Ok(T::new(
+        protection_type,
+        evt,
+        Some(Box::new(input)),
+        output,
+        None,
+        serial_options(param),
+        keep_rds.to_vec(),
+    ))
+}
+
+/// Creates a serial device that serves its input and output to a client connecting to
+/// `param.tcp_port` on the loopback interface, e.g. with `telnet`.
+pub(crate) fn create_tcp_serial_device<T: SerialDevice>(
+    param: &SerialParameters,
+    protection_type: ProtectionType,
+    evt: Event,
+    keep_rds: &mut Vec<RawDescriptor>,
+) -> std::result::Result<T, Error> {
+    let port = param
+        .tcp_port
+        .ok_or_else(|| Error::InvalidConfig("type=tcp requires tcp-port".to_string()))?;
+    let serial = TcpSerial::bind(port).map_err(Error::SocketCreate)?;
+    if let Ok(addr) = serial.local_addr() {
+        info!(
+            "serial device {} ({}) listening on {}",
+            param.num, param.hardware, addr
+        );
+    }
+    let (input, output) = serial.into_input_output().map_err(Error::SocketCreate)?;
+    keep_rds.extend(input.as_raw_descriptors());
+    let output = param.tee_output(Some(Box::new(output)), keep_rds)?;
 
     Ok(T::new(
         protection_type,
         evt,
         Some(Box::new(input)),
-        Some(Box::new(output)),
+        output,
         None,
-        SerialOptions {
-            name: param.name.clone(),
-            out_timestamp: param.out_timestamp,
-            console: param.console,
-            pci_address: param.pci_address,
-            max_queue_sizes: param.max_queue_sizes.clone(),
-        },
+        serial_options(param),
         keep_rds.to_vec(),
     ))
 }
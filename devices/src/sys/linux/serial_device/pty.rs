@@ -0,0 +1,209 @@
+// Copyright 2025 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Serial device backend over a pseudo-terminal, which can be attached with e.g. `screen` or
+//! `minicom`.
+
+use std::ffi::CStr;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::os::fd::FromRawFd;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::path::PathBuf;
+
+use base::add_fd_flags;
+use base::AsRawDescriptor;
+use base::ReadNotifier;
+
+use crate::serial_device::SerialInput;
+
+/// A pseudo-terminal allocated for a serial device.
+pub struct Pty {
+    master: File,
+    // Kept open so that the master side doesn't hang up while no program has the terminal open.
+    slave: File,
+    path: PathBuf,
+}
+
+impl Pty {
+    /// Allocates a pseudo-terminal in raw mode.
+    pub fn new() -> io::Result<Pty> {
+        // SAFETY:
+        // Safe because we check the return value and take ownership of the returned fd.
+        let fd = unsafe { libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY | libc::O_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY:
+        // Safe because `fd` is a valid fd that nothing else owns.
+        let master = unsafe { File::from_raw_fd(fd) };
+
+        // SAFETY:
+        // Safe because `master` is a valid pseudo-terminal master and we check the return values.
+        if unsafe { libc::grantpt(master.as_raw_descriptor()) } < 0
+            || unsafe { libc::unlockpt(master.as_raw_descriptor()) } < 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut name = [0u8; 128];
+        // SAFETY:
+        // Safe because the kernel writes at most `name.len()` bytes to `name` and we check the
+        // return value.
+        let ret = unsafe {
+            libc::ptsname_r(
+                master.as_raw_descriptor(),
+                name.as_mut_ptr() as *mut libc::c_char,
+                name.len(),
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::from_raw_os_error(ret));
+        }
+        let path = PathBuf::from(
+            CStr::from_bytes_until_nul(&name)
+                .map_err(|_| io::Error::from(io::ErrorKind::InvalidData))?
+                .to_str()
+                .map_err(|_| io::Error::from(io::ErrorKind::InvalidData))?,
+        );
+
+        let slave = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(&path)?;
+
+        // Without raw mode, the line discipline would echo the output of the guest back to it
+        // while no program has the terminal open.
+        // SAFETY:
+        // Safe because termios is plain old data that tcgetattr overwrites.
+        let mut termios: libc::termios = unsafe { std::mem::zeroed() };
+        // SAFETY:
+        // Safe because the calls only access the extent of termios and we check the return values.
+        unsafe {
+            if libc::tcgetattr(slave.as_raw_descriptor(), &mut termios) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            libc::cfmakeraw(&mut termios);
+            if libc::tcsetattr(slave.as_raw_descriptor(), libc::TCSANOW, &termios) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        // Output is dropped rather than blocking the device when nothing reads the terminal.
+        add_fd_flags(master.as_raw_descriptor(), libc::O_NONBLOCK)?;
+
+        Ok(Pty {
+            master,
+            slave,
+            path,
+        })
+    }
+
+    /// Path of the terminal that programs open to talk to the device.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Splits the terminal into the input and output of a serial device.
+    pub fn into_input_output(self) -> io::Result<(PtyInput, PtyOutput)> {
+        let output = PtyOutput {
+            master: self.master.try_clone()?,
+            slave: self.slave,
+        };
+        Ok((PtyInput(self.master), output))
+    }
+}
+
+/// Input of a serial device read from the master side of a pseudo-terminal.
+pub struct PtyInput(File);
+
+impl Read for PtyInput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.0.read(buf) {
+            // The master is non-blocking, report spurious wakeups as interruptions so that callers
+            // wait for the next one.
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                Err(io::Error::from(io::ErrorKind::Interrupted))
+            }
+            res => res,
+        }
+    }
+}
+
+impl ReadNotifier for PtyInput {
+    fn get_read_notifier(&self) -> &dyn AsRawDescriptor {
+        &self.0
+    }
+}
+
+impl SerialInput for PtyInput {}
+
+/// Output of a serial device written to the master side of a pseudo-terminal.
+pub struct PtyOutput {
+    master: File,
+    // See `Pty::slave`.
+    slave: File,
+}
+
+impl PtyOutput {
+    /// Descriptors that must stay open for the output to work.
+    pub fn as_raw_descriptors(&self) -> [base::RawDescriptor; 2] {
+        [
+            self.master.as_raw_descriptor(),
+            self.slave.as_raw_descriptor(),
+        ]
+    }
+}
+
+impl Write for PtyOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.master.write(buf) {
+            // Nothing reads the terminal and its buffer is full, drop the output.
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(buf.len()),
+            res => res,
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pty_input_output() {
+        let pty = Pty::new().unwrap();
+        let mut terminal = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(pty.path())
+            .unwrap();
+        let (mut input, mut output) = pty.into_input_output().unwrap();
+
+        output.write_all(b"out").unwrap();
+        let mut buf = [0u8; 3];
+        terminal.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"out");
+
+        terminal.write_all(b"in").unwrap();
+        let mut buf = [0u8; 2];
+        let mut len = 0;
+        while len < buf.len() {
+            match input.read(&mut buf[len..]) {
+                Ok(n) => len += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => panic!("failed to read input: {}", e),
+            }
+        }
+        assert_eq!(&buf, b"in");
+    }
+}
@@ -0,0 +1,371 @@
+// Copyright 2025 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Serial device backend over TCP, which can be attached with e.g. `telnet`.
+//!
+//! The device listens on a port of the loopback interface and serves one client at a time. When
+//! the client disconnects, the next connection is accepted. Output written while no client is
+//! connected is dropped.
+
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::net::Ipv4Addr;
+use std::net::SocketAddr;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::time::Duration;
+
+use base::info;
+use base::warn;
+use base::AsRawDescriptor;
+use base::EventToken;
+use base::RawDescriptor;
+use base::ReadNotifier;
+use base::WaitContext;
+use sync::Mutex;
+
+use crate::serial_device::SerialInput;
+
+const IAC: u8 = 255;
+const DONT: u8 = 254;
+const WILL: u8 = 251;
+const SB: u8 = 250;
+const SE: u8 = 240;
+const OPT_ECHO: u8 = 1;
+const OPT_SUPPRESS_GO_AHEAD: u8 = 3;
+const OPT_LINEMODE: u8 = 34;
+
+// Sent to new clients so that telnet passes keystrokes as they are typed and lets the guest echo
+// them.
+const NEGOTIATION: [u8; 9] = [
+    IAC,
+    WILL,
+    OPT_ECHO,
+    IAC,
+    WILL,
+    OPT_SUPPRESS_GO_AHEAD,
+    IAC,
+    DONT,
+    OPT_LINEMODE,
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum TelnetState {
+    Data,
+    // After a carriage return, which telnet clients follow with NUL or LF.
+    CarriageReturn,
+    Command,
+    // Option negotiation, whose option byte is ignored.
+    Option,
+    Subnegotiation,
+    SubnegotiationCommand,
+}
+
+/// Strips the telnet commands from the data received from a client.
+struct TelnetDecoder {
+    state: TelnetState,
+}
+
+impl TelnetDecoder {
+    fn new() -> TelnetDecoder {
+        TelnetDecoder {
+            state: TelnetState::Data,
+        }
+    }
+
+    /// Decodes `buf` in place and returns the length of the decoded data.
+    fn decode(&mut self, buf: &mut [u8]) -> usize {
+        let mut len = 0;
+        for i in 0..buf.len() {
+            let byte = buf[i];
+            let mut emit = false;
+            self.state = match self.state {
+                TelnetState::CarriageReturn if byte == 0 || byte == b'\n' => TelnetState::Data,
+                TelnetState::Data | TelnetState::CarriageReturn => match byte {
+                    IAC => TelnetState::Command,
+                    b'\r' => {
+                        emit = true;
+                        TelnetState::CarriageReturn
+                    }
+                    _ => {
+                        emit = true;
+                        TelnetState::Data
+                    }
+                },
+                TelnetState::Command => match byte {
+                    // Escaped 255 byte.
+                    IAC => {
+                        emit = true;
+                        TelnetState::Data
+                    }
+                    SB => TelnetState::Subnegotiation,
+                    WILL..=DONT => TelnetState::Option,
+                    _ => TelnetState::Data,
+                },
+                TelnetState::Option => TelnetState::Data,
+                TelnetState::Subnegotiation => match byte {
+                    IAC => TelnetState::SubnegotiationCommand,
+                    _ => TelnetState::Subnegotiation,
+                },
+                TelnetState::SubnegotiationCommand => match byte {
+                    SE => TelnetState::Data,
+                    _ => TelnetState::Subnegotiation,
+                },
+            };
+            if emit {
+                buf[len] = byte;
+                len += 1;
+            }
+        }
+        len
+    }
+}
+
+#[derive(EventToken)]
+enum Token {
+    Connection,
+    Client,
+}
+
+/// Listens for the TCP connections of clients of a serial device.
+pub struct TcpSerial {
+    listener: TcpListener,
+}
+
+impl TcpSerial {
+    /// Listens on `port` of the loopback interface, or on a free port if `port` is 0.
+    pub fn bind(port: u16) -> io::Result<TcpSerial> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+        // Connections are accepted when the listener is readable, but they may be aborted before.
+        listener.set_nonblocking(true)?;
+        Ok(TcpSerial { listener })
+    }
+
+    /// Address the device listens on.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Splits the listener into the input and output of a serial device.
+    pub fn into_input_output(self) -> io::Result<(TcpInput, TcpOutput)> {
+        let wait_ctx = WaitContext::build_with(&[(&self.listener, Token::Connection)])?;
+        let client = Arc::new(Mutex::new(None));
+        let output = TcpOutput {
+            client: client.clone(),
+        };
+        let input = TcpInput {
+            listener: self.listener,
+            wait_ctx,
+            client,
+            decoder: TelnetDecoder::new(),
+        };
+        Ok((input, output))
+    }
+}
+
+/// Input of a serial device received from the connected client, if any.
+///
+/// Connections are accepted and closed while reading, in which case reads fail with
+/// `ErrorKind::Interrupted` if no data was received.
+pub struct TcpInput {
+    listener: TcpListener,
+    // Contains the listener and the connected client. Readable when either one is.
+    wait_ctx: WaitContext<Token>,
+    client: Arc<Mutex<Option<TcpStream>>>,
+    decoder: TelnetDecoder,
+}
+
+impl TcpInput {
+    /// Descriptors that must stay open for the input to work.
+    pub fn as_raw_descriptors(&self) -> [RawDescriptor; 2] {
+        [
+            self.listener.as_raw_descriptor(),
+            self.wait_ctx.as_raw_descriptor(),
+        ]
+    }
+
+    fn accept(&mut self) {
+        let stream = match self.listener.accept() {
+            Ok((stream, _addr)) => stream,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+            Err(e) => {
+                warn!("serial: failed to accept connection: {}", e);
+                return;
+            }
+        };
+        let mut client = self.client.lock();
+        if client.is_some() {
+            // Only one client is served at a time, the new connection is closed.
+            return;
+        }
+        if let Err(e) = self.wait_ctx.add(&stream, Token::Client) {
+            warn!("serial: failed to wait for client: {}", e);
+            return;
+        }
+        if let Ok(addr) = stream.peer_addr() {
+            info!("serial: client {} connected", addr);
+        }
+        send_nonblocking(&stream, &NEGOTIATION);
+        *client = Some(stream);
+    }
+
+    fn read_client(&mut self, buf: &mut [u8]) -> usize {
+        let mut client = self.client.lock();
+        let Some(stream) = client.as_mut() else {
+            return 0;
+        };
+        match stream.read(buf) {
+            Ok(0) => {}
+            Ok(n) => return self.decoder.decode(&mut buf[..n]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => return 0,
+            Err(e) => warn!("serial: failed to read from client: {}", e),
+        }
+        // The client disconnected.
+        let _ = self.wait_ctx.delete(stream);
+        *client = None;
+        self.decoder = TelnetDecoder::new();
+        info!("serial: client disconnected");
+        0
+    }
+}
+
+impl Read for TcpInput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let events = self.wait_ctx.wait_timeout(Duration::ZERO)?;
+        let connection = events.iter().any(|e| matches!(e.token, Token::Connection));
+        let client = events.iter().any(|e| matches!(e.token, Token::Client));
+
+        let len = if client { self.read_client(buf) } else { 0 };
+        if connection {
+            self.accept();
+        }
+        if len == 0 {
+            return Err(io::Error::from(io::ErrorKind::Interrupted));
+        }
+        Ok(len)
+    }
+}
+
+impl ReadNotifier for TcpInput {
+    fn get_read_notifier(&self) -> &dyn AsRawDescriptor {
+        &self.wait_ctx
+    }
+}
+
+impl SerialInput for TcpInput {}
+
+/// Output of a serial device sent to the connected client, if any.
+pub struct TcpOutput {
+    client: Arc<Mutex<Option<TcpStream>>>,
+}
+
+impl Write for TcpOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(stream) = self.client.lock().as_ref() {
+            // Escape the bytes that telnet would interpret as commands.
+            let mut escaped = Vec::with_capacity(buf.len());
+            for &byte in buf {
+                escaped.push(byte);
+                if byte == IAC {
+                    escaped.push(IAC);
+                }
+            }
+            send_nonblocking(stream, &escaped);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Sends as much of `data` as possible to `stream` without blocking. The device doesn't wait for
+// slow clients, and errors are detected when reading from the client.
+fn send_nonblocking(stream: &TcpStream, data: &[u8]) {
+    // SAFETY:
+    // Safe because the kernel only reads from `data` within its length.
+    unsafe {
+        libc::send(
+            stream.as_raw_descriptor(),
+            data.as_ptr() as *const libc::c_void,
+            data.len(),
+            libc::MSG_DONTWAIT | libc::MSG_NOSIGNAL,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(decoder: &mut TelnetDecoder, data: &[u8]) -> Vec<u8> {
+        let mut buf = data.to_vec();
+        let len = decoder.decode(&mut buf);
+        buf.truncate(len);
+        buf
+    }
+
+    #[test]
+    fn telnet_decoder() {
+        let mut decoder = TelnetDecoder::new();
+        // Option negotiation and subnegotiation are stripped.
+        assert_eq!(
+            decode(
+                &mut decoder,
+                &[b'a', IAC, WILL, OPT_ECHO, b'b', IAC, SB, 31, 0, 80, IAC, SE, b'c']
+            ),
+            b"abc"
+        );
+        // Escaped IAC.
+        assert_eq!(decode(&mut decoder, &[IAC, IAC]), [IAC]);
+        // Line endings.
+        assert_eq!(decode(&mut decoder, b"x\r\0y\r\nz"), b"x\ry\rz");
+        // Commands split across reads.
+        assert_eq!(decode(&mut decoder, &[b'd', IAC]), b"d");
+        assert_eq!(decode(&mut decoder, &[DONT]), b"");
+        assert_eq!(decode(&mut decoder, &[OPT_LINEMODE, b'e']), b"e");
+    }
+
+    fn read_input(input: &mut TcpInput, buf: &mut [u8]) -> usize {
+        loop {
+            match input.read(buf) {
+                Ok(n) => return n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {
+                    std::thread::sleep(Duration::from_millis(1))
+                }
+                Err(e) => panic!("failed to read input: {}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn reconnect() {
+        let serial = TcpSerial::bind(0).unwrap();
+        let addr = serial.local_addr().unwrap();
+        let (mut input, mut output) = serial.into_input_output().unwrap();
+
+        for _ in 0..2 {
+            let mut client = TcpStream::connect(addr).unwrap();
+            client.write_all(b"in").unwrap();
+            let mut buf = [0u8; 2];
+            assert_eq!(read_input(&mut input, &mut buf), 2);
+            assert_eq!(&buf, b"in");
+
+            output.write_all(&[b'o', IAC]).unwrap();
+            let mut buf = [0u8; NEGOTIATION.len() + 3];
+            client.read_exact(&mut buf).unwrap();
+            assert_eq!(buf[..NEGOTIATION.len()], NEGOTIATION);
+            assert_eq!(buf[NEGOTIATION.len()..], [b'o', IAC, IAC]);
+
+            drop(client);
+            // Wait for the disconnection to be noticed.
+            while input.client.lock().is_some() {
+                let _ = input.read(&mut buf);
+            }
+        }
+    }
+}
@@ -109,7 +109,7 @@ const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE, QUEUE_SIZE, QUEUE_SIZE, QUEUE_SIZE, QU
 const INFLATEQ: usize = 0;
 const DEFLATEQ: usize = 1;
 const STATSQ: usize = 2;
-const _FREE_PAGE_VQ: usize = 3;
+const FREE_PAGE_VQ: usize = 3;
 const REPORTING_VQ: usize = 4;
 const WS_DATA_VQ: usize = 5;
 const WS_OP_VQ: usize = 6;
@@ -121,6 +121,7 @@ const VIRTIO_BALLOON_PF_SIZE: u64 = 1 << VIRTIO_BALLOON_PFN_SHIFT;
 const VIRTIO_BALLOON_F_MUST_TELL_HOST: u32 = 0; // Tell before reclaiming pages
 const VIRTIO_BALLOON_F_STATS_VQ: u32 = 1; // Stats reporting enabled
 const VIRTIO_BALLOON_F_DEFLATE_ON_OOM: u32 = 2; // Deflate balloon on OOM
+const VIRTIO_BALLOON_F_FREE_PAGE_HINT: u32 = 3; // Free page hinting virtqueue
 const VIRTIO_BALLOON_F_PAGE_REPORTING: u32 = 5; // Page reporting virtqueue
                                                 // TODO(b/273973298): this should maybe be bit 6? to be changed later
 const VIRTIO_BALLOON_F_WS_REPORTING: u32 = 8; // Working Set Reporting virtqueues
@@ -129,6 +130,8 @@ const VIRTIO_BALLOON_F_WS_REPORTING: u32 = 8; // Working Set Reporting virtqueue
 #[repr(u32)]
 // Balloon virtqueues
 pub enum BalloonFeatures {
+    // Free Page Hinting enabled
+    FreePageHinting = VIRTIO_BALLOON_F_FREE_PAGE_HINT,
     // Page Reporting enabled
     PageReporting = VIRTIO_BALLOON_F_PAGE_REPORTING,
     // WS Reporting enabled
@@ -159,6 +162,27 @@ struct BalloonState {
     // Adjusted success/failure response is sent.
     failable_update: bool,
     pending_adjusted_responses: VecDeque<u32>,
+    // Command id of the free page hinting session requested from the guest, or one of the
+    // VIRTIO_BALLOON_CMD_ID_* values.
+    #[serde(default)]
+    free_page_hint_cmd_id: u32,
+    // Command id of the last session, which the guest doesn't start again.
+    #[serde(default)]
+    last_free_page_hint_cmd_id: u32,
+    // Bytes hinted by the guest since the host requested free page hints, until they are reported
+    // to the host.
+    #[serde(skip)]
+    free_pages_hinted: Option<u64>,
+}
+
+// Values of free_page_hint_cmd_id that don't identify a session.
+const VIRTIO_BALLOON_CMD_ID_STOP: u32 = 0;
+const VIRTIO_BALLOON_CMD_ID_DONE: u32 = 1;
+
+fn next_free_page_hint_cmd_id(last: u32) -> u32 {
+    last.checked_add(1)
+        .filter(|&id| id > VIRTIO_BALLOON_CMD_ID_DONE)
+        .unwrap_or(VIRTIO_BALLOON_CMD_ID_DONE + 1)
 }
 
 // The constants defining stats types in virtio_baloon_stat
@@ -428,6 +452,77 @@ where
     }
 }
 
+async fn send_free_pages_hinted_response(tube: &AsyncTube, num_bytes: u64) {
+    let result = BalloonTubeResult::FreePagesHinted { num_bytes };
+    if let Err(e) = tube.send(result).await {
+        error!("failed to send free pages hinted result: {}", e);
+    }
+}
+
+// Async task that handles the free page hinting queue. The guest starts a session requested in the
+// config with a buffer holding its command id, then adds its free pages to the queue, and ends the
+// session with a buffer holding VIRTIO_BALLOON_CMD_ID_STOP. The hinted pages are not used by the
+// guest until the command id is set to VIRTIO_BALLOON_CMD_ID_DONE, so their contents can be
+// discarded in the meantime.
+async fn handle_free_page_queue<F>(
+    mut queue: Queue,
+    mut queue_event: EventAsync,
+    command_tube: &AsyncTube,
+    release_memory_tube: Option<&Tube>,
+    state: Arc<AsyncRwLock<BalloonState>>,
+    mut desc_handler: F,
+    mut stop_rx: oneshot::Receiver<()>,
+) -> Queue
+where
+    F: FnMut(Vec<(GuestAddress, u64)>),
+{
+    // Command id of the session the guest is hinting pages for.
+    let mut session = None;
+    loop {
+        let mut avail_desc = match queue
+            .next_async_interruptable(&mut queue_event, &mut stop_rx)
+            .await
+        {
+            Ok(Some(res)) => res,
+            Ok(None) => return queue,
+            Err(e) => {
+                error!("Failed to read descriptor {}", e);
+                return queue;
+            }
+        };
+
+        let mut state = state.lock().await;
+        if avail_desc.reader.available_bytes() != 0 {
+            match avail_desc.reader.read_obj::<Le32>() {
+                Ok(cmd_id) if cmd_id.to_native() == VIRTIO_BALLOON_CMD_ID_STOP => {
+                    session = None;
+                    if let Some(num_bytes) = state.free_pages_hinted.take() {
+                        send_free_pages_hinted_response(command_tube, num_bytes).await;
+                    }
+                }
+                Ok(cmd_id) => session = Some(cmd_id.to_native()),
+                Err(e) => error!("error while reading free page hint command id: {}", e),
+            }
+        } else if session.is_some() && session == Some(state.free_page_hint_cmd_id) {
+            // Hints of a session that is no longer requested are ignored, since the guest may be
+            // reusing the pages.
+            let num_bytes = avail_desc.writer.available_bytes() as u64;
+            if let Err(e) =
+                handle_reported_buffer(release_memory_tube, &avail_desc, &mut desc_handler)
+            {
+                error!("balloon: failed to process hinted buffer: {}", e);
+            }
+            if let Some(hinted) = state.free_pages_hinted.as_mut() {
+                *hinted += num_bytes;
+            }
+        }
+        drop(state);
+
+        queue.add_used(avail_desc, 0);
+        queue.trigger_interrupt();
+    }
+}
+
 fn parse_balloon_stats(reader: &mut Reader) -> BalloonStats {
     let mut stats: BalloonStats = Default::default();
     for res in reader.iter::<BalloonStat>() {
@@ -691,6 +786,7 @@ async fn handle_command_tube(
     state: Arc<AsyncRwLock<BalloonState>>,
     mut stats_tx: mpsc::Sender<()>,
     mut ws_op_tx: mpsc::Sender<WSOp>,
+    free_page_hinting: bool,
     mut stop_rx: oneshot::Receiver<()>,
 ) -> Result<()> {
     loop {
@@ -743,6 +839,32 @@ async fn handle_command_tube(
                         error!("failed to send report request to ws handler: {}", e);
                     }
                 }
+                BalloonTubeCommand::FreePageHint => {
+                    if !free_page_hinting {
+                        // The guest can't hint pages, don't make the host wait for it.
+                        send_free_pages_hinted_response(command_tube, 0).await;
+                        continue;
+                    }
+                    let mut state = state.lock().await;
+                    let cmd_id = next_free_page_hint_cmd_id(state.last_free_page_hint_cmd_id);
+                    state.free_page_hint_cmd_id = cmd_id;
+                    state.last_free_page_hint_cmd_id = cmd_id;
+                    state.free_pages_hinted = Some(0);
+                    interrupt.signal_config_changed();
+                }
+                BalloonTubeCommand::FreePageHintDone => {
+                    if !free_page_hinting {
+                        continue;
+                    }
+                    let mut state = state.lock().await;
+                    state.free_page_hint_cmd_id = VIRTIO_BALLOON_CMD_ID_DONE;
+                    interrupt.signal_config_changed();
+                    // The guest stops hinting once it notices the new command id. Report what it
+                    // hinted so far if it hasn't ended the session yet.
+                    if let Some(num_bytes) = state.free_pages_hinted.take() {
+                        send_free_pages_hinted_response(command_tube, num_bytes).await;
+                    }
+                }
             },
             #[cfg(windows)]
             Err(base::TubeError::Recv(e)) if e.kind() == std::io::ErrorKind::TimedOut => {
@@ -785,6 +907,7 @@ struct BalloonQueues {
     inflate: Queue,
     deflate: Queue,
     stats: Option<Queue>,
+    free_page: Option<Queue>,
     reporting: Option<Queue>,
     ws_data: Option<Queue>,
     ws_op: Option<Queue>,
@@ -796,6 +919,7 @@ impl BalloonQueues {
             inflate,
             deflate,
             stats: None,
+            free_page: None,
             reporting: None,
             ws_data: None,
             ws_op: None,
@@ -808,6 +932,7 @@ struct PausedQueues {
     inflate: Queue,
     deflate: Queue,
     stats: Option<Queue>,
+    free_page: Option<Queue>,
     reporting: Option<Queue>,
     ws_data: Option<Queue>,
     ws_op: Option<Queue>,
@@ -819,6 +944,7 @@ impl PausedQueues {
             inflate,
             deflate,
             stats: None,
+            free_page: None,
             reporting: None,
             ws_data: None,
             ws_op: None,
@@ -841,6 +967,7 @@ impl From<Box<PausedQueues>> for BTreeMap<usize, Queue> {
         ret.push(queues.inflate);
         ret.push(queues.deflate);
         apply_if_some(queues.stats, |stats| ret.push(stats));
+        apply_if_some(queues.free_page, |free_page| ret.push(free_page));
         apply_if_some(queues.reporting, |reporting| ret.push(reporting));
         apply_if_some(queues.ws_data, |ws_data| ret.push(ws_data));
         apply_if_some(queues.ws_op, |ws_op| ret.push(ws_op));
@@ -894,6 +1021,7 @@ fn run_worker(
     inflate_queue: Queue,
     deflate_queue: Queue,
     stats_queue: Option<Queue>,
+    free_page_queue: Option<Queue>,
     reporting_queue: Option<Queue>,
     ws_data_queue: Option<Queue>,
     ws_op_queue: Option<Queue>,
@@ -977,6 +1105,31 @@ fn run_worker(
         let stats = stats.fuse();
         pin_mut!(stats);
 
+        // The next queue is used for free page hints if VIRTIO_BALLOON_F_FREE_PAGE_HINT is
+        // negotiated.
+        let has_free_page_queue = free_page_queue.is_some();
+        let free_page = if let Some(free_page_queue) = free_page_queue {
+            let stop_rx = create_stop_oneshot(&mut stop_queue_oneshots);
+            let free_page_queue_evt = free_page_queue
+                .event()
+                .try_clone()
+                .expect("failed to clone queue event");
+            handle_free_page_queue(
+                free_page_queue,
+                EventAsync::new(free_page_queue_evt, &ex).expect("failed to create async event"),
+                &command_tube,
+                release_memory_tube.as_ref(),
+                state.clone(),
+                |ranges| free_memory(&vm_memory_client, &mem, ranges),
+                stop_rx,
+            )
+            .left_future()
+        } else {
+            std::future::pending().right_future()
+        };
+        let free_page = free_page.fuse();
+        pin_mut!(free_page);
+
         // The next queue is used for reporting messages
         let has_reporting_queue = reporting_queue.is_some();
         let reporting = if let Some(reporting_queue) = reporting_queue {
@@ -1054,6 +1207,7 @@ fn run_worker(
             state.clone(),
             stats_tx,
             ws_op_tx,
+            has_free_page_queue,
             stop_rx,
         );
         pin_mut!(command);
@@ -1080,6 +1234,7 @@ fn run_worker(
                 _ = inflate => return Err(anyhow!("inflate stopped unexpectedly")),
                 _ = deflate => return Err(anyhow!("deflate stopped unexpectedly")),
                 _ = stats => return Err(anyhow!("stats stopped unexpectedly")),
+                _ = free_page => return Err(anyhow!("free_page stopped unexpectedly")),
                 _ = reporting => return Err(anyhow!("reporting stopped unexpectedly")),
                 _ = command.fuse() => return Err(anyhow!("command stopped unexpectedly")),
                 _ = ws_op => return Err(anyhow!("ws_op stopped unexpectedly")),
@@ -1108,6 +1263,9 @@ fn run_worker(
             if has_stats_queue {
                 paused_queues.stats = Some(stats.await);
             }
+            if has_free_page_queue {
+                paused_queues.free_page = Some(free_page.await);
+            }
             if has_ws_data_queue {
                 paused_queues.ws_data = Some(ws_data.await.context("failed to stop ws_data queue")?);
             }
@@ -1179,6 +1337,8 @@ pub struct Balloon {
     registered_evt_q: Option<SendTube>,
     ws_num_bins: u8,
     target_reached_evt: Option<Event>,
+    // Set when the config changed while the device was asleep.
+    config_changed_on_wake: bool,
 }
 
 /// Snapshot of the [Balloon] state.
@@ -1223,6 +1383,9 @@ impl Balloon {
                 failable_update: false,
                 pending_adjusted_responses: VecDeque::new(),
                 expecting_ws: false,
+                free_page_hint_cmd_id: VIRTIO_BALLOON_CMD_ID_STOP,
+                last_free_page_hint_cmd_id: VIRTIO_BALLOON_CMD_ID_STOP,
+                free_pages_hinted: None,
             })),
             worker_thread: None,
             features,
@@ -1231,6 +1394,7 @@ impl Balloon {
             registered_evt_q,
             ws_num_bins,
             target_reached_evt: None,
+            config_changed_on_wake: false,
         })
     }
 
@@ -1239,11 +1403,10 @@ impl Balloon {
         virtio_balloon_config {
             num_pages: state.num_pages.into(),
             actual: state.actual_pages.into(),
-            // crosvm does not (currently) use free_page_hint_cmd_id or
-            // poison_val, but they must be present in the right order and size
-            // for the virtio-balloon driver in the guest to deserialize the
-            // config correctly.
-            free_page_hint_cmd_id: 0.into(),
+            free_page_hint_cmd_id: state.free_page_hint_cmd_id.into(),
+            // crosvm does not (currently) use poison_val, but it must be present
+            // in the right order and size for the virtio-balloon driver in the
+            // guest to deserialize the config correctly.
             poison_val: 0.into(),
             ws_num_bins: self.ws_num_bins,
             _reserved: [0, 0, 0],
@@ -1306,6 +1469,9 @@ impl Balloon {
         if self.acked_features & (1 << VIRTIO_BALLOON_F_STATS_VQ) != 0 {
             queue_struct.stats = Some(pop_queue(&mut queues, STATSQ, "statsq")?);
         }
+        if self.acked_features & (1 << VIRTIO_BALLOON_F_FREE_PAGE_HINT) != 0 {
+            queue_struct.free_page = Some(pop_queue(&mut queues, FREE_PAGE_VQ, "free_page_vq")?);
+        }
         if self.acked_features & (1 << VIRTIO_BALLOON_F_PAGE_REPORTING) != 0 {
             queue_struct.reporting = Some(pop_queue(&mut queues, REPORTING_VQ, "reporting_vq")?);
         }
@@ -1350,6 +1516,7 @@ impl Balloon {
                 queues.inflate,
                 queues.deflate,
                 queues.stats,
+                queues.free_page,
                 queues.reporting,
                 queues.ws_data,
                 queues.ws_op,
@@ -1475,6 +1642,9 @@ impl VirtioDevice for Balloon {
             }
 
             let balloon_queues = self.get_queues_from_map(queues)?;
+            if std::mem::take(&mut self.config_changed_on_wake) {
+                interrupt.signal_config_changed();
+            }
             self.start_worker(mem, interrupt, balloon_queues)?;
         }
         Ok(())
@@ -1511,6 +1681,12 @@ impl VirtioDevice for Balloon {
             .now_or_never()
             .context("failed to acquire balloon lock")?;
         *state = snap.state;
+        if state.free_page_hint_cmd_id > VIRTIO_BALLOON_CMD_ID_DONE {
+            // The snapshot was taken while the guest was hinting its free pages, which it keeps
+            // until it is told that hinting is done.
+            state.free_page_hint_cmd_id = VIRTIO_BALLOON_CMD_ID_DONE;
+            self.config_changed_on_wake = true;
+        }
         self.ws_num_bins = snap.ws_num_bins;
         self.acked_features = snap.acked_features;
         Ok(())
@@ -1594,5 +1770,29 @@ mod tests {
         )
     }
 
+    #[test]
+    fn free_page_hint_cmd_ids() {
+        assert_eq!(next_free_page_hint_cmd_id(VIRTIO_BALLOON_CMD_ID_STOP), 2);
+        assert_eq!(next_free_page_hint_cmd_id(2), 3);
+        // Ids wrap around without reaching STOP or DONE.
+        assert_eq!(next_free_page_hint_cmd_id(u32::MAX), 2);
+    }
+
+    #[test]
+    fn restore_ends_free_page_hinting() {
+        let (_context, mut balloon) = create_device();
+        block_on(balloon.state.lock()).free_page_hint_cmd_id = 5;
+        let snap = balloon.virtio_snapshot().unwrap();
+
+        let (_context, mut balloon) = create_device();
+        balloon.virtio_restore(snap).unwrap();
+        // The guest is told to reuse the pages it hinted before the snapshot.
+        assert_eq!(
+            balloon.get_config().free_page_hint_cmd_id.to_native(),
+            VIRTIO_BALLOON_CMD_ID_DONE
+        );
+        assert!(balloon.config_changed_on_wake);
+    }
+
     suspendable_virtio_tests!(balloon, create_device, 2, modify_device);
 }
@@ -48,18 +48,28 @@ use futures::channel::oneshot;
 use futures::pin_mut;
 use futures::stream::FuturesUnordered;
 use futures::stream::StreamExt;
+use futures::Future;
 use futures::FutureExt;
 use remain::sorted;
 use snapshot::AnySnapshot;
+use sync::Mutex;
 use thiserror::Error as ThisError;
 use virtio_sys::virtio_config::VIRTIO_F_RING_PACKED;
+use vm_control::BlockJobCommand;
+use vm_control::BlockJobKind;
+use vm_control::BlockJobState;
 use vm_control::DiskControlCommand;
 use vm_control::DiskControlResult;
+use vm_control::DiskMedia;
+use vm_control::DiskThrottle;
+use vm_control::InternalSnapshotCommand;
 use vm_memory::GuestMemory;
 use zerocopy::IntoBytes;
 
 use crate::virtio::async_utils;
+use crate::virtio::block::job::BlockJob;
 use crate::virtio::block::sys::*;
+use crate::virtio::block::throttle::Throttle;
 use crate::virtio::block::DiskOption;
 use crate::virtio::copy_config;
 use crate::virtio::device_constants::block::virtio_blk_config;
@@ -122,6 +132,8 @@ enum ExecuteError {
     Flush(disk::Error),
     #[error("not enough space in descriptor chain to write status")]
     MissingStatus,
+    #[error("no media in the disk")]
+    NoMedia,
     #[error("out of range")]
     OutOfRange,
     #[error("failed to read message: {0}")]
@@ -138,6 +150,8 @@ enum ExecuteError {
     ReceivingCommand(TubeError),
     #[error("failed to send command response: {0}")]
     SendingResponse(TubeError),
+    #[error("failed to delay a throttled request: {0}")]
+    Throttle(cros_async::Error),
     #[error("couldn't reset the timer: {0}")]
     TimerReset(base::Error),
     #[error("unsupported ({0})")]
@@ -164,12 +178,14 @@ impl ExecuteError {
             ExecuteError::DiscardWriteZeroes { .. } => VIRTIO_BLK_S_IOERR,
             ExecuteError::Flush(_) => VIRTIO_BLK_S_IOERR,
             ExecuteError::MissingStatus => VIRTIO_BLK_S_IOERR,
+            ExecuteError::NoMedia => VIRTIO_BLK_S_IOERR,
             ExecuteError::OutOfRange { .. } => VIRTIO_BLK_S_IOERR,
             ExecuteError::Read(_) => VIRTIO_BLK_S_IOERR,
             ExecuteError::ReadIo { .. } => VIRTIO_BLK_S_IOERR,
             ExecuteError::ReadOnly { .. } => VIRTIO_BLK_S_IOERR,
             ExecuteError::ReceivingCommand(_) => VIRTIO_BLK_S_IOERR,
             ExecuteError::SendingResponse(_) => VIRTIO_BLK_S_IOERR,
+            ExecuteError::Throttle(_) => VIRTIO_BLK_S_IOERR,
             ExecuteError::TimerReset(_) => VIRTIO_BLK_S_IOERR,
             ExecuteError::WriteIo { .. } => VIRTIO_BLK_S_IOERR,
             ExecuteError::WriteStatus(_) => VIRTIO_BLK_S_IOERR,
@@ -186,7 +202,8 @@ impl ExecuteError {
             ExecuteError::ReadIo { .. }
             | ExecuteError::WriteIo { .. }
             | ExecuteError::Flush { .. }
-            | ExecuteError::DiscardWriteZeroes { .. } => LogLevel::Debug,
+            | ExecuteError::DiscardWriteZeroes { .. }
+            | ExecuteError::NoMedia => LogLevel::Debug,
             // Log all other failures as errors.
             _ => LogLevel::Error,
         }
@@ -214,21 +231,59 @@ type BlockId = [u8; ID_LEN];
 
 /// Tracks the state of an anynchronous disk.
 struct DiskState {
-    disk_image: Box<dyn AsyncDisk>,
+    /// `None` while the media is ejected.
+    disk_image: Option<Box<dyn AsyncDisk>>,
     read_only: bool,
     sparse: bool,
     id: Option<BlockId>,
+    /// The options the device was created with, used to open new media.
+    disk_option: DiskOption,
+    /// The running or last block job.
+    job: Option<BlockJob>,
     /// A DiskState is owned by each worker's executor and cannot be shared by workers, thus
     /// `worker_shared_state` holds the state shared by workers in Arc.
     worker_shared_state: Arc<AsyncRwLock<WorkerSharedState>>,
 }
 
+impl DiskState {
+    fn media(&self) -> result::Result<&dyn AsyncDisk, ExecuteError> {
+        self.disk_image.as_deref().ok_or(ExecuteError::NoMedia)
+    }
+
+    /// Runs `write`, which modifies `len` bytes of `disk_image` at `offset`, and lets the block job
+    /// know about it.
+    async fn job_write<T>(
+        &self,
+        disk_image: &dyn AsyncDisk,
+        offset: u64,
+        len: u64,
+        write: impl Future<Output = T>,
+    ) -> T {
+        let Some(job) = &self.job else {
+            return write.await;
+        };
+        job.before_write(disk_image, offset, len).await;
+        let result = write.await;
+        job.after_write(offset, len);
+        result
+    }
+
+    /// Returns whether a block job is copying the disk, which prevents changing the disk in other
+    /// ways.
+    fn job_active(&self) -> bool {
+        self.job.as_ref().is_some_and(BlockJob::is_active)
+    }
+}
+
 /// Disk state which can be modified by other worker threads
 struct WorkerSharedState {
     disk_size: Arc<AtomicU64>,
+    /// Shared so the limits apply to the disk as a whole rather than to each worker.
+    throttle: Mutex<Throttle>,
 }
 
 async fn process_one_request(
+    ex: &Executor,
     avail_desc: &mut DescriptorChain,
     disk_state: &AsyncRwLock<DiskState>,
     flush_timer: &RefCell<TimerAsync<Timer>>,
@@ -247,6 +302,7 @@ async fn process_one_request(
     let mut status_writer = writer.split_at(status_offset);
 
     let status = match BlockAsync::execute_request(
+        ex,
         reader,
         writer,
         disk_state,
@@ -273,14 +329,21 @@ async fn process_one_request(
 
 /// Process one descriptor chain asynchronously.
 async fn process_one_chain(
+    ex: &Executor,
     queue: &RefCell<Queue>,
     mut avail_desc: DescriptorChain,
     disk_state: &AsyncRwLock<DiskState>,
     flush_timer: &RefCell<TimerAsync<Timer>>,
     flush_timer_armed: &RefCell<bool>,
 ) {
-    let len = match process_one_request(&mut avail_desc, disk_state, flush_timer, flush_timer_armed)
-        .await
+    let len = match process_one_request(
+        ex,
+        &mut avail_desc,
+        disk_state,
+        flush_timer,
+        flush_timer_armed,
+    )
+    .await
     {
         Ok(len) => len,
         Err(e) => {
@@ -298,6 +361,7 @@ async fn process_one_chain(
 // Receives messages from the guest and queues a task to complete the operations with the async
 // executor.
 async fn handle_queue(
+    ex: Executor,
     disk_state: Rc<AsyncRwLock<DiskState>>,
     queue: Queue,
     evt: EventAsync,
@@ -334,6 +398,7 @@ async fn handle_queue(
         };
         while let Some(descriptor_chain) = queue.borrow_mut().pop() {
             background_tasks.push(process_one_chain(
+                &ex,
                 &queue,
                 descriptor_chain,
                 &disk_state,
@@ -345,6 +410,7 @@ async fn handle_queue(
 }
 
 async fn handle_command_tube(
+    ex: &Executor,
     command_tube: &Option<AsyncTube>,
     interrupt: &RefCell<Option<Interrupt>>,
     disk_state: Rc<AsyncRwLock<DiskState>>,
@@ -359,8 +425,30 @@ async fn handle_command_tube(
     loop {
         match command_tube.next().await {
             Ok(command) => {
+                let config_changed = matches!(
+                    command,
+                    DiskControlCommand::Resize { .. }
+                        | DiskControlCommand::Eject
+                        | DiskControlCommand::Insert(_)
+                        | DiskControlCommand::Replace(_)
+                        | DiskControlCommand::BlockJob(BlockJobCommand::Complete)
+                );
                 let resp = match command {
                     DiskControlCommand::Resize { new_size } => resize(&disk_state, new_size).await,
+                    DiskControlCommand::InternalSnapshot(command) => {
+                        internal_snapshot(&disk_state, command).await
+                    }
+                    DiskControlCommand::Eject => change_media(ex, &disk_state, None, false).await,
+                    DiskControlCommand::Insert(media) => {
+                        change_media(ex, &disk_state, Some(media), false).await
+                    }
+                    DiskControlCommand::Replace(media) => {
+                        change_media(ex, &disk_state, Some(media), true).await
+                    }
+                    DiskControlCommand::BlockJob(command) => {
+                        block_job(ex, &disk_state, command).await
+                    }
+                    DiskControlCommand::Throttle(limits) => throttle(&disk_state, limits).await,
                 };
 
                 let resp_clone = resp.clone();
@@ -368,7 +456,7 @@ async fn handle_command_tube(
                     .send(resp_clone)
                     .await
                     .map_err(ExecuteError::SendingResponse)?;
-                if let DiskControlResult::Ok = resp {
+                if config_changed && resp == DiskControlResult::Ok {
                     if let Some(interrupt) = &*interrupt.borrow() {
                         interrupt.signal_config_changed();
                     }
@@ -379,6 +467,21 @@ async fn handle_command_tube(
     }
 }
 
+async fn throttle(disk_state: &AsyncRwLock<DiskState>, limits: DiskThrottle) -> DiskControlResult {
+    // The limits are shared with the other workers, so this doesn't need to wait for requests to
+    // complete.
+    let disk_state = disk_state.read_lock().await;
+    let worker_shared_state = disk_state.worker_shared_state.read_lock().await;
+    let mut throttle = worker_shared_state.throttle.lock();
+    info!(
+        "Changing disk throttle from {} to {}",
+        throttle.limits(),
+        limits
+    );
+    throttle.set_limits(limits);
+    DiskControlResult::Ok
+}
+
 async fn resize(disk_state: &AsyncRwLock<DiskState>, new_size: u64) -> DiskControlResult {
     // Acquire exclusive, mutable access to the state so the virtqueue task won't be able to read
     // the state while resizing.
@@ -392,22 +495,385 @@ async fn resize(disk_state: &AsyncRwLock<DiskState>, new_size: u64) -> DiskContr
         return DiskControlResult::Err(SysError::new(libc::EROFS));
     }
 
+    let Some(disk_image) = &disk_state.disk_image else {
+        error!("Attempted to resize block device without media");
+        return DiskControlResult::Err(SysError::new(libc::ENOMEDIUM));
+    };
+
+    if disk_state.job_active() {
+        error!("Attempted to resize block device while a block job is running");
+        return DiskControlResult::Err(SysError::new(libc::EBUSY));
+    }
+
     info!("Resizing block device to {} bytes", new_size);
 
-    if let Err(e) = disk_state.disk_image.set_len(new_size) {
+    if let Err(e) = disk_image.set_len(new_size) {
         error!("Resizing disk failed! {:#}", e);
         return DiskControlResult::Err(SysError::new(libc::EIO));
     }
 
     // Allocate new space if the disk image is not sparse.
     if !disk_state.sparse {
-        if let Err(e) = disk_state.disk_image.allocate(0, new_size) {
+        if let Err(e) = disk_image.allocate(0, new_size) {
             error!("Allocating disk space after resize failed! {:#}", e);
             return DiskControlResult::Err(SysError::new(libc::EIO));
         }
     }
 
-    if let Ok(new_disk_size) = disk_state.disk_image.get_len() {
+    if let Ok(new_disk_size) = disk_image.get_len() {
+        worker_shared_state
+            .disk_size
+            .store(new_disk_size, Ordering::Release);
+    }
+    DiskControlResult::Ok
+}
+
+async fn internal_snapshot(
+    disk_state: &AsyncRwLock<DiskState>,
+    command: InternalSnapshotCommand,
+) -> DiskControlResult {
+    // Hold the same locks as `resize` so no request is in flight while the image's tables are
+    // rewritten.
+    let disk_state = disk_state.lock().await;
+    let worker_shared_state = Arc::clone(&disk_state.worker_shared_state);
+    let _worker_shared_state = worker_shared_state.lock().await;
+
+    if disk_state.read_only && !matches!(command, InternalSnapshotCommand::List) {
+        error!("Attempted to modify snapshots of read-only block device");
+        return DiskControlResult::Err(SysError::new(libc::EROFS));
+    }
+
+    let Some(disk_image) = &disk_state.disk_image else {
+        error!("Attempted to manage snapshots of block device without media");
+        return DiskControlResult::Err(SysError::new(libc::ENOMEDIUM));
+    };
+
+    if disk_state.job_active() && !matches!(command, InternalSnapshotCommand::List) {
+        error!("Attempted to modify snapshots of block device while a block job is running");
+        return DiskControlResult::Err(SysError::new(libc::EBUSY));
+    }
+
+    info!("Block device snapshot command: {}", command);
+
+    let result = match &command {
+        InternalSnapshotCommand::Create { name } => disk_image
+            .create_internal_snapshot(name)
+            .await
+            .map(|_| DiskControlResult::Ok),
+        InternalSnapshotCommand::List => disk_image
+            .internal_snapshots()
+            .await
+            .map(DiskControlResult::InternalSnapshots),
+        InternalSnapshotCommand::Apply { snapshot } => disk_image
+            .apply_internal_snapshot(snapshot)
+            .await
+            .map(|_| DiskControlResult::Ok),
+        InternalSnapshotCommand::Delete { snapshot } => disk_image
+            .delete_internal_snapshot(snapshot)
+            .await
+            .map(|_| DiskControlResult::Ok),
+    };
+    result.unwrap_or_else(|e| {
+        error!("Block device snapshot command {} failed: {:#}", command, e);
+        let errno = match e {
+            disk::Error::InternalSnapshot(e) => e.raw_os_error().unwrap_or(libc::EIO),
+            disk::Error::UnsupportedOperation => libc::ENOTSUP,
+            _ => libc::EIO,
+        };
+        DiskControlResult::Err(SysError::new(errno))
+    })
+}
+
+/// Ejects the current media if there is any and inserts `new_media`. Inserting media into a disk
+/// that has media fails unless `replace` is set.
+async fn change_media(
+    ex: &Executor,
+    disk_state: &AsyncRwLock<DiskState>,
+    new_media: Option<DiskMedia>,
+    replace: bool,
+) -> DiskControlResult {
+    // Every request holds a shared lock on the state until it completes, so once we have exclusive
+    // access nothing is in flight on the old media.
+    let mut disk_state = disk_state.lock().await;
+    let worker_shared_state = Arc::clone(&disk_state.worker_shared_state);
+    let worker_shared_state = worker_shared_state.lock().await;
+
+    // The other workers have their own clone of the disk image that can't be swapped from here.
+    if disk_state.disk_option.multiple_workers {
+        error!("Attempted to change the media of a block device with multiple workers");
+        return DiskControlResult::Err(SysError::new(libc::ENOTSUP));
+    }
+
+    if disk_state.job_active() {
+        error!("Attempted to change the media of a block device while a block job is running");
+        return DiskControlResult::Err(SysError::new(libc::EBUSY));
+    }
+
+    if new_media.is_some() && !replace && disk_state.disk_image.is_some() {
+        error!("Attempted to insert media into a block device that has media");
+        return DiskControlResult::Err(SysError::new(libc::EBUSY));
+    }
+
+    let new_image = match new_media {
+        Some(DiskMedia { path, file }) => {
+            info!("Inserting {} into block device", path.display());
+            let image = disk_state
+                .disk_option
+                .open_media(path, file, disk_state.read_only)
+                .and_then(|disk_file| {
+                    disk_file
+                        .to_async_disk(ex)
+                        .context("failed to create async disk")
+                });
+            match image {
+                Ok(image) => Some(image),
+                Err(e) => {
+                    error!("Failed to open new media: {:#}", e);
+                    return DiskControlResult::Err(SysError::new(libc::EINVAL));
+                }
+            }
+        }
+        None => {
+            info!("Ejecting media from block device");
+            None
+        }
+    };
+    let new_disk_size = match &new_image {
+        Some(image) => match image.get_len() {
+            Ok(size) => size,
+            Err(e) => {
+                error!("Failed to get the size of new media: {:#}", e);
+                return DiskControlResult::Err(SysError::new(libc::EIO));
+            }
+        },
+        None => 0,
+    };
+    let block_size = u64::from(disk_state.disk_option.block_size);
+    if new_disk_size % block_size != 0 {
+        warn!(
+            "Disk size {} is not a multiple of block size {}; \
+             the remainder will not be visible to the guest.",
+            new_disk_size, block_size,
+        );
+    }
+
+    // Write out any state the old media has buffered before dropping it.
+    if let Some(old_image) = &disk_state.disk_image {
+        if let Err(e) = old_image.flush().await {
+            error!("Failed to flush the old media: {:#}", e);
+            return DiskControlResult::Err(SysError::new(libc::EIO));
+        }
+        if let Err(e) = old_image.fsync().await {
+            error!("Failed to fsync the old media: {:#}", e);
+            return DiskControlResult::Err(SysError::new(libc::EIO));
+        }
+    }
+
+    disk_state.disk_image = new_image;
+    worker_shared_state
+        .disk_size
+        .store(new_disk_size, Ordering::Release);
+    DiskControlResult::Ok
+}
+
+async fn block_job(
+    ex: &Executor,
+    disk_state: &Rc<AsyncRwLock<DiskState>>,
+    command: BlockJobCommand,
+) -> DiskControlResult {
+    match command {
+        BlockJobCommand::Mirror(target) => {
+            start_block_job(ex, disk_state, BlockJobKind::Mirror, target).await
+        }
+        BlockJobCommand::Backup(target) => {
+            start_block_job(ex, disk_state, BlockJobKind::Backup, target).await
+        }
+        BlockJobCommand::Status => match &disk_state.read_lock().await.job {
+            Some(job) => DiskControlResult::BlockJobStatus(job.status()),
+            None => DiskControlResult::Err(SysError::new(libc::ENOENT)),
+        },
+        BlockJobCommand::Complete => complete_block_job(disk_state).await,
+        BlockJobCommand::Cancel => {
+            let mut disk_state = disk_state.lock().await;
+            match &mut disk_state.job {
+                Some(job) if job.is_active() => {
+                    info!("Cancelling block job");
+                    job.set_state(BlockJobState::Cancelled);
+                    job.take_target();
+                    DiskControlResult::Ok
+                }
+                _ => DiskControlResult::Err(SysError::new(libc::ENOENT)),
+            }
+        }
+    }
+}
+
+async fn start_block_job(
+    ex: &Executor,
+    disk_state: &Rc<AsyncRwLock<DiskState>>,
+    kind: BlockJobKind,
+    target: DiskMedia,
+) -> DiskControlResult {
+    let mut state = disk_state.lock().await;
+
+    // Writes handled by other workers would not be tracked.
+    if state.disk_option.multiple_workers {
+        error!("Attempted to start a block job on a block device with multiple workers");
+        return DiskControlResult::Err(SysError::new(libc::ENOTSUP));
+    }
+    let Some(disk_image) = &state.disk_image else {
+        error!("Attempted to start a block job on a block device without media");
+        return DiskControlResult::Err(SysError::new(libc::ENOMEDIUM));
+    };
+    if state.job_active() {
+        error!("Attempted to start a block job while another one is running");
+        return DiskControlResult::Err(SysError::new(libc::EBUSY));
+    }
+
+    info!("Starting {:?} block job to {}", kind, target.path.display());
+    let disk_size = match disk_image.get_len() {
+        Ok(size) => size,
+        Err(e) => {
+            error!("Failed to get the size of the disk: {:#}", e);
+            return DiskControlResult::Err(SysError::new(libc::EIO));
+        }
+    };
+    let target = state
+        .disk_option
+        .open_media(target.path, target.file, false)
+        .and_then(|disk_file| {
+            disk_file
+                .to_async_disk(ex)
+                .context("failed to create async disk")
+        })
+        .and_then(|target| {
+            // Grow the target if needed, so it can hold the entire disk.
+            if target.get_len()? < disk_size {
+                target.set_len(disk_size)?;
+            }
+            Ok(target)
+        });
+    let target = match target {
+        Ok(target) => target,
+        Err(e) => {
+            error!("Failed to open block job target: {:#}", e);
+            return DiskControlResult::Err(SysError::new(libc::EINVAL));
+        }
+    };
+
+    let id = state.job.as_ref().map_or(0, |job| job.id() + 1);
+    state.job = Some(BlockJob::new(id, kind, target, disk_size));
+    ex.spawn_local(run_block_job(ex.clone(), Rc::clone(disk_state), id))
+        .detach();
+    DiskControlResult::Ok
+}
+
+/// Copies the disk to the target of the job `id` until the job stops being active.
+async fn run_block_job(ex: Executor, disk_state: Rc<AsyncRwLock<DiskState>>, id: u64) {
+    // How long a mirror that is in sync waits before checking for new writes.
+    const MIRROR_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+    loop {
+        let kind = {
+            let state = disk_state.read_lock().await;
+            let (Some(job), Some(disk_image)) = (&state.job, &state.disk_image) else {
+                return;
+            };
+            if job.id() != id || !job.is_active() {
+                return;
+            }
+            match job.copy_next(&**disk_image).await {
+                Ok(true) => continue,
+                Ok(false) => {}
+                Err(e) => {
+                    error!("Block job failed: {:#}", e);
+                    job.set_state(BlockJobState::Failed);
+                    return;
+                }
+            }
+            if job.kind() == BlockJobKind::Mirror && job.state() == BlockJobState::Running {
+                info!("Block job mirror is ready");
+                job.set_state(BlockJobState::Ready);
+            }
+            job.kind()
+        };
+
+        match kind {
+            BlockJobKind::Mirror => {
+                if let Err(e) = TimerAsync::sleep(&ex, MIRROR_POLL_INTERVAL).await {
+                    error!("Block job failed to wait for writes: {:#}", e);
+                    return;
+                }
+            }
+            BlockJobKind::Backup => {
+                let mut state = disk_state.lock().await;
+                let Some(job) = state
+                    .job
+                    .as_mut()
+                    .filter(|job| job.id() == id && job.is_active())
+                else {
+                    return;
+                };
+                let synced = match job.target() {
+                    Some(target) => target.fsync().await,
+                    None => Ok(()),
+                };
+                match synced {
+                    Ok(()) => {
+                        info!("Block job finished the backup");
+                        job.set_state(BlockJobState::Completed);
+                    }
+                    Err(e) => {
+                        error!("Block job failed to sync the target: {:#}", e);
+                        job.set_state(BlockJobState::Failed);
+                    }
+                }
+                job.take_target();
+                return;
+            }
+        }
+    }
+}
+
+/// Switches the disk over to the target of a mirror job that is ready.
+async fn complete_block_job(disk_state: &AsyncRwLock<DiskState>) -> DiskControlResult {
+    // No requests are in flight while we have exclusive access, so the disk can't change while
+    // the last dirty chunks are copied.
+    let mut disk_state = disk_state.lock().await;
+    let worker_shared_state = Arc::clone(&disk_state.worker_shared_state);
+    let worker_shared_state = worker_shared_state.lock().await;
+
+    let state = &mut *disk_state;
+    let (Some(job), Some(disk_image)) = (&mut state.job, &state.disk_image) else {
+        return DiskControlResult::Err(SysError::new(libc::ENOENT));
+    };
+    match (job.kind(), job.state()) {
+        (BlockJobKind::Mirror, BlockJobState::Ready) => {}
+        (_, BlockJobState::Running) => return DiskControlResult::Err(SysError::new(libc::EBUSY)),
+        _ => return DiskControlResult::Err(SysError::new(libc::EINVAL)),
+    }
+
+    info!("Completing block job");
+    let result = async {
+        while job.copy_next(&**disk_image).await? {}
+        if let Some(target) = job.target() {
+            target.fsync().await?;
+        }
+        disk_image.flush().await
+    }
+    .await;
+    if let Err(e) = result {
+        error!("Failed to complete block job: {:#}", e);
+        job.set_state(BlockJobState::Failed);
+        job.take_target();
+        return DiskControlResult::Err(SysError::new(libc::EIO));
+    }
+
+    job.set_state(BlockJobState::Completed);
+    let target = job.take_target();
+    let new_disk_size = target.as_ref().and_then(|target| target.get_len().ok());
+    state.disk_image = target;
+    if let Some(new_disk_size) = new_disk_size {
         worker_shared_state
             .disk_size
             .store(new_disk_size, Ordering::Release);
@@ -431,13 +897,12 @@ async fn flush_disk(
         // call fdatasync will be committed eventually.
         *armed.borrow_mut() = false;
 
-        disk_state
-            .read_lock()
-            .await
-            .disk_image
-            .fdatasync()
-            .await
-            .map_err(ControlError::FdatasyncDisk)?;
+        if let Some(disk_image) = &disk_state.read_lock().await.disk_image {
+            disk_image
+                .fdatasync()
+                .await
+                .map_err(ControlError::FdatasyncDisk)?;
+        }
     }
 }
 
@@ -479,7 +944,8 @@ async fn run_worker(
 
     // Handles control requests.
     let control_interrupt = RefCell::new(None);
-    let control = handle_command_tube(control_tube, &control_interrupt, disk_state.clone()).fuse();
+    let control =
+        handle_command_tube(ex, control_tube, &control_interrupt, disk_state.clone()).fuse();
     pin_mut!(control);
 
     // Handle all the queues in one sub-select call.
@@ -523,6 +989,7 @@ async fn run_worker(
                         let (tx, rx) = oneshot::channel();
                         let kick_evt = queue.event().try_clone().expect("Failed to clone queue event");
                         let (handle_queue_future, remote_handle) = handle_queue(
+                            ex.clone(),
                             Rc::clone(disk_state),
                             queue,
                             EventAsync::new(kick_evt, ex).expect("Failed to create async event for queue"),
@@ -634,6 +1101,8 @@ pub struct BlockAsync {
     #[cfg(windows)]
     pub(super) io_concurrency: u32,
     pci_address: Option<PciAddress>,
+    // Used to open media inserted while the device is running.
+    disk_option: DiskOption,
 }
 
 impl BlockAsync {
@@ -698,6 +1167,7 @@ impl BlockAsync {
         let disk_size = Arc::new(AtomicU64::new(disk_size));
         let shared_state = Arc::new(AsyncRwLock::new(WorkerSharedState {
             disk_size: disk_size.clone(),
+            throttle: Mutex::new(Throttle::new(disk_option.throttle)),
         }));
 
         Ok(BlockAsync {
@@ -720,6 +1190,10 @@ impl BlockAsync {
             #[cfg(windows)]
             io_concurrency,
             pci_address: disk_option.pci_address,
+            disk_option: DiskOption {
+                multiple_workers: worker_per_queue,
+                ..disk_option.clone()
+            },
         })
     }
 
@@ -757,6 +1231,7 @@ impl BlockAsync {
     // It is up to the caller to convert the result of this function into a status byte
     // and write it to the expected location in guest memory.
     async fn execute_request(
+        ex: &Executor,
         reader: &mut Reader,
         writer: &mut Writer,
         disk_state: &AsyncRwLock<DiskState>,
@@ -779,6 +1254,22 @@ impl BlockAsync {
             });
         }
 
+        let throttled = match req_type {
+            VIRTIO_BLK_T_IN => Some((false, writer.available_bytes())),
+            VIRTIO_BLK_T_OUT => Some((true, reader.available_bytes())),
+            // Count discard and write zeroes requests as writes that don't transfer data.
+            VIRTIO_BLK_T_DISCARD | VIRTIO_BLK_T_WRITE_ZEROES => Some((true, 0)),
+            _ => None,
+        };
+        if let Some((write, len)) = throttled {
+            let delay = worker_shared_state.throttle.lock().delay(write, len as u64);
+            if !delay.is_zero() {
+                TimerAsync::sleep(ex, delay)
+                    .await
+                    .map_err(ExecuteError::Throttle)?;
+            }
+        }
+
         /// Check that a request accesses only data within the disk's current size.
         /// All parameters are in units of bytes.
         fn check_range(
@@ -807,9 +1298,8 @@ impl BlockAsync {
                     .checked_shl(u32::from(SECTOR_SHIFT))
                     .ok_or(ExecuteError::OutOfRange)?;
                 check_range(offset, data_len as u64, disk_size)?;
-                let disk_image = &disk_state.disk_image;
                 writer
-                    .write_all_from_at_fut(&**disk_image, data_len, offset)
+                    .write_all_from_at_fut(disk_state.media()?, data_len, offset)
                     .await
                     .map_err(|desc_error| ExecuteError::ReadIo {
                         length: data_len,
@@ -826,9 +1316,13 @@ impl BlockAsync {
                     .checked_shl(u32::from(SECTOR_SHIFT))
                     .ok_or(ExecuteError::OutOfRange)?;
                 check_range(offset, data_len as u64, disk_size)?;
-                let disk_image = &disk_state.disk_image;
-                reader
-                    .read_exact_to_at_fut(&**disk_image, data_len, offset)
+                let disk_image = disk_state.media()?;
+                disk_state
+                    .job_write(disk_image, offset, data_len as u64, async {
+                        reader
+                            .read_exact_to_at_fut(disk_image, data_len, offset)
+                            .await
+                    })
                     .await
                     .map_err(|desc_error| ExecuteError::WriteIo {
                         length: data_len,
@@ -883,14 +1377,20 @@ impl BlockAsync {
                         .ok_or(ExecuteError::OutOfRange)?;
                     check_range(offset, length, disk_size)?;
 
+                    let disk_image = disk_state.media()?;
                     if req_type == VIRTIO_BLK_T_DISCARD {
                         // Since Discard is just a hint and some filesystems may not implement
                         // FALLOC_FL_PUNCH_HOLE, ignore punch_hole errors.
-                        let _ = disk_state.disk_image.punch_hole(offset, length).await;
+                        let _ = disk_state
+                            .job_write(disk_image, offset, length, async {
+                                disk_image.punch_hole(offset, length).await
+                            })
+                            .await;
                     } else {
                         disk_state
-                            .disk_image
-                            .write_zeroes_at(offset, length)
+                            .job_write(disk_image, offset, length, async {
+                                disk_image.write_zeroes_at(offset, length).await
+                            })
                             .await
                             .map_err(|e| ExecuteError::DiscardWriteZeroes {
                                 ioerr: Some(e),
@@ -903,7 +1403,7 @@ impl BlockAsync {
             }
             VIRTIO_BLK_T_FLUSH => {
                 disk_state
-                    .disk_image
+                    .media()?
                     .fdatasync()
                     .await
                     .map_err(ExecuteError::Flush)?;
@@ -979,6 +1479,7 @@ impl BlockAsync {
         let read_only = self.read_only;
         let sparse = self.sparse;
         let id = self.id;
+        let disk_option = self.disk_option.clone();
         let worker_shared_state = self.shared_state.clone();
 
         let (worker_tx, worker_rx) = mpsc::unbounded();
@@ -992,10 +1493,12 @@ impl BlockAsync {
             };
 
             let disk_state = Rc::new(AsyncRwLock::new(DiskState {
-                disk_image: async_image,
+                disk_image: Some(async_image),
                 read_only,
                 sparse,
                 id,
+                disk_option,
+                job: None,
                 worker_shared_state,
             }));
 
@@ -1003,8 +1506,10 @@ impl BlockAsync {
                 .run_until(async {
                     let r = run_worker(&ex, &disk_state, &async_control, worker_rx, kill_evt).await;
                     // Flush any in-memory disk image state to file.
-                    if let Err(e) = disk_state.lock().await.disk_image.flush().await {
-                        error!("failed to flush disk image when stopping worker: {e:?}");
+                    if let Some(disk_image) = &disk_state.lock().await.disk_image {
+                        if let Err(e) = disk_image.flush().await {
+                            error!("failed to flush disk image when stopping worker: {e:?}");
+                        }
                     }
                     r
                 })
@@ -1172,6 +1677,9 @@ impl VirtioDevice for BlockAsync {
 #[cfg(test)]
 mod tests {
     use std::fs::File;
+    use std::io::Read;
+    use std::io::Seek;
+    use std::io::SeekFrom;
     use std::mem::size_of_val;
     use std::sync::atomic::AtomicU64;
 
@@ -1373,16 +1881,20 @@ mod tests {
         let flush_timer_armed = Rc::new(RefCell::new(false));
 
         let disk_state = Rc::new(AsyncRwLock::new(DiskState {
-            disk_image: Box::new(af),
+            disk_image: Some(Box::new(af)),
             read_only: false,
             sparse: true,
             id: None,
+            disk_option: DiskOption::default(),
+            job: None,
             worker_shared_state: Arc::new(AsyncRwLock::new(WorkerSharedState {
                 disk_size: Arc::new(AtomicU64::new(disk_size)),
+                throttle: Mutex::new(Throttle::new(DiskThrottle::default())),
             })),
         }));
 
         let fut = process_one_request(
+            &ex,
             &mut avail_desc,
             &disk_state,
             &flush_timer,
@@ -1441,16 +1953,20 @@ mod tests {
         ));
         let flush_timer_armed = Rc::new(RefCell::new(false));
         let disk_state = Rc::new(AsyncRwLock::new(DiskState {
-            disk_image: Box::new(af),
+            disk_image: Some(Box::new(af)),
             read_only: false,
             sparse: true,
             id: None,
+            disk_option: DiskOption::default(),
+            job: None,
             worker_shared_state: Arc::new(AsyncRwLock::new(WorkerSharedState {
                 disk_size: Arc::new(AtomicU64::new(disk_size)),
+                throttle: Mutex::new(Throttle::new(DiskThrottle::default())),
             })),
         }));
 
         let fut = process_one_request(
+            &ex,
             &mut avail_desc,
             &disk_state,
             &flush_timer,
@@ -1511,16 +2027,20 @@ mod tests {
         let id = b"a20-byteserialnumber";
 
         let disk_state = Rc::new(AsyncRwLock::new(DiskState {
-            disk_image: Box::new(af),
+            disk_image: Some(Box::new(af)),
             read_only: false,
             sparse: true,
             id: Some(*id),
+            disk_option: DiskOption::default(),
+            job: None,
             worker_shared_state: Arc::new(AsyncRwLock::new(WorkerSharedState {
                 disk_size: Arc::new(AtomicU64::new(disk_size)),
+                throttle: Mutex::new(Throttle::new(DiskThrottle::default())),
             })),
         }));
 
         let fut = process_one_request(
+            &ex,
             &mut avail_desc,
             &disk_state,
             &flush_timer,
@@ -1793,6 +2313,181 @@ mod tests {
         );
     }
 
+    #[test]
+    fn change_media() {
+        let f = tempfile().unwrap();
+        f.set_len(0x1000).unwrap();
+
+        let mem = GuestMemory::new(&[(GuestAddress(0u64), 4 * 1024 * 1024)])
+            .expect("Creating guest memory failed.");
+        let (control_tube, control_tube_device) = Tube::pair().unwrap();
+
+        let features = base_features(ProtectionType::Unprotected);
+        let disk_option = DiskOption::default();
+        let mut b = BlockAsync::new(
+            features,
+            Box::new(f),
+            &disk_option,
+            Some(control_tube_device),
+            None,
+            None,
+        )
+        .unwrap();
+
+        let interrupt = Interrupt::new_for_test();
+        let mut q0 = QueueConfig::new(DEFAULT_QUEUE_SIZE, 0);
+        q0.set_ready(true);
+        let q0 = q0
+            .activate(&mem, Event::new().unwrap(), interrupt.clone())
+            .expect("QueueConfig::activate");
+        b.activate(mem, interrupt.clone(), BTreeMap::from([(0, q0)]))
+            .expect("activate should succeed");
+
+        let new_media = |size| {
+            let file = tempfile().unwrap();
+            file.set_len(size).unwrap();
+            DiskMedia {
+                path: "new_media".into(),
+                file,
+            }
+        };
+        let send = |command| {
+            control_tube.send(&command).unwrap();
+            control_tube.recv::<DiskControlResult>().unwrap()
+        };
+
+        // Media can only be inserted after the old media is ejected.
+        assert_eq!(
+            send(DiskControlCommand::Insert(new_media(0x2000))),
+            DiskControlResult::Err(SysError::new(libc::EBUSY))
+        );
+        assert_eq!(send(DiskControlCommand::Eject), DiskControlResult::Ok);
+        assert_eq!(b.disk_size.load(Ordering::Acquire), 0);
+        assert_eq!(
+            send(DiskControlCommand::Resize { new_size: 0x1000 }),
+            DiskControlResult::Err(SysError::new(libc::ENOMEDIUM))
+        );
+        assert_eq!(
+            send(DiskControlCommand::Insert(new_media(0x2000))),
+            DiskControlResult::Ok
+        );
+        assert_eq!(b.disk_size.load(Ordering::Acquire), 0x2000);
+
+        // Replace doesn't need an eject first.
+        assert_eq!(
+            send(DiskControlCommand::Replace(new_media(0x3000))),
+            DiskControlResult::Ok
+        );
+        let mut capacity = [0u8; 8];
+        b.read_config(0, &mut capacity);
+        assert_eq!(u64::from_le_bytes(capacity), 0x3000 >> SECTOR_SHIFT);
+
+        interrupt
+            .get_interrupt_evt()
+            .wait()
+            .expect("interrupt should be signaled");
+        assert_eq!(
+            interrupt.read_interrupt_status(),
+            crate::virtio::INTERRUPT_STATUS_CONFIG_CHANGED as u8,
+            "INTERRUPT_STATUS_CONFIG_CHANGED should be signaled"
+        );
+    }
+
+    #[test]
+    fn block_jobs() {
+        let mut f = tempfile().unwrap();
+        f.set_len(0x3000).unwrap();
+        f.seek(SeekFrom::Start(0x2000)).unwrap();
+        f.write_all(&[1]).unwrap();
+
+        let mem = GuestMemory::new(&[(GuestAddress(0u64), 4 * 1024 * 1024)])
+            .expect("Creating guest memory failed.");
+        let (control_tube, control_tube_device) = Tube::pair().unwrap();
+
+        let features = base_features(ProtectionType::Unprotected);
+        let disk_option = DiskOption::default();
+        let mut b = BlockAsync::new(
+            features,
+            Box::new(f),
+            &disk_option,
+            Some(control_tube_device),
+            None,
+            None,
+        )
+        .unwrap();
+
+        let interrupt = Interrupt::new_for_test();
+        let mut q0 = QueueConfig::new(DEFAULT_QUEUE_SIZE, 0);
+        q0.set_ready(true);
+        let q0 = q0
+            .activate(&mem, Event::new().unwrap(), interrupt.clone())
+            .expect("QueueConfig::activate");
+        b.activate(mem, interrupt, BTreeMap::from([(0, q0)]))
+            .expect("activate should succeed");
+
+        let send = |command| {
+            control_tube
+                .send(&DiskControlCommand::BlockJob(command))
+                .unwrap();
+            control_tube.recv::<DiskControlResult>().unwrap()
+        };
+        let wait_for_state = |state| loop {
+            match send(BlockJobCommand::Status) {
+                DiskControlResult::BlockJobStatus(status) if status.state == state => {
+                    break status;
+                }
+                DiskControlResult::BlockJobStatus(_) => {
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+                r => panic!("unexpected response {:?}", r),
+            }
+        };
+        let target = |file: &File| DiskMedia {
+            path: "target".into(),
+            file: file.try_clone().unwrap(),
+        };
+        let read_byte = |mut file: &File, offset| {
+            let mut buf = [0u8];
+            file.seek(SeekFrom::Start(offset)).unwrap();
+            file.read_exact(&mut buf).unwrap();
+            buf[0]
+        };
+
+        assert_eq!(
+            send(BlockJobCommand::Status),
+            DiskControlResult::Err(SysError::new(libc::ENOENT))
+        );
+
+        let backup = tempfile().unwrap();
+        assert_eq!(
+            send(BlockJobCommand::Backup(target(&backup))),
+            DiskControlResult::Ok
+        );
+        let status = wait_for_state(BlockJobState::Completed);
+        assert_eq!(status.kind, BlockJobKind::Backup);
+        assert_eq!(status.total_bytes, 0x3000);
+        assert_eq!(status.remaining_bytes, 0);
+        assert_eq!(read_byte(&backup, 0x2000), 1);
+
+        let mirror = tempfile().unwrap();
+        assert_eq!(
+            send(BlockJobCommand::Mirror(target(&mirror))),
+            DiskControlResult::Ok
+        );
+        assert_eq!(
+            send(BlockJobCommand::Backup(target(&backup))),
+            DiskControlResult::Err(SysError::new(libc::EBUSY))
+        );
+        wait_for_state(BlockJobState::Ready);
+        assert_eq!(send(BlockJobCommand::Complete), DiskControlResult::Ok);
+        assert_eq!(
+            wait_for_state(BlockJobState::Completed).kind,
+            BlockJobKind::Mirror
+        );
+        assert_eq!(mirror.metadata().unwrap().len(), 0x3000);
+        assert_eq!(read_byte(&mirror, 0x2000), 1);
+    }
+
     #[test]
     fn run_worker_threads() {
         // Create an empty duplicable disk image
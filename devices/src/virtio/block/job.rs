@@ -0,0 +1,335 @@
+// Copyright 2025 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Block jobs copy the contents of a disk to a target image while the guest keeps using the disk.
+//!
+//! A mirror job tracks the chunks the guest writes to and copies them again, so the target stays in
+//! sync with the disk until the disk is switched over to it. A backup job copies the contents the
+//! disk had when the job started: before the guest overwrites a chunk that hasn't been copied yet,
+//! the old contents of the chunk are copied to the target first.
+
+use std::cell::Cell;
+use std::cell::RefCell;
+
+use base::error;
+use cros_async::sync::RwLock as AsyncRwLock;
+use disk::AsyncDisk;
+use vm_control::BlockJobKind;
+use vm_control::BlockJobState;
+use vm_control::BlockJobStatus;
+
+/// Granularity in bytes at which the job tracks and copies the disk.
+pub const CHUNK_SIZE: u64 = 1 << 20;
+
+pub struct BlockJob {
+    id: u64,
+    kind: BlockJobKind,
+    state: Cell<BlockJobState>,
+    /// `None` once the job has completed or was cancelled.
+    target: Option<Box<dyn AsyncDisk>>,
+    disk_size: u64,
+    /// Bitmap of the chunks that still have to be copied.
+    dirty: RefCell<Vec<u64>>,
+    dirty_chunks: Cell<u64>,
+    /// Where the search for the next dirty chunk starts.
+    cursor: Cell<u64>,
+    /// Held while a backup copies a chunk, so the guest can't overwrite it halfway through.
+    copy_lock: AsyncRwLock<()>,
+}
+
+impl BlockJob {
+    /// Creates a job that copies all of a disk of `disk_size` bytes to `target`.
+    pub fn new(
+        id: u64,
+        kind: BlockJobKind,
+        target: Box<dyn AsyncDisk>,
+        disk_size: u64,
+    ) -> BlockJob {
+        let chunks = disk_size.div_ceil(CHUNK_SIZE);
+        let mut dirty = vec![u64::MAX; chunks.div_ceil(64) as usize];
+        if chunks % 64 != 0 {
+            *dirty.last_mut().unwrap() = (1 << (chunks % 64)) - 1;
+        }
+        BlockJob {
+            id,
+            kind,
+            state: Cell::new(BlockJobState::Running),
+            target: Some(target),
+            disk_size,
+            dirty: RefCell::new(dirty),
+            dirty_chunks: Cell::new(chunks),
+            cursor: Cell::new(0),
+            copy_lock: AsyncRwLock::new(()),
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn kind(&self) -> BlockJobKind {
+        self.kind
+    }
+
+    pub fn state(&self) -> BlockJobState {
+        self.state.get()
+    }
+
+    pub fn set_state(&self, state: BlockJobState) {
+        self.state.set(state);
+    }
+
+    /// Returns whether the job is still copying the disk.
+    pub fn is_active(&self) -> bool {
+        matches!(
+            self.state.get(),
+            BlockJobState::Running | BlockJobState::Ready
+        )
+    }
+
+    pub fn target(&self) -> Option<&dyn AsyncDisk> {
+        self.target.as_deref()
+    }
+
+    /// Removes the target from the job, e.g. to release it once the job has finished.
+    pub fn take_target(&mut self) -> Option<Box<dyn AsyncDisk>> {
+        self.target.take()
+    }
+
+    pub fn status(&self) -> BlockJobStatus {
+        let mut remaining_bytes = self.dirty_chunks.get() * CHUNK_SIZE;
+        // The last chunk may be partial.
+        let chunks = self.disk_size.div_ceil(CHUNK_SIZE);
+        if chunks > 0 && self.is_dirty(chunks - 1) {
+            remaining_bytes -= chunks * CHUNK_SIZE - self.disk_size;
+        }
+        BlockJobStatus {
+            kind: self.kind,
+            state: self.state.get(),
+            total_bytes: self.disk_size,
+            remaining_bytes,
+        }
+    }
+
+    /// Must be called before the guest modifies `len` bytes of the disk at `offset`.
+    ///
+    /// A failure to copy the old contents fails the job rather than the guest's write.
+    pub async fn before_write(&self, disk: &dyn AsyncDisk, offset: u64, len: u64) {
+        if self.kind != BlockJobKind::Backup || !self.is_active() {
+            return;
+        }
+        let _copy_lock = self.copy_lock.lock().await;
+        for chunk in self.chunks(offset, len) {
+            if self.clear_dirty(chunk) {
+                if let Err(e) = self.copy_chunk(disk, chunk).await {
+                    error!("block job failed to back up chunk {}: {:#}", chunk, e);
+                    self.state.set(BlockJobState::Failed);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Must be called after the guest modified `len` bytes of the disk at `offset`, whether the
+    /// write succeeded or not.
+    pub fn after_write(&self, offset: u64, len: u64) {
+        if self.kind != BlockJobKind::Mirror || !self.is_active() {
+            return;
+        }
+        for chunk in self.chunks(offset, len) {
+            self.set_dirty(chunk);
+        }
+    }
+
+    /// Copies the next dirty chunk from `disk` to the target. Returns false if no chunk was dirty.
+    pub async fn copy_next(&self, disk: &dyn AsyncDisk) -> disk::Result<bool> {
+        // A mirror clears the bit before copying, so a write racing with the copy marks the chunk
+        // dirty again. A backup's copy has to finish before the guest modifies the chunk.
+        let _copy_lock = match self.kind {
+            BlockJobKind::Backup => Some(self.copy_lock.lock().await),
+            BlockJobKind::Mirror => None,
+        };
+        let Some(chunk) = self.next_dirty() else {
+            return Ok(false);
+        };
+        self.clear_dirty(chunk);
+        self.copy_chunk(disk, chunk).await?;
+        Ok(true)
+    }
+
+    fn chunks(&self, offset: u64, len: u64) -> std::ops::Range<u64> {
+        let end = offset.saturating_add(len).min(self.disk_size);
+        if len == 0 || offset >= end {
+            return 0..0;
+        }
+        offset / CHUNK_SIZE..end.div_ceil(CHUNK_SIZE)
+    }
+
+    fn next_dirty(&self) -> Option<u64> {
+        if self.dirty_chunks.get() == 0 {
+            return None;
+        }
+        let dirty = self.dirty.borrow();
+        let start = (self.cursor.get() / 64) as usize;
+        let (index, word) = dirty[start..]
+            .iter()
+            .enumerate()
+            .map(|(i, word)| (start + i, word))
+            .chain(dirty[..start].iter().enumerate())
+            .find(|(_, word)| **word != 0)?;
+        let chunk = index as u64 * 64 + u64::from(word.trailing_zeros());
+        self.cursor.set(chunk);
+        Some(chunk)
+    }
+
+    fn is_dirty(&self, chunk: u64) -> bool {
+        self.dirty.borrow()[(chunk / 64) as usize] & (1 << (chunk % 64)) != 0
+    }
+
+    fn set_dirty(&self, chunk: u64) {
+        let word = &mut self.dirty.borrow_mut()[(chunk / 64) as usize];
+        let bit = 1 << (chunk % 64);
+        if *word & bit == 0 {
+            *word |= bit;
+            self.dirty_chunks.set(self.dirty_chunks.get() + 1);
+        }
+    }
+
+    // Returns whether the chunk was dirty.
+    fn clear_dirty(&self, chunk: u64) -> bool {
+        let word = &mut self.dirty.borrow_mut()[(chunk / 64) as usize];
+        let bit = 1 << (chunk % 64);
+        if *word & bit == 0 {
+            return false;
+        }
+        *word &= !bit;
+        self.dirty_chunks.set(self.dirty_chunks.get() - 1);
+        true
+    }
+
+    async fn copy_chunk(&self, disk: &dyn AsyncDisk, chunk: u64) -> disk::Result<()> {
+        let Some(target) = self.target.as_deref() else {
+            return Ok(());
+        };
+        let offset = chunk * CHUNK_SIZE;
+        let mut buf = vec![0u8; CHUNK_SIZE.min(self.disk_size - offset) as usize];
+        let mut done = 0;
+        while done < buf.len() {
+            let n = disk
+                .read_double_buffered(offset + done as u64, &mut buf[done..])
+                .await?;
+            if n == 0 {
+                // Reading past the end of the disk; the rest of the chunk stays zero.
+                break;
+            }
+            done += n;
+        }
+        if buf.iter().all(|b| *b == 0) {
+            return target.write_zeroes_at(offset, buf.len() as u64).await;
+        }
+        let mut done = 0;
+        while done < buf.len() {
+            done += target
+                .write_double_buffered(offset + done as u64, &buf[done..])
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cros_async::Executor;
+    use disk::SingleFileDisk;
+    use tempfile::tempfile;
+
+    use super::*;
+
+    fn new_disk(ex: &Executor, len: u64) -> Box<dyn AsyncDisk> {
+        let f = tempfile().unwrap();
+        f.set_len(len).unwrap();
+        Box::new(SingleFileDisk::new(f, ex).unwrap())
+    }
+
+    async fn read(disk: &dyn AsyncDisk, offset: u64) -> u8 {
+        let mut buf = [0u8];
+        disk.read_double_buffered(offset, &mut buf).await.unwrap();
+        buf[0]
+    }
+
+    #[test]
+    fn mirror_recopies_written_chunks() {
+        let ex = Executor::new().unwrap();
+        ex.run_until(async {
+            let disk_size = 2 * CHUNK_SIZE + 512;
+            let disk = new_disk(&ex, disk_size);
+            disk.write_double_buffered(CHUNK_SIZE, &[1]).await.unwrap();
+            let job = BlockJob::new(0, BlockJobKind::Mirror, new_disk(&ex, disk_size), disk_size);
+            assert_eq!(job.status().remaining_bytes, disk_size);
+
+            while job.copy_next(&*disk).await.unwrap() {}
+            assert_eq!(job.status().remaining_bytes, 0);
+            assert_eq!(read(job.target().unwrap(), CHUNK_SIZE).await, 1);
+
+            disk.write_double_buffered(2 * CHUNK_SIZE, &[2])
+                .await
+                .unwrap();
+            job.after_write(2 * CHUNK_SIZE, 1);
+            assert_eq!(job.status().remaining_bytes, 512);
+            assert!(job.copy_next(&*disk).await.unwrap());
+            assert!(!job.copy_next(&*disk).await.unwrap());
+            assert_eq!(read(job.target().unwrap(), 2 * CHUNK_SIZE).await, 2);
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn backup_copies_old_contents() {
+        let ex = Executor::new().unwrap();
+        ex.run_until(async {
+            let disk_size = 3 * CHUNK_SIZE;
+            let disk = new_disk(&ex, disk_size);
+            disk.write_double_buffered(CHUNK_SIZE, &[1]).await.unwrap();
+            let job = BlockJob::new(0, BlockJobKind::Backup, new_disk(&ex, disk_size), disk_size);
+
+            // The guest overwrites a chunk that wasn't copied yet.
+            job.before_write(&*disk, CHUNK_SIZE, 1).await;
+            disk.write_double_buffered(CHUNK_SIZE, &[2]).await.unwrap();
+            job.after_write(CHUNK_SIZE, 1);
+            assert_eq!(job.status().remaining_bytes, 2 * CHUNK_SIZE);
+
+            while job.copy_next(&*disk).await.unwrap() {}
+            assert_eq!(read(job.target().unwrap(), CHUNK_SIZE).await, 1);
+
+            // Writes after the chunk was copied don't reach the target.
+            job.before_write(&*disk, 0, 1).await;
+            disk.write_double_buffered(0, &[3]).await.unwrap();
+            assert_eq!(read(job.target().unwrap(), 0).await, 0);
+            assert_eq!(job.status().remaining_bytes, 0);
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn dirty_bitmap() {
+        let ex = Executor::new().unwrap();
+        let disk_size = 130 * CHUNK_SIZE;
+        let job = BlockJob::new(0, BlockJobKind::Mirror, new_disk(&ex, 0), disk_size);
+        for chunk in 0..130 {
+            assert!(job.clear_dirty(chunk));
+        }
+        assert_eq!(job.next_dirty(), None);
+
+        job.after_write(129 * CHUNK_SIZE + 1, CHUNK_SIZE);
+        job.after_write(3 * CHUNK_SIZE - 1, 2);
+        assert_eq!(job.dirty_chunks.get(), 3);
+        assert_eq!(job.next_dirty(), Some(2));
+        assert!(job.clear_dirty(2));
+        assert_eq!(job.next_dirty(), Some(3));
+        assert!(job.clear_dirty(3));
+        assert_eq!(job.next_dirty(), Some(129));
+        assert!(job.clear_dirty(129));
+        assert_eq!(job.next_dirty(), None);
+    }
+}
@@ -6,16 +6,22 @@
 use std::num::NonZeroU32;
 use std::path::PathBuf;
 
+use anyhow::bail;
+use anyhow::Context;
 use cros_async::ExecutorKind;
+use disk::DiskFile;
 use serde::Deserialize;
 use serde::Deserializer;
 use serde::Serialize;
 use serde::Serializer;
+use vm_control::DiskThrottle;
 
 use crate::PciAddress;
 
 pub mod asynchronous;
+mod job;
 pub(crate) mod sys;
+mod throttle;
 
 pub use asynchronous::BlockAsync;
 
@@ -137,6 +143,21 @@ pub struct DiskOption {
 
     /// Specify PCI address will be used to attach this device
     pub pci_address: Option<PciAddress>,
+
+    #[serde(default)]
+    /// Limits on the rate of guest requests, e.g. `throttle=[read-iops=1000,write-bps=10485760]`.
+    /// Can be changed at runtime with `crosvm disk throttle`.
+    pub throttle: DiskThrottle,
+
+    /// Path of a qcow2 image that receives the guest's writes instead of the image at `path`,
+    /// which is only read. It is created with the image as its backing file if it doesn't
+    /// exist.
+    pub overlay: Option<PathBuf>,
+
+    #[serde(default)]
+    /// Discard the guest's writes when the VM exits. Writes go to a temporary qcow2 overlay over
+    /// the image at `path`, which is only read.
+    pub ephemeral: bool,
 }
 
 impl Default for DiskOption {
@@ -157,10 +178,28 @@ impl Default for DiskOption {
             packed_queue: false,
             bootindex: None,
             pci_address: None,
+            throttle: DiskThrottle::default(),
+            overlay: None,
+            ephemeral: false,
         }
     }
 }
 
+impl DiskOption {
+    // Opens the image, below an overlay if the guest's writes shouldn't reach it.
+    fn open_with_params(&self, params: disk::DiskFileParams) -> anyhow::Result<Box<dyn DiskFile>> {
+        Ok(match (&self.overlay, self.ephemeral) {
+            (None, false) => disk::open_disk_file(params)?,
+            (Some(overlay), false) => disk::open_disk_file_with_overlay(params, overlay)?,
+            (None, true) => {
+                let overlay = tempfile::tempfile().context("failed to create temporary overlay")?;
+                disk::open_disk_file_with_temporary_overlay(params, overlay)?
+            }
+            (Some(_), true) => bail!("`overlay` can't be used with `ephemeral`"),
+        })
+    }
+}
+
 #[cfg(test)]
 mod tests {
     #[cfg(any(target_os = "android", target_os = "linux"))]
@@ -217,6 +256,9 @@ mod tests {
                 packed_queue: false,
                 bootindex: None,
                 pci_address: None,
+                throttle: DiskThrottle::default(),
+                overlay: None,
+                ephemeral: false,
             }
         );
 
@@ -240,6 +282,9 @@ mod tests {
                 packed_queue: false,
                 bootindex: Some(5),
                 pci_address: None,
+                throttle: DiskThrottle::default(),
+                overlay: None,
+                ephemeral: false,
             }
         );
 
@@ -263,6 +308,9 @@ mod tests {
                 packed_queue: false,
                 bootindex: None,
                 pci_address: None,
+                throttle: DiskThrottle::default(),
+                overlay: None,
+                ephemeral: false,
             }
         );
 
@@ -286,6 +334,9 @@ mod tests {
                 packed_queue: false,
                 bootindex: None,
                 pci_address: None,
+                throttle: DiskThrottle::default(),
+                overlay: None,
+                ephemeral: false,
             }
         );
 
@@ -309,6 +360,9 @@ mod tests {
                 packed_queue: false,
                 bootindex: None,
                 pci_address: None,
+                throttle: DiskThrottle::default(),
+                overlay: None,
+                ephemeral: false,
             }
         );
 
@@ -332,6 +386,9 @@ mod tests {
                 packed_queue: false,
                 bootindex: None,
                 pci_address: None,
+                throttle: DiskThrottle::default(),
+                overlay: None,
+                ephemeral: false,
             }
         );
         let params = from_block_arg("/some/path.img,sparse=false").unwrap();
@@ -353,6 +410,9 @@ mod tests {
                 packed_queue: false,
                 bootindex: None,
                 pci_address: None,
+                throttle: DiskThrottle::default(),
+                overlay: None,
+                ephemeral: false,
             }
         );
 
@@ -376,6 +436,9 @@ mod tests {
                 packed_queue: false,
                 bootindex: None,
                 pci_address: None,
+                throttle: DiskThrottle::default(),
+                overlay: None,
+                ephemeral: false,
             }
         );
 
@@ -399,6 +462,9 @@ mod tests {
                 packed_queue: false,
                 bootindex: None,
                 pci_address: None,
+                throttle: DiskThrottle::default(),
+                overlay: None,
+                ephemeral: false,
             }
         );
 
@@ -422,6 +488,9 @@ mod tests {
                 packed_queue: false,
                 bootindex: None,
                 pci_address: None,
+                throttle: DiskThrottle::default(),
+                overlay: None,
+                ephemeral: false,
             }
         );
 
@@ -445,6 +514,9 @@ mod tests {
                 packed_queue: false,
                 bootindex: None,
                 pci_address: None,
+                throttle: DiskThrottle::default(),
+                overlay: None,
+                ephemeral: false,
             }
         );
 
@@ -469,6 +541,9 @@ mod tests {
                     packed_queue: false,
                     bootindex: None,
                     pci_address: None,
+                    throttle: DiskThrottle::default(),
+                    overlay: None,
+                    ephemeral: false,
                 }
             );
             let params = from_block_arg("/some/path.img,async-executor=overlapped").unwrap();
@@ -489,6 +564,9 @@ mod tests {
                     packed_queue: false,
                     bootindex: None,
                     pci_address: None,
+                    throttle: DiskThrottle::default(),
+                    overlay: None,
+                    ephemeral: false,
                 }
             );
             let params =
@@ -516,6 +594,9 @@ mod tests {
                     packed_queue: false,
                     bootindex: None,
                     pci_address: None,
+                    throttle: DiskThrottle::default(),
+                    overlay: None,
+                    ephemeral: false,
                 }
             );
         }
@@ -540,6 +621,9 @@ mod tests {
                 packed_queue: false,
                 bootindex: None,
                 pci_address: None,
+                throttle: DiskThrottle::default(),
+                overlay: None,
+                ephemeral: false,
             }
         );
         let err = from_block_arg("/some/path.img,id=DISK_ID_IS_WAY_TOO_LONG").unwrap_err();
@@ -576,6 +660,9 @@ mod tests {
                 packed_queue: false,
                 bootindex: None,
                 pci_address: None,
+                throttle: DiskThrottle::default(),
+                overlay: None,
+                ephemeral: false,
             }
         );
 
@@ -599,6 +686,9 @@ mod tests {
                 packed_queue: true,
                 bootindex: None,
                 pci_address: None,
+                throttle: DiskThrottle::default(),
+                overlay: None,
+                ephemeral: false,
             }
         );
 
@@ -626,6 +716,9 @@ mod tests {
                     dev: 1,
                     func: 1,
                 }),
+                throttle: DiskThrottle::default(),
+                overlay: None,
+                ephemeral: false,
             }
         );
 
@@ -649,6 +742,9 @@ mod tests {
                 packed_queue: false,
                 bootindex: None,
                 pci_address: None,
+                throttle: DiskThrottle::default(),
+                overlay: None,
+                ephemeral: false,
             }
         );
         // lock=false
@@ -671,9 +767,39 @@ mod tests {
                 packed_queue: false,
                 bootindex: None,
                 pci_address: None,
+                throttle: DiskThrottle::default(),
+                overlay: None,
+                ephemeral: false,
             }
         );
 
+        // throttle
+        let params =
+            from_block_arg("/path/to/disk.img,throttle=[read-iops=1000,write-bps=1048576]")
+                .unwrap();
+        assert_eq!(
+            params.throttle,
+            DiskThrottle {
+                read_iops: Some(1000),
+                write_iops: None,
+                read_bps: None,
+                write_bps: Some(1048576),
+            }
+        );
+        let err = from_block_arg("/path/to/disk.img,throttle=[iops=1000]").unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::SerdeError(_)));
+
+        // overlay
+        let params =
+            from_block_arg("/path/to/disk.img.zst,overlay=/path/to/overlay.qcow2").unwrap();
+        assert_eq!(params.overlay, Some("/path/to/overlay.qcow2".into()));
+        assert!(!params.ephemeral);
+
+        // ephemeral
+        let params = from_block_arg("/path/to/disk.img.zst,ephemeral").unwrap();
+        assert_eq!(params.overlay, None);
+        assert!(params.ephemeral);
+
         // All together
         let params = from_block_arg(&format!(
             "/some/path.img,block_size=256,ro,root,sparse=false,id=DISK_LABEL\
@@ -702,6 +828,9 @@ mod tests {
                     dev: 1,
                     func: 1,
                 }),
+                throttle: DiskThrottle::default(),
+                overlay: None,
+                ephemeral: false,
             }
         );
     }
@@ -725,6 +854,9 @@ mod tests {
             packed_queue: false,
             bootindex: None,
             pci_address: None,
+            throttle: DiskThrottle::default(),
+            overlay: None,
+            ephemeral: false,
         };
         let json = serde_json::to_string(&original).unwrap();
         let deserialized = serde_json::from_str(&json).unwrap();
@@ -747,6 +879,9 @@ mod tests {
             packed_queue: false,
             bootindex: None,
             pci_address: None,
+            throttle: DiskThrottle::default(),
+            overlay: None,
+            ephemeral: false,
         };
         let json = serde_json::to_string(&original).unwrap();
         let deserialized = serde_json::from_str(&json).unwrap();
@@ -769,6 +904,9 @@ mod tests {
             packed_queue: false,
             bootindex: None,
             pci_address: None,
+            throttle: DiskThrottle::default(),
+            overlay: None,
+            ephemeral: false,
         };
         let json = serde_json::to_string(&original).unwrap();
         let deserialized = serde_json::from_str(&json).unwrap();
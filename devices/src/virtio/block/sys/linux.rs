@@ -4,6 +4,8 @@
 
 use std::cmp::max;
 use std::cmp::min;
+use std::fs::File;
+use std::path::PathBuf;
 
 use anyhow::Context;
 use base::unix::iov_max;
@@ -25,7 +27,7 @@ pub fn get_seg_max(queue_size: u16) -> u32 {
 impl DiskOption {
     /// Open the specified disk file.
     pub fn open(&self) -> anyhow::Result<Box<dyn DiskFile>> {
-        disk::open_disk_file(disk::DiskFileParams {
+        self.open_with_params(disk::DiskFileParams {
             path: self.path.clone(),
             is_read_only: self.read_only,
             is_sparse_file: self.sparse,
@@ -36,6 +38,29 @@ impl DiskOption {
         })
         .context("open_disk_file failed")
     }
+
+    /// Create a disk file for media or a block job target that was opened from `path` by the
+    /// sender of a disk command.
+    pub fn open_media(
+        &self,
+        path: PathBuf,
+        file: File,
+        read_only: bool,
+    ) -> anyhow::Result<Box<dyn DiskFile>> {
+        disk::create_disk_file(
+            file,
+            disk::DiskFileParams {
+                path,
+                is_read_only: read_only,
+                is_sparse_file: self.sparse,
+                is_overlapped: false,
+                is_direct: false,
+                lock: false,
+                depth: 0,
+            },
+        )
+        .context("create_disk_file failed")
+    }
 }
 
 impl BlockAsync {
@@ -2,6 +2,9 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std::fs::File;
+use std::path::PathBuf;
+
 use anyhow::Context;
 use base::warn;
 use cros_async::sys::windows::ExecutorKindSys;
@@ -19,7 +22,7 @@ pub fn get_seg_max(_queue_size: u16) -> u32 {
 impl DiskOption {
     /// Open the specified disk file.
     pub fn open(&self) -> anyhow::Result<Box<dyn disk::DiskFile>> {
-        Ok(disk::open_disk_file(disk::DiskFileParams {
+        self.open_with_params(disk::DiskFileParams {
             path: self.path.clone(),
             is_read_only: self.read_only,
             is_sparse_file: self.sparse,
@@ -30,7 +33,32 @@ impl DiskOption {
             is_direct: self.direct,
             lock: self.lock,
             depth: 0,
-        })?)
+        })
+    }
+
+    /// Create a disk file for media or a block job target that was opened from `path` by the
+    /// sender of a disk command.
+    pub fn open_media(
+        &self,
+        path: PathBuf,
+        file: File,
+        read_only: bool,
+    ) -> anyhow::Result<Box<dyn disk::DiskFile>> {
+        Ok(disk::create_disk_file(
+            file,
+            disk::DiskFileParams {
+                path,
+                is_read_only: read_only,
+                is_sparse_file: self.sparse,
+                is_overlapped: matches!(
+                    self.async_executor.unwrap_or_default(),
+                    ExecutorKind::SysVariants(ExecutorKindSys::Overlapped { .. })
+                ),
+                is_direct: false,
+                lock: false,
+                depth: 0,
+            },
+        )?)
     }
 }
 
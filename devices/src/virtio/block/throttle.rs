@@ -0,0 +1,112 @@
+// Copyright 2025 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Limits the rate of guest requests to a disk so a single VM can't saturate the host's storage.
+
+use std::time::Duration;
+
+use vm_control::DiskThrottle;
+
+use crate::Ratelimit;
+
+/// Rate limiters for the reads and writes of a disk, in requests and in bytes per second.
+pub struct Throttle {
+    limits: DiskThrottle,
+    read_ops: Ratelimit,
+    write_ops: Ratelimit,
+    read_bytes: Ratelimit,
+    write_bytes: Ratelimit,
+}
+
+impl Throttle {
+    pub fn new(limits: DiskThrottle) -> Throttle {
+        let mut throttle = Throttle {
+            limits: DiskThrottle::default(),
+            read_ops: Ratelimit::new(),
+            write_ops: Ratelimit::new(),
+            read_bytes: Ratelimit::new(),
+            write_bytes: Ratelimit::new(),
+        };
+        throttle.set_limits(limits);
+        throttle
+    }
+
+    pub fn limits(&self) -> DiskThrottle {
+        self.limits
+    }
+
+    /// Replaces the limits. Requests that are already being delayed are not affected.
+    pub fn set_limits(&mut self, limits: DiskThrottle) {
+        // A speed of zero disables a limiter.
+        self.read_ops
+            .ratelimit_set_speed(limits.read_iops.unwrap_or(0));
+        self.write_ops
+            .ratelimit_set_speed(limits.write_iops.unwrap_or(0));
+        self.read_bytes
+            .ratelimit_set_speed(limits.read_bps.unwrap_or(0));
+        self.write_bytes
+            .ratelimit_set_speed(limits.write_bps.unwrap_or(0));
+        self.limits = limits;
+    }
+
+    /// Accounts for a request that reads or writes `len` bytes and returns how long it has to wait
+    /// before it is submitted to the disk.
+    pub fn delay(&mut self, write: bool, len: u64) -> Duration {
+        let (ops, bytes) = if write {
+            (&mut self.write_ops, &mut self.write_bytes)
+        } else {
+            (&mut self.read_ops, &mut self.read_bytes)
+        };
+        let ops_delay = ops.ratelimit_calculate_delay(1);
+        let bytes_delay = if len > 0 {
+            bytes.ratelimit_calculate_delay(len)
+        } else {
+            0
+        };
+        Duration::from_nanos(ops_delay.max(bytes_delay))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unlimited() {
+        let mut throttle = Throttle::new(DiskThrottle::default());
+        for _ in 0..1000 {
+            assert_eq!(throttle.delay(false, 1 << 20), Duration::ZERO);
+            assert_eq!(throttle.delay(true, 1 << 20), Duration::ZERO);
+        }
+    }
+
+    #[test]
+    fn iops_limit() {
+        let mut throttle = Throttle::new(DiskThrottle {
+            write_iops: Some(10),
+            ..Default::default()
+        });
+        for _ in 0..9 {
+            assert_eq!(throttle.delay(true, 512), Duration::ZERO);
+        }
+        // The tenth write uses up the quota of the current second.
+        assert!(throttle.delay(true, 512) > Duration::ZERO);
+        // Reads are limited separately.
+        assert_eq!(throttle.delay(false, 512), Duration::ZERO);
+    }
+
+    #[test]
+    fn bandwidth_limit() {
+        let mut throttle = Throttle::new(DiskThrottle {
+            read_bps: Some(1 << 20),
+            ..Default::default()
+        });
+        assert_eq!(throttle.delay(false, 512 << 10), Duration::ZERO);
+        // Once four times the limit has been read, the next second starts three seconds later.
+        assert!(throttle.delay(false, 7 << 19) > Duration::from_secs(2));
+
+        throttle.set_limits(DiskThrottle::default());
+        assert_eq!(throttle.delay(false, 4 << 20), Duration::ZERO);
+    }
+}
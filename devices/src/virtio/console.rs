@@ -9,6 +9,7 @@ pub mod device;
 pub mod input;
 pub mod output;
 pub mod port;
+pub mod size;
 pub mod worker;
 
 mod sys;
@@ -16,7 +17,13 @@ mod sys;
 use std::collections::BTreeMap;
 
 use anyhow::Context;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use base::AsRawDescriptor;
 use base::RawDescriptor;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use base::Tube;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use base::WorkerThread;
 use hypervisor::ProtectionType;
 use snapshot::AnySnapshot;
 use vm_memory::GuestMemory;
@@ -25,6 +32,8 @@ use crate::serial::sys::InStreamType;
 use crate::virtio::console::device::ConsoleDevice;
 use crate::virtio::console::device::ConsoleSnapshot;
 use crate::virtio::console::port::ConsolePort;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use crate::virtio::run_device_control_worker;
 use crate::virtio::DeviceType;
 use crate::virtio::Interrupt;
 use crate::virtio::Queue;
@@ -38,6 +47,11 @@ pub struct Console {
     console: ConsoleDevice,
     max_queue_sizes: Vec<u16>,
     pci_address: Option<PciAddress>,
+    // Receives `ConsoleDeviceCommand`s, served by `control_worker` once the device is sandboxed.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    control_tube: Option<Tube>,
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    control_worker: Option<WorkerThread<Tube>>,
 }
 
 impl Console {
@@ -65,13 +79,34 @@ impl Console {
             console,
             max_queue_sizes,
             pci_address,
+            #[cfg(any(target_os = "android", target_os = "linux"))]
+            control_tube: None,
+            #[cfg(any(target_os = "android", target_os = "linux"))]
+            control_worker: None,
         }
     }
+
+    /// Sets the window size of port 0, e.g. to the size of the terminal it is connected to.
+    pub fn resize(&mut self, cols: u16, rows: u16) -> anyhow::Result<()> {
+        self.console.resize(0, cols, rows)
+    }
+
+    /// Sets the tube on which `ConsoleDeviceCommand`s are received.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    pub fn set_control_tube(&mut self, tube: Tube) {
+        self.control_tube = Some(tube);
+    }
 }
 
 impl VirtioDevice for Console {
     fn keep_rds(&self) -> Vec<RawDescriptor> {
-        self.console.keep_rds()
+        #[allow(unused_mut)]
+        let mut keep_rds = self.console.keep_rds();
+        #[cfg(any(target_os = "android", target_os = "linux"))]
+        if let Some(control_tube) = &self.control_tube {
+            keep_rds.push(control_tube.as_raw_descriptor());
+        }
+        keep_rds
     }
 
     fn features(&self) -> u64 {
@@ -90,8 +125,22 @@ impl VirtioDevice for Console {
         self.console.read_config(offset, data);
     }
 
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        self.console.write_config(offset, data);
+    }
+
     fn on_device_sandboxed(&mut self) {
         self.console.start_input_threads();
+
+        #[cfg(any(target_os = "android", target_os = "linux"))]
+        if let Some(tube) = self.control_tube.take() {
+            let sizes = self.console.port_sizes();
+            self.control_worker = Some(WorkerThread::start("v_console_ctrl", move |kill_evt| {
+                run_device_control_worker("console", tube, kill_evt, |command| {
+                    sys::handle_command(&sizes, command)
+                })
+            }));
+        }
     }
 
     fn activate(
@@ -223,6 +272,39 @@ mod tests {
 
     suspendable_virtio_tests!(console, create_device, 2, modify_device);
 
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    #[test]
+    fn test_size_and_emergency_write() {
+        use std::io::Read;
+        use std::io::Seek;
+
+        let output = tempfile().unwrap();
+        let mut written = output.try_clone().unwrap();
+        let mut console = Console::new(
+            hypervisor::ProtectionType::Unprotected,
+            None,
+            Some(Box::new(output)),
+            Vec::new(),
+            None,
+            None,
+        );
+
+        let size_feature = 1 << crate::virtio::device_constants::console::VIRTIO_CONSOLE_F_SIZE;
+        assert_eq!(console.features() & size_feature, 0);
+        console.resize(80, 24).unwrap();
+        assert_ne!(console.features() & size_feature, 0);
+        let mut size = [0u8; 4];
+        console.read_config(0, &mut size);
+        assert_eq!(size, [80, 0, 24, 0]);
+
+        // The device is not activated, the byte is written directly to the output.
+        console.write_config(8, &[b'!', 0, 0, 0]);
+        let mut buf = Vec::new();
+        written.rewind().unwrap();
+        written.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"!");
+    }
+
     #[test]
     fn test_inactive_sleep_resume() {
         let (_ctx, mut device) = create_device();
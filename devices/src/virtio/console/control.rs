@@ -13,14 +13,18 @@ use base::debug;
 use base::error;
 use zerocopy::IntoBytes;
 
+use crate::virtio::console::size::ConsoleSize;
+use crate::virtio::console::size::PortSizes;
 use crate::virtio::console::worker::WorkerPort;
 use crate::virtio::device_constants::console::virtio_console_control;
+use crate::virtio::device_constants::console::virtio_console_resize;
 use crate::virtio::device_constants::console::VIRTIO_CONSOLE_CONSOLE_PORT;
 use crate::virtio::device_constants::console::VIRTIO_CONSOLE_DEVICE_ADD;
 use crate::virtio::device_constants::console::VIRTIO_CONSOLE_DEVICE_READY;
 use crate::virtio::device_constants::console::VIRTIO_CONSOLE_PORT_NAME;
 use crate::virtio::device_constants::console::VIRTIO_CONSOLE_PORT_OPEN;
 use crate::virtio::device_constants::console::VIRTIO_CONSOLE_PORT_READY;
+use crate::virtio::device_constants::console::VIRTIO_CONSOLE_RESIZE;
 use crate::virtio::Queue;
 use crate::virtio::Reader;
 
@@ -39,9 +43,19 @@ fn control_msg(id: u32, event: u16, value: u16, extra_bytes: &[u8]) -> ControlMs
     .collect()
 }
 
+/// Returns the control message telling the driver that the window of port `id` is `size`.
+pub fn resize_msg(id: u32, size: ConsoleSize) -> ControlMsgBytes {
+    let resize = virtio_console_resize {
+        cols: size.cols.into(),
+        rows: size.rows.into(),
+    };
+    control_msg(id, VIRTIO_CONSOLE_RESIZE, 0, resize.as_bytes())
+}
+
 fn process_control_msg(
     reader: &mut Reader,
     ports: &[WorkerPort],
+    sizes: &PortSizes,
     pending_receive_control_msgs: &mut VecDeque<ControlMsgBytes>,
 ) -> anyhow::Result<()> {
     let ctrl_msg: virtio_console_control =
@@ -102,6 +116,10 @@ fn process_control_msg(
                     1,
                     &[],
                 ));
+
+                if let Some(size) = sizes.get(id) {
+                    pending_receive_control_msgs.push_back(resize_msg(id, size));
+                }
             }
             Ok(())
         }
@@ -122,14 +140,18 @@ fn process_control_msg(
 pub fn process_control_transmit_queue(
     queue: &mut Queue,
     ports: &[WorkerPort],
+    sizes: &PortSizes,
     pending_receive_control_msgs: &mut VecDeque<ControlMsgBytes>,
 ) {
     let mut needs_interrupt = false;
 
     while let Some(mut avail_desc) = queue.pop() {
-        if let Err(e) =
-            process_control_msg(&mut avail_desc.reader, ports, pending_receive_control_msgs)
-        {
+        if let Err(e) = process_control_msg(
+            &mut avail_desc.reader,
+            ports,
+            sizes,
+            pending_receive_control_msgs,
+        ) {
             error!("failed to handle control msg: {:#}", e);
         }
 
@@ -4,7 +4,12 @@
 
 //! virtio-console and vhost-user-console device shared backend implementation
 
+use std::io::Write;
+
+use base::error;
+use base::AsRawDescriptor;
 use base::RawDescriptor;
+use data_model::Le16;
 use data_model::Le32;
 use hypervisor::ProtectionType;
 use serde::Deserialize;
@@ -14,16 +19,24 @@ use zerocopy::IntoBytes;
 use crate::virtio::base_features;
 use crate::virtio::console::port::ConsolePort;
 use crate::virtio::console::port::ConsolePortSnapshot;
+use crate::virtio::console::size::ConsoleSize;
+use crate::virtio::console::size::PortSizes;
 use crate::virtio::console::worker::WorkerHandle;
 use crate::virtio::console::worker::WorkerPort;
 use crate::virtio::copy_config;
 use crate::virtio::device_constants::console::virtio_console_config;
+use crate::virtio::device_constants::console::VIRTIO_CONSOLE_F_EMERG_WRITE;
 use crate::virtio::device_constants::console::VIRTIO_CONSOLE_F_MULTIPORT;
+use crate::virtio::device_constants::console::VIRTIO_CONSOLE_F_SIZE;
 use crate::virtio::Queue;
 
+// Offset of `emerg_wr` in `virtio_console_config`.
+const EMERG_WR_OFFSET: u64 = 8;
+
 pub struct ConsoleDevice {
     avail_features: u64,
     pub(crate) ports: Vec<ConsolePort>,
+    sizes: PortSizes,
     worker: Option<WorkerHandle>,
 }
 
@@ -33,12 +46,18 @@ pub struct ConsoleSnapshot {
     pub(super) ports: Vec<ConsolePortSnapshot>,
 }
 
+// `VIRTIO_CONSOLE_F_SIZE` is only offered once the size of port 0 is known, see `resize`.
+fn console_features(protection_type: ProtectionType) -> u64 {
+    base_features(protection_type) | (1 << VIRTIO_CONSOLE_F_EMERG_WRITE)
+}
+
 impl ConsoleDevice {
     /// Create a console device that does not support the multiport feature.
     pub fn new_single_port(protection_type: ProtectionType, port: ConsolePort) -> ConsoleDevice {
         ConsoleDevice {
-            avail_features: base_features(protection_type),
+            avail_features: console_features(protection_type),
             ports: vec![port],
+            sizes: PortSizes::new(1).expect("failed to create console port sizes"),
             worker: None,
         }
     }
@@ -51,11 +70,13 @@ impl ConsoleDevice {
         // Port 0 must always exist.
         assert!(!ports.is_empty());
 
-        let avail_features = base_features(protection_type) | (1 << VIRTIO_CONSOLE_F_MULTIPORT);
+        let avail_features = console_features(protection_type) | (1 << VIRTIO_CONSOLE_F_MULTIPORT);
+        let sizes = PortSizes::new(ports.len()).expect("failed to create console port sizes");
 
         ConsoleDevice {
             avail_features,
             ports,
+            sizes,
             worker: None,
         }
     }
@@ -84,15 +105,61 @@ impl ConsoleDevice {
 
     pub fn read_config(&self, offset: u64, data: &mut [u8]) {
         let max_nr_ports = self.max_ports();
+        // Only the size of port 0 is reported in the config space, the driver learns the size of
+        // the other ports from `VIRTIO_CONSOLE_RESIZE` control messages.
+        let size = self.sizes.get(0).unwrap_or_default();
         let config = virtio_console_config {
+            cols: Le16::from(size.cols),
+            rows: Le16::from(size.rows),
             max_nr_ports: Le32::from(max_nr_ports as u32),
             ..Default::default()
         };
         copy_config(data, 0, config.as_bytes(), offset);
     }
 
+    pub fn write_config(&mut self, offset: u64, data: &[u8]) {
+        // Writing `emerg_wr` outputs its low byte to port 0, even before the queues are set up.
+        // The other fields are read-only.
+        if offset != EMERG_WR_OFFSET || data.is_empty() {
+            return;
+        }
+        let byte = data[0];
+        match self.worker.as_mut() {
+            Some(worker) => worker.emergency_write(byte),
+            None => {
+                if let Some(output) = self.ports[0].output.as_mut() {
+                    if let Err(e) = output.write_all(&[byte]).and_then(|()| output.flush()) {
+                        error!("failed to write console emergency output: {}", e);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Sets the window size of port `port_id` and notifies the driver of the change if the device
+    /// is active.
+    ///
+    /// The driver only reads the size of port 0 if it was set before the features were negotiated,
+    /// as `VIRTIO_CONSOLE_F_SIZE` is offered from then on. Otherwise it would get an empty window.
+    pub fn resize(&mut self, port_id: u32, cols: u16, rows: u16) -> anyhow::Result<()> {
+        self.sizes.set(port_id, ConsoleSize { cols, rows })?;
+        if port_id == 0 {
+            self.avail_features |= 1 << VIRTIO_CONSOLE_F_SIZE;
+        }
+        Ok(())
+    }
+
+    /// Returns a handle to the window sizes of the ports, which can resize them from another
+    /// thread.
+    pub fn port_sizes(&self) -> PortSizes {
+        self.sizes.clone()
+    }
+
     pub fn keep_rds(&self) -> Vec<RawDescriptor> {
-        self.ports.iter().flat_map(ConsolePort::keep_rds).collect()
+        let mut rds: Vec<RawDescriptor> =
+            self.ports.iter().flat_map(ConsolePort::keep_rds).collect();
+        rds.push(self.sizes.changed_event().as_raw_descriptor());
+        rds
     }
 
     fn ensure_worker_started(&mut self) -> &mut WorkerHandle {
@@ -102,7 +169,7 @@ impl ConsoleDevice {
                 .iter_mut()
                 .map(WorkerPort::from_console_port)
                 .collect();
-            WorkerHandle::new(ports).expect("failed to create console worker")
+            WorkerHandle::new(ports, self.sizes.clone()).expect("failed to create console worker")
         })
     }
 
@@ -160,12 +227,16 @@ impl ConsoleDevice {
     }
 
     pub fn restore(&mut self, snap: &ConsoleSnapshot) -> anyhow::Result<()> {
+        // Whether the size is reported depends on the terminal crosvm runs in, which may differ
+        // from the one of the snapshotted VM. The driver keeps what it negotiated.
+        let size_feature = 1 << VIRTIO_CONSOLE_F_SIZE;
         anyhow::ensure!(
-            self.avail_features == snap.avail_features,
+            self.avail_features & !size_feature == snap.avail_features & !size_feature,
             "Virtio console incorrect features for restore: Expected: {}, Actual: {}",
             self.avail_features,
             snap.avail_features,
         );
+        self.avail_features = snap.avail_features;
 
         for (port, port_snap) in self.ports.iter_mut().zip(snap.ports.iter()) {
             port.restore(port_snap);
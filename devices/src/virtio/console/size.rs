@@ -0,0 +1,111 @@
+// Copyright 2025 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Window size of the ports of a virtio console device.
+
+use std::collections::BTreeSet;
+use std::sync::Arc;
+
+use anyhow::Context;
+use base::Event;
+use sync::Mutex;
+
+/// Window size of a console port, in characters.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ConsoleSize {
+    pub cols: u16,
+    pub rows: u16,
+}
+
+struct PortSizesInner {
+    // Indexed by port ID. `None` until the host sets the size of the port.
+    sizes: Vec<Option<ConsoleSize>>,
+    // Ports whose size changed since the driver was last notified.
+    changed: BTreeSet<u32>,
+}
+
+/// Window sizes of the ports of a console device, shared by the device, its worker and the thread
+/// serving the resize requests of the host.
+///
+/// Cloned handles refer to the same sizes.
+#[derive(Clone)]
+pub struct PortSizes {
+    inner: Arc<Mutex<PortSizesInner>>,
+    // Signaled when the size of a port changes.
+    changed_evt: Arc<Event>,
+}
+
+impl PortSizes {
+    /// Creates the sizes of `num_ports` ports, all unknown.
+    pub fn new(num_ports: usize) -> anyhow::Result<PortSizes> {
+        Ok(PortSizes {
+            inner: Arc::new(Mutex::new(PortSizesInner {
+                sizes: vec![None; num_ports],
+                changed: BTreeSet::new(),
+            })),
+            changed_evt: Arc::new(Event::new().context("failed to create resize Event")?),
+        })
+    }
+
+    /// Returns the size of port `port_id`, if it is known.
+    pub fn get(&self, port_id: u32) -> Option<ConsoleSize> {
+        self.inner
+            .lock()
+            .sizes
+            .get(port_id as usize)
+            .copied()
+            .flatten()
+    }
+
+    /// Sets the size of port `port_id` and signals `changed_event()` if it differs from the
+    /// previous one.
+    pub fn set(&self, port_id: u32, size: ConsoleSize) -> anyhow::Result<()> {
+        let mut inner = self.inner.lock();
+        let port_size = inner
+            .sizes
+            .get_mut(port_id as usize)
+            .with_context(|| format!("invalid port id {port_id}"))?;
+        if *port_size == Some(size) {
+            return Ok(());
+        }
+        *port_size = Some(size);
+        inner.changed.insert(port_id);
+        self.changed_evt
+            .signal()
+            .context("failed to signal resize Event")
+    }
+
+    /// Returns the ports whose size changed since the last call.
+    pub fn take_changed(&self) -> BTreeSet<u32> {
+        std::mem::take(&mut self.inner.lock().changed)
+    }
+
+    /// Event signaled when the size of a port changes.
+    pub fn changed_event(&self) -> &Event {
+        &self.changed_evt
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_and_take_changed() {
+        let sizes = PortSizes::new(2).unwrap();
+        assert_eq!(sizes.get(0), None);
+
+        let size = ConsoleSize { cols: 80, rows: 24 };
+        sizes.set(1, size).unwrap();
+        assert_eq!(sizes.get(1), Some(size));
+        assert_eq!(sizes.take_changed(), BTreeSet::from([1]));
+        assert!(sizes.take_changed().is_empty());
+
+        // Setting the same size again is not a change.
+        sizes.set(1, size).unwrap();
+        assert!(sizes.take_changed().is_empty());
+
+        assert!(sizes.set(2, size).is_err());
+    }
+}
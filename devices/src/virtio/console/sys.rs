@@ -12,4 +12,6 @@ cfg_if::cfg_if! {
     }
 }
 
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub(in crate::virtio::console) use platform::handle_command;
 pub(in crate::virtio::console) use platform::spawn_input_thread;
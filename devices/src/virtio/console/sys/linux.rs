@@ -17,6 +17,8 @@ use base::RawDescriptor;
 use base::WaitContext;
 use base::WorkerThread;
 use sync::Mutex;
+use vm_control::ConsoleDeviceCommand;
+use vm_control::ConsoleDeviceResult;
 
 use crate::serial::sys::InStreamType;
 use crate::serial_device::SerialInput;
@@ -24,6 +26,8 @@ use crate::serial_device::SerialOptions;
 use crate::virtio::console::device::ConsoleDevice;
 use crate::virtio::console::port::ConsolePort;
 use crate::virtio::console::port::ConsolePortInfo;
+use crate::virtio::console::size::ConsoleSize;
+use crate::virtio::console::size::PortSizes;
 use crate::virtio::console::Console;
 use crate::virtio::ProtectionType;
 use crate::SerialDevice;
@@ -182,3 +186,21 @@ fn read_input(
 
     Ok(())
 }
+
+/// Handles `command`, received on the control tube of the console whose ports have `sizes`.
+pub(in crate::virtio::console) fn handle_command(
+    sizes: &PortSizes,
+    command: ConsoleDeviceCommand,
+) -> ConsoleDeviceResult {
+    match command {
+        ConsoleDeviceCommand::Resize { port, cols, rows } => {
+            match sizes.set(port, ConsoleSize { cols, rows }) {
+                Ok(()) => ConsoleDeviceResult::Ok,
+                Err(e) => {
+                    error!("console: failed to resize port {}: {:#}", port, e);
+                    ConsoleDeviceResult::Err(base::Error::new(libc::EINVAL))
+                }
+            }
+        }
+    }
+}
@@ -6,6 +6,7 @@
 
 use std::collections::BTreeMap;
 use std::collections::VecDeque;
+use std::io::Write;
 use std::sync::mpsc;
 use std::sync::Arc;
 
@@ -20,11 +21,13 @@ use sync::Mutex;
 
 use crate::virtio::console::control::process_control_receive_queue;
 use crate::virtio::console::control::process_control_transmit_queue;
+use crate::virtio::console::control::resize_msg;
 use crate::virtio::console::control::ControlMsgBytes;
 use crate::virtio::console::input::process_receive_queue;
 use crate::virtio::console::output::process_transmit_queue;
 use crate::virtio::console::port::ConsolePort;
 use crate::virtio::console::port::ConsolePortInfo;
+use crate::virtio::console::size::PortSizes;
 use crate::virtio::Queue;
 
 const PORT0_RECEIVEQ_IDX: usize = 0;
@@ -82,6 +85,7 @@ enum Token {
     InputAvailable(u32),
     ControlReceiveQueueAvailable,
     ControlTransmitQueueAvailable,
+    PortResized,
     WorkerRequest,
     Kill,
 }
@@ -96,6 +100,9 @@ pub enum WorkerRequest {
         idx: usize,
         response_sender: mpsc::SyncSender<Option<Queue>>,
     },
+    EmergencyWrite {
+        byte: u8,
+    },
 }
 
 pub struct Worker {
@@ -111,6 +118,8 @@ pub struct Worker {
     // Device-to-driver messages to be received by the driver via the control receiveq.
     pending_receive_control_msgs: VecDeque<ControlMsgBytes>,
 
+    sizes: PortSizes,
+
     worker_receiver: mpsc::Receiver<WorkerRequest>,
     worker_event: Event,
 }
@@ -118,12 +127,14 @@ pub struct Worker {
 impl Worker {
     pub fn new(
         ports: Vec<WorkerPort>,
+        sizes: PortSizes,
         worker_receiver: mpsc::Receiver<WorkerRequest>,
         worker_event: Event,
     ) -> anyhow::Result<Self> {
         let wait_ctx = WaitContext::new().context("WaitContext::new() failed")?;
 
         wait_ctx.add(&worker_event, Token::WorkerRequest)?;
+        wait_ctx.add(sizes.changed_event(), Token::PortResized)?;
 
         for (index, port) in ports.iter().enumerate() {
             let port_id = index as u32;
@@ -135,6 +146,7 @@ impl Worker {
             queues: BTreeMap::new(),
             ports,
             pending_receive_control_msgs: VecDeque::new(),
+            sizes,
             worker_receiver,
             worker_event,
         })
@@ -206,6 +218,7 @@ impl Worker {
                             process_control_transmit_queue(
                                 ctrl_transmitq,
                                 &self.ports,
+                                &self.sizes,
                                 &mut self.pending_receive_control_msgs,
                             );
                         }
@@ -218,6 +231,10 @@ impl Worker {
                             )
                         }
                     }
+                    Token::PortResized => {
+                        self.sizes.changed_event().wait()?;
+                        self.process_resized_ports();
+                    }
                     Token::WorkerRequest => {
                         self.worker_event.wait()?;
                         self.process_worker_requests();
@@ -247,6 +264,33 @@ impl Worker {
                     let res = self.stop_queue(idx);
                     let _ = response_sender.send(res);
                 }
+                WorkerRequest::EmergencyWrite { byte } => {
+                    let output = &mut self.ports[0].output;
+                    if let Err(e) = output.write_all(&[byte]).and_then(|()| output.flush()) {
+                        error!("failed to write console emergency output: {}", e);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Notifies the driver of the ports whose window size changed.
+    fn process_resized_ports(&mut self) {
+        let changed = self.sizes.take_changed();
+        // The control queues only run when the driver negotiated `VIRTIO_CONSOLE_F_MULTIPORT`,
+        // in which case sizes are sent as control messages. Otherwise the driver reads the size
+        // of port 0 from the config space.
+        if let Some(ctrl_receiveq) = self.queues.get_mut(&CONTROL_RECEIVEQ_IDX) {
+            for port_id in changed {
+                if let Some(size) = self.sizes.get(port_id) {
+                    self.pending_receive_control_msgs
+                        .push_back(resize_msg(port_id, size));
+                }
+            }
+            process_control_receive_queue(ctrl_receiveq, &mut self.pending_receive_control_msgs);
+        } else if changed.contains(&0) {
+            if let Some(queue) = self.queues.values().next() {
+                queue.interrupt().signal_config_changed();
             }
         }
     }
@@ -290,12 +334,12 @@ pub struct WorkerHandle {
 }
 
 impl WorkerHandle {
-    pub fn new(ports: Vec<WorkerPort>) -> anyhow::Result<Self> {
+    pub fn new(ports: Vec<WorkerPort>, sizes: PortSizes) -> anyhow::Result<Self> {
         let worker_event = Event::new().context("Event::new")?;
         let worker_event_clone = worker_event.try_clone().context("Event::try_clone")?;
         let (worker_sender, worker_receiver) = mpsc::channel();
         let worker_thread = WorkerThread::start("v_console", move |kill_evt| {
-            let mut worker = Worker::new(ports, worker_receiver, worker_event_clone)
+            let mut worker = Worker::new(ports, sizes, worker_receiver, worker_event_clone)
                 .expect("console Worker::new() failed");
             if let Err(e) = worker.run(&kill_evt) {
                 error!("console worker failed: {:#}", e);
@@ -334,6 +378,20 @@ impl WorkerHandle {
         response_receiver.recv().context("mpsc::Receiver::recv")
     }
 
+    /// Writes `byte` to the output of port 0 without waiting for it to be written.
+    pub fn emergency_write(&mut self, byte: u8) {
+        if let Err(e) = self
+            .worker_sender
+            .send(WorkerRequest::EmergencyWrite { byte })
+        {
+            error!("failed to send console emergency write: {}", e);
+            return;
+        }
+        if let Err(e) = self.worker_event.signal() {
+            error!("failed to signal console worker: {}", e);
+        }
+    }
+
     pub fn stop(self) -> Vec<WorkerPort> {
         self.worker_thread.stop()
     }
@@ -0,0 +1,76 @@
+// Copyright 2025 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Worker serving the requests the host sends to a running virtio device through its control
+//! tube.
+
+use base::error;
+use base::Event;
+use base::EventToken;
+use base::ReadNotifier;
+use base::Tube;
+use base::TubeError;
+use base::WaitContext;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// Receives commands on `tube` and sends back the result of `handler` for each of them, until
+/// `kill_evt` is signaled or the other end of `tube` is closed. Returns `tube`, so that it can be
+/// served again once the device is restarted.
+///
+/// `name` prefixes the errors logged by the worker.
+pub(crate) fn run_device_control_worker<C, R>(
+    name: &str,
+    tube: Tube,
+    kill_evt: Event,
+    mut handler: impl FnMut(C) -> R,
+) -> Tube
+where
+    C: DeserializeOwned,
+    R: Serialize,
+{
+    #[derive(EventToken, Debug)]
+    enum Token {
+        Command,
+        Kill,
+    }
+    let wait_ctx: WaitContext<Token> = match WaitContext::build_with(&[
+        (tube.get_read_notifier(), Token::Command),
+        (&kill_evt, Token::Kill),
+    ]) {
+        Ok(wait_ctx) => wait_ctx,
+        Err(e) => {
+            error!("{}: failed creating WaitContext: {}", name, e);
+            return tube;
+        }
+    };
+    'wait: loop {
+        let events = match wait_ctx.wait() {
+            Ok(v) => v,
+            Err(e) => {
+                error!("{}: failed polling for events: {}", name, e);
+                break;
+            }
+        };
+        for event in events.iter().filter(|e| e.is_readable) {
+            match event.token {
+                Token::Command => {
+                    let command = match tube.recv::<C>() {
+                        Ok(command) => command,
+                        Err(TubeError::Disconnected) => break 'wait,
+                        Err(e) => {
+                            error!("{}: failed to receive device command: {}", name, e);
+                            continue;
+                        }
+                    };
+                    if let Err(e) = tube.send(&handler(command)) {
+                        error!("{}: failed to send device command result: {}", name, e);
+                    }
+                }
+                Token::Kill => break 'wait,
+            }
+        }
+    }
+    tube
+}
@@ -10,6 +10,8 @@ mod balloon;
 mod descriptor_chain;
 mod descriptor_utils;
 pub mod device_constants;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+mod device_control;
 pub mod input;
 mod interrupt;
 mod iommu;
@@ -54,6 +56,8 @@ pub use self::descriptor_utils::create_descriptor_chain;
 pub use self::descriptor_utils::DescriptorType;
 pub use self::descriptor_utils::Reader;
 pub use self::descriptor_utils::Writer;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub(crate) use self::device_control::run_device_control_worker;
 #[cfg(feature = "gpu")]
 pub use self::gpu::DisplayBackend;
 #[cfg(feature = "gpu")]
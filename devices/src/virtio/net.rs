@@ -2,11 +2,23 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+#[cfg(any(target_os = "android", target_os = "linux"))]
+mod capture;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+mod control;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+mod filter;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+mod rss;
 mod sys;
 
 use std::collections::BTreeMap;
 use std::fmt;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use std::fs::File;
 use std::io;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use std::io::Read;
 use std::io::Write;
 use std::net::Ipv4Addr;
 use std::os::raw::c_uint;
@@ -19,15 +31,22 @@ use base::error;
 #[cfg(windows)]
 use base::named_pipes::OverlappedWrapper;
 use base::warn;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use base::AsRawDescriptor;
 use base::Error as SysError;
 use base::Event;
 use base::EventToken;
 use base::RawDescriptor;
 use base::ReadNotifier;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use base::Tube;
 use base::WaitContext;
 use base::WorkerThread;
 use data_model::Le16;
+use data_model::Le32;
 use data_model::Le64;
+#[cfg(all(feature = "slirp", any(target_os = "android", target_os = "linux")))]
+use net_util::slirp::HostForward;
 use net_util::Error as TapError;
 use net_util::MacAddress;
 use net_util::TapT;
@@ -35,13 +54,37 @@ use remain::sorted;
 use serde::Deserialize;
 use serde::Serialize;
 use snapshot::AnySnapshot;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use sync::Mutex;
 use thiserror::Error as ThisError;
 use virtio_sys::virtio_config::VIRTIO_F_RING_PACKED;
 use virtio_sys::virtio_net;
 use virtio_sys::virtio_net::VIRTIO_NET_CTRL_GUEST_OFFLOADS;
 use virtio_sys::virtio_net::VIRTIO_NET_CTRL_GUEST_OFFLOADS_SET;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use virtio_sys::virtio_net::VIRTIO_NET_CTRL_MAC;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use virtio_sys::virtio_net::VIRTIO_NET_CTRL_MAC_ADDR_SET;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use virtio_sys::virtio_net::VIRTIO_NET_CTRL_MAC_TABLE_SET;
 use virtio_sys::virtio_net::VIRTIO_NET_CTRL_MQ;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use virtio_sys::virtio_net::VIRTIO_NET_CTRL_MQ_HASH_CONFIG;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use virtio_sys::virtio_net::VIRTIO_NET_CTRL_MQ_RSS_CONFIG;
 use virtio_sys::virtio_net::VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use virtio_sys::virtio_net::VIRTIO_NET_CTRL_RX;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use virtio_sys::virtio_net::VIRTIO_NET_CTRL_RX_ALLMULTI;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use virtio_sys::virtio_net::VIRTIO_NET_CTRL_RX_PROMISC;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use virtio_sys::virtio_net::VIRTIO_NET_CTRL_VLAN;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use virtio_sys::virtio_net::VIRTIO_NET_CTRL_VLAN_ADD;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use virtio_sys::virtio_net::VIRTIO_NET_CTRL_VLAN_DEL;
 use virtio_sys::virtio_net::VIRTIO_NET_ERR;
 use virtio_sys::virtio_net::VIRTIO_NET_OK;
 use vm_memory::GuestMemory;
@@ -51,6 +94,8 @@ use zerocopy::IntoBytes;
 use zerocopy::KnownLayout;
 
 use super::copy_config;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use super::run_device_control_worker;
 use super::DeviceType;
 use super::Interrupt;
 use super::Queue;
@@ -65,9 +110,29 @@ use crate::PciAddress;
 pub(crate) const MAX_BUFFER_SIZE: usize = 65562;
 const QUEUE_SIZE: u16 = 256;
 
+/// Features implemented with the receive filter and the link state of `SharedState`, which only
+/// the Linux workers apply.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+const RX_FILTER_FEATURES: u64 = 1 << virtio_net::VIRTIO_NET_F_STATUS
+    | 1 << virtio_net::VIRTIO_NET_F_CTRL_RX
+    | 1 << virtio_net::VIRTIO_NET_F_CTRL_VLAN
+    | 1 << virtio_net::VIRTIO_NET_F_CTRL_MAC_ADDR;
+
 #[cfg(any(target_os = "android", target_os = "linux"))]
 pub static VHOST_NET_DEFAULT_PATH: &str = "/dev/vhost-net";
 
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub(crate) use capture::Direction;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub(crate) use capture::PacketCapture;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub(crate) use control::SharedState;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub(crate) use filter::RxFilter;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub(crate) use rss::RssConfig;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub(crate) use rss::RxSteering;
 pub(crate) use sys::process_rx;
 pub(crate) use sys::process_tx;
 pub(crate) use sys::validate_and_configure_tap;
@@ -172,6 +237,43 @@ pub enum NetParametersMode {
         netmask: Ipv4Addr,
         mac: MacAddress,
     },
+    /// Ethernet frames exchanged with a peer, e.g. a switch connecting several VMs, over the
+    /// stream or datagram Unix socket at `socket`.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    #[serde(rename_all = "kebab-case")]
+    Socket {
+        socket: PathBuf,
+        mac: Option<MacAddress>,
+    },
+    /// Like `Socket`, but with an already connected socket, e.g. one end of a socket pair.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    #[serde(rename_all = "kebab-case")]
+    SocketFd {
+        socket_fd: i32,
+        mac: Option<MacAddress>,
+    },
+    /// User-mode networking through libslirp, which needs neither a tap device nor any
+    /// privileges.
+    #[cfg(all(feature = "slirp", any(target_os = "android", target_os = "linux")))]
+    #[serde(rename_all = "kebab-case")]
+    Slirp {
+        #[serde(deserialize_with = "deserialize_slirp")]
+        slirp: bool,
+        #[serde(default)]
+        host_fwd: Vec<HostForward>,
+        mac: Option<MacAddress>,
+    },
+}
+
+/// Only accepts `true`, so that `slirp=false` doesn't select the slirp backend.
+#[cfg(all(feature = "slirp", any(target_os = "android", target_os = "linux")))]
+fn deserialize_slirp<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    if !bool::deserialize(deserializer)? {
+        return Err(serde::de::Error::custom(
+            "slirp=false doesn't select a network backend",
+        ));
+    }
+    Ok(true)
 }
 
 #[cfg(any(target_os = "android", target_os = "linux"))]
@@ -206,6 +308,9 @@ pub struct NetParameters {
     // to the fact this struct is used for argument parsing.
     #[cfg(any(target_os = "android", target_os = "linux"))]
     pub vhost_net: Option<VhostNetParameters>,
+    /// Path of a pcapng file to write the frames exchanged by the device to.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    pub capture: Option<PathBuf>,
     #[serde(default)]
     pub packed_queue: bool,
     pub pci_address: Option<PciAddress>,
@@ -232,6 +337,11 @@ pub struct VirtioNetConfig {
     status: Le16,
     max_vq_pairs: Le16,
     mtu: Le16,
+    speed: Le32,
+    duplex: u8,
+    rss_max_key_size: u8,
+    rss_max_indirection_table_length: Le16,
+    supported_hash_types: Le32,
 }
 
 fn process_ctrl_request<T: TapT>(
@@ -239,11 +349,16 @@ fn process_ctrl_request<T: TapT>(
     tap: &mut T,
     acked_features: u64,
     vq_pairs: u16,
+    #[cfg(any(target_os = "android", target_os = "linux"))] state: Option<&SharedState>,
 ) -> Result<(), NetError> {
     let ctrl_hdr: virtio_net_ctrl_hdr = reader.read_obj().map_err(NetError::ReadCtrlHeader)?;
 
     match ctrl_hdr.class as c_uint {
         VIRTIO_NET_CTRL_GUEST_OFFLOADS => {
+            if acked_features & 1 << virtio_net::VIRTIO_NET_F_CTRL_GUEST_OFFLOADS == 0 {
+                error!("VIRTIO_NET_CTRL_GUEST_OFFLOADS without VIRTIO_NET_F_CTRL_GUEST_OFFLOADS");
+                return Err(NetError::InvalidCmd);
+            }
             if ctrl_hdr.cmd != VIRTIO_NET_CTRL_GUEST_OFFLOADS_SET as u8 {
                 error!(
                     "invalid cmd for VIRTIO_NET_CTRL_GUEST_OFFLOADS: {}",
@@ -271,7 +386,28 @@ fn process_ctrl_request<T: TapT>(
                     return Err(NetError::InvalidCmd);
                 }
             }
+            #[cfg(any(target_os = "android", target_os = "linux"))]
+            if ctrl_hdr.cmd == VIRTIO_NET_CTRL_MQ_RSS_CONFIG as u8
+                || ctrl_hdr.cmd == VIRTIO_NET_CTRL_MQ_HASH_CONFIG as u8
+            {
+                let Some(state) = state else {
+                    warn!("no hash configuration for cmd {}", ctrl_hdr.cmd);
+                    return Err(NetError::InvalidCmd);
+                };
+                let config = read_rss_config(reader, ctrl_hdr.cmd, acked_features, vq_pairs)?;
+                *state.rss.lock() = Some(config);
+            }
         }
+        #[cfg(any(target_os = "android", target_os = "linux"))]
+        VIRTIO_NET_CTRL_RX | VIRTIO_NET_CTRL_MAC | VIRTIO_NET_CTRL_VLAN => match state {
+            Some(state) => {
+                process_rx_filter_request(reader, ctrl_hdr, acked_features, &state.rx_filter)?
+            }
+            None => {
+                warn!("no receive filter for control class {}", ctrl_hdr.class);
+                return Err(NetError::InvalidCmd);
+            }
+        },
         _ => {
             warn!(
                 "unimplemented class for VIRTIO_NET_CTRL_GUEST_OFFLOADS: {}",
@@ -284,15 +420,165 @@ fn process_ctrl_request<T: TapT>(
     Ok(())
 }
 
+// Reads a table of MAC addresses of a `VIRTIO_NET_CTRL_MAC_TABLE_SET` command.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn read_mac_table(reader: &mut Reader) -> Result<Vec<[u8; 6]>, NetError> {
+    let entries: Le32 = reader.read_obj().map_err(NetError::ReadCtrlData)?;
+    (0..entries.to_native())
+        .map(|_| reader.read_obj().map_err(NetError::ReadCtrlData))
+        .collect()
+}
+
+// Handles the commands of the `VIRTIO_NET_CTRL_RX`, `VIRTIO_NET_CTRL_MAC` and
+// `VIRTIO_NET_CTRL_VLAN` classes, which program `rx_filter`.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn process_rx_filter_request(
+    reader: &mut Reader,
+    ctrl_hdr: virtio_net_ctrl_hdr,
+    acked_features: u64,
+    rx_filter: &Mutex<RxFilter>,
+) -> Result<(), NetError> {
+    let acked = |feature: c_uint| acked_features & 1 << feature != 0;
+    let class = ctrl_hdr.class as c_uint;
+    let cmd = ctrl_hdr.cmd as c_uint;
+    match (class, cmd) {
+        (VIRTIO_NET_CTRL_RX, VIRTIO_NET_CTRL_RX_PROMISC | VIRTIO_NET_CTRL_RX_ALLMULTI)
+            if acked(virtio_net::VIRTIO_NET_F_CTRL_RX) =>
+        {
+            let on: u8 = reader.read_obj().map_err(NetError::ReadCtrlData)?;
+            let mut rx_filter = rx_filter.lock();
+            if cmd == VIRTIO_NET_CTRL_RX_PROMISC {
+                rx_filter.set_promisc(on != 0);
+            } else {
+                rx_filter.set_all_multi(on != 0);
+            }
+        }
+        (VIRTIO_NET_CTRL_MAC, VIRTIO_NET_CTRL_MAC_TABLE_SET)
+            if acked(virtio_net::VIRTIO_NET_F_CTRL_RX) =>
+        {
+            let unicast = read_mac_table(reader)?;
+            let multicast = read_mac_table(reader)?;
+            rx_filter.lock().set_mac_tables(unicast, multicast);
+        }
+        (VIRTIO_NET_CTRL_MAC, VIRTIO_NET_CTRL_MAC_ADDR_SET)
+            if acked(virtio_net::VIRTIO_NET_F_CTRL_MAC_ADDR) =>
+        {
+            let mac: [u8; 6] = reader.read_obj().map_err(NetError::ReadCtrlData)?;
+            rx_filter.lock().set_mac(mac);
+        }
+        (VIRTIO_NET_CTRL_VLAN, VIRTIO_NET_CTRL_VLAN_ADD | VIRTIO_NET_CTRL_VLAN_DEL)
+            if acked(virtio_net::VIRTIO_NET_F_CTRL_VLAN) =>
+        {
+            let vid: Le16 = reader.read_obj().map_err(NetError::ReadCtrlData)?;
+            let vid = vid.to_native();
+            if vid > filter::MAX_VLAN_ID {
+                error!("invalid VLAN id: {}", vid);
+                return Err(NetError::InvalidCmd);
+            }
+            let mut rx_filter = rx_filter.lock();
+            let filtering = if cmd == VIRTIO_NET_CTRL_VLAN_ADD {
+                rx_filter.add_vlan(vid)
+            } else {
+                rx_filter.remove_vlan(vid)
+            };
+            if !filtering {
+                return Err(NetError::InvalidCmd);
+            }
+        }
+        _ => {
+            error!("invalid cmd for control class {}: {}", class, cmd);
+            return Err(NetError::InvalidCmd);
+        }
+    }
+    Ok(())
+}
+
+// Reads the hash configuration of a `VIRTIO_NET_CTRL_MQ_RSS_CONFIG` or
+// `VIRTIO_NET_CTRL_MQ_HASH_CONFIG` command.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn read_rss_config(
+    reader: &mut Reader,
+    cmd: u8,
+    acked_features: u64,
+    vq_pairs: u16,
+) -> Result<RssConfig, NetError> {
+    let rss = cmd == VIRTIO_NET_CTRL_MQ_RSS_CONFIG as u8;
+    let feature = if rss {
+        virtio_net::VIRTIO_NET_F_RSS
+    } else {
+        virtio_net::VIRTIO_NET_F_HASH_REPORT
+    };
+    if acked_features & 1 << feature == 0 {
+        error!("hash configuration cmd {} without its feature", cmd);
+        return Err(NetError::InvalidCmd);
+    }
+
+    let hash_types: Le32 = reader.read_obj().map_err(NetError::ReadCtrlData)?;
+    let (indirection_table, unclassified_queue) = if rss {
+        let mask: Le16 = reader.read_obj().map_err(NetError::ReadCtrlData)?;
+        let unclassified_queue: Le16 = reader.read_obj().map_err(NetError::ReadCtrlData)?;
+        let len = mask.to_native() as usize + 1;
+        if !len.is_power_of_two() || len > rss::RSS_MAX_INDIRECTION_TABLE_LENGTH as usize {
+            error!("invalid RSS indirection table length: {}", len);
+            return Err(NetError::InvalidCmd);
+        }
+        let indirection_table = (0..len)
+            .map(|_| {
+                let queue: Le16 = reader.read_obj().map_err(NetError::ReadCtrlData)?;
+                Ok(queue.to_native())
+            })
+            .collect::<Result<Vec<u16>, NetError>>()?;
+        // Transmit queues are used as the driver chooses.
+        let _max_tx_vq: Le16 = reader.read_obj().map_err(NetError::ReadCtrlData)?;
+        let unclassified_queue = unclassified_queue.to_native();
+        if let Some(queue) = indirection_table
+            .iter()
+            .chain([&unclassified_queue])
+            .find(|&&queue| queue >= vq_pairs)
+        {
+            error!("RSS queue {} out of {} queues", queue, vq_pairs);
+            return Err(NetError::InvalidCmd);
+        }
+        (indirection_table, unclassified_queue)
+    } else {
+        let _reserved: [Le16; 4] = reader.read_obj().map_err(NetError::ReadCtrlData)?;
+        (Vec::new(), 0)
+    };
+
+    let key_len: u8 = reader.read_obj().map_err(NetError::ReadCtrlData)?;
+    if key_len > rss::RSS_MAX_KEY_SIZE {
+        error!("invalid RSS key length: {}", key_len);
+        return Err(NetError::InvalidCmd);
+    }
+    let mut key = vec![0u8; key_len as usize];
+    reader
+        .read_exact(&mut key)
+        .map_err(NetError::ReadCtrlData)?;
+
+    Ok(RssConfig::new(
+        hash_types.to_native(),
+        key,
+        indirection_table,
+        unclassified_queue,
+    ))
+}
+
 pub fn process_ctrl<T: TapT>(
     ctrl_queue: &mut Queue,
     tap: &mut T,
     acked_features: u64,
     vq_pairs: u16,
+    #[cfg(any(target_os = "android", target_os = "linux"))] state: Option<&SharedState>,
 ) -> Result<(), NetError> {
     while let Some(mut desc_chain) = ctrl_queue.pop() {
-        if let Err(e) = process_ctrl_request(&mut desc_chain.reader, tap, acked_features, vq_pairs)
-        {
+        if let Err(e) = process_ctrl_request(
+            &mut desc_chain.reader,
+            tap,
+            acked_features,
+            vq_pairs,
+            #[cfg(any(target_os = "android", target_os = "linux"))]
+            state,
+        ) {
             error!("process_ctrl_request failed: {}", e);
             desc_chain
                 .writer
@@ -322,6 +608,8 @@ pub enum Token {
     TxQueue,
     // The control queue has a message.
     CtrlQueue,
+    // Other workers have steered frames to the receive queue.
+    RxSteered,
     // crosvm has requested the device to shut down.
     Kill,
 }
@@ -339,6 +627,11 @@ pub(super) struct Worker<T: TapT> {
     pub(super) rx_count: usize,
     #[cfg(windows)]
     pub(super) deferred_rx: bool,
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    pub(super) state: SharedState,
+    // Set when frames are steered between the receive queues by their hash.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    pub(super) rx_steering: Option<RxSteering>,
     acked_features: u64,
     vq_pairs: u16,
     #[allow(dead_code)]
@@ -350,7 +643,12 @@ where
     T: TapT + ReadNotifier,
 {
     fn process_tx(&mut self) {
-        process_tx(&mut self.tx_queue, &mut self.tap)
+        process_tx(
+            &mut self.tx_queue,
+            &mut self.tap,
+            #[cfg(any(target_os = "android", target_os = "linux"))]
+            Some(&self.state),
+        )
     }
 
     fn process_ctrl(&mut self) -> Result<(), NetError> {
@@ -364,6 +662,8 @@ where
             &mut self.tap,
             self.acked_features,
             self.vq_pairs,
+            #[cfg(any(target_os = "android", target_os = "linux"))]
+            Some(&self.state),
         )
     }
 
@@ -392,6 +692,13 @@ where
                 .map_err(NetError::CreateWaitContext)?;
         }
 
+        #[cfg(any(target_os = "android", target_os = "linux"))]
+        if let Some(rx_steering) = &self.rx_steering {
+            wait_ctx
+                .add(rx_steering.event(), Token::RxSteered)
+                .map_err(NetError::CreateWaitContext)?;
+        }
+
         let mut tap_polling_enabled = true;
         'wait: loop {
             let events = wait_ctx.wait().map_err(NetError::WaitError)?;
@@ -435,6 +742,12 @@ where
                             break 'wait;
                         }
                     }
+                    Token::RxSteered => {
+                        let _trace =
+                            cros_tracing::trace_event!(VirtioNet, "handle RxSteered event");
+                        #[cfg(any(target_os = "android", target_os = "linux"))]
+                        self.handle_rx_steered()?;
+                    }
                     Token::Kill => {
                         let _ = self.kill_evt.wait();
                         break 'wait;
@@ -469,12 +782,26 @@ pub struct Net<T: TapT + ReadNotifier + 'static> {
     pci_address: Option<PciAddress>,
     #[cfg(windows)]
     slirp_kill_evt: Option<Event>,
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    state: SharedState,
+    // Receives `NetDeviceCommand`s, e.g. to capture frames or change the link state.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    control_tube: Option<Tube>,
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    control_worker: Option<WorkerThread<Tube>>,
 }
 
 #[derive(Serialize, Deserialize)]
 struct NetSnapshot {
     avail_features: u64,
     acked_features: u64,
+    // Absent from the snapshots of devices that don't filter frames, which accept every frame.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    #[serde(default)]
+    rx_filter: Option<RxFilter>,
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    #[serde(default)]
+    rss: Option<RssConfig>,
 }
 
 impl<T> Net<T>
@@ -523,6 +850,16 @@ where
             avail_features |= 1 << virtio_net::VIRTIO_NET_F_MQ;
         }
 
+        // Hashes are reported in a larger virtio-net header, which only taps can be configured
+        // for.
+        #[cfg(any(target_os = "android", target_os = "linux"))]
+        {
+            avail_features |= 1 << virtio_net::VIRTIO_NET_F_HASH_REPORT;
+            if vq_pairs > 1 {
+                avail_features |= 1 << virtio_net::VIRTIO_NET_F_RSS;
+            }
+        }
+
         if use_packed_queue {
             avail_features |= 1 << VIRTIO_F_RING_PACKED;
         }
@@ -542,6 +879,42 @@ where
         )
     }
 
+    /// Creates a new virtio network device from a single queue pair backend that exchanges plain
+    /// Ethernet frames, like libslirp or a socket. Such backends can't handle offloads, so unlike
+    /// `Net::new` only the control queue and the MTU are offered on top of `base_features`.
+    pub fn new_without_offloads(
+        base_features: u64,
+        tap: T,
+        mac_addr: Option<MacAddress>,
+        use_packed_queue: bool,
+        pci_address: Option<PciAddress>,
+    ) -> Result<Net<T>, NetError> {
+        validate_and_configure_tap(&tap, 1)?;
+        let mtu = tap.mtu().map_err(NetError::TapGetMtu)?;
+
+        let mut avail_features = base_features
+            | 1 << virtio_net::VIRTIO_NET_F_CTRL_VQ
+            | 1 << virtio_net::VIRTIO_NET_F_MTU;
+
+        if use_packed_queue {
+            avail_features |= 1 << VIRTIO_F_RING_PACKED;
+        }
+
+        if mac_addr.is_some() {
+            avail_features |= 1 << virtio_net::VIRTIO_NET_F_MAC;
+        }
+
+        Self::new_internal(
+            vec![tap],
+            avail_features,
+            mtu,
+            mac_addr,
+            pci_address,
+            #[cfg(windows)]
+            None,
+        )
+    }
+
     pub(crate) fn new_internal(
         taps: Vec<T>,
         avail_features: u64,
@@ -550,8 +923,11 @@ where
         pci_address: Option<PciAddress>,
         #[cfg(windows)] slirp_kill_evt: Option<Event>,
     ) -> Result<Self, NetError> {
+        #[cfg(any(target_os = "android", target_os = "linux"))]
+        let avail_features = avail_features | RX_FILTER_FEATURES;
+        let guest_mac = mac_addr.map(|mac| mac.octets());
         let net = Self {
-            guest_mac: mac_addr.map(|mac| mac.octets()),
+            guest_mac,
             queue_sizes: vec![QUEUE_SIZE; taps.len() * 2 + 1].into_boxed_slice(),
             worker_threads: Vec::new(),
             taps,
@@ -561,11 +937,29 @@ where
             pci_address,
             #[cfg(windows)]
             slirp_kill_evt: None,
+            #[cfg(any(target_os = "android", target_os = "linux"))]
+            state: SharedState::new(guest_mac),
+            #[cfg(any(target_os = "android", target_os = "linux"))]
+            control_tube: None,
+            #[cfg(any(target_os = "android", target_os = "linux"))]
+            control_worker: None,
         };
         cros_tracing::trace_simple_print!("New Net device created: {:?}", net);
         Ok(net)
     }
 
+    /// Starts writing the frames exchanged by the device to `file` in the pcapng format.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    pub fn start_capture(&self, file: File) -> io::Result<()> {
+        self.state.capture.start(file)
+    }
+
+    /// Sets the tube on which `NetDeviceCommand`s are received.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    pub fn set_control_tube(&mut self, tube: Tube) {
+        self.control_tube = Some(tube);
+    }
+
     /// Returns the maximum number of receive/transmit queue pairs for this device.
     /// Only relevant when multi-queue support is negotiated.
     fn max_virtqueue_pairs(&self) -> usize {
@@ -598,6 +992,14 @@ where
             keep_rds.push(tap.as_raw_descriptor());
         }
 
+        #[cfg(any(target_os = "android", target_os = "linux"))]
+        {
+            keep_rds.extend(self.state.capture.as_raw_descriptor());
+            if let Some(control_tube) = &self.control_tube {
+                keep_rds.push(control_tube.as_raw_descriptor());
+            }
+        }
+
         keep_rds
     }
 
@@ -639,14 +1041,32 @@ where
 
     fn read_config(&self, offset: u64, data: &mut [u8]) {
         let vq_pairs = self.queue_sizes.len() / 2;
-        let config_space = build_config(vq_pairs as u16, self.mtu, self.guest_mac);
+        #[cfg_attr(windows, allow(unused_mut))]
+        let mut config_space = build_config(vq_pairs as u16, self.mtu, self.guest_mac);
+        #[cfg(any(target_os = "android", target_os = "linux"))]
+        {
+            if let Some(mac) = self.state.rx_filter.lock().mac() {
+                config_space.mac = mac;
+            }
+            if self.state.is_link_up() {
+                config_space.status = Le16::from(virtio_net::VIRTIO_NET_S_LINK_UP as u16);
+            }
+            let hash_features =
+                1 << virtio_net::VIRTIO_NET_F_RSS | 1 << virtio_net::VIRTIO_NET_F_HASH_REPORT;
+            if self.avail_features & hash_features != 0 {
+                config_space.rss_max_key_size = rss::RSS_MAX_KEY_SIZE;
+                config_space.rss_max_indirection_table_length =
+                    Le16::from(rss::RSS_MAX_INDIRECTION_TABLE_LENGTH);
+                config_space.supported_hash_types = Le32::from(rss::SUPPORTED_HASH_TYPES);
+            }
+        }
         copy_config(data, 0, config_space.as_bytes(), offset);
     }
 
     fn activate(
         &mut self,
         _mem: GuestMemory,
-        _interrupt: Interrupt,
+        #[cfg_attr(windows, allow(unused_variables))] interrupt: Interrupt,
         mut queues: BTreeMap<usize, Queue>,
     ) -> anyhow::Result<()> {
         let ctrl_vq_enabled = self.acked_features & (1 << virtio_net::VIRTIO_NET_F_CTRL_VQ) != 0;
@@ -679,6 +1099,42 @@ where
             ));
         }
 
+        #[cfg(any(target_os = "android", target_os = "linux"))]
+        {
+            let vlan_enabled = self.acked_features & (1 << virtio_net::VIRTIO_NET_F_CTRL_VLAN) != 0;
+            let mut rx_filter = self.state.rx_filter.lock();
+            // Keep the VLANs programmed before the device was put to sleep or snapshotted.
+            if rx_filter.filters_vlans() != vlan_enabled {
+                rx_filter.set_vlan_filtering(vlan_enabled);
+            }
+        }
+        #[cfg(any(target_os = "android", target_os = "linux"))]
+        self.state.set_interrupt(Some(interrupt));
+
+        #[cfg(any(target_os = "android", target_os = "linux"))]
+        let mut rx_steering = {
+            let hash_report =
+                self.acked_features & (1 << virtio_net::VIRTIO_NET_F_HASH_REPORT) != 0;
+            self.state.set_hash_report(hash_report);
+            if self.avail_features & (1 << virtio_net::VIRTIO_NET_F_HASH_REPORT) != 0 {
+                let vnet_hdr_len = self.state.vnet_hdr_len();
+                for tap in &self.taps {
+                    tap.set_vnet_hdr_size(vnet_hdr_len)
+                        .context("net: failed to set vnet header size")?;
+                }
+            }
+            let rss_enabled = self.acked_features & (1 << virtio_net::VIRTIO_NET_F_RSS) != 0;
+            if rss_enabled && vq_pairs > 1 {
+                RxSteering::new(vq_pairs)
+                    .context("net: failed to create rx steering")?
+                    .into_iter()
+                    .map(Some)
+                    .collect()
+            } else {
+                vec![None; vq_pairs]
+            }
+        };
+
         for i in 0..vq_pairs {
             let tap = self.taps.remove(0);
             let acked_features = self.acked_features;
@@ -694,6 +1150,10 @@ where
             let pairs = vq_pairs as u16;
             #[cfg(windows)]
             let overlapped_wrapper = OverlappedWrapper::new(true).unwrap();
+            #[cfg(any(target_os = "android", target_os = "linux"))]
+            let state = self.state.clone();
+            #[cfg(any(target_os = "android", target_os = "linux"))]
+            let rx_steering = rx_steering.remove(0);
             self.worker_threads
                 .push(WorkerThread::start(format!("v_net:{i}"), move |kill_evt| {
                     let mut worker = Worker {
@@ -711,6 +1171,10 @@ where
                         rx_count: 0,
                         #[cfg(windows)]
                         deferred_rx: false,
+                        #[cfg(any(target_os = "android", target_os = "linux"))]
+                        state,
+                        #[cfg(any(target_os = "android", target_os = "linux"))]
+                        rx_steering,
                         kill_evt,
                     };
                     let result = worker.run();
@@ -771,6 +1235,10 @@ where
         AnySnapshot::to_any(NetSnapshot {
             acked_features: self.acked_features,
             avail_features: self.avail_features,
+            #[cfg(any(target_os = "android", target_os = "linux"))]
+            rx_filter: Some(self.state.rx_filter.lock().clone()),
+            #[cfg(any(target_os = "android", target_os = "linux"))]
+            rss: self.state.rss.lock().clone(),
         })
         .context("failed to snapshot virtio Net device")
     }
@@ -778,13 +1246,23 @@ where
     fn virtio_restore(&mut self, data: AnySnapshot) -> anyhow::Result<()> {
         let deser: NetSnapshot =
             AnySnapshot::from_any(data).context("failed to deserialize Net device")?;
+        // Snapshots taken before a feature was added to the device don't offer it. They can still
+        // be restored, as long as the device keeps offering only what the snapshot had.
+        let missing_features = deser.avail_features & !self.avail_features;
         anyhow::ensure!(
-            self.avail_features == deser.avail_features,
-            "Available features for net device do not match. expected: {},  got: {}",
-            deser.avail_features,
-            self.avail_features
+            missing_features == 0,
+            "net device doesn't support features of the snapshot: {:#x}",
+            missing_features
         );
+        self.avail_features = deser.avail_features;
         self.acked_features = deser.acked_features;
+        #[cfg(any(target_os = "android", target_os = "linux"))]
+        {
+            *self.state.rx_filter.lock() = deser
+                .rx_filter
+                .unwrap_or_else(|| RxFilter::new(self.guest_mac));
+            *self.state.rss.lock() = deser.rss;
+        }
         Ok(())
     }
 
@@ -794,8 +1272,27 @@ where
             self.taps.push(worker.tap);
         }
 
+        #[cfg(any(target_os = "android", target_os = "linux"))]
+        {
+            self.state.set_interrupt(None);
+            *self.state.rx_filter.lock() = RxFilter::new(self.guest_mac);
+            *self.state.rss.lock() = None;
+        }
+
         Ok(())
     }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn on_device_sandboxed(&mut self) {
+        if let Some(tube) = self.control_tube.take() {
+            let state = self.state.clone();
+            self.control_worker = Some(WorkerThread::start("v_net_ctrl", move |kill_evt| {
+                run_device_control_worker("net", tube, kill_evt, |command| {
+                    control::handle_command(&state, command)
+                })
+            }));
+        }
+    }
 }
 
 impl<T> std::fmt::Debug for Net<T>
@@ -836,6 +1333,8 @@ mod tests {
             NetParameters {
                 #[cfg(any(target_os = "android", target_os = "linux"))]
                 vhost_net: None,
+                #[cfg(any(target_os = "android", target_os = "linux"))]
+                capture: None,
                 vq_pairs: None,
                 mode: NetParametersMode::TapName {
                     tap_name: "tap".to_string(),
@@ -852,6 +1351,8 @@ mod tests {
             NetParameters {
                 #[cfg(any(target_os = "android", target_os = "linux"))]
                 vhost_net: None,
+                #[cfg(any(target_os = "android", target_os = "linux"))]
+                capture: None,
                 vq_pairs: None,
                 mode: NetParametersMode::TapName {
                     tap_name: "tap".to_string(),
@@ -868,6 +1369,8 @@ mod tests {
             NetParameters {
                 #[cfg(any(target_os = "android", target_os = "linux"))]
                 vhost_net: None,
+                #[cfg(any(target_os = "android", target_os = "linux"))]
+                capture: None,
                 vq_pairs: None,
                 mode: NetParametersMode::TapFd {
                     tap_fd: 12,
@@ -884,6 +1387,8 @@ mod tests {
             NetParameters {
                 #[cfg(any(target_os = "android", target_os = "linux"))]
                 vhost_net: None,
+                #[cfg(any(target_os = "android", target_os = "linux"))]
+                capture: None,
                 vq_pairs: None,
                 mode: NetParametersMode::TapFd {
                     tap_fd: 12,
@@ -903,6 +1408,8 @@ mod tests {
             NetParameters {
                 #[cfg(any(target_os = "android", target_os = "linux"))]
                 vhost_net: None,
+                #[cfg(any(target_os = "android", target_os = "linux"))]
+                capture: None,
                 vq_pairs: None,
                 mode: NetParametersMode::RawConfig {
                     host_ip: Ipv4Addr::from_str("192.168.10.1").unwrap(),
@@ -920,6 +1427,8 @@ mod tests {
             NetParameters {
                 #[cfg(any(target_os = "android", target_os = "linux"))]
                 vhost_net: None,
+                #[cfg(any(target_os = "android", target_os = "linux"))]
+                capture: None,
                 vq_pairs: None,
                 mode: NetParametersMode::TapFd {
                     tap_fd: 12,
@@ -960,6 +1469,8 @@ mod tests {
                 vhost_net: Some(VhostNetParameters {
                     device: PathBuf::from("/dev/foo")
                 }),
+                #[cfg(any(target_os = "android", target_os = "linux"))]
+                capture: None,
                 vq_pairs: None,
                 mode: NetParametersMode::RawConfig {
                     host_ip: Ipv4Addr::from_str("192.168.10.1").unwrap(),
@@ -976,6 +1487,8 @@ mod tests {
             params,
             NetParameters {
                 vhost_net: Some(Default::default()),
+                #[cfg(any(target_os = "android", target_os = "linux"))]
+                capture: None,
                 vq_pairs: None,
                 mode: NetParametersMode::TapFd {
                     tap_fd: 3,
@@ -991,6 +1504,8 @@ mod tests {
             params,
             NetParameters {
                 vhost_net: Some(Default::default()),
+                #[cfg(any(target_os = "android", target_os = "linux"))]
+                capture: None,
                 vq_pairs: None,
                 mode: NetParametersMode::TapName {
                     tap_name: "crosvm_tap".to_owned(),
@@ -1007,6 +1522,8 @@ mod tests {
             params,
             NetParameters {
                 vhost_net: Some(Default::default()),
+                #[cfg(any(target_os = "android", target_os = "linux"))]
+                capture: None,
                 vq_pairs: None,
                 mode: NetParametersMode::TapName {
                     tap_name: "crosvm_tap".to_owned(),
@@ -1023,6 +1540,8 @@ mod tests {
             NetParameters {
                 #[cfg(any(target_os = "android", target_os = "linux"))]
                 vhost_net: None,
+                #[cfg(any(target_os = "android", target_os = "linux"))]
+                capture: None,
                 vq_pairs: None,
                 mode: NetParametersMode::TapName {
                     tap_name: "tap".to_string(),
@@ -1039,6 +1558,8 @@ mod tests {
             NetParameters {
                 #[cfg(any(target_os = "android", target_os = "linux"))]
                 vhost_net: None,
+                #[cfg(any(target_os = "android", target_os = "linux"))]
+                capture: None,
                 vq_pairs: None,
                 mode: NetParametersMode::TapName {
                     tap_name: "tap".to_string(),
@@ -1054,6 +1575,8 @@ mod tests {
             params,
             NetParameters {
                 vhost_net: Some(Default::default()),
+                #[cfg(any(target_os = "android", target_os = "linux"))]
+                capture: None,
                 vq_pairs: None,
                 mode: NetParametersMode::TapName {
                     tap_name: "crosvm_tap".to_owned(),
@@ -1078,4 +1601,209 @@ mod tests {
         )
         .is_err());
     }
+
+    #[test]
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn params_from_key_values_socket() {
+        let params = from_net_arg("socket=/run/switch.sock").unwrap();
+        assert_eq!(
+            params,
+            NetParameters {
+                vhost_net: None,
+                capture: None,
+                vq_pairs: None,
+                mode: NetParametersMode::Socket {
+                    socket: PathBuf::from("/run/switch.sock"),
+                    mac: None,
+                },
+                packed_queue: false,
+                pci_address: None,
+            }
+        );
+
+        let params = from_net_arg("socket-fd=3,mac=\"3d:70:eb:61:1a:91\"").unwrap();
+        assert_eq!(
+            params.mode,
+            NetParametersMode::SocketFd {
+                socket_fd: 3,
+                mac: Some(MacAddress::from_str("3d:70:eb:61:1a:91").unwrap()),
+            }
+        );
+
+        // a socket replaces the tap
+        assert!(from_net_arg("socket=/run/switch.sock,tap-fd=3").is_err());
+    }
+
+    #[test]
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn params_from_key_values_capture() {
+        let params = from_net_arg("tap-name=tap,capture=/tmp/net0.pcapng").unwrap();
+        assert_eq!(params.capture, Some(PathBuf::from("/tmp/net0.pcapng")));
+
+        let params = from_net_arg("tap-name=tap").unwrap();
+        assert_eq!(params.capture, None);
+    }
+
+    #[test]
+    #[cfg(all(feature = "slirp", any(target_os = "android", target_os = "linux")))]
+    fn params_from_key_values_slirp() {
+        let params = from_net_arg("slirp").unwrap();
+        assert_eq!(
+            params,
+            NetParameters {
+                vhost_net: None,
+                capture: None,
+                vq_pairs: None,
+                mode: NetParametersMode::Slirp {
+                    slirp: true,
+                    host_fwd: Vec::new(),
+                    mac: None,
+                },
+                packed_queue: false,
+                pci_address: None,
+            }
+        );
+
+        let params = from_net_arg(
+            "slirp,host-fwd=[tcp::2222-:22,udp:127.0.0.1:5353-10.0.2.15:53],\
+            mac=\"3d:70:eb:61:1a:91\"",
+        )
+        .unwrap();
+        assert_eq!(
+            params.mode,
+            NetParametersMode::Slirp {
+                slirp: true,
+                host_fwd: vec![
+                    "tcp::2222-:22".parse().unwrap(),
+                    "udp:127.0.0.1:5353-10.0.2.15:53".parse().unwrap(),
+                ],
+                mac: Some(MacAddress::from_str("3d:70:eb:61:1a:91").unwrap()),
+            }
+        );
+
+        // host-fwd only makes sense with slirp
+        assert!(from_net_arg("tap-name=tap,host-fwd=[tcp::2222-:22]").is_err());
+        assert!(from_net_arg("slirp,host-fwd=[tcp::2222]").is_err());
+        assert!(from_net_arg("slirp=false").is_err());
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    #[test]
+    fn rx_filter_mac_table_set() {
+        use vm_memory::GuestAddress;
+
+        use crate::virtio::create_descriptor_chain;
+        use crate::virtio::DescriptorType;
+
+        let unicast = [0x52, 0x54, 0x00, 0x12, 0x34, 0x57];
+        let multicast = [0x01, 0x00, 0x5e, 0x00, 0x00, 0x01];
+        let mut request = vec![
+            VIRTIO_NET_CTRL_MAC as u8,
+            VIRTIO_NET_CTRL_MAC_TABLE_SET as u8,
+        ];
+        request.extend_from_slice(&1u32.to_le_bytes());
+        request.extend_from_slice(&unicast);
+        request.extend_from_slice(&1u32.to_le_bytes());
+        request.extend_from_slice(&multicast);
+
+        let memory = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        memory
+            .write_all_at_addr(&request, GuestAddress(0x100))
+            .unwrap();
+        let mut chain = create_descriptor_chain(
+            &memory,
+            GuestAddress(0),
+            GuestAddress(0x100),
+            vec![(DescriptorType::Readable, request.len() as u32)],
+            0,
+        )
+        .unwrap();
+
+        let rx_filter = Mutex::new(RxFilter::new(None));
+        let ctrl_hdr: virtio_net_ctrl_hdr = chain.reader.read_obj().unwrap();
+        // The MAC tables can only be set once VIRTIO_NET_F_CTRL_RX is negotiated.
+        let acked_features = 1 << virtio_net::VIRTIO_NET_F_CTRL_RX;
+        process_rx_filter_request(&mut chain.reader, ctrl_hdr, acked_features, &rx_filter).unwrap();
+
+        let mut expected = RxFilter::new(None);
+        expected.set_mac_tables(vec![unicast], vec![multicast]);
+        assert_eq!(*rx_filter.lock(), expected);
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    #[test]
+    fn guest_offloads_need_feature() {
+        use net_util::sys::linux::SocketTap;
+        use vm_memory::GuestAddress;
+
+        use crate::virtio::create_descriptor_chain;
+        use crate::virtio::DescriptorType;
+
+        let mut request = vec![
+            VIRTIO_NET_CTRL_GUEST_OFFLOADS as u8,
+            VIRTIO_NET_CTRL_GUEST_OFFLOADS_SET as u8,
+        ];
+        request.extend_from_slice(&(1u64 << virtio_net::VIRTIO_NET_F_GUEST_CSUM).to_le_bytes());
+
+        let memory = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        memory
+            .write_all_at_addr(&request, GuestAddress(0x100))
+            .unwrap();
+        let chain = || {
+            create_descriptor_chain(
+                &memory,
+                GuestAddress(0),
+                GuestAddress(0x100),
+                vec![(DescriptorType::Readable, request.len() as u32)],
+                0,
+            )
+            .unwrap()
+        };
+        let (socket, _peer) = std::os::unix::net::UnixDatagram::pair().unwrap();
+        let mut tap = SocketTap::from_socket(std::os::fd::OwnedFd::from(socket).into()).unwrap();
+
+        assert!(matches!(
+            process_ctrl_request(&mut chain().reader, &mut tap, 0, 1, None),
+            Err(NetError::InvalidCmd)
+        ));
+        // The socket backend can't offload, which is reported to the guest rather than panicking.
+        let acked_features = 1 << virtio_net::VIRTIO_NET_F_CTRL_GUEST_OFFLOADS;
+        assert!(matches!(
+            process_ctrl_request(&mut chain().reader, &mut tap, acked_features, 1, None),
+            Err(NetError::TapSetOffload(_))
+        ));
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    #[test]
+    fn restore_snapshot_with_fewer_features() {
+        use net_util::sys::linux::SocketTap;
+
+        let (socket, _peer) = std::os::unix::net::UnixDatagram::pair().unwrap();
+        let tap = SocketTap::from_socket(std::os::fd::OwnedFd::from(socket).into()).unwrap();
+        let mut net = Net::new_without_offloads(0, tap, None, false, None).unwrap();
+        let features = net.features();
+
+        // A snapshot of a device from before the rx filter existed.
+        let old_features = features & !RX_FILTER_FEATURES;
+        let snapshot = NetSnapshot {
+            avail_features: old_features,
+            acked_features: old_features,
+            rx_filter: None,
+            rss: None,
+        };
+        net.virtio_restore(AnySnapshot::to_any(snapshot).unwrap())
+            .unwrap();
+        assert_eq!(net.features(), old_features);
+
+        let snapshot = NetSnapshot {
+            avail_features: features | 1 << virtio_net::VIRTIO_NET_F_HASH_REPORT,
+            acked_features: 0,
+            rx_filter: None,
+            rss: None,
+        };
+        assert!(net
+            .virtio_restore(AnySnapshot::to_any(snapshot).unwrap())
+            .is_err());
+    }
 }
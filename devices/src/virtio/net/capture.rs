@@ -0,0 +1,230 @@
+// Copyright 2025 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Capture of the frames exchanged by a virtio-net device, written in the pcapng format.
+//!
+//! The capture file has a single Ethernet interface, the guest's network card. Frames received by
+//! the guest are recorded as inbound and frames sent by the guest as outbound.
+
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use base::warn;
+use base::AsRawDescriptor;
+use base::RawDescriptor;
+use sync::Mutex;
+
+const BLOCK_TYPE_SECTION_HEADER: u32 = 0x0a0d_0d0a;
+const BLOCK_TYPE_INTERFACE_DESCRIPTION: u32 = 1;
+const BLOCK_TYPE_ENHANCED_PACKET: u32 = 6;
+const BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+const LINKTYPE_ETHERNET: u16 = 1;
+const OPT_ENDOFOPT: u16 = 0;
+const OPT_EPB_FLAGS: u16 = 2;
+
+/// Direction of a captured frame, from the point of view of the guest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// Received by the guest.
+    Inbound,
+    /// Sent by the guest.
+    Outbound,
+}
+
+impl Direction {
+    // Value of the direction bits of the `epb_flags` option.
+    fn epb_flags(self) -> u32 {
+        match self {
+            Direction::Inbound => 1,
+            Direction::Outbound => 2,
+        }
+    }
+}
+
+/// Handle to the packet capture of a virtio-net device, shared by its workers.
+///
+/// Cloned handles refer to the same capture.
+#[derive(Clone, Default)]
+pub struct PacketCapture {
+    // Checked for every frame, so that frames aren't copied when no capture is in progress.
+    active: Arc<AtomicBool>,
+    file: Arc<Mutex<Option<File>>>,
+}
+
+impl PacketCapture {
+    /// Starts writing the frames to `file`, replacing the capture in progress if any.
+    pub fn start(&self, mut file: File) -> io::Result<()> {
+        let mut headers = Vec::new();
+        write_section_header(&mut headers);
+        write_interface_description(&mut headers);
+        file.write_all(&headers)?;
+
+        *self.file.lock() = Some(file);
+        self.active.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    /// Stops the capture in progress, if any.
+    pub fn stop(&self) {
+        self.active.store(false, Ordering::Release);
+        *self.file.lock() = None;
+    }
+
+    /// Returns whether frames are being captured.
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::Acquire)
+    }
+
+    /// Records `frame`, which starts with the virtio-net header of `vnet_hdr_len` bytes. The
+    /// capture is stopped if the frame can't be written.
+    pub fn record(&self, direction: Direction, frame: &[u8], vnet_hdr_len: usize) {
+        let mut file = self.file.lock();
+        let Some(f) = file.as_mut() else {
+            return;
+        };
+        let Some(frame) = frame.get(vnet_hdr_len..) else {
+            return;
+        };
+
+        let mut block = Vec::with_capacity(frame.len() + 48);
+        write_enhanced_packet(&mut block, direction, frame, SystemTime::now());
+        if let Err(e) = f.write_all(&block) {
+            warn!(
+                "net: stopping packet capture after failing to write a frame: {}",
+                e
+            );
+            self.active.store(false, Ordering::Release);
+            *file = None;
+        }
+    }
+
+    /// Returns the descriptor of the capture file, if a capture is in progress.
+    pub fn as_raw_descriptor(&self) -> Option<RawDescriptor> {
+        self.file.lock().as_ref().map(|f| f.as_raw_descriptor())
+    }
+}
+
+// Appends a block with the given type and body to `buf`. The body must be padded to 32 bits.
+fn write_block(buf: &mut Vec<u8>, block_type: u32, body: &[u8]) {
+    debug_assert_eq!(body.len() % 4, 0);
+    let len = (body.len() + 12) as u32;
+    buf.extend_from_slice(&block_type.to_le_bytes());
+    buf.extend_from_slice(&len.to_le_bytes());
+    buf.extend_from_slice(body);
+    buf.extend_from_slice(&len.to_le_bytes());
+}
+
+fn write_section_header(buf: &mut Vec<u8>) {
+    let mut body = Vec::new();
+    body.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+    // Version 1.0.
+    body.extend_from_slice(&1u16.to_le_bytes());
+    body.extend_from_slice(&0u16.to_le_bytes());
+    // The length of the section isn't known in advance.
+    body.extend_from_slice(&(-1i64).to_le_bytes());
+    write_block(buf, BLOCK_TYPE_SECTION_HEADER, &body);
+}
+
+fn write_interface_description(buf: &mut Vec<u8>) {
+    let mut body = Vec::new();
+    body.extend_from_slice(&LINKTYPE_ETHERNET.to_le_bytes());
+    // Reserved.
+    body.extend_from_slice(&0u16.to_le_bytes());
+    // Frames are never truncated. Timestamps use the default resolution of microseconds.
+    body.extend_from_slice(&0u32.to_le_bytes());
+    write_block(buf, BLOCK_TYPE_INTERFACE_DESCRIPTION, &body);
+}
+
+fn write_enhanced_packet(buf: &mut Vec<u8>, direction: Direction, frame: &[u8], time: SystemTime) {
+    let timestamp = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64;
+    let mut body = Vec::with_capacity(frame.len() + 36);
+    // Interface id.
+    body.extend_from_slice(&0u32.to_le_bytes());
+    body.extend_from_slice(&((timestamp >> 32) as u32).to_le_bytes());
+    body.extend_from_slice(&(timestamp as u32).to_le_bytes());
+    // Captured and original lengths.
+    body.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+    body.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+    body.extend_from_slice(frame);
+    body.resize(body.len().next_multiple_of(4), 0);
+    body.extend_from_slice(&OPT_EPB_FLAGS.to_le_bytes());
+    body.extend_from_slice(&4u16.to_le_bytes());
+    body.extend_from_slice(&direction.epb_flags().to_le_bytes());
+    body.extend_from_slice(&OPT_ENDOFOPT.to_le_bytes());
+    body.extend_from_slice(&0u16.to_le_bytes());
+    write_block(buf, BLOCK_TYPE_ENHANCED_PACKET, &body);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::io::Seek;
+    use std::time::Duration;
+
+    use super::*;
+
+    fn u32_at(buf: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn enhanced_packet_layout() {
+        let mut buf = Vec::new();
+        let time = UNIX_EPOCH + Duration::from_micros(0x1_0000_0002);
+        write_enhanced_packet(&mut buf, Direction::Outbound, &[0xaa; 5], time);
+
+        // 28 bytes of header, 8 bytes of padded frame, 12 bytes of options and the trailing length.
+        assert_eq!(buf.len(), 52);
+        assert_eq!(u32_at(&buf, 0), BLOCK_TYPE_ENHANCED_PACKET);
+        assert_eq!(u32_at(&buf, 4), 52);
+        assert_eq!(u32_at(&buf, 12), 1);
+        assert_eq!(u32_at(&buf, 16), 2);
+        assert_eq!(u32_at(&buf, 20), 5);
+        assert_eq!(u32_at(&buf, 24), 5);
+        assert_eq!(&buf[28..36], &[0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0, 0, 0]);
+        // epb_flags option with the outbound direction.
+        assert_eq!(u32_at(&buf, 36), 0x0004_0002);
+        assert_eq!(u32_at(&buf, 40), 2);
+        assert_eq!(u32_at(&buf, 44), 0);
+        assert_eq!(u32_at(&buf, 48), 52);
+    }
+
+    #[test]
+    fn capture_strips_vnet_header() {
+        let capture = PacketCapture::default();
+        assert!(!capture.is_active());
+        // Frames are ignored while no capture is in progress.
+        capture.record(Direction::Inbound, &[0; 16], 12);
+
+        let mut file = tempfile::tempfile().unwrap();
+        capture.start(file.try_clone().unwrap()).unwrap();
+        assert!(capture.is_active());
+        capture.record(Direction::Inbound, &[0, 0, 0, 0, 1, 2, 3, 4], 4);
+        capture.stop();
+        assert!(!capture.is_active());
+        capture.record(Direction::Outbound, &[0; 16], 12);
+
+        let mut contents = Vec::new();
+        file.rewind().unwrap();
+        file.read_to_end(&mut contents).unwrap();
+        // Section header, interface description and a single packet.
+        assert_eq!(contents.len(), 28 + 20 + 48);
+        assert_eq!(u32_at(&contents, 0), BLOCK_TYPE_SECTION_HEADER);
+        assert_eq!(u32_at(&contents, 8), BYTE_ORDER_MAGIC);
+        assert_eq!(u32_at(&contents, 28), BLOCK_TYPE_INTERFACE_DESCRIPTION);
+        assert_eq!(u32_at(&contents, 48), BLOCK_TYPE_ENHANCED_PACKET);
+        assert_eq!(u32_at(&contents, 48 + 20), 4);
+        assert_eq!(&contents[48 + 28..48 + 32], &[1, 2, 3, 4]);
+        assert_eq!(u32_at(&contents, 48 + 36), 1);
+    }
+}
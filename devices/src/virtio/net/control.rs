@@ -0,0 +1,123 @@
+// Copyright 2025 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! State of a virtio-net device that changes while it runs, either from the driver through the
+//! control queue or from the host through the control tube of the device.
+
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use base::error;
+use sync::Mutex;
+use virtio_sys::virtio_net::virtio_net_hdr_v1;
+use virtio_sys::virtio_net::virtio_net_hdr_v1_hash;
+use vm_control::NetDeviceCommand;
+use vm_control::NetDeviceResult;
+
+use super::PacketCapture;
+use super::RssConfig;
+use super::RxFilter;
+use crate::virtio::Interrupt;
+
+/// State shared by a virtio-net device, its workers and its control worker.
+///
+/// Cloned handles refer to the same state.
+#[derive(Clone)]
+pub struct SharedState {
+    pub capture: PacketCapture,
+    pub rx_filter: Arc<Mutex<RxFilter>>,
+    // Set by `VIRTIO_NET_CTRL_MQ_RSS_CONFIG` or `VIRTIO_NET_CTRL_MQ_HASH_CONFIG`.
+    pub rss: Arc<Mutex<Option<RssConfig>>>,
+    // Whether `VIRTIO_NET_F_HASH_REPORT` was negotiated when the device was activated.
+    hash_report: Arc<AtomicBool>,
+    link_down: Arc<AtomicBool>,
+    // Set while the device is activated, to notify the driver of link changes.
+    interrupt: Arc<Mutex<Option<Interrupt>>>,
+}
+
+impl SharedState {
+    /// Creates the state of a device whose address is `mac`, if known. The link is up.
+    pub fn new(mac: Option<[u8; 6]>) -> SharedState {
+        SharedState {
+            capture: PacketCapture::default(),
+            rx_filter: Arc::new(Mutex::new(RxFilter::new(mac))),
+            rss: Arc::new(Mutex::new(None)),
+            hash_report: Arc::new(AtomicBool::new(false)),
+            link_down: Arc::new(AtomicBool::new(false)),
+            interrupt: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Returns whether frames can be exchanged.
+    pub fn is_link_up(&self) -> bool {
+        !self.link_down.load(Ordering::Acquire)
+    }
+
+    /// Brings the link up or down, notifying the driver of the change if the device is active.
+    pub fn set_link_up(&self, up: bool) {
+        if self.link_down.swap(!up, Ordering::AcqRel) != up {
+            return;
+        }
+        if let Some(interrupt) = self.interrupt.lock().as_ref() {
+            interrupt.signal_config_changed();
+        }
+    }
+
+    /// Sets the interrupt used to notify the driver of link changes, or clears it when the device
+    /// is reset.
+    pub fn set_interrupt(&self, interrupt: Option<Interrupt>) {
+        *self.interrupt.lock() = interrupt;
+    }
+
+    /// Sets whether the hash of received frames is reported in their virtio-net header.
+    pub fn set_hash_report(&self, enabled: bool) {
+        self.hash_report.store(enabled, Ordering::Release);
+    }
+
+    /// Returns whether the hash of received frames is reported in their virtio-net header.
+    pub fn hash_report(&self) -> bool {
+        self.hash_report.load(Ordering::Acquire)
+    }
+
+    /// Returns the size of the virtio-net header of the frames exchanged with the driver.
+    pub fn vnet_hdr_len(&self) -> usize {
+        if self.hash_report() {
+            std::mem::size_of::<virtio_net_hdr_v1_hash>()
+        } else {
+            std::mem::size_of::<virtio_net_hdr_v1>()
+        }
+    }
+
+    /// Returns whether received frames must be inspected before they're passed to the driver,
+    /// rather than moved directly to its buffers.
+    pub fn inspects_rx(&self) -> bool {
+        self.capture.is_active()
+            || !self.is_link_up()
+            || self.hash_report()
+            || self.rss.lock().is_some()
+            || !self.rx_filter.lock().accepts_all()
+    }
+}
+
+/// Handles `command`, received on the control tube of the device with `state`.
+pub(super) fn handle_command(state: &SharedState, command: NetDeviceCommand) -> NetDeviceResult {
+    match command {
+        NetDeviceCommand::StartCapture { file } => match state.capture.start(file) {
+            Ok(()) => NetDeviceResult::Ok,
+            Err(e) => {
+                error!("net: failed to start packet capture: {}", e);
+                NetDeviceResult::Err(e.into())
+            }
+        },
+        NetDeviceCommand::StopCapture => {
+            state.capture.stop();
+            NetDeviceResult::Ok
+        }
+        NetDeviceCommand::SetLink { up } => {
+            state.set_link_up(up);
+            NetDeviceResult::Ok
+        }
+    }
+}
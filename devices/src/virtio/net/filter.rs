@@ -0,0 +1,252 @@
+// Copyright 2025 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Filtering of the frames received by a virtio-net device, as programmed by the driver through
+//! the `VIRTIO_NET_CTRL_RX`, `VIRTIO_NET_CTRL_MAC` and `VIRTIO_NET_CTRL_VLAN` classes of the
+//! control queue.
+
+use std::collections::BTreeSet;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+/// Number of addresses kept in each of the unicast and multicast MAC tables. Drivers that program
+/// more addresses than this get every frame of the corresponding kind.
+pub const MAC_TABLE_ENTRIES: usize = 64;
+/// Largest VLAN id that fits in the 12 bits of an 802.1Q tag.
+pub const MAX_VLAN_ID: u16 = 4095;
+
+const ETH_ALEN: usize = 6;
+const ETH_HLEN: usize = 14;
+const ETH_P_8021Q: u16 = 0x8100;
+const BROADCAST_ADDR: [u8; ETH_ALEN] = [0xff; ETH_ALEN];
+
+/// A table of MAC addresses the driver wants to receive frames for.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct MacTable {
+    entries: Vec<[u8; ETH_ALEN]>,
+    // The driver programmed more than `MAC_TABLE_ENTRIES` addresses.
+    overflow: bool,
+}
+
+impl MacTable {
+    fn set(&mut self, entries: Vec<[u8; ETH_ALEN]>) {
+        self.overflow = entries.len() > MAC_TABLE_ENTRIES;
+        self.entries = if self.overflow { Vec::new() } else { entries };
+    }
+
+    fn contains(&self, addr: &[u8; ETH_ALEN]) -> bool {
+        self.overflow || self.entries.contains(addr)
+    }
+}
+
+/// Receive filter of a virtio-net device.
+///
+/// A new filter accepts every frame, like a device whose driver doesn't use the control queue.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RxFilter {
+    mac: Option<[u8; ETH_ALEN]>,
+    promisc: bool,
+    all_multi: bool,
+    unicast: MacTable,
+    multicast: MacTable,
+    // Ids of the VLANs whose tagged frames are accepted, or `None` when tagged frames aren't
+    // filtered because `VIRTIO_NET_F_CTRL_VLAN` wasn't negotiated.
+    vlans: Option<BTreeSet<u16>>,
+}
+
+impl RxFilter {
+    /// Creates a filter for a device whose address is `mac`, if known.
+    pub fn new(mac: Option<[u8; ETH_ALEN]>) -> RxFilter {
+        RxFilter {
+            mac,
+            promisc: true,
+            all_multi: false,
+            unicast: MacTable::default(),
+            multicast: MacTable::default(),
+            vlans: None,
+        }
+    }
+
+    /// Returns the address of the device, as set by the driver or at creation.
+    pub fn mac(&self) -> Option<[u8; ETH_ALEN]> {
+        self.mac
+    }
+
+    /// Sets the address of the device.
+    pub fn set_mac(&mut self, mac: [u8; ETH_ALEN]) {
+        self.mac = Some(mac);
+    }
+
+    /// Sets whether every frame is accepted regardless of its destination.
+    pub fn set_promisc(&mut self, promisc: bool) {
+        self.promisc = promisc;
+    }
+
+    /// Sets whether every multicast frame is accepted.
+    pub fn set_all_multi(&mut self, all_multi: bool) {
+        self.all_multi = all_multi;
+    }
+
+    /// Replaces the unicast and multicast address tables.
+    pub fn set_mac_tables(&mut self, unicast: Vec<[u8; ETH_ALEN]>, multicast: Vec<[u8; ETH_ALEN]>) {
+        self.unicast.set(unicast);
+        self.multicast.set(multicast);
+    }
+
+    /// Starts or stops filtering tagged frames by VLAN id. No VLAN is accepted when filtering
+    /// starts.
+    pub fn set_vlan_filtering(&mut self, enabled: bool) {
+        self.vlans = enabled.then(BTreeSet::new);
+    }
+
+    /// Returns whether tagged frames are filtered by VLAN id.
+    pub fn filters_vlans(&self) -> bool {
+        self.vlans.is_some()
+    }
+
+    /// Accepts the frames tagged with `vid`. Returns false if VLANs aren't filtered.
+    pub fn add_vlan(&mut self, vid: u16) -> bool {
+        match &mut self.vlans {
+            Some(vlans) => {
+                vlans.insert(vid);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Stops accepting the frames tagged with `vid`. Returns false if VLANs aren't filtered.
+    pub fn remove_vlan(&mut self, vid: u16) -> bool {
+        match &mut self.vlans {
+            Some(vlans) => {
+                vlans.remove(&vid);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns whether every frame is accepted, in which case frames don't need to be inspected.
+    pub fn accepts_all(&self) -> bool {
+        self.promisc && self.vlans.is_none()
+    }
+
+    /// Returns whether the Ethernet `frame`, without a virtio-net header, should be passed to
+    /// the driver.
+    pub fn accepts(&self, frame: &[u8]) -> bool {
+        // Leave runt frames to the driver.
+        if frame.len() < ETH_HLEN {
+            return true;
+        }
+
+        if let Some(vlans) = &self.vlans {
+            let ethertype = u16::from_be_bytes([frame[12], frame[13]]);
+            if ethertype == ETH_P_8021Q {
+                let Some(tci) = frame.get(14..16) else {
+                    return false;
+                };
+                let vid = u16::from_be_bytes([tci[0], tci[1]]) & MAX_VLAN_ID;
+                if !vlans.contains(&vid) {
+                    return false;
+                }
+            }
+        }
+
+        if self.promisc {
+            return true;
+        }
+
+        let mut dest = [0u8; ETH_ALEN];
+        dest.copy_from_slice(&frame[..ETH_ALEN]);
+        if dest == BROADCAST_ADDR {
+            true
+        } else if dest[0] & 1 != 0 {
+            self.all_multi || self.multicast.contains(&dest)
+        } else {
+            self.mac == Some(dest) || self.mac.is_none() || self.unicast.contains(&dest)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+    const OTHER_MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x57];
+    const MULTICAST: [u8; 6] = [0x01, 0x00, 0x5e, 0x00, 0x00, 0x01];
+
+    fn frame(dest: [u8; 6], vid: Option<u16>) -> Vec<u8> {
+        let mut frame = dest.to_vec();
+        frame.extend_from_slice(&OTHER_MAC);
+        if let Some(vid) = vid {
+            frame.extend_from_slice(&ETH_P_8021Q.to_be_bytes());
+            frame.extend_from_slice(&vid.to_be_bytes());
+        }
+        // IPv4 and some payload.
+        frame.extend_from_slice(&[0x08, 0x00]);
+        frame.extend_from_slice(&[0u8; 46]);
+        frame
+    }
+
+    #[test]
+    fn promisc_by_default() {
+        let filter = RxFilter::new(Some(MAC));
+        assert!(filter.accepts_all());
+        assert!(filter.accepts(&frame(OTHER_MAC, None)));
+        assert!(filter.accepts(&frame(MULTICAST, Some(10))));
+    }
+
+    #[test]
+    fn unicast_and_multicast_tables() {
+        let mut filter = RxFilter::new(Some(MAC));
+        filter.set_promisc(false);
+        assert!(!filter.accepts_all());
+        assert!(filter.accepts(&frame(MAC, None)));
+        assert!(filter.accepts(&frame(BROADCAST_ADDR, None)));
+        assert!(!filter.accepts(&frame(OTHER_MAC, None)));
+        assert!(!filter.accepts(&frame(MULTICAST, None)));
+
+        filter.set_mac_tables(vec![OTHER_MAC], vec![MULTICAST]);
+        assert!(filter.accepts(&frame(OTHER_MAC, None)));
+        assert!(filter.accepts(&frame(MULTICAST, None)));
+
+        filter.set_mac_tables(Vec::new(), Vec::new());
+        filter.set_all_multi(true);
+        assert!(!filter.accepts(&frame(OTHER_MAC, None)));
+        assert!(filter.accepts(&frame(MULTICAST, None)));
+
+        filter.set_mac(OTHER_MAC);
+        assert!(!filter.accepts(&frame(MAC, None)));
+        assert!(filter.accepts(&frame(OTHER_MAC, None)));
+    }
+
+    #[test]
+    fn mac_table_overflow() {
+        let mut filter = RxFilter::new(Some(MAC));
+        filter.set_promisc(false);
+        filter.set_mac_tables(vec![OTHER_MAC; MAC_TABLE_ENTRIES + 1], Vec::new());
+        assert!(filter.accepts(&frame([0x52, 0, 0, 0, 0, 1], None)));
+        assert!(!filter.accepts(&frame(MULTICAST, None)));
+    }
+
+    #[test]
+    fn vlan_filtering() {
+        let mut filter = RxFilter::new(Some(MAC));
+        assert!(!filter.add_vlan(10));
+        filter.set_vlan_filtering(true);
+        assert!(!filter.accepts_all());
+        assert!(filter.accepts(&frame(MAC, None)));
+        assert!(!filter.accepts(&frame(MAC, Some(10))));
+
+        assert!(filter.add_vlan(10));
+        // The priority bits of the tag are ignored.
+        assert!(filter.accepts(&frame(MAC, Some(0xe000 | 10))));
+        assert!(!filter.accepts(&frame(MAC, Some(11))));
+
+        assert!(filter.remove_vlan(10));
+        assert!(!filter.accepts(&frame(MAC, Some(10))));
+    }
+}
@@ -0,0 +1,335 @@
+// Copyright 2025 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Receive-side scaling and hash reporting, as configured by the driver with the
+//! `VIRTIO_NET_CTRL_MQ_RSS_CONFIG` and `VIRTIO_NET_CTRL_MQ_HASH_CONFIG` commands.
+//!
+//! Frames are hashed with the Toeplitz function over their addresses and ports. With receive-side
+//! scaling, the hash selects the receive queue of the frame through an indirection table, so the
+//! worker that read a frame hands it over to the worker of that queue with an [`RxSteering`].
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use base::Event;
+use serde::Deserialize;
+use serde::Serialize;
+use sync::Mutex;
+use virtio_sys::virtio_net::VIRTIO_NET_HASH_REPORT_IPv4;
+use virtio_sys::virtio_net::VIRTIO_NET_HASH_REPORT_IPv6;
+use virtio_sys::virtio_net::VIRTIO_NET_HASH_REPORT_TCPv4;
+use virtio_sys::virtio_net::VIRTIO_NET_HASH_REPORT_TCPv6;
+use virtio_sys::virtio_net::VIRTIO_NET_HASH_REPORT_UDPv4;
+use virtio_sys::virtio_net::VIRTIO_NET_HASH_REPORT_UDPv6;
+use virtio_sys::virtio_net::VIRTIO_NET_RSS_HASH_TYPE_IPv4;
+use virtio_sys::virtio_net::VIRTIO_NET_RSS_HASH_TYPE_IPv6;
+use virtio_sys::virtio_net::VIRTIO_NET_RSS_HASH_TYPE_TCPv4;
+use virtio_sys::virtio_net::VIRTIO_NET_RSS_HASH_TYPE_TCPv6;
+use virtio_sys::virtio_net::VIRTIO_NET_RSS_HASH_TYPE_UDPv4;
+use virtio_sys::virtio_net::VIRTIO_NET_RSS_HASH_TYPE_UDPv6;
+
+/// Largest hash key accepted from the driver, the size of the key of the Microsoft RSS
+/// specification.
+pub const RSS_MAX_KEY_SIZE: u8 = 40;
+/// Largest indirection table accepted from the driver.
+pub const RSS_MAX_INDIRECTION_TABLE_LENGTH: u16 = 128;
+/// Hash types that can be computed. Hashes over IPv6 extension headers aren't supported.
+pub const SUPPORTED_HASH_TYPES: u32 = VIRTIO_NET_RSS_HASH_TYPE_IPv4
+    | VIRTIO_NET_RSS_HASH_TYPE_TCPv4
+    | VIRTIO_NET_RSS_HASH_TYPE_UDPv4
+    | VIRTIO_NET_RSS_HASH_TYPE_IPv6
+    | VIRTIO_NET_RSS_HASH_TYPE_TCPv6
+    | VIRTIO_NET_RSS_HASH_TYPE_UDPv6;
+
+// Number of frames waiting for a receive queue beyond which frames steered to it are dropped.
+const MAX_STEERED_FRAMES: usize = 256;
+
+const ETH_HLEN: usize = 14;
+const ETH_P_IP: u16 = 0x0800;
+const ETH_P_IPV6: u16 = 0x86dd;
+const ETH_P_8021Q: u16 = 0x8100;
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+const IPV6_HLEN: usize = 40;
+
+/// Computes the Toeplitz hash of `input` with `key`, as defined by the Microsoft RSS
+/// specification. Bits past the end of `key` are zeroes.
+pub fn toeplitz_hash(key: &[u8], input: &[u8]) -> u32 {
+    let key_bit = |bit: usize| {
+        key.get(bit / 8)
+            .map_or(0, |b| (b >> (7 - bit % 8)) as u32 & 1)
+    };
+    let mut window = (0..32).fold(0u32, |window, bit| window << 1 | key_bit(bit));
+    let mut hash = 0;
+    for (i, byte) in input.iter().enumerate() {
+        for bit in 0..8 {
+            if byte & (0x80 >> bit) != 0 {
+                hash ^= window;
+            }
+            window = window << 1 | key_bit(32 + i * 8 + bit);
+        }
+    }
+    hash
+}
+
+/// Hash configuration of a virtio-net device.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RssConfig {
+    hash_types: u32,
+    key: Vec<u8>,
+    // Empty unless frames are steered to queues, i.e. for `VIRTIO_NET_CTRL_MQ_HASH_CONFIG`.
+    indirection_table: Vec<u16>,
+    unclassified_queue: u16,
+}
+
+impl RssConfig {
+    /// Creates a configuration that hashes frames with the `hash_types` computable by the device
+    /// and steers them to the queues of `indirection_table`, whose length must be a power of two,
+    /// or to `unclassified_queue` when they have no hash.
+    ///
+    /// Frames aren't steered if `indirection_table` is empty.
+    pub fn new(
+        hash_types: u32,
+        key: Vec<u8>,
+        indirection_table: Vec<u16>,
+        unclassified_queue: u16,
+    ) -> RssConfig {
+        debug_assert!(indirection_table.is_empty() || indirection_table.len().is_power_of_two());
+        RssConfig {
+            hash_types: hash_types & SUPPORTED_HASH_TYPES,
+            key,
+            indirection_table,
+            unclassified_queue,
+        }
+    }
+
+    /// Returns the hash of the Ethernet `frame` and its `VIRTIO_NET_HASH_REPORT_*` type, or `None`
+    /// if the frame doesn't match any of the configured hash types.
+    pub fn hash(&self, frame: &[u8]) -> Option<(u32, u16)> {
+        let mut l3 = ETH_HLEN;
+        let mut ethertype = u16::from_be_bytes([*frame.get(12)?, *frame.get(13)?]);
+        if ethertype == ETH_P_8021Q {
+            l3 += 4;
+            ethertype = u16::from_be_bytes([*frame.get(16)?, *frame.get(17)?]);
+        }
+        let packet = frame.get(l3..)?;
+
+        // Fields hashed for the IP and the transport hash types, and the types themselves.
+        let (addrs, protocol, l4, types) = match ethertype {
+            ETH_P_IP => {
+                let ihl = (*packet.first()? & 0xf) as usize * 4;
+                let fragmented = u16::from_be_bytes([*packet.get(6)?, *packet.get(7)?]) & 0x3fff;
+                let protocol = if fragmented != 0 {
+                    None
+                } else {
+                    Some(*packet.get(9)?)
+                };
+                (
+                    packet.get(12..20)?,
+                    protocol,
+                    packet.get(ihl..),
+                    [
+                        (VIRTIO_NET_RSS_HASH_TYPE_TCPv4, VIRTIO_NET_HASH_REPORT_TCPv4),
+                        (VIRTIO_NET_RSS_HASH_TYPE_UDPv4, VIRTIO_NET_HASH_REPORT_UDPv4),
+                        (VIRTIO_NET_RSS_HASH_TYPE_IPv4, VIRTIO_NET_HASH_REPORT_IPv4),
+                    ],
+                )
+            }
+            ETH_P_IPV6 => (
+                packet.get(8..IPV6_HLEN)?,
+                Some(*packet.get(6)?),
+                packet.get(IPV6_HLEN..),
+                [
+                    (VIRTIO_NET_RSS_HASH_TYPE_TCPv6, VIRTIO_NET_HASH_REPORT_TCPv6),
+                    (VIRTIO_NET_RSS_HASH_TYPE_UDPv6, VIRTIO_NET_HASH_REPORT_UDPv6),
+                    (VIRTIO_NET_RSS_HASH_TYPE_IPv6, VIRTIO_NET_HASH_REPORT_IPv6),
+                ],
+            ),
+            _ => return None,
+        };
+        let [(tcp_type, tcp_report), (udp_type, udp_report), (ip_type, ip_report)] = types;
+
+        let ports = l4.and_then(|l4| l4.get(..4));
+        let mut input = addrs.to_vec();
+        let report = match (protocol, ports) {
+            (Some(IPPROTO_TCP), Some(ports)) if self.hash_types & tcp_type != 0 => {
+                input.extend_from_slice(ports);
+                tcp_report
+            }
+            (Some(IPPROTO_UDP), Some(ports)) if self.hash_types & udp_type != 0 => {
+                input.extend_from_slice(ports);
+                udp_report
+            }
+            _ if self.hash_types & ip_type != 0 => ip_report,
+            _ => return None,
+        };
+        Some((toeplitz_hash(&self.key, &input), report as u16))
+    }
+
+    /// Returns the queue a frame with `hash` is steered to, or `None` if frames aren't steered.
+    pub fn queue(&self, hash: Option<u32>) -> Option<usize> {
+        if self.indirection_table.is_empty() {
+            return None;
+        }
+        let queue = match hash {
+            Some(hash) => {
+                self.indirection_table[hash as usize & (self.indirection_table.len() - 1)]
+            }
+            None => self.unclassified_queue,
+        };
+        Some(queue as usize)
+    }
+}
+
+// Frames steered to a receive queue by the workers of the other queues.
+struct Inbox {
+    frames: Mutex<VecDeque<Vec<u8>>>,
+    // Signaled when frames are added.
+    event: Event,
+}
+
+/// Handle of the worker of a receive queue to the frames steered between the receive queues of a
+/// device.
+#[derive(Clone)]
+pub struct RxSteering {
+    index: usize,
+    inboxes: Arc<Vec<Inbox>>,
+}
+
+impl RxSteering {
+    /// Creates the handles of the workers of `queues` receive queues, in queue order.
+    pub fn new(queues: usize) -> base::Result<Vec<RxSteering>> {
+        let inboxes = (0..queues)
+            .map(|_| {
+                Ok(Inbox {
+                    frames: Mutex::new(VecDeque::new()),
+                    event: Event::new()?,
+                })
+            })
+            .collect::<base::Result<Vec<_>>>()?;
+        let inboxes = Arc::new(inboxes);
+        Ok((0..queues)
+            .map(|index| RxSteering {
+                index,
+                inboxes: inboxes.clone(),
+            })
+            .collect())
+    }
+
+    /// Returns the index of the receive queue of the worker.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Returns the event signaled when frames are steered to the receive queue of the worker.
+    pub fn event(&self) -> &Event {
+        &self.inboxes[self.index].event
+    }
+
+    /// Hands `frame` over to the worker of `queue`. The frame is dropped if there is no such
+    /// queue or if too many frames are already waiting for it.
+    pub fn steer(&self, queue: usize, frame: Vec<u8>) {
+        let Some(inbox) = self.inboxes.get(queue) else {
+            return;
+        };
+        let mut frames = inbox.frames.lock();
+        if frames.len() >= MAX_STEERED_FRAMES {
+            return;
+        }
+        frames.push_back(frame);
+        drop(frames);
+        let _ = inbox.event.signal();
+    }
+
+    /// Takes the oldest frame steered to the receive queue of the worker, if any.
+    pub fn take(&self) -> Option<Vec<u8>> {
+        self.inboxes[self.index].frames.lock().pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Key and verification suite of the Microsoft RSS specification.
+    const KEY: [u8; 40] = [
+        0x6d, 0x5a, 0x56, 0xda, 0x25, 0x5b, 0x0e, 0xc2, 0x41, 0x67, 0x25, 0x3d, 0x43, 0xa3, 0x8f,
+        0xb0, 0xd0, 0xca, 0x2b, 0xcb, 0xae, 0x7b, 0x30, 0xb4, 0x77, 0xcb, 0x2d, 0xa3, 0x80, 0x30,
+        0xf2, 0x0c, 0x6a, 0x42, 0xb7, 0x3b, 0xbe, 0xac, 0x01, 0xfa,
+    ];
+
+    fn tcpv4_frame(fragment: u16) -> Vec<u8> {
+        let mut frame = vec![0u8; ETH_HLEN];
+        frame[12..14].copy_from_slice(&ETH_P_IP.to_be_bytes());
+        let mut ip = [0u8; 20];
+        ip[0] = 0x45;
+        ip[6..8].copy_from_slice(&fragment.to_be_bytes());
+        ip[9] = IPPROTO_TCP;
+        // 66.9.149.187:2794 to 161.142.100.80:1766.
+        ip[12..16].copy_from_slice(&[66, 9, 149, 187]);
+        ip[16..20].copy_from_slice(&[161, 142, 100, 80]);
+        frame.extend_from_slice(&ip);
+        frame.extend_from_slice(&2794u16.to_be_bytes());
+        frame.extend_from_slice(&1766u16.to_be_bytes());
+        frame.extend_from_slice(&[0u8; 16]);
+        frame
+    }
+
+    #[test]
+    fn toeplitz_verification_suite() {
+        let addrs = [66, 9, 149, 187, 161, 142, 100, 80];
+        assert_eq!(toeplitz_hash(&KEY, &addrs), 0x323e8fc2);
+        let mut input = addrs.to_vec();
+        input.extend_from_slice(&[0x0a, 0xea, 0x06, 0xe6]);
+        assert_eq!(toeplitz_hash(&KEY, &input), 0x51ccc178);
+    }
+
+    #[test]
+    fn hash_types() {
+        let tcp = RssConfig::new(
+            VIRTIO_NET_RSS_HASH_TYPE_IPv4 | VIRTIO_NET_RSS_HASH_TYPE_TCPv4,
+            KEY.to_vec(),
+            Vec::new(),
+            0,
+        );
+        assert_eq!(
+            tcp.hash(&tcpv4_frame(0)),
+            Some((0x51ccc178, VIRTIO_NET_HASH_REPORT_TCPv4 as u16))
+        );
+        // Fragments don't have ports.
+        assert_eq!(
+            tcp.hash(&tcpv4_frame(0x2000)),
+            Some((0x323e8fc2, VIRTIO_NET_HASH_REPORT_IPv4 as u16))
+        );
+
+        let ip = RssConfig::new(VIRTIO_NET_RSS_HASH_TYPE_IPv4, KEY.to_vec(), Vec::new(), 0);
+        assert_eq!(
+            ip.hash(&tcpv4_frame(0)),
+            Some((0x323e8fc2, VIRTIO_NET_HASH_REPORT_IPv4 as u16))
+        );
+
+        let ipv6 = RssConfig::new(VIRTIO_NET_RSS_HASH_TYPE_IPv6, KEY.to_vec(), Vec::new(), 0);
+        assert_eq!(ipv6.hash(&tcpv4_frame(0)), None);
+    }
+
+    #[test]
+    fn steering() {
+        let hash_only = RssConfig::new(SUPPORTED_HASH_TYPES, KEY.to_vec(), Vec::new(), 0);
+        assert_eq!(hash_only.queue(Some(3)), None);
+
+        let rss = RssConfig::new(SUPPORTED_HASH_TYPES, KEY.to_vec(), vec![0, 1, 2, 3], 2);
+        assert_eq!(rss.queue(Some(0x51ccc178)), Some(0));
+        assert_eq!(rss.queue(Some(0x323e8fc3)), Some(3));
+        assert_eq!(rss.queue(None), Some(2));
+
+        let steering = RxSteering::new(2).unwrap();
+        steering[0].steer(1, vec![1]);
+        steering[0].steer(1, vec![2]);
+        steering[0].steer(2, vec![3]);
+        assert_eq!(steering[0].take(), None);
+        assert_eq!(steering[1].take(), Some(vec![1]));
+        assert_eq!(steering[1].take(), Some(vec![2]));
+        assert_eq!(steering[1].take(), None);
+    }
+}
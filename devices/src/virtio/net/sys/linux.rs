@@ -3,6 +3,8 @@
 // found in the LICENSE file.
 
 use std::io;
+use std::io::Read;
+use std::io::Write;
 use std::result;
 
 use base::error;
@@ -14,10 +16,15 @@ use net_util::TapT;
 use virtio_sys::virtio_net;
 use virtio_sys::virtio_net::virtio_net_hdr_v1;
 
+use super::super::super::net::Direction;
 use super::super::super::net::NetError;
+use super::super::super::net::RxSteering;
+use super::super::super::net::SharedState;
 use super::super::super::net::Token;
 use super::super::super::net::Worker;
 use super::super::super::Queue;
+use super::super::super::Reader;
+use super::super::super::Writer;
 
 // Ensure that the tap interface has the correct flags and sets the offload and VNET header size
 // to the appropriate values.
@@ -80,7 +87,109 @@ pub fn virtio_features_to_tap_offload(features: u64) -> u32 {
     tap_offloads
 }
 
-pub fn process_rx<T: TapT>(rx_queue: &mut Queue, mut tap: &mut T) -> result::Result<(), NetError> {
+// Reports the hash of `frame`, which starts with a `virtio_net_hdr_v1_hash`, in its header when
+// hash reporting is enabled, and returns the receive queue it is steered to, if any.
+fn hash_frame(frame: &mut [u8], state: &SharedState) -> Option<usize> {
+    let rss = state.rss.lock();
+    let config = rss.as_ref()?;
+    let hash = frame
+        .get(state.vnet_hdr_len()..)
+        .and_then(|eth_frame| config.hash(eth_frame));
+    if state.hash_report() {
+        let (value, report) = hash.unwrap_or_default();
+        let offset = std::mem::size_of::<virtio_net_hdr_v1>();
+        frame[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        frame[offset + 4..offset + 6].copy_from_slice(&report.to_le_bytes());
+    }
+    config.queue(hash.map(|(value, _)| value))
+}
+
+// Reads a frame from `tap` into `writer` through a bounce buffer, so it can be filtered, hashed
+// and captured. Frames that are dropped because of the link state or the receive filter, or that
+// are steered to the receive queue of another worker, aren't written to `writer`.
+fn read_frame_inspected<T: TapT>(
+    tap: &mut T,
+    writer: &mut Writer,
+    state: &SharedState,
+    steering: Option<&RxSteering>,
+) -> io::Result<()> {
+    let vnet_hdr_len = state.vnet_hdr_len();
+    let mut frame = vec![0u8; writer.available_bytes()];
+    let count = tap.read(&mut frame)?;
+    frame.truncate(count);
+    if !state.is_link_up() {
+        return Ok(());
+    }
+    if let Some(eth_frame) = frame.get(vnet_hdr_len..) {
+        if !state.rx_filter.lock().accepts(eth_frame) {
+            return Ok(());
+        }
+    }
+    let queue = hash_frame(&mut frame, state);
+    state
+        .capture
+        .record(Direction::Inbound, &frame, vnet_hdr_len);
+    match (queue, steering) {
+        (Some(queue), Some(steering)) if queue != steering.index() => {
+            steering.steer(queue, frame);
+            Ok(())
+        }
+        _ => writer.write_all(&frame),
+    }
+}
+
+// Writes the frame in `reader` to `tap` through a bounce buffer, so it can be captured.
+fn write_frame_captured<T: TapT>(
+    tap: &mut T,
+    reader: &mut Reader,
+    state: &SharedState,
+) -> io::Result<usize> {
+    let mut frame = vec![0u8; reader.available_bytes()];
+    reader.read_exact(&mut frame)?;
+    state
+        .capture
+        .record(Direction::Outbound, &frame, state.vnet_hdr_len());
+    tap.write(&frame)
+}
+
+// Moves the frames steered to `rx_queue` by the workers of the other receive queues into it, until
+// it runs out of buffers.
+fn receive_steered(rx_queue: &mut Queue, steering: &RxSteering) -> result::Result<(), NetError> {
+    let mut needs_interrupt = false;
+    while let Some(mut desc_chain) = rx_queue.peek() {
+        let Some(frame) = steering.take() else {
+            break;
+        };
+        if frame.len() > desc_chain.writer.available_bytes() {
+            warn!("net: rx: buffer is too small to hold steered frame");
+            continue;
+        }
+        desc_chain
+            .writer
+            .write_all(&frame)
+            .map_err(NetError::WriteBuffer)?;
+        let bytes_written = desc_chain.writer.bytes_written() as u32;
+        let desc_chain = desc_chain.pop();
+        rx_queue.add_used(desc_chain, bytes_written);
+        needs_interrupt = true;
+    }
+
+    if needs_interrupt {
+        rx_queue.trigger_interrupt();
+    }
+    Ok(())
+}
+
+/// Moves frames from `tap` to `rx_queue`. With a `state`, frames are dropped while the link is
+/// down or when the receive filter rejects them, hashed if receive-side scaling or hash reporting
+/// is configured, and recorded if a capture is active. With a `steering`, frames are steered to
+/// the receive queue selected by their hash.
+pub fn process_rx<T: TapT>(
+    rx_queue: &mut Queue,
+    mut tap: &mut T,
+    state: Option<&SharedState>,
+    steering: Option<&RxSteering>,
+) -> result::Result<(), NetError> {
     let mut needs_interrupt = false;
     let mut exhausted_queue = false;
 
@@ -96,7 +205,13 @@ pub fn process_rx<T: TapT>(rx_queue: &mut Queue, mut tap: &mut T) -> result::Res
 
         let writer = &mut desc_chain.writer;
 
-        match writer.write_from(&mut tap, writer.available_bytes()) {
+        let result = match state.filter(|state| state.inspects_rx()) {
+            Some(state) => read_frame_inspected(tap, writer, state, steering),
+            None => writer
+                .write_from(&mut tap, writer.available_bytes())
+                .map(|_| ()),
+        };
+        match result {
             Ok(_) => {}
             Err(ref e) if e.kind() == io::ErrorKind::WriteZero => {
                 warn!("net: rx: buffer is too small to hold frame");
@@ -133,11 +248,21 @@ pub fn process_rx<T: TapT>(rx_queue: &mut Queue, mut tap: &mut T) -> result::Res
     }
 }
 
-pub fn process_tx<T: TapT>(tx_queue: &mut Queue, mut tap: &mut T) {
+/// Moves frames from `tx_queue` to `tap`. With a `state`, frames are dropped while the link is
+/// down and recorded if a capture is active.
+pub fn process_tx<T: TapT>(tx_queue: &mut Queue, mut tap: &mut T, state: Option<&SharedState>) {
     while let Some(mut desc_chain) = tx_queue.pop() {
+        if state.is_some_and(|state| !state.is_link_up()) {
+            tx_queue.add_used(desc_chain, 0);
+            continue;
+        }
         let reader = &mut desc_chain.reader;
         let expected_count = reader.available_bytes();
-        match reader.read_to(&mut tap, expected_count) {
+        let result = match state.filter(|state| state.capture.is_active()) {
+            Some(state) => write_frame_captured(tap, reader, state),
+            None => reader.read_to(&mut tap, expected_count),
+        };
+        match result {
             Ok(count) => {
                 // Tap writes must be done in one call. If the entire frame was not
                 // written, it's an error.
@@ -182,6 +307,9 @@ where
         wait_ctx: &WaitContext<Token>,
         tap_polling_enabled: bool,
     ) -> result::Result<(), NetError> {
+        if let Some(steering) = &self.rx_steering {
+            receive_steered(&mut self.rx_queue, steering)?;
+        }
         if !tap_polling_enabled {
             wait_ctx
                 .modify(&self.tap, EventType::Read, Token::RxTap)
@@ -189,7 +317,25 @@ where
         }
         Ok(())
     }
+    pub(in crate::virtio) fn handle_rx_steered(&mut self) -> result::Result<(), NetError> {
+        let Some(steering) = &self.rx_steering else {
+            return Ok(());
+        };
+        if let Err(e) = steering.event().wait() {
+            error!("net: error reading rx steering Event: {}", e);
+        }
+        // Frames left for lack of buffers are received when the driver adds some.
+        receive_steered(&mut self.rx_queue, steering)
+    }
     pub(super) fn process_rx(&mut self) -> result::Result<(), NetError> {
-        process_rx(&mut self.rx_queue, &mut self.tap)
+        if let Some(steering) = &self.rx_steering {
+            receive_steered(&mut self.rx_queue, steering)?;
+        }
+        process_rx(
+            &mut self.rx_queue,
+            &mut self.tap,
+            Some(&self.state),
+            self.rx_steering.as_ref(),
+        )
     }
 }
@@ -3,6 +3,7 @@
 // found in the LICENSE file.
 
 use std::cmp;
+use std::io;
 use std::io::Read;
 use std::io::Write;
 
@@ -15,11 +16,26 @@ use zerocopy::Immutable;
 use zerocopy::IntoBytes;
 use zerocopy::KnownLayout;
 
+use crate::virtio::scsi::constants::GET_LBA_STATUS;
 use crate::virtio::scsi::constants::INQUIRY;
 use crate::virtio::scsi::constants::MAINTENANCE_IN;
 use crate::virtio::scsi::constants::MODE_SELECT_6;
+use crate::virtio::scsi::constants::MODE_SENSE_10;
 use crate::virtio::scsi::constants::MODE_SENSE_6;
+use crate::virtio::scsi::constants::PERSISTENT_RESERVE_IN;
+use crate::virtio::scsi::constants::PERSISTENT_RESERVE_OUT;
+use crate::virtio::scsi::constants::PR_CLEAR;
+use crate::virtio::scsi::constants::PR_PREEMPT;
+use crate::virtio::scsi::constants::PR_PREEMPT_AND_ABORT;
+use crate::virtio::scsi::constants::PR_READ_KEYS;
+use crate::virtio::scsi::constants::PR_READ_RESERVATION;
+use crate::virtio::scsi::constants::PR_REGISTER;
+use crate::virtio::scsi::constants::PR_REGISTER_AND_IGNORE_EXISTING_KEY;
+use crate::virtio::scsi::constants::PR_RELEASE;
+use crate::virtio::scsi::constants::PR_REPORT_CAPABILITIES;
+use crate::virtio::scsi::constants::PR_RESERVE;
 use crate::virtio::scsi::constants::READ_10;
+use crate::virtio::scsi::constants::READ_16;
 use crate::virtio::scsi::constants::READ_6;
 use crate::virtio::scsi::constants::READ_CAPACITY_10;
 use crate::virtio::scsi::constants::READ_CAPACITY_16;
@@ -30,7 +46,10 @@ use crate::virtio::scsi::constants::SYNCHRONIZE_CACHE_10;
 use crate::virtio::scsi::constants::TEST_UNIT_READY;
 use crate::virtio::scsi::constants::TYPE_DISK;
 use crate::virtio::scsi::constants::UNMAP;
+use crate::virtio::scsi::constants::VERIFY_10;
+use crate::virtio::scsi::constants::VERIFY_16;
 use crate::virtio::scsi::constants::WRITE_10;
+use crate::virtio::scsi::constants::WRITE_16;
 use crate::virtio::scsi::constants::WRITE_SAME_10;
 use crate::virtio::scsi::constants::WRITE_SAME_16;
 use crate::virtio::scsi::device::AsyncLogicalUnit;
@@ -47,11 +66,19 @@ pub enum Command {
     ModeSense6(ModeSense6),
     ReadCapacity10(ReadCapacity10),
     ReadCapacity16(ReadCapacity16),
+    GetLbaStatus(GetLbaStatus),
     Read10(Read10),
     Write10(Write10),
+    Verify10(Verify10),
     SynchronizeCache10(SynchronizeCache10),
     WriteSame10(WriteSame10),
     Unmap(Unmap),
+    ModeSense10(ModeSense10),
+    PersistentReserveIn(PersistentReserveIn),
+    PersistentReserveOut(PersistentReserveOut),
+    Read16(Read16),
+    Write16(Write16),
+    Verify16(Verify16),
     WriteSame16(WriteSame16),
     ReportLuns(ReportLuns),
     ReportSupportedTMFs(ReportSupportedTMFs),
@@ -69,9 +96,16 @@ impl Command {
             READ_CAPACITY_10 => Ok(Self::ReadCapacity10(Self::parse_command(cdb)?)),
             READ_10 => Ok(Self::Read10(Self::parse_command(cdb)?)),
             WRITE_10 => Ok(Self::Write10(Self::parse_command(cdb)?)),
+            VERIFY_10 => Ok(Self::Verify10(Self::parse_command(cdb)?)),
             SYNCHRONIZE_CACHE_10 => Ok(Self::SynchronizeCache10(Self::parse_command(cdb)?)),
             WRITE_SAME_10 => Ok(Self::WriteSame10(Self::parse_command(cdb)?)),
             UNMAP => Ok(Self::Unmap(Self::parse_command(cdb)?)),
+            MODE_SENSE_10 => Ok(Self::ModeSense10(Self::parse_command(cdb)?)),
+            PERSISTENT_RESERVE_IN => Ok(Self::PersistentReserveIn(Self::parse_command(cdb)?)),
+            PERSISTENT_RESERVE_OUT => Ok(Self::PersistentReserveOut(Self::parse_command(cdb)?)),
+            READ_16 => Ok(Self::Read16(Self::parse_command(cdb)?)),
+            WRITE_16 => Ok(Self::Write16(Self::parse_command(cdb)?)),
+            VERIFY_16 => Ok(Self::Verify16(Self::parse_command(cdb)?)),
             WRITE_SAME_16 => Ok(Self::WriteSame16(Self::parse_command(cdb)?)),
             SERVICE_ACTION_IN_16 => Self::parse_service_action_in_16(cdb),
             REPORT_LUNS => Ok(Self::ReportLuns(Self::parse_command(cdb)?)),
@@ -110,6 +144,7 @@ impl Command {
         let service_action = cdb[1] & 0x1f;
         match service_action {
             READ_CAPACITY_16 => Ok(Self::ReadCapacity16(Self::parse_command(cdb)?)),
+            GET_LBA_STATUS => Ok(Self::GetLbaStatus(Self::parse_command(cdb)?)),
             _ => {
                 warn!(
                     "service action {:#x?} for SERVICE_ACTION_IN_16 is not implemented",
@@ -125,6 +160,7 @@ impl Command {
         reader: &mut Reader,
         writer: &mut Writer,
         dev: &AsyncLogicalUnit,
+        luns: &[u16],
     ) -> Result<(), ExecuteError> {
         match self {
             Self::TestUnitReady(_) => Ok(()), // noop as the device is ready.
@@ -134,20 +170,46 @@ impl Command {
             Self::ModeSense6(mode_sense_6) => mode_sense_6.emulate(writer, dev),
             Self::ReadCapacity10(read_capacity_10) => read_capacity_10.emulate(writer, dev),
             Self::ReadCapacity16(read_capacity_16) => read_capacity_16.emulate(writer, dev),
+            Self::GetLbaStatus(get_lba_status) => get_lba_status.emulate(writer, dev),
             Self::Read10(read_10) => read_10.emulate(writer, dev).await,
             Self::Write10(write_10) => write_10.emulate(reader, dev).await,
+            Self::Verify10(verify_10) => verify_10.emulate(reader, dev).await,
             Self::SynchronizeCache10(synchronize_cache_10) => {
                 synchronize_cache_10.emulate(dev).await
             }
             Self::WriteSame10(write_same_10) => write_same_10.emulate(reader, dev).await,
             Self::Unmap(unmap) => unmap.emulate(reader, dev).await,
+            Self::ModeSense10(mode_sense_10) => mode_sense_10.emulate(writer, dev),
+            Self::PersistentReserveIn(persistent_reserve_in) => {
+                persistent_reserve_in.emulate(writer, dev)
+            }
+            Self::PersistentReserveOut(persistent_reserve_out) => {
+                persistent_reserve_out.emulate(reader, dev)
+            }
+            Self::Read16(read_16) => read_16.emulate(writer, dev).await,
+            Self::Write16(write_16) => write_16.emulate(reader, dev).await,
+            Self::Verify16(verify_16) => verify_16.emulate(reader, dev).await,
             Self::WriteSame16(write_same_16) => write_same_16.emulate(reader, dev).await,
-            Self::ReportLuns(report_luns) => report_luns.emulate(writer),
+            Self::ReportLuns(report_luns) => report_luns.emulate(writer, luns),
             Self::ReportSupportedTMFs(report_supported_tmfs) => {
                 report_supported_tmfs.emulate(writer)
             }
         }
     }
+
+    /// Executes a command addressed to a LUN that the target does not have. `luns` are the LUNs
+    /// that the target does have.
+    pub fn execute_without_unit(
+        &self,
+        writer: &mut Writer,
+        luns: &[u16],
+    ) -> Result<(), ExecuteError> {
+        match self {
+            Self::Inquiry(inquiry) => inquiry.emulate_without_unit(writer),
+            Self::ReportLuns(report_luns) => report_luns.emulate(writer, luns),
+            _ => Err(ExecuteError::LogicalUnitNotSupported),
+        }
+    }
 }
 
 #[derive(
@@ -302,6 +364,27 @@ impl Inquiry {
             .map_err(ExecuteError::Write)
     }
 
+    fn emulate_without_unit(&self, writer: &mut Writer) -> Result<(), ExecuteError> {
+        let _trace = cros_tracing::trace_event!(VirtioScsi, "INQUIRY");
+        if self.vital_product_data_enabled() || self.page_code() != 0 {
+            return Err(ExecuteError::InvalidField);
+        }
+        let alloc_len = self.alloc_len();
+        let mut outbuf = vec![0u8; cmp::max(36, alloc_len)];
+        // Peripheral qualifier 011b: the target is not capable of supporting a device on this
+        // logical unit. Peripheral device type 1Fh: unknown or no device type.
+        outbuf[0] = 0x7f;
+        // Version 0x5 indicates that the device complies to SPC-3.
+        outbuf[2] = 0x5;
+        // Hierarchical Support | Response Data Format
+        outbuf[3] = 0x10 | 0x2;
+        // Additional Length
+        outbuf[4] = 36 - 5;
+        writer
+            .write_all(&outbuf[..alloc_len])
+            .map_err(ExecuteError::Write)
+    }
+
     fn emulate_vital_product_data_page(
         &self,
         writer: &mut Writer,
@@ -597,13 +680,7 @@ impl ModeSense6 {
     }
 
     fn page_control(&self) -> Result<PageControl, ExecuteError> {
-        match self.page_control_and_page_code >> 6 {
-            0 => Ok(PageControl::Current),
-            1 => Ok(PageControl::Changable),
-            2 => Ok(PageControl::Default),
-            3 => Err(ExecuteError::SavingParamNotSupported),
-            _ => Err(ExecuteError::InvalidField),
-        }
+        parse_page_control(self.page_control_and_page_code)
     }
 
     fn subpage_code(&self) -> u8 {
@@ -637,80 +714,178 @@ impl ModeSense6 {
             4
         };
 
-        let page_control = self.page_control()?;
-        let page_code = self.page_code();
-        let subpage_code = self.subpage_code();
-        // The pair of the page code and the subpage code specifies which mode pages and subpages
-        // to return. Refer to the Table 99 in the SPC-3 spec for more details:
-        // <https://www.t10.org/cgi-bin/ac.pl?t=f&f=spc3r23.pdf>
-        match (page_code, subpage_code) {
-            // Return all mode pages with subpage 0.
-            (0x3f, 0x00) => {
-                Self::add_all_page_codes(subpage_code, page_control, &mut outbuf, &mut idx)
-            }
-            // Return all mode pages with subpages 0x00-0xfe.
-            (0x3f, 0xff) => {
-                for subpage_code in 0..0xff {
-                    Self::add_all_page_codes(subpage_code, page_control, &mut outbuf, &mut idx)
-                }
-            }
-            // subpage_code other than 0x00 or 0xff are reserved.
-            (0x3f, _) => return Err(ExecuteError::InvalidField),
-            // Return a specific mode page with subpages 0x00-0xfe.
-            (_, 0xff) => {
-                for subpage_code in 0..0xff {
-                    match fill_mode_page(
-                        page_code,
-                        subpage_code,
-                        page_control,
-                        &mut outbuf[idx as usize..],
-                    ) {
-                        Some(n) => idx += n,
-                        None => return Err(ExecuteError::InvalidField),
-                    };
-                }
-            }
-            (_, _) => {
-                match fill_mode_page(
-                    page_code,
-                    subpage_code,
-                    page_control,
-                    &mut outbuf[idx as usize..],
-                ) {
-                    Some(n) => idx += n,
-                    None => return Err(ExecuteError::InvalidField),
-                };
-            }
-        };
-        outbuf[0] = idx - 1;
+        add_mode_pages(
+            self.page_code(),
+            self.subpage_code(),
+            self.page_control()?,
+            &mut outbuf,
+            &mut idx,
+        )?;
+        outbuf[0] = (idx - 1).try_into().unwrap_or(u8::MAX);
         writer
             .write_all(&outbuf[..alloc_len])
             .map_err(ExecuteError::Write)
     }
+}
+
+fn parse_page_control(page_control_and_page_code: u8) -> Result<PageControl, ExecuteError> {
+    match page_control_and_page_code >> 6 {
+        0 => Ok(PageControl::Current),
+        1 => Ok(PageControl::Changable),
+        2 => Ok(PageControl::Default),
+        3 => Err(ExecuteError::SavingParamNotSupported),
+        _ => Err(ExecuteError::InvalidField),
+    }
+}
 
-    // Fill in mode pages with a specific subpage_code.
-    fn add_all_page_codes(
-        subpage_code: u8,
-        page_control: PageControl,
-        outbuf: &mut [u8],
-        idx: &mut u8,
-    ) {
-        for page_code in 1..0x3f {
-            if let Some(n) = fill_mode_page(
-                page_code,
-                subpage_code,
-                page_control,
-                &mut outbuf[*idx as usize..],
-            ) {
-                *idx += n;
+// Fill in the mode pages requested by MODE SENSE(6) or MODE SENSE(10) from `outbuf[*idx]`, and
+// advance `idx` past them.
+fn add_mode_pages(
+    page_code: u8,
+    subpage_code: u8,
+    page_control: PageControl,
+    outbuf: &mut [u8],
+    idx: &mut usize,
+) -> Result<(), ExecuteError> {
+    // The pair of the page code and the subpage code specifies which mode pages and subpages
+    // to return. Refer to the Table 99 in the SPC-3 spec for more details:
+    // <https://www.t10.org/cgi-bin/ac.pl?t=f&f=spc3r23.pdf>
+    match (page_code, subpage_code) {
+        // Return all mode pages with subpage 0.
+        (0x3f, 0x00) => add_all_page_codes(subpage_code, page_control, outbuf, idx),
+        // Return all mode pages with subpages 0x00-0xfe.
+        (0x3f, 0xff) => {
+            for subpage_code in 0..0xff {
+                add_all_page_codes(subpage_code, page_control, outbuf, idx)
             }
         }
-        // Add mode page 0 after all other mode pages were returned.
-        if let Some(n) = fill_mode_page(0, subpage_code, page_control, &mut outbuf[*idx as usize..])
+        // subpage_code other than 0x00 or 0xff are reserved.
+        (0x3f, _) => return Err(ExecuteError::InvalidField),
+        // Return a specific mode page with subpages 0x00-0xfe.
+        (_, 0xff) => {
+            for subpage_code in 0..0xff {
+                match fill_mode_page(page_code, subpage_code, page_control, &mut outbuf[*idx..]) {
+                    Some(n) => *idx += n as usize,
+                    None => return Err(ExecuteError::InvalidField),
+                };
+            }
+        }
+        (_, _) => {
+            match fill_mode_page(page_code, subpage_code, page_control, &mut outbuf[*idx..]) {
+                Some(n) => *idx += n as usize,
+                None => return Err(ExecuteError::InvalidField),
+            };
+        }
+    };
+    Ok(())
+}
+
+// Fill in mode pages with a specific subpage_code.
+fn add_all_page_codes(
+    subpage_code: u8,
+    page_control: PageControl,
+    outbuf: &mut [u8],
+    idx: &mut usize,
+) {
+    for page_code in 1..0x3f {
+        if let Some(n) = fill_mode_page(page_code, subpage_code, page_control, &mut outbuf[*idx..])
         {
-            *idx += n;
+            *idx += n as usize;
         }
     }
+    // Add mode page 0 after all other mode pages were returned.
+    if let Some(n) = fill_mode_page(0, subpage_code, page_control, &mut outbuf[*idx..]) {
+        *idx += n as usize;
+    }
+}
+
+#[derive(
+    Copy, Clone, Debug, Default, FromBytes, Immutable, IntoBytes, KnownLayout, PartialEq, Eq,
+)]
+#[repr(C, packed)]
+pub struct ModeSense10 {
+    opcode: u8,
+    llbaa_dbd_field: u8,
+    page_control_and_page_code: u8,
+    subpage_code: u8,
+    _reserved: [u8; 3],
+    alloc_len_bytes: [u8; 2],
+    control: u8,
+}
+
+impl ModeSense10 {
+    fn alloc_len(&self) -> usize {
+        u16::from_be_bytes(self.alloc_len_bytes) as usize
+    }
+
+    fn disable_block_desc(&self) -> bool {
+        self.llbaa_dbd_field & 0x8 != 0
+    }
+
+    fn long_lba_accepted(&self) -> bool {
+        self.llbaa_dbd_field & 0x10 != 0
+    }
+
+    fn page_code(&self) -> u8 {
+        // The top two bits represents page control field, and the rest is page code.
+        self.page_control_and_page_code & 0x3f
+    }
+
+    fn page_control(&self) -> Result<PageControl, ExecuteError> {
+        parse_page_control(self.page_control_and_page_code)
+    }
+
+    fn subpage_code(&self) -> u8 {
+        self.subpage_code
+    }
+
+    fn emulate(&self, writer: &mut Writer, dev: &AsyncLogicalUnit) -> Result<(), ExecuteError> {
+        let _trace = cros_tracing::trace_event!(VirtioScsi, "MODE_SENSE(10)");
+        let alloc_len = self.alloc_len();
+        let mut outbuf = vec![0u8; cmp::max(4096, alloc_len)];
+        // outbuf[0..2]: Represents data length. Will be filled later.
+        // outbuf[2]: Medium type should be 0.
+
+        // Device specific parameter
+        // We do not support the disabled page out (DPO) and forced unit access (FUA) bit.
+        outbuf[3] = if dev.read_only { 0x80 } else { 0x00 };
+        let mut idx = if !self.disable_block_desc() && dev.max_lba > 0 {
+            if self.long_lba_accepted() {
+                // LONGLBA: the block descriptor is in the 16 byte format.
+                outbuf[4] = 0x1;
+                // Block descriptor length.
+                outbuf[6..8].copy_from_slice(&16u16.to_be_bytes());
+                outbuf[8..16].copy_from_slice(&dev.max_lba.to_be_bytes());
+                // outbuf[16..20]: reserved.
+                outbuf[20..24].copy_from_slice(&dev.block_size.to_be_bytes());
+                24
+            } else {
+                // Block descriptor length.
+                outbuf[6..8].copy_from_slice(&8u16.to_be_bytes());
+                // The number of sectors is 0xffffffff if it does not fit in the field.
+                let sectors: u32 = dev.max_lba.try_into().unwrap_or(u32::MAX);
+                outbuf[8..12].copy_from_slice(&sectors.to_be_bytes());
+                // outbuf[12]: reserved.
+                outbuf[13..16].copy_from_slice(&dev.block_size.to_be_bytes()[1..]);
+                16
+            }
+        } else {
+            8
+        };
+
+        add_mode_pages(
+            self.page_code(),
+            self.subpage_code(),
+            self.page_control()?,
+            &mut outbuf,
+            &mut idx,
+        )?;
+        let mode_data_len: u16 = (idx - 2).try_into().unwrap_or(u16::MAX);
+        outbuf[0..2].copy_from_slice(&mode_data_len.to_be_bytes());
+        writer
+            .write_all(&outbuf[..alloc_len])
+            .map_err(ExecuteError::Write)
+    }
 }
 
 #[derive(
@@ -765,6 +940,56 @@ impl ReadCapacity16 {
     }
 }
 
+#[derive(
+    Copy, Clone, Debug, Default, FromBytes, Immutable, IntoBytes, KnownLayout, PartialEq, Eq,
+)]
+#[repr(C, packed)]
+pub struct GetLbaStatus {
+    opcode: u8,
+    service_action_field: u8,
+    lba_bytes: [u8; 8],
+    alloc_len_bytes: [u8; 4],
+    _reserved: u8,
+    control: u8,
+}
+
+impl GetLbaStatus {
+    fn lba(&self) -> u64 {
+        u64::from_be_bytes(self.lba_bytes)
+    }
+
+    fn alloc_len(&self) -> usize {
+        u32::from_be_bytes(self.alloc_len_bytes) as usize
+    }
+
+    fn emulate(&self, writer: &mut Writer, dev: &AsyncLogicalUnit) -> Result<(), ExecuteError> {
+        let lba = self.lba();
+        let _trace = cros_tracing::trace_event!(VirtioScsi, "GET_LBA_STATUS", lba);
+        if lba >= dev.max_lba {
+            return Err(ExecuteError::LbaOutOfRange {
+                length: 0,
+                sector: lba,
+                max_lba: dev.max_lba,
+            });
+        }
+        // The parameter data is an 8 byte header followed by a single LBA status descriptor.
+        let mut outbuf = [0u8; 24];
+        // Parameter data length, which does not include the field itself.
+        outbuf[0..4].copy_from_slice(&20u32.to_be_bytes());
+        // outbuf[4..8]: reserved.
+        // Starting LBA of the descriptor
+        outbuf[8..16].copy_from_slice(&lba.to_be_bytes());
+        // Number of logical blocks the descriptor covers
+        let nblocks: u32 = (dev.max_lba - lba).try_into().unwrap_or(u32::MAX);
+        outbuf[16..20].copy_from_slice(&nblocks.to_be_bytes());
+        // outbuf[20]: Provisioning status 0 means mapped or unknown. The async disk does not tell
+        // which parts of the image are allocated, so the whole range is reported as mapped.
+        writer
+            .write_all(&outbuf[..cmp::min(outbuf.len(), self.alloc_len())])
+            .map_err(ExecuteError::Write)
+    }
+}
+
 #[derive(
     Copy, Clone, Debug, Default, FromBytes, Immutable, IntoBytes, KnownLayout, PartialEq, Eq,
 )]
@@ -833,6 +1058,95 @@ impl Write10 {
     }
 }
 
+#[derive(
+    Copy, Clone, Debug, Default, FromBytes, Immutable, IntoBytes, KnownLayout, PartialEq, Eq,
+)]
+#[repr(C, packed)]
+pub struct Verify10 {
+    opcode: u8,
+    vrprotect_bytchk: u8,
+    lba_bytes: [u8; 4],
+    group_number: u8,
+    verification_len_bytes: [u8; 2],
+    control: u8,
+}
+
+impl Verify10 {
+    fn lba(&self) -> u64 {
+        u32::from_be_bytes(self.lba_bytes) as u64
+    }
+
+    fn verification_len(&self) -> usize {
+        u16::from_be_bytes(self.verification_len_bytes) as usize
+    }
+
+    fn byte_check(&self) -> u8 {
+        (self.vrprotect_bytchk >> 1) & 0x3
+    }
+
+    async fn emulate(
+        &self,
+        reader: &mut Reader,
+        dev: &AsyncLogicalUnit,
+    ) -> Result<(), ExecuteError> {
+        let verification_len = self.verification_len();
+        let lba = self.lba();
+        let _trace = cros_tracing::trace_event!(VirtioScsi, "VERIFY(10)", lba, verification_len);
+        verify(reader, dev, verification_len, lba, self.byte_check()).await
+    }
+}
+
+async fn verify(
+    reader: &mut Reader,
+    dev: &AsyncLogicalUnit,
+    nblocks: usize,
+    lba: u64,
+    byte_check: u8,
+) -> Result<(), ExecuteError> {
+    check_lba_range(dev.max_lba, lba, nblocks)?;
+    let block_size = dev.block_size as usize;
+    let mut expected = vec![0u8; block_size];
+    match byte_check {
+        // No data is transferred and there is no medium to check, so verifying the range is
+        // enough.
+        0b00 => return Ok(()),
+        // Each block is compared with its own block in the Data-Out buffer.
+        0b01 => (),
+        // All blocks are compared with the single block in the Data-Out buffer.
+        0b11 => reader
+            .read_exact(&mut expected)
+            .map_err(ExecuteError::Read)?,
+        _ => return Err(ExecuteError::InvalidField),
+    }
+    let mut actual = vec![0u8; block_size];
+    for sector in lba..lba + nblocks as u64 {
+        if byte_check == 0b01 {
+            reader
+                .read_exact(&mut expected)
+                .map_err(ExecuteError::Read)?;
+        }
+        let offset = sector * block_size as u64;
+        let mut done = 0;
+        while done < block_size {
+            let n = dev
+                .disk_image
+                .read_double_buffered(offset + done as u64, &mut actual[done..])
+                .await
+                .map_err(|e| ExecuteError::Read(io::Error::other(e)))?;
+            if n == 0 {
+                // Reading past the end of the image; the rest of the block reads as zeros.
+                actual[done..].fill(0);
+                break;
+            }
+            done += n;
+        }
+        if actual != expected {
+            return Err(ExecuteError::Miscompare(sector));
+        }
+    }
+    Ok(())
+}
+
 async fn write_to_disk(
     reader: &mut Reader,
     dev: &AsyncLogicalUnit,
@@ -1040,6 +1354,112 @@ impl Unmap {
     }
 }
 
+#[derive(
+    Copy, Clone, Debug, Default, FromBytes, Immutable, IntoBytes, KnownLayout, PartialEq, Eq,
+)]
+#[repr(C, packed)]
+pub struct Read16 {
+    opcode: u8,
+    rdprotect: u8,
+    lba_bytes: [u8; 8],
+    xfer_len_bytes: [u8; 4],
+    group_number: u8,
+    control: u8,
+}
+
+impl Read16 {
+    fn lba(&self) -> u64 {
+        u64::from_be_bytes(self.lba_bytes)
+    }
+
+    fn xfer_len(&self) -> usize {
+        u32::from_be_bytes(self.xfer_len_bytes) as usize
+    }
+
+    async fn emulate(
+        &self,
+        writer: &mut Writer,
+        dev: &AsyncLogicalUnit,
+    ) -> Result<(), ExecuteError> {
+        let xfer_len = self.xfer_len();
+        let lba = self.lba();
+        let _trace = cros_tracing::trace_event!(VirtioScsi, "READ(16)", lba, xfer_len);
+        read_from_disk(writer, dev, xfer_len, lba).await
+    }
+}
+
+#[derive(
+    Copy, Clone, Debug, Default, FromBytes, Immutable, IntoBytes, KnownLayout, PartialEq, Eq,
+)]
+#[repr(C, packed)]
+pub struct Write16 {
+    opcode: u8,
+    wrprotect: u8,
+    lba_bytes: [u8; 8],
+    xfer_len_bytes: [u8; 4],
+    group_number: u8,
+    control: u8,
+}
+
+impl Write16 {
+    fn lba(&self) -> u64 {
+        u64::from_be_bytes(self.lba_bytes)
+    }
+
+    fn xfer_len(&self) -> usize {
+        u32::from_be_bytes(self.xfer_len_bytes) as usize
+    }
+
+    async fn emulate(
+        &self,
+        reader: &mut Reader,
+        dev: &AsyncLogicalUnit,
+    ) -> Result<(), ExecuteError> {
+        let xfer_len = self.xfer_len();
+        let lba = self.lba();
+        let _trace = cros_tracing::trace_event!(VirtioScsi, "WRITE(16)", lba, xfer_len);
+        write_to_disk(reader, dev, xfer_len, lba).await
+    }
+}
+
+#[derive(
+    Copy, Clone, Debug, Default, FromBytes, Immutable, IntoBytes, KnownLayout, PartialEq, Eq,
+)]
+#[repr(C, packed)]
+pub struct Verify16 {
+    opcode: u8,
+    vrprotect_bytchk: u8,
+    lba_bytes: [u8; 8],
+    verification_len_bytes: [u8; 4],
+    group_number: u8,
+    control: u8,
+}
+
+impl Verify16 {
+    fn lba(&self) -> u64 {
+        u64::from_be_bytes(self.lba_bytes)
+    }
+
+    fn verification_len(&self) -> usize {
+        u32::from_be_bytes(self.verification_len_bytes) as usize
+    }
+
+    fn byte_check(&self) -> u8 {
+        (self.vrprotect_bytchk >> 1) & 0x3
+    }
+
+    async fn emulate(
+        &self,
+        reader: &mut Reader,
+        dev: &AsyncLogicalUnit,
+    ) -> Result<(), ExecuteError> {
+        let verification_len = self.verification_len();
+        let lba = self.lba();
+        let _trace = cros_tracing::trace_event!(VirtioScsi, "VERIFY(16)", lba, verification_len);
+        verify(reader, dev, verification_len, lba, self.byte_check()).await
+    }
+}
+
 #[derive(
     Copy, Clone, Debug, Default, FromBytes, Immutable, IntoBytes, KnownLayout, PartialEq, Eq,
 )]
@@ -1094,6 +1514,298 @@ impl WriteSame16 {
     }
 }
 
+/// Persistent reservation state of a logical unit.
+///
+/// The guest is the only initiator of the controller, so there is a single I_T nexus and at most
+/// one registered reservation key. The state is kept in memory and does not persist through
+/// power loss.
+#[derive(Debug, Default)]
+pub struct PersistentReservations {
+    // Incremented every time the set of registered keys changes.
+    generation: u32,
+    // The reservation key registered by the guest.
+    key: Option<u64>,
+    // The type of the persistent reservation held by the guest.
+    reservation_type: Option<u8>,
+}
+
+impl PersistentReservations {
+    fn check_key(&self, key: u64) -> Result<(), ExecuteError> {
+        if self.key == Some(key) {
+            Ok(())
+        } else {
+            Err(ExecuteError::ReservationConflict)
+        }
+    }
+
+    fn check_scope_and_type(scope: u8, reservation_type: u8) -> Result<(), ExecuteError> {
+        // Only LU_SCOPE is defined.
+        if scope != 0 {
+            return Err(ExecuteError::InvalidField);
+        }
+        match reservation_type {
+            // WRITE EXCLUSIVE, EXCLUSIVE ACCESS and their REGISTRANTS ONLY and ALL REGISTRANTS
+            // variants.
+            0x1 | 0x3 | 0x5 | 0x6 | 0x7 | 0x8 => Ok(()),
+            _ => Err(ExecuteError::InvalidField),
+        }
+    }
+
+    fn register(
+        &mut self,
+        key: u64,
+        service_action_key: u64,
+        ignore_existing_key: bool,
+    ) -> Result<(), ExecuteError> {
+        match self.key {
+            Some(registered) => {
+                if !ignore_existing_key && key != registered {
+                    return Err(ExecuteError::ReservationConflict);
+                }
+                if service_action_key == 0 {
+                    // Unregistering releases the reservation held by the nexus.
+                    self.key = None;
+                    self.reservation_type = None;
+                } else {
+                    self.key = Some(service_action_key);
+                }
+            }
+            None => {
+                if !ignore_existing_key && key != 0 {
+                    return Err(ExecuteError::ReservationConflict);
+                }
+                // Unregistering a nexus that is not registered does nothing.
+                if service_action_key == 0 {
+                    return Ok(());
+                }
+                self.key = Some(service_action_key);
+            }
+        }
+        self.generation = self.generation.wrapping_add(1);
+        Ok(())
+    }
+
+    fn reserve(&mut self, key: u64, scope: u8, reservation_type: u8) -> Result<(), ExecuteError> {
+        self.check_key(key)?;
+        Self::check_scope_and_type(scope, reservation_type)?;
+        match self.reservation_type {
+            Some(t) if t != reservation_type => Err(ExecuteError::ReservationConflict),
+            _ => {
+                self.reservation_type = Some(reservation_type);
+                Ok(())
+            }
+        }
+    }
+
+    fn release(&mut self, key: u64, scope: u8, reservation_type: u8) -> Result<(), ExecuteError> {
+        self.check_key(key)?;
+        match self.reservation_type {
+            None => Ok(()),
+            Some(_) if scope != 0 => Err(ExecuteError::InvalidField),
+            Some(t) if t != reservation_type => Err(ExecuteError::InvalidRelease),
+            Some(_) => {
+                self.reservation_type = None;
+                Ok(())
+            }
+        }
+    }
+
+    fn clear(&mut self, key: u64) -> Result<(), ExecuteError> {
+        self.check_key(key)?;
+        self.key = None;
+        self.reservation_type = None;
+        self.generation = self.generation.wrapping_add(1);
+        Ok(())
+    }
+
+    fn preempt(
+        &mut self,
+        key: u64,
+        service_action_key: u64,
+        scope: u8,
+        reservation_type: u8,
+    ) -> Result<(), ExecuteError> {
+        self.check_key(key)?;
+        // The guest can only preempt its own registration, which is kept. A reservation it
+        // holds is replaced with one of the requested type.
+        if self.key != Some(service_action_key) {
+            return Err(ExecuteError::ReservationConflict);
+        }
+        if self.reservation_type.is_some() {
+            Self::check_scope_and_type(scope, reservation_type)?;
+            self.reservation_type = Some(reservation_type);
+        }
+        self.generation = self.generation.wrapping_add(1);
+        Ok(())
+    }
+}
+
+#[derive(
+    Copy, Clone, Debug, Default, FromBytes, Immutable, IntoBytes, KnownLayout, PartialEq, Eq,
+)]
+#[repr(C, packed)]
+pub struct PersistentReserveIn {
+    opcode: u8,
+    service_action_field: u8,
+    _reserved: [u8; 5],
+    alloc_len_bytes: [u8; 2],
+    control: u8,
+}
+
+impl PersistentReserveIn {
+    fn service_action(&self) -> u8 {
+        // Top three bits are reserved.
+        self.service_action_field & 0x1f
+    }
+
+    fn alloc_len(&self) -> usize {
+        u16::from_be_bytes(self.alloc_len_bytes) as usize
+    }
+
+    fn emulate(&self, writer: &mut Writer, dev: &AsyncLogicalUnit) -> Result<(), ExecuteError> {
+        let service_action = self.service_action();
+        let _trace =
+            cros_tracing::trace_event!(VirtioScsi, "PERSISTENT_RESERVE_IN", service_action);
+        let state = dev.reservations.lock();
+        let mut outbuf = Vec::new();
+        match service_action {
+            PR_READ_KEYS => {
+                outbuf.extend_from_slice(&state.generation.to_be_bytes());
+                // Additional length: each key takes 8 bytes.
+                let keys_len = if state.key.is_some() { 8u32 } else { 0 };
+                outbuf.extend_from_slice(&keys_len.to_be_bytes());
+                if let Some(key) = state.key {
+                    outbuf.extend_from_slice(&key.to_be_bytes());
+                }
+            }
+            PR_READ_RESERVATION => {
+                outbuf.extend_from_slice(&state.generation.to_be_bytes());
+                match (state.key, state.reservation_type) {
+                    (Some(key), Some(reservation_type)) => {
+                        // Additional length of a single reservation descriptor.
+                        outbuf.extend_from_slice(&16u32.to_be_bytes());
+                        let mut descriptor = [0u8; 16];
+                        descriptor[0..8].copy_from_slice(&key.to_be_bytes());
+                        // descriptor[8..12]: obsolete.
+                        // descriptor[12]: reserved.
+                        // SCOPE | TYPE: the scope is always LU_SCOPE.
+                        descriptor[13] = reservation_type;
+                        outbuf.extend_from_slice(&descriptor);
+                    }
+                    _ => outbuf.extend_from_slice(&0u32.to_be_bytes()),
+                }
+            }
+            PR_REPORT_CAPABILITIES => {
+                outbuf.resize(8, 0);
+                // Length
+                outbuf[0..2].copy_from_slice(&8u16.to_be_bytes());
+                // outbuf[2]: crosvm supports neither SPEC_I_PT, ALL_TG_PT nor persisting through
+                // power loss.
+                // Type mask valid
+                outbuf[3] = 0x80;
+                // Persistent reservation type mask: WR_EX_AR | EX_AC_RO | WR_EX_RO | EX_AC | WR_EX
+                outbuf[4] = 0x80 | 0x40 | 0x20 | 0x08 | 0x02;
+                // EX_AC_AR
+                outbuf[5] = 0x01;
+            }
+            _ => {
+                warn!(
+                    "service action {:#x?} for PERSISTENT_RESERVE_IN is not implemented",
+                    service_action
+                );
+                return Err(ExecuteError::InvalidField);
+            }
+        }
+        writer
+            .write_all(&outbuf[..cmp::min(outbuf.len(), self.alloc_len())])
+            .map_err(ExecuteError::Write)
+    }
+}
+
+#[derive(
+    Copy, Clone, Debug, Default, FromBytes, Immutable, IntoBytes, KnownLayout, PartialEq, Eq,
+)]
+#[repr(C, packed)]
+pub struct PersistentReserveOut {
+    opcode: u8,
+    service_action_field: u8,
+    scope_and_type: u8,
+    _reserved: [u8; 2],
+    param_list_len_bytes: [u8; 4],
+    control: u8,
+}
+
+impl PersistentReserveOut {
+    fn service_action(&self) -> u8 {
+        // Top three bits are reserved.
+        self.service_action_field & 0x1f
+    }
+
+    fn scope(&self) -> u8 {
+        self.scope_and_type >> 4
+    }
+
+    fn reservation_type(&self) -> u8 {
+        self.scope_and_type & 0xf
+    }
+
+    fn param_list_len(&self) -> u32 {
+        u32::from_be_bytes(self.param_list_len_bytes)
+    }
+
+    fn emulate(&self, reader: &mut Reader, dev: &AsyncLogicalUnit) -> Result<(), ExecuteError> {
+        #[derive(
+            Copy, Clone, Debug, Default, FromBytes, Immutable, IntoBytes, KnownLayout, PartialEq, Eq,
+        )]
+        #[repr(C, packed)]
+        struct ParameterList {
+            key: Be64,
+            service_action_key: Be64,
+            _obsolete: [u8; 4],
+            flags: u8,
+            _reserved: u8,
+            _obsolete2: [u8; 2],
+        }
+
+        let service_action = self.service_action();
+        let _trace =
+            cros_tracing::trace_event!(VirtioScsi, "PERSISTENT_RESERVE_OUT", service_action);
+        if self.param_list_len() as usize != std::mem::size_of::<ParameterList>() {
+            return Err(ExecuteError::InvalidParamLen);
+        }
+        let params = reader
+            .read_obj::<ParameterList>()
+            .map_err(ExecuteError::Read)?;
+        // crosvm supports neither SPEC_I_PT, ALL_TG_PT nor APTPL.
+        if params.flags & 0x0d != 0 {
+            return Err(ExecuteError::InvalidField);
+        }
+        let key = params.key.to_native();
+        let service_action_key = params.service_action_key.to_native();
+        let scope = self.scope();
+        let reservation_type = self.reservation_type();
+        let mut state = dev.reservations.lock();
+        match service_action {
+            PR_REGISTER => state.register(key, service_action_key, false),
+            PR_RESERVE => state.reserve(key, scope, reservation_type),
+            PR_RELEASE => state.release(key, scope, reservation_type),
+            PR_CLEAR => state.clear(key),
+            // There are no commands from other nexuses to abort.
+            PR_PREEMPT | PR_PREEMPT_AND_ABORT => {
+                state.preempt(key, service_action_key, scope, reservation_type)
+            }
+            PR_REGISTER_AND_IGNORE_EXISTING_KEY => state.register(key, service_action_key, true),
+            _ => {
+                warn!(
+                    "service action {:#x?} for PERSISTENT_RESERVE_OUT is not implemented",
+                    service_action
+                );
+                Err(ExecuteError::InvalidField)
+            }
+        }
+    }
+}
+
 #[derive(
     Copy, Clone, Debug, Default, FromBytes, Immutable, IntoBytes, KnownLayout, PartialEq, Eq,
 )]
@@ -1113,23 +1825,34 @@ impl ReportLuns {
         u32::from_be_bytes(self.alloc_len_bytes) as usize
     }
 
-    fn emulate(&self, writer: &mut Writer) -> Result<(), ExecuteError> {
+    fn emulate(&self, writer: &mut Writer, luns: &[u16]) -> Result<(), ExecuteError> {
         let _trace = cros_tracing::trace_event!(VirtioScsi, "REPORT_LUNS");
         // We need at least 16 bytes.
         if self.alloc_len() < 16 {
             return Err(ExecuteError::InvalidField);
         }
-        // Each LUN takes 8 bytes and we only support LUN0.
-        let lun_list_len = 8u32;
-        writer
-            .write_all(&lun_list_len.to_be_bytes())
-            .map_err(ExecuteError::Write)?;
-        let reserved = [0; 4];
-        writer.write_all(&reserved).map_err(ExecuteError::Write)?;
-        let lun0 = 0u64;
-        writer
-            .write_all(&lun0.to_be_bytes())
-            .map_err(ExecuteError::Write)
+        // The LUN list length is followed by 4 reserved bytes, and each LUN takes 8 bytes.
+        let lun_list_len = (8 * luns.len()) as u32;
+        let mut outbuf = Vec::with_capacity(8 + 8 * luns.len());
+        outbuf.extend_from_slice(&lun_list_len.to_be_bytes());
+        outbuf.extend_from_slice(&[0; 4]);
+        for &lun in luns {
+            outbuf.extend_from_slice(&Self::encode_lun(lun));
+        }
+        outbuf.truncate(self.alloc_len());
+        writer.write_all(&outbuf).map_err(ExecuteError::Write)
+    }
+
+    fn encode_lun(lun: u16) -> [u8; 8] {
+        let mut encoded = [0; 8];
+        if lun < 256 {
+            // Peripheral device addressing method.
+            encoded[1] = lun as u8;
+        } else {
+            // Flat space addressing method.
+            encoded[..2].copy_from_slice(&(0x4000 | lun).to_be_bytes());
+        }
+        encoded
     }
 }
 
@@ -1260,6 +1983,168 @@ mod tests {
         assert_eq!(write10.lba(), 0x00000000);
     }
 
+    #[test]
+    fn parse_read16() {
+        let cdb = [
+            0x88, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x3c, 0x00, 0x01, 0x00, 0x08,
+            0x00, 0x00,
+        ];
+        let command = Command::new(&cdb).unwrap();
+        let read16 = match command {
+            Command::Read16(r) => r,
+            _ => panic!("unexpected command type: {:?}", command),
+        };
+        assert_eq!(read16.xfer_len(), 0x00010008);
+        assert_eq!(read16.lba(), 0x010000003c);
+    }
+
+    #[test]
+    fn parse_write16() {
+        let cdb = [
+            0x8a, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x08,
+            0x00, 0x00,
+        ];
+        let command = Command::new(&cdb).unwrap();
+        let write16 = match command {
+            Command::Write16(w) => w,
+            _ => panic!("unexpected command type: {:?}", command),
+        };
+        assert_eq!(write16.xfer_len(), 0x0008);
+        assert_eq!(write16.lba(), 0x10);
+    }
+
+    #[test]
+    fn parse_verify() {
+        let cdb = [0x2f, 0x02, 0x00, 0x00, 0x00, 0x3c, 0x00, 0x00, 0x08, 0x00];
+        let command = Command::new(&cdb).unwrap();
+        let verify10 = match command {
+            Command::Verify10(v) => v,
+            _ => panic!("unexpected command type: {:?}", command),
+        };
+        assert_eq!(verify10.verification_len(), 0x0008);
+        assert_eq!(verify10.lba(), 0x3c);
+        assert_eq!(verify10.byte_check(), 0b01);
+
+        let cdb = [
+            0x8f, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x3c, 0x00, 0x00, 0x00, 0x08,
+            0x00, 0x00,
+        ];
+        let command = Command::new(&cdb).unwrap();
+        let verify16 = match command {
+            Command::Verify16(v) => v,
+            _ => panic!("unexpected command type: {:?}", command),
+        };
+        assert_eq!(verify16.verification_len(), 0x0008);
+        assert_eq!(verify16.lba(), 0x3c);
+        assert_eq!(verify16.byte_check(), 0b00);
+    }
+
+    #[test]
+    fn parse_mode_sense_10() {
+        let cdb = [0x5a, 0x10, 0x3f, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00];
+        let command = Command::new(&cdb).unwrap();
+        let mode_sense_10 = match command {
+            Command::ModeSense10(m) => m,
+            _ => panic!("unexpected command type: {:?}", command),
+        };
+        assert_eq!(mode_sense_10.alloc_len(), 0x0100);
+        assert_eq!(mode_sense_10.page_code(), 0x3f);
+        assert_eq!(mode_sense_10.page_control().unwrap(), PageControl::Current);
+        assert!(mode_sense_10.long_lba_accepted());
+        assert!(!mode_sense_10.disable_block_desc());
+    }
+
+    #[test]
+    fn parse_get_lba_status() {
+        let cdb = [
+            0x9e, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x80, 0x00, 0x00, 0x00, 0x18,
+            0x00, 0x00,
+        ];
+        let command = Command::new(&cdb).unwrap();
+        let get_lba_status = match command {
+            Command::GetLbaStatus(g) => g,
+            _ => panic!("unexpected command type: {:?}", command),
+        };
+        assert_eq!(get_lba_status.lba(), 0x80);
+        assert_eq!(get_lba_status.alloc_len(), 0x18);
+    }
+
+    #[test]
+    fn parse_persistent_reserve() {
+        let cdb = [0x5e, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00];
+        let command = Command::new(&cdb).unwrap();
+        let persistent_reserve_in = match command {
+            Command::PersistentReserveIn(p) => p,
+            _ => panic!("unexpected command type: {:?}", command),
+        };
+        assert_eq!(persistent_reserve_in.service_action(), PR_READ_RESERVATION);
+        assert_eq!(persistent_reserve_in.alloc_len(), 0x1000);
+
+        let cdb = [0x5f, 0x01, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x18, 0x00];
+        let command = Command::new(&cdb).unwrap();
+        let persistent_reserve_out = match command {
+            Command::PersistentReserveOut(p) => p,
+            _ => panic!("unexpected command type: {:?}", command),
+        };
+        assert_eq!(persistent_reserve_out.service_action(), PR_RESERVE);
+        assert_eq!(persistent_reserve_out.scope(), 0);
+        assert_eq!(persistent_reserve_out.reservation_type(), 3);
+        assert_eq!(persistent_reserve_out.param_list_len(), 24);
+    }
+
+    #[test]
+    fn persistent_reservations() {
+        let mut state = PersistentReservations::default();
+        // A nexus that is not registered cannot reserve.
+        assert!(matches!(
+            state.reserve(0x1234, 0, 1),
+            Err(ExecuteError::ReservationConflict)
+        ));
+        // Registering requires a reservation key of zero.
+        assert!(matches!(
+            state.register(0x1, 0x1234, false),
+            Err(ExecuteError::ReservationConflict)
+        ));
+        state.register(0, 0x1234, false).unwrap();
+        assert_eq!(state.key, Some(0x1234));
+        assert_eq!(state.generation, 1);
+
+        state.reserve(0x1234, 0, 1).unwrap();
+        // Reserving again with the same type is allowed, but not with another type.
+        state.reserve(0x1234, 0, 1).unwrap();
+        assert!(matches!(
+            state.reserve(0x1234, 0, 3),
+            Err(ExecuteError::ReservationConflict)
+        ));
+        assert!(matches!(
+            state.release(0x1234, 0, 3),
+            Err(ExecuteError::InvalidRelease)
+        ));
+        state.release(0x1234, 0, 1).unwrap();
+        assert_eq!(state.reservation_type, None);
+
+        // Preempting replaces the reservation.
+        state.reserve(0x1234, 0, 5).unwrap();
+        state.preempt(0x1234, 0x1234, 0, 6).unwrap();
+        assert_eq!(state.reservation_type, Some(6));
+        assert_eq!(state.generation, 2);
+
+        // Changing the key keeps the reservation, and unregistering releases it.
+        state.register(0x1234, 0x5678, false).unwrap();
+        assert_eq!(state.key, Some(0x5678));
+        assert_eq!(state.reservation_type, Some(6));
+        state.register(0, 0, true).unwrap();
+        assert_eq!(state.key, None);
+        assert_eq!(state.reservation_type, None);
+
+        state.register(0, 0x1, false).unwrap();
+        state.reserve(0x1, 0, 3).unwrap();
+        state.clear(0x1).unwrap();
+        assert_eq!(state.key, None);
+        assert_eq!(state.reservation_type, None);
+        assert_eq!(state.generation, 6);
+    }
+
     #[test]
     fn parse_synchronize_cache_10() {
         let cdb = [0x35, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
@@ -1290,6 +2175,13 @@ mod tests {
         assert_eq!(report_luns.alloc_len(), 0xabcdef12);
     }
 
+    #[test]
+    fn report_luns_encoding() {
+        assert_eq!(ReportLuns::encode_lun(0), [0; 8]);
+        assert_eq!(ReportLuns::encode_lun(5), [0, 5, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(ReportLuns::encode_lun(300), [0x41, 0x2c, 0, 0, 0, 0, 0, 0]);
+    }
+
     #[test]
     fn parse_report_supported_tmfs() {
         let cdb = [
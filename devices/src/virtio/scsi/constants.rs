@@ -26,12 +26,26 @@ pub const READ_CAPACITY_10: u8 = 0x25;
 pub const READ_10: u8 = 0x28;
 /// Opcode for WRITE(10) command.
 pub const WRITE_10: u8 = 0x2a;
+/// Opcode for VERIFY(10) command.
+pub const VERIFY_10: u8 = 0x2f;
 /// Opcode for SYNCHRONIZE CACHE(10) command.
 pub const SYNCHRONIZE_CACHE_10: u8 = 0x35;
 /// Opcode for WRITE SAME(10) command.
 pub const WRITE_SAME_10: u8 = 0x41;
 /// Opcode for UNMAP command.
 pub const UNMAP: u8 = 0x42;
+/// Opcode for MODE SENSE(10) command.
+pub const MODE_SENSE_10: u8 = 0x5a;
+/// Opcode for PERSISTENT RESERVE IN command.
+pub const PERSISTENT_RESERVE_IN: u8 = 0x5e;
+/// Opcode for PERSISTENT RESERVE OUT command.
+pub const PERSISTENT_RESERVE_OUT: u8 = 0x5f;
+/// Opcode for READ(16) command.
+pub const READ_16: u8 = 0x88;
+/// Opcode for WRITE(16) command.
+pub const WRITE_16: u8 = 0x8a;
+/// Opcode for VERIFY(16) command.
+pub const VERIFY_16: u8 = 0x8f;
 /// Opcode for WRITE SAME(16) command.
 pub const WRITE_SAME_16: u8 = 0x93;
 /// Opcode for SERVICE ACTION IN(16) command.
@@ -48,12 +62,40 @@ pub const REPORT_SUPPORTED_TASK_MANAGEMENT_FUNCTIONS: u8 = 0x0d;
 // The service actions of SERVICE ACTION IN(16) command.
 /// READ CAPACITY(16)
 pub const READ_CAPACITY_16: u8 = 0x10;
+/// GET LBA STATUS
+pub const GET_LBA_STATUS: u8 = 0x12;
+
+// The service actions of PERSISTENT RESERVE IN command.
+/// READ KEYS
+pub const PR_READ_KEYS: u8 = 0x00;
+/// READ RESERVATION
+pub const PR_READ_RESERVATION: u8 = 0x01;
+/// REPORT CAPABILITIES
+pub const PR_REPORT_CAPABILITIES: u8 = 0x02;
+
+// The service actions of PERSISTENT RESERVE OUT command.
+/// REGISTER
+pub const PR_REGISTER: u8 = 0x00;
+/// RESERVE
+pub const PR_RESERVE: u8 = 0x01;
+/// RELEASE
+pub const PR_RELEASE: u8 = 0x02;
+/// CLEAR
+pub const PR_CLEAR: u8 = 0x03;
+/// PREEMPT
+pub const PR_PREEMPT: u8 = 0x04;
+/// PREEMPT AND ABORT
+pub const PR_PREEMPT_AND_ABORT: u8 = 0x05;
+/// REGISTER AND IGNORE EXISTING KEY
+pub const PR_REGISTER_AND_IGNORE_EXISTING_KEY: u8 = 0x06;
 
 // SAM status code
 /// Indicates the completion of the command without error.
 pub const GOOD: u8 = 0x00;
 /// Indicates that sense data has been delivered in the buffer.
 pub const CHECK_CONDITION: u8 = 0x02;
+/// Indicates that the command conflicts with a persistent reservation.
+pub const RESERVATION_CONFLICT: u8 = 0x18;
 
 // Device Types
 /// Indicates the id of disk type.
@@ -69,3 +111,5 @@ pub const MEDIUM_ERROR: u8 = 0x03;
 pub const ILLEGAL_REQUEST: u8 = 0x05;
 /// Indicates that a unit attention condition has been established.
 pub const UNIT_ATTENTION: u8 = 0x06;
+/// Indicates that the data from the Data-Out buffer did not match the data read from the medium.
+pub const MISCOMPARE: u8 = 0x0e;
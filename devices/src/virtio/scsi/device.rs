@@ -4,32 +4,45 @@
 
 #![deny(missing_docs)]
 //! A SCSI controller has SCSI target(s), a SCSI target has logical unit(s).
-//! crosvm currently supports only one logical unit in a target (LUN0), therefore a SCSI target is
-//! tied to a logical unit and a disk image belongs to a logical unit in crosvm.
+//! A disk image belongs to a logical unit in crosvm, and logical units are addressed by their
+//! target id and their LUN in the target. Logical units can be added and removed while the guest
+//! is running.
 
 use std::cell::RefCell;
 use std::collections::BTreeMap;
 use std::collections::BTreeSet;
+use std::collections::VecDeque;
 use std::io;
 use std::io::Read;
 use std::io::Write;
 use std::rc::Rc;
+use std::sync::Arc;
 
+use anyhow::anyhow;
+use anyhow::bail;
 use anyhow::Context;
 use base::error;
+use base::info;
 use base::warn;
+use base::AsRawDescriptor;
+use base::Error as SysError;
 use base::Event;
+use base::Tube;
 use base::WorkerThread;
+use cros_async::AsyncTube;
 use cros_async::EventAsync;
 use cros_async::Executor;
 use cros_async::ExecutorKind;
 use disk::AsyncDisk;
 use disk::DiskFile;
+use futures::channel::mpsc;
+use futures::channel::oneshot;
 use futures::pin_mut;
 use futures::stream::FuturesUnordered;
 use futures::FutureExt;
 use futures::StreamExt;
 use remain::sorted;
+use sync::Mutex;
 use thiserror::Error as ThisError;
 use virtio_sys::virtio_scsi::virtio_scsi_config;
 use virtio_sys::virtio_scsi::virtio_scsi_ctrl_an_resp;
@@ -37,6 +50,9 @@ use virtio_sys::virtio_scsi::virtio_scsi_ctrl_tmf_req;
 use virtio_sys::virtio_scsi::virtio_scsi_ctrl_tmf_resp;
 use virtio_sys::virtio_scsi::virtio_scsi_event;
 use virtio_sys::virtio_scsi::VIRTIO_SCSI_CDB_DEFAULT_SIZE;
+use virtio_sys::virtio_scsi::VIRTIO_SCSI_EVT_RESET_REMOVED;
+use virtio_sys::virtio_scsi::VIRTIO_SCSI_EVT_RESET_RESCAN;
+use virtio_sys::virtio_scsi::VIRTIO_SCSI_F_HOTPLUG;
 use virtio_sys::virtio_scsi::VIRTIO_SCSI_SENSE_DEFAULT_SIZE;
 use virtio_sys::virtio_scsi::VIRTIO_SCSI_S_BAD_TARGET;
 use virtio_sys::virtio_scsi::VIRTIO_SCSI_S_FUNCTION_REJECTED;
@@ -48,6 +64,10 @@ use virtio_sys::virtio_scsi::VIRTIO_SCSI_T_AN_SUBSCRIBE;
 use virtio_sys::virtio_scsi::VIRTIO_SCSI_T_TMF;
 use virtio_sys::virtio_scsi::VIRTIO_SCSI_T_TMF_I_T_NEXUS_RESET;
 use virtio_sys::virtio_scsi::VIRTIO_SCSI_T_TMF_LOGICAL_UNIT_RESET;
+use virtio_sys::virtio_scsi::VIRTIO_SCSI_T_TRANSPORT_RESET;
+use vm_control::DiskMedia;
+use vm_control::ScsiControlCommand;
+use vm_control::ScsiControlResult;
 use vm_memory::GuestMemory;
 use zerocopy::FromBytes;
 use zerocopy::Immutable;
@@ -58,10 +78,13 @@ use crate::virtio::async_utils;
 use crate::virtio::block::sys::get_seg_max;
 use crate::virtio::copy_config;
 use crate::virtio::scsi::commands::Command;
+use crate::virtio::scsi::commands::PersistentReservations;
 use crate::virtio::scsi::constants::CHECK_CONDITION;
 use crate::virtio::scsi::constants::GOOD;
 use crate::virtio::scsi::constants::ILLEGAL_REQUEST;
 use crate::virtio::scsi::constants::MEDIUM_ERROR;
+use crate::virtio::scsi::constants::MISCOMPARE;
+use crate::virtio::scsi::constants::RESERVATION_CONFLICT;
 use crate::virtio::DescriptorChain;
 use crate::virtio::DeviceType as VirtioDeviceType;
 use crate::virtio::Interrupt;
@@ -138,12 +161,18 @@ pub enum ExecuteError {
     InvalidField,
     #[error("invalid parameter length")]
     InvalidParamLen,
+    #[error("invalid release of persistent reservation")]
+    InvalidRelease,
     #[error("{length} bytes from sector {sector} exceeds end of this device {max_lba}")]
     LbaOutOfRange {
         length: usize,
         sector: u64,
         max_lba: u64,
     },
+    #[error("logical unit not supported")]
+    LogicalUnitNotSupported,
+    #[error("miscompare during verify operation at sector {0}")]
+    Miscompare(u64),
     #[error("failed to read message: {0}")]
     Read(io::Error),
     #[error("failed to read command from cdb")]
@@ -155,6 +184,8 @@ pub enum ExecuteError {
     },
     #[error("writing to a read only device")]
     ReadOnly,
+    #[error("reservation conflict")]
+    ReservationConflict,
     #[error("saving parameters not supported")]
     SavingParamNotSupported,
     #[error("synchronization error")]
@@ -209,6 +240,30 @@ impl ExecuteError {
                     ascq: 0x00,
                 }
             }
+            Self::InvalidRelease => {
+                // INVALID RELEASE OF PERSISTENT RESERVATION
+                Sense {
+                    key: ILLEGAL_REQUEST,
+                    asc: 0x26,
+                    ascq: 0x04,
+                }
+            }
+            Self::LogicalUnitNotSupported => {
+                // LOGICAL UNIT NOT SUPPORTED
+                Sense {
+                    key: ILLEGAL_REQUEST,
+                    asc: 0x25,
+                    ascq: 0x00,
+                }
+            }
+            Self::Miscompare(_) => {
+                // MISCOMPARE DURING VERIFY OPERATION
+                Sense {
+                    key: MISCOMPARE,
+                    asc: 0x1d,
+                    ascq: 0x00,
+                }
+            }
             Self::Unsupported(_) => {
                 // INVALID COMMAND OPERATION CODE
                 Sense {
@@ -237,6 +292,14 @@ impl ExecuteError {
                 asc: 0x16,
                 ascq: 0x00,
             },
+            // RESERVATION CONFLICT is reported with the status alone, without sense data.
+            Self::ReservationConflict => {
+                let hdr = VirtioScsiCmdRespHeader {
+                    status: RESERVATION_CONFLICT,
+                    ..resp
+                };
+                return (hdr, Sense::default());
+            }
             // Ignore these errors.
             Self::ReadIo { resid, desc_error } | Self::WriteIo { resid, desc_error } => {
                 warn!("error while performing I/O {}", desc_error);
@@ -306,9 +369,39 @@ struct LogicalUnit {
     read_only: bool,
     // Represents the image on disk.
     disk_image: Box<dyn DiskFile>,
+    // Persistent reservation state, shared by the workers of all request queues.
+    reservations: Arc<Mutex<PersistentReservations>>,
 }
 
 impl LogicalUnit {
+    fn new(
+        disk_image: Box<dyn DiskFile>,
+        block_size: u32,
+        read_only: bool,
+    ) -> anyhow::Result<Self> {
+        let max_lba = disk_image
+            .get_len()
+            .context("Failed to get the length of the disk image")?
+            / block_size as u64;
+        Ok(LogicalUnit {
+            max_lba,
+            block_size,
+            read_only,
+            disk_image,
+            reservations: Default::default(),
+        })
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(LogicalUnit {
+            disk_image: self.disk_image.try_clone()?,
+            max_lba: self.max_lba,
+            block_size: self.block_size,
+            read_only: self.read_only,
+            reservations: self.reservations.clone(),
+        })
+    }
+
     fn make_async(self, ex: &Executor) -> anyhow::Result<AsyncLogicalUnit> {
         let disk_image = self
             .disk_image
@@ -319,6 +412,7 @@ impl LogicalUnit {
             block_size: self.block_size,
             read_only: self.read_only,
             disk_image,
+            reservations: self.reservations,
         })
     }
 }
@@ -330,33 +424,56 @@ pub struct AsyncLogicalUnit {
     pub read_only: bool,
     // Represents the async image on disk.
     pub disk_image: Box<dyn AsyncDisk>,
+    pub reservations: Arc<Mutex<PersistentReservations>>,
 }
 
 type TargetId = u8;
-struct Targets(BTreeMap<TargetId, LogicalUnit>);
+type Lun = u16;
+// A logical unit is addressed by its target id and its LUN in the target.
+type LunAddress = (TargetId, Lun);
+
+// Decodes the `lun` field of virtio-scsi requests.
+fn decode_lun(lun: [u8; 8]) -> Option<LunAddress> {
+    // First byte should be 1.
+    if lun[0] != 1 {
+        return None;
+    }
+    // The second byte is the target id, and the next two bytes are the LUN in the flat space
+    // addressing method.
+    Some((lun[1], u16::from_be_bytes([lun[2], lun[3]]) & 0x3fff))
+}
+
+// Encodes the address of a logical unit in the format of the `lun` field of virtio-scsi events.
+fn encode_lun((target, lun): LunAddress) -> [u8; 8] {
+    let [hi, lo] = (0x4000 | lun).to_be_bytes();
+    [1, target, hi, lo, 0, 0, 0, 0]
+}
+
+// Returns the LUNs of the logical units of `target`.
+fn target_luns<'a>(
+    addresses: impl IntoIterator<Item = &'a LunAddress>,
+    target: TargetId,
+) -> Vec<Lun> {
+    addresses
+        .into_iter()
+        .filter(|(t, _)| *t == target)
+        .map(|(_, lun)| *lun)
+        .collect()
+}
+
+struct Targets(BTreeMap<LunAddress, LogicalUnit>);
 
 impl Targets {
     fn try_clone(&self) -> io::Result<Self> {
         let logical_units = self
             .0
             .iter()
-            .map(|(id, logical_unit)| {
-                let disk_image = logical_unit.disk_image.try_clone()?;
-                Ok((
-                    *id,
-                    LogicalUnit {
-                        disk_image,
-                        max_lba: logical_unit.max_lba,
-                        block_size: logical_unit.block_size,
-                        read_only: logical_unit.read_only,
-                    },
-                ))
-            })
+            .map(|(address, logical_unit)| Ok((*address, logical_unit.try_clone()?)))
             .collect::<io::Result<_>>()?;
         Ok(Self(logical_units))
     }
 
-    fn target_ids(&self) -> BTreeSet<TargetId> {
+    fn addresses(&self) -> BTreeSet<LunAddress> {
         self.0.keys().cloned().collect()
     }
 }
@@ -369,6 +486,10 @@ pub struct DiskConfig {
     pub block_size: u32,
     /// Indicates whether the SCSI disk is read only.
     pub read_only: bool,
+    /// The id of the target the disk belongs to.
+    pub target: u8,
+    /// The logical unit number of the disk in its target.
+    pub lun: u16,
 }
 
 /// Vitio device for exposing SCSI command operations on a host file.
@@ -385,43 +506,54 @@ pub struct Controller {
     cdb_size: u32,
     executor_kind: ExecutorKind,
     worker_threads: Vec<WorkerThread<()>>,
-    // Stores target devices by its target id. Currently we only support bus id 0.
+    // Stores logical units by their target id and LUN. Currently we only support bus id 0.
     targets: Option<Targets>,
     // Whether the devices handles requests in multiple request queues.
     // If true, each virtqueue will be handled in a separate worker thread.
     multi_queue: bool,
+    // Receives requests to add and remove logical units.
+    control_tube: Option<Tube>,
 }
 
 impl Controller {
-    /// Creates a virtio-scsi device.
-    pub fn new(base_features: u64, disks: Vec<DiskConfig>) -> anyhow::Result<Self> {
+    /// Creates a virtio-scsi device. Logical units can be added and removed while the device is
+    /// running through `control_tube`.
+    pub fn new(
+        base_features: u64,
+        disks: Vec<DiskConfig>,
+        control_tube: Option<Tube>,
+    ) -> anyhow::Result<Self> {
         let multi_queue = disks.iter().all(|disk| disk.file.try_clone().is_ok());
         let num_queues = if multi_queue {
             MAX_NUM_QUEUES
         } else {
             MIN_NUM_QUEUES
         };
-        let logical_units = disks
-            .into_iter()
-            .enumerate()
-            .map(|(i, disk)| {
-                let max_lba = disk
-                    .file
-                    .get_len()
-                    .context("Failed to get the length of the disk image")?
-                    / disk.block_size as u64;
-                let target = LogicalUnit {
-                    max_lba,
-                    block_size: disk.block_size,
-                    read_only: disk.read_only,
-                    disk_image: disk.file,
-                };
-                Ok((i as TargetId, target))
-            })
-            .collect::<anyhow::Result<_>>()?;
+        let mut logical_units = BTreeMap::new();
+        for disk in disks {
+            if disk.lun as u32 > DEFAULT_MAX_LUN {
+                bail!(
+                    "LUN {} exceeds the maximum LUN {}",
+                    disk.lun,
+                    DEFAULT_MAX_LUN
+                );
+            }
+            let logical_unit = LogicalUnit::new(disk.file, disk.block_size, disk.read_only)?;
+            if logical_units
+                .insert((disk.target, disk.lun), logical_unit)
+                .is_some()
+            {
+                bail!("multiple disks at target {} LUN {}", disk.target, disk.lun);
+            }
+        }
         // b/300560198: Support feature bits in virtio-scsi.
+        let avail_features = if control_tube.is_some() {
+            base_features | 1 << VIRTIO_SCSI_F_HOTPLUG
+        } else {
+            base_features
+        };
         Ok(Self {
-            avail_features: base_features,
+            avail_features,
             queue_sizes: vec![DEFAULT_QUEUE_SIZE; num_queues],
             seg_max: get_seg_max(DEFAULT_QUEUE_SIZE),
             sense_size: VIRTIO_SCSI_SENSE_DEFAULT_SIZE,
@@ -430,6 +562,7 @@ impl Controller {
             worker_threads: vec![],
             targets: Some(Targets(logical_units)),
             multi_queue,
+            control_tube,
         })
     }
 
@@ -454,7 +587,7 @@ impl Controller {
     fn execute_control(
         reader: &mut Reader,
         writer: &mut Writer,
-        target_ids: &BTreeSet<TargetId>,
+        addresses: &BTreeSet<LunAddress>,
     ) -> Result<(), ExecuteError> {
         let typ = reader.peek_obj::<u32>().map_err(ExecuteError::Read)?;
         match typ {
@@ -462,7 +595,7 @@ impl Controller {
                 let tmf = reader
                     .read_obj::<virtio_scsi_ctrl_tmf_req>()
                     .map_err(ExecuteError::Read)?;
-                let resp = Self::execute_tmf(tmf, target_ids);
+                let resp = Self::execute_tmf(tmf, addresses);
                 writer.write_obj(resp).map_err(ExecuteError::Write)?;
                 Ok(())
             }
@@ -486,22 +619,22 @@ impl Controller {
     // Executes a TMF (task management function) request.
     fn execute_tmf(
         tmf: virtio_scsi_ctrl_tmf_req,
-        target_ids: &BTreeSet<TargetId>,
+        addresses: &BTreeSet<LunAddress>,
     ) -> virtio_scsi_ctrl_tmf_resp {
         match tmf.subtype {
             VIRTIO_SCSI_T_TMF_LOGICAL_UNIT_RESET | VIRTIO_SCSI_T_TMF_I_T_NEXUS_RESET => {
-                // We only have LUN0.
-                let lun = tmf.lun;
-                let target_id = lun[1];
-                let response = if target_ids.contains(&target_id) {
-                    let is_lun0 = u16::from_be_bytes([lun[2], lun[3]]) & 0x3fff == 0;
-                    if is_lun0 {
-                        VIRTIO_SCSI_S_FUNCTION_SUCCEEDED as u8
-                    } else {
-                        VIRTIO_SCSI_S_INCORRECT_LUN as u8
+                let response = match decode_lun(tmf.lun) {
+                    Some((target, lun)) if !target_luns(addresses, target).is_empty() => {
+                        // I_T NEXUS RESET applies to every logical unit of the target.
+                        if tmf.subtype == VIRTIO_SCSI_T_TMF_I_T_NEXUS_RESET
+                            || addresses.contains(&(target, lun))
+                        {
+                            VIRTIO_SCSI_S_FUNCTION_SUCCEEDED as u8
+                        } else {
+                            VIRTIO_SCSI_S_INCORRECT_LUN as u8
+                        }
                     }
-                } else {
-                    VIRTIO_SCSI_S_BAD_TARGET as u8
+                    _ => VIRTIO_SCSI_S_BAD_TARGET as u8,
                 };
                 virtio_scsi_ctrl_tmf_resp { response }
             }
@@ -518,80 +651,82 @@ impl Controller {
         reader: &mut Reader,
         resp_writer: &mut Writer,
         data_writer: &mut Writer,
-        targets: &BTreeMap<TargetId, AsyncLogicalUnit>,
+        targets: &RefCell<BTreeMap<LunAddress, Rc<AsyncLogicalUnit>>>,
         sense_size: u32,
         cdb_size: u32,
     ) -> Result<(), ExecuteError> {
         let req_header = reader
             .read_obj::<VirtioScsiCmdReqHeader>()
             .map_err(ExecuteError::Read)?;
-        match Self::get_logical_unit(req_header.lun, targets) {
-            Some(target) => {
-                let mut cdb = vec![0; cdb_size as usize];
-                reader.read_exact(&mut cdb).map_err(ExecuteError::Read)?;
-                let command = Command::new(&cdb)?;
-                match command.execute(reader, data_writer, target).await {
-                    Ok(()) => {
-                        let hdr = VirtioScsiCmdRespHeader {
-                            sense_len: 0,
-                            resid: 0,
-                            status_qualifier: 0,
-                            status: GOOD,
-                            response: VIRTIO_SCSI_S_OK as u8,
-                        };
-                        resp_writer.write_obj(hdr).map_err(ExecuteError::Write)?;
-                        resp_writer.consume_bytes(sense_size as usize);
-                        Ok(())
-                    }
-                    Err(err) => {
-                        error!("error while executing a scsi request: {err}");
-                        let (hdr, sense) = err.as_resp();
-                        resp_writer.write_obj(hdr).map_err(ExecuteError::Write)?;
-                        sense.write_to(resp_writer, sense_size)
-                    }
-                }
+        // The logical unit is looked up once, so that the request keeps using it even if it gets
+        // removed while the request is in flight.
+        let (logical_unit, luns) = match decode_lun(req_header.lun) {
+            Some((target, lun)) => {
+                let targets = targets.borrow();
+                (
+                    targets.get(&(target, lun)).cloned(),
+                    target_luns(targets.keys(), target),
+                )
+            }
+            None => (None, Vec::new()),
+        };
+        if luns.is_empty() {
+            let hdr = VirtioScsiCmdRespHeader {
+                response: VIRTIO_SCSI_S_BAD_TARGET as u8,
+                ..Default::default()
+            };
+            resp_writer.write_obj(hdr).map_err(ExecuteError::Write)?;
+            resp_writer.consume_bytes(sense_size as usize);
+            return Ok(());
+        }
+        let mut cdb = vec![0; cdb_size as usize];
+        reader.read_exact(&mut cdb).map_err(ExecuteError::Read)?;
+        let command = Command::new(&cdb)?;
+        let result = match logical_unit {
+            Some(logical_unit) => {
+                command
+                    .execute(reader, data_writer, &logical_unit, &luns)
+                    .await
             }
-            None => {
+            None => command.execute_without_unit(data_writer, &luns),
+        };
+        match result {
+            Ok(()) => {
                 let hdr = VirtioScsiCmdRespHeader {
-                    response: VIRTIO_SCSI_S_BAD_TARGET as u8,
-                    ..Default::default()
+                    sense_len: 0,
+                    resid: 0,
+                    status_qualifier: 0,
+                    status: GOOD,
+                    response: VIRTIO_SCSI_S_OK as u8,
                 };
                 resp_writer.write_obj(hdr).map_err(ExecuteError::Write)?;
                 resp_writer.consume_bytes(sense_size as usize);
                 Ok(())
             }
+            Err(err) => {
+                error!("error while executing a scsi request: {err}");
+                let (hdr, sense) = err.as_resp();
+                resp_writer.write_obj(hdr).map_err(ExecuteError::Write)?;
+                sense.write_to(resp_writer, sense_size)
+            }
         }
     }
-
-    fn get_logical_unit(
-        lun: [u8; 8],
-        targets: &BTreeMap<TargetId, AsyncLogicalUnit>,
-    ) -> Option<&AsyncLogicalUnit> {
-        // First byte should be 1.
-        if lun[0] != 1 {
-            return None;
-        }
-        // General search strategy for scsi devices is as follows:
-        // 1) Look for a device which has the same bus id and lun indicated by the given lun. If
-        //    there is one, that is the target device.
-        // 2) If we cannot find such device, then we return the first device that has the same bus
-        //    id.
-        // Since we only support one LUN per target, we only need to use the target id.
-        let target_id = lun[1];
-        targets.get(&target_id)
-    }
 }
 
 impl VirtioDevice for Controller {
     fn keep_rds(&self) -> Vec<base::RawDescriptor> {
-        match &self.targets {
+        let mut keep_rds: Vec<_> = match &self.targets {
             Some(targets) => targets
                 .0
                 .values()
                 .flat_map(|t| t.disk_image.as_raw_descriptors())
                 .collect(),
             None => vec![],
+        };
+        if let Some(control_tube) = &self.control_tube {
+            keep_rds.push(control_tube.as_raw_descriptor());
         }
+        keep_rds
     }
 
     fn features(&self) -> u64 {
@@ -629,14 +764,15 @@ impl VirtioDevice for Controller {
         // 0th virtqueue is the controlq.
         let controlq = queues.remove(&0).context("controlq should be present")?;
         // 1st virtqueue is the eventq.
-        // We do not send any events through eventq.
-        let _eventq = queues.remove(&1).context("eventq should be present")?;
+        // We only send events through eventq when logical units are added or removed.
+        let eventq = queues.remove(&1).context("eventq should be present")?;
         let targets = self.targets.take().context("failed to take SCSI targets")?;
-        let target_ids = targets.target_ids();
+        let addresses = targets.addresses();
+        let control_tube = self.control_tube.take();
         let sense_size = self.sense_size;
         let cdb_size = self.cdb_size;
         // The rest of the queues are request queues.
-        let request_queues = if self.multi_queue {
+        let request_queues: Vec<_> = if self.multi_queue {
             queues
                 .into_values()
                 .map(|queue| {
@@ -655,51 +791,57 @@ impl VirtioDevice for Controller {
                 targets,
             )]
         };
+        // The controlq worker forwards logical units added or removed through the control tube to
+        // the request queue workers.
+        let (update_senders, update_receivers): (Vec<_>, Vec<_>) =
+            request_queues.iter().map(|_| mpsc::unbounded()).unzip();
 
         let worker_thread = WorkerThread::start("v_scsi_ctrlq", move |kill_evt| {
             let ex =
                 Executor::with_executor_kind(executor_kind).expect("Failed to create an executor");
+            let hotplug = control_tube.map(|tube| Hotplug {
+                tube: AsyncTube::new(&ex, tube).expect("Failed to create async tube"),
+                update_senders,
+            });
             if let Err(err) = ex
-                .run_until(run_worker(
-                    &ex,
-                    controlq,
-                    kill_evt,
-                    QueueType::Control { target_ids },
-                    sense_size,
-                    cdb_size,
+                .run_until(run_control_worker(
+                    &ex, controlq, eventq, kill_evt, addresses, hotplug, sense_size, cdb_size,
                 ))
                 .expect("run_until failed")
             {
-                error!("run_worker failed: {err}");
+                error!("run_control_worker failed: {err}");
             }
         });
         self.worker_threads.push(worker_thread);
 
-        for (i, (queue, targets)) in request_queues.into_iter().enumerate() {
+        for (i, ((queue, targets), updates)) in
+            request_queues.into_iter().zip(update_receivers).enumerate()
+        {
             let worker_thread =
                 WorkerThread::start(format!("v_scsi_req_{}", i + 2), move |kill_evt| {
                     let ex = Executor::with_executor_kind(executor_kind)
                         .expect("Failed to create an executor");
-                    let async_logical_unit = targets
+                    let async_logical_units = targets
                         .0
                         .into_iter()
-                        .map(|(idx, unit)| match unit.make_async(&ex) {
-                            Ok(async_unit) => (idx, async_unit),
+                        .map(|(address, unit)| match unit.make_async(&ex) {
+                            Ok(async_unit) => (address, Rc::new(async_unit)),
                             Err(err) => panic!("{err}"),
                         })
                         .collect();
                     if let Err(err) = ex
-                        .run_until(run_worker(
+                        .run_until(run_request_worker(
                             &ex,
                             queue,
                             kill_evt,
-                            QueueType::Request(async_logical_unit),
+                            async_logical_units,
+                            updates,
                             sense_size,
                             cdb_size,
                         ))
                         .expect("run_until failed")
                     {
-                        error!("run_worker failed: {err}");
+                        error!("run_request_worker failed: {err}");
                     }
                 });
             self.worker_threads.push(worker_thread);
@@ -709,21 +851,102 @@ impl VirtioDevice for Controller {
 }
 
 enum QueueType {
-    Control { target_ids: BTreeSet<TargetId> },
-    Request(BTreeMap<TargetId, AsyncLogicalUnit>),
+    Control {
+        addresses: Rc<RefCell<BTreeSet<LunAddress>>>,
+    },
+    Request(Rc<RefCell<BTreeMap<LunAddress, Rc<AsyncLogicalUnit>>>>),
+}
+
+// A change to the logical units of a request queue worker. The sender is notified once the change
+// has been applied.
+enum LunUpdate {
+    Add(LunAddress, LogicalUnit, oneshot::Sender<anyhow::Result<()>>),
+    Remove(LunAddress, oneshot::Sender<anyhow::Result<()>>),
 }
 
-async fn run_worker(
+// Adds and removes logical units on requests from the control tube.
+struct Hotplug {
+    tube: AsyncTube,
+    // One sender for each request queue worker.
+    update_senders: Vec<mpsc::UnboundedSender<LunUpdate>>,
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn run_control_worker(
+    ex: &Executor,
+    controlq: Queue,
+    eventq: Queue,
+    kill_evt: Event,
+    addresses: BTreeSet<LunAddress>,
+    hotplug: Option<Hotplug>,
+    sense_size: u32,
+    cdb_size: u32,
+) -> anyhow::Result<()> {
+    let kill = async_utils::await_and_exit(ex, kill_evt).fuse();
+    pin_mut!(kill);
+
+    let addresses = Rc::new(RefCell::new(addresses));
+    let kick_evt = controlq
+        .event()
+        .try_clone()
+        .expect("Failed to clone queue event");
+    let controlq_handler = handle_queue(
+        Rc::new(RefCell::new(controlq)),
+        EventAsync::new(kick_evt, ex).expect("Failed to create async event for queue"),
+        QueueType::Control {
+            addresses: addresses.clone(),
+        },
+        sense_size,
+        cdb_size,
+    )
+    .fuse();
+    pin_mut!(controlq_handler);
+
+    let kick_evt = eventq
+        .event()
+        .try_clone()
+        .expect("Failed to clone queue event");
+    let eventq = RefCell::new(eventq);
+    let pending_events = RefCell::new(VecDeque::new());
+    let eventq_handler = handle_event_queue(
+        &eventq,
+        EventAsync::new(kick_evt, ex).expect("Failed to create async event for queue"),
+        &pending_events,
+    )
+    .fuse();
+    pin_mut!(eventq_handler);
+
+    let hotplug_handler = async {
+        if let Some(hotplug) = &hotplug {
+            handle_hotplug(hotplug, &addresses, &eventq, &pending_events).await;
+        }
+        // Keep handling the queues without hotplug.
+        futures::future::pending::<()>().await
+    }
+    .fuse();
+    pin_mut!(hotplug_handler);
+
+    futures::select! {
+        _ = controlq_handler => anyhow::bail!("controlq handler exited unexpectedly"),
+        _ = eventq_handler => anyhow::bail!("eventq handler exited unexpectedly"),
+        _ = hotplug_handler => anyhow::bail!("hotplug handler exited unexpectedly"),
+        r = kill => r.context("failed to wait on the kill event"),
+    }
+}
+
+async fn run_request_worker(
     ex: &Executor,
     queue: Queue,
     kill_evt: Event,
-    queue_type: QueueType,
+    logical_units: BTreeMap<LunAddress, Rc<AsyncLogicalUnit>>,
+    updates: mpsc::UnboundedReceiver<LunUpdate>,
     sense_size: u32,
     cdb_size: u32,
 ) -> anyhow::Result<()> {
     let kill = async_utils::await_and_exit(ex, kill_evt).fuse();
     pin_mut!(kill);
 
+    let logical_units = Rc::new(RefCell::new(logical_units));
     let kick_evt = queue
         .event()
         .try_clone()
@@ -731,19 +954,238 @@ async fn run_worker(
     let queue_handler = handle_queue(
         Rc::new(RefCell::new(queue)),
         EventAsync::new(kick_evt, ex).expect("Failed to create async event for queue"),
-        queue_type,
+        QueueType::Request(logical_units.clone()),
         sense_size,
         cdb_size,
     )
     .fuse();
     pin_mut!(queue_handler);
 
+    let update_handler = handle_lun_updates(ex, updates, &logical_units).fuse();
+    pin_mut!(update_handler);
+
     futures::select! {
         _ = queue_handler => anyhow::bail!("queue handler exited unexpectedly"),
+        _ = update_handler => anyhow::bail!("update handler exited unexpectedly"),
         r = kill => r.context("failed to wait on the kill event"),
     }
 }
 
+async fn handle_lun_updates(
+    ex: &Executor,
+    mut updates: mpsc::UnboundedReceiver<LunUpdate>,
+    logical_units: &RefCell<BTreeMap<LunAddress, Rc<AsyncLogicalUnit>>>,
+) {
+    while let Some(update) = updates.next().await {
+        match update {
+            LunUpdate::Add(address, unit, done) => {
+                let result = unit.make_async(ex).map(|unit| {
+                    logical_units.borrow_mut().insert(address, Rc::new(unit));
+                });
+                let _ = done.send(result);
+            }
+            LunUpdate::Remove(address, done) => {
+                // Requests in flight hold a reference to the logical unit until they complete.
+                logical_units.borrow_mut().remove(&address);
+                let _ = done.send(Ok(()));
+            }
+        }
+    }
+    // The controlq worker is gone, so there won't be any more updates.
+    futures::future::pending().await
+}
+
+async fn handle_hotplug(
+    hotplug: &Hotplug,
+    addresses: &RefCell<BTreeSet<LunAddress>>,
+    eventq: &RefCell<Queue>,
+    pending_events: &RefCell<VecDeque<virtio_scsi_event>>,
+) {
+    loop {
+        let command = match hotplug.tube.next::<ScsiControlCommand>().await {
+            Ok(command) => command,
+            Err(e) => {
+                error!("failed to read the scsi control command: {e}");
+                return;
+            }
+        };
+        let (address, reason, result) = match command {
+            ScsiControlCommand::AddLun {
+                target,
+                lun,
+                media,
+                read_only,
+                block_size,
+            } => {
+                let address = (target, lun);
+                let result = add_lun(
+                    address,
+                    media,
+                    read_only,
+                    block_size,
+                    addresses,
+                    &hotplug.update_senders,
+                )
+                .await;
+                (address, VIRTIO_SCSI_EVT_RESET_RESCAN, result)
+            }
+            ScsiControlCommand::RemoveLun { target, lun } => {
+                let address = (target, lun);
+                let result = remove_lun(address, addresses, &hotplug.update_senders).await;
+                (address, VIRTIO_SCSI_EVT_RESET_REMOVED, result)
+            }
+        };
+        let resp = match result {
+            Ok(()) => {
+                pending_events.borrow_mut().push_back(virtio_scsi_event {
+                    event: VIRTIO_SCSI_T_TRANSPORT_RESET,
+                    lun: encode_lun(address),
+                    reason,
+                });
+                send_events(eventq, pending_events);
+                ScsiControlResult::Ok
+            }
+            Err(e) => ScsiControlResult::Err(e),
+        };
+        if let Err(e) = hotplug.tube.send(resp).await {
+            error!("failed to send the scsi control result: {e}");
+        }
+    }
+}
+
+async fn add_lun(
+    address: LunAddress,
+    media: DiskMedia,
+    read_only: bool,
+    block_size: u32,
+    addresses: &RefCell<BTreeSet<LunAddress>>,
+    update_senders: &[mpsc::UnboundedSender<LunUpdate>],
+) -> Result<(), SysError> {
+    let (target, lun) = address;
+    if lun as u32 > DEFAULT_MAX_LUN || block_size == 0 {
+        error!("invalid LUN {lun} or block size {block_size}");
+        return Err(SysError::new(libc::EINVAL));
+    }
+    if addresses.borrow().contains(&address) {
+        error!("target {target} already has LUN {lun}");
+        return Err(SysError::new(libc::EEXIST));
+    }
+    info!(
+        "attaching {} to target {target} as LUN {lun}",
+        media.path.display()
+    );
+    let logical_unit = match super::sys::open_media(media.path, media.file, read_only)
+        .and_then(|disk_image| LogicalUnit::new(disk_image, block_size, read_only))
+    {
+        Ok(logical_unit) => logical_unit,
+        Err(e) => {
+            error!("failed to open the disk image: {e:#}");
+            return Err(SysError::new(libc::EINVAL));
+        }
+    };
+    // Each request queue worker needs its own copy of the disk image.
+    let mut logical_units = Vec::with_capacity(update_senders.len());
+    for _ in 1..update_senders.len() {
+        match logical_unit.try_clone() {
+            Ok(logical_unit) => logical_units.push(logical_unit),
+            Err(e) => {
+                error!("failed to clone the disk image for multiple request queues: {e}");
+                return Err(SysError::new(libc::ENOTSUP));
+            }
+        }
+    }
+    logical_units.push(logical_unit);
+    let result = update_workers(update_senders, |done| {
+        LunUpdate::Add(
+            address,
+            logical_units.pop().expect("one logical unit per worker"),
+            done,
+        )
+    })
+    .await;
+    if let Err(e) = result {
+        error!("failed to add target {target} LUN {lun}: {e:#}");
+        // Don't leave the logical unit behind in the workers that did add it.
+        let _ = update_workers(update_senders, |done| LunUpdate::Remove(address, done)).await;
+        return Err(SysError::new(libc::EIO));
+    }
+    addresses.borrow_mut().insert(address);
+    Ok(())
+}
+
+async fn remove_lun(
+    address: LunAddress,
+    addresses: &RefCell<BTreeSet<LunAddress>>,
+    update_senders: &[mpsc::UnboundedSender<LunUpdate>],
+) -> Result<(), SysError> {
+    let (target, lun) = address;
+    if !addresses.borrow_mut().remove(&address) {
+        error!("target {target} has no LUN {lun}");
+        return Err(SysError::new(libc::ENOENT));
+    }
+    info!("detaching LUN {lun} from target {target}");
+    if let Err(e) = update_workers(update_senders, |done| LunUpdate::Remove(address, done)).await {
+        error!("failed to remove target {target} LUN {lun}: {e:#}");
+        return Err(SysError::new(libc::EIO));
+    }
+    Ok(())
+}
+
+// Sends an update to every request queue worker and waits for all of them to apply it.
+async fn update_workers(
+    update_senders: &[mpsc::UnboundedSender<LunUpdate>],
+    mut update: impl FnMut(oneshot::Sender<anyhow::Result<()>>) -> LunUpdate,
+) -> anyhow::Result<()> {
+    let mut results = Vec::with_capacity(update_senders.len());
+    for sender in update_senders {
+        let (done, result) = oneshot::channel();
+        sender
+            .unbounded_send(update(done))
+            .map_err(|_| anyhow!("request queue worker is gone"))?;
+        results.push(result);
+    }
+    for result in results {
+        result.await.context("request queue worker is gone")??;
+    }
+    Ok(())
+}
+
+async fn handle_event_queue(
+    eventq: &RefCell<Queue>,
+    evt: EventAsync,
+    pending_events: &RefCell<VecDeque<virtio_scsi_event>>,
+) {
+    loop {
+        if let Err(e) = evt.next_val().await {
+            error!("Failed to read the next queue event: {e}");
+            continue;
+        }
+        send_events(eventq, pending_events);
+    }
+}
+
+// Delivers as many pending events as the driver has provided buffers for.
+fn send_events(eventq: &RefCell<Queue>, pending_events: &RefCell<VecDeque<virtio_scsi_event>>) {
+    let mut eventq = eventq.borrow_mut();
+    let mut pending_events = pending_events.borrow_mut();
+    let mut sent = false;
+    while let Some(event) = pending_events.front() {
+        let Some(mut chain) = eventq.pop() else {
+            break;
+        };
+        if let Err(e) = chain.writer.write_obj(*event) {
+            error!("failed to write a scsi event: {e}");
+        }
+        let len = chain.writer.bytes_written();
+        eventq.add_used(chain, len as u32);
+        pending_events.pop_front();
+        sent = true;
+    }
+    if sent {
+        eventq.trigger_interrupt();
+    }
+}
+
 async fn handle_queue(
     queue: Rc<RefCell<Queue>>,
     evt: EventAsync,
@@ -800,8 +1242,9 @@ async fn process_one_request(
     let reader = &mut avail_desc.reader;
     let resp_writer = &mut avail_desc.writer;
     match queue_type {
-        QueueType::Control { target_ids } => {
-            if let Err(err) = Controller::execute_control(reader, resp_writer, target_ids) {
+        QueueType::Control { addresses } => {
+            if let Err(err) = Controller::execute_control(reader, resp_writer, &addresses.borrow())
+            {
                 error!("failed to execute control request: {err}");
             }
             resp_writer.bytes_written()
@@ -869,26 +1312,30 @@ mod tests {
         (f, file_content)
     }
 
-    fn build_read_req_header(target_id: u8, start_lba: u8, xfer_blocks: u8) -> virtio_scsi_cmd_req {
+    fn build_read_req_header(
+        address: LunAddress,
+        start_lba: u8,
+        xfer_blocks: u8,
+    ) -> virtio_scsi_cmd_req {
         let mut cdb = [0; 32];
         cdb[0] = READ_10;
         cdb[5] = start_lba;
         cdb[8] = xfer_blocks;
         virtio_scsi_cmd_req {
-            lun: [1, 0, 0, target_id, 0, 0, 0, 0],
+            lun: encode_lun(address),
             cdb,
             ..Default::default()
         }
     }
 
     fn setup_desciptor_chain(
-        target_id: TargetId,
+        address: LunAddress,
         start_lba: u8,
         xfer_blocks: u8,
         block_size: u32,
         mem: &Rc<GuestMemory>,
     ) -> DescriptorChain {
-        let req_hdr = build_read_req_header(target_id, start_lba, xfer_blocks);
+        let req_hdr = build_read_req_header(address, start_lba, xfer_blocks);
         let xfer_bytes = xfer_blocks as u32 * block_size;
         create_descriptor_chain(
             mem,
@@ -911,8 +1358,8 @@ mod tests {
 
     fn read_blocks(
         ex: &Executor,
-        file_disks: &[File],
-        target_id: u8,
+        file_disks: &[(LunAddress, File)],
+        address: LunAddress,
         start_lba: u8,
         xfer_blocks: u8,
         block_size: u32,
@@ -922,16 +1369,15 @@ mod tests {
             GuestMemory::new(&[(GuestAddress(0u64), 4 * 1024 * 1024)])
                 .expect("Creating guest memory failed."),
         );
-        let req_hdr = build_read_req_header(target_id, start_lba, xfer_blocks);
+        let req_hdr = build_read_req_header(address, start_lba, xfer_blocks);
         mem.write_obj_at_addr(req_hdr, GuestAddress(0x1000))
             .expect("writing req failed");
 
-        let mut avail_desc = setup_desciptor_chain(target_id, 0, xfer_blocks, block_size, &mem);
+        let mut avail_desc = setup_desciptor_chain(address, 0, xfer_blocks, block_size, &mem);
 
         let targets = file_disks
             .iter()
-            .enumerate()
-            .map(|(i, file)| {
+            .map(|(address, file)| {
                 let file = file.try_clone().unwrap();
                 let disk_image = Box::new(SingleFileDisk::new(file, ex).unwrap());
                 let logical_unit = AsyncLogicalUnit {
@@ -939,18 +1385,19 @@ mod tests {
                     block_size,
                     read_only: false,
                     disk_image,
+                    reservations: Default::default(),
                 };
-                (i as TargetId, logical_unit)
+                (*address, Rc::new(logical_unit))
             })
             .collect();
         ex.run_until(process_one_request(
             &mut avail_desc,
-            &QueueType::Request(targets),
+            &QueueType::Request(Rc::new(RefCell::new(targets))),
             VIRTIO_SCSI_SENSE_DEFAULT_SIZE,
             VIRTIO_SCSI_CDB_DEFAULT_SIZE,
         ))
         .expect("running executor failed");
-        let resp_offset = GuestAddress((0x1000 + size_of::<virtio_scsi_cmd_resp>()) as u64);
+        let resp_offset = GuestAddress((0x1000 + size_of::<virtio_scsi_cmd_req>()) as u64);
         let resp = mem
             .read_obj_from_addr::<virtio_scsi_cmd_resp>(resp_offset)
             .unwrap();
@@ -966,7 +1413,7 @@ mod tests {
     }
 
     fn test_read_blocks(
-        num_targets: usize,
+        addresses: &[LunAddress],
         blocks: u8,
         start_lba: u8,
         xfer_blocks: u8,
@@ -977,17 +1424,16 @@ mod tests {
         let xfer_bytes = xfer_blocks as usize * block_size as usize;
         let start_off = start_lba as usize * block_size as usize;
 
-        let (files, file_contents): (Vec<_>, Vec<_>) =
-            (0..num_targets).map(|_| setup_disk(file_len)).unzip();
-        for (target_id, file_content) in file_contents.iter().enumerate() {
-            let (resp, dataout) = read_blocks(
-                &ex,
-                &files,
-                target_id as TargetId,
-                start_lba,
-                xfer_blocks,
-                block_size,
-            );
+        let (files, file_contents): (Vec<_>, Vec<_>) = addresses
+            .iter()
+            .map(|address| {
+                let (file, file_content) = setup_disk(file_len);
+                ((*address, file), file_content)
+            })
+            .unzip();
+        for (address, file_content) in addresses.iter().zip(file_contents) {
+            let (resp, dataout) =
+                read_blocks(&ex, &files, *address, start_lba, xfer_blocks, block_size);
 
             let sense_len = resp.sense_len;
             assert_eq!(sense_len, 0);
@@ -1005,9 +1451,9 @@ mod tests {
         let start_lba = 0u8;
         let xfer_blocks = 3u8;
 
-        test_read_blocks(1, blocks, start_lba, xfer_blocks, 64u32);
-        test_read_blocks(1, blocks, start_lba, xfer_blocks, 128u32);
-        test_read_blocks(1, blocks, start_lba, xfer_blocks, 512u32);
+        test_read_blocks(&[(0, 0)], blocks, start_lba, xfer_blocks, 64u32);
+        test_read_blocks(&[(0, 0)], blocks, start_lba, xfer_blocks, 128u32);
+        test_read_blocks(&[(0, 0)], blocks, start_lba, xfer_blocks, 512u32);
     }
 
     #[test]
@@ -1017,9 +1463,9 @@ mod tests {
         let start_lba = 1u8;
         let xfer_blocks = 3u8;
 
-        test_read_blocks(1, blocks, start_lba, xfer_blocks, 64u32);
-        test_read_blocks(1, blocks, start_lba, xfer_blocks, 128u32);
-        test_read_blocks(1, blocks, start_lba, xfer_blocks, 512u32);
+        test_read_blocks(&[(0, 0)], blocks, start_lba, xfer_blocks, 64u32);
+        test_read_blocks(&[(0, 0)], blocks, start_lba, xfer_blocks, 128u32);
+        test_read_blocks(&[(0, 0)], blocks, start_lba, xfer_blocks, 512u32);
     }
 
     #[test]
@@ -1029,9 +1475,27 @@ mod tests {
         let start_lba = 0u8;
         let xfer_blocks = 3u8;
 
-        test_read_blocks(3, blocks, start_lba, xfer_blocks, 64u32);
-        test_read_blocks(3, blocks, start_lba, xfer_blocks, 128u32);
-        test_read_blocks(3, blocks, start_lba, xfer_blocks, 512u32);
+        test_read_blocks(
+            &[(0, 0), (1, 0), (2, 0)],
+            blocks,
+            start_lba,
+            xfer_blocks,
+            64u32,
+        );
+        test_read_blocks(
+            &[(0, 0), (1, 0), (2, 0)],
+            blocks,
+            start_lba,
+            xfer_blocks,
+            128u32,
+        );
+        test_read_blocks(
+            &[(0, 0), (1, 0), (2, 0)],
+            blocks,
+            start_lba,
+            xfer_blocks,
+            512u32,
+        );
     }
 
     #[test]
@@ -1041,8 +1505,91 @@ mod tests {
         let start_lba = 1u8;
         let xfer_blocks = 3u8;
 
-        test_read_blocks(3, blocks, start_lba, xfer_blocks, 64u32);
-        test_read_blocks(3, blocks, start_lba, xfer_blocks, 128u32);
-        test_read_blocks(3, blocks, start_lba, xfer_blocks, 512u32);
+        test_read_blocks(
+            &[(0, 0), (1, 0), (2, 0)],
+            blocks,
+            start_lba,
+            xfer_blocks,
+            64u32,
+        );
+        test_read_blocks(
+            &[(0, 0), (1, 0), (2, 0)],
+            blocks,
+            start_lba,
+            xfer_blocks,
+            128u32,
+        );
+        test_read_blocks(
+            &[(0, 0), (1, 0), (2, 0)],
+            blocks,
+            start_lba,
+            xfer_blocks,
+            512u32,
+        );
+    }
+
+    #[test]
+    fn read_blocks_with_multiple_luns() {
+        // Read 3 blocks from the 2nd block in the 8-block device.
+        let blocks = 8u8;
+        let start_lba = 1u8;
+        let xfer_blocks = 3u8;
+
+        let addresses = [(0, 0), (0, 1), (0, 300), (3, 7)];
+        test_read_blocks(&addresses, blocks, start_lba, xfer_blocks, 512u32);
+    }
+
+    #[test]
+    fn read_blocks_from_missing_lun() {
+        let ex = Executor::new().expect("creating an executor failed");
+        let (file, _) = setup_disk(8 * 512);
+        let files = [((0, 0), file)];
+
+        // The target exists but does not have the LUN.
+        let (resp, _) = read_blocks(&ex, &files, (0, 1), 0, 1, 512);
+        assert_eq!(resp.response, VIRTIO_SCSI_S_OK as u8);
+        assert_eq!(resp.status, CHECK_CONDITION);
+        // The target does not exist.
+        let (resp, _) = read_blocks(&ex, &files, (1, 0), 0, 1, 512);
+        assert_eq!(resp.response, VIRTIO_SCSI_S_BAD_TARGET as u8);
+    }
+
+    #[test]
+    fn lun_encoding() {
+        assert_eq!(decode_lun(encode_lun((0, 0))), Some((0, 0)));
+        assert_eq!(decode_lun(encode_lun((2, 300))), Some((2, 300)));
+        assert_eq!(decode_lun([0, 2, 0, 0, 0, 0, 0, 0]), None);
+        // LUNs below 256 may also use the peripheral device addressing method.
+        assert_eq!(decode_lun([1, 2, 0, 5, 0, 0, 0, 0]), Some((2, 5)));
+    }
+
+    #[test]
+    fn tmf_logical_unit_reset() {
+        let addresses = BTreeSet::from([(0, 0), (0, 2)]);
+        let reset = |address, subtype| {
+            let tmf = virtio_scsi_ctrl_tmf_req {
+                type_: VIRTIO_SCSI_T_TMF,
+                subtype,
+                lun: encode_lun(address),
+                ..Default::default()
+            };
+            Controller::execute_tmf(tmf, &addresses).response as u32
+        };
+        assert_eq!(
+            reset((0, 2), VIRTIO_SCSI_T_TMF_LOGICAL_UNIT_RESET),
+            VIRTIO_SCSI_S_FUNCTION_SUCCEEDED
+        );
+        assert_eq!(
+            reset((0, 1), VIRTIO_SCSI_T_TMF_LOGICAL_UNIT_RESET),
+            VIRTIO_SCSI_S_INCORRECT_LUN
+        );
+        assert_eq!(
+            reset((0, 1), VIRTIO_SCSI_T_TMF_I_T_NEXUS_RESET),
+            VIRTIO_SCSI_S_FUNCTION_SUCCEEDED
+        );
+        assert_eq!(
+            reset((1, 0), VIRTIO_SCSI_T_TMF_LOGICAL_UNIT_RESET),
+            VIRTIO_SCSI_S_BAD_TARGET
+        );
     }
 }
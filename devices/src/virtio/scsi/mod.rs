@@ -42,6 +42,32 @@ pub struct ScsiOption {
     /// adding specific command-line options.
     #[serde(default)]
     pub root: bool,
+    /// The target the logical unit belongs to. Defaults to the position of the disk among the
+    /// scsi devices.
+    #[serde(default)]
+    pub target: Option<u8>,
+    /// The logical unit number of the disk in its target.
+    #[serde(default)]
+    pub lun: u16,
+}
+
+impl ScsiOption {
+    /// Returns the target and LUN of the disk, given that it is the `index`-th scsi device.
+    ///
+    /// Fails if the disk has no explicit target and `index` doesn't fit in a target id.
+    pub fn address(&self, index: usize) -> Result<(u8, u16), String> {
+        let target = match self.target {
+            Some(target) => target,
+            None => u8::try_from(index).map_err(|_| {
+                format!(
+                    "scsi device {} needs an explicit target: only the first 256 scsi devices get \
+                     a default one",
+                    self.path.display()
+                )
+            })?,
+        };
+        Ok((target, self.lun))
+    }
 }
 
 #[cfg(test)]
@@ -63,6 +89,8 @@ mod tests {
                 lock: scsi_option_lock_default(),
                 block_size: 512,
                 root: false,
+                target: None,
+                lun: 0,
             }
         );
 
@@ -75,6 +103,8 @@ mod tests {
                 lock: scsi_option_lock_default(),
                 block_size: 512,
                 root: false,
+                target: None,
+                lun: 0,
             }
         );
 
@@ -87,6 +117,8 @@ mod tests {
                 lock: scsi_option_lock_default(),
                 block_size: 1024,
                 root: false,
+                target: None,
+                lun: 0,
             }
         );
 
@@ -100,7 +132,33 @@ mod tests {
                 lock: scsi_option_lock_default(),
                 block_size: 1024,
                 root: true,
+                target: None,
+                lun: 0,
+            }
+        );
+
+        let scsi_option = from_key_values::<ScsiOption>("/path/to/image,target=2,lun=300").unwrap();
+        assert_eq!(
+            scsi_option,
+            ScsiOption {
+                path: Path::new("/path/to/image").to_path_buf(),
+                read_only: false,
+                lock: scsi_option_lock_default(),
+                block_size: 512,
+                root: false,
+                target: Some(2),
+                lun: 300,
             }
         );
+        assert_eq!(scsi_option.address(0), Ok((2, 300)));
+        assert_eq!(scsi_option.address(300), Ok((2, 300)));
+    }
+
+    #[test]
+    fn address_default_target() {
+        let scsi_option = from_key_values::<ScsiOption>("/path/to/image").unwrap();
+        assert_eq!(scsi_option.address(3), Ok((3, 0)));
+        assert_eq!(scsi_option.address(255), Ok((255, 0)));
+        assert!(scsi_option.address(256).is_err());
     }
 }
@@ -2,6 +2,9 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std::fs::File;
+use std::path::PathBuf;
+
 use anyhow::Context;
 use disk::DiskFile;
 
@@ -22,3 +25,21 @@ impl ScsiOption {
         .context("open_disk_file failed")
     }
 }
+
+/// Create a disk file for a logical unit that was opened from `path` by the sender of a hotplug
+/// command.
+pub fn open_media(path: PathBuf, file: File, read_only: bool) -> anyhow::Result<Box<dyn DiskFile>> {
+    disk::create_disk_file(
+        file,
+        disk::DiskFileParams {
+            path,
+            is_read_only: read_only,
+            is_sparse_file: true,
+            is_overlapped: false,
+            is_direct: false,
+            lock: false,
+            depth: 0,
+        },
+    )
+    .context("create_disk_file failed")
+}
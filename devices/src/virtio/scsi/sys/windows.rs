@@ -2,6 +2,9 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std::fs::File;
+use std::path::PathBuf;
+
 use anyhow::bail;
 use anyhow::Context;
 use disk::DiskFile;
@@ -13,3 +16,11 @@ impl ScsiOption {
         bail!("ScsiOption::open() is yet to be implemented for windows.")
     }
 }
+
+pub fn open_media(
+    _path: PathBuf,
+    _file: File,
+    _read_only: bool,
+) -> anyhow::Result<Box<dyn DiskFile>> {
+    bail!("open_media() is yet to be implemented for windows.")
+}
@@ -92,6 +92,10 @@ impl VhostUserDevice for ConsoleBackend {
         self.device.console.read_config(offset, data);
     }
 
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        self.device.console.write_config(offset, data);
+    }
+
     fn reset(&mut self) {
         if let Err(e) = self.device.console.reset() {
             error!("console reset failed: {:#}", e);
@@ -208,13 +212,20 @@ pub fn create_vu_console_device(
     params: &SerialParameters,
     keep_rds: &mut Vec<RawDescriptor>,
 ) -> anyhow::Result<VhostUserConsoleDevice> {
-    let device = params.create_serial_device::<ConsoleDevice>(
+    let mut device = params.create_serial_device::<ConsoleDevice>(
         ProtectionType::Unprotected,
         // We need to pass an event as per Serial Device API but we don't really use it anyway.
         &Event::new()?,
         keep_rds,
     )?;
 
+    if params.stdin {
+        // Report the size of the terminal the input comes from, if any.
+        if let Ok((cols, rows)) = std::io::stdin().window_size() {
+            device.resize(0, cols, rows)?;
+        }
+    }
+
     Ok(VhostUserConsoleDevice {
         console: device,
         raw_stdin: params.stdin,
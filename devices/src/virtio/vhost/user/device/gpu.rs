@@ -135,7 +135,7 @@ impl VhostUserDevice for GpuBackend {
         self.gpu.borrow().read_config(offset, dst)
     }
 
-    fn write_config(&self, offset: u64, data: &[u8]) {
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
         self.gpu.borrow_mut().write_config(offset, data)
     }
 
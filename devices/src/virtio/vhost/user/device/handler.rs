@@ -154,7 +154,7 @@ pub trait VhostUserDevice {
     fn read_config(&self, offset: u64, dst: &mut [u8]);
 
     /// writes `data` to this device's configuration space at `offset`.
-    fn write_config(&self, _offset: u64, _data: &[u8]) {}
+    fn write_config(&mut self, _offset: u64, _data: &[u8]) {}
 
     /// Indicates that the backend should start processing requests for virtio queue number `idx`.
     /// This method must not block the current thread so device backends should either spawn an
@@ -68,7 +68,12 @@ async fn run_tx_queue<T: TapT>(
             }
         }
 
-        process_tx(&mut queue, &mut tap);
+        process_tx(
+            &mut queue,
+            &mut tap,
+            #[cfg(any(target_os = "android", target_os = "linux"))]
+            None,
+        );
     }
     queue
 }
@@ -97,7 +102,14 @@ async fn run_ctrl_queue<T: TapT>(
             }
         }
 
-        if let Err(e) = process_ctrl(&mut queue, &mut tap, acked_features, vq_pairs) {
+        if let Err(e) = process_ctrl(
+            &mut queue,
+            &mut tap,
+            acked_features,
+            vq_pairs,
+            #[cfg(any(target_os = "android", target_os = "linux"))]
+            None,
+        ) {
             error!("Failed to process ctrl queue: {}", e);
             break;
         }
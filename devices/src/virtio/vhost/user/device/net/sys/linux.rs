@@ -160,7 +160,7 @@ async fn run_rx_queue<T: TapT>(
             }
         }
 
-        match process_rx(&mut queue, tap.as_source_mut()) {
+        match process_rx(&mut queue, tap.as_source_mut(), None, None) {
             Ok(()) => {}
             Err(NetError::RxDescriptorsExhausted) => {
                 select_biased! {
@@ -4,10 +4,16 @@
 
 //! This module implements the virtio vsock device.
 //!
-//! Currently, this is only implemented for Windows.
-//! For Linux, please use the vhost-vsock device, which delegates the vsock
-//! implementation to the kernel.
+//! On Windows, connections are bridged to named pipes of the host.
+//!
+//! On Linux, the vhost-vsock device, which delegates the vsock implementation
+//! to the kernel, is used by default. A userspace device that bridges guest
+//! connections to Unix domain sockets of the host is used instead when a
+//! socket path is configured, which doesn't need the vhost-vsock kernel module.
 
+pub mod protocol;
 mod sys;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub use sys::UserspaceVsock;
 pub use sys::Vsock;
 pub use sys::VsockConfig;
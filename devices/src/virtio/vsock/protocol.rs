@@ -12,6 +12,9 @@ use zerocopy::KnownLayout;
 
 pub const TYPE_STREAM_SOCKET: u16 = 1;
 
+/// CID of the host, the only peer of the guest.
+pub const VMADDR_CID_HOST: u64 = 2;
+
 /// virtio_vsock_config is the vsock device configuration space defined by the virtio spec.
 #[derive(Copy, Clone, Debug, Default, FromBytes, Immutable, IntoBytes, KnownLayout)]
 #[repr(C)]
@@ -61,3 +64,11 @@ pub mod vsock_op {
     /* Request the peer to send the credit info to us */
     pub const VIRTIO_VSOCK_OP_CREDIT_REQUEST: u16 = 7;
 }
+
+/// Flags of `VIRTIO_VSOCK_OP_SHUTDOWN` packets.
+pub mod shutdown_flags {
+    /// The sender will not receive any more data.
+    pub const VIRTIO_VSOCK_SHUTDOWN_F_RECEIVE: u32 = 1;
+    /// The sender will not send any more data.
+    pub const VIRTIO_VSOCK_SHUTDOWN_F_SEND: u32 = 2;
+}
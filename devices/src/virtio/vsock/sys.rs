@@ -6,6 +6,7 @@ cfg_if::cfg_if! {
     if #[cfg(any(target_os = "android", target_os = "linux"))] {
         mod linux;
         use linux as platform;
+        pub use linux::UserspaceVsock;
         pub use crate::virtio::vhost::Vsock;
     } else if #[cfg(windows)] {
         mod windows;
@@ -2,12 +2,16 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+mod muxer;
+mod userspace;
+
 use std::path::Path;
 use std::path::PathBuf;
 
 use serde::Deserialize;
 use serde::Serialize;
 use serde_keyvalue::FromKeyValues;
+pub use userspace::UserspaceVsock;
 
 static VHOST_VSOCK_DEFAULT_PATH: &str = "/dev/vhost-vsock";
 
@@ -26,6 +30,11 @@ pub struct VsockConfig {
     pub vhost_device: PathBuf,
     #[serde(default)]
     pub max_queue_sizes: Option<[u16; 3]>,
+    /// Path of the Unix domain socket on which host programs connect to the guest. If set, a
+    /// userspace device that forwards guest connections to port `P` to the socket at
+    /// `<uds_path>_P` is used instead of the vhost-vsock device.
+    #[serde(default)]
+    pub uds_path: Option<PathBuf>,
 }
 
 impl VsockConfig {
@@ -39,6 +48,7 @@ impl VsockConfig {
                 .map(|p| PathBuf::from(p.as_ref()))
                 .unwrap_or_else(|| PathBuf::from(VHOST_VSOCK_DEFAULT_PATH)),
             max_queue_sizes: None,
+            uds_path: None,
         }
     }
 }
@@ -64,6 +74,7 @@ mod tests {
                 vhost_device: VHOST_VSOCK_DEFAULT_PATH.into(),
                 cid: 56,
                 max_queue_sizes: None,
+                uds_path: None,
             }
         );
 
@@ -84,6 +95,7 @@ mod tests {
                 vhost_device: VHOST_VSOCK_DEFAULT_PATH.into(),
                 cid: 78,
                 max_queue_sizes: None,
+                uds_path: None,
             }
         );
 
@@ -101,7 +113,7 @@ mod tests {
             from_vsock_arg("invalid=foo").unwrap_err(),
             ParseError {
                 kind: ErrorKind::SerdeError(
-                    "unknown field `invalid`, expected one of `cid`, `device`, `max-queue-sizes`"
+                    "unknown field `invalid`, expected one of `cid`, `device`, `max-queue-sizes`, `uds-path`"
                         .into()
                 ),
                 pos: 0,
@@ -115,6 +127,7 @@ mod tests {
                 vhost_device: "/some/path".into(),
                 cid: 56,
                 max_queue_sizes: None,
+                uds_path: None,
             }
         );
 
@@ -125,6 +138,7 @@ mod tests {
                 vhost_device: "/some/path".into(),
                 cid: 56,
                 max_queue_sizes: None,
+                uds_path: None,
             }
         );
 
@@ -153,6 +167,18 @@ mod tests {
                 vhost_device: VHOST_VSOCK_DEFAULT_PATH.into(),
                 cid: 56,
                 max_queue_sizes: Some([1, 2, 4]),
+                uds_path: None,
+            }
+        );
+
+        // Userspace device
+        assert_eq!(
+            from_vsock_arg("cid=56,uds-path=/run/vm.vsock").unwrap(),
+            VsockConfig {
+                vhost_device: VHOST_VSOCK_DEFAULT_PATH.into(),
+                cid: 56,
+                max_queue_sizes: None,
+                uds_path: Some("/run/vm.vsock".into()),
             }
         );
     }
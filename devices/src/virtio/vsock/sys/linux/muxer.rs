@@ -0,0 +1,936 @@
+// Copyright 2025 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Bridge between the stream connections of the guest and Unix domain sockets of the host.
+//!
+//! A connection from the guest to port `P` of the host is forwarded to the socket listening at
+//! `<uds_path>_P`. Host programs connect to port `P` of the guest by connecting to the socket
+//! listening at `uds_path` and writing `CONNECT P\n`. Once the guest accepts the connection, the
+//! device answers `OK <host port>\n` and forwards the rest of the stream; the socket is closed if
+//! the guest refuses it.
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::VecDeque;
+use std::io;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Write;
+use std::net::Shutdown;
+use std::num::Wrapping;
+use std::os::unix::net::UnixListener;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::Context;
+use base::error;
+use base::warn;
+use base::AsRawDescriptor;
+use base::EventToken;
+use base::EventType;
+use base::RawDescriptor;
+use base::WaitContext;
+
+use crate::virtio::vsock::protocol::shutdown_flags::VIRTIO_VSOCK_SHUTDOWN_F_RECEIVE;
+use crate::virtio::vsock::protocol::shutdown_flags::VIRTIO_VSOCK_SHUTDOWN_F_SEND;
+use crate::virtio::vsock::protocol::virtio_vsock_hdr;
+use crate::virtio::vsock::protocol::vsock_op;
+use crate::virtio::vsock::protocol::TYPE_STREAM_SOCKET;
+use crate::virtio::vsock::protocol::VMADDR_CID_HOST;
+
+/// Buffer space the device advertises to the guest for each connection.
+const BUF_ALLOC: u32 = 256 * 1024;
+/// Largest payload of the packets sent to the guest, like the vhost-vsock driver of Linux.
+pub const MAX_PKT_PAYLOAD: usize = 64 * 1024;
+// The guest is told how much data was forwarded to the host after this many bytes, so that it
+// doesn't run out of credit when it only sends data.
+const CREDIT_UPDATE_THRESHOLD: u32 = BUF_ALLOC / 4;
+// First port of the host side of the connections initiated by the host.
+const FIRST_LOCAL_PORT: u32 = 1 << 30;
+// Longest `CONNECT <port>` line accepted from the host.
+const MAX_CONNECT_LINE_LEN: usize = 32;
+// Most packets without data waiting for the driver. The guest can make the device queue a reset
+// for every packet it sends to a port without connection, faster than it takes them.
+const MAX_CONTROL_PACKETS: usize = 1024;
+const BOTH_SHUTDOWN_FLAGS: u32 = VIRTIO_VSOCK_SHUTDOWN_F_RECEIVE | VIRTIO_VSOCK_SHUTDOWN_F_SEND;
+
+/// A packet exchanged with the driver. Only `VIRTIO_VSOCK_OP_RW` packets have data.
+pub struct Packet {
+    pub hdr: virtio_vsock_hdr,
+    pub data: Vec<u8>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct ConnKey {
+    // Port on the host side.
+    local_port: u32,
+    // Port on the guest side.
+    peer_port: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    // Initiated by the host, waiting for the guest to accept it.
+    Requested,
+    Established,
+}
+
+#[derive(EventToken)]
+enum Token {
+    Listener,
+    Stream { id: u64 },
+}
+
+struct Connection {
+    id: u64,
+    stream: UnixStream,
+    state: State,
+    // Events the stream is registered for, if any.
+    interest: Option<EventType>,
+    // Buffer space and count of consumed bytes of the guest, from the last packet it sent.
+    peer_buf_alloc: u32,
+    peer_fwd_cnt: Wrapping<u32>,
+    // Bytes sent to the guest.
+    tx_cnt: Wrapping<u32>,
+    // Bytes received from the guest and written to the host socket.
+    fwd_cnt: Wrapping<u32>,
+    // `fwd_cnt` when it was last sent to the guest.
+    last_fwd_cnt: Wrapping<u32>,
+    // Bytes received from the guest that the host socket didn't accept yet.
+    pending: VecDeque<u8>,
+    // Shutdown flags received from the guest.
+    peer_shutdown: u32,
+    // The host stopped sending data.
+    host_eof: bool,
+    // The write side of the host socket was shut down.
+    host_write_shut: bool,
+}
+
+impl Connection {
+    fn new(id: u64, stream: UnixStream, state: State) -> Connection {
+        Connection {
+            id,
+            stream,
+            state,
+            interest: None,
+            peer_buf_alloc: 0,
+            peer_fwd_cnt: Wrapping(0),
+            tx_cnt: Wrapping(0),
+            fwd_cnt: Wrapping(0),
+            last_fwd_cnt: Wrapping(0),
+            pending: VecDeque::new(),
+            peer_shutdown: 0,
+            host_eof: false,
+            host_write_shut: false,
+        }
+    }
+
+    fn update_peer_credit(&mut self, hdr: &virtio_vsock_hdr) {
+        self.peer_buf_alloc = { hdr.buf_alloc }.to_native();
+        self.peer_fwd_cnt = Wrapping({ hdr.fwd_cnt }.to_native());
+    }
+
+    // Bytes the guest can still receive.
+    fn peer_credit(&self) -> u32 {
+        self.peer_buf_alloc
+            .saturating_sub((self.tx_cnt - self.peer_fwd_cnt).0)
+    }
+
+    fn wants_read(&self) -> bool {
+        self.state == State::Established
+            && !self.host_eof
+            && self.peer_shutdown & VIRTIO_VSOCK_SHUTDOWN_F_RECEIVE == 0
+            && self.peer_credit() > 0
+    }
+
+    fn needs_credit_update(&self) -> bool {
+        (self.fwd_cnt - self.last_fwd_cnt).0 >= CREDIT_UPDATE_THRESHOLD
+    }
+
+    // Forwards `data` from the guest, keeping what the host socket doesn't accept yet.
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        let mut written = 0;
+        if self.pending.is_empty() {
+            written = match self.stream.write(data) {
+                Ok(n) => n,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => 0,
+                Err(e) => return Err(e),
+            };
+            self.fwd_cnt += written as u32;
+        }
+        self.pending.extend(&data[written..]);
+        Ok(())
+    }
+
+    // Writes as much pending data as the host socket accepts.
+    fn flush(&mut self) -> io::Result<()> {
+        while !self.pending.is_empty() {
+            let (front, _) = self.pending.as_slices();
+            match self.stream.write(front) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    self.pending.drain(..n);
+                    self.fwd_cnt += n as u32;
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if self.pending.is_empty()
+            && self.peer_shutdown & VIRTIO_VSOCK_SHUTDOWN_F_SEND != 0
+            && !self.host_write_shut
+        {
+            self.host_write_shut = true;
+            self.stream.shutdown(Shutdown::Write)?;
+        }
+        Ok(())
+    }
+}
+
+// A connection from the host that didn't send its `CONNECT` line yet.
+struct Handshake {
+    stream: UnixStream,
+    line: Vec<u8>,
+}
+
+impl Handshake {
+    // Reads the `CONNECT` line one byte at a time, so that the data following it stays in the
+    // socket. Returns `None` until the whole line is received.
+    fn read_line(&mut self) -> io::Result<Option<&[u8]>> {
+        let mut byte = [0u8];
+        loop {
+            match self.stream.read(&mut byte) {
+                Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+                Ok(_) if byte[0] == b'\n' => return Ok(Some(&self.line)),
+                Ok(_) => {
+                    if self.line.len() == MAX_CONNECT_LINE_LEN {
+                        return Err(io::Error::new(ErrorKind::InvalidData, "line too long"));
+                    }
+                    self.line.push(byte[0]);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(None),
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+fn parse_connect_line(line: &[u8]) -> Option<u32> {
+    std::str::from_utf8(line)
+        .ok()?
+        .trim_end()
+        .strip_prefix("CONNECT ")?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Forwards the stream connections of a guest to Unix domain sockets of the host.
+///
+/// The muxer is driven by the device worker: packets of the driver are passed to
+/// [`Muxer::send_packet`], packets for the driver are taken from [`Muxer::recv_packet`] and
+/// [`Muxer::handle_events`] is called whenever the muxer, which can be waited on, is readable.
+pub struct Muxer {
+    guest_cid: u64,
+    uds_path: PathBuf,
+    listener: UnixListener,
+    wait_ctx: WaitContext<Token>,
+    next_id: u64,
+    next_local_port: u32,
+    handshakes: BTreeMap<u64, Handshake>,
+    connections: BTreeMap<ConnKey, Connection>,
+    ids: BTreeMap<u64, ConnKey>,
+    // Connections whose host socket may have data for the guest, served in turn.
+    readable: VecDeque<ConnKey>,
+    // Packets without data waiting to be sent to the guest, as their connection, operation and
+    // flags.
+    control: VecDeque<(ConnKey, u16, u32)>,
+    // The packets of `control`, which are queued only once.
+    queued_control: BTreeSet<(ConnKey, u16, u32)>,
+    // The driver has no buffer for the data of the host sockets.
+    rx_paused: bool,
+}
+
+impl Muxer {
+    /// Creates a muxer for the guest with `guest_cid`, which accepts the connections of the host
+    /// on `listener`, bound to `uds_path`.
+    pub fn new(guest_cid: u64, uds_path: PathBuf, listener: UnixListener) -> anyhow::Result<Muxer> {
+        listener
+            .set_nonblocking(true)
+            .context("failed to set the vsock listener non-blocking")?;
+        let wait_ctx = WaitContext::build_with(&[(&listener, Token::Listener)])
+            .context("failed creating WaitContext")?;
+        Ok(Muxer {
+            guest_cid,
+            uds_path,
+            listener,
+            wait_ctx,
+            next_id: 0,
+            next_local_port: FIRST_LOCAL_PORT,
+            handshakes: BTreeMap::new(),
+            connections: BTreeMap::new(),
+            ids: BTreeMap::new(),
+            readable: VecDeque::new(),
+            control: VecDeque::new(),
+            queued_control: BTreeSet::new(),
+            rx_paused: false,
+        })
+    }
+
+    /// Processes the events of the host sockets without blocking.
+    pub fn handle_events(&mut self) {
+        let events = match self.wait_ctx.wait_timeout(Duration::ZERO) {
+            Ok(events) => events,
+            Err(e) => {
+                error!("vsock: failed polling host sockets: {}", e);
+                return;
+            }
+        };
+        for event in events.iter() {
+            match event.token {
+                Token::Listener => self.accept(),
+                Token::Stream { id } => {
+                    if self.handshakes.contains_key(&id) {
+                        self.process_handshake(id);
+                        continue;
+                    }
+                    let Some(&key) = self.ids.get(&id) else {
+                        continue;
+                    };
+                    if event.is_writable {
+                        self.flush(key);
+                    }
+                    if (event.is_readable || event.is_hungup) && !self.readable.contains(&key) {
+                        self.readable.push_back(key);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Stops or resumes reading the host sockets, depending on whether the driver has buffers for
+    /// their data.
+    pub fn set_rx_paused(&mut self, paused: bool) {
+        if self.rx_paused == paused {
+            return;
+        }
+        self.rx_paused = paused;
+        let keys: Vec<ConnKey> = self.connections.keys().copied().collect();
+        for key in keys {
+            self.refresh(key);
+        }
+    }
+
+    /// Handles a packet sent by the driver, whose data is `data`.
+    pub fn send_packet(&mut self, hdr: &virtio_vsock_hdr, data: &[u8]) {
+        let key = ConnKey {
+            local_port: { hdr.dst_port }.to_native(),
+            peer_port: { hdr.src_port }.to_native(),
+        };
+        let op = { hdr.op }.to_native();
+        if { hdr.src_cid }.to_native() != self.guest_cid
+            || { hdr.dst_cid }.to_native() != VMADDR_CID_HOST
+            || { hdr.type_ }.to_native() != TYPE_STREAM_SOCKET
+        {
+            if op != vsock_op::VIRTIO_VSOCK_OP_RST {
+                self.queue_control(key, vsock_op::VIRTIO_VSOCK_OP_RST, 0);
+            }
+            return;
+        }
+
+        match op {
+            vsock_op::VIRTIO_VSOCK_OP_REQUEST => {
+                self.connect_to_host(key, hdr);
+                return;
+            }
+            vsock_op::VIRTIO_VSOCK_OP_RST => {
+                self.remove(key);
+                return;
+            }
+            _ => {}
+        }
+
+        let Some(conn) = self.connections.get_mut(&key) else {
+            self.queue_control(key, vsock_op::VIRTIO_VSOCK_OP_RST, 0);
+            return;
+        };
+        conn.update_peer_credit(hdr);
+        let result = match op {
+            vsock_op::VIRTIO_VSOCK_OP_RESPONSE if conn.state == State::Requested => {
+                conn.state = State::Established;
+                conn.stream
+                    .write_all(format!("OK {}\n", key.local_port).as_bytes())
+            }
+            vsock_op::VIRTIO_VSOCK_OP_RW
+                if conn.state == State::Established
+                    && conn.peer_shutdown & VIRTIO_VSOCK_SHUTDOWN_F_SEND == 0 =>
+            {
+                if conn.pending.len() + data.len() > BUF_ALLOC as usize {
+                    Err(io::Error::new(
+                        ErrorKind::OutOfMemory,
+                        "guest exceeded its credit",
+                    ))
+                } else {
+                    conn.write(data)
+                }
+            }
+            vsock_op::VIRTIO_VSOCK_OP_CREDIT_UPDATE => Ok(()),
+            // Answered below, once the connection is no longer borrowed.
+            vsock_op::VIRTIO_VSOCK_OP_CREDIT_REQUEST => Ok(()),
+            vsock_op::VIRTIO_VSOCK_OP_SHUTDOWN => {
+                conn.peer_shutdown |= { hdr.flags }.to_native() & BOTH_SHUTDOWN_FLAGS;
+                if conn.peer_shutdown == BOTH_SHUTDOWN_FLAGS {
+                    // The guest closed the connection and waits for the final reset.
+                    self.reset(key);
+                    return;
+                }
+                conn.flush()
+            }
+            _ => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("unexpected operation {}", op),
+            )),
+        };
+        if let Err(e) = result {
+            warn!(
+                "vsock: resetting connection of host port {} to guest port {}: {}",
+                key.local_port, key.peer_port, e
+            );
+            self.reset(key);
+            return;
+        }
+        if op == vsock_op::VIRTIO_VSOCK_OP_CREDIT_REQUEST {
+            self.queue_control(key, vsock_op::VIRTIO_VSOCK_OP_CREDIT_UPDATE, 0);
+        }
+        self.queue_credit_update(key);
+        self.refresh(key);
+    }
+
+    /// Returns the next packet for the driver, with at most `max_data_len` bytes of data.
+    pub fn recv_packet(&mut self, max_data_len: usize) -> Option<Packet> {
+        loop {
+            if let Some(packet) = self.control.pop_front() {
+                self.queued_control.remove(&packet);
+                let (key, op, flags) = packet;
+                // Packets of connections that were since closed are dropped, except resets.
+                if op != vsock_op::VIRTIO_VSOCK_OP_RST && !self.connections.contains_key(&key) {
+                    continue;
+                }
+                return Some(Packet {
+                    hdr: self.header(key, op, flags, 0),
+                    data: Vec::new(),
+                });
+            }
+            if max_data_len == 0 {
+                return None;
+            }
+            let key = *self.readable.front()?;
+            let Some(conn) = self.connections.get_mut(&key).filter(|c| c.wants_read()) else {
+                self.readable.pop_front();
+                continue;
+            };
+            let len = max_data_len
+                .min(MAX_PKT_PAYLOAD)
+                .min(conn.peer_credit() as usize);
+            let mut data = vec![0u8; len];
+            match conn.stream.read(&mut data) {
+                Ok(0) => {
+                    conn.host_eof = true;
+                    self.readable.pop_front();
+                    self.queue_control(
+                        key,
+                        vsock_op::VIRTIO_VSOCK_OP_SHUTDOWN,
+                        BOTH_SHUTDOWN_FLAGS,
+                    );
+                    self.refresh(key);
+                }
+                Ok(n) => {
+                    data.truncate(n);
+                    conn.tx_cnt += n as u32;
+                    // Let the other connections send data before this one sends more.
+                    self.readable.rotate_left(1);
+                    let hdr = self.header(key, vsock_op::VIRTIO_VSOCK_OP_RW, 0, n);
+                    self.refresh(key);
+                    return Some(Packet { hdr, data });
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    self.readable.pop_front();
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => {
+                    warn!(
+                        "vsock: failed to read host socket of port {}: {}",
+                        key.local_port, e
+                    );
+                    self.reset(key);
+                }
+            }
+        }
+    }
+
+    fn accept(&mut self) {
+        loop {
+            let stream = match self.listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    error!("vsock: failed to accept host connection: {}", e);
+                    return;
+                }
+            };
+            if let Err(e) = stream.set_nonblocking(true) {
+                error!("vsock: failed to set host connection non-blocking: {}", e);
+                continue;
+            }
+            let id = self.alloc_id();
+            if let Err(e) = self.wait_ctx.add(&stream, Token::Stream { id }) {
+                error!("vsock: failed to watch host connection: {}", e);
+                continue;
+            }
+            self.handshakes.insert(
+                id,
+                Handshake {
+                    stream,
+                    line: Vec::new(),
+                },
+            );
+            self.process_handshake(id);
+        }
+    }
+
+    fn process_handshake(&mut self, id: u64) {
+        let Some(handshake) = self.handshakes.get_mut(&id) else {
+            return;
+        };
+        let port = match handshake.read_line() {
+            Ok(None) => return,
+            Ok(Some(line)) => parse_connect_line(line),
+            Err(e) => {
+                warn!("vsock: failed to read request of host connection: {}", e);
+                None
+            }
+        };
+        let handshake = self.handshakes.remove(&id).unwrap();
+        // The stream is watched again once the guest accepts the connection.
+        if let Err(e) = self.wait_ctx.delete(&handshake.stream) {
+            error!("vsock: failed to stop watching host connection: {}", e);
+        }
+        let Some(port) = port else {
+            warn!(
+                "vsock: invalid request of host connection: {:?}",
+                String::from_utf8_lossy(&handshake.line)
+            );
+            return;
+        };
+
+        let key = self.alloc_local_port(port);
+        self.connections
+            .insert(key, Connection::new(id, handshake.stream, State::Requested));
+        self.ids.insert(id, key);
+        self.queue_control(key, vsock_op::VIRTIO_VSOCK_OP_REQUEST, 0);
+    }
+
+    fn connect_to_host(&mut self, key: ConnKey, hdr: &virtio_vsock_hdr) {
+        if self.connections.contains_key(&key) {
+            self.reset(key);
+            return;
+        }
+        let mut path = self.uds_path.clone().into_os_string();
+        path.push(format!("_{}", key.local_port));
+        let stream = match UnixStream::connect(&path) {
+            Ok(stream) => stream,
+            Err(e) => {
+                // Connecting to a port nobody listens to is expected, the guest gets a reset.
+                if e.kind() != ErrorKind::NotFound && e.kind() != ErrorKind::ConnectionRefused {
+                    warn!(
+                        "vsock: failed to connect to {}: {}",
+                        PathBuf::from(path).display(),
+                        e
+                    );
+                }
+                self.queue_control(key, vsock_op::VIRTIO_VSOCK_OP_RST, 0);
+                return;
+            }
+        };
+        if let Err(e) = stream.set_nonblocking(true) {
+            error!("vsock: failed to set host connection non-blocking: {}", e);
+            self.queue_control(key, vsock_op::VIRTIO_VSOCK_OP_RST, 0);
+            return;
+        }
+
+        let id = self.alloc_id();
+        let mut conn = Connection::new(id, stream, State::Established);
+        conn.update_peer_credit(hdr);
+        self.connections.insert(key, conn);
+        self.ids.insert(id, key);
+        self.queue_control(key, vsock_op::VIRTIO_VSOCK_OP_RESPONSE, 0);
+        self.refresh(key);
+    }
+
+    fn flush(&mut self, key: ConnKey) {
+        let Some(conn) = self.connections.get_mut(&key) else {
+            return;
+        };
+        if let Err(e) = conn.flush() {
+            warn!(
+                "vsock: failed to write host socket of port {}: {}",
+                key.local_port, e
+            );
+            self.reset(key);
+            return;
+        }
+        self.queue_credit_update(key);
+        self.refresh(key);
+    }
+
+    fn queue_credit_update(&mut self, key: ConnKey) {
+        let Some(conn) = self.connections.get(&key) else {
+            return;
+        };
+        if conn.needs_credit_update() {
+            self.queue_control(key, vsock_op::VIRTIO_VSOCK_OP_CREDIT_UPDATE, 0);
+        }
+    }
+
+    // Queues a packet without data for the driver, unless the same packet is already queued. Once
+    // `MAX_CONTROL_PACKETS` are queued, packets are dropped and the connection they belong to is
+    // closed, as the guest would never learn about its state.
+    fn queue_control(&mut self, key: ConnKey, op: u16, flags: u32) {
+        let packet = (key, op, flags);
+        if self.queued_control.contains(&packet) {
+            return;
+        }
+        if self.control.len() >= MAX_CONTROL_PACKETS {
+            if op != vsock_op::VIRTIO_VSOCK_OP_RST {
+                warn!(
+                    "vsock: too many packets queued for the guest, closing connection of host \
+                     port {} to guest port {}",
+                    key.local_port, key.peer_port
+                );
+                self.remove(key);
+            }
+            return;
+        }
+        self.queued_control.insert(packet);
+        self.control.push_back(packet);
+    }
+
+    // Closes the connection and sends a reset to the guest.
+    fn reset(&mut self, key: ConnKey) {
+        self.remove(key);
+        self.queue_control(key, vsock_op::VIRTIO_VSOCK_OP_RST, 0);
+    }
+
+    fn remove(&mut self, key: ConnKey) {
+        let Some(conn) = self.connections.remove(&key) else {
+            return;
+        };
+        self.ids.remove(&conn.id);
+        self.readable.retain(|k| *k != key);
+        if conn.interest.is_some() {
+            if let Err(e) = self.wait_ctx.delete(&conn.stream) {
+                error!("vsock: failed to stop watching host connection: {}", e);
+            }
+        }
+    }
+
+    // Registers the host socket of the connection for the events it can handle now. Sockets are
+    // unregistered rather than registered for no event, as hangups are always reported.
+    fn refresh(&mut self, key: ConnKey) {
+        let Some(conn) = self.connections.get_mut(&key) else {
+            return;
+        };
+        let read = !self.rx_paused && conn.wants_read();
+        let write = !conn.pending.is_empty();
+        let interest = match (read, write) {
+            (true, true) => Some(EventType::ReadWrite),
+            (true, false) => Some(EventType::Read),
+            (false, true) => Some(EventType::Write),
+            (false, false) => None,
+        };
+        if interest == conn.interest {
+            return;
+        }
+        let token = Token::Stream { id: conn.id };
+        let result = match (conn.interest, interest) {
+            (None, Some(event_type)) => {
+                self.wait_ctx.add_for_event(&conn.stream, event_type, token)
+            }
+            (Some(_), Some(event_type)) => self.wait_ctx.modify(&conn.stream, event_type, token),
+            (Some(_), None) => self.wait_ctx.delete(&conn.stream),
+            (None, None) => Ok(()),
+        };
+        match result {
+            Ok(()) => conn.interest = interest,
+            Err(e) => error!("vsock: failed to watch host connection: {}", e),
+        }
+    }
+
+    fn header(&mut self, key: ConnKey, op: u16, flags: u32, len: usize) -> virtio_vsock_hdr {
+        let (buf_alloc, fwd_cnt) = match self.connections.get_mut(&key) {
+            Some(conn) => {
+                conn.last_fwd_cnt = conn.fwd_cnt;
+                (BUF_ALLOC, conn.fwd_cnt.0)
+            }
+            None => (0, 0),
+        };
+        virtio_vsock_hdr {
+            src_cid: VMADDR_CID_HOST.into(),
+            dst_cid: self.guest_cid.into(),
+            src_port: key.local_port.into(),
+            dst_port: key.peer_port.into(),
+            len: (len as u32).into(),
+            type_: TYPE_STREAM_SOCKET.into(),
+            op: op.into(),
+            flags: flags.into(),
+            buf_alloc: buf_alloc.into(),
+            fwd_cnt: fwd_cnt.into(),
+        }
+    }
+
+    fn alloc_id(&mut self) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+
+    // Picks the host port of a connection from the host to `peer_port` of the guest.
+    fn alloc_local_port(&mut self, peer_port: u32) -> ConnKey {
+        loop {
+            let key = ConnKey {
+                local_port: self.next_local_port,
+                peer_port,
+            };
+            self.next_local_port = self
+                .next_local_port
+                .checked_add(1)
+                .unwrap_or(FIRST_LOCAL_PORT);
+            if !self.connections.contains_key(&key) {
+                return key;
+            }
+        }
+    }
+}
+
+impl AsRawDescriptor for Muxer {
+    fn as_raw_descriptor(&self) -> RawDescriptor {
+        self.wait_ctx.as_raw_descriptor()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    const GUEST_CID: u64 = 3;
+    const GUEST_BUF_ALLOC: u32 = 4096;
+
+    fn guest_hdr(
+        src_port: u32,
+        dst_port: u32,
+        op: u16,
+        flags: u32,
+        len: usize,
+    ) -> virtio_vsock_hdr {
+        virtio_vsock_hdr {
+            src_cid: GUEST_CID.into(),
+            dst_cid: VMADDR_CID_HOST.into(),
+            src_port: src_port.into(),
+            dst_port: dst_port.into(),
+            len: (len as u32).into(),
+            type_: TYPE_STREAM_SOCKET.into(),
+            op: op.into(),
+            flags: flags.into(),
+            buf_alloc: GUEST_BUF_ALLOC.into(),
+            fwd_cnt: 0.into(),
+        }
+    }
+
+    fn new_muxer(dir: &Path) -> Muxer {
+        let uds_path = dir.join("vsock");
+        let listener = UnixListener::bind(&uds_path).unwrap();
+        Muxer::new(GUEST_CID, uds_path, listener).unwrap()
+    }
+
+    fn recv_op(muxer: &mut Muxer) -> (virtio_vsock_hdr, u16) {
+        let packet = muxer.recv_packet(MAX_PKT_PAYLOAD).expect("no packet");
+        (packet.hdr, { packet.hdr.op }.to_native())
+    }
+
+    #[test]
+    fn guest_connects_to_host() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut muxer = new_muxer(dir.path());
+
+        // Nothing listens to port 1234 yet.
+        muxer.send_packet(
+            &guest_hdr(5000, 1234, vsock_op::VIRTIO_VSOCK_OP_REQUEST, 0, 0),
+            &[],
+        );
+        assert_eq!(recv_op(&mut muxer).1, vsock_op::VIRTIO_VSOCK_OP_RST);
+
+        let host_listener = UnixListener::bind(dir.path().join("vsock_1234")).unwrap();
+        muxer.send_packet(
+            &guest_hdr(5000, 1234, vsock_op::VIRTIO_VSOCK_OP_REQUEST, 0, 0),
+            &[],
+        );
+        let (hdr, op) = recv_op(&mut muxer);
+        assert_eq!(op, vsock_op::VIRTIO_VSOCK_OP_RESPONSE);
+        assert_eq!({ hdr.src_port }.to_native(), 1234);
+        assert_eq!({ hdr.dst_port }.to_native(), 5000);
+        assert_eq!({ hdr.dst_cid }.to_native(), GUEST_CID);
+        assert_eq!({ hdr.buf_alloc }.to_native(), BUF_ALLOC);
+        let (mut host, _) = host_listener.accept().unwrap();
+
+        muxer.send_packet(
+            &guest_hdr(5000, 1234, vsock_op::VIRTIO_VSOCK_OP_RW, 0, 5),
+            b"hello",
+        );
+        let mut buf = [0u8; 5];
+        host.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+
+        host.write_all(b"world").unwrap();
+        muxer.handle_events();
+        let packet = muxer.recv_packet(MAX_PKT_PAYLOAD).unwrap();
+        assert_eq!({ packet.hdr.op }.to_native(), vsock_op::VIRTIO_VSOCK_OP_RW);
+        assert_eq!({ packet.hdr.fwd_cnt }.to_native(), 5);
+        assert_eq!(packet.data, b"world");
+
+        // Closing the host socket shuts the connection down.
+        drop(host);
+        muxer.handle_events();
+        let (hdr, op) = recv_op(&mut muxer);
+        assert_eq!(op, vsock_op::VIRTIO_VSOCK_OP_SHUTDOWN);
+        assert_eq!({ hdr.flags }.to_native(), BOTH_SHUTDOWN_FLAGS);
+        muxer.send_packet(
+            &guest_hdr(5000, 1234, vsock_op::VIRTIO_VSOCK_OP_RST, 0, 0),
+            &[],
+        );
+        assert!(muxer.connections.is_empty());
+        assert!(muxer.recv_packet(MAX_PKT_PAYLOAD).is_none());
+    }
+
+    #[test]
+    fn host_connects_to_guest() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut muxer = new_muxer(dir.path());
+
+        let mut host = UnixStream::connect(dir.path().join("vsock")).unwrap();
+        host.write_all(b"CONNECT 52\nping").unwrap();
+        muxer.handle_events();
+        let (hdr, op) = recv_op(&mut muxer);
+        assert_eq!(op, vsock_op::VIRTIO_VSOCK_OP_REQUEST);
+        assert_eq!({ hdr.src_port }.to_native(), FIRST_LOCAL_PORT);
+        assert_eq!({ hdr.dst_port }.to_native(), 52);
+        // The data following the request isn't sent before the guest accepts the connection.
+        assert!(muxer.recv_packet(MAX_PKT_PAYLOAD).is_none());
+
+        muxer.send_packet(
+            &guest_hdr(
+                52,
+                FIRST_LOCAL_PORT,
+                vsock_op::VIRTIO_VSOCK_OP_RESPONSE,
+                0,
+                0,
+            ),
+            &[],
+        );
+        let mut buf = [0u8; 14];
+        host.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, format!("OK {}\n", FIRST_LOCAL_PORT).as_bytes());
+
+        muxer.handle_events();
+        let packet = muxer.recv_packet(MAX_PKT_PAYLOAD).unwrap();
+        assert_eq!(packet.data, b"ping");
+
+        // The guest closes the connection.
+        muxer.send_packet(
+            &guest_hdr(
+                52,
+                FIRST_LOCAL_PORT,
+                vsock_op::VIRTIO_VSOCK_OP_SHUTDOWN,
+                BOTH_SHUTDOWN_FLAGS,
+                0,
+            ),
+            &[],
+        );
+        assert_eq!(recv_op(&mut muxer).1, vsock_op::VIRTIO_VSOCK_OP_RST);
+        assert_eq!(host.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn invalid_host_request() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut muxer = new_muxer(dir.path());
+
+        let mut host = UnixStream::connect(dir.path().join("vsock")).unwrap();
+        host.write_all(b"LISTEN 52\n").unwrap();
+        muxer.handle_events();
+        assert!(muxer.recv_packet(MAX_PKT_PAYLOAD).is_none());
+        let mut buf = [0u8; 1];
+        assert_eq!(host.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn data_is_limited_by_guest_credit() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut muxer = new_muxer(dir.path());
+        let host_listener = UnixListener::bind(dir.path().join("vsock_80")).unwrap();
+        muxer.send_packet(
+            &guest_hdr(6000, 80, vsock_op::VIRTIO_VSOCK_OP_REQUEST, 0, 0),
+            &[],
+        );
+        assert_eq!(recv_op(&mut muxer).1, vsock_op::VIRTIO_VSOCK_OP_RESPONSE);
+        let (mut host, _) = host_listener.accept().unwrap();
+
+        host.write_all(&[0xaa; GUEST_BUF_ALLOC as usize * 2])
+            .unwrap();
+        muxer.handle_events();
+        let mut received = 0;
+        while let Some(packet) = muxer.recv_packet(1000) {
+            assert!(packet.data.len() <= 1000);
+            received += packet.data.len();
+        }
+        assert_eq!(received, GUEST_BUF_ALLOC as usize);
+
+        // The guest consumed everything.
+        let mut hdr = guest_hdr(6000, 80, vsock_op::VIRTIO_VSOCK_OP_CREDIT_UPDATE, 0, 0);
+        hdr.fwd_cnt = GUEST_BUF_ALLOC.into();
+        muxer.send_packet(&hdr, &[]);
+        muxer.handle_events();
+        let mut received = 0;
+        while let Some(packet) = muxer.recv_packet(MAX_PKT_PAYLOAD) {
+            received += packet.data.len();
+        }
+        assert_eq!(received, GUEST_BUF_ALLOC as usize);
+    }
+
+    #[test]
+    fn control_packets_are_bounded() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut muxer = new_muxer(dir.path());
+
+        // Packets for connections that don't exist are each answered by a single reset.
+        for _ in 0..2 {
+            muxer.send_packet(&guest_hdr(7000, 1, vsock_op::VIRTIO_VSOCK_OP_RW, 0, 0), &[]);
+        }
+        assert_eq!(recv_op(&mut muxer).1, vsock_op::VIRTIO_VSOCK_OP_RST);
+        assert!(muxer.recv_packet(MAX_PKT_PAYLOAD).is_none());
+
+        for port in 0..MAX_CONTROL_PACKETS as u32 * 2 {
+            muxer.send_packet(&guest_hdr(port, 1, vsock_op::VIRTIO_VSOCK_OP_RW, 0, 0), &[]);
+        }
+        let mut resets = 0;
+        while muxer.recv_packet(MAX_PKT_PAYLOAD).is_some() {
+            resets += 1;
+        }
+        assert_eq!(resets, MAX_CONTROL_PACKETS);
+    }
+}
@@ -0,0 +1,382 @@
+// Copyright 2025 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Virtio vsock device implemented in userspace, which bridges the connections of the guest to
+//! Unix domain sockets of the host as described in the [`muxer`](super::muxer) module.
+
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::io::Write;
+use std::os::unix::net::UnixListener;
+use std::path::PathBuf;
+
+use anyhow::anyhow;
+use anyhow::Context;
+use base::error;
+use base::warn;
+use base::AsRawDescriptor;
+use base::Event;
+use base::EventToken;
+use base::RawDescriptor;
+use base::WaitContext;
+use base::WorkerThread;
+use data_model::Le64;
+use serde::Deserialize;
+use serde::Serialize;
+use snapshot::AnySnapshot;
+use vm_memory::GuestMemory;
+use zerocopy::IntoBytes;
+
+use super::muxer::Muxer;
+use crate::virtio::copy_config;
+use crate::virtio::device_constants::vsock::NUM_QUEUES;
+use crate::virtio::vsock::protocol::virtio_vsock_event;
+use crate::virtio::vsock::protocol::virtio_vsock_hdr;
+use crate::virtio::vsock::VsockConfig;
+use crate::virtio::DeviceType;
+use crate::virtio::Interrupt;
+use crate::virtio::Queue;
+use crate::virtio::VirtioDevice;
+
+const DEFAULT_MAX_QUEUE_SIZE: u16 = 256;
+const RX_QUEUE: usize = 0;
+const TX_QUEUE: usize = 1;
+const EVENT_QUEUE: usize = 2;
+
+struct Worker {
+    rx_queue: Queue,
+    tx_queue: Queue,
+    event_queue: Queue,
+    muxer: Muxer,
+    // If true, a TRANSPORT_RESET event is sent to the guest as soon as the event queue has a
+    // buffer.
+    needs_transport_reset: bool,
+}
+
+impl Worker {
+    fn process_tx(&mut self) {
+        let mut needs_interrupt = false;
+        while let Some(mut desc) = self.tx_queue.pop() {
+            match desc.reader.read_obj::<virtio_vsock_hdr>() {
+                Ok(hdr) => {
+                    let len = ({ hdr.len }.to_native() as usize).min(desc.reader.available_bytes());
+                    let mut data = vec![0u8; len];
+                    match desc.reader.read_exact(&mut data) {
+                        Ok(()) => self.muxer.send_packet(&hdr, &data),
+                        Err(e) => error!("vsock: failed to read packet data: {}", e),
+                    }
+                }
+                Err(e) => error!("vsock: failed to read packet header: {}", e),
+            }
+            self.tx_queue.add_used(desc, 0);
+            needs_interrupt = true;
+        }
+        if needs_interrupt {
+            self.tx_queue.trigger_interrupt();
+        }
+    }
+
+    fn process_rx(&mut self) {
+        const HDR_LEN: usize = std::mem::size_of::<virtio_vsock_hdr>();
+
+        let mut needs_interrupt = false;
+        loop {
+            let Some(mut desc) = self.rx_queue.peek() else {
+                // Stop reading the host sockets until the driver provides more buffers.
+                self.muxer.set_rx_paused(true);
+                break;
+            };
+            let available = desc.writer.available_bytes();
+            if available < HDR_LEN {
+                error!("vsock: rx buffer of {} bytes is too small", available);
+                let desc = desc.pop();
+                self.rx_queue.add_used(desc, 0);
+                needs_interrupt = true;
+                continue;
+            }
+            let Some(packet) = self.muxer.recv_packet(available - HDR_LEN) else {
+                break;
+            };
+            if let Err(e) = desc
+                .writer
+                .write_obj(packet.hdr)
+                .and_then(|()| desc.writer.write_all(&packet.data))
+            {
+                error!("vsock: failed to write packet to the guest: {}", e);
+            }
+            let len = desc.writer.bytes_written() as u32;
+            let desc = desc.pop();
+            self.rx_queue.add_used(desc, len);
+            needs_interrupt = true;
+        }
+        if needs_interrupt {
+            self.rx_queue.trigger_interrupt();
+        }
+    }
+
+    fn send_transport_reset(&mut self) {
+        if !self.needs_transport_reset {
+            return;
+        }
+        let Some(mut desc) = self.event_queue.pop() else {
+            return;
+        };
+        let event = virtio_vsock_event {
+            id: virtio_sys::virtio_vsock::virtio_vsock_event_id_VIRTIO_VSOCK_EVENT_TRANSPORT_RESET
+                .into(),
+        };
+        if let Err(e) = desc.writer.write_obj(event) {
+            error!("vsock: failed to write transport reset event: {}", e);
+        }
+        let len = desc.writer.bytes_written() as u32;
+        self.event_queue.add_used(desc, len);
+        self.event_queue.trigger_interrupt();
+        self.needs_transport_reset = false;
+    }
+
+    fn run(&mut self, kill_evt: Event) -> anyhow::Result<()> {
+        #[derive(EventToken)]
+        enum Token {
+            RxQueue,
+            TxQueue,
+            EventQueue,
+            Muxer,
+            Kill,
+        }
+
+        let wait_ctx = WaitContext::build_with(&[
+            (self.rx_queue.event(), Token::RxQueue),
+            (self.tx_queue.event(), Token::TxQueue),
+            (self.event_queue.event(), Token::EventQueue),
+            (&self.muxer, Token::Muxer),
+            (&kill_evt, Token::Kill),
+        ])
+        .context("failed creating WaitContext")?;
+
+        self.send_transport_reset();
+        // Deliver the packets that were pending when the device went to sleep.
+        self.muxer.set_rx_paused(false);
+        self.process_rx();
+
+        loop {
+            let events = wait_ctx.wait().context("failed polling for events")?;
+            for event in events.iter().filter(|e| e.is_readable) {
+                match event.token {
+                    Token::RxQueue => {
+                        self.rx_queue
+                            .event()
+                            .wait()
+                            .context("failed reading rx queue Event")?;
+                        self.muxer.set_rx_paused(false);
+                    }
+                    Token::TxQueue => {
+                        self.tx_queue
+                            .event()
+                            .wait()
+                            .context("failed reading tx queue Event")?;
+                        self.process_tx();
+                    }
+                    Token::EventQueue => {
+                        self.event_queue
+                            .event()
+                            .wait()
+                            .context("failed reading event queue Event")?;
+                        self.send_transport_reset();
+                    }
+                    Token::Muxer => self.muxer.handle_events(),
+                    Token::Kill => return Ok(()),
+                }
+            }
+            self.process_rx();
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct UserspaceVsockSnapshot {
+    cid: u64,
+    avail_features: u64,
+    acked_features: u64,
+}
+
+/// Virtio vsock device that forwards the stream connections of the guest to Unix domain sockets
+/// of the host, without the vhost-vsock kernel module.
+pub struct UserspaceVsock {
+    cid: u64,
+    uds_path: PathBuf,
+    listener: UnixListener,
+    avail_features: u64,
+    acked_features: u64,
+    max_queue_sizes: [u16; NUM_QUEUES],
+    // Kept while the device sleeps so that connections outlive it.
+    muxer: Option<Muxer>,
+    worker_thread: Option<WorkerThread<Worker>>,
+    needs_transport_reset: bool,
+}
+
+impl UserspaceVsock {
+    /// Creates a new userspace vsock device, listening for the connections of the host on the
+    /// `uds-path` socket of `vsock_config`.
+    pub fn new(base_features: u64, vsock_config: &VsockConfig) -> anyhow::Result<UserspaceVsock> {
+        let uds_path = vsock_config
+            .uds_path
+            .clone()
+            .context("userspace vsock device requires a socket path")?;
+        let listener = UnixListener::bind(&uds_path)
+            .with_context(|| format!("failed to bind vsock socket {}", uds_path.display()))?;
+
+        Ok(UserspaceVsock {
+            cid: vsock_config.cid,
+            uds_path,
+            listener,
+            avail_features: base_features,
+            acked_features: 0,
+            max_queue_sizes: vsock_config
+                .max_queue_sizes
+                .unwrap_or([DEFAULT_MAX_QUEUE_SIZE; NUM_QUEUES]),
+            muxer: None,
+            worker_thread: None,
+            needs_transport_reset: false,
+        })
+    }
+}
+
+impl VirtioDevice for UserspaceVsock {
+    fn keep_rds(&self) -> Vec<RawDescriptor> {
+        vec![self.listener.as_raw_descriptor()]
+    }
+
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Vsock
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        &self.max_queue_sizes[..]
+    }
+
+    fn features(&self) -> u64 {
+        self.avail_features
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        let cid = Le64::from(self.cid);
+        copy_config(data, 0, cid.as_bytes(), offset);
+    }
+
+    fn ack_features(&mut self, value: u64) {
+        let unrequested_features = value & !self.avail_features;
+        if unrequested_features != 0 {
+            warn!("vsock: virtio-vsock got unknown feature ack: {:x}", value);
+        }
+        self.acked_features |= value & self.avail_features;
+    }
+
+    fn activate(
+        &mut self,
+        _mem: GuestMemory,
+        _interrupt: Interrupt,
+        mut queues: BTreeMap<usize, Queue>,
+    ) -> anyhow::Result<()> {
+        if queues.len() != NUM_QUEUES {
+            return Err(anyhow!(
+                "vsock: expected {} queues, got {}",
+                NUM_QUEUES,
+                queues.len()
+            ));
+        }
+
+        let muxer = match self.muxer.take() {
+            Some(muxer) => muxer,
+            None => Muxer::new(
+                self.cid,
+                self.uds_path.clone(),
+                self.listener
+                    .try_clone()
+                    .context("failed to clone vsock listener")?,
+            )?,
+        };
+        let mut worker = Worker {
+            rx_queue: queues.remove(&RX_QUEUE).unwrap(),
+            tx_queue: queues.remove(&TX_QUEUE).unwrap(),
+            event_queue: queues.remove(&EVENT_QUEUE).unwrap(),
+            muxer,
+            needs_transport_reset: self.needs_transport_reset,
+        };
+        self.needs_transport_reset = false;
+
+        self.worker_thread = Some(WorkerThread::start("v_vsock", move |kill_evt| {
+            if let Err(e) = worker.run(kill_evt) {
+                error!("vsock worker thread failed: {:#}", e);
+            }
+            worker
+        }));
+
+        Ok(())
+    }
+
+    fn reset(&mut self) -> anyhow::Result<()> {
+        if let Some(worker_thread) = self.worker_thread.take() {
+            let _worker = worker_thread.stop();
+        }
+        self.muxer = None;
+        self.acked_features = 0;
+        self.needs_transport_reset = false;
+        Ok(())
+    }
+
+    fn virtio_sleep(&mut self) -> anyhow::Result<Option<BTreeMap<usize, Queue>>> {
+        if let Some(worker_thread) = self.worker_thread.take() {
+            let worker = worker_thread.stop();
+            self.muxer = Some(worker.muxer);
+            self.needs_transport_reset = worker.needs_transport_reset;
+            return Ok(Some(BTreeMap::from([
+                (RX_QUEUE, worker.rx_queue),
+                (TX_QUEUE, worker.tx_queue),
+                (EVENT_QUEUE, worker.event_queue),
+            ])));
+        }
+        Ok(None)
+    }
+
+    fn virtio_wake(
+        &mut self,
+        queues_state: Option<(GuestMemory, Interrupt, BTreeMap<usize, Queue>)>,
+    ) -> anyhow::Result<()> {
+        if let Some((mem, interrupt, queues)) = queues_state {
+            self.activate(mem, interrupt, queues)?;
+        }
+        Ok(())
+    }
+
+    fn virtio_snapshot(&mut self) -> anyhow::Result<AnySnapshot> {
+        // Connections can't be snapshotted, the guest is told they were reset on restore.
+        AnySnapshot::to_any(UserspaceVsockSnapshot {
+            cid: self.cid,
+            avail_features: self.avail_features,
+            acked_features: self.acked_features,
+        })
+        .context("failed to snapshot virtio vsock")
+    }
+
+    fn virtio_restore(&mut self, data: AnySnapshot) -> anyhow::Result<()> {
+        let deser: UserspaceVsockSnapshot =
+            AnySnapshot::from_any(data).context("failed to deserialize virtio vsock")?;
+        anyhow::ensure!(
+            self.cid == deser.cid,
+            "Virtio vsock incorrect cid for restore:\n Expected: {}, Actual: {}",
+            self.cid,
+            deser.cid,
+        );
+        anyhow::ensure!(
+            self.avail_features == deser.avail_features,
+            "Virtio vsock incorrect avail features for restore:\n Expected: {}, Actual: {}",
+            self.avail_features,
+            deser.avail_features,
+        );
+        self.acked_features = deser.acked_features;
+        self.muxer = None;
+        self.needs_transport_reset = true;
+        Ok(())
+    }
+}
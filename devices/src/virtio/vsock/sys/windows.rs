@@ -2,16 +2,16 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
-pub mod protocol;
 pub mod vsock;
 
-pub(crate) use protocol::*;
 use serde::Deserialize;
 use serde::Serialize;
 use serde_keyvalue::FromKeyValues;
 pub use vsock::Vsock;
 pub use vsock::VsockError;
 
+pub(crate) use crate::virtio::vsock::protocol::*;
+
 #[derive(Debug, Deserialize, Serialize, PartialEq, Eq, FromKeyValues)]
 #[serde(deny_unknown_fields)]
 // Configuration for a Vsock device.
@@ -24,6 +24,7 @@ use crate::AsyncDisk;
 use crate::DiskFile;
 use crate::DiskGetLen;
 use crate::Error;
+use crate::InternalSnapshot;
 use crate::Result;
 
 /// Async wrapper around a non-async `DiskFile` using a `BlockingPool`.
@@ -184,4 +185,51 @@ impl<
             })
             .await
     }
+
+    async fn internal_snapshots(&self) -> Result<Vec<InternalSnapshot>> {
+        let inner_clone = self.inner.clone();
+        self.blocking_pool
+            .spawn(move || {
+                inner_clone
+                    .internal_snapshots()
+                    .map_err(Error::InternalSnapshot)
+            })
+            .await
+    }
+
+    async fn create_internal_snapshot(&self, name: &str) -> Result<()> {
+        let inner_clone = self.inner.clone();
+        let name = name.to_string();
+        self.blocking_pool
+            .spawn(move || {
+                inner_clone
+                    .create_internal_snapshot(&name)
+                    .map_err(Error::InternalSnapshot)
+            })
+            .await
+    }
+
+    async fn apply_internal_snapshot(&self, snapshot: &str) -> Result<()> {
+        let inner_clone = self.inner.clone();
+        let snapshot = snapshot.to_string();
+        self.blocking_pool
+            .spawn(move || {
+                inner_clone
+                    .apply_internal_snapshot(&snapshot)
+                    .map_err(Error::InternalSnapshot)
+            })
+            .await
+    }
+
+    async fn delete_internal_snapshot(&self, snapshot: &str) -> Result<()> {
+        let inner_clone = self.inner.clone();
+        let snapshot = snapshot.to_string();
+        self.blocking_pool
+            .spawn(move || {
+                inner_clone
+                    .delete_internal_snapshot(&snapshot)
+                    .map_err(Error::InternalSnapshot)
+            })
+            .await
+    }
 }
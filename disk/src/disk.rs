@@ -10,6 +10,7 @@ use std::fs::File;
 use std::io;
 use std::io::Seek;
 use std::io::SeekFrom;
+use std::path::Path;
 use std::path::PathBuf;
 use std::sync::Arc;
 
@@ -23,6 +24,8 @@ use cros_async::BackingMemory;
 use cros_async::Executor;
 use cros_async::IoSource;
 use cros_async::MemRegionIter;
+use serde::Deserialize;
+use serde::Serialize;
 use thiserror::Error as ThisError;
 
 mod asynchronous;
@@ -76,6 +79,20 @@ use zstd::ZSTD_SKIPPABLE_MAGIC_HIGH;
 #[cfg(feature = "zstd")]
 use zstd::ZSTD_SKIPPABLE_MAGIC_LOW;
 
+#[cfg(feature = "vhdx")]
+mod vhdx;
+#[cfg(feature = "vhdx")]
+use vhdx::VhdxDisk;
+#[cfg(feature = "vhdx")]
+use vhdx::VHDX_MAGIC;
+
+#[cfg(feature = "vmdk")]
+mod vmdk;
+#[cfg(feature = "vmdk")]
+use vmdk::VmdkDisk;
+#[cfg(feature = "vmdk")]
+use vmdk::VMDK_MAGIC;
+
 /// Nesting depth limit for disk formats that can open other disk files.
 const MAX_NESTING_DEPTH: u32 = 10;
 
@@ -96,6 +113,12 @@ pub enum Error {
     CreateZstdDisk(anyhow::Error),
     #[error("failure creating single file disk: {0}")]
     CreateSingleFileDisk(cros_async::AsyncError),
+    #[cfg(feature = "vhdx")]
+    #[error("failure in vhdx disk: {0}")]
+    CreateVhdxDisk(vhdx::Error),
+    #[cfg(feature = "vmdk")]
+    #[error("failure in vmdk disk: {0}")]
+    CreateVmdkDisk(vmdk::Error),
     #[error("failed to set O_DIRECT on disk image: {0}")]
     DirectFailed(base::Error),
     #[error("failure with fdatasync: {0}")]
@@ -114,6 +137,8 @@ pub enum Error {
     IoPunchHole(io::Error),
     #[error("checking host fs type: {0}")]
     HostFsType(base::Error),
+    #[error("failure managing internal snapshots: {0}")]
+    InternalSnapshot(io::Error),
     #[error("maximum disk nesting depth exceeded")]
     MaxNestingDepthExceeded,
     #[error("failed to open disk file \"{0}\": {1}")]
@@ -190,6 +215,54 @@ pub trait DiskFile:
             "unsupported operation",
         ))
     }
+
+    /// Lists the snapshots stored inside the disk image.
+    ///
+    /// The internal snapshot functions return [`io::ErrorKind::Unsupported`] Error if the image
+    /// format can't hold snapshots.
+    fn internal_snapshots(&self) -> io::Result<Vec<InternalSnapshot>> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "unsupported operation",
+        ))
+    }
+
+    /// Stores a snapshot of the current contents of the disk named `name` inside the image.
+    fn create_internal_snapshot(&self, _name: &str) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "unsupported operation",
+        ))
+    }
+
+    /// Reverts the contents of the disk to the snapshot with the ID or name `snapshot`.
+    fn apply_internal_snapshot(&self, _snapshot: &str) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "unsupported operation",
+        ))
+    }
+
+    /// Deletes the snapshot with the ID or name `snapshot`, freeing any space only it uses.
+    fn delete_internal_snapshot(&self, _snapshot: &str) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "unsupported operation",
+        ))
+    }
+}
+
+/// Describes a snapshot stored inside a disk image, such as a qcow2 internal snapshot.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InternalSnapshot {
+    /// Unique ID of the snapshot within the image.
+    pub id: String,
+    /// Name given to the snapshot when it was created.
+    pub name: String,
+    /// Size of the disk in bytes at the time of the snapshot.
+    pub disk_size: u64,
+    /// Creation time in seconds since the Unix epoch.
+    pub date_sec: u32,
 }
 
 /// A `DiskFile` that can be converted for asychronous access.
@@ -216,6 +289,8 @@ pub enum ImageType {
     CompositeDisk,
     AndroidSparse,
     Zstd,
+    Vhdx,
+    Vmdk,
 }
 
 /// Detect the type of an image file by checking for a valid header of the supported formats.
@@ -254,7 +329,13 @@ pub fn detect_image_type(file: &File, overlapped_mode: bool) -> Result<ImageType
         }
     }
 
-    #[allow(unused_variables)] // magic4 is only used with the qcow/android-sparse/zstd features.
+    #[cfg(feature = "vhdx")]
+    if magic.data.get(0..VHDX_MAGIC.len()) == Some(VHDX_MAGIC.as_slice()) {
+        return Ok(ImageType::Vhdx);
+    }
+
+    #[allow(unused_variables)]
+    // magic4 is only used with the qcow/android-sparse/zstd/vmdk features.
     if let Some(magic4) = magic
         .data
         .get(0..4)
@@ -275,6 +356,10 @@ pub fn detect_image_type(file: &File, overlapped_mode: bool) -> Result<ImageType
         {
             return Ok(ImageType::Zstd);
         }
+        #[cfg(feature = "vmdk")]
+        if magic4 == VMDK_MAGIC.to_le_bytes() {
+            return Ok(ImageType::Vmdk);
+        }
     }
 
     Ok(ImageType::Raw)
@@ -310,6 +395,13 @@ pub fn open_disk_file(params: DiskFileParams) -> Result<Box<dyn DiskFile>> {
     }
 
     let raw_image = sys::open_raw_disk_image(&params)?;
+    create_disk_file(raw_image, params)
+}
+
+/// Inspect the type of an already opened image file and create an appropriate disk file to match
+/// it. `params.path` is only used to find backing files and in error messages; the caller is
+/// responsible for how `raw_image` was opened and locked.
+pub fn create_disk_file(raw_image: File, params: DiskFileParams) -> Result<Box<dyn DiskFile>> {
     let image_type = detect_image_type(&raw_image, params.is_overlapped)?;
     Ok(match image_type {
         ImageType::Raw => {
@@ -335,11 +427,117 @@ pub fn open_disk_file(params: DiskFileParams) -> Result<Box<dyn DiskFile>> {
         #[cfg(feature = "zstd")]
         ImageType::Zstd => Box::new(ZstdDisk::from_file(raw_image).map_err(Error::CreateZstdDisk)?)
             as Box<dyn DiskFile>,
+        #[cfg(feature = "vhdx")]
+        ImageType::Vhdx => Box::new(VhdxDisk::from_file(raw_image).map_err(Error::CreateVhdxDisk)?)
+            as Box<dyn DiskFile>,
+        #[cfg(feature = "vmdk")]
+        ImageType::Vmdk => Box::new(VmdkDisk::from_file(raw_image).map_err(Error::CreateVmdkDisk)?)
+            as Box<dyn DiskFile>,
         #[allow(unreachable_patterns)]
         _ => return Err(Error::UnknownType),
     })
 }
 
+/// Opens the disk image described by `params` read-only below a qcow2 overlay, so that writes to
+/// the returned disk go to the overlay instead of the image. This makes images in read-only formats
+/// such as zstd usable as writable disks.
+///
+/// `overlay` is created with the image as its backing file if it doesn't exist. Otherwise it is
+/// opened like any other disk image, and is expected to be an overlay of the same image.
+pub fn open_disk_file_with_overlay(
+    params: DiskFileParams,
+    overlay: &Path,
+) -> Result<Box<dyn DiskFile>> {
+    #[cfg(feature = "qcow")]
+    {
+        if !overlay.exists() {
+            create_overlay_file(&params.path, overlay, params.depth)?;
+        }
+        open_disk_file(DiskFileParams {
+            path: overlay.to_path_buf(),
+            ..params
+        })
+    }
+    #[cfg(not(feature = "qcow"))]
+    {
+        let _ = (params, overlay);
+        Err(Error::UnsupportedOperation)
+    }
+}
+
+/// Like `open_disk_file_with_overlay`, but creates the overlay in `overlay`, an empty file that is
+/// usually temporary. The overlay doesn't record the path of the image, so the data written to it
+/// can only be used through the returned disk.
+pub fn open_disk_file_with_temporary_overlay(
+    params: DiskFileParams,
+    overlay: File,
+) -> Result<Box<dyn DiskFile>> {
+    #[cfg(feature = "qcow")]
+    {
+        let path = params.path.clone();
+        let depth = params.depth;
+        let image = open_disk_file(DiskFileParams {
+            is_read_only: true,
+            is_sparse_file: false,
+            ..params
+        })?;
+        let size = image.get_len().map_err(Error::SeekingFile)?;
+        let overlay_params = DiskFileParams {
+            path,
+            is_read_only: false,
+            is_sparse_file: true,
+            is_overlapped: false,
+            is_direct: false,
+            lock: false,
+            depth,
+        };
+        let mut qcow = QcowFile::new(overlay, overlay_params, size).map_err(Error::QcowError)?;
+        qcow.set_backing_file(Some(image));
+        Ok(Box::new(qcow))
+    }
+    #[cfg(not(feature = "qcow"))]
+    {
+        let _ = (params, overlay);
+        Err(Error::UnsupportedOperation)
+    }
+}
+
+// Creates a qcow2 image at `overlay` that uses the image at `image_path` as its backing file.
+#[cfg(feature = "qcow")]
+fn create_overlay_file(image_path: &Path, overlay: &Path, depth: u32) -> Result<()> {
+    let open_error =
+        |path: &Path, e: io::Error| Error::OpenFile(path.display().to_string(), e.into());
+    // The overlay may be used from another working directory later, so the image is recorded with
+    // an absolute path.
+    let image_path = image_path
+        .canonicalize()
+        .map_err(|e| open_error(image_path, e))?;
+    let backing_file = image_path
+        .to_str()
+        .ok_or_else(|| open_error(&image_path, io::ErrorKind::InvalidInput.into()))?;
+    let file = File::options()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(overlay)
+        .map_err(|e| open_error(overlay, e))?;
+    let params = DiskFileParams {
+        path: overlay.to_path_buf(),
+        is_read_only: false,
+        is_sparse_file: true,
+        is_overlapped: false,
+        is_direct: false,
+        lock: false,
+        depth,
+    };
+    if let Err(e) = QcowFile::new_from_backing(file, params, backing_file) {
+        // Don't leave a broken overlay behind that would be opened next time.
+        let _ = std::fs::remove_file(overlay);
+        return Err(Error::QcowError(e));
+    }
+    Ok(())
+}
+
 /// An asynchronously accessible disk.
 #[async_trait(?Send)]
 pub trait AsyncDisk: DiskGetLen + FileSetLen + FileAllocate {
@@ -417,6 +615,26 @@ pub trait AsyncDisk: DiskGetLen + FileSetLen + FileAllocate {
         )
         .await
     }
+
+    /// Lists the snapshots stored inside the disk image.
+    async fn internal_snapshots(&self) -> Result<Vec<InternalSnapshot>> {
+        Err(Error::UnsupportedOperation)
+    }
+
+    /// Stores a snapshot of the current contents of the disk named `name` inside the image.
+    async fn create_internal_snapshot(&self, _name: &str) -> Result<()> {
+        Err(Error::UnsupportedOperation)
+    }
+
+    /// Reverts the contents of the disk to the snapshot with the ID or name `snapshot`.
+    async fn apply_internal_snapshot(&self, _snapshot: &str) -> Result<()> {
+        Err(Error::UnsupportedOperation)
+    }
+
+    /// Deletes the snapshot with the ID or name `snapshot`.
+    async fn delete_internal_snapshot(&self, _snapshot: &str) -> Result<()> {
+        Err(Error::UnsupportedOperation)
+    }
 }
 
 /// A disk backed by a single file that implements `AsyncDisk` for access.
@@ -4,12 +4,14 @@
 
 mod qcow_raw_file;
 mod refcount;
+mod snapshot_table;
 mod vec_cache;
 
 use std::cmp::max;
 use std::cmp::min;
 use std::fs::File;
 use std::io;
+use std::io::BufReader;
 use std::io::Read;
 use std::io::Seek;
 use std::io::SeekFrom;
@@ -17,6 +19,8 @@ use std::io::Write;
 use std::mem::size_of;
 use std::path::PathBuf;
 use std::str;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
 
 use base::error;
 use base::AsRawDescriptor;
@@ -31,7 +35,9 @@ use base::VolatileMemory;
 use base::VolatileSlice;
 use base::WriteZeroesAt;
 use cros_async::Executor;
+use libc::EEXIST;
 use libc::EINVAL;
+use libc::ENOENT;
 use libc::ENOSPC;
 use libc::ENOTSUP;
 use remain::sorted;
@@ -42,6 +48,8 @@ use crate::asynchronous::DiskFlush;
 use crate::open_disk_file;
 use crate::qcow::qcow_raw_file::QcowRawFile;
 use crate::qcow::refcount::RefCount;
+use crate::qcow::snapshot_table::SnapshotEntry;
+use crate::qcow::snapshot_table::MAX_SNAPSHOTS;
 use crate::qcow::vec_cache::CacheMap;
 use crate::qcow::vec_cache::Cacheable;
 use crate::qcow::vec_cache::VecCache;
@@ -50,6 +58,7 @@ use crate::AsyncDiskFileWrapper;
 use crate::DiskFile;
 use crate::DiskFileParams;
 use crate::DiskGetLen;
+use crate::InternalSnapshot;
 use crate::ToAsyncDisk;
 
 #[sorted]
@@ -61,8 +70,8 @@ pub enum Error {
     BackingFileOpen(Box<crate::Error>),
     #[error("backing file name is too long: {0} bytes over")]
     BackingFileTooLong(usize),
-    #[error("compressed blocks not supported")]
-    CompressedBlocksNotSupported,
+    #[error("data file open error: {0}")]
+    DataFileOpen(base::Error),
     #[error("failed to evict cache: {0}")]
     EvictingCache(io::Error),
     #[error("file larger than max of {MAX_QCOW_FILE_SIZE}: {0}")]
@@ -77,6 +86,10 @@ pub enum Error {
     InvalidClusterIndex,
     #[error("invalid cluster size")]
     InvalidClusterSize,
+    #[error("failed to parse data file name: {0}")]
+    InvalidDataFileName(str::Utf8Error),
+    #[error("invalid header extension {0:#x}")]
+    InvalidHeaderExtension(u32),
     #[error("invalid index")]
     InvalidIndex,
     #[error("invalid L1 table offset")]
@@ -91,6 +104,8 @@ pub enum Error {
     InvalidRefcountTableOffset,
     #[error("invalid refcount table size: {0}")]
     InvalidRefcountTableSize(u64),
+    #[error("image uses a data file but doesn't name it")]
+    MissingDataFile,
     #[error("no free clusters")]
     NoFreeClusters,
     #[error("no refcount clusters")]
@@ -107,6 +122,8 @@ pub enum Error {
     ReadingRefCountBlock(refcount::Error),
     #[error("failed to read ref counts: {0}")]
     ReadingRefCounts(io::Error),
+    #[error("failed to read snapshot table: {0}")]
+    ReadingSnapshotTable(io::Error),
     #[error("failed to rebuild ref counts: {0}")]
     RebuildingRefCounts(io::Error),
     #[error("refcount table offset past file end")]
@@ -123,6 +140,12 @@ pub enum Error {
     TooManyL1Entries(u64),
     #[error("ref count table too large: {0}")]
     TooManyRefcounts(u64),
+    #[error("too many snapshots: {0}")]
+    TooManySnapshots(u32),
+    #[error("unsupported compression type: {0}")]
+    UnsupportedCompressionType(u8),
+    #[error("unsupported incompatible features: {0:#x}")]
+    UnsupportedFeatures(u64),
     #[error("unsupported refcount order")]
     UnsupportedRefcountOrder,
     #[error("unsupported version: {0}")]
@@ -152,6 +175,8 @@ const MAX_RAM_POINTER_TABLE_SIZE: u64 = 35_000_000;
 const DEFAULT_REFCOUNT_ORDER: u32 = 4;
 
 const V3_BARE_HEADER_SIZE: u32 = 104;
+// Offset of `nb_snapshots` in the header, `snapshots_offset` follows it directly.
+const NB_SNAPSHOTS_OFFSET: u64 = 60;
 
 // bits 0-8 and 56-63 are reserved.
 const L1_TABLE_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
@@ -160,9 +185,36 @@ const L2_TABLE_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
 const COMPRESSED_FLAG: u64 = 1 << 62;
 const CLUSTER_USED_FLAG: u64 = 1 << 63;
 const COMPATIBLE_FEATURES_LAZY_REFCOUNTS: u64 = 1 << 0;
-
-// The format supports a "header extension area", that crosvm does not use.
+const INCOMPATIBLE_FEATURES_DIRTY: u64 = 1 << 0;
+const INCOMPATIBLE_FEATURES_DATA_FILE: u64 = 1 << 2;
+const INCOMPATIBLE_FEATURES_COMPRESSION_TYPE: u64 = 1 << 3;
+const INCOMPATIBLE_FEATURES_EXTENDED_L2: u64 = 1 << 4;
+// The corrupt bit and anything newer than extended L2 entries are rejected.
+const INCOMPATIBLE_FEATURES_SUPPORTED: u64 = INCOMPATIBLE_FEATURES_DIRTY
+    | INCOMPATIBLE_FEATURES_DATA_FILE
+    | INCOMPATIBLE_FEATURES_COMPRESSION_TYPE
+    | INCOMPATIBLE_FEATURES_EXTENDED_L2;
+// Offset of `incompatible_features` in the header, `compatible_features` follows it directly.
+const INCOMPATIBLE_FEATURES_OFFSET: u64 = 72;
+
+// Extended L2 entries are followed by a bitmap of the state of the 32 subclusters of the cluster.
+// The low half flags allocated subclusters, the high half subclusters that read as zeros.
+const SUBCLUSTERS_PER_CLUSTER: u64 = 32;
+const SUBCLUSTERS_ALL_ALLOCATED: u64 = 0xffff_ffff;
+// qemu requires subclusters of at least 512 bytes.
+const MIN_EXTENDED_L2_CLUSTER_BITS: u32 = 14;
+
+// Compression methods that can be named by the `compression_type` header field.
+const COMPRESSION_TYPE_ZLIB: u8 = 0;
+const COMPRESSION_TYPE_ZSTD: u8 = 1;
+// Compressed cluster descriptors count their length in 512 byte sectors.
+const COMPRESSED_SECTOR_SIZE: u64 = 512;
+
+// The format supports a "header extension area" after the header. crosvm only writes the end
+// marker and, for images using an external data file, the name of that file.
 const QCOW_EMPTY_HEADER_EXTENSION_SIZE: u32 = 8;
+const HEADER_EXTENSION_END: u32 = 0;
+const HEADER_EXTENSION_DATA_FILE: u32 = 0x4441_5441;
 
 // Defined by the specification
 const MAX_BACKING_FILE_SIZE: u32 = 1023;
@@ -195,9 +247,13 @@ pub struct QcowHeader {
     pub autoclear_features: u64,
     pub refcount_order: u32,
     pub header_size: u32,
+    // Only present if `header_size` is larger than `V3_BARE_HEADER_SIZE`, zlib otherwise.
+    pub compression_type: u8,
 
     // Post-header entries
     pub backing_file_path: Option<String>,
+    // From the data file name header extension.
+    pub data_file_path: Option<String>,
 }
 
 // Reads the next u16 from the file.
@@ -256,8 +312,19 @@ impl QcowHeader {
             autoclear_features: read_u64_from_file(f)?,
             refcount_order: read_u32_from_file(f)?,
             header_size: read_u32_from_file(f)?,
+            compression_type: COMPRESSION_TYPE_ZLIB,
             backing_file_path: None,
+            data_file_path: None,
         };
+        if header.header_size > V3_BARE_HEADER_SIZE {
+            let mut compression_type = [0u8; 1];
+            f.read_exact(&mut compression_type)
+                .map_err(Error::ReadingHeader)?;
+            header.compression_type = compression_type[0];
+        }
+        if header.version == 3 {
+            header.read_extensions(f)?;
+        }
         if header.backing_file_size > MAX_BACKING_FILE_SIZE {
             return Err(Error::BackingFileTooLong(header.backing_file_size as usize));
         }
@@ -326,11 +393,47 @@ impl QcowHeader {
             autoclear_features: 0,
             refcount_order: DEFAULT_REFCOUNT_ORDER,
             header_size: V3_BARE_HEADER_SIZE,
+            compression_type: COMPRESSION_TYPE_ZLIB,
             backing_file_path: backing_file.map(String::from),
+            data_file_path: None,
         })
     }
 
-    /// Write the header to `file`.
+    // Reads the header extensions that follow the header, up to the end of the first cluster.
+    fn read_extensions(&mut self, f: &mut File) -> Result<()> {
+        if !(MIN_CLUSTER_BITS..=MAX_CLUSTER_BITS).contains(&self.cluster_bits) {
+            return Err(Error::InvalidClusterSize);
+        }
+        let extensions_end = 0x01u64 << self.cluster_bits;
+        let mut offset = u64::from(self.header_size);
+        while offset + 8 <= extensions_end {
+            f.seek(SeekFrom::Start(offset))
+                .map_err(Error::ReadingHeader)?;
+            let extension_type = read_u32_from_file(f)?;
+            let len = u64::from(read_u32_from_file(f)?);
+            if extension_type == HEADER_EXTENSION_END {
+                break;
+            }
+            offset += 8;
+            if offset + len > extensions_end {
+                return Err(Error::InvalidHeaderExtension(extension_type));
+            }
+            // Extensions crosvm doesn't know about are optional by definition and skipped.
+            if extension_type == HEADER_EXTENSION_DATA_FILE {
+                let mut name = vec![0u8; len as usize];
+                f.read_exact(&mut name).map_err(Error::ReadingHeader)?;
+                self.data_file_path = Some(
+                    String::from_utf8(name)
+                        .map_err(|err| Error::InvalidDataFileName(err.utf8_error()))?,
+                );
+            }
+            offset += len.next_multiple_of(8);
+        }
+        Ok(())
+    }
+
+    /// Write the header to `file`. `backing_file_offset` has to leave room for the header
+    /// extensions.
     pub fn write_to<F: Write + Seek>(&self, file: &mut F) -> Result<()> {
         // Writes the next u32 to the file.
         fn write_u32_to_file<F: Write>(f: &mut F, value: u32) -> Result<()> {
@@ -362,6 +465,21 @@ impl QcowHeader {
         write_u64_to_file(file, self.autoclear_features)?;
         write_u32_to_file(file, self.refcount_order)?;
         write_u32_to_file(file, self.header_size)?;
+        if self.header_size > V3_BARE_HEADER_SIZE {
+            // Write the compression type followed by padding up to the declared header size.
+            let mut additional_fields =
+                vec![0u8; (self.header_size - V3_BARE_HEADER_SIZE) as usize];
+            additional_fields[0] = self.compression_type;
+            file.write_all(&additional_fields)
+                .map_err(Error::WritingHeader)?;
+        }
+        if let Some(data_file_path) = self.data_file_path.as_ref() {
+            write_u32_to_file(file, HEADER_EXTENSION_DATA_FILE)?;
+            write_u32_to_file(file, data_file_path.len() as u32)?;
+            let mut name = data_file_path.as_bytes().to_vec();
+            name.resize(name.len().next_multiple_of(8), 0);
+            file.write_all(&name).map_err(Error::WritingHeader)?;
+        }
         write_u32_to_file(file, 0)?; // header extension type: end of header extension area
         write_u32_to_file(file, 0)?; // length of header extension data: 0
         if let Some(backing_file_path) = self.backing_file_path.as_ref() {
@@ -439,9 +557,117 @@ struct QcowFileInner {
     // removal of references to them have been synced to disk.
     avail_clusters: Vec<u64>,
     backing_file: Option<Box<dyn DiskFile>>,
+    // The L2 entry and contents of the most recently decompressed cluster.
+    decompressed_cluster: Option<(u64, Vec<u8>)>,
+    // The internal snapshots stored in the file.
+    snapshots: Vec<SnapshotEntry>,
+    l2_format: L2Format,
+    // Holds the guest data instead of `raw_file` if the image uses an external data file.
+    data_file: Option<File>,
 }
 
-impl DiskFile for QcowFile {}
+// The layout of the entries of the L2 tables of an image.
+#[derive(Clone, Copy, Debug)]
+struct L2Format {
+    // Each entry is followed by a bitmap of the state of its subclusters.
+    extended: bool,
+    // Data clusters are stored in an external file, at the same offset as in the guest. Offset 0
+    // is valid in that file, so allocated entries keep the `CLUSTER_USED_FLAG` to tell them apart
+    // from unallocated ones.
+    external_data: bool,
+}
+
+impl L2Format {
+    fn from_header(header: &QcowHeader) -> L2Format {
+        L2Format {
+            extended: header.incompatible_features & INCOMPATIBLE_FEATURES_EXTENDED_L2 != 0,
+            external_data: header.incompatible_features & INCOMPATIBLE_FEATURES_DATA_FILE != 0,
+        }
+    }
+
+    // Returns the number of u64 words taken up by each entry.
+    fn entry_words(self) -> usize {
+        if self.extended {
+            2
+        } else {
+            1
+        }
+    }
+
+    // Returns the number of entries in an L2 table of a cluster.
+    fn entries_per_table(self, cluster_size: u64) -> u64 {
+        cluster_size / (self.entry_words() * size_of::<u64>()) as u64
+    }
+
+    // Returns true if the standard cluster descriptor `entry` points at a data cluster.
+    fn is_allocated(self, entry: u64) -> bool {
+        entry & L2_TABLE_OFFSET_MASK != 0 || (self.external_data && entry & CLUSTER_USED_FLAG != 0)
+    }
+}
+
+// Where the data of a range of a guest cluster is found.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ClusterMapping {
+    // Not allocated, reads from the backing file or as zeros.
+    Unallocated,
+    // Reads as zeros, even if there is a backing file.
+    Zero,
+    // A compressed cluster with the given descriptor.
+    Compressed(u64),
+    // A standard cluster stored at the given host offset.
+    Data(u64),
+}
+
+// The data source `QcowFileInner::read_cb` found for a range of the disk.
+enum ReadSource<'a> {
+    // A file to read from, either the image itself or its backing file.
+    Disk(&'a mut dyn DiskFile),
+    // The decompressed contents of a compressed cluster.
+    Buffer(&'a [u8]),
+    // The range is unallocated and reads as zeros.
+    Zeroes,
+}
+
+impl ReadSource<'_> {
+    // Fills `slice` with the data at `offset` in this source.
+    fn read_exact_at_volatile(self, slice: VolatileSlice, offset: u64) -> io::Result<()> {
+        match self {
+            ReadSource::Disk(f) => f.read_exact_at_volatile(slice, offset),
+            ReadSource::Buffer(data) => {
+                let start = offset as usize;
+                slice.copy_from(&data[start..start + slice.size()]);
+                Ok(())
+            }
+            ReadSource::Zeroes => {
+                slice.write_bytes(0);
+                Ok(())
+            }
+        }
+    }
+}
+
+impl DiskFile for QcowFile {
+    fn internal_snapshots(&self) -> io::Result<Vec<InternalSnapshot>> {
+        let inner = self.inner.lock();
+        Ok(inner
+            .snapshots
+            .iter()
+            .map(|s| s.info(inner.header.size))
+            .collect())
+    }
+
+    fn create_internal_snapshot(&self, name: &str) -> io::Result<()> {
+        self.inner.lock().create_snapshot(name)
+    }
+
+    fn apply_internal_snapshot(&self, snapshot: &str) -> io::Result<()> {
+        self.inner.lock().apply_snapshot(snapshot)
+    }
+
+    fn delete_internal_snapshot(&self, snapshot: &str) -> io::Result<()> {
+        self.inner.lock().delete_snapshot(snapshot)
+    }
+}
 
 impl DiskFlush for QcowFile {
     fn flush(&self) -> io::Result<()> {
@@ -461,6 +687,23 @@ impl QcowFile {
             return Err(Error::UnsupportedVersion(header.version));
         }
 
+        let unsupported_features = header.incompatible_features & !INCOMPATIBLE_FEATURES_SUPPORTED;
+        if unsupported_features != 0 {
+            return Err(Error::UnsupportedFeatures(unsupported_features));
+        }
+        let l2_format = L2Format::from_header(&header);
+
+        // Compressed clusters can be zlib or, if the decoder is built in, zstd. Any type other
+        // than the default must also be flagged as an incompatible feature.
+        match header.compression_type {
+            COMPRESSION_TYPE_ZLIB => {}
+            COMPRESSION_TYPE_ZSTD
+                if cfg!(feature = "zstd")
+                    && header.incompatible_features & INCOMPATIBLE_FEATURES_COMPRESSION_TYPE
+                        != 0 => {}
+            t => return Err(Error::UnsupportedCompressionType(t)),
+        }
+
         // Make sure that the L1 table fits in RAM.
         if u64::from(header.l1_size) > MAX_RAM_POINTER_TABLE_SIZE {
             return Err(Error::InvalidL1TableSize(header.l1_size));
@@ -470,6 +713,9 @@ impl QcowFile {
         if !(MIN_CLUSTER_BITS..=MAX_CLUSTER_BITS).contains(&cluster_bits) {
             return Err(Error::InvalidClusterSize);
         }
+        if l2_format.extended && cluster_bits < MIN_EXTENDED_L2_CLUSTER_BITS {
+            return Err(Error::InvalidClusterSize);
+        }
         let cluster_size = 0x01u64 << cluster_bits;
 
         // Limit the total size of the disk.
@@ -496,6 +742,26 @@ impl QcowFile {
             None
         };
 
+        let data_file = if l2_format.external_data {
+            let data_file_name = header
+                .data_file_path
+                .as_ref()
+                .ok_or(Error::MissingDataFile)?;
+            // Like qemu, resolve relative names from the directory of the image.
+            let data_file_path = match params.path.parent() {
+                Some(dir) => dir.join(data_file_name),
+                None => PathBuf::from(data_file_name),
+            };
+            let mut options = File::options();
+            options.read(true).write(!params.is_read_only);
+            Some(
+                base::open_file_or_duplicate(&data_file_path, &options)
+                    .map_err(Error::DataFileOpen)?,
+            )
+        } else {
+            None
+        };
+
         // Only support two byte refcounts.
         let refcount_bits: u64 = 0x01u64
             .checked_shl(header.refcount_order)
@@ -533,17 +799,21 @@ impl QcowFile {
             }
         }
 
-        if (header.compatible_features & COMPATIBLE_FEATURES_LAZY_REFCOUNTS) != 0 {
+        // Refcounts of images that weren't closed cleanly may be out of date.
+        if (header.compatible_features & COMPATIBLE_FEATURES_LAZY_REFCOUNTS) != 0
+            || (header.incompatible_features & INCOMPATIBLE_FEATURES_DIRTY) != 0
+        {
             refcount_rebuild_required = true;
         }
 
         let mut raw_file =
             QcowRawFile::from(file, cluster_size).ok_or(Error::InvalidClusterSize)?;
+        let snapshots = read_snapshot_table(&mut raw_file, &header)?;
         if refcount_rebuild_required {
             QcowFileInner::rebuild_refcounts(&mut raw_file, header.clone())?;
         }
 
-        let l2_size = cluster_size / size_of::<u64>() as u64;
+        let l2_size = l2_format.entries_per_table(cluster_size);
         let num_clusters = header.size.div_ceil(cluster_size);
         let num_l2_clusters = num_clusters.div_ceil(l2_size);
         let l1_clusters = num_l2_clusters.div_ceil(cluster_size);
@@ -575,16 +845,21 @@ impl QcowFile {
             return Err(Error::TooManyRefcounts(refcount_clusters));
         }
         let refcount_block_entries = cluster_size / refcount_bytes;
+        // Use all of the allocated table, snapshots can grow the file past the clusters needed to
+        // store the disk contents once.
+        let refcount_table_entries = (u64::from(header.refcount_table_clusters) * cluster_size
+            / size_of::<u64>() as u64)
+            .clamp(refcount_clusters, MAX_RAM_POINTER_TABLE_SIZE);
         let refcounts = RefCount::new(
             &mut raw_file,
             header.refcount_table_offset,
-            refcount_clusters,
+            refcount_table_entries,
             refcount_block_entries,
             cluster_size,
         )
         .map_err(Error::ReadingRefCounts)?;
 
-        let l2_entries = cluster_size / size_of::<u64>() as u64;
+        let l2_entries = l2_format.entries_per_table(cluster_size);
 
         let mut inner = QcowFileInner {
             raw_file,
@@ -597,6 +872,10 @@ impl QcowFile {
             unref_clusters: Vec::new(),
             avail_clusters: Vec::new(),
             backing_file,
+            decompressed_cluster: None,
+            snapshots,
+            l2_format,
+            data_file,
         };
 
         // Check that the L1 and refcount tables fit in a 64bit address space.
@@ -770,18 +1049,17 @@ impl QcowFileInner {
         // Traverse the L1 and L2 tables to find all reachable data clusters.
         fn set_data_refcounts(
             refcounts: &mut [u16],
-            header: QcowHeader,
+            header: &QcowHeader,
+            l1_table_offset: u64,
+            l1_size: u32,
             cluster_size: u64,
             raw_file: &mut QcowRawFile,
         ) -> Result<()> {
+            let l2_format = L2Format::from_header(header);
             let l1_table = raw_file
-                .read_pointer_table(
-                    header.l1_table_offset,
-                    header.l1_size as u64,
-                    Some(L1_TABLE_OFFSET_MASK),
-                )
+                .read_pointer_table(l1_table_offset, l1_size as u64, Some(L1_TABLE_OFFSET_MASK))
                 .map_err(Error::ReadingPointers)?;
-            for l1_index in 0..header.l1_size as usize {
+            for l1_index in 0..l1_size as usize {
                 let l2_addr_disk = *l1_table.get(l1_index).ok_or(Error::InvalidIndex)?;
                 if l2_addr_disk != 0 {
                     // Add a reference to the L2 table cluster itself.
@@ -792,12 +1070,20 @@ impl QcowFileInner {
                         .read_pointer_table(
                             l2_addr_disk,
                             cluster_size / size_of::<u64>() as u64,
-                            Some(L2_TABLE_OFFSET_MASK),
+                            None,
                         )
                         .map_err(Error::ReadingPointers)?;
-                    for data_cluster_addr in l2_table {
-                        if data_cluster_addr != 0 {
-                            add_ref(refcounts, cluster_size, data_cluster_addr)?;
+                    for entry in l2_table.into_iter().step_by(l2_format.entry_words()) {
+                        if entry & COMPRESSED_FLAG != 0 {
+                            // Compressed data can span several host clusters.
+                            for data_cluster_addr in
+                                compressed_host_clusters(entry, header.cluster_bits, cluster_size)
+                            {
+                                add_ref(refcounts, cluster_size, data_cluster_addr)?;
+                            }
+                        } else if entry & L2_TABLE_OFFSET_MASK != 0 && !l2_format.external_data {
+                            // Clusters in an external data file aren't refcounted.
+                            add_ref(refcounts, cluster_size, entry & L2_TABLE_OFFSET_MASK)?;
                         }
                     }
                 }
@@ -806,6 +1092,43 @@ impl QcowFileInner {
             Ok(())
         }
 
+        // Add references to the snapshot table and everything reachable from each snapshot.
+        fn set_snapshot_refcounts(
+            refcounts: &mut [u16],
+            header: &QcowHeader,
+            cluster_size: u64,
+            raw_file: &mut QcowRawFile,
+        ) -> Result<()> {
+            let snapshots = read_snapshot_table(raw_file, header)?;
+            let table_size: u64 = snapshots.iter().map(|s| s.size() as u64).sum();
+            for i in 0..table_size.div_ceil(cluster_size) {
+                add_ref(
+                    refcounts,
+                    cluster_size,
+                    header.snapshots_offset + i * cluster_size,
+                )?;
+            }
+            for snapshot in snapshots {
+                let l1_bytes = u64::from(snapshot.l1_size) * size_of::<u64>() as u64;
+                for i in 0..l1_bytes.div_ceil(cluster_size) {
+                    add_ref(
+                        refcounts,
+                        cluster_size,
+                        snapshot.l1_table_offset + i * cluster_size,
+                    )?;
+                }
+                set_data_refcounts(
+                    refcounts,
+                    header,
+                    snapshot.l1_table_offset,
+                    snapshot.l1_size,
+                    cluster_size,
+                    raw_file,
+                )?;
+            }
+            Ok(())
+        }
+
         // Add references to the top-level refcount table clusters.
         fn set_refcount_table_refcounts(
             refcounts: &mut [u16],
@@ -855,6 +1178,18 @@ impl QcowFileInner {
             Ok(ref_table)
         }
 
+        // Rewrites the feature fields of the header. The rest of the header, including any
+        // extensions crosvm doesn't know about, is left alone.
+        fn write_features(raw_file: &mut QcowRawFile, header: &QcowHeader) -> Result<()> {
+            let mut features = Vec::with_capacity(16);
+            features.extend_from_slice(&header.incompatible_features.to_be_bytes());
+            features.extend_from_slice(&header.compatible_features.to_be_bytes());
+            let file = raw_file.file_mut();
+            file.seek(SeekFrom::Start(INCOMPATIBLE_FEATURES_OFFSET))
+                .map_err(Error::SeekingFile)?;
+            file.write_all(&features).map_err(Error::WritingHeader)
+        }
+
         // Write the updated reference count blocks and reftable.
         fn write_refblocks(
             refcounts: &[u16],
@@ -865,11 +1200,7 @@ impl QcowFileInner {
         ) -> Result<()> {
             // Rewrite the header with lazy refcounts enabled while we are rebuilding the tables.
             header.compatible_features |= COMPATIBLE_FEATURES_LAZY_REFCOUNTS;
-            raw_file
-                .file_mut()
-                .seek(SeekFrom::Start(0))
-                .map_err(Error::SeekingFile)?;
-            header.write_to(raw_file.file_mut())?;
+            write_features(raw_file, &header)?;
 
             for (i, refblock_addr) in ref_table.iter().enumerate() {
                 // Write a block of refcounts to the location indicated by refblock_addr.
@@ -901,15 +1232,11 @@ impl QcowFileInner {
                 .write_pointer_table(header.refcount_table_offset, ref_table, 0)
                 .map_err(Error::WritingHeader)?;
 
-            // Rewrite the header again, now with lazy refcounts disabled.
+            // Rewrite the header again, now with lazy refcounts disabled and the refcounts
+            // consistent.
             header.compatible_features &= !COMPATIBLE_FEATURES_LAZY_REFCOUNTS;
-            raw_file
-                .file_mut()
-                .seek(SeekFrom::Start(0))
-                .map_err(Error::SeekingFile)?;
-            header.write_to(raw_file.file_mut())?;
-
-            Ok(())
+            header.incompatible_features &= !INCOMPATIBLE_FEATURES_DIRTY;
+            write_features(raw_file, &header)
         }
 
         let cluster_size = raw_file.cluster_size();
@@ -925,7 +1252,8 @@ impl QcowFileInner {
         let refcount_block_entries = cluster_size / refcount_bytes;
         let pointers_per_cluster = cluster_size / size_of::<u64>() as u64;
         let data_clusters = header.size.div_ceil(cluster_size);
-        let l2_clusters = data_clusters.div_ceil(pointers_per_cluster);
+        let l2_clusters =
+            data_clusters.div_ceil(L2Format::from_header(&header).entries_per_table(cluster_size));
         let l1_clusters = l2_clusters.div_ceil(cluster_size);
         let header_clusters = (size_of::<QcowHeader>() as u64).div_ceil(cluster_size);
         let max_clusters = data_clusters + l2_clusters + l1_clusters + header_clusters;
@@ -953,7 +1281,15 @@ impl QcowFileInner {
         // Find all references clusters and rebuild refcounts.
         set_header_refcount(&mut refcounts, cluster_size)?;
         set_l1_refcounts(&mut refcounts, header.clone(), cluster_size)?;
-        set_data_refcounts(&mut refcounts, header.clone(), cluster_size, raw_file)?;
+        set_data_refcounts(
+            &mut refcounts,
+            &header,
+            header.l1_table_offset,
+            header.l1_size,
+            cluster_size,
+            raw_file,
+        )?;
+        set_snapshot_refcounts(&mut refcounts, &header, cluster_size, raw_file)?;
         set_refcount_table_refcounts(&mut refcounts, header.clone(), cluster_size)?;
 
         // Allocate clusters to store the new reference count blocks.
@@ -1010,9 +1346,10 @@ impl QcowFileInner {
         (address / self.raw_file.cluster_size()) % self.l2_entries
     }
 
-    // Gets the offset of the given guest address in the host file. If L1, L2, or data clusters have
-    // yet to be allocated, return None.
-    fn file_offset_read(&mut self, address: u64) -> std::io::Result<Option<u64>> {
+    // Gets the L2 entry for the cluster containing the given guest address, followed by its
+    // subcluster bitmap if the image uses extended L2 entries. If the L1 or L2 entry has yet to be
+    // allocated, or the entries aren't extended, the respective value is 0.
+    fn l2_entry(&mut self, address: u64) -> std::io::Result<(u64, u64)> {
         if address >= self.virtual_size() {
             return Err(std::io::Error::from_raw_os_error(EINVAL));
         }
@@ -1025,32 +1362,121 @@ impl QcowFileInner {
 
         if l2_addr_disk == 0 {
             // Reading from an unallocated cluster will return zeros.
-            return Ok(None);
+            return Ok((0, 0));
         }
 
         let l2_index = self.l2_table_index(address) as usize;
 
         if !self.l2_cache.contains_key(&l1_index) {
             // Not in the cache.
-            let table =
-                VecCache::from_vec(Self::read_l2_cluster(&mut self.raw_file, l2_addr_disk)?);
+            let table = VecCache::from_vec(Self::read_l2_cluster(
+                &mut self.raw_file,
+                l2_addr_disk,
+                self.l2_format,
+            )?);
 
             let l1_table = &self.l1_table;
             let raw_file = &mut self.raw_file;
+            let used_flag = l2_used_flag(&self.snapshots);
+            let l2_format = self.l2_format;
             self.l2_cache.insert(l1_index, table, |index, evicted| {
-                raw_file.write_pointer_table(
+                write_l2_table(
+                    raw_file,
                     l1_table[index],
                     evicted.get_values(),
-                    CLUSTER_USED_FLAG,
+                    used_flag,
+                    l2_format,
                 )
             })?;
         };
 
-        let cluster_addr = self.l2_cache.get(&l1_index).unwrap()[l2_index];
-        if cluster_addr == 0 {
-            return Ok(None);
+        Ok(self.cached_l2_entry(l1_index, l2_index))
+    }
+
+    // Returns the L2 entry and subcluster bitmap at `l2_index` of the cached L2 table for
+    // `l1_index`, which must be in the cache.
+    fn cached_l2_entry(&self, l1_index: usize, l2_index: usize) -> (u64, u64) {
+        let table = self.l2_cache.get(&l1_index).unwrap();
+        let index = l2_index * self.l2_format.entry_words();
+        if self.l2_format.extended {
+            (table[index], table[index + 1])
+        } else {
+            (table[index], 0)
+        }
+    }
+
+    // Returns where the data at guest `address` is found and for how many of the following
+    // `count` bytes that holds. The range must not cross a cluster boundary.
+    fn cluster_mapping(
+        &mut self,
+        address: u64,
+        count: usize,
+    ) -> std::io::Result<(ClusterMapping, usize)> {
+        let (entry, bitmap) = self.l2_entry(address)?;
+        if entry & COMPRESSED_FLAG != 0 {
+            return Ok((ClusterMapping::Compressed(entry), count));
+        }
+        let allocated = self.l2_format.is_allocated(entry);
+        let host_addr = entry & L2_TABLE_OFFSET_MASK;
+        if !self.l2_format.extended {
+            let mapping = if allocated {
+                ClusterMapping::Data(host_addr)
+            } else {
+                ClusterMapping::Unallocated
+            };
+            return Ok((mapping, count));
+        }
+
+        let subcluster_mapping = |index: u64| {
+            if bitmap & (1 << index) != 0 {
+                if !allocated {
+                    // Subclusters can't be allocated without their cluster.
+                    return Err(std::io::Error::from_raw_os_error(EINVAL));
+                }
+                Ok(ClusterMapping::Data(host_addr))
+            } else if bitmap & (1 << (index + SUBCLUSTERS_PER_CLUSTER)) != 0 {
+                Ok(ClusterMapping::Zero)
+            } else {
+                Ok(ClusterMapping::Unallocated)
+            }
+        };
+        let subcluster_size = self.raw_file.cluster_size() / SUBCLUSTERS_PER_CLUSTER;
+        let cluster_offset = self.raw_file.cluster_offset(address);
+        let first = cluster_offset / subcluster_size;
+        let last = (cluster_offset + count as u64 - 1) / subcluster_size;
+        let mapping = subcluster_mapping(first)?;
+        for index in first + 1..=last {
+            if subcluster_mapping(index)? != mapping {
+                let run = index * subcluster_size - cluster_offset;
+                return Ok((mapping, run as usize));
+            }
+        }
+        Ok((mapping, count))
+    }
+
+    // Returns the contents of the guest cluster containing `address` as it currently reads.
+    fn read_guest_cluster(&mut self, address: u64) -> std::io::Result<Vec<u8>> {
+        let cluster_size = self.raw_file.cluster_size();
+        let cluster_begin = address - self.raw_file.cluster_offset(address);
+        let mut cluster_data = vec![0u8; cluster_size as usize];
+        let volatile_slice = VolatileSlice::new(&mut cluster_data);
+        self.read_cb(
+            cluster_begin,
+            cluster_size as usize,
+            |source, already_read, offset, count| {
+                let sub_slice = volatile_slice.get_slice(already_read, count).unwrap();
+                source.read_exact_at_volatile(sub_slice, offset)
+            },
+        )?;
+        Ok(cluster_data)
+    }
+
+    // Returns the file holding the guest data, either the external data file or the image itself.
+    fn data_file_mut(&mut self) -> &mut File {
+        match self.data_file.as_mut() {
+            Some(data_file) => data_file,
+            None => self.raw_file.file_mut(),
         }
-        Ok(Some(cluster_addr + self.raw_file.cluster_offset(address)))
     }
 
     // Gets the offset of the given guest address in the host file. If L1, L2, or data clusters need
@@ -1078,24 +1504,36 @@ impl QcowFileInner {
                 // The cluster refcount starts at one meaning it is used but doesn't need COW.
                 set_refcounts.push((new_addr, 1));
                 self.l1_table[l1_index] = new_addr;
-                VecCache::new(self.l2_entries as usize)
+                VecCache::new((self.raw_file.cluster_size() / size_of::<u64>() as u64) as usize)
             } else {
-                VecCache::from_vec(Self::read_l2_cluster(&mut self.raw_file, l2_addr_disk)?)
+                VecCache::from_vec(Self::read_l2_cluster(
+                    &mut self.raw_file,
+                    l2_addr_disk,
+                    self.l2_format,
+                )?)
             };
             let l1_table = &self.l1_table;
             let raw_file = &mut self.raw_file;
+            let used_flag = l2_used_flag(&self.snapshots);
+            let l2_format = self.l2_format;
             self.l2_cache.insert(l1_index, l2_table, |index, evicted| {
-                raw_file.write_pointer_table(
+                write_l2_table(
+                    raw_file,
                     l1_table[index],
                     evicted.get_values(),
-                    CLUSTER_USED_FLAG,
+                    used_flag,
+                    l2_format,
                 )
             })?;
         }
 
-        let cluster_addr = match self.l2_cache.get(&l1_index).unwrap()[l2_index] {
+        let (entry, bitmap) = self.cached_l2_entry(l1_index, l2_index);
+        let cluster_entry = match entry {
             0 => {
-                let initial_data = if let Some(backing) = self.backing_file.as_mut() {
+                let initial_data = if bitmap != 0 {
+                    // Some subclusters read as zeros even if there is a backing file.
+                    Some(self.read_guest_cluster(address)?)
+                } else if let Some(backing) = self.backing_file.as_mut() {
                     let cluster_size = self.raw_file.cluster_size();
                     let cluster_begin = address - (address % cluster_size);
                     let mut cluster_data = vec![0u8; cluster_size as usize];
@@ -1106,11 +1544,51 @@ impl QcowFileInner {
                     None
                 };
                 // Need to allocate a data cluster
-                let cluster_addr = self.append_data_cluster(initial_data)?;
-                self.update_cluster_addr(l1_index, l2_index, cluster_addr, &mut set_refcounts)?;
-                cluster_addr
+                let cluster_entry = self.append_data_cluster(address, initial_data)?;
+                self.update_cluster_addr(l1_index, l2_index, cluster_entry, &mut set_refcounts)?;
+                cluster_entry
+            }
+            entry if entry & COMPRESSED_FLAG != 0 => {
+                // Compressed clusters are never written in place. Copy the decompressed data to a
+                // new standard cluster and drop the references to the compressed data.
+                let initial_data = self.decompress_cluster(entry)?.to_vec();
+                self.decompressed_cluster = None;
+                let cluster_entry = self.append_data_cluster(address, Some(initial_data))?;
+                self.update_cluster_addr(l1_index, l2_index, cluster_entry, &mut set_refcounts)?;
+                self.unref_compressed_cluster(entry, &mut set_refcounts)?;
+                cluster_entry
+            }
+            entry => {
+                let a = entry & L2_TABLE_OFFSET_MASK;
+                let refcount = self.data_cluster_refcount(a)?;
+                if refcount > 1 {
+                    // The cluster is shared with a snapshot, copy it before modifying it.
+                    let cluster_data = self.read_guest_cluster(address)?;
+                    let cluster_entry = self.append_data_cluster(address, Some(cluster_data))?;
+                    self.update_cluster_addr(
+                        l1_index,
+                        l2_index,
+                        cluster_entry,
+                        &mut set_refcounts,
+                    )?;
+                    set_refcounts.push((a, refcount - 1));
+                    cluster_entry
+                } else if self.l2_format.extended
+                    && bitmap & SUBCLUSTERS_ALL_ALLOCATED != SUBCLUSTERS_ALL_ALLOCATED
+                {
+                    // Fill in the subclusters that aren't allocated yet, so the whole cluster is
+                    // valid once it is marked as allocated.
+                    let mut cluster_data = self.read_guest_cluster(address)?;
+                    let cluster_begin = address - self.raw_file.cluster_offset(address);
+                    cluster_data.truncate(self.limit_range_file(cluster_begin, cluster_data.len()));
+                    self.data_file_mut()
+                        .write_all_at_volatile(VolatileSlice::new(&mut cluster_data), a)?;
+                    self.update_cluster_addr(l1_index, l2_index, entry, &mut set_refcounts)?;
+                    entry
+                } else {
+                    entry
+                }
             }
-            a => a,
         };
 
         for (addr, count) in set_refcounts {
@@ -1118,10 +1596,11 @@ impl QcowFileInner {
             self.unref_clusters.append(&mut newly_unref);
         }
 
-        Ok(cluster_addr + self.raw_file.cluster_offset(address))
+        Ok((cluster_entry & L2_TABLE_OFFSET_MASK) + self.raw_file.cluster_offset(address))
     }
 
-    // Updates the l1 and l2 tables to point to the new `cluster_addr`.
+    // Updates the l1 and l2 tables to point to the new `cluster_addr`. Extended entries are marked
+    // as fully allocated, or as unallocated if `cluster_addr` is 0.
     fn update_cluster_addr(
         &mut self,
         l1_index: usize,
@@ -1130,14 +1609,20 @@ impl QcowFileInner {
         set_refcounts: &mut Vec<(u64, u16)>,
     ) -> io::Result<()> {
         if !self.l2_cache.get(&l1_index).unwrap().dirty() {
-            // Free the previously used cluster if one exists. Modified tables are always
-            // witten to new clusters so the L1 table can be committed to disk after they
-            // are and L1 never points at an invalid table.
+            // Drop the reference to the previously used cluster if one exists, snapshots may
+            // still use it. Modified tables are always witten to new clusters so the L1 table can
+            // be committed to disk after they are and L1 never points at an invalid table.
             // The index must be valid from when it was insterted.
             let addr = self.l1_table[l1_index];
             if addr != 0 {
-                self.unref_clusters.push(addr);
-                set_refcounts.push((addr, 0));
+                let refcount = self
+                    .refcounts
+                    .get_cluster_refcount(&mut self.raw_file, addr)
+                    .map_err(|_| std::io::Error::from_raw_os_error(EINVAL))?;
+                if refcount <= 1 {
+                    self.unref_clusters.push(addr);
+                }
+                set_refcounts.push((addr, refcount.saturating_sub(1)));
             }
 
             // Allocate a new cluster to store the L2 table and update the L1 table to point
@@ -1149,11 +1634,33 @@ impl QcowFileInner {
             set_refcounts.push((new_addr, 1));
             self.l1_table[l1_index] = new_addr;
         }
+        let index = l2_index * self.l2_format.entry_words();
+        let extended = self.l2_format.extended;
         // 'unwrap' is OK because it was just added.
-        self.l2_cache.get_mut(&l1_index).unwrap()[l2_index] = cluster_addr;
+        let l2_table = self.l2_cache.get_mut(&l1_index).unwrap();
+        l2_table[index] = cluster_addr;
+        if extended {
+            l2_table[index + 1] = if cluster_addr == 0 {
+                0
+            } else {
+                SUBCLUSTERS_ALL_ALLOCATED
+            };
+        }
         Ok(())
     }
 
+    // Returns the refcount of the data cluster at `cluster_addr`. Only snapshots share data
+    // clusters, so without any the lookup is skipped. Clusters in an external data file are never
+    // shared.
+    fn data_cluster_refcount(&mut self, cluster_addr: u64) -> std::io::Result<u16> {
+        if self.snapshots.is_empty() || self.data_file.is_some() {
+            return Ok(1);
+        }
+        self.refcounts
+            .get_cluster_refcount(&mut self.raw_file, cluster_addr)
+            .map_err(|_| std::io::Error::from_raw_os_error(EINVAL))
+    }
+
     // Allocate a new cluster and return its offset within the raw file.
     fn get_new_cluster(&mut self, initial_data: Option<Vec<u8>>) -> std::io::Result<u64> {
         // First use a pre allocated cluster if one is available.
@@ -1178,9 +1685,29 @@ impl QcowFileInner {
         }
     }
 
-    // Allocate and initialize a new data cluster. Returns the offset of the
-    // cluster in to the file on success.
-    fn append_data_cluster(&mut self, initial_data: Option<Vec<u8>>) -> std::io::Result<u64> {
+    // Allocate and initialize a new data cluster for the guest cluster containing `address`.
+    // Returns the L2 entry pointing at the cluster on success.
+    fn append_data_cluster(
+        &mut self,
+        address: u64,
+        initial_data: Option<Vec<u8>>,
+    ) -> std::io::Result<u64> {
+        let cluster_size = self.raw_file.cluster_size();
+        if let Some(data_file) = self.data_file.as_ref() {
+            // Clusters in an external data file are stored at their guest offset. Don't grow the
+            // file past the end of the disk, it is usually a raw image of the disk.
+            let cluster_begin = address - (address % cluster_size);
+            let len = self.limit_range_file(cluster_begin, cluster_size as usize);
+            match initial_data {
+                Some(mut data) => {
+                    data.truncate(len);
+                    data_file.write_all_at_volatile(VolatileSlice::new(&mut data), cluster_begin)?
+                }
+                None => data_file.write_zeroes_all_at(cluster_begin, len)?,
+            }
+            return Ok(cluster_begin | CLUSTER_USED_FLAG);
+        }
+
         let new_addr: u64 = self.get_new_cluster(initial_data)?;
         // The cluster refcount starts at one indicating it is used but doesn't need COW.
         let mut newly_unref = self.set_cluster_refcount(new_addr, 1)?;
@@ -1210,25 +1737,67 @@ impl QcowFileInner {
 
         if !self.l2_cache.contains_key(&l1_index) {
             // Not in the cache.
-            let table =
-                VecCache::from_vec(Self::read_l2_cluster(&mut self.raw_file, l2_addr_disk)?);
+            let table = VecCache::from_vec(Self::read_l2_cluster(
+                &mut self.raw_file,
+                l2_addr_disk,
+                self.l2_format,
+            )?);
             let l1_table = &self.l1_table;
             let raw_file = &mut self.raw_file;
+            let used_flag = l2_used_flag(&self.snapshots);
+            let l2_format = self.l2_format;
             self.l2_cache.insert(l1_index, table, |index, evicted| {
-                raw_file.write_pointer_table(
+                write_l2_table(
+                    raw_file,
                     l1_table[index],
                     evicted.get_values(),
-                    CLUSTER_USED_FLAG,
+                    used_flag,
+                    l2_format,
                 )
             })?;
         }
 
-        let cluster_addr = self.l2_cache.get(&l1_index).unwrap()[l2_index];
-        if cluster_addr == 0 {
+        let (cluster_addr, bitmap) = self.cached_l2_entry(l1_index, l2_index);
+        if cluster_addr == 0 && bitmap == 0 {
             // This cluster is already unallocated; nothing to do.
             return Ok(());
         }
 
+        // Rewrite the L2 entry to remove the cluster mapping.
+        let mut set_refcounts = Vec::new();
+        self.update_cluster_addr(l1_index, l2_index, 0, &mut set_refcounts)?;
+        for (addr, count) in set_refcounts {
+            let mut newly_unref = self.set_cluster_refcount(addr, count)?;
+            self.unref_clusters.append(&mut newly_unref);
+        }
+
+        if cluster_addr == 0 {
+            // Only subclusters that read as zeros were dropped.
+            return Ok(());
+        }
+
+        if cluster_addr & COMPRESSED_FLAG != 0 {
+            // The compressed data may share host clusters with other compressed clusters, so only
+            // drop this cluster's references to them.
+            let mut set_refcounts = Vec::new();
+            self.unref_compressed_cluster(cluster_addr, &mut set_refcounts)?;
+            for (addr, count) in set_refcounts {
+                let mut newly_unref = self.set_cluster_refcount(addr, count)?;
+                self.unref_clusters.append(&mut newly_unref);
+            }
+            self.decompressed_cluster = None;
+            return Ok(());
+        }
+
+        if let Some(data_file) = self.data_file.as_ref() {
+            // Clusters in an external data file aren't refcounted, just release the storage.
+            let _ = data_file.punch_hole(
+                cluster_addr & L2_TABLE_OFFSET_MASK,
+                self.raw_file.cluster_size(),
+            );
+            return Ok(());
+        }
+
         // Decrement the refcount.
         let refcount = self
             .refcounts
@@ -1242,10 +1811,6 @@ impl QcowFileInner {
         let mut newly_unref = self.set_cluster_refcount(cluster_addr, new_refcount)?;
         self.unref_clusters.append(&mut newly_unref);
 
-        // Rewrite the L2 entry to remove the cluster mapping.
-        // unwrap is safe as we just checked/inserted this entry.
-        self.l2_cache.get_mut(&l1_index).unwrap()[l2_index] = 0;
-
         if new_refcount == 0 {
             let cluster_size = self.raw_file.cluster_size();
             // This cluster is no longer in use; deallocate the storage.
@@ -1273,19 +1838,26 @@ impl QcowFileInner {
                 self.deallocate_cluster(curr_addr)?;
             } else {
                 // Partial cluster - zero out the relevant bytes.
-                let offset = if self.backing_file.is_some() {
-                    // There is a backing file, so we need to allocate a cluster in order to
-                    // zero out the hole-punched bytes such that the backing file contents do not
-                    // show through.
-                    Some(self.file_offset_write(curr_addr)?)
-                } else {
+                let (mapping, run) = self.cluster_mapping(curr_addr, count)?;
+                let reads_zeroes = match mapping {
+                    ClusterMapping::Zero => true,
+                    ClusterMapping::Unallocated => self.backing_file.is_none(),
+                    _ => false,
+                };
+                let offset = if reads_zeroes && run == count {
                     // Any space in unallocated clusters can be left alone, since
                     // unallocated clusters already read back as zeroes.
-                    self.file_offset_read(curr_addr)?
+                    None
+                } else {
+                    // If there is a backing file, we need to allocate a cluster in order to
+                    // zero out the hole-punched bytes such that the backing file contents do not
+                    // show through. Compressed clusters and clusters shared with snapshots have to
+                    // be copied before they can be modified.
+                    Some(self.file_offset_write(curr_addr)?)
                 };
                 if let Some(offset) = offset {
                     // Partial cluster - zero it out.
-                    self.raw_file.file().write_zeroes_all_at(offset, count)?;
+                    self.data_file_mut().write_zeroes_all_at(offset, count)?;
                 }
             }
 
@@ -1294,19 +1866,106 @@ impl QcowFileInner {
         Ok(())
     }
 
-    // Reads an L2 cluster from the disk, returning an error if the file can't be read or if any
-    // cluster is compressed.
-    fn read_l2_cluster(raw_file: &mut QcowRawFile, cluster_addr: u64) -> std::io::Result<Vec<u64>> {
+    // Reads an L2 cluster from the disk, returning an error if the file can't be read. Standard
+    // cluster entries are reduced to their offset, plus the used flag for external data files.
+    // Compressed cluster descriptors and subcluster bitmaps are kept whole.
+    fn read_l2_cluster(
+        raw_file: &mut QcowRawFile,
+        cluster_addr: u64,
+        l2_format: L2Format,
+    ) -> std::io::Result<Vec<u64>> {
         let file_values = raw_file.read_pointer_cluster(cluster_addr, None)?;
-        if file_values.iter().any(|entry| entry & COMPRESSED_FLAG != 0) {
-            return Err(std::io::Error::from_raw_os_error(ENOTSUP));
-        }
+        let offset_mask = if l2_format.external_data {
+            L2_TABLE_OFFSET_MASK | CLUSTER_USED_FLAG
+        } else {
+            L2_TABLE_OFFSET_MASK
+        };
         Ok(file_values
             .iter()
-            .map(|entry| *entry & L2_TABLE_OFFSET_MASK)
+            .enumerate()
+            .map(|(i, entry)| {
+                if i % l2_format.entry_words() != 0 {
+                    *entry
+                } else if entry & COMPRESSED_FLAG != 0 {
+                    *entry & !CLUSTER_USED_FLAG
+                } else {
+                    *entry & offset_mask
+                }
+            })
             .collect())
     }
 
+    // Returns the contents of the compressed cluster described by `entry`. The most recently
+    // decompressed cluster is kept around as guests often read a cluster in several requests.
+    fn decompress_cluster(&mut self, entry: u64) -> std::io::Result<&[u8]> {
+        let cached = matches!(&self.decompressed_cluster, Some((e, _)) if *e == entry);
+        if !cached {
+            let (offset, len) = compressed_cluster_range(entry, self.header.cluster_bits);
+            // The last sector of compressed data at the end of the image may be truncated.
+            let file_size = self.raw_file.file().metadata()?.len();
+            let len = min(len, file_size.saturating_sub(offset));
+            let mut compressed = vec![0u8; len as usize];
+            self.raw_file
+                .file()
+                .read_exact_at_volatile(VolatileSlice::new(&mut compressed), offset)?;
+
+            let cluster_size = self.raw_file.cluster_size() as usize;
+            let data = match self.header.compression_type {
+                COMPRESSION_TYPE_ZLIB => {
+                    miniz_oxide::inflate::decompress_to_vec_with_limit(&compressed, cluster_size)
+                        .map_err(|e| {
+                            std::io::Error::new(
+                                std::io::ErrorKind::InvalidData,
+                                format!("failed to inflate compressed cluster: {:?}", e.status),
+                            )
+                        })?
+                }
+                #[cfg(feature = "zstd")]
+                COMPRESSION_TYPE_ZSTD => {
+                    let mut data = vec![0u8; cluster_size];
+                    ::zstd::stream::read::Decoder::with_buffer(&compressed[..])?
+                        .single_frame()
+                        .read_exact(&mut data)?;
+                    data
+                }
+                _ => return Err(std::io::Error::from_raw_os_error(ENOTSUP)),
+            };
+            if data.len() != cluster_size {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "compressed cluster has the wrong size",
+                ));
+            }
+            self.decompressed_cluster = Some((entry, data));
+        }
+        // Unwrap is safe as the cache was filled above.
+        Ok(&self.decompressed_cluster.as_ref().unwrap().1)
+    }
+
+    // Queues a decrement of the refcount of each host cluster holding the compressed data
+    // described by `entry`.
+    fn unref_compressed_cluster(
+        &mut self,
+        entry: u64,
+        set_refcounts: &mut Vec<(u64, u16)>,
+    ) -> std::io::Result<()> {
+        let cluster_size = self.raw_file.cluster_size();
+        for addr in compressed_host_clusters(entry, self.header.cluster_bits, cluster_size) {
+            let refcount = self
+                .refcounts
+                .get_cluster_refcount(&mut self.raw_file, addr)
+                .map_err(|_| std::io::Error::from_raw_os_error(EINVAL))?;
+            if refcount == 0 {
+                return Err(std::io::Error::from_raw_os_error(EINVAL));
+            }
+            set_refcounts.push((addr, refcount - 1));
+            if refcount == 1 {
+                self.unref_clusters.push(addr);
+            }
+        }
+        Ok(())
+    }
+
     // Set the refcount for a cluster with the given address.
     // Returns a list of any refblocks that can be reused, this happens when a refblock is moved,
     // the old location can be reused.
@@ -1370,10 +2029,12 @@ impl QcowFileInner {
             // The index must be valid from when we insterted it.
             let addr = self.l1_table[*l1_index];
             if addr != 0 {
-                self.raw_file.write_pointer_table(
+                write_l2_table(
+                    &mut self.raw_file,
                     addr,
                     l2_table.get_values(),
-                    CLUSTER_USED_FLAG,
+                    l2_used_flag(&self.snapshots),
+                    self.l2_format,
                 )?;
             } else {
                 return Err(std::io::Error::from_raw_os_error(EINVAL));
@@ -1405,27 +2066,38 @@ impl QcowFileInner {
     }
 
     // Reads `count` bytes starting at `address`, calling `cb` repeatedly with the data source,
-    // number of bytes read so far, offset to read from, and number of bytes to read from the file
-    // in that invocation. If None is given to `cb` in place of the backing file, the `cb` should
-    // infer zeros would have been read.
+    // number of bytes read so far, offset to read from, and number of bytes to read from the source
+    // in that invocation.
     fn read_cb<F>(&mut self, address: u64, count: usize, mut cb: F) -> std::io::Result<usize>
     where
-        F: FnMut(Option<&mut dyn DiskFile>, usize, u64, usize) -> std::io::Result<()>,
+        F: FnMut(ReadSource, usize, u64, usize) -> std::io::Result<()>,
     {
         let read_count: usize = self.limit_range_file(address, count);
 
         let mut nread: usize = 0;
         while nread < read_count {
             let curr_addr = address + nread as u64;
-            let file_offset = self.file_offset_read(curr_addr)?;
+            let cluster_offset = self.raw_file.cluster_offset(curr_addr);
             let count = self.limit_range_cluster(curr_addr, read_count - nread);
+            let (mapping, count) = self.cluster_mapping(curr_addr, count)?;
 
-            if let Some(offset) = file_offset {
-                cb(Some(self.raw_file.file_mut()), nread, offset, count)?;
-            } else if let Some(backing) = self.backing_file.as_mut() {
-                cb(Some(backing.as_mut()), nread, curr_addr, count)?;
-            } else {
-                cb(None, nread, 0, count)?;
+            match mapping {
+                ClusterMapping::Compressed(entry) => {
+                    let data = self.decompress_cluster(entry)?;
+                    cb(ReadSource::Buffer(data), nread, cluster_offset, count)?;
+                }
+                ClusterMapping::Data(host_addr) => {
+                    let offset = host_addr + cluster_offset;
+                    cb(ReadSource::Disk(self.data_file_mut()), nread, offset, count)?;
+                }
+                ClusterMapping::Unallocated | ClusterMapping::Zero => {
+                    match self.backing_file.as_mut() {
+                        Some(backing) if mapping == ClusterMapping::Unallocated => {
+                            cb(ReadSource::Disk(backing.as_mut()), nread, curr_addr, count)?;
+                        }
+                        _ => cb(ReadSource::Zeroes, nread, 0, count)?,
+                    }
+                }
             }
 
             nread += count;
@@ -1448,12 +2120,277 @@ impl QcowFileInner {
             let offset = self.file_offset_write(curr_addr)?;
             let count = self.limit_range_cluster(curr_addr, write_count - nwritten);
 
-            cb(self.raw_file.file_mut(), nwritten, offset, count)?;
+            cb(self.data_file_mut(), nwritten, offset, count)?;
 
             nwritten += count;
         }
         Ok(write_count)
     }
+
+    // Finds the snapshot with the ID or name `snapshot`. IDs take precedence, as in qemu.
+    fn find_snapshot(&self, snapshot: &str) -> std::io::Result<usize> {
+        self.snapshots
+            .iter()
+            .position(|s| s.id == snapshot)
+            .or_else(|| self.snapshots.iter().position(|s| s.name == snapshot))
+            .ok_or_else(|| std::io::Error::from_raw_os_error(ENOENT))
+    }
+
+    // Returns an error if the image can't have snapshots. Snapshots share data clusters through
+    // refcounts, which clusters in an external data file don't have.
+    fn check_snapshots_supported(&self) -> std::io::Result<()> {
+        if self.data_file.is_some() {
+            return Err(std::io::Error::from_raw_os_error(ENOTSUP));
+        }
+        Ok(())
+    }
+
+    // Stores the current state of the disk as a new snapshot named `name`.
+    fn create_snapshot(&mut self, name: &str) -> std::io::Result<()> {
+        self.check_snapshots_supported()?;
+        if name.is_empty() || name.len() > u16::MAX as usize {
+            return Err(std::io::Error::from_raw_os_error(EINVAL));
+        }
+        if self.snapshots.iter().any(|s| s.name == name) {
+            return Err(std::io::Error::from_raw_os_error(EEXIST));
+        }
+        if self.snapshots.len() >= MAX_SNAPSHOTS as usize {
+            return Err(std::io::Error::from_raw_os_error(ENOSPC));
+        }
+
+        // The snapshot shares all of the active tables as they are on disk.
+        self.sync_caches()?;
+        let l1_table = self.l1_table.get_values().to_vec();
+        self.update_tree_refcounts(&l1_table, 1)?;
+        // The active L2 tables are now shared too. crosvm never flags L1 entries as unshared, but
+        // other implementations do.
+        self.raw_file
+            .write_pointer_table(self.header.l1_table_offset, &l1_table, 0)?;
+
+        let l1_table_offset =
+            self.allocate_table_clusters((l1_table.len() * size_of::<u64>()) as u64)?;
+        self.raw_file
+            .write_pointer_table(l1_table_offset, &l1_table, 0)?;
+
+        let id = self
+            .snapshots
+            .iter()
+            .filter_map(|s| s.id.parse::<u64>().ok())
+            .max()
+            .unwrap_or(0)
+            + 1;
+        let date = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut snapshots = self.snapshots.clone();
+        snapshots.push(SnapshotEntry::new(
+            id.to_string(),
+            name.to_string(),
+            l1_table_offset,
+            l1_table.len() as u32,
+            self.header.size,
+            date.as_secs() as u32,
+            date.subsec_nanos(),
+        ));
+        self.write_snapshot_table(snapshots)
+    }
+
+    // Reverts the disk to the state stored in the snapshot with the ID or name `snapshot`.
+    fn apply_snapshot(&mut self, snapshot: &str) -> std::io::Result<()> {
+        self.check_snapshots_supported()?;
+        let snapshot = self.snapshots[self.find_snapshot(snapshot)?].clone();
+        if snapshot.disk_size().unwrap_or(self.header.size) != self.header.size {
+            // Resizing qcow files isn't supported.
+            return Err(std::io::Error::from_raw_os_error(ENOTSUP));
+        }
+        let mut l1_table = self.read_snapshot_l1_table(&snapshot)?;
+        let l1_size = self.l1_table.len();
+        if l1_table.iter().skip(l1_size).any(|&addr| addr != 0) {
+            return Err(std::io::Error::from_raw_os_error(EINVAL));
+        }
+        l1_table.resize(l1_size, 0);
+
+        self.sync_caches()?;
+        self.update_tree_refcounts(&l1_table, 1)?;
+        // Make sure the new references are counted on disk before the active L1 table uses them.
+        self.sync_caches()?;
+        self.raw_file
+            .write_pointer_table(self.header.l1_table_offset, &l1_table, 0)?;
+        self.raw_file.file_mut().sync_data()?;
+
+        let old_l1_table = std::mem::replace(&mut self.l1_table, VecCache::from_vec(l1_table));
+        // All cached tables are clean after the sync above.
+        self.l2_cache.clear();
+        self.decompressed_cluster = None;
+        self.update_tree_refcounts(old_l1_table.get_values(), -1)?;
+        self.sync_caches()
+    }
+
+    // Deletes the snapshot with the ID or name `snapshot`.
+    fn delete_snapshot(&mut self, snapshot: &str) -> std::io::Result<()> {
+        self.check_snapshots_supported()?;
+        let mut snapshots = self.snapshots.clone();
+        let snapshot = snapshots.remove(self.find_snapshot(snapshot)?);
+        let l1_table = self.read_snapshot_l1_table(&snapshot)?;
+
+        // Drop the snapshot from the table before releasing its clusters, so the table never
+        // references freed clusters.
+        self.sync_caches()?;
+        self.write_snapshot_table(snapshots)?;
+        self.update_tree_refcounts(&l1_table, -1)?;
+        self.free_table_clusters(
+            snapshot.l1_table_offset,
+            (l1_table.len() * size_of::<u64>()) as u64,
+        )?;
+        self.sync_caches()
+    }
+
+    // Reads the L1 table of `snapshot`.
+    fn read_snapshot_l1_table(&mut self, snapshot: &SnapshotEntry) -> std::io::Result<Vec<u64>> {
+        if u64::from(snapshot.l1_size) > MAX_RAM_POINTER_TABLE_SIZE
+            || self.raw_file.cluster_offset(snapshot.l1_table_offset) != 0
+        {
+            return Err(std::io::Error::from_raw_os_error(EINVAL));
+        }
+        self.raw_file.read_pointer_table(
+            snapshot.l1_table_offset,
+            u64::from(snapshot.l1_size),
+            Some(L1_TABLE_OFFSET_MASK),
+        )
+    }
+
+    // Writes `snapshots` to a new snapshot table, points the header at it and frees the old table.
+    fn write_snapshot_table(&mut self, snapshots: Vec<SnapshotEntry>) -> std::io::Result<()> {
+        let table: Vec<u8> = snapshots.iter().flat_map(|s| s.to_bytes()).collect();
+        let table_offset = if table.is_empty() {
+            0
+        } else {
+            let offset = self.allocate_table_clusters(table.len() as u64)?;
+            let file = self.raw_file.file_mut();
+            file.seek(SeekFrom::Start(offset))?;
+            file.write_all(&table)?;
+            offset
+        };
+        // The table and everything it references must be on disk before the header points at it.
+        self.sync_caches()?;
+        let mut header_fields = Vec::with_capacity(12);
+        header_fields.extend_from_slice(&(snapshots.len() as u32).to_be_bytes());
+        header_fields.extend_from_slice(&table_offset.to_be_bytes());
+        let file = self.raw_file.file_mut();
+        file.seek(SeekFrom::Start(NB_SNAPSHOTS_OFFSET))?;
+        file.write_all(&header_fields)?;
+        file.sync_data()?;
+
+        let old_table_offset = self.header.snapshots_offset;
+        let old_table_size: usize = self.snapshots.iter().map(|s| s.size()).sum();
+        self.header.nb_snapshots = snapshots.len() as u32;
+        self.header.snapshots_offset = table_offset;
+        self.snapshots = snapshots;
+        self.free_table_clusters(old_table_offset, old_table_size as u64)
+    }
+
+    // Adds `addend` to the refcount of every L2 table and data cluster reachable from `l1_table`.
+    // The L2 tables are read from the file, so the caches must have been synced.
+    fn update_tree_refcounts(&mut self, l1_table: &[u64], addend: i32) -> std::io::Result<()> {
+        let cluster_size = self.raw_file.cluster_size();
+        let entry_words = self.l2_format.entry_words();
+        for &l2_addr in l1_table.iter().filter(|&&addr| addr != 0) {
+            let l2_table = self.raw_file.read_pointer_cluster(l2_addr, None)?;
+            for &entry in l2_table.iter().step_by(entry_words) {
+                if entry & COMPRESSED_FLAG != 0 {
+                    for addr in
+                        compressed_host_clusters(entry, self.header.cluster_bits, cluster_size)
+                    {
+                        self.add_cluster_refcount(addr, addend)?;
+                    }
+                } else if entry & L2_TABLE_OFFSET_MASK != 0 {
+                    self.add_cluster_refcount(entry & L2_TABLE_OFFSET_MASK, addend)?;
+                }
+            }
+            if addend > 0
+                && l2_table
+                    .iter()
+                    .step_by(entry_words)
+                    .any(|entry| entry & CLUSTER_USED_FLAG != 0)
+            {
+                // The data clusters are shared now, so they may no longer be written in place.
+                // Subcluster bitmaps are left as they are.
+                let entries: Vec<u64> = l2_table
+                    .iter()
+                    .enumerate()
+                    .map(|(i, entry)| {
+                        if i % entry_words == 0 {
+                            entry & !CLUSTER_USED_FLAG
+                        } else {
+                            *entry
+                        }
+                    })
+                    .collect();
+                self.raw_file.write_pointer_table(l2_addr, &entries, 0)?;
+            }
+            self.add_cluster_refcount(l2_addr, addend)?;
+        }
+        Ok(())
+    }
+
+    // Adds `addend` to the refcount of the cluster at `addr`, releasing the cluster once nothing
+    // references it.
+    fn add_cluster_refcount(&mut self, addr: u64, addend: i32) -> std::io::Result<()> {
+        let refcount = self
+            .refcounts
+            .get_cluster_refcount(&mut self.raw_file, addr)
+            .map_err(|_| std::io::Error::from_raw_os_error(EINVAL))?;
+        let refcount = u16::try_from(i32::from(refcount) + addend)
+            .map_err(|_| std::io::Error::from_raw_os_error(EINVAL))?;
+        let mut newly_unref = self.set_cluster_refcount(addr, refcount)?;
+        self.unref_clusters.append(&mut newly_unref);
+        if refcount == 0 {
+            // The underlying FS may not support FALLOC_FL_PUNCH_HOLE, and nothing reads the
+            // cluster anymore, so don't treat an error as fatal.
+            let _ = self
+                .raw_file
+                .file()
+                .punch_hole(addr, self.raw_file.cluster_size());
+            self.unref_clusters.push(addr);
+        }
+        Ok(())
+    }
+
+    // Allocates contiguous clusters for a table of `len` bytes and returns the address of the
+    // first one.
+    fn allocate_table_clusters(&mut self, len: u64) -> std::io::Result<u64> {
+        let cluster_size = self.raw_file.cluster_size();
+        let count = len.div_ceil(cluster_size);
+        let table_addr = if count == 1 {
+            self.get_new_cluster(None)?
+        } else {
+            // Clusters added to the end of the file one after the other are contiguous.
+            let max_valid_cluster_offset = self.refcounts.max_valid_cluster_offset();
+            let mut table_addr = None;
+            for _ in 0..count {
+                let addr = self
+                    .raw_file
+                    .add_cluster_end(max_valid_cluster_offset)?
+                    .ok_or_else(|| std::io::Error::from_raw_os_error(ENOSPC))?;
+                table_addr.get_or_insert(addr);
+            }
+            table_addr.ok_or_else(|| std::io::Error::from_raw_os_error(EINVAL))?
+        };
+        for i in 0..count {
+            let mut newly_unref = self.set_cluster_refcount(table_addr + i * cluster_size, 1)?;
+            self.unref_clusters.append(&mut newly_unref);
+        }
+        Ok(table_addr)
+    }
+
+    // Drops the references to the clusters of a table of `len` bytes at `addr`.
+    fn free_table_clusters(&mut self, addr: u64, len: u64) -> std::io::Result<()> {
+        let cluster_size = self.raw_file.cluster_size();
+        for i in 0..len.div_ceil(cluster_size) {
+            self.add_cluster_refcount(addr + i * cluster_size, -1)?;
+        }
+        Ok(())
+    }
 }
 
 impl Drop for QcowFile {
@@ -1468,6 +2405,9 @@ impl AsRawDescriptors for QcowFile {
         // sandboxing, so it should be OK.
         let inner = self.inner.lock();
         let mut descriptors = vec![inner.raw_file.file().as_raw_descriptor()];
+        if let Some(data_file) = &inner.data_file {
+            descriptors.push(data_file.as_raw_descriptor());
+        }
         if let Some(backing) = &inner.backing_file {
             descriptors.append(&mut backing.as_raw_descriptors());
         }
@@ -1483,15 +2423,9 @@ impl Read for QcowFile {
         let read_count = inner.read_cb(
             inner.current_offset,
             len,
-            |file, already_read, offset, count| {
+            |source, already_read, offset, count| {
                 let sub_slice = slice.get_slice(already_read, count).unwrap();
-                match file {
-                    Some(f) => f.read_exact_at_volatile(sub_slice, offset),
-                    None => {
-                        sub_slice.write_bytes(0);
-                        Ok(())
-                    }
-                }
+                source.read_exact_at_volatile(sub_slice, offset)
             },
         )?;
         inner.current_offset += read_count as u64;
@@ -1555,15 +2489,9 @@ impl Write for QcowFile {
 impl FileReadWriteAtVolatile for QcowFile {
     fn read_at_volatile(&self, slice: VolatileSlice, offset: u64) -> io::Result<usize> {
         let mut inner = self.inner.lock();
-        inner.read_cb(offset, slice.size(), |file, read, offset, count| {
+        inner.read_cb(offset, slice.size(), |source, read, offset, count| {
             let sub_slice = slice.get_slice(read, count).unwrap();
-            match file {
-                Some(f) => f.read_exact_at_volatile(sub_slice, offset),
-                None => {
-                    sub_slice.write_bytes(0);
-                    Ok(())
-                }
-            }
+            source.read_exact_at_volatile(sub_slice, offset)
         })
     }
 
@@ -1648,6 +2576,84 @@ impl ToAsyncDisk for QcowFile {
     }
 }
 
+// Writes an L2 table to `addr`, adding `used_flag` to standard clusters. Compressed cluster
+// descriptors and subcluster bitmaps are written back unchanged.
+fn write_l2_table(
+    raw_file: &mut QcowRawFile,
+    addr: u64,
+    table: &[u64],
+    used_flag: u64,
+    l2_format: L2Format,
+) -> io::Result<()> {
+    let entries: Vec<u64> = table
+        .iter()
+        .enumerate()
+        .map(|(i, entry)| {
+            if i % l2_format.entry_words() != 0 || *entry == 0 || *entry & COMPRESSED_FLAG != 0 {
+                *entry
+            } else {
+                *entry | used_flag
+            }
+        })
+        .collect();
+    raw_file.write_pointer_table(addr, &entries, 0)
+}
+
+// Returns the flag to add to standard L2 entries. The flag promises other qcow implementations
+// that the cluster isn't shared and can be written in place. Data clusters can only be shared
+// with snapshots, so while there are any the flag is left off rather than checking the refcount
+// of every entry.
+fn l2_used_flag(snapshots: &[SnapshotEntry]) -> u64 {
+    if snapshots.is_empty() {
+        CLUSTER_USED_FLAG
+    } else {
+        0
+    }
+}
+
+// Reads the snapshot table described by `header`.
+fn read_snapshot_table(
+    raw_file: &mut QcowRawFile,
+    header: &QcowHeader,
+) -> Result<Vec<SnapshotEntry>> {
+    if header.nb_snapshots > MAX_SNAPSHOTS {
+        return Err(Error::TooManySnapshots(header.nb_snapshots));
+    }
+    let file = raw_file.file_mut();
+    file.seek(SeekFrom::Start(header.snapshots_offset))
+        .map_err(Error::SeekingFile)?;
+    let mut reader = BufReader::new(file);
+    (0..header.nb_snapshots)
+        .map(|_| SnapshotEntry::read_from(&mut reader))
+        .collect::<io::Result<_>>()
+        .map_err(Error::ReadingSnapshotTable)
+}
+
+// Returns the host offset and length in bytes of the data described by the compressed cluster
+// descriptor `entry`.
+fn compressed_cluster_range(entry: u64, cluster_bits: u32) -> (u64, u64) {
+    // The offset takes the low `62 - (cluster_bits - 8)` bits, the rest up to bit 61 count the
+    // sectors following the one that contains the offset.
+    let offset_bits = 62 - (cluster_bits - 8);
+    let offset = entry & ((1 << offset_bits) - 1);
+    let additional_sectors = (entry & !(COMPRESSED_FLAG | CLUSTER_USED_FLAG)) >> offset_bits;
+    let len = (additional_sectors + 1) * COMPRESSED_SECTOR_SIZE - offset % COMPRESSED_SECTOR_SIZE;
+    (offset, len)
+}
+
+// Returns the addresses of the host clusters that hold the data of the compressed cluster
+// descriptor `entry`.
+fn compressed_host_clusters(
+    entry: u64,
+    cluster_bits: u32,
+    cluster_size: u64,
+) -> impl Iterator<Item = u64> {
+    let (offset, len) = compressed_cluster_range(entry, cluster_bits);
+    let first = offset & !(cluster_size - 1);
+    let last = (offset + len - 1) & !(cluster_size - 1);
+    (first..=last).step_by(cluster_size as usize)
+}
+
 // Returns an Error if the given offset doesn't align to a cluster boundary.
 fn offset_is_cluster_boundary(offset: u64, cluster_bits: u32) -> Result<()> {
     if offset & ((0x01 << cluster_bits) - 1) != 0 {
@@ -2597,4 +3603,547 @@ mod tests {
             }
         });
     }
+
+    // Builds an image with one compressed cluster per entry of `clusters`, laid out the way
+    // `qemu-img convert -c` stores them: each descriptor points at the start of a host cluster.
+    fn compressed_file<F>(clusters: &[Vec<u8>], compress: F) -> File
+    where
+        F: Fn(&[u8]) -> Vec<u8>,
+    {
+        let file = tempfile().expect("failed to create temp file");
+        let mut raw = file.try_clone().unwrap();
+        let mut q = QcowFile::new(file, test_params(), 0x10_0000).unwrap();
+        let inner = q.inner.get_mut();
+        let cluster_size = inner.raw_file.cluster_size();
+        let cluster_bits = inner.header.cluster_bits;
+        let mut host_addrs = Vec::new();
+        for (i, data) in clusters.iter().enumerate() {
+            let addr = i as u64 * cluster_size;
+            inner
+                .write_cb(addr, data.len(), |file, offset, raw_offset, count| {
+                    file.seek(SeekFrom::Start(raw_offset))?;
+                    file.write_all(&data[offset..(offset + count)])
+                })
+                .expect("failed to write cluster");
+            host_addrs.push(inner.l2_entry(addr).unwrap().0);
+        }
+        let l2_addr = inner.l1_table[0];
+        drop(q);
+
+        let offset_bits = 62 - (cluster_bits - 8);
+        for (i, (data, host_addr)) in clusters.iter().zip(host_addrs).enumerate() {
+            let compressed = compress(data);
+            assert!((compressed.len() as u64) < cluster_size);
+            let additional_sectors = (compressed.len() as u64 - 1) / COMPRESSED_SECTOR_SIZE;
+            let entry = COMPRESSED_FLAG | (additional_sectors << offset_bits) | host_addr;
+            raw.seek(SeekFrom::Start(host_addr)).unwrap();
+            raw.write_all(&compressed).unwrap();
+            raw.seek(SeekFrom::Start(l2_addr + i as u64 * 8)).unwrap();
+            raw.write_all(&entry.to_be_bytes()).unwrap();
+        }
+        raw
+    }
+
+    fn deflate(data: &[u8]) -> Vec<u8> {
+        miniz_oxide::deflate::compress_to_vec(data, 6)
+    }
+
+    fn test_clusters() -> Vec<Vec<u8>> {
+        (0..3u8)
+            .map(|c| (0..0x1_0000).map(|i| (i % 251) as u8 ^ c).collect())
+            .collect()
+    }
+
+    #[test]
+    fn read_compressed_clusters() {
+        let clusters = test_clusters();
+        let disk_file = compressed_file(&clusters, deflate);
+        let mut q = QcowFile::from(disk_file, test_params()).unwrap();
+        for (i, data) in clusters.iter().enumerate() {
+            let mut buf = vec![0u8; data.len()];
+            read_exact_at(&mut q, &mut buf, i as u64 * 0x1_0000).expect("Failed to read.");
+            assert_eq!(&buf, data);
+        }
+        // Unaligned read spanning two compressed clusters.
+        let mut buf = [0u8; 16];
+        read_exact_at(&mut q, &mut buf, 0x1_0000 - 8).expect("Failed to read.");
+        assert_eq!(&buf[..8], &clusters[0][0x1_0000 - 8..]);
+        assert_eq!(&buf[8..], &clusters[1][..8]);
+        // Clusters past the compressed ones are still unallocated.
+        read_exact_at(&mut q, &mut buf, 0x3_0000).expect("Failed to read.");
+        assert_eq!(buf, [0u8; 16]);
+    }
+
+    #[test]
+    fn write_compressed_cluster() {
+        let clusters = test_clusters();
+        let disk_file = compressed_file(&clusters, deflate);
+        let mut q = QcowFile::from(disk_file, test_params()).unwrap();
+        let (compressed_entry, _) = q.inner.get_mut().l2_entry(0x1_0000).unwrap();
+        assert_ne!(compressed_entry & COMPRESSED_FLAG, 0);
+
+        write_all_at(&mut q, b"TEST", 0x1_0010).expect("Failed to write.");
+        let mut buf = vec![0u8; 0x1_0000];
+        read_exact_at(&mut q, &mut buf, 0x1_0000).expect("Failed to read.");
+        assert_eq!(&buf[..0x10], &clusters[1][..0x10]);
+        assert_eq!(&buf[0x10..0x14], b"TEST");
+        assert_eq!(&buf[0x14..], &clusters[1][0x14..]);
+
+        // The cluster was copied to a standard cluster and the compressed data released.
+        let inner = q.inner.get_mut();
+        assert_eq!(inner.l2_entry(0x1_0000).unwrap().0 & COMPRESSED_FLAG, 0);
+        let (host_offset, _) =
+            compressed_cluster_range(compressed_entry, inner.header.cluster_bits);
+        assert_eq!(
+            inner
+                .refcounts
+                .get_cluster_refcount(&mut inner.raw_file, host_offset)
+                .unwrap(),
+            0
+        );
+
+        // The neighbouring compressed clusters are unaffected.
+        read_exact_at(&mut q, &mut buf, 0).expect("Failed to read.");
+        assert_eq!(buf, clusters[0]);
+        read_exact_at(&mut q, &mut buf, 0x2_0000).expect("Failed to read.");
+        assert_eq!(buf, clusters[2]);
+    }
+
+    #[test]
+    fn punch_hole_compressed_cluster() {
+        let clusters = test_clusters();
+        let disk_file = compressed_file(&clusters, deflate);
+        let mut q = QcowFile::from(disk_file, test_params()).unwrap();
+        // A whole cluster is deallocated, a partial one is copied and then zeroed.
+        q.punch_hole(0, 0x1_0000).expect("Failed to punch hole.");
+        q.punch_hole(0x1_0000, 0x100)
+            .expect("Failed to punch hole.");
+        let mut buf = vec![0u8; 0x1_0000];
+        read_exact_at(&mut q, &mut buf, 0).expect("Failed to read.");
+        assert!(buf.iter().all(|b| *b == 0));
+        read_exact_at(&mut q, &mut buf, 0x1_0000).expect("Failed to read.");
+        assert!(buf[..0x100].iter().all(|b| *b == 0));
+        assert_eq!(&buf[0x100..], &clusters[1][0x100..]);
+    }
+
+    #[test]
+    fn rebuild_refcounts_compressed() {
+        let clusters = test_clusters();
+        let mut disk_file = compressed_file(&clusters, deflate);
+        let header = QcowHeader::new(&mut disk_file).expect("Failed to create Header.");
+        let mut raw_file =
+            QcowRawFile::from(disk_file, 0x1_0000).expect("Failed to create QcowRawFile.");
+        QcowFileInner::rebuild_refcounts(&mut raw_file, header)
+            .expect("Failed to rebuild recounts.");
+        let mut q = QcowFile::from(raw_file.file().try_clone().unwrap(), test_params()).unwrap();
+        let mut buf = vec![0u8; 0x1_0000];
+        read_exact_at(&mut q, &mut buf, 0x2_0000).expect("Failed to read.");
+        assert_eq!(buf, clusters[2]);
+    }
+
+    #[test]
+    fn unsupported_compression_type() {
+        let mut disk_file = compressed_file(&test_clusters(), deflate);
+        let mut header = QcowHeader::new(&mut disk_file).expect("Failed to create Header.");
+        header.header_size = 112;
+        header.compression_type = 2;
+        header.incompatible_features |= INCOMPATIBLE_FEATURES_COMPRESSION_TYPE;
+        disk_file.rewind().unwrap();
+        header.write_to(&mut disk_file).unwrap();
+        assert!(matches!(
+            QcowFile::from(disk_file, test_params()),
+            Err(Error::UnsupportedCompressionType(2))
+        ));
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn read_zstd_compressed_clusters() {
+        let clusters = test_clusters();
+        let mut disk_file =
+            compressed_file(&clusters, |data| ::zstd::bulk::compress(data, 0).unwrap());
+        let mut header = QcowHeader::new(&mut disk_file).expect("Failed to create Header.");
+        header.header_size = 112;
+        header.compression_type = COMPRESSION_TYPE_ZSTD;
+        header.incompatible_features |= INCOMPATIBLE_FEATURES_COMPRESSION_TYPE;
+        disk_file.rewind().unwrap();
+        header.write_to(&mut disk_file).unwrap();
+
+        let mut q = QcowFile::from(disk_file, test_params()).unwrap();
+        for (i, data) in clusters.iter().enumerate() {
+            let mut buf = vec![0u8; data.len()];
+            read_exact_at(&mut q, &mut buf, i as u64 * 0x1_0000).expect("Failed to read.");
+            assert_eq!(&buf, data);
+        }
+    }
+
+    // Returns the refcount of the host cluster that holds the guest cluster at `address`.
+    fn data_refcount(q: &mut QcowFile, address: u64) -> u16 {
+        let inner = q.inner.get_mut();
+        let (entry, _) = inner.l2_entry(address).unwrap();
+        inner
+            .refcounts
+            .get_cluster_refcount(&mut inner.raw_file, entry)
+            .unwrap()
+    }
+
+    #[test]
+    fn snapshot_create_apply_delete() {
+        with_default_file(0x10_0000, |mut q| {
+            write_all_at(&mut q, &[0xaa; 0x1000], 0x1_0000).expect("Failed to write.");
+            q.create_internal_snapshot("first").unwrap();
+            assert_eq!(data_refcount(&mut q, 0x1_0000), 2);
+
+            // Writes after the snapshot go to a copy of the cluster.
+            write_all_at(&mut q, &[0xbb; 0x100], 0x1_0000).expect("Failed to write.");
+            assert_eq!(data_refcount(&mut q, 0x1_0000), 1);
+            let mut buf = [0u8; 0x200];
+            read_exact_at(&mut q, &mut buf, 0x1_0000).expect("Failed to read.");
+            assert!(buf[..0x100].iter().all(|b| *b == 0xbb));
+            assert!(buf[0x100..].iter().all(|b| *b == 0xaa));
+
+            let snapshots = q.internal_snapshots().unwrap();
+            assert_eq!(snapshots.len(), 1);
+            assert_eq!(snapshots[0].id, "1");
+            assert_eq!(snapshots[0].name, "first");
+            assert_eq!(snapshots[0].disk_size, 0x10_0000);
+
+            q.apply_internal_snapshot("first").unwrap();
+            read_exact_at(&mut q, &mut buf, 0x1_0000).expect("Failed to read.");
+            assert!(buf.iter().all(|b| *b == 0xaa));
+            assert_eq!(data_refcount(&mut q, 0x1_0000), 2);
+
+            q.delete_internal_snapshot("1").unwrap();
+            assert!(q.internal_snapshots().unwrap().is_empty());
+            read_exact_at(&mut q, &mut buf, 0x1_0000).expect("Failed to read.");
+            assert!(buf.iter().all(|b| *b == 0xaa));
+            assert_eq!(data_refcount(&mut q, 0x1_0000), 1);
+        });
+    }
+
+    #[test]
+    fn snapshot_punch_hole_keeps_snapshot_data() {
+        with_default_file(0x10_0000, |mut q| {
+            write_all_at(&mut q, &[0xaa; 0x1_0000], 0).expect("Failed to write.");
+            q.create_internal_snapshot("before").unwrap();
+            q.punch_hole(0, 0x1_0000).expect("Failed to punch hole.");
+            let mut buf = [0u8; 0x100];
+            read_exact_at(&mut q, &mut buf, 0).expect("Failed to read.");
+            assert!(buf.iter().all(|b| *b == 0));
+
+            q.apply_internal_snapshot("before").unwrap();
+            read_exact_at(&mut q, &mut buf, 0).expect("Failed to read.");
+            assert!(buf.iter().all(|b| *b == 0xaa));
+        });
+    }
+
+    #[test]
+    fn snapshot_persists_across_open() {
+        let file = tempfile().expect("failed to create temp file");
+        {
+            let mut q = QcowFile::new(file.try_clone().unwrap(), test_params(), 0x10_0000)
+                .expect("Failed to create qcow file.");
+            write_all_at(&mut q, &[0x11; 0x100], 0x2_0000).expect("Failed to write.");
+            q.create_internal_snapshot("a").unwrap();
+            write_all_at(&mut q, &[0x22; 0x100], 0x2_0000).expect("Failed to write.");
+            q.create_internal_snapshot("b").unwrap();
+            write_all_at(&mut q, &[0x33; 0x100], 0x2_0000).expect("Failed to write.");
+        }
+
+        let mut q = QcowFile::from(file, test_params()).expect("Failed to open qcow file.");
+        let names: Vec<String> = q
+            .internal_snapshots()
+            .unwrap()
+            .into_iter()
+            .map(|s| s.name)
+            .collect();
+        assert_eq!(names, ["a", "b"]);
+
+        let mut buf = [0u8; 0x100];
+        read_exact_at(&mut q, &mut buf, 0x2_0000).expect("Failed to read.");
+        assert!(buf.iter().all(|b| *b == 0x33));
+        q.apply_internal_snapshot("a").unwrap();
+        read_exact_at(&mut q, &mut buf, 0x2_0000).expect("Failed to read.");
+        assert!(buf.iter().all(|b| *b == 0x11));
+        q.delete_internal_snapshot("a").unwrap();
+        q.apply_internal_snapshot("b").unwrap();
+        read_exact_at(&mut q, &mut buf, 0x2_0000).expect("Failed to read.");
+        assert!(buf.iter().all(|b| *b == 0x22));
+    }
+
+    #[test]
+    fn snapshot_errors() {
+        with_default_file(0x10_0000, |q| {
+            q.create_internal_snapshot("a").unwrap();
+            assert_eq!(
+                q.create_internal_snapshot("a").unwrap_err().raw_os_error(),
+                Some(EEXIST)
+            );
+            assert_eq!(
+                q.apply_internal_snapshot("missing")
+                    .unwrap_err()
+                    .raw_os_error(),
+                Some(ENOENT)
+            );
+            assert_eq!(
+                q.delete_internal_snapshot("2").unwrap_err().raw_os_error(),
+                Some(ENOENT)
+            );
+            assert_eq!(
+                q.create_internal_snapshot("").unwrap_err().raw_os_error(),
+                Some(EINVAL)
+            );
+        });
+    }
+
+    #[test]
+    fn rebuild_refcounts_with_snapshot() {
+        let file = tempfile().expect("failed to create temp file");
+        {
+            let mut q = QcowFile::new(file.try_clone().unwrap(), test_params(), 0x10_0000)
+                .expect("Failed to create qcow file.");
+            write_all_at(&mut q, &[0x11; 0x100], 0).expect("Failed to write.");
+            q.create_internal_snapshot("a").unwrap();
+            write_all_at(&mut q, &[0x22; 0x100], 0x1_0000).expect("Failed to write.");
+        }
+
+        let mut disk_file = file.try_clone().unwrap();
+        let header = QcowHeader::new(&mut disk_file).expect("Failed to create Header.");
+        let mut raw_file =
+            QcowRawFile::from(disk_file, 0x1_0000).expect("Failed to create QcowRawFile.");
+        QcowFileInner::rebuild_refcounts(&mut raw_file, header)
+            .expect("Failed to rebuild recounts.");
+
+        let mut q = QcowFile::from(file, test_params()).expect("Failed to open qcow file.");
+        assert_eq!(data_refcount(&mut q, 0), 2);
+        assert_eq!(data_refcount(&mut q, 0x1_0000), 1);
+        q.apply_internal_snapshot("a").unwrap();
+        let mut buf = [0u8; 0x100];
+        read_exact_at(&mut q, &mut buf, 0).expect("Failed to read.");
+        assert!(buf.iter().all(|b| *b == 0x11));
+        read_exact_at(&mut q, &mut buf, 0x1_0000).expect("Failed to read.");
+        assert!(buf.iter().all(|b| *b == 0));
+    }
+
+    // Rewrites the header of `disk_file` after `update` has modified it.
+    fn update_header<F: FnOnce(&mut QcowHeader)>(disk_file: &mut File, update: F) {
+        let mut header = QcowHeader::new(disk_file).expect("Failed to create Header.");
+        update(&mut header);
+        disk_file.rewind().unwrap();
+        header.write_to(disk_file).unwrap();
+    }
+
+    // Creates an empty image that uses extended L2 entries.
+    fn extended_l2_file() -> File {
+        let file = tempfile().expect("failed to create temp file");
+        QcowFile::new(file.try_clone().unwrap(), test_params(), 0x10_0000)
+            .expect("Failed to create qcow file.");
+        let mut disk_file = file.try_clone().unwrap();
+        update_header(&mut disk_file, |header| {
+            header.incompatible_features |= INCOMPATIBLE_FEATURES_EXTENDED_L2;
+        });
+        file
+    }
+
+    #[test]
+    fn header_with_data_file() {
+        let mut header = QcowHeader::create_for_size_and_path(0x10_0000, None).unwrap();
+        header.data_file_path = Some("disk.raw".to_string());
+        let mut disk_file = tempfile().expect("failed to create temp file");
+        header.write_to(&mut disk_file).unwrap();
+        disk_file.rewind().unwrap();
+        let read_header = QcowHeader::new(&mut disk_file).expect("Failed to create header.");
+        assert_eq!(read_header.data_file_path, header.data_file_path);
+    }
+
+    #[test]
+    fn unsupported_features() {
+        let file = tempfile().expect("failed to create temp file");
+        QcowFile::new(file.try_clone().unwrap(), test_params(), 0x10_0000).unwrap();
+        let mut disk_file = file.try_clone().unwrap();
+        // The corrupt bit.
+        update_header(&mut disk_file, |header| {
+            header.incompatible_features |= 1 << 1
+        });
+        assert!(matches!(
+            QcowFile::from(file, test_params()),
+            Err(Error::UnsupportedFeatures(2))
+        ));
+    }
+
+    #[test]
+    fn extended_l2_write_read() {
+        let file = extended_l2_file();
+        {
+            let mut q = QcowFile::from(file.try_clone().unwrap(), test_params()).unwrap();
+            write_all_at(&mut q, b"first", 0x1_0100).expect("Failed to write.");
+            write_all_at(&mut q, b"last", 0x10_0000 - 4).expect("Failed to write.");
+            let (_, bitmap) = q.inner.get_mut().l2_entry(0x1_0000).unwrap();
+            assert_eq!(bitmap, SUBCLUSTERS_ALL_ALLOCATED);
+        }
+
+        let mut disk_file = file.try_clone().unwrap();
+        let header = QcowHeader::new(&mut disk_file).expect("Failed to create Header.");
+        let mut raw_file =
+            QcowRawFile::from(disk_file, 0x1_0000).expect("Failed to create QcowRawFile.");
+        QcowFileInner::rebuild_refcounts(&mut raw_file, header)
+            .expect("Failed to rebuild recounts.");
+
+        let mut q = QcowFile::from(file, test_params()).unwrap();
+        let mut buf = [0u8; 5];
+        read_exact_at(&mut q, &mut buf, 0x1_0100).expect("Failed to read.");
+        assert_eq!(&buf, b"first");
+        read_exact_at(&mut q, &mut buf[..4], 0x10_0000 - 4).expect("Failed to read.");
+        assert_eq!(&buf[..4], b"last");
+        read_exact_at(&mut q, &mut buf, 0x2_0000).expect("Failed to read.");
+        assert_eq!(buf, [0u8; 5]);
+    }
+
+    #[test]
+    fn extended_l2_subclusters() {
+        const SUBCLUSTER_SIZE: usize = 0x800;
+        let file = extended_l2_file();
+        let l2_addr = {
+            let mut q = QcowFile::from(file.try_clone().unwrap(), test_params()).unwrap();
+            write_all_at(&mut q, &[0xaa; 0x1_0000], 0).expect("Failed to write.");
+            q.inner.get_mut().l1_table[0]
+        };
+        // Only the first subcluster is allocated, the second reads as zeros and the rest from the
+        // backing file.
+        let bitmap: u64 = 1 | 1 << (SUBCLUSTERS_PER_CLUSTER + 1);
+        let mut raw = file.try_clone().unwrap();
+        raw.seek(SeekFrom::Start(l2_addr + 8)).unwrap();
+        raw.write_all(&bitmap.to_be_bytes()).unwrap();
+
+        let mut backing = tempfile().expect("failed to create temp file");
+        backing.write_all(&[0xbb; 0x1_0000]).unwrap();
+        backing.set_len(0x10_0000).unwrap();
+        let mut q = QcowFile::from(file, test_params()).unwrap();
+        q.set_backing_file(Some(Box::new(backing)));
+
+        let mut expected = vec![0xbb; 0x1_0000];
+        expected[..SUBCLUSTER_SIZE].fill(0xaa);
+        expected[SUBCLUSTER_SIZE..2 * SUBCLUSTER_SIZE].fill(0);
+        let mut buf = vec![0u8; 0x1_0000];
+        read_exact_at(&mut q, &mut buf, 0).expect("Failed to read.");
+        assert_eq!(buf, expected);
+
+        // Writing to an unallocated subcluster allocates the whole cluster without changing what
+        // the other subclusters read.
+        write_all_at(&mut q, b"TEST", 0x3000).expect("Failed to write.");
+        expected[0x3000..0x3004].copy_from_slice(b"TEST");
+        read_exact_at(&mut q, &mut buf, 0).expect("Failed to read.");
+        assert_eq!(buf, expected);
+        let (_, bitmap) = q.inner.get_mut().l2_entry(0).unwrap();
+        assert_eq!(bitmap, SUBCLUSTERS_ALL_ALLOCATED);
+    }
+
+    #[test]
+    fn data_file_write_read() {
+        let tmp_dir = TempDir::new().unwrap();
+        let image_path = tmp_dir.path().join("disk.qcow2");
+        let data_path = tmp_dir.path().join("disk.raw");
+        File::create(&data_path).unwrap();
+        let params = || DiskFileParams {
+            path: image_path.clone(),
+            ..test_params()
+        };
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&image_path)
+            .unwrap();
+        QcowFile::new(file.try_clone().unwrap(), params(), 0x10_0000).unwrap();
+        let mut disk_file = file.try_clone().unwrap();
+        update_header(&mut disk_file, |header| {
+            header.incompatible_features |= INCOMPATIBLE_FEATURES_DATA_FILE;
+            header.data_file_path = Some("disk.raw".to_string());
+        });
+
+        {
+            let mut q = QcowFile::from(file.try_clone().unwrap(), params()).unwrap();
+            write_all_at(&mut q, &[0x55; 0x1000], 0).expect("Failed to write.");
+            write_all_at(&mut q, b"data", 0x2_0010).expect("Failed to write.");
+            assert_eq!(
+                q.create_internal_snapshot("a").unwrap_err().raw_os_error(),
+                Some(ENOTSUP)
+            );
+        }
+
+        // The data is stored at the guest offsets in the data file, not in the image.
+        let data = std::fs::read(&data_path).unwrap();
+        assert!(data[..0x1000].iter().all(|b| *b == 0x55));
+        assert!(data[0x1000..0x2_0010].iter().all(|b| *b == 0));
+        assert_eq!(&data[0x2_0010..0x2_0014], b"data");
+        let image = std::fs::read(&image_path).unwrap();
+        assert!(!image.windows(4).any(|w| w == b"data"));
+
+        let mut q = QcowFile::from(file, params()).unwrap();
+        let mut buf = [0u8; 4];
+        read_exact_at(&mut q, &mut buf, 0xffc).expect("Failed to read.");
+        assert_eq!(buf, [0x55; 4]);
+        read_exact_at(&mut q, &mut buf, 0x2_0010).expect("Failed to read.");
+        assert_eq!(&buf, b"data");
+
+        q.punch_hole(0, 0x1_0000).expect("Failed to punch hole.");
+        read_exact_at(&mut q, &mut buf, 0).expect("Failed to read.");
+        assert_eq!(buf, [0u8; 4]);
+        assert_eq!(q.inner.get_mut().l2_entry(0).unwrap().0, 0);
+    }
+
+    #[test]
+    fn overlay_over_raw_image() {
+        let tmp_dir = TempDir::new().unwrap();
+        let image_path = tmp_dir.path().join("disk.img");
+        let overlay_path = tmp_dir.path().join("overlay.qcow2");
+        std::fs::write(&image_path, vec![0x11u8; 0x2_0000]).unwrap();
+        let params = || DiskFileParams {
+            path: image_path.clone(),
+            ..test_params()
+        };
+
+        {
+            let disk = crate::open_disk_file_with_overlay(params(), &overlay_path).unwrap();
+            assert_eq!(disk.get_len().unwrap(), 0x2_0000);
+            disk.write_all_at_volatile(VolatileSlice::new(&mut [0x22u8; 0x1000]), 0x1000)
+                .unwrap();
+        }
+        // The image is unchanged and the overlay keeps the writes when it's opened again.
+        assert!(std::fs::read(&image_path)
+            .unwrap()
+            .iter()
+            .all(|b| *b == 0x11));
+        let disk = crate::open_disk_file_with_overlay(params(), &overlay_path).unwrap();
+        let mut buf = [0u8; 0x2000];
+        disk.read_exact_at_volatile(VolatileSlice::new(&mut buf), 0)
+            .unwrap();
+        assert!(buf[..0x1000].iter().all(|b| *b == 0x11));
+        assert!(buf[0x1000..].iter().all(|b| *b == 0x22));
+    }
+
+    #[test]
+    fn temporary_overlay() {
+        let tmp_dir = TempDir::new().unwrap();
+        let image_path = tmp_dir.path().join("disk.img");
+        std::fs::write(&image_path, vec![0x11u8; 0x2_0000]).unwrap();
+        let params = DiskFileParams {
+            path: image_path.clone(),
+            ..test_params()
+        };
+
+        let disk =
+            crate::open_disk_file_with_temporary_overlay(params, tempfile().unwrap()).unwrap();
+        disk.write_all_at_volatile(VolatileSlice::new(&mut [0x22u8; 4]), 0x10)
+            .unwrap();
+        let mut buf = [0u8; 8];
+        disk.read_exact_at_volatile(VolatileSlice::new(&mut buf), 0xc)
+            .unwrap();
+        assert_eq!(buf, [0x11, 0x11, 0x11, 0x11, 0x22, 0x22, 0x22, 0x22]);
+        drop(disk);
+        assert!(std::fs::read(&image_path)
+            .unwrap()
+            .iter()
+            .all(|b| *b == 0x11));
+    }
 }
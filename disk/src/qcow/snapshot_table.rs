@@ -0,0 +1,191 @@
+// Copyright 2025 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::io;
+use std::io::Read;
+
+use crate::InternalSnapshot;
+
+// Limits taken from qemu, they keep a corrupt table from causing huge allocations.
+pub const MAX_SNAPSHOTS: u32 = 65536;
+const MAX_EXTRA_DATA_SIZE: u32 = 1024;
+
+// Size of the fixed fields at the start of every entry.
+const ENTRY_HEADER_SIZE: usize = 40;
+// Version 3 images require the extra data to hold at least the 64 bit VM state size and the disk
+// size.
+const V3_EXTRA_DATA_SIZE: usize = 16;
+
+/// An entry of the snapshot table of a qcow file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SnapshotEntry {
+    pub l1_table_offset: u64,
+    pub l1_size: u32,
+    pub id: String,
+    pub name: String,
+    pub date_sec: u32,
+    pub date_nsec: u32,
+    pub vm_clock_nsec: u64,
+    pub vm_state_size: u32,
+    // Kept as read so fields crosvm doesn't know about survive rewriting the table.
+    pub extra_data: Vec<u8>,
+}
+
+impl SnapshotEntry {
+    /// Creates an entry for a snapshot of a disk of `disk_size` bytes without VM state.
+    pub fn new(
+        id: String,
+        name: String,
+        l1_table_offset: u64,
+        l1_size: u32,
+        disk_size: u64,
+        date_sec: u32,
+        date_nsec: u32,
+    ) -> SnapshotEntry {
+        let mut extra_data = vec![0u8; V3_EXTRA_DATA_SIZE];
+        extra_data[8..16].copy_from_slice(&disk_size.to_be_bytes());
+        SnapshotEntry {
+            l1_table_offset,
+            l1_size,
+            id,
+            name,
+            date_sec,
+            date_nsec,
+            vm_clock_nsec: 0,
+            vm_state_size: 0,
+            extra_data,
+        }
+    }
+
+    /// Reads the next entry from `r`, including the padding that follows it.
+    pub fn read_from<R: Read>(r: &mut R) -> io::Result<SnapshotEntry> {
+        fn read_bytes<R: Read>(r: &mut R, len: usize) -> io::Result<Vec<u8>> {
+            let mut bytes = vec![0u8; len];
+            r.read_exact(&mut bytes)?;
+            Ok(bytes)
+        }
+
+        fn read_string<R: Read>(r: &mut R, len: usize) -> io::Result<String> {
+            String::from_utf8(read_bytes(r, len)?)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        }
+
+        let mut header = [0u8; ENTRY_HEADER_SIZE];
+        r.read_exact(&mut header)?;
+        let u16_at = |o: usize| u16::from_be_bytes(header[o..o + 2].try_into().unwrap());
+        let u32_at = |o: usize| u32::from_be_bytes(header[o..o + 4].try_into().unwrap());
+        let u64_at = |o: usize| u64::from_be_bytes(header[o..o + 8].try_into().unwrap());
+
+        let id_size = u16_at(12) as usize;
+        let name_size = u16_at(14) as usize;
+        let extra_data_size = u32_at(36);
+        if extra_data_size > MAX_EXTRA_DATA_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("snapshot extra data too large: {}", extra_data_size),
+            ));
+        }
+
+        let entry = SnapshotEntry {
+            l1_table_offset: u64_at(0),
+            l1_size: u32_at(8),
+            date_sec: u32_at(16),
+            date_nsec: u32_at(20),
+            vm_clock_nsec: u64_at(24),
+            vm_state_size: u32_at(32),
+            extra_data: read_bytes(r, extra_data_size as usize)?,
+            id: read_string(r, id_size)?,
+            name: read_string(r, name_size)?,
+        };
+        read_bytes(r, entry.size() - entry.unpadded_size())?;
+        Ok(entry)
+    }
+
+    /// Returns the entry as it is stored in the file, padded to a multiple of eight bytes.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.size());
+        bytes.extend_from_slice(&self.l1_table_offset.to_be_bytes());
+        bytes.extend_from_slice(&self.l1_size.to_be_bytes());
+        bytes.extend_from_slice(&(self.id.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&(self.name.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&self.date_sec.to_be_bytes());
+        bytes.extend_from_slice(&self.date_nsec.to_be_bytes());
+        bytes.extend_from_slice(&self.vm_clock_nsec.to_be_bytes());
+        bytes.extend_from_slice(&self.vm_state_size.to_be_bytes());
+        bytes.extend_from_slice(&(self.extra_data.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&self.extra_data);
+        bytes.extend_from_slice(self.id.as_bytes());
+        bytes.extend_from_slice(self.name.as_bytes());
+        bytes.resize(self.size(), 0);
+        bytes
+    }
+
+    /// Returns the number of bytes the entry takes up in the table.
+    pub fn size(&self) -> usize {
+        self.unpadded_size().next_multiple_of(8)
+    }
+
+    fn unpadded_size(&self) -> usize {
+        ENTRY_HEADER_SIZE + self.extra_data.len() + self.id.len() + self.name.len()
+    }
+
+    /// Returns the virtual size of the disk when the snapshot was taken, if it was recorded.
+    pub fn disk_size(&self) -> Option<u64> {
+        self.extra_data
+            .get(8..16)
+            .map(|size| u64::from_be_bytes(size.try_into().unwrap()))
+    }
+
+    /// Returns a description of the snapshot for users of the disk. `default_disk_size` is used
+    /// for old entries that don't record the disk size.
+    pub fn info(&self, default_disk_size: u64) -> InternalSnapshot {
+        InternalSnapshot {
+            id: self.id.clone(),
+            name: self.name.clone(),
+            disk_size: self.disk_size().unwrap_or(default_disk_size),
+            date_sec: self.date_sec,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entry_round_trip() {
+        let entry = SnapshotEntry::new(
+            "1".to_string(),
+            "base".to_string(),
+            0x30000,
+            2,
+            0x10_0000,
+            1000,
+            5,
+        );
+        let bytes = entry.to_bytes();
+        // 40 byte header, 16 bytes of extra data, 5 bytes of strings and 3 bytes of padding.
+        assert_eq!(bytes.len(), 64);
+        assert_eq!(entry.size(), 64);
+        let read = SnapshotEntry::read_from(&mut &bytes[..]).unwrap();
+        assert_eq!(read, entry);
+        assert_eq!(read.disk_size(), Some(0x10_0000));
+    }
+
+    #[test]
+    fn entry_without_disk_size() {
+        let mut entry = SnapshotEntry::new("7".to_string(), "old".to_string(), 0x30000, 2, 1, 0, 0);
+        entry.extra_data.clear();
+        let read = SnapshotEntry::read_from(&mut &entry.to_bytes()[..]).unwrap();
+        assert_eq!(read.disk_size(), None);
+        assert_eq!(read.info(4096).disk_size, 4096);
+    }
+
+    #[test]
+    fn entry_extra_data_too_large() {
+        let mut entry = SnapshotEntry::new("1".to_string(), "a".to_string(), 0, 0, 0, 0, 0);
+        entry.extra_data = vec![0u8; MAX_EXTRA_DATA_SIZE as usize + 8];
+        assert!(SnapshotEntry::read_from(&mut &entry.to_bytes()[..]).is_err());
+    }
+}
@@ -176,6 +176,30 @@ mod tests {
         assert_eq!(image_type, ImageType::CompositeDisk);
     }
 
+    #[test]
+    #[cfg(feature = "vhdx")]
+    fn detect_image_type_vhdx() {
+        let mut t = tempfile::tempfile().unwrap();
+        // Write the VHDX file identifier signature. The rest of the file is not filled in, so if
+        // detect_image_type is ever updated to validate more of the header, this test would need
+        // to be updated.
+        t.write_all(b"vhdxfile").unwrap();
+        let image_type = detect_image_type(&t, false).expect("failed to detect image type");
+        assert_eq!(image_type, ImageType::Vhdx);
+    }
+
+    #[test]
+    #[cfg(feature = "vmdk")]
+    fn detect_image_type_vmdk() {
+        let mut t = tempfile::tempfile().unwrap();
+        // Write the VMDK sparse extent magic signature. The rest of the header is not filled in, so
+        // if detect_image_type is ever updated to validate more of the header, this test would need
+        // to be updated.
+        t.write_all(b"KDMV").unwrap();
+        let image_type = detect_image_type(&t, false).expect("failed to detect image type");
+        assert_eq!(image_type, ImageType::Vmdk);
+    }
+
     #[test]
     fn detect_image_type_small_file() {
         let mut t = tempfile::tempfile().unwrap();
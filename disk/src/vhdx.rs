@@ -0,0 +1,687 @@
+// Copyright 2025 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Read-only access to VHDX disk images.
+//!
+//! Only dynamic and fixed images are supported. Differencing images and images whose log has to
+//! be replayed are rejected.
+//!
+//! https://learn.microsoft.com/en-us/openspecs/windows_protocols/ms-vhdx
+
+use std::cmp::min;
+use std::fs::File;
+use std::io;
+use std::io::ErrorKind;
+use std::sync::Arc;
+
+use base::AsRawDescriptor;
+use base::FileAllocate;
+use base::FileReadWriteAtVolatile;
+use base::FileSetLen;
+use base::FileSync;
+use base::PunchHole;
+use base::RawDescriptor;
+use base::VolatileSlice;
+use base::WriteZeroesAt;
+use cros_async::Executor;
+use remain::sorted;
+use thiserror::Error;
+
+use crate::asynchronous::DiskFlush;
+use crate::AsyncDisk;
+use crate::AsyncDiskFileWrapper;
+use crate::DiskFile;
+use crate::DiskGetLen;
+use crate::Result as DiskResult;
+use crate::ToAsyncDisk;
+
+#[sorted]
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("differencing VHDX images are not supported")]
+    DifferencingDisk,
+    #[error("invalid VHDX block size {0}")]
+    InvalidBlockSize(u32),
+    #[error("invalid VHDX logical sector size {0}")]
+    InvalidSectorSize(u32),
+    #[error("invalid VHDX image: {0}")]
+    InvalidSpecification(String),
+    #[error("the VHDX log must be replayed before the image can be used")]
+    LogNotEmpty,
+    #[error("VHDX metadata item {0} is missing")]
+    MissingMetadata(&'static str),
+    #[error("VHDX region {0} is missing")]
+    MissingRegion(&'static str),
+    #[error("no valid VHDX header found")]
+    NoValidHeader,
+    #[error("failed to read VHDX image: {0}")]
+    ReadingImage(io::Error),
+    #[error("VHDX image requires unsupported {0}")]
+    Unsupported(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+pub const VHDX_MAGIC: &[u8; 8] = b"vhdxfile";
+
+const HEADER_OFFSETS: [u64; 2] = [64 << 10, 128 << 10];
+const HEADER_SIZE: usize = 4 << 10;
+const HEADER_SIGNATURE: &[u8; 4] = b"head";
+const HEADER_VERSION: u16 = 1;
+
+const REGION_TABLE_OFFSETS: [u64; 2] = [192 << 10, 256 << 10];
+const REGION_TABLE_SIZE: usize = 64 << 10;
+const REGION_TABLE_SIGNATURE: &[u8; 4] = b"regi";
+const REGION_ENTRY_SIZE: usize = 32;
+const REGION_ENTRY_REQUIRED: u32 = 1;
+
+const METADATA_TABLE_SIZE: usize = 64 << 10;
+const METADATA_SIGNATURE: &[u8; 8] = b"metadata";
+const METADATA_ENTRY_SIZE: usize = 32;
+const METADATA_ENTRY_REQUIRED: u32 = 1 << 2;
+// Both tables are limited to 2047 entries after their header.
+const MAX_TABLE_ENTRIES: usize = 2047;
+
+const FILE_PARAMETERS_HAS_PARENT: u32 = 1 << 1;
+
+const MIN_BLOCK_SIZE: u32 = 1 << 20;
+const MAX_BLOCK_SIZE: u32 = 256 << 20;
+
+// Payload block states stored in the low bits of BAT entries. The other states leave the block
+// without data, so it reads as zeroes.
+const BAT_STATE_MASK: u64 = 0x7;
+const PAYLOAD_BLOCK_FULLY_PRESENT: u64 = 6;
+const PAYLOAD_BLOCK_PARTIALLY_PRESENT: u64 = 7;
+// The file offset of a block is stored in megabytes above bit 20.
+const BAT_OFFSET_MASK: u64 = !((1 << 20) - 1);
+
+// GUIDs are stored with their first three fields in little endian.
+const fn guid(d1: u32, d2: u16, d3: u16, d4: [u8; 8]) -> [u8; 16] {
+    let d1 = d1.to_le_bytes();
+    let d2 = d2.to_le_bytes();
+    let d3 = d3.to_le_bytes();
+    [
+        d1[0], d1[1], d1[2], d1[3], d2[0], d2[1], d3[0], d3[1], d4[0], d4[1], d4[2], d4[3], d4[4],
+        d4[5], d4[6], d4[7],
+    ]
+}
+
+const BAT_GUID: [u8; 16] = guid(
+    0x2dc27766,
+    0xf623,
+    0x4200,
+    [0x9d, 0x64, 0x11, 0x5e, 0x9b, 0xfd, 0x4a, 0x08],
+);
+const METADATA_GUID: [u8; 16] = guid(
+    0x8b7ca206,
+    0x4790,
+    0x4b9a,
+    [0xb8, 0xfe, 0x57, 0x5f, 0x05, 0x0f, 0x88, 0x6e],
+);
+const FILE_PARAMETERS_GUID: [u8; 16] = guid(
+    0xcaa16737,
+    0xfa36,
+    0x4d43,
+    [0xb3, 0xb6, 0x33, 0xf0, 0xaa, 0x44, 0xe7, 0x6b],
+);
+const VIRTUAL_DISK_SIZE_GUID: [u8; 16] = guid(
+    0x2fa54224,
+    0xcd1b,
+    0x4876,
+    [0xb2, 0x11, 0x5d, 0xbe, 0xd8, 0x3b, 0xf4, 0xb8],
+);
+const VIRTUAL_DISK_ID_GUID: [u8; 16] = guid(
+    0xbeca12ab,
+    0xb2e6,
+    0x4523,
+    [0x93, 0xef, 0xc3, 0x09, 0xe0, 0x00, 0xc7, 0x46],
+);
+const LOGICAL_SECTOR_SIZE_GUID: [u8; 16] = guid(
+    0x8141bf1d,
+    0xa96f,
+    0x4709,
+    [0xba, 0x47, 0xf2, 0x33, 0xa8, 0xfa, 0xab, 0x5f],
+);
+const PHYSICAL_SECTOR_SIZE_GUID: [u8; 16] = guid(
+    0xcda348c7,
+    0x445d,
+    0x4471,
+    [0x9c, 0xc9, 0xe9, 0x88, 0x52, 0x51, 0xc5, 0x56],
+);
+const PARENT_LOCATOR_GUID: [u8; 16] = guid(
+    0xa8d35f2d,
+    0xb30b,
+    0x454d,
+    [0xab, 0xf7, 0xd3, 0xd8, 0x48, 0x34, 0xab, 0x0c],
+);
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+// CRC-32C (Castagnoli) as used by the VHDX checksums.
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0x82f6_3b78 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+// Checks the checksum stored at offset 4 of a header or region table, which covers the whole
+// structure with the checksum field set to zero.
+fn checksum_valid(bytes: &[u8]) -> bool {
+    let mut copy = bytes.to_vec();
+    copy[4..8].fill(0);
+    crc32c(&copy) == u32_at(bytes, 4)
+}
+
+fn read_bytes(file: &File, offset: u64, len: usize) -> Result<Vec<u8>> {
+    let mut bytes = vec![0u8; len];
+    file.read_exact_at_volatile(VolatileSlice::new(&mut bytes), offset)
+        .map_err(Error::ReadingImage)?;
+    Ok(bytes)
+}
+
+struct Region {
+    offset: u64,
+    length: u32,
+}
+
+/// A read-only VHDX disk image.
+#[derive(Debug)]
+pub struct VhdxDisk {
+    file: File,
+    virtual_size: u64,
+    block_size: u64,
+    chunk_ratio: u64,
+    // Shared between clones, it never changes as the image is read-only.
+    bat: Arc<Vec<u64>>,
+}
+
+impl VhdxDisk {
+    pub fn from_file(file: File) -> Result<VhdxDisk> {
+        let signature = read_bytes(&file, 0, VHDX_MAGIC.len())?;
+        if signature != VHDX_MAGIC {
+            return Err(Error::InvalidSpecification(
+                "file identifier signature mismatch".to_string(),
+            ));
+        }
+
+        Self::check_header(&file)?;
+        let (bat_region, metadata_region) = Self::read_region_table(&file)?;
+
+        let metadata = read_bytes(&file, metadata_region.offset, METADATA_TABLE_SIZE)?;
+        if &metadata[0..8] != METADATA_SIGNATURE {
+            return Err(Error::InvalidSpecification(
+                "metadata table signature mismatch".to_string(),
+            ));
+        }
+        let entry_count = u16_at(&metadata, 10) as usize;
+        if entry_count > MAX_TABLE_ENTRIES {
+            return Err(Error::InvalidSpecification(format!(
+                "{} metadata entries",
+                entry_count
+            )));
+        }
+        let mut file_parameters = None;
+        let mut virtual_size = None;
+        let mut logical_sector_size = None;
+        for i in 0..entry_count {
+            let entry = &metadata[(i + 1) * METADATA_ENTRY_SIZE..(i + 2) * METADATA_ENTRY_SIZE];
+            let item_offset = u32_at(entry, 16) as u64;
+            let item_length = u32_at(entry, 20);
+            let flags = u32_at(entry, 24);
+            let read_item = |min_length: u32| {
+                if item_length < min_length
+                    || item_offset + item_length as u64 > metadata_region.length as u64
+                {
+                    return Err(Error::InvalidSpecification(
+                        "metadata item out of bounds".to_string(),
+                    ));
+                }
+                read_bytes(
+                    &file,
+                    metadata_region.offset + item_offset,
+                    min_length as usize,
+                )
+            };
+            match entry[0..16].try_into().unwrap() {
+                FILE_PARAMETERS_GUID => {
+                    let item = read_item(8)?;
+                    file_parameters = Some((u32_at(&item, 0), u32_at(&item, 4)));
+                }
+                VIRTUAL_DISK_SIZE_GUID => virtual_size = Some(u64_at(&read_item(8)?, 0)),
+                LOGICAL_SECTOR_SIZE_GUID => logical_sector_size = Some(u32_at(&read_item(4)?, 0)),
+                PARENT_LOCATOR_GUID => return Err(Error::DifferencingDisk),
+                VIRTUAL_DISK_ID_GUID | PHYSICAL_SECTOR_SIZE_GUID => {}
+                _ => {
+                    if flags & METADATA_ENTRY_REQUIRED != 0 {
+                        return Err(Error::Unsupported("metadata item".to_string()));
+                    }
+                }
+            }
+        }
+
+        let (block_size, file_flags) =
+            file_parameters.ok_or(Error::MissingMetadata("file parameters"))?;
+        if file_flags & FILE_PARAMETERS_HAS_PARENT != 0 {
+            return Err(Error::DifferencingDisk);
+        }
+        if !block_size.is_power_of_two() || !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&block_size)
+        {
+            return Err(Error::InvalidBlockSize(block_size));
+        }
+        let virtual_size = virtual_size.ok_or(Error::MissingMetadata("virtual disk size"))?;
+        let logical_sector_size =
+            logical_sector_size.ok_or(Error::MissingMetadata("logical sector size"))?;
+        if logical_sector_size != 512 && logical_sector_size != 4096 {
+            return Err(Error::InvalidSectorSize(logical_sector_size));
+        }
+
+        // A sector bitmap entry follows every `chunk_ratio` payload block entries in the BAT.
+        let chunk_ratio = ((1u64 << 23) * logical_sector_size as u64) / block_size as u64;
+        let block_size = block_size as u64;
+        let payload_blocks = virtual_size.div_ceil(block_size);
+        let bat_entries = match payload_blocks {
+            0 => 0,
+            n => n + (n - 1) / chunk_ratio,
+        };
+        if bat_entries * 8 > bat_region.length as u64 {
+            return Err(Error::InvalidSpecification(format!(
+                "BAT region of {} bytes too small for {} entries",
+                bat_region.length, bat_entries
+            )));
+        }
+        let bat_bytes = read_bytes(&file, bat_region.offset, bat_entries as usize * 8)?;
+        let bat = bat_bytes
+            .chunks_exact(8)
+            .map(|entry| u64::from_le_bytes(entry.try_into().unwrap()))
+            .collect();
+
+        Ok(VhdxDisk {
+            file,
+            virtual_size,
+            block_size,
+            chunk_ratio,
+            bat: Arc::new(bat),
+        })
+    }
+
+    // Finds the current header and makes sure the image doesn't need a log replay.
+    fn check_header(file: &File) -> Result<()> {
+        let mut current: Option<Vec<u8>> = None;
+        for offset in HEADER_OFFSETS {
+            let header = read_bytes(file, offset, HEADER_SIZE)?;
+            if &header[0..4] != HEADER_SIGNATURE || !checksum_valid(&header) {
+                continue;
+            }
+            // The header with the highest sequence number is the current one.
+            match &current {
+                Some(c) if u64_at(c, 8) >= u64_at(&header, 8) => {}
+                _ => current = Some(header),
+            }
+        }
+        let header = current.ok_or(Error::NoValidHeader)?;
+        let version = u16_at(&header, 66);
+        if version != HEADER_VERSION {
+            return Err(Error::Unsupported(format!("version {}", version)));
+        }
+        // The log GUID is only set while the log holds entries that haven't been applied.
+        if header[48..64].iter().any(|&b| b != 0) {
+            return Err(Error::LogNotEmpty);
+        }
+        Ok(())
+    }
+
+    // Returns the BAT and metadata regions from the first valid region table.
+    fn read_region_table(file: &File) -> Result<(Region, Region)> {
+        let mut table = None;
+        for offset in REGION_TABLE_OFFSETS {
+            let bytes = read_bytes(file, offset, REGION_TABLE_SIZE)?;
+            if &bytes[0..4] == REGION_TABLE_SIGNATURE && checksum_valid(&bytes) {
+                table = Some(bytes);
+                break;
+            }
+        }
+        let table = table.ok_or(Error::InvalidSpecification(
+            "no valid region table".to_string(),
+        ))?;
+        let entry_count = u32_at(&table, 8) as usize;
+        if entry_count > MAX_TABLE_ENTRIES {
+            return Err(Error::InvalidSpecification(format!(
+                "{} region table entries",
+                entry_count
+            )));
+        }
+        let mut bat = None;
+        let mut metadata = None;
+        for i in 0..entry_count {
+            let entry = &table[(i + 1) * REGION_ENTRY_SIZE..(i + 2) * REGION_ENTRY_SIZE];
+            let region = Region {
+                offset: u64_at(entry, 16),
+                length: u32_at(entry, 24),
+            };
+            match entry[0..16].try_into().unwrap() {
+                BAT_GUID => bat = Some(region),
+                METADATA_GUID => metadata = Some(region),
+                _ => {
+                    if u32_at(entry, 28) & REGION_ENTRY_REQUIRED != 0 {
+                        return Err(Error::Unsupported("region".to_string()));
+                    }
+                }
+            }
+        }
+        Ok((
+            bat.ok_or(Error::MissingRegion("BAT"))?,
+            metadata.ok_or(Error::MissingRegion("metadata"))?,
+        ))
+    }
+}
+
+impl DiskGetLen for VhdxDisk {
+    fn get_len(&self) -> io::Result<u64> {
+        Ok(self.virtual_size)
+    }
+}
+
+impl FileSetLen for VhdxDisk {
+    fn set_len(&self, _len: u64) -> io::Result<()> {
+        Err(io::Error::new(
+            ErrorKind::PermissionDenied,
+            "unsupported operation",
+        ))
+    }
+}
+
+impl FileAllocate for VhdxDisk {
+    fn allocate(&self, _offset: u64, _len: u64) -> io::Result<()> {
+        Err(io::Error::new(
+            ErrorKind::PermissionDenied,
+            "unsupported operation",
+        ))
+    }
+}
+
+impl PunchHole for VhdxDisk {
+    fn punch_hole(&self, _offset: u64, _length: u64) -> io::Result<()> {
+        Err(io::Error::new(
+            ErrorKind::PermissionDenied,
+            "unsupported operation",
+        ))
+    }
+}
+
+impl WriteZeroesAt for VhdxDisk {
+    fn write_zeroes_at(&self, _offset: u64, _length: usize) -> io::Result<usize> {
+        Err(io::Error::new(
+            ErrorKind::PermissionDenied,
+            "unsupported operation",
+        ))
+    }
+}
+
+// Nothing is ever written, so there is nothing to flush.
+impl DiskFlush for VhdxDisk {
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl FileSync for VhdxDisk {
+    fn fsync(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn fdatasync(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsRawDescriptor for VhdxDisk {
+    fn as_raw_descriptor(&self) -> RawDescriptor {
+        self.file.as_raw_descriptor()
+    }
+}
+
+// Performs reads up to the block boundary.
+impl FileReadWriteAtVolatile for VhdxDisk {
+    fn read_at_volatile(&self, slice: VolatileSlice, offset: u64) -> io::Result<usize> {
+        if offset >= self.virtual_size {
+            return Ok(0);
+        }
+        let block = offset / self.block_size;
+        let block_offset = offset % self.block_size;
+        let len = min(
+            slice.size() as u64,
+            min(self.block_size - block_offset, self.virtual_size - offset),
+        );
+        let subslice = slice
+            .sub_slice(0, len as usize)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, format!("{:?}", e)))?;
+        let entry = self.bat[(block + block / self.chunk_ratio) as usize];
+        match entry & BAT_STATE_MASK {
+            PAYLOAD_BLOCK_FULLY_PRESENT => self
+                .file
+                .read_at_volatile(subslice, (entry & BAT_OFFSET_MASK) + block_offset),
+            PAYLOAD_BLOCK_PARTIALLY_PRESENT => Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "partially present block {} in non-differencing image",
+                    block
+                ),
+            )),
+            _ => {
+                subslice.write_bytes(0);
+                Ok(subslice.size())
+            }
+        }
+    }
+
+    fn write_at_volatile(&self, _slice: VolatileSlice, _offset: u64) -> io::Result<usize> {
+        Err(io::Error::new(
+            ErrorKind::PermissionDenied,
+            "unsupported operation",
+        ))
+    }
+}
+
+impl DiskFile for VhdxDisk {
+    fn try_clone(&self) -> io::Result<Box<dyn DiskFile>> {
+        Ok(Box::new(VhdxDisk {
+            file: self.file.try_clone()?,
+            virtual_size: self.virtual_size,
+            block_size: self.block_size,
+            chunk_ratio: self.chunk_ratio,
+            bat: self.bat.clone(),
+        }))
+    }
+}
+
+impl ToAsyncDisk for VhdxDisk {
+    fn to_async_disk(self: Box<Self>, ex: &Executor) -> DiskResult<Box<dyn AsyncDisk>> {
+        Ok(Box::new(AsyncDiskFileWrapper::new(*self, ex)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Seek;
+    use std::io::SeekFrom;
+    use std::io::Write;
+
+    use super::*;
+
+    const MB: u64 = 1 << 20;
+    const METADATA_OFFSET: u64 = MB;
+    const BAT_OFFSET: u64 = 2 * MB;
+    const DATA_OFFSET: u64 = 3 * MB;
+
+    fn write_at(file: &mut File, offset: u64, bytes: &[u8]) {
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        file.write_all(bytes).unwrap();
+    }
+
+    fn with_checksum(mut bytes: Vec<u8>) -> Vec<u8> {
+        bytes[4..8].fill(0);
+        let crc = crc32c(&bytes);
+        bytes[4..8].copy_from_slice(&crc.to_le_bytes());
+        bytes
+    }
+
+    fn header(sequence: u64, log_guid: [u8; 16]) -> Vec<u8> {
+        let mut header = vec![0u8; HEADER_SIZE];
+        header[0..4].copy_from_slice(HEADER_SIGNATURE);
+        header[8..16].copy_from_slice(&sequence.to_le_bytes());
+        header[48..64].copy_from_slice(&log_guid);
+        header[66..68].copy_from_slice(&HEADER_VERSION.to_le_bytes());
+        with_checksum(header)
+    }
+
+    fn metadata_entry(table: &mut [u8], index: usize, id: [u8; 16], item: &[u8], item_offset: u32) {
+        let entry =
+            &mut table[(index + 1) * METADATA_ENTRY_SIZE..(index + 2) * METADATA_ENTRY_SIZE];
+        entry[0..16].copy_from_slice(&id);
+        entry[16..20].copy_from_slice(&item_offset.to_le_bytes());
+        entry[20..24].copy_from_slice(&(item.len() as u32).to_le_bytes());
+        entry[24..28].copy_from_slice(&METADATA_ENTRY_REQUIRED.to_le_bytes());
+        table[item_offset as usize..item_offset as usize + item.len()].copy_from_slice(item);
+    }
+
+    // Creates a 4 MiB image with 1 MiB blocks, whose BAT entries are set to `bat`.
+    fn test_image(bat: &[u64], file_flags: u32) -> File {
+        let mut file = tempfile::tempfile().unwrap();
+        write_at(&mut file, 0, VHDX_MAGIC);
+        write_at(&mut file, HEADER_OFFSETS[0], &header(1, [0; 16]));
+        write_at(&mut file, HEADER_OFFSETS[1], &header(2, [0; 16]));
+
+        let mut regions = vec![0u8; REGION_TABLE_SIZE];
+        regions[0..4].copy_from_slice(REGION_TABLE_SIGNATURE);
+        regions[8..12].copy_from_slice(&2u32.to_le_bytes());
+        for (i, (id, offset)) in [(BAT_GUID, BAT_OFFSET), (METADATA_GUID, METADATA_OFFSET)]
+            .into_iter()
+            .enumerate()
+        {
+            let entry = &mut regions[(i + 1) * REGION_ENTRY_SIZE..(i + 2) * REGION_ENTRY_SIZE];
+            entry[0..16].copy_from_slice(&id);
+            entry[16..24].copy_from_slice(&offset.to_le_bytes());
+            entry[24..28].copy_from_slice(&(MB as u32).to_le_bytes());
+            entry[28..32].copy_from_slice(&REGION_ENTRY_REQUIRED.to_le_bytes());
+        }
+        let regions = with_checksum(regions);
+        write_at(&mut file, REGION_TABLE_OFFSETS[0], &regions);
+        write_at(&mut file, REGION_TABLE_OFFSETS[1], &regions);
+
+        let mut metadata = vec![0u8; 2 * METADATA_TABLE_SIZE];
+        metadata[0..8].copy_from_slice(METADATA_SIGNATURE);
+        metadata[10..12].copy_from_slice(&3u16.to_le_bytes());
+        let mut file_parameters = (MB as u32).to_le_bytes().to_vec();
+        file_parameters.extend_from_slice(&file_flags.to_le_bytes());
+        let items = [
+            (FILE_PARAMETERS_GUID, file_parameters),
+            (VIRTUAL_DISK_SIZE_GUID, (4 * MB).to_le_bytes().to_vec()),
+            (LOGICAL_SECTOR_SIZE_GUID, 512u32.to_le_bytes().to_vec()),
+        ];
+        for (i, (id, item)) in items.iter().enumerate() {
+            let item_offset = METADATA_TABLE_SIZE as u32 + i as u32 * 8;
+            metadata_entry(&mut metadata, i, *id, item, item_offset);
+        }
+        write_at(&mut file, METADATA_OFFSET, &metadata);
+
+        let bat_bytes: Vec<u8> = bat.iter().flat_map(|e| e.to_le_bytes()).collect();
+        write_at(&mut file, BAT_OFFSET, &bat_bytes);
+        file.set_len(BAT_OFFSET + MB).unwrap();
+        file
+    }
+
+    fn present(offset: u64) -> u64 {
+        offset | PAYLOAD_BLOCK_FULLY_PRESENT
+    }
+
+    fn read(disk: &VhdxDisk, offset: u64, len: usize) -> Vec<u8> {
+        let mut buf = vec![0x55u8; len];
+        disk.read_exact_at_volatile(VolatileSlice::new(&mut buf), offset)
+            .unwrap();
+        buf
+    }
+
+    #[test]
+    fn crc32c_check_value() {
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+    }
+
+    #[test]
+    fn read_blocks() {
+        // Blocks 0 and 3 hold data, block 1 was never written and block 2 was zeroed.
+        let mut file = test_image(&[present(DATA_OFFSET), 0, 2, present(DATA_OFFSET + MB)], 0);
+        write_at(&mut file, DATA_OFFSET, &vec![0xaa; MB as usize]);
+        write_at(&mut file, DATA_OFFSET + MB, &vec![0xbb; MB as usize]);
+        let disk = VhdxDisk::from_file(file).unwrap();
+        assert_eq!(disk.get_len().unwrap(), 4 * MB);
+
+        assert_eq!(read(&disk, 0, 4096), vec![0xaa; 4096]);
+        assert_eq!(read(&disk, 2 * MB, 4096), vec![0; 4096]);
+        // A read that spans blocks.
+        let data = read(&disk, MB - 512, 1024);
+        assert_eq!(&data[..512], &[0xaa; 512]);
+        assert_eq!(&data[512..], &[0; 512]);
+        let data = read(&disk, 3 * MB - 512, 1024);
+        assert_eq!(&data[..512], &[0; 512]);
+        assert_eq!(&data[512..], &[0xbb; 512]);
+
+        let mut buf = [0u8; 512];
+        assert!(disk
+            .write_at_volatile(VolatileSlice::new(&mut buf), 0)
+            .is_err());
+        assert!(disk.set_len(MB).is_err());
+    }
+
+    #[test]
+    fn newest_valid_header() {
+        let mut file = test_image(&[0; 4], 0);
+        // The newer header is corrupt, so the older one without a log is used.
+        let mut corrupt = header(3, [1; 16]);
+        corrupt[100] = 1;
+        write_at(&mut file, HEADER_OFFSETS[1], &corrupt);
+        assert!(VhdxDisk::from_file(file.try_clone().unwrap()).is_ok());
+
+        write_at(&mut file, HEADER_OFFSETS[1], &header(3, [1; 16]));
+        assert!(matches!(VhdxDisk::from_file(file), Err(Error::LogNotEmpty)));
+    }
+
+    #[test]
+    fn differencing_rejected() {
+        let file = test_image(&[0; 4], FILE_PARAMETERS_HAS_PARENT);
+        assert!(matches!(
+            VhdxDisk::from_file(file),
+            Err(Error::DifferencingDisk)
+        ));
+    }
+
+    #[test]
+    fn bat_too_small() {
+        let mut file = test_image(&[0; 4], 0);
+        // Shrink the BAT region to less than the four entries of the disk.
+        let mut regions = read_bytes(&file, REGION_TABLE_OFFSETS[0], REGION_TABLE_SIZE).unwrap();
+        regions[REGION_ENTRY_SIZE + 24..REGION_ENTRY_SIZE + 28]
+            .copy_from_slice(&16u32.to_le_bytes());
+        let regions = with_checksum(regions);
+        write_at(&mut file, REGION_TABLE_OFFSETS[0], &regions);
+        assert!(matches!(
+            VhdxDisk::from_file(file),
+            Err(Error::InvalidSpecification(_))
+        ));
+    }
+}
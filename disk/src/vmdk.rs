@@ -0,0 +1,487 @@
+// Copyright 2025 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Read-only access to monolithic sparse VMDK disk images.
+//!
+//! Images with compressed grains, such as stream-optimized images, and child images of a delta
+//! link are rejected.
+//!
+//! https://www.vmware.com/app/vmdk/?src=vmdk
+
+use std::cmp::min;
+use std::fs::File;
+use std::io;
+use std::io::ErrorKind;
+use std::sync::Arc;
+
+use base::AsRawDescriptor;
+use base::FileAllocate;
+use base::FileReadWriteAtVolatile;
+use base::FileSetLen;
+use base::FileSync;
+use base::PunchHole;
+use base::RawDescriptor;
+use base::VolatileSlice;
+use base::WriteZeroesAt;
+use cros_async::Executor;
+use remain::sorted;
+use thiserror::Error;
+
+use crate::asynchronous::DiskFlush;
+use crate::AsyncDisk;
+use crate::AsyncDiskFileWrapper;
+use crate::DiskFile;
+use crate::DiskGetLen;
+use crate::Result as DiskResult;
+use crate::ToAsyncDisk;
+
+#[sorted]
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("VMDK images with compressed grains are not supported")]
+    CompressedGrains,
+    #[error("VMDK images with a parent are not supported")]
+    DeltaLink,
+    #[error("invalid VMDK grain size of {0} sectors")]
+    InvalidGrainSize(u64),
+    #[error("invalid VMDK image: {0}")]
+    InvalidSpecification(String),
+    #[error("failed to read VMDK image: {0}")]
+    ReadingImage(io::Error),
+    #[error("unsupported VMDK version {0}")]
+    UnsupportedVersion(u32),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+pub const VMDK_MAGIC: u32 = 0x564d_444b; // "KDMV"
+
+const SECTOR_SIZE: u64 = 512;
+const HEADER_SIZE: usize = 512;
+const MAX_VERSION: u32 = 3;
+const FLAG_COMPRESSED: u32 = 1 << 16;
+// Stream-optimized images store the grain directory location in a footer.
+const GD_AT_END: u64 = u64::MAX;
+const GTES_PER_GT: u32 = 512;
+// Same limit as qemu: grains of at most 64 KiB.
+const MAX_GRAIN_SECTORS: u64 = 128;
+// Keeps a corrupt header from causing a huge allocation, like the table size limit of qcow. Enough
+// for 16 TiB with the smallest grains.
+const MAX_GRAIN_DIRECTORY_ENTRIES: u64 = 1 << 23;
+// The embedded descriptor is plain text, this only keeps a corrupt header from causing huge
+// allocations.
+const MAX_DESCRIPTOR_SECTORS: u64 = 2048;
+// A parent CID of all ones means the image has no parent.
+const NO_PARENT_CID: &str = "ffffffff";
+
+// Grain table entries without a grain. An unallocated grain would be read from the parent image,
+// which isn't supported, so both read as zeroes.
+const GTE_UNALLOCATED: u32 = 0;
+const GTE_ZEROED: u32 = 1;
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+// Converts a sector count or offset read from the header to bytes.
+fn sectors_to_bytes(sectors: u64, field: &str) -> Result<u64> {
+    sectors
+        .checked_mul(SECTOR_SIZE)
+        .ok_or_else(|| Error::InvalidSpecification(format!("{} too large", field)))
+}
+
+fn read_bytes(file: &File, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+    let mut bytes = vec![0u8; len];
+    file.read_exact_at_volatile(VolatileSlice::new(&mut bytes), offset)?;
+    Ok(bytes)
+}
+
+/// A read-only monolithic sparse VMDK disk image.
+#[derive(Debug)]
+pub struct VmdkDisk {
+    file: File,
+    capacity: u64,
+    grain_size: u64,
+    // Sector offsets of the grain tables, loaded at open. Grain table entries are read as needed.
+    grain_directory: Arc<Vec<u32>>,
+}
+
+impl VmdkDisk {
+    pub fn from_file(file: File) -> Result<VmdkDisk> {
+        let header = read_bytes(&file, 0, HEADER_SIZE).map_err(Error::ReadingImage)?;
+        if u32_at(&header, 0) != VMDK_MAGIC {
+            return Err(Error::InvalidSpecification(
+                "sparse extent magic mismatch".to_string(),
+            ));
+        }
+        let version = u32_at(&header, 4);
+        if version == 0 || version > MAX_VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        let flags = u32_at(&header, 8);
+        let capacity_sectors = u64_at(&header, 12);
+        let grain_sectors = u64_at(&header, 20);
+        let descriptor_offset = u64_at(&header, 28);
+        let descriptor_size = u64_at(&header, 36);
+        let gtes_per_gt = u32_at(&header, 44);
+        let gd_offset = u64_at(&header, 56);
+        let compress_algorithm = u16::from_le_bytes(header[77..79].try_into().unwrap());
+
+        if flags & FLAG_COMPRESSED != 0 || compress_algorithm != 0 || gd_offset == GD_AT_END {
+            return Err(Error::CompressedGrains);
+        }
+        if !grain_sectors.is_power_of_two() || !(8..=MAX_GRAIN_SECTORS).contains(&grain_sectors) {
+            return Err(Error::InvalidGrainSize(grain_sectors));
+        }
+        if gtes_per_gt != GTES_PER_GT {
+            return Err(Error::InvalidSpecification(format!(
+                "{} entries per grain table",
+                gtes_per_gt
+            )));
+        }
+        let capacity = sectors_to_bytes(capacity_sectors, "capacity")?;
+
+        if descriptor_offset != 0 {
+            if descriptor_size > MAX_DESCRIPTOR_SECTORS {
+                return Err(Error::InvalidSpecification(format!(
+                    "descriptor of {} sectors",
+                    descriptor_size
+                )));
+            }
+            let descriptor = read_bytes(
+                &file,
+                sectors_to_bytes(descriptor_offset, "descriptor offset")?,
+                (descriptor_size * SECTOR_SIZE) as usize,
+            )
+            .map_err(Error::ReadingImage)?;
+            if has_parent(&descriptor) {
+                return Err(Error::DeltaLink);
+            }
+        }
+
+        // Both are bounded by the grain size check.
+        let grain_size = grain_sectors * SECTOR_SIZE;
+        let grain_directory_entries = capacity.div_ceil(grain_size * GTES_PER_GT as u64);
+        if grain_directory_entries > MAX_GRAIN_DIRECTORY_ENTRIES {
+            return Err(Error::InvalidSpecification(format!(
+                "{} grain directory entries",
+                grain_directory_entries
+            )));
+        }
+        let grain_directory = read_bytes(
+            &file,
+            sectors_to_bytes(gd_offset, "grain directory offset")?,
+            grain_directory_entries as usize * 4,
+        )
+        .map_err(Error::ReadingImage)?
+        .chunks_exact(4)
+        .map(|entry| u32::from_le_bytes(entry.try_into().unwrap()))
+        .collect();
+
+        Ok(VmdkDisk {
+            file,
+            capacity,
+            grain_size,
+            grain_directory: Arc::new(grain_directory),
+        })
+    }
+
+    // Returns the file offset of the grain holding `offset`, or `None` if it reads as zeroes.
+    fn grain_offset(&self, offset: u64) -> io::Result<Option<u64>> {
+        let grain = offset / self.grain_size;
+        let table_sector = self.grain_directory[(grain / GTES_PER_GT as u64) as usize];
+        if table_sector == 0 {
+            return Ok(None);
+        }
+        let entry_offset = table_sector as u64 * SECTOR_SIZE + (grain % GTES_PER_GT as u64) * 4;
+        let entry = read_bytes(&self.file, entry_offset, 4)?;
+        match u32_at(&entry, 0) {
+            GTE_UNALLOCATED | GTE_ZEROED => Ok(None),
+            sector => Ok(Some(sector as u64 * SECTOR_SIZE)),
+        }
+    }
+}
+
+// Checks the `parentCID` of an embedded descriptor.
+fn has_parent(descriptor: &[u8]) -> bool {
+    String::from_utf8_lossy(descriptor)
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("parentCID"))
+        .filter_map(|rest| rest.trim_start().strip_prefix('='))
+        .any(|cid| !cid.trim().eq_ignore_ascii_case(NO_PARENT_CID))
+}
+
+impl DiskGetLen for VmdkDisk {
+    fn get_len(&self) -> io::Result<u64> {
+        Ok(self.capacity)
+    }
+}
+
+impl FileSetLen for VmdkDisk {
+    fn set_len(&self, _len: u64) -> io::Result<()> {
+        Err(io::Error::new(
+            ErrorKind::PermissionDenied,
+            "unsupported operation",
+        ))
+    }
+}
+
+impl FileAllocate for VmdkDisk {
+    fn allocate(&self, _offset: u64, _len: u64) -> io::Result<()> {
+        Err(io::Error::new(
+            ErrorKind::PermissionDenied,
+            "unsupported operation",
+        ))
+    }
+}
+
+impl PunchHole for VmdkDisk {
+    fn punch_hole(&self, _offset: u64, _length: u64) -> io::Result<()> {
+        Err(io::Error::new(
+            ErrorKind::PermissionDenied,
+            "unsupported operation",
+        ))
+    }
+}
+
+impl WriteZeroesAt for VmdkDisk {
+    fn write_zeroes_at(&self, _offset: u64, _length: usize) -> io::Result<usize> {
+        Err(io::Error::new(
+            ErrorKind::PermissionDenied,
+            "unsupported operation",
+        ))
+    }
+}
+
+// Nothing is ever written, so there is nothing to flush.
+impl DiskFlush for VmdkDisk {
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl FileSync for VmdkDisk {
+    fn fsync(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn fdatasync(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsRawDescriptor for VmdkDisk {
+    fn as_raw_descriptor(&self) -> RawDescriptor {
+        self.file.as_raw_descriptor()
+    }
+}
+
+// Performs reads up to the grain boundary.
+impl FileReadWriteAtVolatile for VmdkDisk {
+    fn read_at_volatile(&self, slice: VolatileSlice, offset: u64) -> io::Result<usize> {
+        if offset >= self.capacity {
+            return Ok(0);
+        }
+        let grain_offset = offset % self.grain_size;
+        let len = min(
+            slice.size() as u64,
+            min(self.grain_size - grain_offset, self.capacity - offset),
+        );
+        let subslice = slice
+            .sub_slice(0, len as usize)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, format!("{:?}", e)))?;
+        match self.grain_offset(offset)? {
+            Some(file_offset) => self
+                .file
+                .read_at_volatile(subslice, file_offset + grain_offset),
+            None => {
+                subslice.write_bytes(0);
+                Ok(subslice.size())
+            }
+        }
+    }
+
+    fn write_at_volatile(&self, _slice: VolatileSlice, _offset: u64) -> io::Result<usize> {
+        Err(io::Error::new(
+            ErrorKind::PermissionDenied,
+            "unsupported operation",
+        ))
+    }
+}
+
+impl DiskFile for VmdkDisk {
+    fn try_clone(&self) -> io::Result<Box<dyn DiskFile>> {
+        Ok(Box::new(VmdkDisk {
+            file: self.file.try_clone()?,
+            capacity: self.capacity,
+            grain_size: self.grain_size,
+            grain_directory: self.grain_directory.clone(),
+        }))
+    }
+}
+
+impl ToAsyncDisk for VmdkDisk {
+    fn to_async_disk(self: Box<Self>, ex: &Executor) -> DiskResult<Box<dyn AsyncDisk>> {
+        Ok(Box::new(AsyncDiskFileWrapper::new(*self, ex)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Seek;
+    use std::io::SeekFrom;
+    use std::io::Write;
+
+    use super::*;
+
+    // 64 KiB grains, so one grain table covers 32 MiB.
+    const GRAIN_SECTORS: u64 = 128;
+    const GRAIN_SIZE: u64 = GRAIN_SECTORS * SECTOR_SIZE;
+    const DESCRIPTOR_SECTOR: u64 = 1;
+    const GD_SECTOR: u64 = 8;
+    const GT_SECTOR: u64 = 16;
+    const DATA_SECTOR: u64 = 32;
+
+    fn write_at(file: &mut File, offset: u64, bytes: &[u8]) {
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        file.write_all(bytes).unwrap();
+    }
+
+    // Creates an image of 40 MiB where the first grain table maps `grains` and the grain table of
+    // the remaining 8 MiB isn't allocated.
+    fn test_image(grains: &[u32], descriptor: &str) -> File {
+        let mut file = tempfile::tempfile().unwrap();
+        let mut header = vec![0u8; HEADER_SIZE];
+        header[0..4].copy_from_slice(&VMDK_MAGIC.to_le_bytes());
+        header[4..8].copy_from_slice(&1u32.to_le_bytes());
+        header[8..12].copy_from_slice(&3u32.to_le_bytes());
+        header[12..20].copy_from_slice(&(40u64 << 11).to_le_bytes());
+        header[20..28].copy_from_slice(&GRAIN_SECTORS.to_le_bytes());
+        header[28..36].copy_from_slice(&DESCRIPTOR_SECTOR.to_le_bytes());
+        header[36..44].copy_from_slice(&1u64.to_le_bytes());
+        header[44..48].copy_from_slice(&GTES_PER_GT.to_le_bytes());
+        header[56..64].copy_from_slice(&GD_SECTOR.to_le_bytes());
+        write_at(&mut file, 0, &header);
+        write_at(
+            &mut file,
+            DESCRIPTOR_SECTOR * SECTOR_SIZE,
+            descriptor.as_bytes(),
+        );
+
+        let mut grain_directory = (GT_SECTOR as u32).to_le_bytes().to_vec();
+        grain_directory.extend_from_slice(&0u32.to_le_bytes());
+        write_at(&mut file, GD_SECTOR * SECTOR_SIZE, &grain_directory);
+        let grain_table: Vec<u8> = grains.iter().flat_map(|e| e.to_le_bytes()).collect();
+        write_at(&mut file, GT_SECTOR * SECTOR_SIZE, &grain_table);
+        file
+    }
+
+    fn read(disk: &VmdkDisk, offset: u64, len: usize) -> Vec<u8> {
+        let mut buf = vec![0x55u8; len];
+        disk.read_exact_at_volatile(VolatileSlice::new(&mut buf), offset)
+            .unwrap();
+        buf
+    }
+
+    const DESCRIPTOR: &str = "# Disk DescriptorFile\nversion=1\nCID=12345678\n\
+        parentCID=ffffffff\ncreateType=\"monolithicSparse\"\n";
+
+    #[test]
+    fn read_grains() {
+        // Grain 0 holds data, grain 1 is unallocated, grain 2 is zeroed and grain 3 holds data.
+        let data_sector = DATA_SECTOR as u32;
+        let grains = [data_sector, 0, 1, data_sector + GRAIN_SECTORS as u32];
+        let mut file = test_image(&grains, DESCRIPTOR);
+        write_at(
+            &mut file,
+            DATA_SECTOR * SECTOR_SIZE,
+            &[0xaa; GRAIN_SIZE as usize],
+        );
+        write_at(
+            &mut file,
+            (DATA_SECTOR + GRAIN_SECTORS) * SECTOR_SIZE,
+            &[0xbb; GRAIN_SIZE as usize],
+        );
+        let disk = VmdkDisk::from_file(file).unwrap();
+        assert_eq!(disk.get_len().unwrap(), 40 << 20);
+
+        assert_eq!(read(&disk, 0, 4096), vec![0xaa; 4096]);
+        assert_eq!(read(&disk, GRAIN_SIZE, 4096), vec![0; 4096]);
+        assert_eq!(read(&disk, 2 * GRAIN_SIZE, 4096), vec![0; 4096]);
+        // A read that spans grains.
+        let data = read(&disk, 4 * GRAIN_SIZE - 512, 1024);
+        assert_eq!(&data[..512], &[0xbb; 512]);
+        assert_eq!(&data[512..], &[0; 512]);
+        // The last 8 MiB don't have a grain table.
+        assert_eq!(read(&disk, 36 << 20, 4096), vec![0; 4096]);
+
+        let mut buf = [0u8; 512];
+        assert!(disk
+            .write_at_volatile(VolatileSlice::new(&mut buf), 0)
+            .is_err());
+        assert!(disk.set_len(GRAIN_SIZE).is_err());
+    }
+
+    #[test]
+    fn delta_link_rejected() {
+        let descriptor = DESCRIPTOR.replace("parentCID=ffffffff", "parentCID = 0badcafe");
+        assert!(matches!(
+            VmdkDisk::from_file(test_image(&[], &descriptor)),
+            Err(Error::DeltaLink)
+        ));
+    }
+
+    #[test]
+    fn compressed_rejected() {
+        let mut file = test_image(&[], DESCRIPTOR);
+        write_at(&mut file, 8, &(3u32 | FLAG_COMPRESSED).to_le_bytes());
+        assert!(matches!(
+            VmdkDisk::from_file(file),
+            Err(Error::CompressedGrains)
+        ));
+    }
+
+    #[test]
+    fn invalid_grain_size() {
+        let mut file = test_image(&[], DESCRIPTOR);
+        write_at(&mut file, 20, &100u64.to_le_bytes());
+        assert!(matches!(
+            VmdkDisk::from_file(file),
+            Err(Error::InvalidGrainSize(100))
+        ));
+        let mut file = test_image(&[], DESCRIPTOR);
+        write_at(&mut file, 20, &(1u64 << 62).to_le_bytes());
+        assert!(matches!(
+            VmdkDisk::from_file(file),
+            Err(Error::InvalidGrainSize(_))
+        ));
+    }
+
+    #[test]
+    fn corrupt_header() {
+        // Offsets of the capacity, descriptor offset and grain directory offset in the header.
+        for (offset, value) in [
+            (12, u64::MAX),
+            (12, 1 << 50),
+            (28, u64::MAX),
+            (56, u64::MAX - 1),
+        ] {
+            let mut file = test_image(&[], DESCRIPTOR);
+            write_at(&mut file, offset, &value.to_le_bytes());
+            assert!(
+                matches!(
+                    VmdkDisk::from_file(file),
+                    Err(Error::InvalidSpecification(_))
+                ),
+                "header field at {} set to {:#x}",
+                offset,
+                value
+            );
+        }
+    }
+}
@@ -430,6 +430,28 @@ impl TestVm {
         TestVm::new_generic_restore(TestVmSys::append_config_args, cfg, false)
     }
 
+    /// Create `TestVm` waiting for a VM migrated on the Unix socket at `socket_path`, using
+    /// `--migrate-from`. Returns once the socket exists.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    pub fn new_migration_destination(cfg: Config, socket_path: &Path) -> Result<TestVm> {
+        let vm = TestVm::new_generic_restore(
+            TestVmSys::append_config_args,
+            cfg.extra_args(vec![
+                "--migrate-from".to_string(),
+                socket_path.to_str().unwrap().to_string(),
+            ]),
+            false,
+        )?;
+        let start = std::time::Instant::now();
+        while !socket_path.exists() {
+            if start.elapsed() > BOOT_TIMEOUT {
+                bail!("{} was not created", socket_path.display());
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        Ok(vm)
+    }
+
     pub fn new_sudo(cfg: Config) -> Result<TestVm> {
         check_can_sudo();
 
@@ -613,6 +635,21 @@ impl TestVm {
             .map(|_| ())
     }
 
+    /// Takes a snapshot with only the memory pages changed since the previous incremental one.
+    pub fn snapshot_incremental(&mut self, filename: &std::path::Path) -> Result<()> {
+        self.sys
+            .crosvm_command(
+                "snapshot",
+                vec![
+                    "take".to_string(),
+                    "--incremental".to_string(),
+                    String::from(filename.to_str().unwrap()),
+                ],
+                self.sudo,
+            )
+            .map(|_| ())
+    }
+
     // No argument is passed in restore as we will always restore snapshot.bkp for testing.
     pub fn restore(&mut self, filename: &std::path::Path) -> Result<()> {
         self.sys
@@ -627,6 +664,22 @@ impl TestVm {
             .map(|_| ())
     }
 
+    /// Migrates the VM to the crosvm instance waiting on `socket_path`, and waits for this one to
+    /// exit.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    pub fn migrate(mut self, socket_path: &Path) -> Result<()> {
+        self.sys.crosvm_command(
+            "migrate",
+            vec![socket_path.to_str().unwrap().to_string()],
+            self.sudo,
+        )?;
+        let status = self.sys.process.take().unwrap().wait()?;
+        if !status.success() {
+            bail!("VM exited illegally after migration: {}", status);
+        }
+        Ok(())
+    }
+
     pub fn swap_command(&mut self, command: &str) -> Result<Vec<u8>> {
         self.sys
             .crosvm_command("swap", vec![command.to_string()], self.sudo)
@@ -642,6 +695,10 @@ impl TestVm {
 
 impl Drop for TestVm {
     fn drop(&mut self) {
+        // The VM already exited, e.g. after migrating.
+        if self.sys.process.is_none() {
+            return;
+        }
         self.stop().unwrap();
         let status = self.sys.process.take().unwrap().wait().unwrap();
         if !status.success() {
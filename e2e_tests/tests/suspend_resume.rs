@@ -152,6 +152,73 @@ fn suspend_resume_system(disabled_sandbox: bool) -> anyhow::Result<()> {
     Ok(())
 }
 
+#[test]
+fn incremental_snapshot_restore() {
+    let new_config = || Config::new().extra_args(vec!["--no-usb".to_string()]);
+    let mut vm = TestVm::new(new_config()).unwrap();
+
+    // Verify RAM is restored from the chain by interacting with a filesystem pinned in RAM (i.e.
+    // tmpfs with swap disabled).
+    vm.exec_in_guest("swapoff -a").unwrap();
+    vm.exec_in_guest("mount -t tmpfs none /tmp").unwrap();
+    vm.exec_in_guest("echo foo > /tmp/foo").unwrap();
+
+    let dir = tempdir().unwrap();
+    let base_path = dir.path().join("base.bkp");
+    vm.suspend_full().unwrap();
+    vm.snapshot_incremental(&base_path).unwrap();
+    vm.resume_full().unwrap();
+
+    vm.exec_in_guest("echo bar > /tmp/bar").unwrap();
+    let incremental_path = dir.path().join("incremental.bkp");
+    vm.suspend_full().unwrap();
+    vm.snapshot_incremental(&incremental_path).unwrap();
+    drop(vm);
+
+    let mut vm = TestVm::new_restore_suspended(new_config().extra_args(vec![
+        "--restore".to_string(),
+        incremental_path.to_str().unwrap().to_string(),
+        "--suspended".to_string(),
+    ]))
+    .unwrap();
+    vm.resume_full().unwrap();
+
+    assert_eq!(
+        "foo",
+        vm.exec_in_guest("cat /tmp/foo").unwrap().stdout.trim()
+    );
+    assert_eq!(
+        "bar",
+        vm.exec_in_guest("cat /tmp/bar").unwrap().stdout.trim()
+    );
+}
+
+#[test]
+fn migrate_loopback() {
+    let new_config = || Config::new().extra_args(vec!["--no-usb".to_string()]);
+    let mut vm = TestVm::new(new_config()).unwrap();
+
+    // Verify RAM is migrated by interacting with a filesystem pinned in RAM (i.e. tmpfs with swap
+    // disabled).
+    vm.exec_in_guest("swapoff -a").unwrap();
+    vm.exec_in_guest("mount -t tmpfs none /tmp").unwrap();
+    vm.exec_in_guest("echo foo > /tmp/foo").unwrap();
+
+    let dir = tempdir().unwrap();
+    let socket_path = dir.path().join("migration.sock");
+    let mut destination = TestVm::new_migration_destination(new_config(), &socket_path).unwrap();
+    vm.migrate(&socket_path).unwrap();
+
+    assert_eq!(
+        "foo",
+        destination
+            .exec_in_guest("cat /tmp/foo")
+            .unwrap()
+            .stdout
+            .trim()
+    );
+}
+
 #[test]
 fn snapshot_vhost_user_root() {
     call_test_with_sudo("snapshot_vhost_user")
@@ -808,9 +808,18 @@ impl Vm for KvmVm {
 
     fn get_dirty_log(&self, slot: MemSlot, dirty_log: &mut [u8]) -> Result<()> {
         let regions = self.mem_regions.lock();
-        let mmap = regions.get(&slot).ok_or_else(|| Error::new(ENOENT))?;
+        let size = match regions.get(&slot) {
+            Some(mmap) => mmap.size(),
+            None => {
+                self.guest_mem
+                    .regions()
+                    .find(|region| region.index as MemSlot == slot)
+                    .ok_or_else(|| Error::new(ENOENT))?
+                    .size
+            }
+        };
         // Ensures that there are as many bytes in dirty_log as there are pages in the mmap.
-        if dirty_log_bitmap_size(mmap.size()) > dirty_log.len() {
+        if dirty_log_bitmap_size(size) > dirty_log.len() {
             return Err(Error::new(EINVAL));
         }
 
@@ -830,6 +839,27 @@ impl Vm for KvmVm {
         }
     }
 
+    fn set_guest_memory_dirty_log(&self, enable: bool) -> Result<()> {
+        for region in self.guest_mem.regions() {
+            // SAFETY:
+            // Safe because the region is registered again at the same place, only the flags
+            // change.
+            unsafe {
+                set_user_memory_region(
+                    self,
+                    region.index as MemSlot,
+                    false,
+                    enable,
+                    MemCacheType::CacheCoherent,
+                    region.guest_addr.offset(),
+                    region.size as u64,
+                    region.host_addr as *mut u8,
+                )
+            }?;
+        }
+        Ok(())
+    }
+
     fn register_ioevent(
         &mut self,
         evt: &Event,
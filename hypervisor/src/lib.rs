@@ -218,6 +218,13 @@ pub trait Vm: Send {
     /// be 2 bytes or greater.
     fn get_dirty_log(&self, slot: MemSlot, dirty_log: &mut [u8]) -> Result<()>;
 
+    /// Enables or disables logging of the pages written by the guest to the regions of
+    /// `get_memory`. While enabled, `get_dirty_log` can be called with the index of a region as
+    /// `slot`. Only works on VMs that support `VmCap::DirtyLog`.
+    fn set_guest_memory_dirty_log(&self, _enable: bool) -> Result<()> {
+        Err(std::io::Error::from(std::io::ErrorKind::Unsupported).into())
+    }
+
     /// Registers an event to be signaled whenever a certain address is written to.
     ///
     /// The `datamatch` parameter can be used to limit signaling `evt` to only the cases where the
@@ -34,8 +34,10 @@ use thiserror::Error as ThisError;
 
 #[cfg(feature = "slirp")]
 pub mod slirp;
-#[cfg(all(feature = "slirp", windows))]
+#[cfg(feature = "slirp")]
 pub use slirp::Slirp;
+#[cfg(all(feature = "slirp", any(target_os = "android", target_os = "linux")))]
+pub use slirp::SlirpHost;
 
 #[sorted]
 #[derive(ThisError, Debug)]
@@ -43,6 +45,9 @@ pub enum Error {
     /// Unable to clone tap interface.
     #[error("failed to clone tap interface: {0}")]
     CloneTap(SysError),
+    /// Failed to connect to a socket.
+    #[error("failed to connect to socket: {0}")]
+    ConnectSocket(SysError),
     /// Failed to create a socket.
     #[error("failed to create a socket: {0}")]
     CreateSocket(SysError),
@@ -55,7 +60,7 @@ pub enum Error {
     /// Couldn't open /dev/net/tun.
     #[error("failed to open /dev/net/tun: {0}")]
     OpenTun(SysError),
-    #[cfg(all(feature = "slirp", windows))]
+    #[cfg(feature = "slirp")]
     #[error("slirp related error")]
     Slirp(slirp::SlirpError),
 }
@@ -65,12 +70,13 @@ pub type Result<T> = std::result::Result<T, Error>;
 impl Error {
     pub fn sys_error(&self) -> SysError {
         match self {
+            Error::ConnectSocket(e) => *e,
             Error::CreateSocket(e) => *e,
             Error::OpenTun(e) => *e,
             Error::CreateTap(e) => *e,
             Error::CloneTap(e) => *e,
             Error::IoctlError(e) => *e,
-            #[cfg(all(feature = "slirp", windows))]
+            #[cfg(feature = "slirp")]
             Error::Slirp(e) => e.sys_error(),
         }
     }
@@ -6,8 +6,6 @@
 //! level interfaces to libslirp that are used to implement that loop, and
 //! diagnostic tools.
 
-#![cfg(windows)]
-
 #[path = "../../third_party/libslirp-rs/src/context.rs"]
 pub mod context;
 
@@ -15,16 +13,28 @@ pub mod context;
 pub mod packet_ring_buffer;
 
 pub mod sys;
+use std::fmt;
+use std::fmt::Display;
+use std::net::AddrParseError;
+use std::net::Ipv4Addr;
+use std::num::ParseIntError;
+use std::str::FromStr;
+
 use base::Error as SysError;
 use remain::sorted;
+use serde::Deserialize;
+use serde::Deserializer;
+use serde::Serialize;
+use serde::Serializer;
 pub use sys::Slirp;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub use sys::SlirpHost;
 use thiserror::Error as ThisError;
 
 /// Length includes space for an ethernet frame & the vnet header. See the virtio spec for details:
 /// <http://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-2050006>
 pub const ETHERNET_FRAME_SIZE: usize = 1526;
 
-#[cfg(windows)]
 #[sorted]
 #[derive(ThisError, Debug)]
 pub enum SlirpError {
@@ -32,6 +42,9 @@ pub enum SlirpError {
     BrokenPipe(std::io::Error),
     #[error("failed to clone object: {0}")]
     CloneFailed(std::io::Error),
+    /// The host port of a forward couldn't be bound.
+    #[error("failed to forward host port {0}: {1}")]
+    HostForwardFailed(HostForward, std::io::Error),
     #[error("overlapped operation failed: {0}")]
     OverlappedError(std::io::Error),
     /// Error encountered while in a Slirp related poll operation.
@@ -40,20 +53,197 @@ pub enum SlirpError {
     /// Error encountered while in a Slirp related poll operation.
     #[error("slirp poll failed: {0}")]
     SlirpPollError(SysError),
+    #[cfg(windows)]
     #[error("WSAStartup failed with code: {0}")]
     WSAStartupError(SysError),
 }
 
-#[cfg(windows)]
 impl SlirpError {
     pub fn sys_error(&self) -> SysError {
         match self {
             SlirpError::BrokenPipe(e) => SysError::new(e.raw_os_error().unwrap_or_default()),
             SlirpError::CloneFailed(e) => SysError::new(e.raw_os_error().unwrap_or_default()),
+            SlirpError::HostForwardFailed(_, e) => {
+                SysError::new(e.raw_os_error().unwrap_or_default())
+            }
             SlirpError::OverlappedError(e) => SysError::new(e.raw_os_error().unwrap_or_default()),
             SlirpError::SlirpIOPollError(e) => SysError::new(e.raw_os_error().unwrap_or_default()),
             SlirpError::SlirpPollError(e) => *e,
+            #[cfg(windows)]
             SlirpError::WSAStartupError(e) => *e,
         }
     }
 }
+
+/// Transport protocol of a `HostForward`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HostForwardProtocol {
+    Tcp,
+    Udp,
+}
+
+#[sorted]
+#[derive(ThisError, Debug, PartialEq, Eq)]
+pub enum HostForwardError {
+    /// Failed to parse an IPv4 address.
+    #[error("invalid address: {0}")]
+    InvalidAddress(AddrParseError),
+    /// The forward isn't of the form `PROTOCOL:[HOST_ADDR]:HOST_PORT-[GUEST_ADDR]:GUEST_PORT`.
+    #[error("invalid host forward `{0}`, expected PROTOCOL:[HOST_ADDR]:HOST_PORT-[GUEST_ADDR]:GUEST_PORT")]
+    InvalidFormat(String),
+    /// Failed to parse a port number.
+    #[error("invalid port: {0}")]
+    InvalidPort(ParseIntError),
+    /// The protocol is neither `tcp` nor `udp`.
+    #[error("invalid protocol `{0}`, expected tcp or udp")]
+    InvalidProtocol(String),
+}
+
+/// A port on the host that is forwarded to a port of the guest.
+///
+/// Forwards use the syntax of QEMU's `hostfwd` option:
+/// `PROTOCOL:[HOST_ADDR]:HOST_PORT-[GUEST_ADDR]:GUEST_PORT`. An empty host address listens on all
+/// of the host's interfaces, and an empty guest address forwards to the first address handed out
+/// by the DHCP server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HostForward {
+    pub protocol: HostForwardProtocol,
+    pub host_addr: Ipv4Addr,
+    pub host_port: u16,
+    pub guest_addr: Ipv4Addr,
+    pub guest_port: u16,
+}
+
+/// Parses one side of a `HostForward`, i.e. `[ADDR]:PORT`.
+fn parse_host_forward_endpoint(s: &str) -> Result<(Ipv4Addr, u16), HostForwardError> {
+    let (addr, port) = s
+        .rsplit_once(':')
+        .ok_or_else(|| HostForwardError::InvalidFormat(s.to_owned()))?;
+    let addr = if addr.is_empty() {
+        Ipv4Addr::UNSPECIFIED
+    } else {
+        addr.parse().map_err(HostForwardError::InvalidAddress)?
+    };
+    let port = port.parse().map_err(HostForwardError::InvalidPort)?;
+    Ok((addr, port))
+}
+
+impl FromStr for HostForward {
+    type Err = HostForwardError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (protocol, endpoints) = s
+            .split_once(':')
+            .ok_or_else(|| HostForwardError::InvalidFormat(s.to_owned()))?;
+        let protocol = match protocol {
+            "tcp" => HostForwardProtocol::Tcp,
+            "udp" => HostForwardProtocol::Udp,
+            p => return Err(HostForwardError::InvalidProtocol(p.to_owned())),
+        };
+        let (host, guest) = endpoints
+            .split_once('-')
+            .ok_or_else(|| HostForwardError::InvalidFormat(s.to_owned()))?;
+        let (host_addr, host_port) = parse_host_forward_endpoint(host)?;
+        let (guest_addr, guest_port) = parse_host_forward_endpoint(guest)?;
+
+        Ok(HostForward {
+            protocol,
+            host_addr,
+            host_port,
+            guest_addr,
+            guest_port,
+        })
+    }
+}
+
+impl Display for HostForward {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let protocol = match self.protocol {
+            HostForwardProtocol::Tcp => "tcp",
+            HostForwardProtocol::Udp => "udp",
+        };
+        write!(
+            f,
+            "{}:{}:{}-{}:{}",
+            protocol, self.host_addr, self.host_port, self.guest_addr, self.guest_port
+        )
+    }
+}
+
+impl<'de> Deserialize<'de> for HostForward {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        FromStr::from_str(&s).map_err(serde::de::Error::custom)
+    }
+}
+
+impl Serialize for HostForward {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(&self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_host_forward() {
+        assert_eq!(
+            "tcp::2222-:22".parse::<HostForward>().unwrap(),
+            HostForward {
+                protocol: HostForwardProtocol::Tcp,
+                host_addr: Ipv4Addr::UNSPECIFIED,
+                host_port: 2222,
+                guest_addr: Ipv4Addr::UNSPECIFIED,
+                guest_port: 22,
+            }
+        );
+        assert_eq!(
+            "udp:127.0.0.1:5353-10.0.2.15:53"
+                .parse::<HostForward>()
+                .unwrap(),
+            HostForward {
+                protocol: HostForwardProtocol::Udp,
+                host_addr: Ipv4Addr::new(127, 0, 0, 1),
+                host_port: 5353,
+                guest_addr: Ipv4Addr::new(10, 0, 2, 15),
+                guest_port: 53,
+            }
+        );
+
+        assert!(matches!(
+            "sctp::1-:1".parse::<HostForward>(),
+            Err(HostForwardError::InvalidProtocol(_))
+        ));
+        assert!(matches!(
+            "tcp::2222".parse::<HostForward>(),
+            Err(HostForwardError::InvalidFormat(_))
+        ));
+        assert!(matches!(
+            "tcp:2222-22".parse::<HostForward>(),
+            Err(HostForwardError::InvalidFormat(_))
+        ));
+        assert!(matches!(
+            "tcp::65536-:22".parse::<HostForward>(),
+            Err(HostForwardError::InvalidPort(_))
+        ));
+        assert!(matches!(
+            "tcp:localhost:2222-:22".parse::<HostForward>(),
+            Err(HostForwardError::InvalidAddress(_))
+        ));
+    }
+
+    #[test]
+    fn host_forward_round_trip() {
+        let forward: HostForward = "tcp:127.0.0.1:8080-:80".parse().unwrap();
+        assert_eq!(forward.to_string(), "tcp:127.0.0.1:8080-0.0.0.0:80");
+        assert_eq!(forward.to_string().parse::<HostForward>().unwrap(), forward);
+    }
+}
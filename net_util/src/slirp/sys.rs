@@ -3,13 +3,18 @@
 // found in the LICENSE file.
 
 cfg_if::cfg_if! {
-    if #[cfg(windows)] {
+    if #[cfg(any(target_os = "android", target_os = "linux"))] {
+        pub mod linux;
+        use linux as platform;
+    } else if #[cfg(windows)] {
         pub mod windows;
         use windows as platform;
     } else {
-        compile_error!("Unsupported platform (slirp supported only on Windows)");
+        compile_error!("Unsupported platform (slirp supported only on Linux and Windows)");
     }
 }
 
 pub use platform::handler;
 pub use platform::Slirp;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub use platform::SlirpHost;
@@ -0,0 +1,245 @@
+// Copyright 2025 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+pub mod handler;
+
+use std::io::Read;
+use std::io::Result as IoResult;
+use std::io::Write;
+use std::net;
+use std::os::raw::*;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::io::RawFd;
+
+use base::volatile_impl;
+use base::warn;
+use base::AsRawDescriptor;
+use base::Error as SysError;
+use base::FileReadWriteVolatile;
+use base::RawDescriptor;
+use base::ReadNotifier;
+use base::UnixSeqpacket;
+use cros_async::IntoAsync;
+
+use crate::slirp::HostForward;
+use crate::slirp::SlirpError;
+use crate::sys::linux::TapTLinux;
+use crate::Error;
+use crate::MacAddress;
+use crate::Result;
+use crate::TapT;
+use crate::TapTCommon;
+
+/// MTU of the virtual network; libslirp uses the same default.
+pub const SLIRP_MTU: u16 = 1500;
+
+/// Handle for a pseudo-tap interface backed by libslirp.
+///
+/// Frames are exchanged with a libslirp instance, run by the matching [`SlirpHost`], over a
+/// `SOCK_SEQPACKET` socket pair, so each read or write carries exactly one frame, prefixed with a
+/// virtio-net header like on a tap with `IFF_VNET_HDR`.
+pub struct Slirp {
+    guest_socket: UnixSeqpacket,
+}
+
+impl Slirp {
+    /// Creates a pseudo-tap interface and the libslirp instance that forwards `host_forwards` to
+    /// the guest through it.
+    ///
+    /// No frames flow until [`SlirpHost::run`] is called. The libslirp instance is meant to run
+    /// in its own process so the caller doesn't become multithreaded.
+    pub fn new(
+        host_forwards: &[HostForward],
+        #[cfg(any(feature = "slirp-ring-capture", feature = "slirp-debug"))]
+        slirp_capture_file: Option<String>,
+    ) -> Result<(Slirp, SlirpHost)> {
+        let (host_socket, guest_socket) = UnixSeqpacket::pair()
+            .map_err(SysError::from)
+            .map_err(Error::CreateSocket)?;
+        for socket in [&host_socket, &guest_socket] {
+            socket
+                .set_nonblocking(true)
+                .map_err(SysError::from)
+                .map_err(Error::CreateSocket)?;
+        }
+
+        Ok((
+            Slirp { guest_socket },
+            SlirpHost {
+                host_socket,
+                host_forwards: host_forwards.to_vec(),
+                #[cfg(any(feature = "slirp-ring-capture", feature = "slirp-debug"))]
+                slirp_capture_file,
+            },
+        ))
+    }
+}
+
+/// Host end of a [`Slirp`] pseudo-tap interface, which runs libslirp.
+pub struct SlirpHost {
+    host_socket: UnixSeqpacket,
+    host_forwards: Vec<HostForward>,
+    #[cfg(any(feature = "slirp-ring-capture", feature = "slirp-debug"))]
+    slirp_capture_file: Option<String>,
+}
+
+impl SlirpHost {
+    /// Returns the descriptors that must be kept open when running `self` in a child process.
+    pub fn as_raw_descriptors(&self) -> Vec<RawDescriptor> {
+        vec![self.host_socket.as_raw_descriptor()]
+    }
+
+    /// Runs libslirp until every handle to the guest end of the pseudo-tap is closed.
+    ///
+    /// `on_started` is called once libslirp is set up, before any frame is processed. Errors
+    /// while setting it up are returned without calling `on_started`.
+    pub fn run(self, on_started: impl FnOnce()) -> Result<()> {
+        let disable_access_to_host = !cfg!(feature = "guest-to-host-net-loopback");
+        let (context, host_socket) = handler::create_slirp_context(
+            self.host_socket,
+            disable_access_to_host,
+            &self.host_forwards,
+            #[cfg(any(feature = "slirp-ring-capture", feature = "slirp-debug"))]
+            self.slirp_capture_file,
+        )?;
+        on_started();
+        match handler::run_slirp(context, host_socket) {
+            Err(Error::Slirp(SlirpError::BrokenPipe(e))) => {
+                warn!("exited slirp listening loop: {}", e);
+                Ok(())
+            }
+            r => r,
+        }
+    }
+}
+
+impl TapT for Slirp {}
+
+// Slirp isn't a host interface, so it has no address, netmask or MAC address to configure.
+impl TapTCommon for Slirp {
+    fn new_with_name(_name: &[u8], _vnet_hdr: bool, _multi_vq: bool) -> Result<Self> {
+        Err(Error::IoctlError(SysError::new(libc::ENOTSUP)))
+    }
+
+    fn new(_vnet_hdr: bool, _multi_vq: bool) -> Result<Slirp> {
+        Err(Error::IoctlError(SysError::new(libc::ENOTSUP)))
+    }
+
+    fn into_mq_taps(self, vq_pairs: u16) -> Result<Vec<Self>> {
+        // libslirp is single threaded; only one vq pair is supported.
+        if vq_pairs != 1 {
+            return Err(Error::IoctlError(SysError::new(libc::EINVAL)));
+        }
+
+        Ok(vec![self])
+    }
+
+    fn ip_addr(&self) -> Result<net::Ipv4Addr> {
+        Err(Error::IoctlError(SysError::new(libc::ENOTSUP)))
+    }
+
+    fn set_ip_addr(&self, _ip_addr: net::Ipv4Addr) -> Result<()> {
+        Err(Error::IoctlError(SysError::new(libc::ENOTSUP)))
+    }
+
+    fn netmask(&self) -> Result<net::Ipv4Addr> {
+        Err(Error::IoctlError(SysError::new(libc::ENOTSUP)))
+    }
+
+    fn set_netmask(&self, _netmask: net::Ipv4Addr) -> Result<()> {
+        Err(Error::IoctlError(SysError::new(libc::ENOTSUP)))
+    }
+
+    fn mtu(&self) -> Result<u16> {
+        Ok(SLIRP_MTU)
+    }
+
+    fn set_mtu(&self, _mtu: u16) -> Result<()> {
+        Err(Error::IoctlError(SysError::new(libc::ENOTSUP)))
+    }
+
+    fn mac_address(&self) -> Result<MacAddress> {
+        Err(Error::IoctlError(SysError::new(libc::ENOTSUP)))
+    }
+
+    fn set_mac_address(&self, _mac_addr: MacAddress) -> Result<()> {
+        Err(Error::IoctlError(SysError::new(libc::ENOTSUP)))
+    }
+
+    fn set_offload(&self, flags: c_uint) -> Result<()> {
+        // Slirp does not support offload. The guest picks the flags, so this must not panic.
+        if flags != 0 {
+            return Err(Error::IoctlError(SysError::new(libc::EINVAL)));
+        }
+        Ok(())
+    }
+
+    fn enable(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn try_clone(&self) -> Result<Self> {
+        Ok(Slirp {
+            guest_socket: self
+                .guest_socket
+                .try_clone()
+                .map_err(|e| Error::Slirp(SlirpError::CloneFailed(e)))?,
+        })
+    }
+
+    unsafe fn from_raw_descriptor(_descriptor: RawDescriptor) -> Result<Self> {
+        Err(Error::IoctlError(SysError::new(libc::ENOTSUP)))
+    }
+}
+
+impl TapTLinux for Slirp {
+    fn set_vnet_hdr_size(&self, size: usize) -> Result<()> {
+        // The handler always exchanges frames with a 12 byte virtio-net header.
+        if size != handler::VETH_HEADER_LENGTH {
+            return Err(Error::IoctlError(SysError::new(libc::EINVAL)));
+        }
+        Ok(())
+    }
+
+    fn if_flags(&self) -> u32 {
+        net_sys::IFF_TAP | net_sys::IFF_NO_PI | net_sys::IFF_VNET_HDR
+    }
+}
+
+impl Read for Slirp {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        self.guest_socket.recv(buf)
+    }
+}
+
+impl Write for Slirp {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        self.guest_socket.send(buf)
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+}
+
+impl AsRawFd for Slirp {
+    fn as_raw_fd(&self) -> RawFd {
+        self.guest_socket.as_raw_descriptor()
+    }
+}
+
+impl AsRawDescriptor for Slirp {
+    fn as_raw_descriptor(&self) -> RawDescriptor {
+        self.guest_socket.as_raw_descriptor()
+    }
+}
+
+impl ReadNotifier for Slirp {
+    fn get_read_notifier(&self) -> &dyn AsRawDescriptor {
+        self
+    }
+}
+
+impl IntoAsync for Slirp {}
+volatile_impl!(Slirp);
@@ -0,0 +1,502 @@
+// Copyright 2025 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::collections::HashMap;
+#[cfg(any(feature = "slirp-ring-capture", feature = "slirp-debug"))]
+use std::fs::File;
+use std::io;
+#[cfg(any(feature = "slirp-ring-capture", feature = "slirp-debug"))]
+use std::io::BufWriter;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use std::time::Duration;
+use std::time::Instant;
+
+#[cfg(any(feature = "slirp-ring-capture", feature = "slirp-debug"))]
+use base::error;
+use base::handle_eintr_errno;
+use base::warn;
+use base::AsRawDescriptor;
+use base::Error as SysError;
+use base::RawDescriptor;
+use base::Timer;
+use base::TimerTrait;
+use base::UnixSeqpacket;
+use libc::c_short;
+use libc::pollfd;
+use libc::POLLERR;
+use libc::POLLHUP;
+use libc::POLLIN;
+use libc::POLLOUT;
+use libc::POLLPRI;
+#[cfg(any(feature = "slirp-ring-capture", feature = "slirp-debug"))]
+use pcap_file::pcap::PcapWriter;
+use virtio_sys::virtio_net::virtio_net_hdr;
+use virtio_sys::virtio_net::virtio_net_hdr_mrg_rxbuf;
+use zerocopy::IntoBytes;
+
+use crate::slirp::context::CallbackHandler;
+use crate::slirp::context::Context;
+use crate::slirp::context::PollEvents;
+#[cfg(feature = "slirp-ring-capture")]
+use crate::slirp::packet_ring_buffer::PacketRingBuffer;
+use crate::slirp::HostForward;
+use crate::slirp::SlirpError;
+use crate::slirp::ETHERNET_FRAME_SIZE;
+use crate::Error;
+use crate::Result;
+
+#[cfg(feature = "slirp-debug")]
+const SLIRP_CAPTURE_FILE_NAME: &str = "slirp_capture.pcap";
+
+#[cfg(any(feature = "slirp-ring-capture", feature = "slirp-debug"))]
+const PCAP_FILE_BUFFER_SIZE: usize = 1024 * 1024; // 1MiB
+
+pub const VETH_HEADER_LENGTH: usize = 12;
+
+#[cfg(feature = "slirp-ring-capture")]
+const PACKET_RING_BUFFER_SIZE_IN_BYTES: usize = 30000000; // 30MBs
+
+type TimerCallback = Box<dyn FnMut()>;
+
+pub struct Handler {
+    start: Instant,
+    socket: UnixSeqpacket,
+    buf: [u8; ETHERNET_FRAME_SIZE],
+    // Stores a handle to each timer and its callback. Note that Timer ownership is held by
+    // libslirp, and created/released via `timer_new` and `timer_free`.
+    timer_callbacks: HashMap<RawDescriptor, (Timer, TimerCallback)>,
+    #[allow(unused)]
+    handler_debug: Option<HandlerDebug>,
+}
+
+/// Additional fields that exist only when debugging the slirp connection.
+#[cfg_attr(
+    not(any(feature = "slirp-ring-capture", feature = "slirp-debug")),
+    allow(dead_code)
+)]
+struct HandlerDebug {
+    #[cfg(any(feature = "slirp-ring-capture", feature = "slirp-debug"))]
+    pcap_writer: PcapWriter<BufWriter<File>>,
+    #[cfg(feature = "slirp-ring-capture")]
+    tx_packet_ring_buffer: PacketRingBuffer,
+    #[cfg(feature = "slirp-ring-capture")]
+    rx_packet_ring_buffer: PacketRingBuffer,
+}
+
+impl CallbackHandler for Handler {
+    type Timer = base::Timer;
+
+    fn clock_get_ns(&mut self) -> i64 {
+        self.start.elapsed().as_nanos() as i64
+    }
+
+    /// Sends a packet to the guest.
+    fn send_packet(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let vnet_hdr = virtio_net_hdr_mrg_rxbuf {
+            hdr: virtio_net_hdr {
+                flags: 0,
+                gso_size: 0,
+                hdr_len: 0,
+                csum_start: 0,
+                csum_offset: 0,
+                gso_type: virtio_sys::virtio_net::VIRTIO_NET_HDR_GSO_NONE as u8,
+            },
+            num_buffers: 1,
+        };
+        let send_buf = [vnet_hdr.as_bytes(), buf].concat();
+
+        #[allow(unused)]
+        if let Some(handler_debug) = self.handler_debug.as_mut() {
+            let d = self.start.elapsed();
+            #[cfg(feature = "slirp-debug")]
+            {
+                handler_debug
+                    .pcap_writer
+                    .write(d.as_secs() as u32, d.subsec_nanos(), buf, buf.len() as u32)
+                    .unwrap();
+            }
+            #[cfg(feature = "slirp-ring-capture")]
+            {
+                handler_debug
+                    .tx_packet_ring_buffer
+                    .add_packet(buf, d)
+                    .expect("Failed to add packet.");
+            }
+        }
+
+        match self.socket.send(&send_buf) {
+            // The guest isn't keeping up with its receive queue; drop the frame like a NIC would.
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(buf.len()),
+            Err(e) => Err(e),
+            Ok(_) => Ok(buf.len()),
+        }
+    }
+
+    // Not required per https://github.com/rootless-containers/slirp4netns/blob/7f6a4a654a84d4356c881a10417bab77fd5be325/slirp4netns.c
+    fn register_poll_fd(&mut self, _fd: i32) {}
+    fn unregister_poll_fd(&mut self, _fd: i32) {}
+
+    fn guest_error(&mut self, msg: &str) {
+        warn!("guest error: {}", msg);
+    }
+
+    // Not required per https://github.com/rootless-containers/slirp4netns/blob/7f6a4a654a84d4356c881a10417bab77fd5be325/slirp4netns.c
+    fn notify(&mut self) {}
+
+    fn timer_new(&mut self, callback: Box<dyn FnMut()>) -> Box<Self::Timer> {
+        let timer = Timer::new().expect("failed to create network timer");
+        let handle = timer.try_clone().expect("failed to clone network timer");
+        self.timer_callbacks
+            .insert(timer.as_raw_descriptor(), (handle, callback));
+        Box::new(timer)
+    }
+
+    fn timer_mod(&mut self, timer: &mut Self::Timer, expire_time: i64) {
+        // expire_time is a clock_get_ns relative deadline in milliseconds. A zero duration would
+        // disarm the timer, so deadlines that have passed fire as soon as possible instead.
+        let timer_duration = Duration::from_millis(expire_time as u64)
+            .saturating_sub(self.start.elapsed())
+            .max(Duration::from_nanos(1));
+
+        timer
+            .reset_oneshot(timer_duration)
+            .expect("failed to modify network timer");
+    }
+
+    fn timer_free(&mut self, timer: Box<Self::Timer>) {
+        self.timer_callbacks.remove(&timer.as_raw_descriptor());
+        // The actual Timer is freed implicitly by the Box drop.
+    }
+
+    fn get_timers(&self) -> Box<dyn Iterator<Item = &RawDescriptor> + '_> {
+        Box::new(self.timer_callbacks.keys())
+    }
+
+    fn execute_timer(&mut self, timer: RawDescriptor) {
+        let (handle, timer_callback) = self
+            .timer_callbacks
+            .get_mut(&timer)
+            .expect("tried to run timer that has no callback");
+        if let Err(e) = handle.mark_waited() {
+            warn!("failed to read network timer: {}", e);
+        }
+        timer_callback()
+    }
+
+    fn begin_read_from_guest(&mut self) -> io::Result<()> {
+        // Frames are read synchronously in `end_read_from_guest`.
+        Ok(())
+    }
+
+    fn end_read_from_guest(&mut self) -> io::Result<&[u8]> {
+        match self.socket.recv(&mut self.buf) {
+            Ok(0) => Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "the guest's virtio-net frontend was closed",
+            )),
+            Ok(len) if len >= VETH_HEADER_LENGTH => {
+                // Skip over the veth header (12 bytes, created by the frontend per the
+                // virtio spec).
+                let ethernet_pkt = &self.buf[VETH_HEADER_LENGTH..len];
+
+                #[allow(unused)]
+                if let Some(handler_debug) = self.handler_debug.as_mut() {
+                    let d = self.start.elapsed();
+
+                    #[cfg(feature = "slirp-debug")]
+                    {
+                        handler_debug
+                            .pcap_writer
+                            .write(
+                                d.as_secs() as u32,
+                                d.subsec_nanos(),
+                                ethernet_pkt,
+                                ethernet_pkt.len() as u32,
+                            )
+                            .unwrap();
+                    }
+                    #[cfg(feature = "slirp-ring-capture")]
+                    {
+                        handler_debug
+                            .rx_packet_ring_buffer
+                            .add_packet(ethernet_pkt, d)
+                            .expect("Failed to add packet.");
+                    }
+                };
+
+                Ok(ethernet_pkt)
+            }
+            Ok(len) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Too few bytes ({}) read from the guest's virtio-net frontend.",
+                    len
+                ),
+            )),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(feature = "slirp-ring-capture")]
+impl Drop for Handler {
+    fn drop(&mut self) {
+        if let Some(handler_debug) = &mut self.handler_debug {
+            let packets = PacketRingBuffer::pop_ring_buffers_and_aggregate(
+                &mut handler_debug.rx_packet_ring_buffer,
+                &mut handler_debug.tx_packet_ring_buffer,
+            );
+
+            for packet in packets {
+                handler_debug
+                    .pcap_writer
+                    .write(
+                        packet.timestamp.as_secs() as u32,
+                        packet.timestamp.subsec_nanos(),
+                        &packet.buf,
+                        packet.buf.len() as u32,
+                    )
+                    .unwrap()
+            }
+        }
+    }
+}
+
+fn poll_events_from_slirp_events(events: PollEvents) -> c_short {
+    let mut poll_events = 0;
+    if events.has_in() {
+        poll_events |= POLLIN;
+    }
+    if events.has_out() {
+        poll_events |= POLLOUT;
+    }
+    if events.has_pri() {
+        poll_events |= POLLPRI;
+    }
+    // POLLERR and POLLHUP are always reported by poll(2).
+    poll_events
+}
+
+fn slirp_events_from_poll_events(events: c_short) -> PollEvents {
+    let mut slirp_events = PollEvents::empty();
+    if events & POLLIN != 0 {
+        slirp_events |= PollEvents::poll_in();
+    }
+    if events & POLLOUT != 0 {
+        slirp_events |= PollEvents::poll_out();
+    }
+    if events & POLLPRI != 0 {
+        slirp_events |= PollEvents::poll_pri();
+    }
+    if events & POLLERR != 0 {
+        slirp_events |= PollEvents::poll_err();
+    }
+    if events & POLLHUP != 0 {
+        slirp_events |= PollEvents::poll_hup();
+    }
+    slirp_events
+}
+
+/// Waits until one of `fds` is ready or `timeout_ms` elapsed.
+fn poll(fds: &mut [pollfd], timeout_ms: u32) -> Result<()> {
+    // Timeouts that don't fit a c_int wait forever, libslirp uses u32::MAX for that.
+    let timeout = i32::try_from(timeout_ms).unwrap_or(-1);
+    // SAFETY:
+    // Safe because fds is a valid slice of pollfd structs and we check the return value.
+    let ret = handle_eintr_errno!(unsafe {
+        libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout)
+    });
+    if ret < 0 {
+        return Err(Error::Slirp(SlirpError::SlirpPollError(SysError::last())));
+    }
+    Ok(())
+}
+
+/// Runs libslirp's main loop until the guest end of `host_socket` is closed.
+///
+/// Packets are exchanged between `host_socket` and the host's network stack.
+pub fn run_slirp(mut context: Box<Context<Handler>>, host_socket: RawDescriptor) -> Result<()> {
+    loop {
+        // Request the FDs that we should poll from Slirp. Each FD is assigned the index of its
+        // pollfd, which libslirp passes back when asking for the FD's events in `pollfds_poll`.
+        let mut poll_fds = Vec::new();
+        // We'd like to sleep as long as possible (assuming no actionable notifications arrive).
+        let mut timeout_ms: u32 = u32::MAX;
+        context.pollfds_fill(&mut timeout_ms, |fd: i32, events: PollEvents| {
+            poll_fds.push(pollfd {
+                fd,
+                events: poll_events_from_slirp_events(events),
+                revents: 0,
+            });
+            (poll_fds.len() - 1) as i32
+        });
+        let slirp_fd_count = poll_fds.len();
+
+        poll_fds.push(pollfd {
+            fd: host_socket,
+            events: POLLIN,
+            revents: 0,
+        });
+        poll_fds.extend(context.get_timers().map(|timer| pollfd {
+            fd: *timer,
+            events: POLLIN,
+            revents: 0,
+        }));
+
+        poll(&mut poll_fds, timeout_ms)?;
+
+        for ready in poll_fds[slirp_fd_count..]
+            .iter()
+            .filter(|fd| fd.revents != 0)
+        {
+            if ready.fd == host_socket {
+                // Collect input from the guest & inject into Slirp. It seems that this input
+                // step should be between pollfds_fill & pollfds_poll.
+                context.handle_guest_input()?;
+            } else {
+                // All other descriptors are timers.
+                context.execute_timer(ready.fd);
+            }
+        }
+
+        // It's possible no socket notified and we got here from a timeout. This is fine, because
+        // libslirp wants to be woken up if timeout has expired (even if no sockets are ready).
+        context.pollfds_poll(false, |fd_index: i32| {
+            slirp_events_from_poll_events(poll_fds[fd_index as usize].revents)
+        })
+    }
+}
+
+/// Creates a libslirp context exchanging packets over `host_socket`, which must be non blocking.
+///
+/// Returns the context and the descriptor of `host_socket` to poll for packets from the guest.
+pub fn create_slirp_context(
+    host_socket: UnixSeqpacket,
+    disable_access_to_host: bool,
+    host_forwards: &[HostForward],
+    #[cfg(any(feature = "slirp-ring-capture", feature = "slirp-debug"))] slirp_capture_file: Option<
+        String,
+    >,
+) -> Result<(Box<Context<Handler>>, RawDescriptor)> {
+    // Set up handler_debug:
+    // - If slirp-debug is used, write to SLIRP_CAPTURE_FILE_NAME if slirp_capture_file not set.
+    // - If slirp-ring-capture is used, write to slirp_capture_file.
+    //     - If slirp_capture_file not set, don't debug.
+    // - Otherwise, set to None.
+    cfg_if::cfg_if! {
+        if #[cfg(any(feature = "slirp-ring-capture", feature = "slirp-debug"))] {
+            #[cfg(feature = "slirp-ring-capture")]
+            let capture_path = slirp_capture_file;
+            #[cfg(feature = "slirp-debug")]
+            let capture_path = slirp_capture_file.or(Some(SLIRP_CAPTURE_FILE_NAME.to_owned()));
+
+            let handler_debug = capture_path
+                .as_ref()
+                .map(File::create)
+                .transpose()
+                .unwrap_or_default()
+                .map(|capture_file| HandlerDebug {
+                    pcap_writer: PcapWriter::new(BufWriter::with_capacity(
+                        PCAP_FILE_BUFFER_SIZE,
+                        capture_file,
+                    ))
+                    .unwrap(),
+                    #[cfg(feature = "slirp-ring-capture")]
+                    tx_packet_ring_buffer: PacketRingBuffer::new(PACKET_RING_BUFFER_SIZE_IN_BYTES),
+                    #[cfg(feature = "slirp-ring-capture")]
+                    rx_packet_ring_buffer: PacketRingBuffer::new(PACKET_RING_BUFFER_SIZE_IN_BYTES),
+                });
+
+            if capture_path.is_some() && handler_debug.is_none() {
+                error!("Failed to start packet capture! Check provided file path or sandboxing?");
+            }
+        } else {
+            let handler_debug = None;
+        }
+    }
+
+    let host_socket_descriptor = host_socket.as_raw_descriptor();
+    let handler = Handler {
+        start: Instant::now(),
+        socket: host_socket,
+        buf: [0; ETHERNET_FRAME_SIZE],
+        timer_callbacks: HashMap::new(),
+        handler_debug,
+    };
+
+    // The virtual network is laid out like QEMU's user mode networking: the host is reachable at
+    // 10.0.2.2 (if access to the host is enabled), libslirp's DNS proxy at 10.0.2.3 and the DHCP
+    // server hands out addresses starting at 10.0.2.15.
+    let v4_network_addr = Ipv4Addr::new(10, 0, 2, 0);
+    let v4_network_mask = Ipv4Addr::new(255, 255, 255, 0);
+    let host_v4_addr = Ipv4Addr::new(10, 0, 2, 2);
+    let dns_addr = Ipv4Addr::new(10, 0, 2, 3);
+    let dhcp_start_addr = Ipv4Addr::new(10, 0, 2, 15);
+    let v6_network_addr = Ipv6Addr::new(0xfd13, 0x6246, 0x3218, 0x0001, 0, 0, 0, 0);
+    let v6_host_addr = Ipv6Addr::new(0xfd13, 0x6246, 0x3218, 0x0001, 0, 0, 0, 2);
+    let v6_dns_addr = Ipv6Addr::new(0xfd13, 0x6246, 0x3218, 0x0001, 0, 0, 0, 3);
+
+    let mut context = Context::new(
+        disable_access_to_host,
+        /* IPv4 enabled */
+        true,
+        v4_network_addr,
+        v4_network_mask,
+        host_v4_addr,
+        /* IPv6 enabled */ true,
+        v6_network_addr,
+        /* virtual_network_v6_prefix_len */ 64,
+        /* host_v6_address */ v6_host_addr,
+        /* host_hostname */ None,
+        dhcp_start_addr,
+        dns_addr,
+        /* dns_server_v6_addr */ v6_dns_addr,
+        /* virtual_network_dns_search_domains */ Vec::new(),
+        /* dns_server_domain_name */ None,
+        handler,
+    )?;
+
+    for forward in host_forwards {
+        context.add_host_forward(forward)?;
+    }
+
+    Ok((context, host_socket_descriptor))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn poll_event_conversion() {
+        let events = PollEvents::poll_in() | PollEvents::poll_out();
+        assert_eq!(poll_events_from_slirp_events(events), POLLIN | POLLOUT);
+
+        let revents = slirp_events_from_poll_events(POLLIN | POLLHUP);
+        assert!(revents.has_in());
+        assert!(revents.has_hup());
+        assert!(!revents.has_out());
+    }
+
+    #[test]
+    fn slirp_stops_when_guest_closes() {
+        let (host_socket, guest_socket) = UnixSeqpacket::pair().unwrap();
+        host_socket.set_nonblocking(true).unwrap();
+        let (context, host_socket) = create_slirp_context(
+            host_socket,
+            /* disable_access_to_host= */ true,
+            &[],
+            #[cfg(any(feature = "slirp-ring-capture", feature = "slirp-debug"))]
+            None,
+        )
+        .expect("failed to create slirp context");
+        drop(guest_socket);
+
+        match run_slirp(context, host_socket) {
+            Err(Error::Slirp(SlirpError::BrokenPipe(_))) => {}
+            r => panic!("unexpected slirp loop result: {:?}", r.err()),
+        }
+    }
+}
@@ -0,0 +1,596 @@
+// Copyright 2025 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::io;
+use std::io::Read;
+use std::io::Result as IoResult;
+use std::io::Write;
+use std::mem;
+use std::net;
+use std::os::fd::OwnedFd;
+use std::os::raw::*;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::io::RawFd;
+use std::os::unix::net::UnixDatagram;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+
+use base::handle_eintr_errno;
+use base::AsRawDescriptor;
+use base::Error as SysError;
+use base::FileReadWriteVolatile;
+use base::FromRawDescriptor;
+use base::RawDescriptor;
+use base::ReadNotifier;
+use base::SafeDescriptor;
+use base::VolatileSlice;
+use cros_async::IntoAsync;
+
+use crate::sys::linux::TapTLinux;
+use crate::Error;
+use crate::MacAddress;
+use crate::Result;
+use crate::TapT;
+use crate::TapTCommon;
+
+/// Size of the virtio-net header exchanged with the device, which the peer never sees.
+pub const SOCKET_VNET_HDR_LEN: usize = 12;
+
+/// MTU advertised to the guest. The peer is expected to forward frames of this size.
+pub const SOCKET_MTU: u16 = 1500;
+
+/// Largest frame that can be received, well above the size of any frame at `SOCKET_MTU`.
+const MAX_FRAME_SIZE: usize = u16::MAX as usize;
+
+/// Size of the length prefix of each frame on stream sockets.
+const STREAM_LEN_SIZE: usize = mem::size_of::<u32>();
+
+/// How frames are delimited on the socket.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SocketFraming {
+    /// Each frame is prefixed with its length as a big-endian `u32`, like QEMU's `stream` netdev.
+    Stream,
+    /// Each datagram carries exactly one frame, like QEMU's `dgram` netdev.
+    Datagram,
+}
+
+/// Handle for a pseudo-tap interface that exchanges Ethernet frames with a peer over a socket.
+///
+/// This lets multiple VMs be connected to each other, e.g. through a userspace switch process,
+/// without any host network configuration. Frames on the socket carry no virtio-net header, so the
+/// header is added and stripped here and no offloads are supported.
+pub struct SocketTap {
+    socket: SafeDescriptor,
+    framing: SocketFraming,
+    // Room for the virtio-net header followed by a received frame. On stream sockets, the length
+    // prefix is received right before the frame, overlapping the end of the header.
+    rx_buf: Box<[u8]>,
+    // Number of bytes of the length prefix and frame currently being received on a stream socket.
+    rx_stream_len: usize,
+    tx_buf: Vec<u8>,
+}
+
+impl SocketTap {
+    /// Connects to the Unix socket at `path`, which may be either a stream or a datagram socket.
+    pub fn connect<P: AsRef<Path>>(path: P) -> Result<SocketTap> {
+        let path = path.as_ref();
+        match UnixStream::connect(path) {
+            Ok(stream) => Self::new(OwnedFd::from(stream).into(), SocketFraming::Stream),
+            Err(e) if e.raw_os_error() == Some(libc::EPROTOTYPE) => {
+                let datagram = UnixDatagram::unbound()
+                    .map_err(SysError::from)
+                    .map_err(Error::CreateSocket)?;
+                autobind(&datagram)?;
+                datagram
+                    .connect(path)
+                    .map_err(SysError::from)
+                    .map_err(Error::ConnectSocket)?;
+                Self::new(OwnedFd::from(datagram).into(), SocketFraming::Datagram)
+            }
+            Err(e) => Err(Error::ConnectSocket(e.into())),
+        }
+    }
+
+    /// Uses an already connected socket, e.g. one end of a `socketpair`.
+    pub fn from_socket(socket: SafeDescriptor) -> Result<SocketTap> {
+        let mut socket_type: c_int = 0;
+        let mut len = mem::size_of::<c_int>() as libc::socklen_t;
+        // SAFETY:
+        // Safe because we give a valid pointer and length for an int and check the return value.
+        let ret = unsafe {
+            libc::getsockopt(
+                socket.as_raw_descriptor(),
+                libc::SOL_SOCKET,
+                libc::SO_TYPE,
+                &mut socket_type as *mut c_int as *mut c_void,
+                &mut len,
+            )
+        };
+        if ret < 0 {
+            return Err(Error::CreateSocket(SysError::last()));
+        }
+        let framing = match socket_type {
+            libc::SOCK_STREAM => SocketFraming::Stream,
+            libc::SOCK_DGRAM | libc::SOCK_SEQPACKET => SocketFraming::Datagram,
+            _ => return Err(Error::CreateSocket(SysError::new(libc::EPROTOTYPE))),
+        };
+        Self::new(socket, framing)
+    }
+
+    fn new(socket: SafeDescriptor, framing: SocketFraming) -> Result<SocketTap> {
+        // Reads never block thanks to `MSG_DONTWAIT`, but writes on stream sockets must, since a
+        // partially written frame would corrupt the stream.
+        // SAFETY:
+        // Safe because the descriptor is valid and we check the return value.
+        let flags = unsafe { libc::fcntl(socket.as_raw_descriptor(), libc::F_GETFL) };
+        // SAFETY:
+        // Same as above.
+        if flags < 0
+            || unsafe {
+                libc::fcntl(
+                    socket.as_raw_descriptor(),
+                    libc::F_SETFL,
+                    flags & !libc::O_NONBLOCK,
+                )
+            } < 0
+        {
+            return Err(Error::CreateSocket(SysError::last()));
+        }
+
+        Ok(SocketTap {
+            socket,
+            framing,
+            rx_buf: vec![0; SOCKET_VNET_HDR_LEN + MAX_FRAME_SIZE].into_boxed_slice(),
+            rx_stream_len: 0,
+            tx_buf: Vec::with_capacity(SOCKET_VNET_HDR_LEN + MAX_FRAME_SIZE),
+        })
+    }
+
+    /// Returns how frames are delimited on the socket.
+    pub fn framing(&self) -> SocketFraming {
+        self.framing
+    }
+
+    /// Receives the next frame into `rx_buf` after a zeroed virtio-net header, and returns the
+    /// length of the header and the frame. Fails with `WouldBlock` until a whole frame is
+    /// available.
+    fn recv_frame(&mut self) -> IoResult<usize> {
+        let frame_len = match self.framing {
+            SocketFraming::Datagram => loop {
+                let len = recv(&self.socket, &mut self.rx_buf[SOCKET_VNET_HDR_LEN..])?;
+                // Empty datagrams carry no frame.
+                if len > 0 {
+                    break len;
+                }
+            },
+            SocketFraming::Stream => loop {
+                // Only read up to the end of the current frame so that the socket stays readable
+                // as long as more frames are pending.
+                let start = SOCKET_VNET_HDR_LEN - STREAM_LEN_SIZE;
+                let prefix = &self.rx_buf[start..SOCKET_VNET_HDR_LEN];
+                let target = if self.rx_stream_len < STREAM_LEN_SIZE {
+                    STREAM_LEN_SIZE
+                } else {
+                    let frame_len = u32::from_be_bytes(prefix.try_into().unwrap()) as usize;
+                    if frame_len > MAX_FRAME_SIZE {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("frame of {} bytes is too large", frame_len),
+                        ));
+                    }
+                    STREAM_LEN_SIZE + frame_len
+                };
+                if self.rx_stream_len == target && target > STREAM_LEN_SIZE {
+                    self.rx_stream_len = 0;
+                    break target - STREAM_LEN_SIZE;
+                }
+                if self.rx_stream_len == target {
+                    // Empty frame, skip it.
+                    self.rx_stream_len = 0;
+                    continue;
+                }
+                let len = recv(
+                    &self.socket,
+                    &mut self.rx_buf[start + self.rx_stream_len..start + target],
+                )?;
+                if len == 0 {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+                }
+                self.rx_stream_len += len;
+            },
+        };
+        self.rx_buf[..SOCKET_VNET_HDR_LEN].fill(0);
+        Ok(SOCKET_VNET_HDR_LEN + frame_len)
+    }
+
+    /// Sends the frame in `tx_buf`, after its virtio-net header.
+    fn send_frame(&mut self) -> IoResult<()> {
+        if self.tx_buf.len() < SOCKET_VNET_HDR_LEN {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        match self.framing {
+            SocketFraming::Datagram => {
+                // Like a congested link, drop the frame rather than stalling the guest when the
+                // peer isn't keeping up.
+                match send(
+                    &self.socket,
+                    &self.tx_buf[SOCKET_VNET_HDR_LEN..],
+                    libc::MSG_DONTWAIT,
+                ) {
+                    Ok(_) => Ok(()),
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+                    Err(e) => Err(e),
+                }
+            }
+            SocketFraming::Stream => {
+                // Replace the end of the header with the length prefix to send both at once.
+                let frame_len = (self.tx_buf.len() - SOCKET_VNET_HDR_LEN) as u32;
+                let start = SOCKET_VNET_HDR_LEN - STREAM_LEN_SIZE;
+                self.tx_buf[start..SOCKET_VNET_HDR_LEN].copy_from_slice(&frame_len.to_be_bytes());
+                let mut sent = start;
+                while sent < self.tx_buf.len() {
+                    match send(&self.socket, &self.tx_buf[sent..], 0)? {
+                        0 => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+                        len => sent += len,
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Receives into `buf` without blocking.
+fn recv(socket: &SafeDescriptor, buf: &mut [u8]) -> IoResult<usize> {
+    // SAFETY:
+    // Safe because the buffer is valid for `buf.len()` bytes and we check the return value.
+    let ret = handle_eintr_errno!(unsafe {
+        libc::recv(
+            socket.as_raw_descriptor(),
+            buf.as_mut_ptr() as *mut c_void,
+            buf.len(),
+            libc::MSG_DONTWAIT,
+        )
+    });
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ret as usize)
+}
+
+/// Sends `buf`, without raising `SIGPIPE` if the peer is gone.
+fn send(socket: &SafeDescriptor, buf: &[u8], flags: c_int) -> IoResult<usize> {
+    // SAFETY:
+    // Safe because the buffer is valid for `buf.len()` bytes and we check the return value.
+    let ret = handle_eintr_errno!(unsafe {
+        libc::send(
+            socket.as_raw_descriptor(),
+            buf.as_ptr() as *const c_void,
+            buf.len(),
+            flags | libc::MSG_NOSIGNAL,
+        )
+    });
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ret as usize)
+}
+
+/// Binds `socket` to an address picked by the kernel in the abstract namespace, so that the peer
+/// can send datagrams back to it.
+fn autobind(socket: &UnixDatagram) -> Result<()> {
+    // SAFETY:
+    // Safe because sockaddr_un is a plain C struct.
+    let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    // SAFETY:
+    // Safe because the address is valid and only its family is passed to request an autobind.
+    let ret = unsafe {
+        libc::bind(
+            socket.as_raw_fd(),
+            &addr as *const libc::sockaddr_un as *const libc::sockaddr,
+            mem::size_of::<libc::sa_family_t>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(Error::CreateSocket(SysError::last()));
+    }
+    Ok(())
+}
+
+impl TapT for SocketTap {}
+
+// A socket isn't a host interface, so it has no address, netmask or MAC address to configure.
+impl TapTCommon for SocketTap {
+    fn new_with_name(_name: &[u8], _vnet_hdr: bool, _multi_vq: bool) -> Result<Self> {
+        Err(Error::IoctlError(SysError::new(libc::ENOTSUP)))
+    }
+
+    fn new(_vnet_hdr: bool, _multi_vq: bool) -> Result<SocketTap> {
+        Err(Error::IoctlError(SysError::new(libc::ENOTSUP)))
+    }
+
+    fn into_mq_taps(self, vq_pairs: u16) -> Result<Vec<Self>> {
+        // Frames can't be spread across queues; only one vq pair is supported.
+        if vq_pairs != 1 {
+            return Err(Error::IoctlError(SysError::new(libc::EINVAL)));
+        }
+
+        Ok(vec![self])
+    }
+
+    fn ip_addr(&self) -> Result<net::Ipv4Addr> {
+        Err(Error::IoctlError(SysError::new(libc::ENOTSUP)))
+    }
+
+    fn set_ip_addr(&self, _ip_addr: net::Ipv4Addr) -> Result<()> {
+        Err(Error::IoctlError(SysError::new(libc::ENOTSUP)))
+    }
+
+    fn netmask(&self) -> Result<net::Ipv4Addr> {
+        Err(Error::IoctlError(SysError::new(libc::ENOTSUP)))
+    }
+
+    fn set_netmask(&self, _netmask: net::Ipv4Addr) -> Result<()> {
+        Err(Error::IoctlError(SysError::new(libc::ENOTSUP)))
+    }
+
+    fn mtu(&self) -> Result<u16> {
+        Ok(SOCKET_MTU)
+    }
+
+    fn set_mtu(&self, _mtu: u16) -> Result<()> {
+        Err(Error::IoctlError(SysError::new(libc::ENOTSUP)))
+    }
+
+    fn mac_address(&self) -> Result<MacAddress> {
+        Err(Error::IoctlError(SysError::new(libc::ENOTSUP)))
+    }
+
+    fn set_mac_address(&self, _mac_addr: MacAddress) -> Result<()> {
+        Err(Error::IoctlError(SysError::new(libc::ENOTSUP)))
+    }
+
+    fn set_offload(&self, flags: c_uint) -> Result<()> {
+        // Frames on the socket don't carry the metadata needed for offloads.
+        if flags != 0 {
+            return Err(Error::IoctlError(SysError::new(libc::EINVAL)));
+        }
+        Ok(())
+    }
+
+    fn enable(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn try_clone(&self) -> Result<Self> {
+        let socket = self.socket.try_clone().map_err(Error::CloneTap)?;
+        Self::new(socket, self.framing)
+    }
+
+    unsafe fn from_raw_descriptor(descriptor: RawDescriptor) -> Result<Self> {
+        Self::from_socket(SafeDescriptor::from_raw_descriptor(descriptor))
+    }
+}
+
+impl TapTLinux for SocketTap {
+    fn set_vnet_hdr_size(&self, size: usize) -> Result<()> {
+        if size != SOCKET_VNET_HDR_LEN {
+            return Err(Error::IoctlError(SysError::new(libc::EINVAL)));
+        }
+        Ok(())
+    }
+
+    fn if_flags(&self) -> u32 {
+        net_sys::IFF_TAP | net_sys::IFF_NO_PI | net_sys::IFF_VNET_HDR
+    }
+}
+
+impl Read for SocketTap {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        // Like on a tap, the part of the frame that doesn't fit is dropped.
+        let len = self.recv_frame()?.min(buf.len());
+        buf[..len].copy_from_slice(&self.rx_buf[..len]);
+        Ok(len)
+    }
+}
+
+impl Write for SocketTap {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        self.tx_buf.clear();
+        self.tx_buf.extend_from_slice(buf);
+        self.send_frame()?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+}
+
+impl FileReadWriteVolatile for SocketTap {
+    fn read_volatile(&mut self, slice: VolatileSlice) -> IoResult<usize> {
+        self.read_vectored_volatile(&[slice])
+    }
+
+    fn read_vectored_volatile(&mut self, bufs: &[VolatileSlice]) -> IoResult<usize> {
+        let len = self.recv_frame()?;
+        let mut copied = 0;
+        for buf in bufs {
+            if copied == len {
+                break;
+            }
+            let count = buf.size().min(len - copied);
+            buf.sub_slice(0, count)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?
+                .copy_from(&self.rx_buf[copied..copied + count]);
+            copied += count;
+        }
+        Ok(copied)
+    }
+
+    fn write_volatile(&mut self, slice: VolatileSlice) -> IoResult<usize> {
+        self.write_vectored_volatile(&[slice])
+    }
+
+    fn write_vectored_volatile(&mut self, bufs: &[VolatileSlice]) -> IoResult<usize> {
+        self.tx_buf.clear();
+        for buf in bufs {
+            let start = self.tx_buf.len();
+            self.tx_buf.resize(start + buf.size(), 0);
+            buf.copy_to(&mut self.tx_buf[start..]);
+        }
+        self.send_frame()?;
+        Ok(self.tx_buf.len())
+    }
+}
+
+impl AsRawFd for SocketTap {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_descriptor()
+    }
+}
+
+impl AsRawDescriptor for SocketTap {
+    fn as_raw_descriptor(&self) -> RawDescriptor {
+        self.socket.as_raw_descriptor()
+    }
+}
+
+impl ReadNotifier for SocketTap {
+    fn get_read_notifier(&self) -> &dyn AsRawDescriptor {
+        self
+    }
+}
+
+impl IntoAsync for SocketTap {}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixListener;
+
+    use tempfile::tempdir;
+
+    use super::*;
+
+    fn pair(socket_type: c_int) -> (SocketTap, SafeDescriptor) {
+        let mut fds = [0; 2];
+        // SAFETY:
+        // Safe because we pass a valid array for two descriptors and check the return value.
+        let ret = unsafe { libc::socketpair(libc::AF_UNIX, socket_type, 0, fds.as_mut_ptr()) };
+        assert_eq!(ret, 0);
+        // SAFETY:
+        // Safe because socketpair just created both descriptors, and each is owned once.
+        let (tap, peer) = unsafe {
+            (
+                SafeDescriptor::from_raw_descriptor(fds[0]),
+                SafeDescriptor::from_raw_descriptor(fds[1]),
+            )
+        };
+        (SocketTap::from_socket(tap).unwrap(), peer)
+    }
+
+    fn frame_with_header(frame: &[u8]) -> Vec<u8> {
+        let mut buf = vec![0; SOCKET_VNET_HDR_LEN];
+        buf.extend_from_slice(frame);
+        buf
+    }
+
+    #[test]
+    fn stream_framing() {
+        let (mut tap, peer) = pair(libc::SOCK_STREAM);
+        assert_eq!(tap.framing(), SocketFraming::Stream);
+        let mut peer = UnixStream::from(std::os::fd::OwnedFd::from(peer));
+
+        tap.write_all(&frame_with_header(b"outgoing")).unwrap();
+        let mut buf = [0u8; 12];
+        peer.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..4], &8u32.to_be_bytes());
+        assert_eq!(&buf[4..], b"outgoing");
+
+        let mut buf = [0u8; 64];
+        assert_eq!(
+            tap.read(&mut buf).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+
+        // Frames split across writes are only delivered once complete, and back-to-back frames
+        // are delivered one at a time.
+        peer.write_all(&5u32.to_be_bytes()).unwrap();
+        peer.write_all(b"fir").unwrap();
+        assert_eq!(
+            tap.read(&mut buf).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+        peer.write_all(b"st").unwrap();
+        peer.write_all(&0u32.to_be_bytes()).unwrap();
+        peer.write_all(&6u32.to_be_bytes()).unwrap();
+        peer.write_all(b"second").unwrap();
+
+        let len = tap.read(&mut buf).unwrap();
+        assert_eq!(&buf[..len], frame_with_header(b"first").as_slice());
+        let len = tap.read(&mut buf).unwrap();
+        assert_eq!(&buf[..len], frame_with_header(b"second").as_slice());
+
+        drop(peer);
+        assert_eq!(
+            tap.read(&mut buf).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn datagram_framing() {
+        let (mut tap, peer) = pair(libc::SOCK_DGRAM);
+        assert_eq!(tap.framing(), SocketFraming::Datagram);
+        let peer = UnixDatagram::from(std::os::fd::OwnedFd::from(peer));
+
+        tap.write_all(&frame_with_header(b"outgoing")).unwrap();
+        let mut buf = [0u8; 64];
+        let len = peer.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"outgoing");
+
+        peer.send(b"incoming").unwrap();
+        let len = tap.read(&mut buf).unwrap();
+        assert_eq!(&buf[..len], frame_with_header(b"incoming").as_slice());
+        assert_eq!(
+            tap.read(&mut buf).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+    }
+
+    #[test]
+    fn connect_detects_socket_type() {
+        let dir = tempdir().unwrap();
+
+        let stream_path = dir.path().join("stream");
+        let _listener = UnixListener::bind(&stream_path).unwrap();
+        let tap = SocketTap::connect(&stream_path).unwrap();
+        assert_eq!(tap.framing(), SocketFraming::Stream);
+
+        // The switch must be able to reply to the address datagrams come from.
+        let dgram_path = dir.path().join("dgram");
+        let switch = UnixDatagram::bind(&dgram_path).unwrap();
+        let mut tap = SocketTap::connect(&dgram_path).unwrap();
+        assert_eq!(tap.framing(), SocketFraming::Datagram);
+        tap.write_all(&frame_with_header(b"hello")).unwrap();
+        let mut buf = [0u8; 64];
+        let (len, addr) = switch.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"hello");
+        switch.send_to_addr(b"reply", &addr).unwrap();
+        let len = tap.read(&mut buf).unwrap();
+        assert_eq!(&buf[..len], frame_with_header(b"reply").as_slice());
+    }
+
+    #[test]
+    fn unsupported_operations_fail() {
+        let (tap, _peer) = pair(libc::SOCK_DGRAM);
+        tap.set_offload(0).unwrap();
+        assert!(tap.set_offload(net_sys::TUN_F_CSUM).is_err());
+        assert!(tap.ip_addr().is_err());
+        assert!(tap.set_mtu(1500).is_err());
+        assert!(tap.mac_address().is_err());
+        assert!(tap.into_mq_taps(2).is_err());
+    }
+}
@@ -0,0 +1,551 @@
+// Copyright 2025 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Single file archive of a snapshot directory.
+//!
+//! The archive holds the fragments of every namespace back to back, followed by an index:
+//!
+//! ```text
+//! | MAGIC | version: u32 | fragment data... | index | index offset: u64 | index size: u64 |
+//! | index crc32: u32 | MAGIC |
+//! ```
+//!
+//! The integers are little endian and the index is a CBOR encoded `Vec<ArchiveEntry>`. The data of
+//! a fragment is stored as is, or as an LZ4 frame if compressed.
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::BufWriter;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::Context;
+use anyhow::Result;
+
+use crate::FragmentChecksum;
+
+const MAGIC: &[u8; 8] = b"CVMSNAPA";
+const VERSION: u32 = 1;
+const HEADER_SIZE: u64 = MAGIC.len() as u64 + 4;
+const FOOTER_SIZE: u64 = 8 + 8 + 4 + MAGIC.len() as u64;
+
+/// A fragment stored in a snapshot archive.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct ArchiveEntry {
+    /// Name of the fragment, prefixed by its namespaces separated by '/'.
+    pub name: String,
+    /// Offset of the data in the archive.
+    pub offset: u64,
+    /// Size of the data in the archive.
+    pub stored_size: u64,
+    /// Size of the fragment once decompressed.
+    pub size: u64,
+    /// CRC32 of the data in the archive.
+    pub checksum: u32,
+    /// Whether the data is an LZ4 frame.
+    pub compressed: bool,
+}
+
+/// Packs the snapshot directory `dir` written by `SnapshotWriter` into a single file archive at
+/// `archive_path`, which must not exist yet. With `compress`, the fragments are compressed unless
+/// the snapshot is encrypted.
+pub fn pack_archive(dir: &Path, archive_path: &Path, compress: bool) -> Result<()> {
+    let mut fragments = Vec::new();
+    collect_fragments(dir, "", &mut fragments)?;
+    // Encrypted data doesn't compress, and its reader needs to seek in the fragment.
+    let compress = compress && !dir.join("enc_metadata").exists();
+
+    let file = File::options()
+        .write(true)
+        .create_new(true)
+        .open(archive_path)
+        .with_context(|| format!("failed to create archive {}", archive_path.display()))?;
+    write_archive(file, fragments, compress).inspect_err(|_| {
+        // Don't leave a truncated archive behind.
+        let _ = std::fs::remove_file(archive_path);
+    })
+}
+
+fn write_archive(file: File, fragments: Vec<(String, PathBuf)>, compress: bool) -> Result<()> {
+    let mut w = HashingWriter::new(BufWriter::new(file));
+    w.write_all(MAGIC)?;
+    w.write_all(&VERSION.to_le_bytes())?;
+
+    let mut entries = Vec::with_capacity(fragments.len());
+    for (name, path) in fragments {
+        let mut fragment = File::open(&path)
+            .with_context(|| format!("failed to open fragment {}", path.display()))?;
+        let offset = w.written;
+        w.reset_hash();
+        let size = if compress {
+            let mut encoder = lz4_flex::frame::FrameEncoder::new(&mut w);
+            let size = std::io::copy(&mut fragment, &mut encoder)?;
+            encoder.finish()?;
+            size
+        } else {
+            std::io::copy(&mut fragment, &mut w)?
+        };
+        entries.push(ArchiveEntry {
+            name,
+            offset,
+            stored_size: w.written - offset,
+            size,
+            checksum: w.hasher.clone().finalize(),
+            compressed: compress,
+        });
+    }
+
+    let index_offset = w.written;
+    let mut index = Vec::new();
+    ciborium::into_writer(&entries, &mut index).context("failed to serialize archive index")?;
+    w.write_all(&index)?;
+    w.write_all(&index_offset.to_le_bytes())?;
+    w.write_all(&(index.len() as u64).to_le_bytes())?;
+    w.write_all(&crc32fast::hash(&index).to_le_bytes())?;
+    w.write_all(MAGIC)?;
+    let file = w
+        .inner
+        .into_inner()
+        .map_err(|e| e.into_error())
+        .context("failed to write archive")?;
+    file.sync_all().context("failed to sync archive")?;
+    Ok(())
+}
+
+/// Appends the files under `dir` to `fragments` in name order, with their names prefixed by
+/// `prefix`.
+pub(crate) fn collect_fragments(
+    dir: &Path,
+    prefix: &str,
+    fragments: &mut Vec<(String, PathBuf)>,
+) -> Result<()> {
+    let mut dir_entries = std::fs::read_dir(dir)
+        .with_context(|| format!("failed to read snapshot dir {}", dir.display()))?
+        .collect::<std::io::Result<Vec<_>>>()?;
+    dir_entries.sort_by_key(|entry| entry.file_name());
+    for entry in dir_entries {
+        let file_name = entry.file_name();
+        let file_name = file_name
+            .to_str()
+            .with_context(|| format!("invalid fragment name {file_name:?}"))?;
+        let name = format!("{prefix}{file_name}");
+        if entry.file_type()?.is_dir() {
+            collect_fragments(&entry.path(), &format!("{name}/"), fragments)?;
+        } else {
+            fragments.push((name, entry.path()));
+        }
+    }
+    Ok(())
+}
+
+/// Writer that counts and hashes the bytes written to it.
+pub(crate) struct HashingWriter<W: Write> {
+    pub(crate) inner: W,
+    pub(crate) hasher: crc32fast::Hasher,
+    pub(crate) written: u64,
+}
+
+impl<W: Write> HashingWriter<W> {
+    pub(crate) fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: crc32fast::Hasher::new(),
+            written: 0,
+        }
+    }
+
+    fn reset_hash(&mut self) {
+        self.hasher = crc32fast::Hasher::new();
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Index of a snapshot archive written by `pack_archive`.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct ArchiveReader {
+    path: PathBuf,
+    entries: BTreeMap<String, ArchiveEntry>,
+}
+
+impl ArchiveReader {
+    /// Reads the index of the archive at `path`.
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut file = File::open(path)
+            .with_context(|| format!("failed to open archive {}", path.display()))?;
+        let file_size = file.metadata()?.len();
+        if file_size < HEADER_SIZE + FOOTER_SIZE {
+            return Err(anyhow::anyhow!("archive {} is truncated", path.display()));
+        }
+
+        let mut header = [0u8; HEADER_SIZE as usize];
+        file.read_exact(&mut header)?;
+        if &header[..MAGIC.len()] != MAGIC {
+            return Err(anyhow::anyhow!(
+                "{} is not a snapshot archive",
+                path.display()
+            ));
+        }
+        let version = u32::from_le_bytes(header[MAGIC.len()..].try_into().unwrap());
+        if version != VERSION {
+            return Err(anyhow::anyhow!(
+                "unsupported snapshot archive version {version}"
+            ));
+        }
+
+        let mut footer = [0u8; FOOTER_SIZE as usize];
+        file.seek(SeekFrom::Start(file_size - FOOTER_SIZE))?;
+        file.read_exact(&mut footer)?;
+        if &footer[20..] != MAGIC {
+            return Err(anyhow::anyhow!("archive {} is truncated", path.display()));
+        }
+        let index_offset = u64::from_le_bytes(footer[..8].try_into().unwrap());
+        let index_size = u64::from_le_bytes(footer[8..16].try_into().unwrap());
+        let index_checksum = u32::from_le_bytes(footer[16..20].try_into().unwrap());
+        let index_end = index_offset
+            .checked_add(index_size)
+            .filter(|end| index_offset >= HEADER_SIZE && *end == file_size - FOOTER_SIZE)
+            .context("invalid archive index location")?;
+
+        let mut index = vec![0u8; (index_end - index_offset) as usize];
+        file.seek(SeekFrom::Start(index_offset))?;
+        file.read_exact(&mut index)?;
+        if crc32fast::hash(&index) != index_checksum {
+            return Err(anyhow::anyhow!("archive index checksum mismatch"));
+        }
+        let index: Vec<ArchiveEntry> =
+            ciborium::from_reader(&index[..]).context("failed to parse archive index")?;
+
+        let mut entries = BTreeMap::new();
+        for entry in index {
+            let end = entry.offset.checked_add(entry.stored_size);
+            if entry.offset < HEADER_SIZE || !matches!(end, Some(end) if end <= index_offset) {
+                return Err(anyhow::anyhow!(
+                    "fragment {:?} is out of the archive data",
+                    entry.name
+                ));
+            }
+            entries.insert(entry.name.clone(), entry);
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+            entries,
+        })
+    }
+
+    /// Path of the archive.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Lists the fragments of every namespace in name order.
+    pub fn entries(&self) -> impl Iterator<Item = &ArchiveEntry> {
+        self.entries.values()
+    }
+
+    /// Gets the fragment `name`, prefixed by its namespaces.
+    pub fn entry(&self, name: &str) -> Result<&ArchiveEntry> {
+        self.entries.get(name).with_context(|| {
+            format!(
+                "snapshot fragment {name:?} not found in archive {}",
+                self.path.display()
+            )
+        })
+    }
+
+    /// Gets a `Read` impl over the decompressed data of a fragment. Reading fails once all the
+    /// data is read if it doesn't match the checksum of the fragment.
+    pub fn fragment(&self, entry: &ArchiveEntry) -> Result<Box<dyn Read>> {
+        let reader = CheckedReader {
+            reader: self.stored_fragment(entry)?,
+            hasher: crc32fast::Hasher::new(),
+            entry: entry.clone(),
+        };
+        if entry.compressed {
+            return Ok(Box::new(lz4_flex::frame::FrameDecoder::new(reader)));
+        }
+        Ok(Box::new(reader))
+    }
+
+    /// Gets a reader over the data of a fragment as stored in the archive.
+    pub(crate) fn stored_fragment(&self, entry: &ArchiveEntry) -> Result<FragmentReader> {
+        let file = File::open(&self.path)
+            .with_context(|| format!("failed to open archive {}", self.path.display()))?;
+        FragmentReader::new(file, entry.offset, entry.stored_size)
+    }
+
+    /// Gets a reader over the data of a fragment as stored in the archive, once it is checked
+    /// against its checksum.
+    pub(crate) fn checked_stored_fragment(&self, entry: &ArchiveEntry) -> Result<FragmentReader> {
+        if !self.verify(entry)? {
+            return Err(anyhow::anyhow!(
+                "snapshot fragment {:?} is corrupted",
+                entry.name
+            ));
+        }
+        self.stored_fragment(entry)
+    }
+
+    /// Checks the data of a fragment against its checksum.
+    pub fn verify(&self, entry: &ArchiveEntry) -> Result<bool> {
+        let checksum = FragmentChecksum::compute(self.stored_fragment(entry)?)
+            .with_context(|| format!("failed to read fragment {:?}", entry.name))?;
+        Ok(checksum.crc32 == entry.checksum)
+    }
+
+    /// Lists the names of the fragments in the namespace `prefix` and its nested namespaces,
+    /// relative to `prefix`.
+    pub(crate) fn list_all_fragments(&self, prefix: &str) -> Vec<String> {
+        self.names_in(prefix).map(str::to_owned).collect()
+    }
+
+    /// Lists the names of the fragments directly in the namespace `prefix`, which is empty or ends
+    /// with '/'.
+    pub(crate) fn list_fragments(&self, prefix: &str) -> Vec<String> {
+        self.names_in(prefix)
+            .filter(|rest| !rest.contains('/'))
+            .map(str::to_owned)
+            .collect()
+    }
+
+    /// Lists the names of the namespaces directly in the namespace `prefix`, which is empty or ends
+    /// with '/'.
+    pub(crate) fn list_namespaces(&self, prefix: &str) -> Vec<String> {
+        self.names_in(prefix)
+            .filter_map(|rest| rest.split_once('/').map(|(namespace, _)| namespace))
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .map(str::to_owned)
+            .collect()
+    }
+
+    fn names_in<'a>(&'a self, prefix: &'a str) -> impl Iterator<Item = &'a str> {
+        self.entries
+            .keys()
+            .filter_map(move |name| name.strip_prefix(prefix))
+    }
+}
+
+/// Reader over a range of the archive file.
+pub(crate) struct FragmentReader {
+    file: File,
+    start: u64,
+    size: u64,
+    pos: u64,
+}
+
+impl FragmentReader {
+    fn new(mut file: File, start: u64, size: u64) -> Result<Self> {
+        file.seek(SeekFrom::Start(start))?;
+        Ok(Self {
+            file,
+            start,
+            size,
+            pos: 0,
+        })
+    }
+}
+
+impl Read for FragmentReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let max = (self.size - self.pos).min(buf.len() as u64) as usize;
+        if max == 0 {
+            return Ok(0);
+        }
+        let n = self.file.read(&mut buf[..max])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for FragmentReader {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        }
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "seek to a negative position",
+            )
+        })?;
+        self.file.seek(SeekFrom::Start(self.start + pos))?;
+        self.pos = pos.min(self.size);
+        Ok(pos)
+    }
+}
+
+/// Reader over the data of a fragment that fails when the end is reached if the data doesn't match
+/// the checksum of the fragment.
+struct CheckedReader {
+    reader: FragmentReader,
+    hasher: crc32fast::Hasher,
+    entry: ArchiveEntry,
+}
+
+impl Read for CheckedReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.reader.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        // Checked by the read reaching the end, since the LZ4 decoder stops at the end of a frame.
+        if n > 0
+            && self.reader.pos == self.reader.size
+            && self.hasher.clone().finalize() != self.entry.checksum
+        {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("snapshot fragment {:?} is corrupted", self.entry.name),
+            ));
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SnapshotReader;
+    use crate::SnapshotWriter;
+
+    fn write_snapshot(dir: &Path) {
+        let writer = SnapshotWriter::new(dir.to_path_buf(), false).unwrap();
+        writer.write_fragment("irqchip", &vec![1u32; 1000]).unwrap();
+        writer
+            .raw_fragment("mem")
+            .unwrap()
+            .write_all(&[0xaa; 10000])
+            .unwrap();
+        let bus = writer.add_namespace("bus0").unwrap();
+        bus.write_fragment("serial", &"state").unwrap();
+        bus.add_namespace("empty").unwrap();
+        writer
+            .add_namespace("vcpu")
+            .unwrap()
+            .write_fragment("0", &42u64)
+            .unwrap();
+    }
+
+    fn check_snapshot(reader: &SnapshotReader) {
+        assert_eq!(
+            reader.read_fragment::<Vec<u32>>("irqchip").unwrap(),
+            vec![1u32; 1000]
+        );
+        let mut mem = Vec::new();
+        reader
+            .raw_fragment("mem")
+            .unwrap()
+            .read_to_end(&mut mem)
+            .unwrap();
+        assert_eq!(mem, vec![0xaa; 10000]);
+        let mut fragments = reader.list_fragments().unwrap();
+        fragments.sort();
+        assert_eq!(fragments, vec!["irqchip", "mem"]);
+        assert_eq!(reader.list_namespaces().unwrap(), vec!["bus0", "vcpu"]);
+
+        let bus = reader.namespace("bus0").unwrap();
+        assert_eq!(bus.read_fragment::<String>("serial").unwrap(), "state");
+        assert_eq!(bus.list_fragments().unwrap(), vec!["serial"]);
+        assert!(bus.read_fragment::<String>("missing").is_err());
+        let vcpu = reader.namespace("vcpu").unwrap();
+        assert_eq!(vcpu.read_fragment::<u64>("0").unwrap(), 42);
+    }
+
+    #[test]
+    fn pack_and_read() {
+        for compress in [false, true] {
+            let tempdir = tempfile::tempdir().unwrap();
+            let dir = tempdir.path().join("snapshot");
+            let archive_path = tempdir.path().join("snapshot.archive");
+            write_snapshot(&dir);
+            pack_archive(&dir, &archive_path, compress).unwrap();
+
+            let archive = ArchiveReader::open(&archive_path).unwrap();
+            let names: Vec<_> = archive.entries().map(|e| e.name.as_str()).collect();
+            assert_eq!(names, vec!["bus0/serial", "irqchip", "mem", "vcpu/0"]);
+            let mem = archive.entry("mem").unwrap();
+            assert_eq!(mem.size, 10000);
+            assert_eq!(mem.compressed, compress);
+            assert_eq!(mem.stored_size < mem.size, compress);
+            for entry in archive.entries() {
+                assert!(archive.verify(entry).unwrap());
+            }
+
+            check_snapshot(&SnapshotReader::new(&archive_path, false).unwrap());
+            assert!(SnapshotReader::new(&archive_path, false)
+                .unwrap()
+                .raw_fragment_file("mem")
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn pack_existing_archive() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path().join("snapshot");
+        write_snapshot(&dir);
+        let archive_path = tempdir.path().join("snapshot.archive");
+        File::create(&archive_path).unwrap();
+        assert!(pack_archive(&dir, &archive_path, false).is_err());
+    }
+
+    #[test]
+    fn corrupted_archive() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path().join("snapshot");
+        let archive_path = tempdir.path().join("snapshot.archive");
+        write_snapshot(&dir);
+        pack_archive(&dir, &archive_path, false).unwrap();
+        let mem = ArchiveReader::open(&archive_path)
+            .unwrap()
+            .entry("mem")
+            .unwrap()
+            .clone();
+
+        let mut file = File::options().write(true).open(&archive_path).unwrap();
+        file.seek(SeekFrom::Start(mem.offset + 1)).unwrap();
+        file.write_all(&[0]).unwrap();
+        let archive = ArchiveReader::open(&archive_path).unwrap();
+        assert!(!archive.verify(&mem).unwrap());
+        assert!(archive.verify(archive.entry("irqchip").unwrap()).unwrap());
+        let reader = SnapshotReader::new(&archive_path, false).unwrap();
+        let mut data = Vec::new();
+        let err = reader
+            .raw_fragment("mem")
+            .unwrap()
+            .read_to_end(&mut data)
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(reader.read_fragment::<Vec<u32>>("irqchip").is_ok());
+
+        // Truncating the archive loses the index.
+        file.set_len(mem.offset).unwrap();
+        assert!(ArchiveReader::open(&archive_path).is_err());
+    }
+
+    #[test]
+    fn not_an_archive() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("file");
+        std::fs::write(&path, [0u8; 100]).unwrap();
+        assert!(ArchiveReader::open(&path).is_err());
+    }
+}
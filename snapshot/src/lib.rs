@@ -2,6 +2,8 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 use std::fmt::Debug;
 use std::fmt::Formatter;
 use std::fs::File;
@@ -9,14 +11,27 @@ use std::io::Read;
 use std::io::Write;
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
 
 use anyhow::Context;
 use anyhow::Result;
 use crypto::CryptKey;
 
 mod any_snapshot;
+mod archive;
+mod manifest;
 
 pub use any_snapshot::AnySnapshot;
+pub use archive::pack_archive;
+pub use archive::ArchiveEntry;
+pub use archive::ArchiveReader;
+pub use manifest::FragmentChecksum;
+pub use manifest::FragmentMismatch;
+pub use manifest::SnapshotManifest;
+
+use crate::archive::HashingWriter;
+use crate::manifest::MANIFEST_FRAGMENT;
 
 // Use 4kB encrypted chunks by default (if encryption is used).
 const DEFAULT_ENCRYPTED_CHUNK_SIZE_BYTES: usize = 1024 * 4;
@@ -26,14 +41,17 @@ const DEFAULT_ENCRYPTED_CHUNK_SIZE_BYTES: usize = 1024 * 4;
 /// Each fragment is an opaque byte blob. Namespaces can be used to avoid fragment naming
 /// collisions between devices.
 ///
-/// In the current implementation, fragments are files and namespaces are directories, but the API
-/// is kept abstract so that we can potentially support something like a single file archive
-/// output.
+/// Fragments are files and namespaces are directories. Once complete, the directory can be packed
+/// into a single file archive with `pack_archive`, which `SnapshotReader` reads as well.
 #[derive(Clone, serde::Serialize, serde::Deserialize)]
 pub struct SnapshotWriter {
     dir: PathBuf,
     /// If encryption is used, the plaintext key will be stored here.
     key: Option<CryptKey>,
+    /// Checksums of the fragments written through this writer or its clones in this process, by
+    /// path. `write_manifest` reads back the other fragments to compute theirs.
+    #[serde(skip)]
+    checksums: Arc<Mutex<BTreeMap<PathBuf, FragmentChecksum>>>,
 }
 
 impl Debug for SnapshotWriter {
@@ -71,12 +89,14 @@ impl SnapshotWriter {
             return Ok(Self {
                 dir: root,
                 key: Some(key),
+                checksums: Default::default(),
             });
         }
 
         Ok(Self {
             dir: root,
             key: None,
+            checksums: Default::default(),
         })
     }
 
@@ -102,6 +122,11 @@ impl SnapshotWriter {
                     path.display()
                 )
             })?;
+        let file = FragmentFile {
+            writer: HashingWriter::new(file),
+            path,
+            checksums: self.checksums.clone(),
+        };
 
         if let Some(key) = self.key.as_ref() {
             return Ok(Box::new(crypto::CryptWriter::new_from_key(
@@ -135,16 +160,78 @@ impl SnapshotWriter {
         Ok(Self {
             dir,
             key: self.key.clone(),
+            checksums: self.checksums.clone(),
         })
     }
+
+    /// Writes the manifest of the snapshot, made of `vm`, a description of the VM, and the
+    /// checksums of all the fragments. Must be called on the root namespace once all the other
+    /// fragments are written. Only the fragments written by other processes are read to compute
+    /// their checksums.
+    pub fn write_manifest<T: serde::Serialize>(&self, vm: &T) -> Result<()> {
+        let mut fragments = Vec::new();
+        archive::collect_fragments(&self.dir, "", &mut fragments)?;
+        let written = self.checksums.lock().unwrap().clone();
+        let mut checksums = BTreeMap::new();
+        for (name, path) in fragments {
+            if let Some(checksum) = written.get(&path) {
+                checksums.insert(name, *checksum);
+                continue;
+            }
+            let file = File::open(&path)
+                .with_context(|| format!("failed to open fragment {}", path.display()))?;
+            let checksum = FragmentChecksum::compute(file)
+                .with_context(|| format!("failed to read fragment {}", path.display()))?;
+            checksums.insert(name, checksum);
+        }
+        self.write_fragment(
+            MANIFEST_FRAGMENT,
+            &SnapshotManifest {
+                vm,
+                fragments: checksums,
+            },
+        )
+    }
+}
+
+/// File of a fragment that records its checksum once it is written and dropped.
+struct FragmentFile {
+    writer: HashingWriter<File>,
+    path: PathBuf,
+    checksums: Arc<Mutex<BTreeMap<PathBuf, FragmentChecksum>>>,
+}
+
+impl Write for FragmentFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
 }
 
-/// Reads snapshots created by `SnapshotWriter`.
+impl Drop for FragmentFile {
+    fn drop(&mut self) {
+        let checksum = FragmentChecksum {
+            size: self.writer.written,
+            crc32: self.writer.hasher.clone().finalize(),
+        };
+        self.checksums
+            .lock()
+            .unwrap()
+            .insert(std::mem::take(&mut self.path), checksum);
+    }
+}
+
+/// Reads snapshots created by `SnapshotWriter`, either as a directory or packed into an archive.
 #[derive(Clone, serde::Serialize, serde::Deserialize)]
 pub struct SnapshotReader {
+    /// Directory of the namespace, relative to the archive root if `archive` is set.
     dir: PathBuf,
     /// If encryption is used, the plaintext key will be stored here.
     key: Option<CryptKey>,
+    archive: Option<ArchiveReader>,
 }
 
 impl Debug for SnapshotReader {
@@ -152,13 +239,34 @@ impl Debug for SnapshotReader {
         f.debug_struct("SnapshotReader")
             .field("dir", &format!("{:?}", self.dir))
             .field("key", if self.key.is_some() { &"Some" } else { &"None" })
+            .field("archive", &self.archive.as_ref().map(ArchiveReader::path))
             .finish()
     }
 }
 
 impl SnapshotReader {
-    /// Reads a snapshot at `root`. Set require_encrypted to require an encrypted snapshot.
+    /// Reads a snapshot at `root`, which is a directory or an archive. Set require_encrypted to
+    /// require an encrypted snapshot.
     pub fn new(root: &Path, require_encrypted: bool) -> Result<Self> {
+        if root.is_file() {
+            let archive = ArchiveReader::open(root)?;
+            let key = match archive.entry("enc_metadata") {
+                Ok(entry) => Some(
+                    crypto::CryptReader::extract_key(archive.checked_stored_fragment(entry)?)
+                        .context("failed to load snapshot key")?,
+                ),
+                Err(_) if require_encrypted => {
+                    return Err(anyhow::anyhow!("snapshot was not encrypted"))
+                }
+                Err(_) => None,
+            };
+            return Ok(Self {
+                dir: PathBuf::new(),
+                key,
+                archive: Some(archive),
+            });
+        }
+
         let enc_metadata_path = root.join("enc_metadata");
         if Path::exists(&enc_metadata_path) {
             let key = Some(
@@ -170,6 +278,7 @@ impl SnapshotReader {
             return Ok(Self {
                 dir: root.to_path_buf(),
                 key,
+                archive: None,
             });
         } else if require_encrypted {
             return Err(anyhow::anyhow!("snapshot was not encrypted"));
@@ -178,11 +287,33 @@ impl SnapshotReader {
         Ok(Self {
             dir: root.to_path_buf(),
             key: None,
+            archive: None,
         })
     }
 
+    /// Prefix of the names of the fragments of this namespace in the archive.
+    fn archive_prefix(&self) -> String {
+        self.dir
+            .iter()
+            .map(|component| format!("{}/", component.to_string_lossy()))
+            .collect()
+    }
+
     /// Gets access to a `Read` impl that represents a fragment.
     pub fn raw_fragment(&self, name: &str) -> Result<Box<dyn Read>> {
+        if let Some(archive) = self.archive.as_ref() {
+            let entry = archive.entry(&format!("{}{name}", self.archive_prefix()))?;
+            if let Some(key) = self.key.as_ref() {
+                if entry.compressed {
+                    return Err(anyhow::anyhow!("encrypted fragment {name:?} is compressed"));
+                }
+                return Ok(Box::new(crypto::CryptReader::from_file_and_key(
+                    archive.checked_stored_fragment(entry)?,
+                    key,
+                )?));
+            }
+            return archive.fragment(entry);
+        }
         let path = self.dir.join(name);
         let file = File::open(&path).with_context(|| {
             format!(
@@ -197,6 +328,40 @@ impl SnapshotReader {
         Ok(Box::new(file))
     }
 
+    /// Opens the file of a fragment, e.g. to map it instead of reading it. The snapshot must not be
+    /// encrypted since the file holds the raw bytes.
+    pub fn raw_fragment_file(&self, name: &str) -> Result<File> {
+        if self.key.is_some() {
+            return Err(anyhow::anyhow!("fragment {name:?} is encrypted"));
+        }
+        if self.archive.is_some() {
+            return Err(anyhow::anyhow!("fragment {name:?} is in an archive"));
+        }
+        let path = self.dir.join(name);
+        File::open(&path).with_context(|| {
+            format!(
+                "failed to open snapshot fragment {name:?} at {}",
+                path.display()
+            )
+        })
+    }
+
+    /// Gets access to the data of a fragment as written by `SnapshotWriter`, without decrypting
+    /// it.
+    fn stored_fragment(&self, name: &str) -> Result<Box<dyn Read>> {
+        if let Some(archive) = self.archive.as_ref() {
+            return archive.fragment(archive.entry(&format!("{}{name}", self.archive_prefix()))?);
+        }
+        let path = self.dir.join(name);
+        let file = File::open(&path).with_context(|| {
+            format!(
+                "failed to open snapshot fragment {name:?} at {}",
+                path.display()
+            )
+        })?;
+        Ok(Box::new(file))
+    }
+
     /// Reads a fragment.
     pub fn read_fragment<T: serde::de::DeserializeOwned>(&self, name: &str) -> Result<T> {
         // NOTE: No BufReader because ciborium::from_reader has an internal buffer.
@@ -205,6 +370,9 @@ impl SnapshotReader {
 
     /// Reads the names of all fragments in this namespace.
     pub fn list_fragments(&self) -> Result<Vec<String>> {
+        if let Some(archive) = self.archive.as_ref() {
+            return Ok(archive.list_fragments(&self.archive_prefix()));
+        }
         let mut result = Vec::new();
         for entry in std::fs::read_dir(&self.dir)? {
             let entry = entry?;
@@ -215,17 +383,78 @@ impl SnapshotReader {
         Ok(result)
     }
 
+    /// Reads the names of all fragments in this namespace and its nested namespaces, prefixed by
+    /// the nested namespaces separated by '/'.
+    fn list_all_fragments(&self) -> Result<Vec<String>> {
+        if let Some(archive) = self.archive.as_ref() {
+            return Ok(archive.list_all_fragments(&self.archive_prefix()));
+        }
+        let mut fragments = Vec::new();
+        archive::collect_fragments(&self.dir, "", &mut fragments)?;
+        Ok(fragments.into_iter().map(|(name, _)| name).collect())
+    }
+
+    /// Reads the manifest written by `SnapshotWriter::write_manifest`, or `None` if the snapshot
+    /// has none.
+    pub fn read_manifest<T: serde::de::DeserializeOwned>(
+        &self,
+    ) -> Result<Option<SnapshotManifest<T>>> {
+        if !self
+            .list_fragments()?
+            .iter()
+            .any(|name| name == MANIFEST_FRAGMENT)
+        {
+            return Ok(None);
+        }
+        self.read_fragment(MANIFEST_FRAGMENT)
+            .context("failed to read the snapshot manifest")
+            .map(Some)
+    }
+
+    /// Checks the fragments of the snapshot against the checksums of its manifest, and returns
+    /// those that don't match.
+    pub fn verify(&self) -> Result<Vec<FragmentMismatch>> {
+        let manifest = self
+            .read_manifest::<AnySnapshot>()?
+            .context("snapshot has no manifest")?;
+        let mut unexpected: BTreeSet<String> = self
+            .list_all_fragments()?
+            .into_iter()
+            .filter(|name| name != MANIFEST_FRAGMENT)
+            .collect();
+        let mut mismatches = Vec::new();
+        for (name, checksum) in manifest.fragments {
+            if !unexpected.remove(&name) {
+                mismatches.push(FragmentMismatch::Missing(name));
+                continue;
+            }
+            let matches = self
+                .stored_fragment(&name)
+                .and_then(|reader| Ok(FragmentChecksum::compute(reader)?))
+                .is_ok_and(|actual| actual == checksum);
+            if !matches {
+                mismatches.push(FragmentMismatch::Corrupted(name));
+            }
+        }
+        mismatches.extend(unexpected.into_iter().map(FragmentMismatch::Unexpected));
+        Ok(mismatches)
+    }
+
     /// Open a namespace.
     pub fn namespace(&self, name: &str) -> Result<Self> {
         let dir = self.dir.join(name);
         Ok(Self {
             dir,
             key: self.key.clone(),
+            archive: self.archive.clone(),
         })
     }
 
     /// Reads the names of all child namespaces
     pub fn list_namespaces(&self) -> Result<Vec<String>> {
+        if let Some(archive) = self.archive.as_ref() {
+            return Ok(archive.list_namespaces(&self.archive_prefix()));
+        }
         let mut result = Vec::new();
         for entry in std::fs::read_dir(&self.dir)? {
             let entry = entry?;
@@ -0,0 +1,204 @@
+// Copyright 2025 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fmt::Display;
+use std::io::Read;
+
+/// Name of the fragment holding the manifest, at the root of the snapshot.
+pub(crate) const MANIFEST_FRAGMENT: &str = "manifest";
+
+/// Manifest of a snapshot, written by `SnapshotWriter::write_manifest` once all the other fragments
+/// are written.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct SnapshotManifest<T> {
+    /// Description of the snapshotted VM, checked against the VM restoring the snapshot.
+    pub vm: T,
+    /// Checksums of the fragments, prefixed by their namespaces separated by '/'.
+    pub fragments: BTreeMap<String, FragmentChecksum>,
+}
+
+/// Size and CRC32 of the data of a fragment, as written by `SnapshotWriter`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct FragmentChecksum {
+    pub size: u64,
+    pub crc32: u32,
+}
+
+impl FragmentChecksum {
+    /// Computes the checksum of the data read from `reader`.
+    pub(crate) fn compute(mut reader: impl Read) -> std::io::Result<Self> {
+        let mut hasher = crc32fast::Hasher::new();
+        let mut size = 0;
+        let mut buf = vec![0u8; 64 * 1024];
+        loop {
+            let n = reader.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+            size += n as u64;
+        }
+        Ok(Self {
+            size,
+            crc32: hasher.finalize(),
+        })
+    }
+}
+
+/// A fragment that doesn't match the manifest of its snapshot.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FragmentMismatch {
+    /// The fragment is in the manifest but not in the snapshot.
+    Missing(String),
+    /// The fragment is in the snapshot but not in the manifest.
+    Unexpected(String),
+    /// The data of the fragment doesn't match its checksum or can't be read.
+    Corrupted(String),
+}
+
+impl Display for FragmentMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FragmentMismatch::Missing(name) => write!(f, "{name}: missing"),
+            FragmentMismatch::Unexpected(name) => write!(f, "{name}: not in the manifest"),
+            FragmentMismatch::Corrupted(name) => write!(f, "{name}: checksum mismatch"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+    use crate::pack_archive;
+    use crate::SnapshotReader;
+    use crate::SnapshotWriter;
+
+    #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+    struct VmInfo {
+        vcpus: usize,
+    }
+
+    fn write_snapshot(dir: &std::path::Path) {
+        let writer = SnapshotWriter::new(dir.to_path_buf(), false).unwrap();
+        writer.write_fragment("irqchip", &vec![1u32; 1000]).unwrap();
+        writer
+            .raw_fragment("mem")
+            .unwrap()
+            .write_all(&[0xaa; 10000])
+            .unwrap();
+        writer
+            .add_namespace("bus0")
+            .unwrap()
+            .write_fragment("serial", &"state")
+            .unwrap();
+        writer.write_manifest(&VmInfo { vcpus: 2 }).unwrap();
+    }
+
+    #[test]
+    fn verify() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path().join("snapshot");
+        write_snapshot(&dir);
+
+        let reader = SnapshotReader::new(&dir, false).unwrap();
+        let manifest = reader.read_manifest::<VmInfo>().unwrap().unwrap();
+        assert_eq!(manifest.vm, VmInfo { vcpus: 2 });
+        assert_eq!(
+            manifest.fragments.keys().collect::<Vec<_>>(),
+            vec!["bus0/serial", "irqchip", "mem"]
+        );
+        assert_eq!(manifest.fragments["mem"].size, 10000);
+        assert_eq!(reader.verify().unwrap(), Vec::new());
+
+        let archive_path = tempdir.path().join("snapshot.archive");
+        pack_archive(&dir, &archive_path, true).unwrap();
+        let archive = SnapshotReader::new(&archive_path, false).unwrap();
+        assert_eq!(
+            archive.read_manifest::<VmInfo>().unwrap().unwrap().vm,
+            VmInfo { vcpus: 2 }
+        );
+        assert_eq!(archive.verify().unwrap(), Vec::new());
+    }
+
+    #[test]
+    fn verify_mismatches() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path().join("snapshot");
+        write_snapshot(&dir);
+        std::fs::write(dir.join("mem"), [0xab; 10000]).unwrap();
+        std::fs::remove_file(dir.join("bus0/serial")).unwrap();
+        std::fs::write(dir.join("extra"), [0]).unwrap();
+
+        let reader = SnapshotReader::new(&dir, false).unwrap();
+        assert_eq!(
+            reader.verify().unwrap(),
+            vec![
+                FragmentMismatch::Missing("bus0/serial".to_owned()),
+                FragmentMismatch::Corrupted("mem".to_owned()),
+                FragmentMismatch::Unexpected("extra".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn fragments_written_elsewhere() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path().join("snapshot");
+        let writer = SnapshotWriter::new(dir.clone(), false).unwrap();
+        writer.write_fragment("irqchip", &0u32).unwrap();
+        // Like a writer sent to a device process.
+        let device_writer: SnapshotWriter =
+            serde_json::from_str(&serde_json::to_string(&writer).unwrap()).unwrap();
+        device_writer
+            .add_namespace("bus0")
+            .unwrap()
+            .write_fragment("serial", &"state")
+            .unwrap();
+        std::fs::write(dir.join("gpu"), [1, 2, 3]).unwrap();
+        writer.write_manifest(&VmInfo { vcpus: 1 }).unwrap();
+
+        let reader = SnapshotReader::new(&dir, false).unwrap();
+        let manifest = reader.read_manifest::<VmInfo>().unwrap().unwrap();
+        assert_eq!(
+            manifest.fragments.keys().collect::<Vec<_>>(),
+            vec!["bus0/serial", "gpu", "irqchip"]
+        );
+        assert_eq!(reader.verify().unwrap(), Vec::new());
+    }
+
+    #[test]
+    fn checksums_computed_while_written() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path().join("snapshot");
+        let writer = SnapshotWriter::new(dir.clone(), false).unwrap();
+        writer.write_fragment("irqchip", &0u32).unwrap();
+        // Changed after it is written, so the manifest only matches if it isn't read back.
+        std::fs::write(dir.join("irqchip"), [0xff]).unwrap();
+        writer.write_manifest(&VmInfo { vcpus: 1 }).unwrap();
+
+        let reader = SnapshotReader::new(&dir, false).unwrap();
+        assert_eq!(
+            reader.verify().unwrap(),
+            vec![FragmentMismatch::Corrupted("irqchip".to_owned())]
+        );
+    }
+
+    #[test]
+    fn no_manifest() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path().join("snapshot");
+        SnapshotWriter::new(dir.clone(), false)
+            .unwrap()
+            .write_fragment("irqchip", &0u32)
+            .unwrap();
+
+        let reader = SnapshotReader::new(&dir, false).unwrap();
+        assert!(reader.read_manifest::<VmInfo>().unwrap().is_none());
+        assert!(reader.verify().is_err());
+    }
+}
@@ -13,6 +13,4 @@ pub mod gdb;
 mod gpu_config;
 #[cfg(feature = "plugin")]
 pub mod plugin;
-#[cfg(target_arch = "x86_64")]
-pub mod ratelimit;
 pub mod sys;
@@ -70,6 +70,8 @@ use serde::Deserializer;
 use serde::Serialize;
 #[cfg(feature = "gpu")]
 use serde_keyvalue::FromKeyValues;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use vm_control::MigrationAddress;
 use vm_memory::FileBackedMappingParameters;
 
 use super::config::PmemOption;
@@ -143,6 +145,7 @@ pub enum CrossPlatformCommands {
     CreateComposite(CreateCompositeCommand),
     #[cfg(feature = "qcow")]
     CreateQcow2(CreateQcow2Command),
+    Console(ConsoleCommand),
     Device(DeviceCommand),
     Disk(DiskCommand),
     #[cfg(feature = "gpu")]
@@ -150,8 +153,11 @@ pub enum CrossPlatformCommands {
     #[cfg(feature = "audio")]
     Snd(SndCommand),
     MakeRT(MakeRTCommand),
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    Migrate(MigrateCommand),
     Resume(ResumeCommand),
     Run(RunCommand),
+    Scsi(ScsiCommand),
     Stop(StopCommand),
     Suspend(SuspendCommand),
     Swap(SwapCommand),
@@ -161,7 +167,6 @@ pub enum CrossPlatformCommands {
     Usb(UsbCommand),
     Version(VersionCommand),
     Vfio(VfioCrosvmCommand),
-    #[cfg(feature = "pci-hotplug")]
     VirtioNet(VirtioNetCommand),
     Snapshot(SnapshotCommand),
 }
@@ -260,6 +265,12 @@ pub struct CreateQcow2Command {
 #[argh(subcommand)]
 pub enum DiskSubcommand {
     Resize(ResizeDiskSubcommand),
+    Snapshot(SnapshotDiskSubcommand),
+    Eject(EjectDiskSubcommand),
+    Insert(InsertDiskSubcommand),
+    Replace(ReplaceDiskSubcommand),
+    Job(JobDiskSubcommand),
+    Throttle(ThrottleDiskSubcommand),
 }
 
 #[derive(FromArgs)]
@@ -277,6 +288,249 @@ pub struct ResizeDiskSubcommand {
     pub socket_path: String,
 }
 
+#[derive(FromArgs)]
+/// limit the rate of guest requests to a disk, replacing the previous limits
+#[argh(subcommand, name = "throttle")]
+pub struct ThrottleDiskSubcommand {
+    #[argh(positional, arg_name = "DISK_INDEX")]
+    /// disk index
+    pub disk_index: usize,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+    #[argh(option, arg_name = "NUM")]
+    /// read requests per second
+    pub read_iops: Option<u64>,
+    #[argh(option, arg_name = "NUM")]
+    /// write, discard and write zeroes requests per second
+    pub write_iops: Option<u64>,
+    #[argh(option, arg_name = "BYTES")]
+    /// bytes read per second
+    pub read_bps: Option<u64>,
+    #[argh(option, arg_name = "BYTES")]
+    /// bytes written per second
+    pub write_bps: Option<u64>,
+}
+
+#[derive(FromArgs)]
+/// remove the media from a disk
+#[argh(subcommand, name = "eject")]
+pub struct EjectDiskSubcommand {
+    #[argh(positional, arg_name = "DISK_INDEX")]
+    /// disk index
+    pub disk_index: usize,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+/// insert media into an ejected disk
+#[argh(subcommand, name = "insert")]
+pub struct InsertDiskSubcommand {
+    #[argh(positional, arg_name = "DISK_INDEX")]
+    /// disk index
+    pub disk_index: usize,
+    #[argh(positional, arg_name = "PATH")]
+    /// disk image to insert
+    pub path: String,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+    #[argh(switch)]
+    /// open the image read-only, which is only valid for read-only disks
+    pub read_only: bool,
+}
+
+#[derive(FromArgs)]
+/// swap the media of a disk once its in-flight requests have completed
+#[argh(subcommand, name = "replace")]
+pub struct ReplaceDiskSubcommand {
+    #[argh(positional, arg_name = "DISK_INDEX")]
+    /// disk index
+    pub disk_index: usize,
+    #[argh(positional, arg_name = "PATH")]
+    /// new disk image
+    pub path: String,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+    #[argh(switch)]
+    /// open the image read-only, which is only valid for read-only disks
+    pub read_only: bool,
+}
+
+#[derive(FromArgs)]
+/// manage the snapshots stored inside a qcow2 disk image, either offline with --image or on a
+/// running VM with --disk-index and --socket
+#[argh(subcommand, name = "snapshot")]
+pub struct SnapshotDiskSubcommand {
+    #[argh(subcommand)]
+    pub command: DiskSnapshotSubcommand,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+pub enum DiskSnapshotSubcommand {
+    Create(CreateDiskSnapshotSubcommand),
+    List(ListDiskSnapshotsSubcommand),
+    Apply(ApplyDiskSnapshotSubcommand),
+    Delete(DeleteDiskSnapshotSubcommand),
+}
+
+#[derive(FromArgs)]
+/// store the current contents of the disk as a new snapshot
+#[argh(subcommand, name = "create")]
+pub struct CreateDiskSnapshotSubcommand {
+    #[argh(positional, arg_name = "NAME")]
+    /// snapshot name
+    pub name: String,
+    #[argh(option, arg_name = "PATH")]
+    /// disk image that is not in use by a VM
+    pub image: Option<String>,
+    #[argh(option, arg_name = "DISK_INDEX")]
+    /// disk index on the running VM
+    pub disk_index: Option<usize>,
+    #[argh(option, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket: Option<String>,
+}
+
+#[derive(FromArgs)]
+/// list the snapshots in the disk image
+#[argh(subcommand, name = "list")]
+pub struct ListDiskSnapshotsSubcommand {
+    #[argh(option, arg_name = "PATH")]
+    /// disk image that is not in use by a VM
+    pub image: Option<String>,
+    #[argh(option, arg_name = "DISK_INDEX")]
+    /// disk index on the running VM
+    pub disk_index: Option<usize>,
+    #[argh(option, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket: Option<String>,
+}
+
+#[derive(FromArgs)]
+/// revert the disk to a snapshot
+#[argh(subcommand, name = "apply")]
+pub struct ApplyDiskSnapshotSubcommand {
+    #[argh(positional, arg_name = "SNAPSHOT")]
+    /// snapshot ID or name
+    pub snapshot: String,
+    #[argh(option, arg_name = "PATH")]
+    /// disk image that is not in use by a VM
+    pub image: Option<String>,
+    #[argh(option, arg_name = "DISK_INDEX")]
+    /// disk index on the running VM
+    pub disk_index: Option<usize>,
+    #[argh(option, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket: Option<String>,
+}
+
+#[derive(FromArgs)]
+/// delete a snapshot
+#[argh(subcommand, name = "delete")]
+pub struct DeleteDiskSnapshotSubcommand {
+    #[argh(positional, arg_name = "SNAPSHOT")]
+    /// snapshot ID or name
+    pub snapshot: String,
+    #[argh(option, arg_name = "PATH")]
+    /// disk image that is not in use by a VM
+    pub image: Option<String>,
+    #[argh(option, arg_name = "DISK_INDEX")]
+    /// disk index on the running VM
+    pub disk_index: Option<usize>,
+    #[argh(option, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket: Option<String>,
+}
+
+#[derive(FromArgs)]
+/// copy a running VM's disk to another image with mirror or backup jobs
+#[argh(subcommand, name = "job")]
+pub struct JobDiskSubcommand {
+    #[argh(subcommand)]
+    pub command: DiskJobSubcommand,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+pub enum DiskJobSubcommand {
+    Mirror(MirrorDiskJobSubcommand),
+    Backup(BackupDiskJobSubcommand),
+    Status(StatusDiskJobSubcommand),
+    Complete(CompleteDiskJobSubcommand),
+    Cancel(CancelDiskJobSubcommand),
+}
+
+#[derive(FromArgs)]
+/// copy the disk to TARGET and keep it in sync with guest writes until the job is completed
+#[argh(subcommand, name = "mirror")]
+pub struct MirrorDiskJobSubcommand {
+    #[argh(positional, arg_name = "DISK_INDEX")]
+    /// disk index
+    pub disk_index: usize,
+    #[argh(positional, arg_name = "TARGET")]
+    /// image to copy the disk to, created as a raw image if it doesn't exist
+    pub target: String,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+/// copy the contents the disk had when the job started to TARGET
+#[argh(subcommand, name = "backup")]
+pub struct BackupDiskJobSubcommand {
+    #[argh(positional, arg_name = "DISK_INDEX")]
+    /// disk index
+    pub disk_index: usize,
+    #[argh(positional, arg_name = "TARGET")]
+    /// image to copy the disk to, created as a raw image if it doesn't exist
+    pub target: String,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+/// show the progress of the disk's block job
+#[argh(subcommand, name = "status")]
+pub struct StatusDiskJobSubcommand {
+    #[argh(positional, arg_name = "DISK_INDEX")]
+    /// disk index
+    pub disk_index: usize,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+/// switch the disk over to the target of a ready mirror job
+#[argh(subcommand, name = "complete")]
+pub struct CompleteDiskJobSubcommand {
+    #[argh(positional, arg_name = "DISK_INDEX")]
+    /// disk index
+    pub disk_index: usize,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+/// stop the disk's block job, leaving the target incomplete
+#[argh(subcommand, name = "cancel")]
+pub struct CancelDiskJobSubcommand {
+    #[argh(positional, arg_name = "DISK_INDEX")]
+    /// disk index
+    pub disk_index: usize,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
 #[derive(FromArgs)]
 #[argh(subcommand, name = "disk")]
 /// Manage attached virtual disk devices
@@ -285,6 +539,111 @@ pub struct DiskCommand {
     pub command: DiskSubcommand,
 }
 
+#[derive(FromArgs)]
+#[argh(subcommand, name = "console")]
+/// Manage the serial and virtio-console devices of the crosvm instance
+pub struct ConsoleCommand {
+    #[argh(subcommand)]
+    pub command: ConsoleSubcommand,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+pub enum ConsoleSubcommand {
+    Dump(DumpConsoleSubcommand),
+    Resize(ResizeConsoleSubcommand),
+}
+
+#[derive(FromArgs)]
+/// print the output history of a serial device with a tee
+#[argh(subcommand, name = "dump")]
+pub struct DumpConsoleSubcommand {
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+    #[argh(option, default = "SerialHardware::Serial")]
+    /// hardware of the device: serial, virtio-console or debugcon (default: serial)
+    pub hardware: SerialHardware,
+    #[argh(option, default = "1")]
+    /// number of the device, as given by the num= option of --serial (default: 1)
+    pub num: u8,
+}
+
+#[derive(FromArgs)]
+/// set the window size of a port of a virtio-console device
+#[argh(subcommand, name = "resize")]
+pub struct ResizeConsoleSubcommand {
+    #[argh(positional, arg_name = "CONSOLE_INDEX")]
+    /// index of the device, in the order of the --serial options with hardware=virtio-console
+    pub console_index: usize,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+    #[argh(option)]
+    /// number of columns of the window
+    pub cols: u16,
+    #[argh(option)]
+    /// number of rows of the window
+    pub rows: u16,
+    #[argh(option, default = "0")]
+    /// port of the device to resize (default: 0)
+    pub port: u32,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "scsi")]
+/// Manage the disks of the virtio-scsi controller
+pub struct ScsiCommand {
+    #[argh(subcommand)]
+    pub command: ScsiSubcommand,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+pub enum ScsiSubcommand {
+    Add(AddScsiSubcommand),
+    Remove(RemoveScsiSubcommand),
+}
+
+#[derive(FromArgs)]
+/// attach a disk to the virtio-scsi controller as a new logical unit
+#[argh(subcommand, name = "add")]
+pub struct AddScsiSubcommand {
+    #[argh(positional, arg_name = "PATH")]
+    /// disk image to attach
+    pub path: String,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+    #[argh(option)]
+    /// target id of the logical unit
+    pub target: u8,
+    #[argh(option, default = "0")]
+    /// logical unit number in the target (default: 0)
+    pub lun: u16,
+    #[argh(option, default = "512")]
+    /// reported block size of the disk (default: 512)
+    pub block_size: u32,
+    #[argh(switch)]
+    /// attach the disk read-only
+    pub read_only: bool,
+}
+
+#[derive(FromArgs)]
+/// detach a logical unit from the virtio-scsi controller
+#[argh(subcommand, name = "remove")]
+pub struct RemoveScsiSubcommand {
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+    #[argh(option)]
+    /// target id of the logical unit
+    pub target: u8,
+    #[argh(option, default = "0")]
+    /// logical unit number in the target (default: 0)
+    pub lun: u16,
+}
+
 #[derive(FromArgs)]
 #[argh(subcommand, name = "make_rt")]
 /// Enables real-time vcpu priority for crosvm instances started with `--delay-rt`
@@ -294,6 +653,19 @@ pub struct MakeRTCommand {
     pub socket_path: String,
 }
 
+#[cfg(any(target_os = "android", target_os = "linux"))]
+#[derive(FromArgs)]
+#[argh(subcommand, name = "migrate")]
+/// Migrates the crosvm instance to the one started with `--migrate-from DESTINATION`, then stops it
+pub struct MigrateCommand {
+    #[argh(positional, arg_name = "DESTINATION")]
+    /// path of a Unix socket, or IP address and port
+    pub destination: MigrationAddress,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
 #[derive(FromArgs)]
 #[argh(subcommand, name = "resume")]
 /// Resumes the crosvm instance. No-op if already running. When starting crosvm with `--restore`,
@@ -517,12 +889,17 @@ pub struct VfioCrosvmCommand {
     pub command: VfioSubCommand,
 }
 
-#[cfg(feature = "pci-hotplug")]
 #[derive(FromArgs)]
 #[argh(subcommand)]
 pub enum VirtioNetSubCommand {
+    #[cfg(feature = "pci-hotplug")]
     AddTap(VirtioNetAddSubCommand),
+    #[cfg(feature = "pci-hotplug")]
     RemoveTap(VirtioNetRemoveSubCommand),
+    CaptureStart(VirtioNetCaptureStartSubCommand),
+    CaptureStop(VirtioNetCaptureStopSubCommand),
+    LinkUp(VirtioNetLinkUpSubCommand),
+    LinkDown(VirtioNetLinkDownSubCommand),
 }
 
 #[cfg(feature = "pci-hotplug")]
@@ -551,10 +928,60 @@ pub struct VirtioNetRemoveSubCommand {
     pub socket_path: String,
 }
 
-#[cfg(feature = "pci-hotplug")]
+#[derive(FromArgs)]
+#[argh(subcommand, name = "capture-start")]
+/// Start writing the frames of a network device to a pcapng file.
+pub struct VirtioNetCaptureStartSubCommand {
+    #[argh(positional, arg_name = "NET_INDEX")]
+    /// index of the network device, in the order of the --net options
+    pub net_index: usize,
+    #[argh(positional, arg_name = "FILE")]
+    /// pcapng file to write the frames to, replaced if it exists
+    pub file: String,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "capture-stop")]
+/// Stop writing the frames of a network device to a pcapng file.
+pub struct VirtioNetCaptureStopSubCommand {
+    #[argh(positional, arg_name = "NET_INDEX")]
+    /// index of the network device, in the order of the --net options
+    pub net_index: usize,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "link-up")]
+/// Bring the link of a network device up.
+pub struct VirtioNetLinkUpSubCommand {
+    #[argh(positional, arg_name = "NET_INDEX")]
+    /// index of the network device, in the order of the --net options
+    pub net_index: usize,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "link-down")]
+/// Bring the link of a network device down, dropping its frames until it is brought up.
+pub struct VirtioNetLinkDownSubCommand {
+    #[argh(positional, arg_name = "NET_INDEX")]
+    /// index of the network device, in the order of the --net options
+    pub net_index: usize,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
 #[derive(FromArgs)]
 #[argh(subcommand, name = "virtio-net")]
-/// add network device as virtio into guest.
+/// add network device as virtio into guest, capture its frames or change its link state.
 pub struct VirtioNetCommand {
     #[argh(subcommand)]
     pub command: VirtioNetSubCommand,
@@ -780,6 +1207,34 @@ pub struct SnapshotTakeCommand {
     #[argh(switch, arg_name = "encrypt")]
     /// whether the snapshot should be encrypted
     pub encrypt: bool,
+    #[argh(switch)]
+    /// only write the memory pages changed since the previous incremental snapshot, which the
+    /// snapshot refers to. The first one is a full snapshot.
+    pub incremental: bool,
+    #[argh(switch)]
+    /// write the snapshot as a single file archive instead of a directory.
+    pub archive: bool,
+    #[argh(switch)]
+    /// compress the fragments of the archive. Requires --archive.
+    pub compress_archive: bool,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "inspect")]
+/// List the fragments of a snapshot archive and check their data
+pub struct SnapshotInspectCommand {
+    #[argh(positional, arg_name = "snapshot_path")]
+    /// snapshot archive path
+    pub snapshot_path: PathBuf,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "verify")]
+/// Check the fragments of a snapshot against the checksums of its manifest
+pub struct SnapshotVerifyCommand {
+    #[argh(positional, arg_name = "snapshot_path")]
+    /// snapshot directory or archive path
+    pub snapshot_path: PathBuf,
 }
 
 #[derive(FromArgs)]
@@ -787,6 +1242,8 @@ pub struct SnapshotTakeCommand {
 /// Snapshot commands
 pub enum SnapshotSubCommands {
     Take(SnapshotTakeCommand),
+    Inspect(SnapshotInspectCommand),
+    Verify(SnapshotVerifyCommand),
 }
 
 /// Container for GpuParameters that have been fixed after parsing using serde.
@@ -1003,6 +1460,14 @@ pub struct RunCommand {
     /// path for balloon controller socket.
     pub balloon_control: Option<PathBuf>,
 
+    #[cfg(feature = "balloon")]
+    #[argh(switch)]
+    #[serde(skip)] // TODO(b/255223604)
+    #[merge(strategy = overwrite_option)]
+    /// enable free page hinting in balloon, so that free guest pages are skipped when taking
+    /// snapshots and enabling vmm-swap.
+    pub balloon_free_page_hinting: Option<bool>,
+
     #[cfg(feature = "balloon")]
     #[argh(switch)]
     #[serde(skip)] // TODO(b/255223604)
@@ -1077,6 +1542,16 @@ pub struct RunCommand {
     ///         after failing to boot from the device with
     ///         bootindex=1.
     ///     pci-address=ADDR - Preferred PCI address, e.g. "00:01.0".
+    ///     throttle=[read-iops=NUM,write-iops=NUM,read-bps=NUM,
+    ///         write-bps=NUM] - Limit the rate of guest requests in
+    ///         requests or bytes per second. Limits that aren't
+    ///         specified are not enforced. (default: no limits)
+    ///     overlay=PATH - Path of a qcow2 image that receives the
+    ///         writes to the disk, which is created if it doesn't
+    ///         exist. The disk image is only read.
+    ///     ephemeral=BOOL - Discard the writes to the disk when the
+    ///         VM exits. The disk image is only read.
+    ///         (default: false)
     block: Vec<DiskOptionWithId>,
 
     #[cfg(any(target_os = "android", target_os = "linux"))]
@@ -1685,6 +2160,14 @@ pub struct RunCommand {
     ///     size=NUM - amount of guest memory in MiB. (default: 256)
     pub mem: Option<MemOptions>,
 
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    #[argh(option, arg_name = "ADDRESS")]
+    #[serde(skip)]
+    #[merge(strategy = overwrite_option)]
+    /// wait for a VM migrated with `crosvm migrate` on ADDRESS, the path of a Unix socket or an IP
+    /// address and port, and run it instead of booting.
+    pub migrate_from: Option<MigrationAddress>,
+
     #[argh(option, from_str_fn(parse_mmio_address_range))]
     #[serde(skip)] // TODO(b/255223604)
     #[merge(strategy = overwrite_option)]
@@ -1727,7 +2210,7 @@ pub struct RunCommand {
     #[cfg(all(unix, feature = "net"))]
     #[argh(
         option,
-        arg_name = "(tap-name=TAP_NAME,mac=MAC_ADDRESS|tap-fd=TAP_FD,mac=MAC_ADDRESS|host-ip=IP,netmask=NETMASK,mac=MAC_ADDRESS),vhost-net=VHOST_NET,vq-pairs=N,pci-address=ADDR"
+        arg_name = "(tap-name=TAP_NAME,mac=MAC_ADDRESS|tap-fd=TAP_FD,mac=MAC_ADDRESS|host-ip=IP,netmask=NETMASK,mac=MAC_ADDRESS|socket=PATH,mac=MAC_ADDRESS|socket-fd=FD,mac=MAC_ADDRESS|slirp,host-fwd=[FORWARD,...],mac=MAC_ADDRESS),vhost-net=VHOST_NET,vq-pairs=N,pci-address=ADDR,capture=PATH"
     )]
     #[serde(default)]
     #[merge(strategy = append)]
@@ -1751,6 +2234,24 @@ pub struct RunCommand {
     ///       AND
     ///         mac=STRING      - MAC address for VM.
     ///      )
+    ///    OR
+    ///      socket=PATH     - path of a Unix stream or datagram
+    ///                          socket to exchange frames with,
+    ///                          using the framing of QEMU's stream
+    ///                          and dgram netdevs.
+    ///      mac=STRING      - MAC address for VM. [Optional]
+    ///    OR
+    ///      socket-fd=INT   - File descriptor of a connected stream
+    ///                          or datagram socket.
+    ///      mac=STRING      - MAC address for VM. [Optional]
+    ///    OR
+    ///      slirp           - use libslirp user-mode networking,
+    ///                          which needs no tap device or
+    ///                          privileges.
+    ///      host-fwd=[FORWARD,...]
+    ///                      - host ports forwarded to the guest, each as PROTOCOL:[HOST_ADDR]:
+    ///                        HOST_PORT-[GUEST_ADDR]:GUEST_PORT, e.g. tcp::2222-:22. [Optional]
+    ///      mac=STRING      - MAC address for VM. [Optional]
     ///   )
     /// AND
     ///   vhost-net
@@ -1768,9 +2269,13 @@ pub struct RunCommand {
     ///                       Default: false.  [Optional]
     ///   pci-address     - preferred PCI address, e.g. "00:01.0"
     ///                       Default: automatic PCI address assignment. [Optional]
+    ///   capture=PATH    - write the frames exchanged by the
+    ///                       device to a pcapng file. Can't be
+    ///                       used with vhost-net. [Optional]
     ///
-    /// Either one tap_name, one tap_fd or a triplet of host_ip,
-    /// netmask and mac must be specified.
+    /// Either one tap_name, one tap_fd, a triplet of host_ip,
+    /// netmask and mac, one socket, one socket_fd, or slirp must be
+    /// specified.
     pub net: Vec<NetParameters>,
 
     #[cfg(all(unix, feature = "net"))]
@@ -2087,6 +2592,14 @@ pub struct RunCommand {
     /// path of the snapshot that is used to restore the VM on startup.
     pub restore: Option<PathBuf>,
 
+    #[cfg(feature = "swap")]
+    #[argh(switch)]
+    #[serde(skip)] // TODO(b/255223604)
+    #[merge(strategy = overwrite_option)]
+    /// load the guest memory of the `--restore` snapshot on demand after the VM resumes instead of
+    /// before. Requires `--swap`.
+    pub restore_lazily: Option<bool>,
+
     #[argh(option, arg_name = "PATH[,key=value[,key=value[,...]]]", short = 'r')]
     #[serde(skip)] // Deprecated - use `block` instead.
     #[merge(strategy = overwrite_option)]
@@ -2177,6 +2690,10 @@ pub struct RunCommand {
     ///         as the root filesystem. This will add the required
     ///         parameters to the kernel command-line. Can only be
     ///         specified once. (default: false)
+    ///     target=NUM - The target id of the disk. (default: the
+    ///         index of the disk among the scsi disks)
+    ///     lun=NUM - The logical unit number of the disk in its
+    ///         target, up to 16383. (default: 0)
     // TODO(b/300580119): Add O_DIRECT and sparse file support.
     scsi_block: Vec<ScsiOption>,
 
@@ -2207,7 +2724,8 @@ pub struct RunCommand {
     ///     type=(stdout,syslog,sink,file) - Where to route the
     ///        serial device.
     ///        Platform-specific options:
-    ///        On Unix: 'unix' (datagram) and 'unix-stream' (stream)
+    ///        On Unix: 'unix' (datagram), 'unix-stream' (stream),
+    ///        'pty' (pseudo-terminal) and 'tcp' (local TCP port)
     ///        On Windows: 'namedpipe'
     ///     hardware=(serial,virtio-console,debugcon) - Which type of
     ///        serial hardware to emulate. Defaults to 8250 UART
@@ -2221,6 +2739,8 @@ pub struct RunCommand {
     ///        expects.
     ///     path=PATH - The path to the file to write to when
     ///        type=file
+    ///        or the symbolic link to create to the terminal when
+    ///        type=pty
     ///     input=PATH - The path to the file to read from when not
     ///        stdin
     ///     input-unix-stream - (Unix-only) Whether to use the given
@@ -2228,6 +2748,8 @@ pub struct RunCommand {
     ///        This flag is only valid when type=unix-stream and
     ///        the socket path is specified with path=.
     ///        Can't be passed when input is specified.
+    ///     tcp-port=PORT - (Unix-only) Local TCP port to listen on
+    ///        when type=tcp.
     ///     console - Use this serial device as the guest console.
     ///        Will default to first serial port if not provided.
     ///     earlycon - Use this serial device as the early console.
@@ -2238,6 +2760,11 @@ pub struct RunCommand {
     ///     pci-address - Preferred PCI address, e.g. "00:01.0".
     ///     max-queue-sizes=[uint,uint] - Max size of each virtio
     ///        queue. Only applicable when hardware=virtio-console.
+    ///     tee=PATH - Write a timestamped copy of the output to
+    ///        PATH and keep it in a history that can be retrieved
+    ///        with `crosvm console dump`.
+    ///     tee-history-size=BYTES - Size of the history kept when
+    ///        tee is given. Defaults to 65536.
     pub serial: Vec<SerialParameters>,
 
     #[cfg(windows)]
@@ -2680,7 +3207,7 @@ pub struct RunCommand {
     ///         per device.
     pub virtio_snd: Vec<SndParameters>,
 
-    #[argh(option, arg_name = "cid=CID[,device=VHOST_DEVICE]")]
+    #[argh(option, arg_name = "cid=CID[,device=VHOST_DEVICE][,uds-path=PATH]")]
     #[serde(default)]
     #[merge(strategy = overwrite_option)]
     /// add a vsock device. Since a guest can only have one CID,
@@ -2690,6 +3217,11 @@ pub struct RunCommand {
     ///         use (Linux only). Defaults to /dev/vhost-vsock.
     ///     max-queue-sizes=[uint,uint,uint] - Max size of each
     ///         virtio queue.
+    ///     uds-path=PATH - use a userspace device instead of
+    ///         vhost-vsock (Linux only). Host programs connect to
+    ///         the guest through the Unix socket at PATH, and
+    ///         guest connections to port P are forwarded to the
+    ///         Unix socket at PATH_P.
     pub vsock: Option<VsockConfig>,
 
     #[cfg(feature = "vtpm")]
@@ -3032,12 +3564,22 @@ impl TryFrom<RunCommand> for super::config::Config {
             .filter(|(_, d)| d.root)
             .map(|(i, d)| (format_disk_letter("/dev/vd", i), d.read_only));
 
-        let virtio_scsi_root_devs = cfg
+        // The guest names scsi disks in the order of their target ids and LUNs.
+        let scsi_addresses = cfg
             .scsis
             .iter()
             .enumerate()
-            .filter(|(_, s)| s.root)
-            .map(|(i, s)| (format_disk_letter("/dev/sd", i), s.read_only));
+            .map(|(i, s)| s.address(i))
+            .collect::<Result<Vec<_>, _>>()?;
+        let virtio_scsi_root_devs = cfg
+            .scsis
+            .iter()
+            .zip(&scsi_addresses)
+            .filter(|(s, _)| s.root)
+            .map(|(s, address)| {
+                let index = scsi_addresses.iter().filter(|a| *a < address).count();
+                (format_disk_letter("/dev/sd", index), s.read_only)
+            });
 
         let virtio_pmem_root_devs = cfg
             .pmems
@@ -3125,6 +3667,14 @@ impl TryFrom<RunCommand> for super::config::Config {
 
         cfg.swap_dir = cmd.swap_dir;
         cfg.restore_path = cmd.restore;
+        #[cfg(feature = "swap")]
+        {
+            cfg.restore_lazily = cmd.restore_lazily.unwrap_or_default();
+        }
+        #[cfg(any(target_os = "android", target_os = "linux"))]
+        {
+            cfg.migrate_from = cmd.migrate_from;
+        }
         cfg.suspended = cmd.suspended.unwrap_or_default();
 
         if let Some(mut socket_path) = cmd.socket {
@@ -3364,6 +3914,7 @@ impl TryFrom<RunCommand> for super::config::Config {
             }
 
             cfg.balloon_control = cmd.balloon_control;
+            cfg.balloon_free_page_hinting = cmd.balloon_free_page_hinting.unwrap_or_default();
             cfg.balloon_page_reporting = cmd.balloon_page_reporting.unwrap_or_default();
             cfg.balloon_ws_num_bins = cmd.balloon_ws_num_bins.unwrap_or(4);
             cfg.balloon_ws_reporting = cmd.balloon_ws_reporting.unwrap_or_default();
@@ -3462,6 +4013,7 @@ impl TryFrom<RunCommand> for super::config::Config {
                         mac: None,
                     },
                     vhost_net: vhost_net_config.clone(),
+                    capture: None,
                     vq_pairs: cmd.net_vq_pairs,
                     packed_queue: false,
                     pci_address: None,
@@ -3476,6 +4028,7 @@ impl TryFrom<RunCommand> for super::config::Config {
                 cfg.net.push(NetParameters {
                     mode: NetParametersMode::TapFd { tap_fd, mac: None },
                     vhost_net: vhost_net_config.clone(),
+                    capture: None,
                     vq_pairs: cmd.net_vq_pairs,
                     packed_queue: false,
                     pci_address: None,
@@ -3508,6 +4061,7 @@ impl TryFrom<RunCommand> for super::config::Config {
                         mac,
                     },
                     vhost_net: vhost_net_config,
+                    capture: None,
                     vq_pairs: cmd.net_vq_pairs,
                     packed_queue: false,
                     pci_address: None,
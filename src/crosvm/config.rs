@@ -62,6 +62,8 @@ use serde::Deserializer;
 use serde::Serialize;
 use serde_keyvalue::FromKeyValues;
 use vm_control::BatteryType;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use vm_control::MigrationAddress;
 use vm_memory::FileBackedMappingParameters;
 #[cfg(target_arch = "x86_64")]
 use x86_64::check_host_hybrid_support;
@@ -573,6 +575,8 @@ pub struct Config {
     #[cfg(feature = "balloon")]
     pub balloon_control: Option<PathBuf>,
     #[cfg(feature = "balloon")]
+    pub balloon_free_page_hinting: bool,
+    #[cfg(feature = "balloon")]
     pub balloon_page_reporting: bool,
     #[cfg(feature = "balloon")]
     pub balloon_ws_num_bins: u8,
@@ -674,6 +678,8 @@ pub struct Config {
     pub media_decoder: Vec<VideoDeviceConfig>,
     pub memory: Option<u64>,
     pub memory_file: Option<PathBuf>,
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    pub migrate_from: Option<MigrationAddress>,
     pub mmio_address_ranges: Vec<AddressRange>,
     #[cfg(target_arch = "aarch64")]
     pub mte: bool,
@@ -717,6 +723,8 @@ pub struct Config {
     /// Must be `Some` iff `protection_type == ProtectionType::UnprotectedWithFirmware`.
     pub pvm_fw: Option<PathBuf>,
     pub restore_path: Option<PathBuf>,
+    #[cfg(feature = "swap")]
+    pub restore_lazily: bool,
     pub rng: bool,
     pub rt_cpus: CpuSet,
     pub scsis: Vec<ScsiOption>,
@@ -809,6 +817,8 @@ impl Default for Config {
             #[cfg(feature = "balloon")]
             balloon_control: None,
             #[cfg(feature = "balloon")]
+            balloon_free_page_hinting: false,
+            #[cfg(feature = "balloon")]
             balloon_page_reporting: false,
             #[cfg(feature = "balloon")]
             balloon_ws_num_bins: VIRTIO_BALLOON_WS_DEFAULT_NUM_BINS,
@@ -918,6 +928,8 @@ impl Default for Config {
             media_decoder: Default::default(),
             memory: None,
             memory_file: None,
+            #[cfg(any(target_os = "android", target_os = "linux"))]
+            migrate_from: None,
             mmio_address_ranges: Vec::new(),
             #[cfg(target_arch = "aarch64")]
             mte: false,
@@ -956,6 +968,8 @@ impl Default for Config {
             pvclock: false,
             pvm_fw: None,
             restore_path: None,
+            #[cfg(feature = "swap")]
+            restore_lazily: false,
             rng: true,
             rt_cpus: Default::default(),
             serial_parameters: BTreeMap::new(),
@@ -1204,6 +1218,15 @@ pub fn validate_config(cfg: &mut Config) -> std::result::Result<(), String> {
         if !cfg.balloon && cfg.balloon_page_reporting {
             return Err("'balloon_page_reporting' requires enabled balloon".to_string());
         }
+
+        if !cfg.balloon && cfg.balloon_free_page_hinting {
+            return Err("'balloon_free_page_hinting' requires enabled balloon".to_string());
+        }
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    if cfg.migrate_from.is_some() && cfg.restore_path.is_some() {
+        return Err("'migrate-from' and 'restore' are mutually exclusive".to_string());
     }
 
     #[cfg(any(target_os = "android", target_os = "linux"))]
@@ -1226,6 +1249,11 @@ pub fn validate_config(cfg: &mut Config) -> std::result::Result<(), String> {
         return Err("'swap' and 'disable-sandbox' are mutually exclusive".to_string());
     }
 
+    #[cfg(feature = "swap")]
+    if cfg.restore_lazily && (cfg.restore_path.is_none() || cfg.swap_dir.is_none()) {
+        return Err("'restore-lazily' requires 'restore' and 'swap'".to_string());
+    }
+
     set_default_serial_parameters(
         &mut cfg.serial_parameters,
         cfg.vhost_user
@@ -604,6 +604,13 @@ pub fn run_config(cfg: Config) -> Result<()> {
                 }
                 tap_interfaces.push(tap);
             }
+            NetParametersMode::Socket { .. } | NetParametersMode::SocketFd { .. } => {
+                bail!("socket networking not supported with plugin");
+            }
+            #[cfg(feature = "slirp")]
+            NetParametersMode::Slirp { .. } => {
+                bail!("slirp networking not supported with plugin");
+            }
         }
     }
 
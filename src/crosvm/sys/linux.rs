@@ -139,6 +139,8 @@ use devices::PcieRootPort;
 use devices::PcieUpstreamPort;
 use devices::PvPanicCode;
 use devices::PvPanicPciDevice;
+#[cfg(target_arch = "x86_64")]
+use devices::Ratelimit;
 #[cfg(feature = "pci-hotplug")]
 use devices::ResourceCarrier;
 use devices::StubPciDevice;
@@ -202,8 +204,6 @@ use crate::crosvm::config::DEFAULT_TOUCH_DEVICE_WIDTH;
 use crate::crosvm::gdb::gdb_thread;
 #[cfg(feature = "gdb")]
 use crate::crosvm::gdb::GdbStub;
-#[cfg(target_arch = "x86_64")]
-use crate::crosvm::ratelimit::Ratelimit;
 use crate::crosvm::sys::cmdline::DevicesCommand;
 use crate::crosvm::sys::config::SharedDir;
 use crate::crosvm::sys::config::SharedDirKind;
@@ -215,6 +215,9 @@ const KVM_PATH: &str = "/dev/kvm";
 const GENIEZONE_PATH: &str = "/dev/gzvm";
 #[cfg(all(any(target_arch = "arm", target_arch = "aarch64"), feature = "gunyah"))]
 static GUNYAH_PATH: &str = "/dev/gunyah";
+// Time given to the guest to hint its free pages before its memory is written out.
+#[cfg(feature = "balloon")]
+const FREE_PAGE_HINT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
 
 fn create_virtio_devices(
     cfg: &Config,
@@ -384,8 +387,12 @@ fn create_virtio_devices(
         .iter()
         .filter(|(_k, v)| v.hardware == SerialHardware::VirtioConsole)
     {
-        let dev =
-            param.create_virtio_device_and_jail(cfg.protection_type, cfg.jail_config.as_ref())?;
+        let (console_host_tube, console_device_tube) =
+            Tube::pair().context("failed to create tube")?;
+        add_control_tube(DeviceControlTube::Console(console_host_tube).into());
+        let console_config = ConsoleConfig::new(param, Some(console_device_tube));
+        let dev = console_config
+            .create_virtio_device_and_jail(cfg.protection_type, cfg.jail_config.as_ref())?;
         devs.push(dev);
     }
 
@@ -400,7 +407,9 @@ fn create_virtio_devices(
     }
 
     if !cfg.scsis.is_empty() {
-        let scsi_config = ScsiConfig(&cfg.scsis);
+        let (scsi_host_tube, scsi_device_tube) = Tube::pair().context("failed to create tube")?;
+        add_control_tube(DeviceControlTube::Scsi(scsi_host_tube).into());
+        let scsi_config = ScsiConfig::new(&cfg.scsis, Some(scsi_device_tube));
         devs.push(
             scsi_config
                 .create_virtio_device_and_jail(cfg.protection_type, cfg.jail_config.as_ref())?,
@@ -696,7 +705,8 @@ fn create_virtio_devices(
 
         let balloon_features = (cfg.balloon_page_reporting as u64)
             << BalloonFeatures::PageReporting as u64
-            | (cfg.balloon_ws_reporting as u64) << BalloonFeatures::WSReporting as u64;
+            | (cfg.balloon_ws_reporting as u64) << BalloonFeatures::WSReporting as u64
+            | (cfg.balloon_free_page_hinting as u64) << BalloonFeatures::FreePageHinting as u64;
 
         let init_balloon_size = if let Some(init_memory) = cfg.init_memory {
             let init_memory_bytes = init_memory.saturating_mul(1024 * 1024);
@@ -749,8 +759,11 @@ fn create_virtio_devices(
 
     #[cfg(feature = "net")]
     for opt in &cfg.net {
-        let dev =
-            opt.create_virtio_device_and_jail(cfg.protection_type, cfg.jail_config.as_ref())?;
+        let (net_host_tube, net_device_tube) = Tube::pair().context("failed to create tube")?;
+        add_control_tube(DeviceControlTube::Net(net_host_tube).into());
+        let net_config = NetConfig::new(opt, Some(net_device_tube));
+        let dev = net_config
+            .create_virtio_device_and_jail(cfg.protection_type, cfg.jail_config.as_ref())?;
         devs.push(dev);
     }
 
@@ -2050,6 +2063,12 @@ where
     // before any jailed devices have been spawned, so that we can catch any of them that fail very
     // quickly.
     let sigchld_fd = SignalFd::new(libc::SIGCHLD).context("failed to create signalfd")?;
+    // The window size of the terminal is forwarded to the virtio-console reading from it.
+    let sigwinch_fd = if stdin_virtio_console_index(&cfg).is_some() {
+        Some(SignalFd::new(libc::SIGWINCH).context("failed to create signalfd")?)
+    } else {
+        None
+    };
 
     let control_server_socket = match &cfg.socket_path {
         Some(path) => Some(UnlinkUnixSeqpacketListener(
@@ -2419,6 +2438,7 @@ where
         vm_evt_rdtube,
         vm_evt_wrtube,
         sigchld_fd,
+        sigwinch_fd,
         gralloc,
         vcpu_ids,
         iommu_host_tube,
@@ -2740,6 +2760,7 @@ fn handle_hotplug_net_add<V: VmArch, Vcpu: VcpuArch>(
     let net_param = NetParameters {
         mode: net_param_mode,
         vhost_net: None,
+        capture: None,
         vq_pairs: None,
         packed_queue: false,
         pci_address: None,
@@ -3058,7 +3079,11 @@ struct ControlLoopState<'a, V: VmArch, Vcpu: VcpuArch> {
     cfg: &'a Config,
     sys_allocator: &'a Arc<Mutex<SystemAllocator>>,
     control_tubes: &'a BTreeMap<usize, TaggedControlTube>,
+    console_host_tubes: &'a [Tube],
     disk_host_tubes: &'a [Tube],
+    #[cfg(feature = "net")]
+    net_host_tubes: &'a [Tube],
+    scsi_host_tube: Option<&'a Tube>,
     #[cfg(feature = "audio")]
     snd_host_tubes: &'a [Tube],
     #[cfg(feature = "gpu")]
@@ -3087,6 +3112,7 @@ struct ControlLoopState<'a, V: VmArch, Vcpu: VcpuArch> {
     pvclock_host_tube: Option<Arc<Tube>>,
     vfio_container_manager: &'a mut VfioContainerManager,
     suspended_pvclock_state: &'a mut Option<hypervisor::ClockState>,
+    snapshot_chain: &'a mut Option<SnapshotChain>,
     vcpus_pid_tid: &'a BTreeMap<usize, (u32, u32)>,
 }
 
@@ -3229,6 +3255,35 @@ fn process_vm_request<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
                 VmResponse::Err(base::Error::new(libc::ENOTSUP))
             }
         }
+        VmRequest::ConsoleDeviceCommand {
+            console_index,
+            ref command,
+        } => match state.console_host_tubes.get(console_index) {
+            Some(tube) => vm_control::handle_console_device_command(command, tube),
+            None => VmResponse::Err(base::Error::new(libc::ENODEV)),
+        },
+        VmRequest::ConsoleHistory { ref hardware, num } => {
+            match hardware
+                .parse::<SerialHardware>()
+                .ok()
+                .and_then(|hardware| devices::serial_device::console_history(hardware, num))
+            {
+                Some(history) => VmResponse::ConsoleHistory(history.contents()),
+                None => VmResponse::Err(base::Error::new(libc::ENODEV)),
+            }
+        }
+        #[cfg(feature = "net")]
+        VmRequest::NetDeviceCommand {
+            net_index,
+            ref command,
+        } => match state.net_host_tubes.get(net_index) {
+            Some(tube) => vm_control::handle_net_device_command(command, tube),
+            None => VmResponse::Err(base::Error::new(libc::ENODEV)),
+        },
+        VmRequest::ScsiCommand(ref command) => match state.scsi_host_tube {
+            Some(tube) => vm_control::handle_scsi_command(command, tube),
+            None => VmResponse::Err(base::Error::new(libc::ENODEV)),
+        },
         VmRequest::VcpuPidTid => VmResponse::VcpuPidTidResponse {
             pid_tid_map: state.vcpus_pid_tid.clone(),
         },
@@ -3264,6 +3319,25 @@ fn process_vm_request<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
                     }
                 }
             }
+            // Let the guest hint its free pages while it is still running, so that they are
+            // skipped when its memory is written out.
+            #[cfg(feature = "balloon")]
+            let free_page_hinting = state.cfg.balloon_free_page_hinting
+                && matches!(
+                    request,
+                    VmRequest::Snapshot(SnapshotCommand::Take { .. })
+                        | VmRequest::Migrate { .. }
+                        | VmRequest::Swap(SwapCommand::Enable)
+                );
+            #[cfg(feature = "balloon")]
+            if free_page_hinting {
+                if let Some(tube) = state.balloon_tube.as_mut() {
+                    match tube.hint_free_pages(FREE_PAGE_HINT_TIMEOUT) {
+                        Ok(num_bytes) => info!("guest hinted {} bytes of free pages", num_bytes),
+                        Err(e) => warn!("proceeding without free page hints: {:#}", e),
+                    }
+                }
+            }
             let kick_all_vcpus = |msg| {
                 if let VcpuControl::RunState(VmRunMode::Running) = msg {
                     for dev in &state.linux.resume_notify_devices {
@@ -3305,7 +3379,21 @@ fn process_vm_request<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
                 state.irq_handler_control,
                 || state.linux.irq_chip.snapshot(state.linux.vcpu_count),
                 state.suspended_pvclock_state,
+                state.snapshot_chain,
+                || state.linux.root_config.lock().device_labels(),
             );
+            #[cfg(feature = "balloon")]
+            if free_page_hinting {
+                if let Some(tube) = state.balloon_tube.as_mut() {
+                    if let Err(e) = tube.free_page_hint_done() {
+                        error!("failed to release free page hints: {:#}", e);
+                    }
+                }
+            }
+            if let (VmRequest::Migrate { .. }, VmResponse::Ok) = (&request, &response) {
+                // The VM runs on the destination now.
+                return Ok(VmRequestResult::new(Some(response), true));
+            }
             if state.cfg.force_s2idle {
                 if let VmRequest::SuspendVcpus = request {
                     // Spawn s2idle wait thread.
@@ -3520,6 +3608,14 @@ fn make_addr_tube_from_maybe_existing(
     }
 }
 
+/// Returns the index among the virtio-console devices of the one reading from stdin, if any.
+fn stdin_virtio_console_index(cfg: &Config) -> Option<usize> {
+    cfg.serial_parameters
+        .values()
+        .filter(|param| param.hardware == SerialHardware::VirtioConsole)
+        .position(|param| param.stdin)
+}
+
 fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
     mut linux: RunnableLinuxVm<V, Vcpu>,
     sys_allocator: SystemAllocator,
@@ -3530,6 +3626,7 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
     vm_evt_rdtube: RecvTube,
     vm_evt_wrtube: SendTube,
     sigchld_fd: SignalFd,
+    sigwinch_fd: Option<SignalFd>,
     gralloc: RutabagaGralloc,
     vcpu_ids: Vec<usize>,
     iommu_host_tube: Option<Tube>,
@@ -3553,11 +3650,15 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
     // Split up `all_control_tubes`.
     #[cfg(feature = "balloon")]
     let mut balloon_host_tube = None;
+    let mut console_host_tubes = Vec::new();
     let mut disk_host_tubes = Vec::new();
     #[cfg(feature = "gpu")]
     let mut gpu_control_tube = None;
     #[cfg(feature = "pvclock")]
     let mut pvclock_host_tube = None;
+    #[cfg(feature = "net")]
+    let mut net_host_tubes = Vec::new();
+    let mut scsi_host_tube = None;
     #[cfg(feature = "audio")]
     let mut snd_host_tubes = Vec::new();
     let mut irq_control_tubes = Vec::new();
@@ -3570,6 +3671,9 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
                 assert!(balloon_host_tube.is_none());
                 balloon_host_tube = Some(t)
             }
+            AnyControlTube::DeviceControlTube(DeviceControlTube::Console(t)) => {
+                console_host_tubes.push(t)
+            }
             AnyControlTube::DeviceControlTube(DeviceControlTube::Disk(t)) => {
                 disk_host_tubes.push(t)
             }
@@ -3583,6 +3687,14 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
                 assert!(pvclock_host_tube.is_none());
                 pvclock_host_tube = Some(Arc::new(t))
             }
+            #[cfg(feature = "net")]
+            AnyControlTube::DeviceControlTube(DeviceControlTube::Net(t)) => {
+                net_host_tubes.push(t);
+            }
+            AnyControlTube::DeviceControlTube(DeviceControlTube::Scsi(t)) => {
+                assert!(scsi_host_tube.is_none());
+                scsi_host_tube = Some(t)
+            }
             #[cfg(feature = "audio")]
             AnyControlTube::DeviceControlTube(DeviceControlTube::Snd(t)) => {
                 snd_host_tubes.push(t);
@@ -3613,6 +3725,7 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
         VmEvent,
         Suspend,
         ChildSignal,
+        WindowResize,
         VmControlServer,
         VmControl {
             id: usize,
@@ -3643,6 +3756,12 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
             .add(socket_server, Token::VmControlServer)
             .context("failed to add descriptor to wait context")?;
     }
+    if let Some(sigwinch_fd) = &sigwinch_fd {
+        wait_ctx
+            .add(sigwinch_fd, Token::WindowResize)
+            .context("failed to add descriptor to wait context")?;
+    }
+    let stdin_console_index = stdin_virtio_console_index(&cfg);
     let mut control_tubes = BTreeMap::from_iter(control_tubes.into_iter().enumerate());
     let mut next_control_id = control_tubes.len();
     for (id, socket) in control_tubes.iter() {
@@ -3786,12 +3905,13 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
         // Wait until a GDB client attaches
         run_mode = VmRunMode::Breakpoint;
     }
-    // If we are restoring from a snapshot, then start suspended.
-    let (run_mode, post_restore_run_mode) = if cfg.restore_path.is_some() {
-        (VmRunMode::Suspending, run_mode)
-    } else {
-        (run_mode, run_mode)
-    };
+    // If we are restoring from a snapshot or a migration, then start suspended.
+    let (run_mode, post_restore_run_mode) =
+        if cfg.restore_path.is_some() || cfg.migrate_from.is_some() {
+            (VmRunMode::Suspending, run_mode)
+        } else {
+            (run_mode, run_mode)
+        };
 
     // Architecture-specific code must supply a vcpu_init element for each VCPU.
     assert_eq!(vcpus.len(), linux.vcpu_init.len());
@@ -3960,6 +4080,7 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
 
     // See comment on `VmRequest::execute`.
     let mut suspended_pvclock_state: Option<hypervisor::ClockState> = None;
+    let mut snapshot_chain = None;
 
     // Restore VM (if applicable).
     // Must happen after the vCPU barrier to avoid deadlock.
@@ -3982,6 +4103,9 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
             /* require_encrypted= */ false,
             &mut suspended_pvclock_state,
             &linux.vm,
+            || linux.root_config.lock().device_labels(),
+            #[cfg(feature = "swap")]
+            swap_controller.as_ref().filter(|_| cfg.restore_lazily),
         )?;
         // Allow the vCPUs to start for real.
         vcpu::kick_all_vcpus(
@@ -3990,6 +4114,32 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
             VcpuControl::RunState(post_restore_run_mode),
         )
     }
+    if let Some(address) = &cfg.migrate_from {
+        vm_control::receive_migration(
+            address,
+            |msg| vcpu::kick_all_vcpus(&vcpu_handles, linux.irq_chip.as_irq_chip(), msg),
+            |msg, index| {
+                vcpu::kick_vcpu(&vcpu_handles.get(index), linux.irq_chip.as_irq_chip(), msg)
+            },
+            &irq_handler_control,
+            &device_ctrl_tube,
+            linux.vcpu_count,
+            |image| {
+                linux
+                    .irq_chip
+                    .try_box_clone()?
+                    .restore(image, linux.vcpu_count)
+            },
+            &mut suspended_pvclock_state,
+            &linux.vm,
+            || linux.root_config.lock().device_labels(),
+        )?;
+        vcpu::kick_all_vcpus(
+            &vcpu_handles,
+            linux.irq_chip.as_irq_chip(),
+            VcpuControl::RunState(post_restore_run_mode),
+        )
+    }
 
     #[cfg(feature = "swap")]
     if let Some(swap_controller) = &swap_controller {
@@ -4177,6 +4327,33 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
                         break 'wait;
                     }
                 }
+                Token::WindowResize => {
+                    if let Some(sigwinch_fd) = &sigwinch_fd {
+                        // Drain the signalfd, only the latest size matters.
+                        while sigwinch_fd
+                            .read()
+                            .context("failed to read signalfd")?
+                            .is_some()
+                        {}
+                    }
+                    let tube = stdin_console_index.and_then(|i| console_host_tubes.get(i));
+                    match (tube, stdin().window_size()) {
+                        (Some(tube), Ok((cols, rows))) => {
+                            let command = ConsoleDeviceCommand::Resize {
+                                port: 0,
+                                cols,
+                                rows,
+                            };
+                            if let VmResponse::Err(e) =
+                                vm_control::handle_console_device_command(&command, tube)
+                            {
+                                warn!("failed to resize console: {}", e);
+                            }
+                        }
+                        (None, _) => {}
+                        (_, Err(e)) => warn!("failed to get terminal window size: {}", e),
+                    }
+                }
                 Token::VmControlServer => {
                     if let Some(socket_server) = &control_server_socket {
                         match socket_server.accept() {
@@ -4200,7 +4377,11 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
                             cfg: &cfg,
                             sys_allocator: &sys_allocator_mutex,
                             control_tubes: &control_tubes,
+                            console_host_tubes: &console_host_tubes[..],
                             disk_host_tubes: &disk_host_tubes[..],
+                            #[cfg(feature = "net")]
+                            net_host_tubes: &net_host_tubes[..],
+                            scsi_host_tube: scsi_host_tube.as_ref(),
                             #[cfg(feature = "audio")]
                             snd_host_tubes: &snd_host_tubes[..],
                             #[cfg(feature = "gpu")]
@@ -4229,6 +4410,7 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
                             pvclock_host_tube: pvclock_host_tube.clone(),
                             vfio_container_manager: &mut vfio_container_manager,
                             suspended_pvclock_state: &mut suspended_pvclock_state,
+                            snapshot_chain: &mut snapshot_chain,
                             vcpus_pid_tid: &vcpus_pid_tid,
                         };
                         let (exit_requested, mut ids_to_remove, add_tubes) =
@@ -5017,7 +5199,7 @@ pub fn start_devices(opts: DevicesCommand) -> anyhow::Result<()> {
 
     // Create serial devices.
     for (i, params) in opts.serial.iter().enumerate() {
-        let serial_config = &params.device;
+        let serial_config = ConsoleConfig::new(&params.device, None);
         add_device(i, serial_config, &params.vhost, jail, &mut devices_jails)?;
     }
 
@@ -5044,7 +5226,8 @@ pub fn start_devices(opts: DevicesCommand) -> anyhow::Result<()> {
     // Create network devices.
     #[cfg(feature = "net")]
     for (i, params) in opts.net.iter().enumerate() {
-        add_device(i, &params.device, &params.vhost, jail, &mut devices_jails)?;
+        let net_config = NetConfig::new(&params.device, None);
+        add_device(i, net_config, &params.vhost, jail, &mut devices_jails)?;
     }
 
     // No device created, that's probably not intended - print the help in that case.
@@ -77,10 +77,18 @@ use hypervisor::ProtectionType;
 use hypervisor::Vm;
 use jail::*;
 use minijail::Minijail;
+#[cfg(all(feature = "net", feature = "slirp"))]
+use net_util::slirp::HostForward;
+#[cfg(feature = "net")]
+use net_util::sys::linux::SocketTap;
 #[cfg(feature = "net")]
 use net_util::sys::linux::Tap;
 #[cfg(feature = "net")]
 use net_util::MacAddress;
+#[cfg(all(feature = "net", feature = "slirp"))]
+use net_util::Slirp;
+#[cfg(feature = "net")]
+use net_util::TapT;
 #[cfg(feature = "net")]
 use net_util::TapTCommon;
 use resources::Alloc;
@@ -132,6 +140,8 @@ pub enum DeviceControlTube {
     // See `BalloonTube`.
     #[cfg(feature = "balloon")]
     Balloon(Tube),
+    // Sends `ConsoleDeviceCommand`.
+    Console(Tube),
     // Sends `DiskControlCommand`.
     Disk(Tube),
     // Sends `GpuControlCommand`.
@@ -140,6 +150,11 @@ pub enum DeviceControlTube {
     // Sends `PvClockCommand`.
     #[cfg(feature = "pvclock")]
     PvClock(Tube),
+    // Sends `NetDeviceCommand`.
+    #[cfg(feature = "net")]
+    Net(Tube),
+    // Sends `ScsiControlCommand`.
+    Scsi(Tube),
     #[cfg(feature = "audio")]
     Snd(Tube),
 }
@@ -356,9 +371,20 @@ impl VirtioDeviceBuilder for DiskConfig<'_> {
     }
 }
 
-pub struct ScsiConfig<'a>(pub &'a [ScsiOption]);
+pub struct ScsiConfig<'a> {
+    /// Options for the disks of the controller.
+    scsis: &'a [ScsiOption],
+    /// Optional control tube for adding and removing disks.
+    device_tube: Option<Tube>,
+}
+
+impl<'a> ScsiConfig<'a> {
+    pub fn new(scsis: &'a [ScsiOption], device_tube: Option<Tube>) -> Self {
+        Self { scsis, device_tube }
+    }
+}
 
-impl<'a> VirtioDeviceBuilder for &'a ScsiConfig<'a> {
+impl VirtioDeviceBuilder for ScsiConfig<'_> {
     const NAME: &'static str = "scsi";
 
     fn create_virtio_device(
@@ -367,19 +393,23 @@ impl<'a> VirtioDeviceBuilder for &'a ScsiConfig<'a> {
     ) -> anyhow::Result<Box<dyn VirtioDevice>> {
         let base_features = virtio::base_features(protection_type);
         let disks = self
-            .0
+            .scsis
             .iter()
-            .map(|op| {
+            .enumerate()
+            .map(|(i, op)| {
                 info!("Trying to attach a scsi device: {}", op.path.display());
                 let file = op.open()?;
+                let (target, lun) = op.address(i).map_err(|e| anyhow!(e))?;
                 Ok(virtio::ScsiDiskConfig {
                     file,
                     block_size: op.block_size,
                     read_only: op.read_only,
+                    target,
+                    lun,
                 })
             })
             .collect::<anyhow::Result<_>>()?;
-        let controller = virtio::ScsiController::new(base_features, disks)
+        let controller = virtio::ScsiController::new(base_features, disks, self.device_tube)
             .context("failed to create a scsi controller")?;
         Ok(Box::new(controller))
     }
@@ -840,44 +870,106 @@ pub fn create_pvclock_device(
     })
 }
 
+/// A one-shot configuration structure for implementing `VirtioDeviceBuilder`. We cannot do it on
+/// `NetParameters` directly because net devices can be passed an optional control tube.
+#[cfg(feature = "net")]
+pub struct NetConfig<'a> {
+    /// Options for net device creation.
+    net: &'a NetParameters,
+    /// Optional control tube for starting and stopping packet captures.
+    device_tube: Option<Tube>,
+}
+
 #[cfg(feature = "net")]
-impl VirtioDeviceBuilder for &NetParameters {
+impl<'a> NetConfig<'a> {
+    pub fn new(net: &'a NetParameters, device_tube: Option<Tube>) -> Self {
+        Self { net, device_tube }
+    }
+}
+
+#[cfg(feature = "net")]
+impl VirtioDeviceBuilder for NetConfig<'_> {
     const NAME: &'static str = "net";
 
     fn create_virtio_device(
         self,
         protection_type: ProtectionType,
     ) -> anyhow::Result<Box<dyn VirtioDevice>> {
-        let vq_pairs = self.vq_pairs.unwrap_or(1);
-        let multi_vq = vq_pairs > 1 && self.vhost_net.is_none();
+        let params = self.net;
+        let vq_pairs = params.vq_pairs.unwrap_or(1);
+        let multi_vq = vq_pairs > 1 && params.vhost_net.is_none();
 
         let features = virtio::base_features(protection_type);
-        let (tap, mac) = create_tap_for_net_device(&self.mode, multi_vq)?;
 
-        Ok(if let Some(vhost_net) = &self.vhost_net {
+        #[cfg(feature = "slirp")]
+        if let NetParametersMode::Slirp { host_fwd, mac, .. } = &params.mode {
+            if params.vhost_net.is_some() {
+                bail!("slirp networking can't be used with vhost-net");
+            }
+            if vq_pairs > 1 {
+                bail!("slirp networking supports only one queue pair");
+            }
+            let slirp = start_slirp(host_fwd).context("failed to start slirp")?;
+            let mut net = virtio::Net::new_without_offloads(
+                features,
+                slirp,
+                *mac,
+                params.packed_queue,
+                params.pci_address,
+            )
+            .context("failed to set up virtio slirp networking")?;
+            configure_net_capture(&mut net, params.capture.as_deref(), self.device_tube)?;
+            return Ok(Box::new(net));
+        }
+
+        if let Some((socket, mac)) = create_socket_for_net_device(&params.mode)? {
+            if params.vhost_net.is_some() {
+                bail!("socket networking can't be used with vhost-net");
+            }
+            if vq_pairs > 1 {
+                bail!("socket networking supports only one queue pair");
+            }
+            let mut net = virtio::Net::new_without_offloads(
+                features,
+                socket,
+                mac,
+                params.packed_queue,
+                params.pci_address,
+            )
+            .context("failed to set up virtio socket networking")?;
+            configure_net_capture(&mut net, params.capture.as_deref(), self.device_tube)?;
+            return Ok(Box::new(net));
+        }
+
+        let (tap, mac) = create_tap_for_net_device(&params.mode, multi_vq)?;
+
+        Ok(if let Some(vhost_net) = &params.vhost_net {
+            if params.capture.is_some() {
+                bail!("packet capture can't be used with vhost-net");
+            }
             Box::new(
                 virtio::vhost::Net::<_, vhost::Net<_>>::new(
                     &vhost_net.device,
                     features,
                     tap,
                     mac,
-                    self.packed_queue,
-                    self.pci_address,
+                    params.packed_queue,
+                    params.pci_address,
                 )
                 .context("failed to set up virtio-vhost networking")?,
             ) as Box<dyn VirtioDevice>
         } else {
-            Box::new(
-                virtio::Net::new(
-                    features,
-                    tap,
-                    vq_pairs,
-                    mac,
-                    self.packed_queue,
-                    self.pci_address,
-                )
-                .context("failed to set up virtio networking")?,
-            ) as Box<dyn VirtioDevice>
+            let mut net = virtio::Net::new(
+                features,
+                tap,
+                vq_pairs,
+                mac,
+                params.packed_queue,
+                params.pci_address,
+            )
+            .context("failed to set up virtio networking")?;
+            configure_net_capture(&mut net, params.capture.as_deref(), self.device_tube)?;
+            Box::new(net) as Box<dyn VirtioDevice>
         })
     }
 
@@ -886,7 +978,7 @@ impl VirtioDeviceBuilder for &NetParameters {
         jail_config: Option<&JailConfig>,
         virtio_transport: VirtioDeviceType,
     ) -> anyhow::Result<Option<Minijail>> {
-        let policy = if self.vhost_net.is_some() {
+        let policy = if self.net.vhost_net.is_some() {
             "vhost_net"
         } else {
             "net"
@@ -899,9 +991,13 @@ impl VirtioDeviceBuilder for &NetParameters {
         self,
         keep_rds: &mut Vec<RawDescriptor>,
     ) -> anyhow::Result<Box<dyn VhostUserDeviceBuilder>> {
-        let vq_pairs = self.vq_pairs.unwrap_or(1);
-        let multi_vq = vq_pairs > 1 && self.vhost_net.is_none();
-        let (tap, _mac) = create_tap_for_net_device(&self.mode, multi_vq)?;
+        let params = self.net;
+        if params.capture.is_some() {
+            bail!("packet capture can't be used with a vhost-user net device");
+        }
+        let vq_pairs = params.vq_pairs.unwrap_or(1);
+        let multi_vq = vq_pairs > 1 && params.vhost_net.is_none();
+        let (tap, _mac) = create_tap_for_net_device(&params.mode, multi_vq)?;
 
         let backend = NetBackend::new(tap)?;
 
@@ -911,6 +1007,26 @@ impl VirtioDeviceBuilder for &NetParameters {
     }
 }
 
+/// Starts capturing the frames of `net` to `capture` if set, and passes it the tube for starting
+/// and stopping captures at runtime.
+#[cfg(feature = "net")]
+fn configure_net_capture<T: TapT + ReadNotifier>(
+    net: &mut virtio::Net<T>,
+    capture: Option<&Path>,
+    device_tube: Option<Tube>,
+) -> Result<()> {
+    if let Some(path) = capture {
+        let file = File::create(path)
+            .with_context(|| format!("failed to create capture file {}", path.display()))?;
+        net.start_capture(file)
+            .context("failed to start packet capture")?;
+    }
+    if let Some(tube) = device_tube {
+        net.set_control_tube(tube);
+    }
+    Ok(())
+}
+
 /// Create a new tap interface based on NetParametersMode.
 #[cfg(feature = "net")]
 fn create_tap_for_net_device(
@@ -948,6 +1064,79 @@ fn create_tap_for_net_device(
             tap.enable().map_err(NetError::TapEnable)?;
             Ok((tap, None))
         }
+        NetParametersMode::Socket { .. } | NetParametersMode::SocketFd { .. } => {
+            bail!("socket networking can't be used with a vhost-user net device")
+        }
+        #[cfg(feature = "slirp")]
+        NetParametersMode::Slirp { .. } => {
+            bail!("slirp networking can't be used with a vhost-user net device")
+        }
+    }
+}
+
+/// Runs libslirp in a child process and returns the pseudo-tap connected to it.
+///
+/// libslirp can't run in a device process since it needs to reach the host network, and it can't
+/// run on a thread of the main process since that process must stay single threaded until every
+/// device is forked.
+#[cfg(all(feature = "net", feature = "slirp"))]
+fn start_slirp(host_forwards: &[HostForward]) -> Result<Slirp> {
+    let (slirp, slirp_host) = Slirp::new(
+        host_forwards,
+        #[cfg(any(feature = "slirp-ring-capture", feature = "slirp-debug"))]
+        None,
+    )?;
+    let (started_tube, parent_tube) = Tube::pair().context("failed to create tube")?;
+
+    let mut keep_rds = slirp_host.as_raw_descriptors();
+    keep_rds.push(started_tube.as_raw_descriptor());
+    syslog::push_descriptors(&mut keep_rds);
+    let jail = create_base_minijail(Path::new("/"), MAX_OPEN_FILES_DEFAULT)?;
+    fork_process(jail, keep_rds, Some(String::from("slirp")), move || {
+        // Startup errors are reported to the parent, which stops waiting at the first message.
+        let result = slirp_host.run(|| {
+            let _ = started_tube.send(&None::<String>);
+        });
+        if let Err(e) = result {
+            error!("slirp exited with error: {}", e);
+            let _ = started_tube.send(&Some(e.to_string()));
+        }
+    })
+    .context("failed to fork the slirp process")?;
+
+    match parent_tube
+        .recv::<Option<String>>()
+        .context("failed to wait for the slirp process")?
+    {
+        None => Ok(slirp),
+        Some(e) => Err(anyhow!(e)),
+    }
+}
+
+/// Connect the socket of a socket based NetParametersMode, if `mode` is one.
+#[cfg(feature = "net")]
+fn create_socket_for_net_device(
+    mode: &NetParametersMode,
+) -> DeviceResult<Option<(SocketTap, Option<MacAddress>)>> {
+    match mode {
+        NetParametersMode::Socket { socket, mac } => {
+            let socket = SocketTap::connect(socket)
+                .with_context(|| format!("failed to connect to {}", socket.display()))?;
+            Ok(Some((socket, *mac)))
+        }
+        NetParametersMode::SocketFd { socket_fd, mac } => {
+            // SAFETY:
+            // Safe because we ensure that we get a unique handle to the fd.
+            let socket = unsafe {
+                SocketTap::from_raw_descriptor(
+                    validate_raw_descriptor(*socket_fd)
+                        .context("failed to validate socket descriptor")?,
+                )
+                .context("failed to create socket device")?
+            };
+            Ok(Some((socket, *mac)))
+        }
+        _ => Ok(None),
     }
 }
 
@@ -1153,12 +1342,47 @@ impl VirtioDeviceBuilder for &VsockConfig {
     ) -> anyhow::Result<Box<dyn VirtioDevice>> {
         let features = virtio::base_features(protection_type);
 
+        if self.uds_path.is_some() {
+            let dev = virtio::vsock::UserspaceVsock::new(features, self)
+                .context("failed to set up userspace virtual socket device")?;
+            return Ok(Box::new(dev));
+        }
+
         let dev = virtio::vhost::Vsock::new(features, self)
             .context("failed to set up virtual socket device")?;
 
         Ok(Box::new(dev))
     }
 
+    fn create_jail(
+        &self,
+        jail_config: Option<&JailConfig>,
+        virtio_transport: VirtioDeviceType,
+    ) -> anyhow::Result<Option<Minijail>> {
+        let Some(uds_path) = &self.uds_path else {
+            return simple_jail(
+                jail_config,
+                &virtio_transport.seccomp_policy_file(Self::NAME),
+            );
+        };
+        let Some(jail_config) = jail_config else {
+            return Ok(None);
+        };
+        // Guest connections are forwarded to sockets next to `uds_path`, which may be created
+        // after the device, so its directory is visible in the jail.
+        let Some(dir) = uds_path.parent().filter(|_| uds_path.is_absolute()) else {
+            bail!("vsock uds-path must be an absolute path when sandboxed");
+        };
+        let policy = virtio_transport.seccomp_policy_file("vsock");
+        let mut config = SandboxConfig::new(jail_config, &policy);
+        config.bind_mounts = true;
+        let mut jail =
+            create_sandbox_minijail(&jail_config.pivot_root, MAX_OPEN_FILES_DEFAULT, &config)?;
+        jail.mount_bind(dir, dir, true)
+            .context("failed to bind mount the vsock socket directory")?;
+        Ok(Some(jail))
+    }
+
     fn create_vhost_user_device(
         self,
         keep_rds: &mut Vec<RawDescriptor>,
@@ -1166,6 +1390,9 @@ impl VirtioDeviceBuilder for &VsockConfig {
         if self.max_queue_sizes.is_some() {
             bail!("vhost-user vsock doesn't support max-queue-sizes option");
         }
+        if self.uds_path.is_some() {
+            bail!("vhost-user vsock doesn't support uds-path option");
+        }
 
         let vsock_device = VhostUserVsockDevice::new(self.cid, &self.vhost_device)?;
 
@@ -1541,8 +1768,26 @@ fn add_bind_mounts(param: &SerialParameters, jail: &mut Minijail) -> Result<(),
     Ok(())
 }
 
+/// A one-shot configuration structure for implementing `VirtioDeviceBuilder`. We cannot do it on
+/// `SerialParameters` directly because console devices can be passed an optional control tube.
+pub struct ConsoleConfig<'a> {
+    /// Options for console device creation.
+    params: &'a SerialParameters,
+    /// Optional control tube for resizing the ports of the device.
+    device_tube: Option<Tube>,
+}
+
+impl<'a> ConsoleConfig<'a> {
+    pub fn new(params: &'a SerialParameters, device_tube: Option<Tube>) -> Self {
+        Self {
+            params,
+            device_tube,
+        }
+    }
+}
+
 /// For creating console virtio devices.
-impl VirtioDeviceBuilder for &SerialParameters {
+impl VirtioDeviceBuilder for ConsoleConfig<'_> {
     const NAME: &'static str = "serial";
 
     fn create_virtio_device(
@@ -1552,10 +1797,21 @@ impl VirtioDeviceBuilder for &SerialParameters {
         let mut keep_rds = Vec::new();
         let evt = Event::new().context("failed to create event")?;
 
-        Ok(Box::new(
-            self.create_serial_device::<Console>(protection_type, &evt, &mut keep_rds)
-                .context("failed to create console device")?,
-        ))
+        let mut dev = self
+            .params
+            .create_serial_device::<Console>(protection_type, &evt, &mut keep_rds)
+            .context("failed to create console device")?;
+        if self.params.stdin {
+            // Report the size of the terminal the input comes from, if any. It is kept up to date
+            // by the main loop on `SIGWINCH`.
+            if let Ok((cols, rows)) = std::io::stdin().window_size() {
+                dev.resize(cols, rows)?;
+            }
+        }
+        if let Some(tube) = self.device_tube {
+            dev.set_control_tube(tube);
+        }
+        Ok(Box::new(dev))
     }
 
     fn create_vhost_user_device(
@@ -1563,7 +1819,8 @@ impl VirtioDeviceBuilder for &SerialParameters {
         keep_rds: &mut Vec<RawDescriptor>,
     ) -> anyhow::Result<Box<dyn VhostUserDeviceBuilder>> {
         Ok(Box::new(virtio::vhost::user::create_vu_console_device(
-            self, keep_rds,
+            self.params,
+            keep_rds,
         )?))
     }
 
@@ -1578,7 +1835,7 @@ impl VirtioDeviceBuilder for &SerialParameters {
             config.bind_mounts = true;
             let mut jail =
                 create_sandbox_minijail(&jail_config.pivot_root, MAX_OPEN_FILES_DEFAULT, &config)?;
-            add_bind_mounts(self, &mut jail)
+            add_bind_mounts(self.params, &mut jail)
                 .context("failed to add bind mounts for console device")?;
             Ok(Some(jail))
         } else {
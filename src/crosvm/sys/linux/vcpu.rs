@@ -33,6 +33,8 @@ use base::signal::BlockedSignal;
 use base::*;
 use devices::Bus;
 use devices::IrqChip;
+#[cfg(target_arch = "x86_64")]
+use devices::Ratelimit;
 use devices::VcpuRunState;
 use hypervisor::IoOperation;
 use hypervisor::IoParams;
@@ -53,8 +55,6 @@ use vm_memory::GuestMemory;
 use x86_64::X8664arch as Arch;
 
 use super::ExitState;
-#[cfg(target_arch = "x86_64")]
-use crate::crosvm::ratelimit::Ratelimit;
 
 // TODO(davidai): Import libc constant when updated
 const SCHED_FLAG_RESET_ON_FORK: u64 = 0x1;
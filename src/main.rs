@@ -7,8 +7,9 @@
 //! ## Feature flags
 #![cfg_attr(feature = "document-features", doc = document_features::document_features!())]
 
-#[cfg(any(feature = "composite-disk", feature = "qcow"))]
+use std::fs::File;
 use std::fs::OpenOptions;
+use std::io::Write;
 use std::path::Path;
 
 use anyhow::anyhow;
@@ -39,9 +40,7 @@ use devices::virtio::vhost::user::device::run_snd_device;
 use disk::create_composite_disk;
 #[cfg(feature = "composite-disk")]
 use disk::create_zero_filler;
-#[cfg(feature = "composite-disk")]
 use disk::open_disk_file;
-#[cfg(any(feature = "composite-disk", feature = "qcow"))]
 use disk::DiskFileParams;
 #[cfg(feature = "composite-disk")]
 use disk::ImagePartitionType;
@@ -77,7 +76,6 @@ use vm_control::client::do_swap_status;
 use vm_control::client::do_usb_attach;
 use vm_control::client::do_usb_detach;
 use vm_control::client::do_usb_list;
-#[cfg(feature = "balloon")]
 use vm_control::client::handle_request;
 use vm_control::client::vms_request;
 #[cfg(feature = "gpu")]
@@ -85,14 +83,20 @@ use vm_control::client::ModifyGpuResult;
 use vm_control::client::ModifyUsbResult;
 #[cfg(feature = "balloon")]
 use vm_control::BalloonControlCommand;
+use vm_control::BlockJobCommand;
+use vm_control::ConsoleDeviceCommand;
 use vm_control::DiskControlCommand;
+use vm_control::DiskMedia;
+use vm_control::DiskThrottle;
 use vm_control::HotPlugDeviceInfo;
 use vm_control::HotPlugDeviceType;
+use vm_control::InternalSnapshotCommand;
+use vm_control::NetDeviceCommand;
+use vm_control::ScsiControlCommand;
 use vm_control::SnapshotCommand;
 use vm_control::SwapCommand;
 use vm_control::UsbControlResult;
 use vm_control::VmRequest;
-#[cfg(feature = "balloon")]
 use vm_control::VmResponse;
 
 use crate::sys::error_to_exit_code;
@@ -338,21 +342,53 @@ fn modify_vfio(cmd: cmdline::VfioCrosvmCommand) -> std::result::Result<(), ()> {
     Ok(())
 }
 
-#[cfg(feature = "pci-hotplug")]
 fn modify_virtio_net(cmd: cmdline::VirtioNetCommand) -> std::result::Result<(), ()> {
     match cmd.command {
+        #[cfg(feature = "pci-hotplug")]
         cmdline::VirtioNetSubCommand::AddTap(c) => {
             let bus_num = do_net_add(&c.tap_name, c.socket_path).map_err(|e| {
                 error!("{}", &e);
             })?;
             info!("Tap device {} plugged to PCI bus {}", &c.tap_name, bus_num);
         }
+        #[cfg(feature = "pci-hotplug")]
         cmdline::VirtioNetSubCommand::RemoveTap(c) => {
             do_net_remove(c.bus, &c.socket_path).map_err(|e| {
                 error!("Tap device remove failed: {:?}", &e);
             })?;
             info!("Tap device removed from PCI bus {}", &c.bus);
         }
+        cmdline::VirtioNetSubCommand::CaptureStart(c) => {
+            let file = File::create(&c.file).map_err(|e| {
+                error!("Failed to create capture file '{}': {}", c.file, e);
+            })?;
+            let request = VmRequest::NetDeviceCommand {
+                net_index: c.net_index,
+                command: NetDeviceCommand::StartCapture { file },
+            };
+            vms_request(&request, c.socket_path)?;
+        }
+        cmdline::VirtioNetSubCommand::CaptureStop(c) => {
+            let request = VmRequest::NetDeviceCommand {
+                net_index: c.net_index,
+                command: NetDeviceCommand::StopCapture,
+            };
+            vms_request(&request, c.socket_path)?;
+        }
+        cmdline::VirtioNetSubCommand::LinkUp(c) => {
+            let request = VmRequest::NetDeviceCommand {
+                net_index: c.net_index,
+                command: NetDeviceCommand::SetLink { up: true },
+            };
+            vms_request(&request, c.socket_path)?;
+        }
+        cmdline::VirtioNetSubCommand::LinkDown(c) => {
+            let request = VmRequest::NetDeviceCommand {
+                net_index: c.net_index,
+                command: NetDeviceCommand::SetLink { up: false },
+            };
+            vms_request(&request, c.socket_path)?;
+        }
     };
 
     Ok(())
@@ -581,6 +617,272 @@ fn disk_cmd(cmd: cmdline::DiskCommand) -> std::result::Result<(), ()> {
             };
             vms_request(&request, cmd.socket_path)
         }
+        cmdline::DiskSubcommand::Snapshot(cmd) => disk_snapshot_cmd(cmd),
+        cmdline::DiskSubcommand::Eject(cmd) => {
+            let request = VmRequest::DiskCommand {
+                disk_index: cmd.disk_index,
+                command: DiskControlCommand::Eject,
+            };
+            vms_request(&request, cmd.socket_path)
+        }
+        cmdline::DiskSubcommand::Insert(cmd) => {
+            let request = VmRequest::DiskCommand {
+                disk_index: cmd.disk_index,
+                command: DiskControlCommand::Insert(open_disk_media(&cmd.path, cmd.read_only)?),
+            };
+            vms_request(&request, cmd.socket_path)
+        }
+        cmdline::DiskSubcommand::Replace(cmd) => {
+            let request = VmRequest::DiskCommand {
+                disk_index: cmd.disk_index,
+                command: DiskControlCommand::Replace(open_disk_media(&cmd.path, cmd.read_only)?),
+            };
+            vms_request(&request, cmd.socket_path)
+        }
+        cmdline::DiskSubcommand::Job(cmd) => disk_job_cmd(cmd),
+        cmdline::DiskSubcommand::Throttle(cmd) => {
+            let request = VmRequest::DiskCommand {
+                disk_index: cmd.disk_index,
+                command: DiskControlCommand::Throttle(DiskThrottle {
+                    read_iops: cmd.read_iops,
+                    write_iops: cmd.write_iops,
+                    read_bps: cmd.read_bps,
+                    write_bps: cmd.write_bps,
+                }),
+            };
+            vms_request(&request, cmd.socket_path)
+        }
+    }
+}
+
+fn console_cmd(cmd: cmdline::ConsoleCommand) -> std::result::Result<(), ()> {
+    match cmd.command {
+        cmdline::ConsoleSubcommand::Dump(cmd) => {
+            let request = VmRequest::ConsoleHistory {
+                hardware: cmd.hardware.to_string(),
+                num: cmd.num,
+            };
+            match handle_request(&request, cmd.socket_path)? {
+                VmResponse::ConsoleHistory(data) => {
+                    std::io::stdout().write_all(&data).map_err(|e| {
+                        error!("failed to write console history: {}", e);
+                    })
+                }
+                r => {
+                    println!("unexpected response: {r}");
+                    Err(())
+                }
+            }
+        }
+        cmdline::ConsoleSubcommand::Resize(cmd) => {
+            let request = VmRequest::ConsoleDeviceCommand {
+                console_index: cmd.console_index,
+                command: ConsoleDeviceCommand::Resize {
+                    port: cmd.port,
+                    cols: cmd.cols,
+                    rows: cmd.rows,
+                },
+            };
+            vms_request(&request, cmd.socket_path)
+        }
+    }
+}
+
+fn scsi_cmd(cmd: cmdline::ScsiCommand) -> std::result::Result<(), ()> {
+    let (command, socket_path) = match cmd.command {
+        cmdline::ScsiSubcommand::Add(cmd) => (
+            ScsiControlCommand::AddLun {
+                target: cmd.target,
+                lun: cmd.lun,
+                media: open_disk_media(&cmd.path, cmd.read_only)?,
+                read_only: cmd.read_only,
+                block_size: cmd.block_size,
+            },
+            cmd.socket_path,
+        ),
+        cmdline::ScsiSubcommand::Remove(cmd) => (
+            ScsiControlCommand::RemoveLun {
+                target: cmd.target,
+                lun: cmd.lun,
+            },
+            cmd.socket_path,
+        ),
+    };
+    vms_request(&VmRequest::ScsiCommand(command), socket_path)
+}
+
+fn disk_job_cmd(cmd: cmdline::JobDiskSubcommand) -> std::result::Result<(), ()> {
+    use cmdline::DiskJobSubcommand::*;
+
+    let (command, disk_index, socket_path) = match cmd.command {
+        Mirror(cmd) => (
+            BlockJobCommand::Mirror(open_job_target(&cmd.target)?),
+            cmd.disk_index,
+            cmd.socket_path,
+        ),
+        Backup(cmd) => (
+            BlockJobCommand::Backup(open_job_target(&cmd.target)?),
+            cmd.disk_index,
+            cmd.socket_path,
+        ),
+        Status(cmd) => (BlockJobCommand::Status, cmd.disk_index, cmd.socket_path),
+        Complete(cmd) => (BlockJobCommand::Complete, cmd.disk_index, cmd.socket_path),
+        Cancel(cmd) => (BlockJobCommand::Cancel, cmd.disk_index, cmd.socket_path),
+    };
+
+    let request = VmRequest::DiskCommand {
+        disk_index,
+        command: DiskControlCommand::BlockJob(command),
+    };
+    match handle_request(&request, socket_path)? {
+        VmResponse::Ok => Ok(()),
+        VmResponse::DiskBlockJobStatus(status) => {
+            println!("{status}");
+            Ok(())
+        }
+        r => {
+            println!("unexpected response: {r}");
+            Err(())
+        }
+    }
+}
+
+// Opens the target of a block job, creating it if needed so a new raw image can be written.
+fn open_job_target(path: &str) -> std::result::Result<DiskMedia, ()> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .map_err(|e| {
+            error!("Failed to open block job target '{}': {}", path, e);
+        })?;
+    let path = std::fs::canonicalize(path).map_err(|e| {
+        error!("Failed to find block job target '{}': {}", path, e);
+    })?;
+    Ok(DiskMedia { path, file })
+}
+
+// Opens a disk image to be sent to a running VM, which may not have access to `path` itself.
+fn open_disk_media(path: &str, read_only: bool) -> std::result::Result<DiskMedia, ()> {
+    let path = std::fs::canonicalize(path).map_err(|e| {
+        error!("Failed to find disk image '{}': {}", path, e);
+    })?;
+    let file = OpenOptions::new()
+        .read(true)
+        .write(!read_only)
+        .open(&path)
+        .map_err(|e| {
+            error!("Failed to open disk image '{}': {}", path.display(), e);
+        })?;
+    Ok(DiskMedia { path, file })
+}
+
+fn disk_snapshot_cmd(cmd: cmdline::SnapshotDiskSubcommand) -> std::result::Result<(), ()> {
+    use cmdline::DiskSnapshotSubcommand::*;
+
+    let (command, image, disk_index, socket_path) = match cmd.command {
+        Create(cmd) => (
+            InternalSnapshotCommand::Create { name: cmd.name },
+            cmd.image,
+            cmd.disk_index,
+            cmd.socket,
+        ),
+        List(cmd) => (
+            InternalSnapshotCommand::List,
+            cmd.image,
+            cmd.disk_index,
+            cmd.socket,
+        ),
+        Apply(cmd) => (
+            InternalSnapshotCommand::Apply {
+                snapshot: cmd.snapshot,
+            },
+            cmd.image,
+            cmd.disk_index,
+            cmd.socket,
+        ),
+        Delete(cmd) => (
+            InternalSnapshotCommand::Delete {
+                snapshot: cmd.snapshot,
+            },
+            cmd.image,
+            cmd.disk_index,
+            cmd.socket,
+        ),
+    };
+
+    match (image, disk_index, socket_path) {
+        (Some(image), None, None) => disk_snapshot_offline(&image, command),
+        (None, Some(disk_index), Some(socket_path)) => {
+            let request = VmRequest::DiskCommand {
+                disk_index,
+                command: DiskControlCommand::InternalSnapshot(command),
+            };
+            match handle_request(&request, socket_path)? {
+                VmResponse::Ok => Ok(()),
+                VmResponse::DiskInternalSnapshots(snapshots) => {
+                    print_internal_snapshots(&snapshots);
+                    Ok(())
+                }
+                r => {
+                    println!("unexpected response: {r}");
+                    Err(())
+                }
+            }
+        }
+        _ => {
+            println!("Specify either --image, or both --disk-index and --socket.");
+            Err(())
+        }
+    }
+}
+
+// Runs `command` on a disk image that isn't in use by a VM.
+fn disk_snapshot_offline(
+    image: &str,
+    command: InternalSnapshotCommand,
+) -> std::result::Result<(), ()> {
+    let disk = open_disk_file(DiskFileParams {
+        path: image.into(),
+        is_read_only: matches!(command, InternalSnapshotCommand::List),
+        is_sparse_file: true,
+        is_overlapped: false,
+        is_direct: false,
+        lock: true,
+        depth: 0,
+    })
+    .map_err(|e| {
+        error!("Failed to open disk image '{}': {}", image, e);
+    })?;
+
+    let result = match &command {
+        InternalSnapshotCommand::Create { name } => disk.create_internal_snapshot(name),
+        InternalSnapshotCommand::List => disk
+            .internal_snapshots()
+            .map(|snapshots| print_internal_snapshots(&snapshots)),
+        InternalSnapshotCommand::Apply { snapshot } => disk.apply_internal_snapshot(snapshot),
+        InternalSnapshotCommand::Delete { snapshot } => disk.delete_internal_snapshot(snapshot),
+    };
+    result.map_err(|e| {
+        error!(
+            "Snapshot command '{}' failed on '{}': {}",
+            command, image, e
+        );
+    })
+}
+
+fn print_internal_snapshots(snapshots: &[disk::InternalSnapshot]) {
+    println!(
+        "{:<8} {:<24} {:>16} {:>12}",
+        "ID", "NAME", "DISK SIZE", "DATE"
+    );
+    for snapshot in snapshots {
+        println!(
+            "{:<8} {:<24} {:>16} {:>12}",
+            snapshot.id, snapshot.name, snapshot.disk_size, snapshot.date_sec
+        );
     }
 }
 
@@ -588,6 +890,14 @@ fn make_rt(cmd: cmdline::MakeRTCommand) -> std::result::Result<(), ()> {
     vms_request(&VmRequest::MakeRT, cmd.socket_path)
 }
 
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn migrate_vm(cmd: cmdline::MigrateCommand) -> std::result::Result<(), ()> {
+    let request = VmRequest::Migrate {
+        destination: cmd.destination,
+    };
+    vms_request(&request, cmd.socket_path)
+}
+
 #[cfg(feature = "gpu")]
 fn gpu_display_add(cmd: cmdline::GpuAddDisplaysCommand) -> ModifyGpuResult {
     do_gpu_display_add(cmd.socket_path, cmd.gpu_display)
@@ -678,28 +988,118 @@ fn snapshot_vm(cmd: cmdline::SnapshotCommand) -> std::result::Result<(), ()> {
     use cmdline::SnapshotSubCommands::*;
     let (socket_path, request) = match cmd.snapshot_command {
         Take(take_cmd) => {
+            if take_cmd.compress_archive && !take_cmd.archive {
+                println!("--compress-archive requires --archive");
+                return Err(());
+            }
             let req = VmRequest::Snapshot(SnapshotCommand::Take {
                 snapshot_path: take_cmd.snapshot_path,
                 compress_memory: take_cmd.compress_memory,
                 encrypt: take_cmd.encrypt,
+                incremental: take_cmd.incremental,
+                archive: take_cmd.archive,
+                compress_archive: take_cmd.compress_archive,
             });
             (take_cmd.socket_path, req)
         }
+        Inspect(inspect_cmd) => return snapshot_inspect(&inspect_cmd.snapshot_path),
+        Verify(verify_cmd) => return snapshot_verify(&verify_cmd.snapshot_path),
     };
     let socket_path = Path::new(&socket_path);
     vms_request(&request, socket_path)
 }
 
-#[allow(clippy::unnecessary_wraps)]
-fn pkg_version() -> std::result::Result<(), ()> {
+// Lists the fragments of a snapshot archive and checks their data.
+fn snapshot_inspect(path: &Path) -> std::result::Result<(), ()> {
+    let archive = snapshot::ArchiveReader::open(path).map_err(|e| {
+        error!(
+            "Failed to open snapshot archive '{}': {:#}",
+            path.display(),
+            e
+        );
+    })?;
+    println!(
+        "{:<40} {:>12} {:>12} {:>10} {:>10}",
+        "FRAGMENT", "SIZE", "STORED SIZE", "CRC32", "STATUS"
+    );
+    let mut ok = true;
+    for entry in archive.entries() {
+        let status = match archive.verify(entry) {
+            Ok(true) => "ok",
+            Ok(false) => "corrupted",
+            Err(e) => {
+                error!("Failed to read fragment {:?}: {:#}", entry.name, e);
+                "unreadable"
+            }
+        };
+        ok &= status == "ok";
+        println!(
+            "{:<40} {:>12} {:>12}   {:08x} {:>10}",
+            entry.name, entry.size, entry.stored_size, entry.checksum, status
+        );
+    }
+    if ok {
+        Ok(())
+    } else {
+        Err(())
+    }
+}
+
+// Checks the fragments of a snapshot against the checksums of its manifest.
+fn snapshot_verify(path: &Path) -> std::result::Result<(), ()> {
+    let reader = snapshot::SnapshotReader::new(path, false).map_err(|e| {
+        error!("Failed to open snapshot '{}': {:#}", path.display(), e);
+    })?;
+    let manifest = match reader.read_manifest::<vm_control::VmManifest>() {
+        Ok(Some(manifest)) => manifest,
+        Ok(None) => {
+            error!("Snapshot '{}' has no manifest", path.display());
+            return Err(());
+        }
+        Err(e) => {
+            error!(
+                "Failed to read the manifest of '{}': {:#}",
+                path.display(),
+                e
+            );
+            return Err(());
+        }
+    };
+    println!("crosvm version: {}", manifest.vm.crosvm_version);
+    for (address, label) in &manifest.vm.pci_devices {
+        println!("PCI device: {} {}", address, label);
+    }
+    for (addr, size) in &manifest.vm.memory_regions {
+        println!("memory region: {:#x}+{:#x}", addr, size);
+    }
+    let mismatches = reader.verify().map_err(|e| {
+        error!("Failed to verify snapshot '{}': {:#}", path.display(), e);
+    })?;
+    if !mismatches.is_empty() {
+        for mismatch in &mismatches {
+            println!("{}", mismatch);
+        }
+        return Err(());
+    }
+    println!("{} fragments ok", manifest.fragments.len());
+    Ok(())
+}
+
+// Returns the version of this build, which is printed by `crosvm version` and recorded in
+// snapshots.
+fn crosvm_version() -> String {
     const VERSION: Option<&'static str> = option_env!("CARGO_PKG_VERSION");
     const PKG_VERSION: Option<&'static str> = option_env!("PKG_VERSION");
 
-    print!("crosvm {}", VERSION.unwrap_or("UNKNOWN"));
     match PKG_VERSION {
-        Some(v) => println!("-{}", v),
-        None => println!(),
+        Some(v) => format!("{}-{}", VERSION.unwrap_or("UNKNOWN"), v),
+        None => VERSION.unwrap_or("UNKNOWN").to_owned(),
     }
+}
+
+#[allow(clippy::unnecessary_wraps)]
+fn pkg_version() -> std::result::Result<(), ()> {
+    println!("crosvm {}", crosvm_version());
     Ok(())
 }
 
@@ -784,6 +1184,8 @@ fn crosvm_main<I: IntoIterator<Item = String>>(args: I) -> Result<CommandStatus>
 
     debug!("CLI arguments parsed.");
 
+    vm_control::set_crosvm_version(crosvm_version());
+
     let mut log_config = LogConfig {
         log_args: LogArgs {
             filter: args.log_level,
@@ -845,6 +1247,9 @@ fn crosvm_main<I: IntoIterator<Item = String>>(args: I) -> Result<CommandStatus>
                     CrossPlatformCommands::CreateQcow2(cmd) => {
                         create_qcow2(cmd).map_err(|_| anyhow!("create_qcow2 subcommand failed"))
                     }
+                    CrossPlatformCommands::Console(cmd) => {
+                        console_cmd(cmd).map_err(|_| anyhow!("console subcommand failed"))
+                    }
                     CrossPlatformCommands::Device(_) => unreachable!(),
                     CrossPlatformCommands::Disk(cmd) => {
                         disk_cmd(cmd).map_err(|_| anyhow!("disk subcommand failed"))
@@ -860,10 +1265,17 @@ fn crosvm_main<I: IntoIterator<Item = String>>(args: I) -> Result<CommandStatus>
                     CrossPlatformCommands::MakeRT(cmd) => {
                         make_rt(cmd).map_err(|_| anyhow!("make_rt subcommand failed"))
                     }
+                    #[cfg(any(target_os = "android", target_os = "linux"))]
+                    CrossPlatformCommands::Migrate(cmd) => {
+                        migrate_vm(cmd).map_err(|_| anyhow!("migrate subcommand failed"))
+                    }
                     CrossPlatformCommands::Resume(cmd) => {
                         resume_vms(cmd).map_err(|_| anyhow!("resume subcommand failed"))
                     }
                     CrossPlatformCommands::Run(_) => unreachable!(),
+                    CrossPlatformCommands::Scsi(cmd) => {
+                        scsi_cmd(cmd).map_err(|_| anyhow!("scsi subcommand failed"))
+                    }
                     CrossPlatformCommands::Stop(cmd) => {
                         stop_vms(cmd).map_err(|_| anyhow!("stop subcommand failed"))
                     }
@@ -891,7 +1303,6 @@ fn crosvm_main<I: IntoIterator<Item = String>>(args: I) -> Result<CommandStatus>
                     CrossPlatformCommands::Vfio(cmd) => {
                         modify_vfio(cmd).map_err(|_| anyhow!("vfio subcommand failed"))
                     }
-                    #[cfg(feature = "pci-hotplug")]
                     CrossPlatformCommands::VirtioNet(cmd) => {
                         modify_virtio_net(cmd).map_err(|_| anyhow!("virtio subcommand failed"))
                     }
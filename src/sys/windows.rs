@@ -924,6 +924,8 @@ fn handle_readable_event<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
             irq_handler_control,
             || guest_os.irq_chip.as_ref().snapshot(vcpu_size),
             suspended_pvclock_state,
+            &mut None,
+            || guest_os.root_config.lock().device_labels(),
         );
         (resp, None)
     };
@@ -1485,6 +1487,9 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
             /* require_encrypted= */ false,
             &mut suspended_pvclock_state,
             &guest_os.vm,
+            || guest_os.root_config.lock().device_labels(),
+            #[cfg(feature = "swap")]
+            None,
         )?;
         // Allow the vCPUs to start for real.
         kick_all_vcpus(
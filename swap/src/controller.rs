@@ -13,6 +13,7 @@ use std::io::stdout;
 use std::ops::Range;
 use std::os::unix::fs::OpenOptionsExt;
 use std::path::Path;
+use std::sync::Arc;
 use std::thread::Scope;
 use std::thread::ScopedJoinHandle;
 use std::time::Duration;
@@ -46,6 +47,7 @@ use once_cell::sync::Lazy;
 use serde::Deserialize;
 use serde::Serialize;
 use sync::Mutex;
+use vm_memory::GuestAddress;
 use vm_memory::GuestMemory;
 
 use crate::file_truncator::FileTruncator;
@@ -67,6 +69,7 @@ use crate::userfaultfd::Factory as UffdFactory;
 use crate::userfaultfd::UffdEvent;
 use crate::userfaultfd::Userfaultfd;
 use crate::worker::BackgroundJobControl;
+use crate::worker::Channel;
 use crate::worker::Worker;
 use crate::SwapMetrics;
 use crate::SwapState;
@@ -126,6 +129,11 @@ enum Command {
         reply_tube: Tube,
     },
     StaticDeviceSetupComplete(u32),
+    LazyRestore {
+        #[serde(with = "base::with_as_descriptor")]
+        memory_file: File,
+        data_ranges: Vec<(GuestAddress, usize)>,
+    },
 }
 
 /// [SwapController] provides APIs to control vmm-swap.
@@ -349,6 +357,42 @@ impl SwapController {
         Ok(())
     }
 
+    /// Restore the guest memory from a snapshot lazily.
+    ///
+    /// The guest memory is emptied and its pages are copied from `memory_file` on page faults,
+    /// while the rest of the pages are copied in background. The guest memory is fully restored
+    /// when the swap state gets back to Ready.
+    ///
+    /// This waits until the monitor process handles the page faults of the guest memory. This must
+    /// be called before vmm-swap is enabled and while no process accesses the guest memory.
+    ///
+    /// # Arguments
+    ///
+    /// * `memory_file` - the file holding the contents of the pages in `data_ranges` back to back.
+    ///   It is not modified.
+    /// * `data_ranges` - the ranges of guest memory in `memory_file`, in the order of the file. The
+    ///   other pages are restored as zero.
+    pub fn restore_lazily(
+        &self,
+        memory_file: File,
+        data_ranges: Vec<(GuestAddress, usize)>,
+    ) -> anyhow::Result<()> {
+        self.command_tube
+            .send(&Command::LazyRestore {
+                memory_file,
+                data_ranges,
+            })
+            .context("send lazy restore request")?;
+        if !self
+            .command_tube
+            .recv::<bool>()
+            .context("receive lazy restore result")?
+        {
+            bail!("failed to start lazy restore");
+        }
+        Ok(())
+    }
+
     /// Return current swap status.
     ///
     /// This blocks until response from the monitor process arrives to the main process.
@@ -690,6 +734,7 @@ fn monitor_process(
                                 &worker,
                                 &mutex_transition,
                                 &bg_job_control,
+                                false,
                             );
                             // Abort background jobs to unblock ScopedJoinHandle eariler on a
                             // failure.
@@ -721,6 +766,93 @@ fn monitor_process(
                         // events are obsolete. Run `WaitContext::wait()` again
                         break;
                     }
+                    Command::LazyRestore {
+                        memory_file,
+                        data_ranges,
+                    } => {
+                        info!("start restoring guest memory lazily");
+
+                        let staging_shmem =
+                            SharedMemory::new("swap staging memory", guest_memory.memory_size())
+                                .context("create staging shmem")?;
+
+                        let regions = regions_from_guest_memory(&guest_memory);
+
+                        let page_handler = match create_lazy_restore_page_handler(
+                            &memory_file,
+                            &staging_shmem,
+                            &regions,
+                            &guest_memory,
+                            &data_ranges,
+                            worker.channel.clone(),
+                        ) {
+                            Ok(page_handler) => page_handler,
+                            Err(e) => {
+                                error!("failed to create lazy restore handler: {:?}", e);
+                                command_tube
+                                    .send(&false)
+                                    .context("send lazy restore result")?;
+                                continue;
+                            }
+                        };
+
+                        // SAFETY:
+                        // Safe because the regions are from guest memory and uffd_list contains all
+                        // the processes of crosvm.
+                        unsafe { register_regions(&regions, uffd_list.get_list()) }
+                            .context("register regions")?;
+
+                        // Removing the pages from the monitor process does not cause
+                        // UFFD_EVENT_REMOVE since the guest memory is not registered to the
+                        // userfaultfd of this process.
+                        for region in guest_memory.regions() {
+                            guest_memory
+                                .remove_range(region.guest_addr, region.size as u64)
+                                .context("remove guest memory")?;
+                        }
+                        command_tube
+                            .send(&true)
+                            .context("send lazy restore result")?;
+
+                        // events may contain unprocessed entries, but those pending events will be
+                        // immediately re-created when handle_vmm_swap checks wait_ctx because
+                        // WaitContext is level triggered.
+                        drop(events);
+
+                        let mutex_transition = Mutex::new(state_transition);
+
+                        bg_job_control.reset()?;
+                        let swap_result = std::thread::scope(|scope| {
+                            let result = handle_vmm_swap(
+                                scope,
+                                &wait_ctx,
+                                &page_handler,
+                                &mut uffd_list,
+                                &guest_memory,
+                                &regions,
+                                &command_tube,
+                                &worker,
+                                &mutex_transition,
+                                &bg_job_control,
+                                true,
+                            );
+                            // Abort background jobs to unblock ScopedJoinHandle eariler on a
+                            // failure.
+                            bg_job_control.abort();
+                            result
+                        })?;
+                        if swap_result.should_exit {
+                            return Ok(());
+                        }
+                        state_transition = mutex_transition.into_inner();
+
+                        unregister_regions(&regions, uffd_list.get_list())
+                            .context("unregister regions")?;
+
+                        info!("guest memory is restored");
+                        // events are obsolete. Run `WaitContext::wait()` again
+                        break;
+                    }
                     Command::Trim => {
                         warn!("swap trim while disabled");
                     }
@@ -765,6 +897,34 @@ fn monitor_process(
     }
 }
 
+/// Creates the [PageHandler] to restore the guest memory from `memory_file`.
+fn create_lazy_restore_page_handler<'a>(
+    memory_file: &'a File,
+    staging_shmem: &'a SharedMemory,
+    regions: &[Range<usize>],
+    guest_memory: &GuestMemory,
+    data_ranges: &[(GuestAddress, usize)],
+    channel: Arc<Channel<MoveToStaging>>,
+) -> anyhow::Result<PageHandler<'a>> {
+    let data_ranges = data_ranges
+        .iter()
+        .map(|&(guest_addr, size)| {
+            let host_addr = guest_memory
+                .get_host_address_range(guest_addr, size)
+                .with_context(|| format!("invalid data range {guest_addr}+{size:#x}"))?
+                as usize;
+            Ok(host_addr..host_addr + size)
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    Ok(PageHandler::create_from_memory_file(
+        memory_file,
+        staging_shmem,
+        regions,
+        &data_ranges,
+        channel,
+    )?)
+}
+
 enum State<'scope> {
     SwapOutPending,
     Trim(ScopedJoinHandle<'scope, anyhow::Result<()>>),
@@ -883,6 +1043,41 @@ fn abort_background_job<T>(
     result.context("failure on background job thread")
 }
 
+/// Starts swapping in all the pages on a background thread.
+fn start_swap_in<'scope, 'env>(
+    scope: &'scope Scope<'scope, 'env>,
+    page_handler: &'env PageHandler<'env>,
+    uffd: Userfaultfd,
+    bg_job_control: &'env BackgroundJobControl,
+    state_transition: &'env Mutex<SwapStateTransition>,
+) -> ScopedJoinHandle<'scope, anyhow::Result<()>> {
+    *state_transition.lock() = SwapStateTransition::default();
+    scope.spawn(move || {
+        let mut ctx = page_handler.start_swap_in();
+        let job = bg_job_control.new_job();
+        let start_time = std::time::Instant::now();
+        while !job.is_aborted() {
+            match ctx.swap_in(&uffd, MAX_SWAP_CHUNK_SIZE) {
+                Ok(num_pages) => {
+                    if num_pages == 0 {
+                        break;
+                    }
+                    let mut state_transition = state_transition.lock();
+                    state_transition.pages += num_pages as u64;
+                    state_transition.time_ms = start_time.elapsed().as_millis().try_into()?;
+                }
+                Err(e) => {
+                    bail!("failed to swap in: {:?}", e);
+                }
+            }
+        }
+        if job.is_aborted() {
+            info!("swap in is aborted");
+        }
+        Ok(())
+    })
+}
+
 struct VmmSwapResult {
     should_exit: bool,
     slow_file_cleanup: bool,
@@ -899,25 +1094,36 @@ fn handle_vmm_swap<'scope, 'env>(
     worker: &Worker<MoveToStaging>,
     state_transition: &'env Mutex<SwapStateTransition>,
     bg_job_control: &'env BackgroundJobControl,
+    lazy_restore: bool,
 ) -> anyhow::Result<VmmSwapResult> {
-    let mut state = match move_guest_to_staging(page_handler, guest_memory, worker) {
-        Ok(transition) => {
-            info!(
-                "move {} pages to staging in {} ms",
-                transition.pages, transition.time_ms
-            );
-            *state_transition.lock() = transition;
-            State::SwapOutPending
-        }
-        Err(e) => {
-            error!("failed to move memory to staging: {}", e);
-            *state_transition.lock() = SwapStateTransition::default();
-            State::Failed
+    let mut state = if lazy_restore {
+        // The guest memory is empty and all the pages are in the file.
+        let uffd = uffd_list.clone_main_uffd().context("clone main uffd")?;
+        State::SwapInInProgress {
+            join_handle: start_swap_in(scope, page_handler, uffd, bg_job_control, state_transition),
+            slow_file_cleanup: false,
         }
+    } else {
+        let state = match move_guest_to_staging(page_handler, guest_memory, worker) {
+            Ok(transition) => {
+                info!(
+                    "move {} pages to staging in {} ms",
+                    transition.pages, transition.time_ms
+                );
+                *state_transition.lock() = transition;
+                State::SwapOutPending
+            }
+            Err(e) => {
+                error!("failed to move memory to staging: {}", e);
+                *state_transition.lock() = SwapStateTransition::default();
+                State::Failed
+            }
+        };
+        command_tube
+            .send(&SwapStatus::dummy())
+            .context("send enable finish signal")?;
+        state
     };
-    command_tube
-        .send(&SwapStatus::dummy())
-        .context("send enable finish signal")?;
 
     let mut try_gc_uffds = false;
     loop {
@@ -1028,6 +1234,14 @@ fn handle_vmm_swap<'scope, 'env>(
                             bail!("failed to set num_static_devices");
                         }
                     }
+                    Command::Enable if lazy_restore => {
+                        // The pages not restored yet are only in the memory file, which must not be
+                        // overwritten by swapping out.
+                        warn!("vmm-swap can't be enabled while restoring the guest memory");
+                        command_tube
+                            .send(&SwapStatus::dummy())
+                            .context("send enable finish signal")?;
+                    }
                     Command::Enable => {
                         let result = handle_enable_command(
                             state,
@@ -1042,6 +1256,12 @@ fn handle_vmm_swap<'scope, 'env>(
                             .context("send enable finish signal")?;
                         state = result?;
                     }
+                    Command::LazyRestore { .. } => {
+                        warn!("lazy restore while vmm-swap is enabled");
+                        command_tube
+                            .send(&false)
+                            .context("send lazy restore result")?;
+                    }
                     Command::Trim => match &state {
                         State::SwapOutPending => {
                             *state_transition.lock() = SwapStateTransition::default();
@@ -1118,34 +1338,15 @@ fn handle_vmm_swap<'scope, 'env>(
                             }
                             _ => {}
                         }
-                        *state_transition.lock() = SwapStateTransition::default();
 
                         let uffd = uffd_list.clone_main_uffd().context("clone main uffd")?;
-                        let join_handle = scope.spawn(move || {
-                            let mut ctx = page_handler.start_swap_in();
-                            let job = bg_job_control.new_job();
-                            let start_time = std::time::Instant::now();
-                            while !job.is_aborted() {
-                                match ctx.swap_in(&uffd, MAX_SWAP_CHUNK_SIZE) {
-                                    Ok(num_pages) => {
-                                        if num_pages == 0 {
-                                            break;
-                                        }
-                                        let mut state_transition = state_transition.lock();
-                                        state_transition.pages += num_pages as u64;
-                                        state_transition.time_ms =
-                                            start_time.elapsed().as_millis().try_into()?;
-                                    }
-                                    Err(e) => {
-                                        bail!("failed to swap in: {:?}", e);
-                                    }
-                                }
-                            }
-                            if job.is_aborted() {
-                                info!("swap in is aborted");
-                            }
-                            Ok(())
-                        });
+                        let join_handle = start_swap_in(
+                            scope,
+                            page_handler,
+                            uffd,
+                            bg_job_control,
+                            state_transition,
+                        );
                         state = State::SwapInInProgress {
                             join_handle,
                             slow_file_cleanup,
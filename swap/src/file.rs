@@ -525,6 +525,31 @@ impl<'a> SwapFile<'a> {
         }
     }
 
+    /// Marks the pages as present on the file without writing them.
+    ///
+    /// This is for a file which already holds the contents of the pages back to back, e.g. a
+    /// snapshot of the guest memory. The file pages are allocated from the head of the file in the
+    /// order of the calls, so this must be called for the ranges in the order of the file before
+    /// any other operation.
+    ///
+    /// # Arguments
+    ///
+    /// * `idx_page_range` - the indices of the pages stored next in the file.
+    pub fn mark_range_as_written(&mut self, idx_page_range: Range<usize>) -> Result<()> {
+        if idx_page_range.end > self.page_states.len() {
+            return Err(Error::OutOfRange);
+        }
+        for cur in idx_page_range {
+            let state = &mut self.page_states[cur];
+            if !state.is_none() {
+                return Err(Error::InvalidIndex);
+            }
+            let idx_file = self.file_states.allocate(cur);
+            state.update(idx_file);
+        }
+        Ok(())
+    }
+
     /// Writes the contents to the swap file.
     ///
     /// # Arguments
@@ -1033,6 +1058,28 @@ mod tests {
         assert_eq!(swap_file.lock_and_async_prefetch(11).unwrap(), 10);
     }
 
+    #[test]
+    fn mark_range_as_written() {
+        let file = tempfile::tempfile().unwrap();
+        file.write_all_at(&vec![1; pagesize()], 0).unwrap();
+        file.write_all_at(&vec![2; 2 * pagesize()], pagesize() as u64)
+            .unwrap();
+        let mut swap_file = SwapFile::new(&file, 200).unwrap();
+
+        swap_file.mark_range_as_written(10..11).unwrap();
+        swap_file.mark_range_as_written(3..5).unwrap();
+
+        assert_page_content(&swap_file, 10, &vec![1; pagesize()]);
+        assert_page_content(&swap_file, 3, &vec![2; pagesize()]);
+        assert_page_content(&swap_file, 4, &vec![2; pagesize()]);
+        assert!(swap_file.page_content(5, false).unwrap().is_none());
+        assert_eq!(swap_file.present_pages(), 3);
+        assert_eq!(swap_file.first_data_range(200).unwrap(), 10..11);
+        // Already marked.
+        assert!(swap_file.mark_range_as_written(4..6).is_err());
+        assert!(swap_file.mark_range_as_written(199..201).is_err());
+    }
+
     #[test]
     fn first_data_range() {
         let file = tempfile::tempfile().unwrap();
@@ -204,6 +204,90 @@ impl<'a> PageHandler<'a> {
             .context("truncate swap file")
             .map_err(Error::CreateFailed)?;
 
+        Self::new(
+            swap_file,
+            staging_shmem,
+            address_ranges,
+            stating_move_context,
+        )
+    }
+
+    /// Creates [PageHandler] which pages in the contents of a file holding some of the pages back
+    /// to back, e.g. a snapshot of the guest memory. The other pages are filled with zero.
+    ///
+    /// The file is not written unless the pages are swapped out.
+    ///
+    /// # Arguments
+    ///
+    /// * `memory_file` - The file holding the contents of the pages in `data_ranges`.
+    /// * `staging_shmem` - The staging memory. Same as [Self::create()].
+    /// * `address_ranges` - The list of address range of the regions. Same as [Self::create()].
+    /// * `data_ranges` - The address ranges of the pages in `memory_file`, in the order of the
+    ///   file. They must be page aligned and within the regions.
+    pub fn create_from_memory_file(
+        memory_file: &'a File,
+        staging_shmem: &'a SharedMemory,
+        address_ranges: &[Range<usize>],
+        data_ranges: &[Range<usize>],
+        stating_move_context: Arc<Channel<MoveToStaging>>,
+    ) -> Result<Self> {
+        let data_size: usize = data_ranges
+            .iter()
+            .map(|r| r.end.saturating_sub(r.start))
+            .sum();
+        let file_size = memory_file
+            .metadata()
+            .context("get memory file size")
+            .map_err(Error::CreateFailed)?
+            .len();
+        // Access beyond the end of file causes SIGBUS.
+        if (data_size as u64) > file_size {
+            return Err(Error::CreateFailed(anyhow::anyhow!(
+                "memory file has {} bytes but the data ranges need {}",
+                file_size,
+                data_size
+            )));
+        }
+
+        let page_handler = Self::new(
+            memory_file,
+            staging_shmem,
+            address_ranges,
+            stating_move_context,
+        )?;
+        {
+            let mut ctx = page_handler.ctx.lock();
+            let PageHandleContext { regions, file, .. } = &mut *ctx;
+            for data_range in data_ranges {
+                if !is_page_aligned(data_range.start) || data_range.end < data_range.start {
+                    return Err(Error::InvalidAddress(data_range.start));
+                } else if !is_page_aligned(data_range.end) {
+                    return Err(Error::InvalidAddress(data_range.end));
+                }
+                let head_page_idx = addr_to_page_idx(data_range.start);
+                let num_pages = bytes_to_pages(data_range.end - data_range.start);
+                if num_pages == 0 {
+                    continue;
+                }
+                let region = Self::find_region(regions, head_page_idx)
+                    .ok_or(Error::InvalidAddress(data_range.start))?;
+                if head_page_idx + num_pages > region.head_page_idx + region.num_pages {
+                    return Err(Error::InvalidAddress(data_range.end));
+                }
+                let idx_in_file =
+                    head_page_idx - region.head_page_idx + region.base_page_idx_in_file;
+                file.mark_range_as_written(idx_in_file..idx_in_file + num_pages)?;
+            }
+        }
+        Ok(page_handler)
+    }
+
+    fn new(
+        file: &'a File,
+        staging_shmem: &'a SharedMemory,
+        address_ranges: &[Range<usize>],
+        stating_move_context: Arc<Channel<MoveToStaging>>,
+    ) -> Result<Self> {
         let mut regions: Vec<Region> = Vec::new();
         let mut offset_pages = 0;
         for address_range in address_ranges {
@@ -259,7 +343,7 @@ impl<'a> PageHandler<'a> {
             }
         }
 
-        let file = SwapFile::new(swap_file, offset_pages)?;
+        let file = SwapFile::new(file, offset_pages)?;
 
         Ok(Self {
             ctx: Mutex::new(PageHandleContext {
@@ -381,7 +465,8 @@ impl<'a> PageHandler<'a> {
     /// Move active pages in the memory region to the staging memory.
     ///
     /// It only moves active contents in the guest memory to the swap file and skips empty pages
-    /// (e.g. pages not touched, freed by balloon) using `lseek(2)` + `SEEK_HOLE/DATA`.
+    /// (e.g. pages not touched, freed by balloon or hinted free by the guest) using `lseek(2)` +
+    /// `SEEK_HOLE/DATA`.
     ///
     /// Returns the count of moved out pages.
     ///
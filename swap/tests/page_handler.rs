@@ -10,6 +10,7 @@
 mod common;
 
 use std::array;
+use std::io::Write;
 use std::ops::Range;
 use std::thread;
 use std::time;
@@ -1227,3 +1228,117 @@ fn trim_success_impl() {
     }
     worker.close();
 }
+
+#[test]
+fn create_from_memory_file_success() {
+    call_test_with_sudo("create_from_memory_file_success_impl")
+}
+
+#[ignore = "Only to be called by create_from_memory_file_success"]
+#[test]
+fn create_from_memory_file_success_impl() {
+    let worker = Worker::new(2, 2);
+    let uffd = create_uffd_for_test();
+    let mut file = tempfile::tempfile().unwrap();
+    for v in [1u8, 2, 3] {
+        file.write_all(&vec![v; pagesize()]).unwrap();
+    }
+    let staging_shmem = SharedMemory::new("test staging memory", 6 * pagesize() as u64).unwrap();
+    let shm = SharedMemory::new("shm", 6 * pagesize() as u64).unwrap();
+    let mmap1 = MemoryMappingBuilder::new(3 * pagesize())
+        .from_shared_memory(&shm)
+        .build()
+        .unwrap();
+    let mmap2 = MemoryMappingBuilder::new(3 * pagesize())
+        .from_shared_memory(&shm)
+        .offset(3 * pagesize() as u64)
+        .build()
+        .unwrap();
+    let base_addr1 = mmap1.as_ptr() as usize;
+    let base_addr2 = mmap2.as_ptr() as usize;
+    let regions = [
+        base_addr1..(base_addr1 + 3 * pagesize()),
+        base_addr2..(base_addr2 + 3 * pagesize()),
+    ];
+    let page_handler = PageHandler::create_from_memory_file(
+        &file,
+        &staging_shmem,
+        &regions,
+        &[
+            (base_addr1 + pagesize())..(base_addr1 + 2 * pagesize()),
+            (base_addr2 + pagesize())..(base_addr2 + 3 * pagesize()),
+        ],
+        worker.channel.clone(),
+    )
+    .unwrap();
+    // TODO(b/315998194): Add safety comment
+    #[allow(clippy::undocumented_unsafe_blocks)]
+    unsafe { register_regions(&regions, array::from_ref(&uffd)) }.unwrap();
+
+    page_handler
+        .handle_page_fault(&uffd, base_addr1 + pagesize())
+        .unwrap();
+    page_handler.handle_page_fault(&uffd, base_addr2).unwrap();
+    let mut swap_in_ctx = page_handler.start_swap_in();
+    while swap_in_ctx.swap_in(&uffd, 1024 * 1024).unwrap() != 0 {}
+    unregister_regions(&regions, array::from_ref(&uffd)).unwrap();
+
+    // read values on another thread to avoid blocking forever
+    let join_handle = thread::spawn(move || {
+        let mut result = Vec::new();
+        for i in 0..3 {
+            for j in 0..pagesize() {
+                let ptr = (base_addr1 + i * pagesize() + j) as *mut u8;
+                // SAFETY: trivially safe
+                unsafe {
+                    result.push(*ptr);
+                }
+            }
+        }
+        for i in 0..3 {
+            for j in 0..pagesize() {
+                let ptr = (base_addr2 + i * pagesize() + j) as *mut u8;
+                // SAFETY: trivially safe
+                unsafe {
+                    result.push(*ptr);
+                }
+            }
+        }
+        result
+    });
+    let result = wait_thread_with_timeout(join_handle, 100);
+    let values: Vec<u8> = vec![0, 1, 0, 0, 2, 3];
+    for (i, v) in values.iter().enumerate() {
+        for j in 0..pagesize() {
+            assert_eq!(&result[i * pagesize() + j], v);
+        }
+    }
+    worker.close();
+}
+
+#[test]
+fn create_from_memory_file_too_short() {
+    call_test_with_sudo("create_from_memory_file_too_short_impl")
+}
+
+#[ignore = "Only to be called by create_from_memory_file_too_short"]
+#[test]
+fn create_from_memory_file_too_short_impl() {
+    let worker = Worker::new(2, 2);
+    let mut file = tempfile::tempfile().unwrap();
+    file.write_all(&vec![1; pagesize()]).unwrap();
+    let staging_shmem = SharedMemory::new("test staging memory", 3 * pagesize() as u64).unwrap();
+    let shm = create_shared_memory("shm", 3 * pagesize());
+    let base_addr = shm.base_addr();
+
+    let result = PageHandler::create_from_memory_file(
+        &file,
+        &staging_shmem,
+        &[base_addr..(base_addr + 3 * pagesize())],
+        &[base_addr..(base_addr + 2 * pagesize())],
+        worker.channel.clone(),
+    );
+
+    assert!(matches!(result, Err(Error::CreateFailed(_))));
+    worker.close();
+}
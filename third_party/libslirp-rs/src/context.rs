@@ -51,6 +51,8 @@ use base::error;
 use base::RawDescriptor;
 use libslirp_sys::*;
 
+use crate::slirp::HostForward;
+use crate::slirp::HostForwardProtocol;
 use crate::slirp::SlirpError;
 use crate::Error;
 use crate::Result;
@@ -596,6 +598,30 @@ impl<H: CallbackHandler> Context<H> {
         Ok(())
     }
 
+    /// Forwards connections to `forward.host_port` on the host to `forward.guest_port` in the
+    /// guest.
+    pub fn add_host_forward(&mut self, forward: &HostForward) -> Result<()> {
+        // SAFETY:
+        // Safe because self.slirp is guaranteed to be valid, and we check the return value.
+        let ret = unsafe {
+            slirp_add_hostfwd(
+                self.slirp,
+                (forward.protocol == HostForwardProtocol::Udp) as c_int,
+                forward.host_addr.into(),
+                forward.host_port as c_int,
+                forward.guest_addr.into(),
+                forward.guest_port as c_int,
+            )
+        };
+        if ret < 0 {
+            return Err(Error::Slirp(SlirpError::HostForwardFailed(
+                *forward,
+                io::Error::last_os_error(),
+            )));
+        }
+        Ok(())
+    }
+
     pub fn connection_info(&mut self) -> &str {
         str::from_utf8(
             // TODO(b/315998194): Add safety comment
@@ -153,6 +153,7 @@ bindgen_generate \
     --with-derive-custom "virtio_scsi_ctrl_an_req=FromBytes,Immutable,IntoBytes,KnownLayout" \
     --with-derive-custom "virtio_scsi_ctrl_tmf_resp=FromBytes,Immutable,IntoBytes,KnownLayout" \
     --with-derive-custom "virtio_scsi_ctrl_an_resp=FromBytes,Immutable,IntoBytes,KnownLayout" \
+    --with-derive-custom "virtio_scsi_event=FromBytes,Immutable,IntoBytes,KnownLayout" \
     "${BINDGEN_LINUX_X86_HEADERS}/include/linux/virtio_scsi.h" \
     -- \
     -isystem "${BINDGEN_LINUX_X86_HEADERS}/include" \
@@ -117,7 +117,7 @@ pub struct virtio_scsi_ctrl_an_resp {
     pub response: u8,
 }
 #[repr(C, packed)]
-#[derive(Debug, Default, Copy, Clone)]
+#[derive(Debug, Default, Copy, Clone, FromBytes, Immutable, IntoBytes, KnownLayout)]
 pub struct virtio_scsi_event {
     pub event: __virtio32,
     pub lun: [u8; 8usize],
@@ -5,6 +5,7 @@
 //! Balloon related control APIs.
 
 use std::collections::VecDeque;
+use std::time::Duration;
 
 use anyhow::bail;
 use anyhow::Context;
@@ -87,6 +88,8 @@ pub struct BalloonTube {
     tube: Tube,
     pending_queue: VecDeque<(BalloonControlCommand, Option<usize>)>,
     pending_adjust_with_completion: Option<(u64, usize)>,
+    // Set when waiting for free page hints timed out, so that the late result is discarded.
+    pending_free_page_hint: bool,
 }
 
 impl BalloonTube {
@@ -95,9 +98,53 @@ impl BalloonTube {
             tube,
             pending_queue: VecDeque::new(),
             pending_adjust_with_completion: None,
+            pending_free_page_hint: false,
         }
     }
 
+    /// Asks the guest to hint its free pages and waits up to `timeout` for it to finish. The
+    /// contents of the hinted pages are discarded, so that they are skipped when the guest memory
+    /// is written out. Returns the number of bytes hinted.
+    ///
+    /// The guest doesn't reuse the hinted pages until `free_page_hint_done` is called, which must
+    /// be done even if this fails.
+    ///
+    /// Fails without asking the guest if other commands are pending, since their results would
+    /// have to be received meanwhile.
+    pub fn hint_free_pages(&mut self, timeout: Duration) -> Result<u64> {
+        if !self.pending_queue.is_empty()
+            || self.pending_adjust_with_completion.is_some()
+            || self.pending_free_page_hint
+        {
+            bail!("balloon commands are pending");
+        }
+        self.tube
+            .send(&BalloonTubeCommand::FreePageHint)
+            .context("failed to send free page hint command")?;
+        self.tube
+            .set_recv_timeout(Some(timeout))
+            .context("failed to set balloon tube timeout")?;
+        let res = self.tube.recv::<BalloonTubeResult>();
+        self.tube
+            .set_recv_timeout(None)
+            .context("failed to reset balloon tube timeout")?;
+        match res {
+            Ok(BalloonTubeResult::FreePagesHinted { num_bytes }) => Ok(num_bytes),
+            Ok(res) => bail!("Unexpected balloon tube result {:?}", res),
+            Err(e) => {
+                self.pending_free_page_hint = true;
+                Err(e).context("failed to receive free page hints")
+            }
+        }
+    }
+
+    /// Lets the guest reuse the pages hinted by `hint_free_pages`.
+    pub fn free_page_hint_done(&mut self) -> Result<()> {
+        self.tube
+            .send(&BalloonTubeCommand::FreePageHintDone)
+            .context("failed to send free page hint done command")
+    }
+
     /// Sends or queues the given command to this tube. Associates the
     /// response with the given key.
     pub fn send_cmd(
@@ -148,6 +195,13 @@ impl BalloonTube {
             .tube
             .recv::<BalloonTubeResult>()
             .context("failed to read balloon tube")?;
+        if let BalloonTubeResult::FreePagesHinted { .. } = res {
+            if !self.pending_free_page_hint {
+                bail!("Unexpected free page hint result");
+            }
+            self.pending_free_page_hint = false;
+            return Ok(vec![]);
+        }
         if let BalloonTubeResult::Adjusted { num_bytes: actual } = res {
             let Some((target, key)) = self.pending_adjust_with_completion else {
                 bail!("Unexpected balloon adjust to {}", actual);
@@ -338,6 +392,52 @@ mod tests {
         assert!(matches!(resp[0].0, VmResponse::Ok));
     }
 
+    #[test]
+    fn test_hint_free_pages() {
+        let (host, device) = Tube::pair().unwrap();
+        let mut balloon_tube = BalloonTube::new(host);
+
+        let device_thread = std::thread::spawn(move || {
+            let cmd = device.recv::<BalloonTubeCommand>().unwrap();
+            assert!(matches!(cmd, BalloonTubeCommand::FreePageHint));
+            device
+                .send(&BalloonTubeResult::FreePagesHinted { num_bytes: 0x1000 })
+                .unwrap();
+            device
+        });
+        let num_bytes = balloon_tube
+            .hint_free_pages(Duration::from_secs(10))
+            .unwrap();
+        assert_eq!(num_bytes, 0x1000);
+        let device = device_thread.join().unwrap();
+
+        balloon_tube.free_page_hint_done().unwrap();
+        let cmd = device.recv::<BalloonTubeCommand>().unwrap();
+        assert!(matches!(cmd, BalloonTubeCommand::FreePageHintDone));
+    }
+
+    #[test]
+    fn test_hint_free_pages_timeout() {
+        let (host, device) = Tube::pair().unwrap();
+        let mut balloon_tube = BalloonTube::new(host);
+
+        assert!(balloon_tube
+            .hint_free_pages(Duration::from_millis(10))
+            .is_err());
+        let cmd = device.recv::<BalloonTubeCommand>().unwrap();
+        assert!(matches!(cmd, BalloonTubeCommand::FreePageHint));
+        // Hinting is not requested again until the result of the previous request arrives.
+        assert!(balloon_tube
+            .hint_free_pages(Duration::from_millis(10))
+            .is_err());
+
+        device
+            .send(&BalloonTubeResult::FreePagesHinted { num_bytes: 0 })
+            .unwrap();
+        let resp = balloon_tube.recv().unwrap();
+        assert!(resp.is_empty());
+    }
+
     #[test]
     fn test_stats_and_adjust_with_reply() {
         let (host, device) = Tube::pair().unwrap();
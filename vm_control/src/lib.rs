@@ -33,6 +33,9 @@ use snapshot::AnySnapshot;
 #[cfg(feature = "balloon")]
 mod balloon_tube;
 pub mod client;
+mod manifest;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+mod migration;
 pub mod sys;
 
 #[cfg(target_arch = "x86_64")]
@@ -56,6 +59,7 @@ use anyhow::bail;
 use anyhow::Context;
 use base::error;
 use base::info;
+use base::pagesize;
 use base::warn;
 use base::with_as_descriptor;
 use base::AsRawDescriptor;
@@ -72,6 +76,7 @@ use base::Result;
 use base::SafeDescriptor;
 use base::SharedMemory;
 use base::Tube;
+use disk::InternalSnapshot;
 use hypervisor::Datamatch;
 use hypervisor::IoEventAddress;
 use hypervisor::IrqRoute;
@@ -88,6 +93,7 @@ use libc::ERANGE;
 use protos::registered_events;
 use remain::sorted;
 use resources::Alloc;
+use resources::PciAddress;
 use resources::SystemAllocator;
 use rutabaga_gfx::DeviceId;
 use rutabaga_gfx::RutabagaDescriptor;
@@ -115,6 +121,7 @@ pub use vm_control_product::GpuSendToMain;
 pub use vm_control_product::GpuSendToService;
 pub use vm_control_product::ServiceSendToGpu;
 use vm_memory::GuestAddress;
+use vm_memory::PageHashes;
 
 #[cfg(feature = "balloon")]
 pub use crate::balloon_tube::BalloonControlCommand;
@@ -130,6 +137,12 @@ pub use crate::gdb::VcpuDebugStatusMessage;
 use crate::gpu::GpuControlCommand;
 #[cfg(feature = "gpu")]
 use crate::gpu::GpuControlResult;
+pub use crate::manifest::set_crosvm_version;
+pub use crate::manifest::VmManifest;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub use crate::migration::receive_migration;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub use crate::migration::MigrationAddress;
 
 /// Control the state of a particular VM CPU.
 #[derive(Clone, Debug)]
@@ -216,6 +229,18 @@ pub const USB_CONTROL_MAX_PORTS: usize = 16;
 pub enum DiskControlCommand {
     /// Resize a disk to `new_size` in bytes.
     Resize { new_size: u64 },
+    /// Manage the snapshots stored inside the disk image.
+    InternalSnapshot(InternalSnapshotCommand),
+    /// Remove the media from the disk. The disk has a capacity of zero until media is inserted.
+    Eject,
+    /// Insert media into a disk that has been ejected.
+    Insert(DiskMedia),
+    /// Swap the media of the disk for new media once the in-flight requests have completed.
+    Replace(DiskMedia),
+    /// Control the job copying the disk to another image.
+    BlockJob(BlockJobCommand),
+    /// Replace the limits on the rate of guest requests to the disk.
+    Throttle(DiskThrottle),
 }
 
 impl Display for DiskControlCommand {
@@ -224,7 +249,157 @@ impl Display for DiskControlCommand {
 
         match self {
             Resize { new_size } => write!(f, "disk_resize {}", new_size),
+            InternalSnapshot(command) => write!(f, "disk_snapshot {}", command),
+            Eject => write!(f, "disk_eject"),
+            Insert(media) => write!(f, "disk_insert {}", media.path.display()),
+            Replace(media) => write!(f, "disk_replace {}", media.path.display()),
+            BlockJob(command) => write!(f, "disk_job {}", command),
+            Throttle(throttle) => write!(f, "disk_throttle {}", throttle),
+        }
+    }
+}
+
+/// A disk image opened by the sender of a media change command.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DiskMedia {
+    /// Path the image was opened from, used to find backing files.
+    pub path: PathBuf,
+    /// The opened image. Media must be writable unless the disk is read-only and block job targets
+    /// must always be writable.
+    #[serde(with = "with_as_descriptor")]
+    pub file: File,
+}
+
+/// Limits on the rate of guest requests to a disk. Limits that are `None` are not enforced.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct DiskThrottle {
+    /// Read requests per second.
+    pub read_iops: Option<u64>,
+    /// Write, discard and write zeroes requests per second.
+    pub write_iops: Option<u64>,
+    /// Bytes read per second.
+    pub read_bps: Option<u64>,
+    /// Bytes written per second.
+    pub write_bps: Option<u64>,
+}
+
+impl Display for DiskThrottle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let limits = [
+            ("read-iops", self.read_iops),
+            ("write-iops", self.write_iops),
+            ("read-bps", self.read_bps),
+            ("write-bps", self.write_bps),
+        ];
+        let mut limits = limits
+            .iter()
+            .filter_map(|(name, limit)| limit.map(|limit| format!("{}={}", name, limit)))
+            .peekable();
+        if limits.peek().is_none() {
+            return write!(f, "unlimited");
         }
+        write!(f, "{}", limits.collect::<Vec<_>>().join(","))
+    }
+}
+
+/// Operations on the snapshots stored inside a disk image, such as qcow2 internal snapshots.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum InternalSnapshotCommand {
+    /// Store the current contents of the disk as a snapshot named `name`.
+    Create { name: String },
+    /// List the snapshots in the image.
+    List,
+    /// Revert the disk to the snapshot with the ID or name `snapshot`.
+    Apply { snapshot: String },
+    /// Delete the snapshot with the ID or name `snapshot`.
+    Delete { snapshot: String },
+}
+
+impl Display for InternalSnapshotCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::InternalSnapshotCommand::*;
+
+        match self {
+            Create { name } => write!(f, "create {}", name),
+            List => write!(f, "list"),
+            Apply { snapshot } => write!(f, "apply {}", snapshot),
+            Delete { snapshot } => write!(f, "delete {}", snapshot),
+        }
+    }
+}
+
+/// Commands for the job that copies a disk to another image while the guest keeps using the disk.
+/// A disk runs at most one job at a time.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum BlockJobCommand {
+    /// Copy the disk to the target image and keep copying the guest's writes to it until the job
+    /// is completed or cancelled.
+    Mirror(DiskMedia),
+    /// Copy the contents the disk has when the job starts to the target image.
+    Backup(DiskMedia),
+    /// Report the progress of the last job.
+    Status,
+    /// Switch the disk to the target of a mirror job that is ready.
+    Complete,
+    /// Stop the running job, leaving the target incomplete.
+    Cancel,
+}
+
+impl Display for BlockJobCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::BlockJobCommand::*;
+
+        match self {
+            Mirror(target) => write!(f, "mirror {}", target.path.display()),
+            Backup(target) => write!(f, "backup {}", target.path.display()),
+            Status => write!(f, "status"),
+            Complete => write!(f, "complete"),
+            Cancel => write!(f, "cancel"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockJobKind {
+    Mirror,
+    Backup,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockJobState {
+    /// The job is copying the disk.
+    Running,
+    /// The target of a mirror job is in sync with the disk and the job can be completed.
+    Ready,
+    /// A backup has been copied entirely or the disk switched to the target of a mirror.
+    Completed,
+    Cancelled,
+    /// Accessing the disk or the target failed; the target is incomplete.
+    Failed,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockJobStatus {
+    pub kind: BlockJobKind,
+    pub state: BlockJobState,
+    /// Number of bytes the job copies.
+    pub total_bytes: u64,
+    /// Number of bytes that still have to be copied. For mirror jobs this grows when the guest
+    /// writes to parts of the disk that were already copied.
+    pub remaining_bytes: u64,
+}
+
+impl Display for BlockJobStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:?} {:?}: {} of {} bytes copied",
+            self.kind,
+            self.state,
+            self.total_bytes - self.remaining_bytes,
+            self.total_bytes
+        )
     }
 }
 
@@ -232,6 +407,44 @@ impl Display for DiskControlCommand {
 pub enum DiskControlResult {
     Ok,
     Err(SysError),
+    /// The snapshots stored inside the disk image.
+    InternalSnapshots(Vec<InternalSnapshot>),
+    /// The progress of the disk's block job.
+    BlockJobStatus(BlockJobStatus),
+}
+
+/// Commands for changing the logical units of a virtio-scsi controller.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum ScsiControlCommand {
+    /// Attach a disk image as logical unit `lun` of `target`.
+    AddLun {
+        target: u8,
+        lun: u16,
+        media: DiskMedia,
+        read_only: bool,
+        block_size: u32,
+    },
+    /// Detach logical unit `lun` of `target`.
+    RemoveLun { target: u8, lun: u16 },
+}
+
+impl Display for ScsiControlCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::ScsiControlCommand::*;
+
+        match self {
+            AddLun {
+                target, lun, media, ..
+            } => write!(f, "scsi_add {}:{} {}", target, lun, media.path.display()),
+            RemoveLun { target, lun } => write!(f, "scsi_remove {}:{}", target, lun),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ScsiControlResult {
+    Ok,
+    Err(SysError),
 }
 
 /// Net control commands for adding and removing tap devices.
@@ -242,6 +455,42 @@ pub enum NetControlCommand {
     RemoveTap(u8),
 }
 
+/// Commands for a running virtio-console device.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum ConsoleDeviceCommand {
+    /// Set the window size of `port`, in characters. The driver is notified of the change if it
+    /// negotiated `VIRTIO_CONSOLE_F_SIZE`.
+    Resize { port: u32, cols: u16, rows: u16 },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ConsoleDeviceResult {
+    Ok,
+    Err(SysError),
+}
+
+/// Commands for a running virtio-net device.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum NetDeviceCommand {
+    /// Start writing every frame to `file` in the pcapng format, replacing the capture in
+    /// progress if any.
+    StartCapture {
+        #[serde(with = "with_as_descriptor")]
+        file: File,
+    },
+    /// Stop the capture in progress, if any.
+    StopCapture,
+    /// Bring the link of the device up or down. No frames are exchanged while the link is down,
+    /// and drivers that negotiated `VIRTIO_NET_F_STATUS` are notified of the change.
+    SetLink { up: bool },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum NetDeviceResult {
+    Ok,
+    Err(SysError),
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub enum UsbControlCommand {
     AttachDevice {
@@ -336,6 +585,12 @@ pub enum SnapshotCommand {
         snapshot_path: PathBuf,
         compress_memory: bool,
         encrypt: bool,
+        /// Only write the memory pages changed since the previous incremental snapshot.
+        incremental: bool,
+        /// Write the snapshot as a single file archive instead of a directory.
+        archive: bool,
+        /// Compress the fragments of the archive.
+        compress_archive: bool,
     },
 }
 
@@ -1471,6 +1726,21 @@ pub enum VmRequest {
         disk_index: usize,
         command: DiskControlCommand,
     },
+    /// Add or remove a logical unit of the virtio-scsi controller.
+    ScsiCommand(ScsiControlCommand),
+    /// Command for a virtio-console device, e.g. to resize one of its ports.
+    ConsoleDeviceCommand {
+        console_index: usize,
+        command: ConsoleDeviceCommand,
+    },
+    /// Get the output history kept by the tee of a serial device, chosen by its `hardware` (e.g.
+    /// "serial" or "virtio-console") and `num`.
+    ConsoleHistory { hardware: String, num: u8 },
+    /// Command for a virtio-net device, e.g. to capture its frames or change its link state.
+    NetDeviceCommand {
+        net_index: usize,
+        command: NetDeviceCommand,
+    },
     /// Command to use controller.
     UsbCommand(UsbControlCommand),
     /// Command to modify the gpu.
@@ -1491,6 +1761,10 @@ pub enum VmRequest {
     HotPlugNetCommand(NetControlCommand),
     /// Command to Snapshot devices
     Snapshot(SnapshotCommand),
+    /// Migrate the VM to the crosvm process waiting for it on `destination`, after which this one
+    /// exits.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    Migrate { destination: MigrationAddress },
     /// Register for event notification
     RegisterListener {
         socket_addr: String,
@@ -1587,6 +1861,60 @@ impl RegisteredEventWithData {
     }
 }
 
+pub fn handle_scsi_command(command: &ScsiControlCommand, scsi_host_tube: &Tube) -> VmResponse {
+    // Forward the request to the scsi controller via its control socket.
+    if let Err(e) = scsi_host_tube.send(command) {
+        error!("scsi socket send failed: {}", e);
+        return VmResponse::Err(SysError::new(EINVAL));
+    }
+
+    match scsi_host_tube.recv() {
+        Ok(ScsiControlResult::Ok) => VmResponse::Ok,
+        Ok(ScsiControlResult::Err(e)) => VmResponse::Err(e),
+        Err(e) => {
+            error!("scsi socket recv failed: {}", e);
+            VmResponse::Err(SysError::new(EINVAL))
+        }
+    }
+}
+
+pub fn handle_console_device_command(
+    command: &ConsoleDeviceCommand,
+    console_host_tube: &Tube,
+) -> VmResponse {
+    // Forward the request to the console device via its control socket.
+    if let Err(e) = console_host_tube.send(command) {
+        error!("console socket send failed: {}", e);
+        return VmResponse::Err(SysError::new(EINVAL));
+    }
+
+    match console_host_tube.recv() {
+        Ok(ConsoleDeviceResult::Ok) => VmResponse::Ok,
+        Ok(ConsoleDeviceResult::Err(e)) => VmResponse::Err(e),
+        Err(e) => {
+            error!("console socket recv failed: {}", e);
+            VmResponse::Err(SysError::new(EINVAL))
+        }
+    }
+}
+
+pub fn handle_net_device_command(command: &NetDeviceCommand, net_host_tube: &Tube) -> VmResponse {
+    // Forward the request to the net device via its control socket.
+    if let Err(e) = net_host_tube.send(command) {
+        error!("net socket send failed: {}", e);
+        return VmResponse::Err(SysError::new(EINVAL));
+    }
+
+    match net_host_tube.recv() {
+        Ok(NetDeviceResult::Ok) => VmResponse::Ok,
+        Ok(NetDeviceResult::Err(e)) => VmResponse::Err(e),
+        Err(e) => {
+            error!("net socket recv failed: {}", e);
+            VmResponse::Err(SysError::new(EINVAL))
+        }
+    }
+}
+
 pub fn handle_disk_command(command: &DiskControlCommand, disk_host_tube: &Tube) -> VmResponse {
     // Forward the request to the block device process via its control socket.
     if let Err(e) = disk_host_tube.send(command) {
@@ -1598,6 +1926,10 @@ pub fn handle_disk_command(command: &DiskControlCommand, disk_host_tube: &Tube)
     match disk_host_tube.recv() {
         Ok(DiskControlResult::Ok) => VmResponse::Ok,
         Ok(DiskControlResult::Err(e)) => VmResponse::Err(e),
+        Ok(DiskControlResult::InternalSnapshots(snapshots)) => {
+            VmResponse::DiskInternalSnapshots(snapshots)
+        }
+        Ok(DiskControlResult::BlockJobStatus(status)) => VmResponse::DiskBlockJobStatus(status),
         Err(e) => {
             error!("disk socket recv failed: {}", e);
             VmResponse::Err(SysError::new(EINVAL))
@@ -1781,6 +2113,11 @@ impl VmRequest {
     /// and restore it right before the vCPUs are resumed (instead of, more naturally, during the
     /// snapshot/restore steps) because the pvclock continues to tick even when the vCPUs are
     /// suspended.
+    ///
+    /// `snapshot_chain`: The state of the incremental snapshots, kept between requests.
+    ///
+    /// `pci_devices`: Lists the debug labels of the PCI devices by address, for the manifest of
+    /// the snapshots and migrations.
     #[allow(unused_variables)]
     pub fn execute(
         &self,
@@ -1800,6 +2137,8 @@ impl VmRequest {
         irq_handler_control: &Tube,
         snapshot_irqchip: impl Fn() -> anyhow::Result<AnySnapshot>,
         suspended_pvclock_state: &mut Option<hypervisor::ClockState>,
+        snapshot_chain: &mut Option<SnapshotChain>,
+        pci_devices: impl Fn() -> BTreeMap<PciAddress, String>,
     ) -> VmResponse {
         match self {
             VmRequest::Exit => {
@@ -2131,6 +2470,11 @@ impl VmRequest {
                     VmResponse::Err(SysError::new(EIO))
                 }
             },
+            // The platform run loop forwards these to the device when there is one.
+            VmRequest::ScsiCommand(_) => VmResponse::Err(SysError::new(ENOTSUP)),
+            VmRequest::ConsoleDeviceCommand { .. } => VmResponse::Err(SysError::new(ENOTSUP)),
+            VmRequest::ConsoleHistory { .. } => VmResponse::Err(SysError::new(ENOTSUP)),
+            VmRequest::NetDeviceCommand { .. } => VmResponse::Err(SysError::new(ENOTSUP)),
             VmRequest::UsbCommand(ref cmd) => {
                 let usb_control_tube = match usb_control_tube {
                     Some(t) => t,
@@ -2210,6 +2554,9 @@ impl VmRequest {
                 ref snapshot_path,
                 compress_memory,
                 encrypt,
+                incremental,
+                archive,
+                compress_archive,
             }) => {
                 info!("Starting crosvm snapshot");
                 match do_snapshot(
@@ -2221,8 +2568,12 @@ impl VmRequest {
                     snapshot_irqchip,
                     *compress_memory,
                     *encrypt,
+                    *incremental,
+                    archive.then_some(*compress_archive),
                     suspended_pvclock_state,
+                    snapshot_chain,
                     vm,
+                    pci_devices,
                 ) {
                     Ok(()) => {
                         info!("Finished crosvm snapshot successfully");
@@ -2234,6 +2585,32 @@ impl VmRequest {
                     }
                 }
             }
+            #[cfg(any(target_os = "android", target_os = "linux"))]
+            VmRequest::Migrate { ref destination } => {
+                info!("Starting crosvm migration to {}", destination);
+                // The migration consumes the dirty log.
+                *snapshot_chain = None;
+                match migration::do_migrate(
+                    destination,
+                    kick_vcpus,
+                    irq_handler_control,
+                    device_control_tube,
+                    vcpu_size,
+                    snapshot_irqchip,
+                    suspended_pvclock_state,
+                    vm,
+                    pci_devices,
+                ) {
+                    Ok(()) => {
+                        info!("Finished crosvm migration successfully");
+                        VmResponse::Ok
+                    }
+                    Err(e) => {
+                        error!("failed to migrate: {:?}", e);
+                        VmResponse::ErrString(format!("failed to migrate: {:#}", e))
+                    }
+                }
+            }
             VmRequest::RegisterListener {
                 socket_addr: _,
                 event: _,
@@ -2262,74 +2639,68 @@ impl VmRequest {
     }
 }
 
-/// Snapshot the VM to file at `snapshot_path`
-fn do_snapshot(
-    snapshot_path: PathBuf,
-    kick_vcpus: impl Fn(VcpuControl),
-    irq_handler_control: &Tube,
-    device_control_tube: &Tube,
-    vcpu_size: usize,
-    snapshot_irqchip: impl Fn() -> anyhow::Result<AnySnapshot>,
-    compress_memory: bool,
-    encrypt: bool,
-    suspended_pvclock_state: &mut Option<hypervisor::ClockState>,
-    vm: &impl Vm,
-) -> anyhow::Result<()> {
-    let snapshot_start = Instant::now();
-
-    let _vcpu_guard = VcpuSuspendGuard::new(&kick_vcpus, vcpu_size)?;
-    let _device_guard = DeviceSleepGuard::new(device_control_tube)?;
-
-    // We want to flush all pending IRQs to the interrupt controller. There are two cases:
-    //
-    // MSIs: these are directly delivered to the interrupt controller.
-    // We must verify the handler thread cycles once to deliver these interrupts.
-    //
-    // Legacy interrupts: in the case of a split IRQ chip, these interrupts may
-    // flow through the userspace IOAPIC. If the hypervisor does not support
-    // irqfds (e.g. WHPX), a single iteration will only flush the IRQ to the
-    // IOAPIC. The underlying MSI will be asserted at this point, but if the
-    // IRQ handler doesn't run another iteration, it won't be delivered to the
-    // interrupt controller. This is why we cycle the handler thread twice (doing so
-    // ensures we process the underlying MSI).
-    //
-    // We can handle both of these cases by iterating until there are no tokens
-    // serviced on the requested iteration. Note that in the legacy case, this
-    // ensures at least two iterations.
-    //
-    // Note: within CrosVM, *all* interrupts are eventually converted into the
-    // same mechanicism that MSIs use. This is why we say "underlying" MSI for
-    // a legacy IRQ.
-    {
-        let mut flush_attempts = 0;
-        loop {
-            irq_handler_control
-                .send(&IrqHandlerRequest::WakeAndNotifyIteration)
-                .context("failed to send flush command to IRQ handler thread")?;
-            let resp = irq_handler_control
-                .recv()
-                .context("failed to recv flush response from IRQ handler thread")?;
-            match resp {
-                IrqHandlerResponse::HandlerIterationComplete(tokens_serviced) => {
-                    if tokens_serviced == 0 {
-                        break;
-                    }
+/// Flushes all pending IRQs to the interrupt controller.
+///
+/// There are two cases:
+///
+/// MSIs: these are directly delivered to the interrupt controller.
+/// We must verify the handler thread cycles once to deliver these interrupts.
+///
+/// Legacy interrupts: in the case of a split IRQ chip, these interrupts may
+/// flow through the userspace IOAPIC. If the hypervisor does not support
+/// irqfds (e.g. WHPX), a single iteration will only flush the IRQ to the
+/// IOAPIC. The underlying MSI will be asserted at this point, but if the
+/// IRQ handler doesn't run another iteration, it won't be delivered to the
+/// interrupt controller. This is why we cycle the handler thread twice (doing so
+/// ensures we process the underlying MSI).
+///
+/// We can handle both of these cases by iterating until there are no tokens
+/// serviced on the requested iteration. Note that in the legacy case, this
+/// ensures at least two iterations.
+///
+/// Note: within CrosVM, *all* interrupts are eventually converted into the
+/// same mechanicism that MSIs use. This is why we say "underlying" MSI for
+/// a legacy IRQ.
+fn flush_irqs(irq_handler_control: &Tube) -> anyhow::Result<()> {
+    let mut flush_attempts = 0;
+    loop {
+        irq_handler_control
+            .send(&IrqHandlerRequest::WakeAndNotifyIteration)
+            .context("failed to send flush command to IRQ handler thread")?;
+        let resp = irq_handler_control
+            .recv()
+            .context("failed to recv flush response from IRQ handler thread")?;
+        match resp {
+            IrqHandlerResponse::HandlerIterationComplete(tokens_serviced) => {
+                if tokens_serviced == 0 {
+                    break;
                 }
-                _ => bail!("received unexpected reply from IRQ handler: {:?}", resp),
-            }
-            flush_attempts += 1;
-            if flush_attempts > EXPECTED_MAX_IRQ_FLUSH_ITERATIONS {
-                warn!(
-                    "flushing IRQs for snapshot may be stalled after iteration {}, expected <= {}
-                      iterations",
-                    flush_attempts, EXPECTED_MAX_IRQ_FLUSH_ITERATIONS
-                );
             }
+            _ => bail!("received unexpected reply from IRQ handler: {:?}", resp),
+        }
+        flush_attempts += 1;
+        if flush_attempts > EXPECTED_MAX_IRQ_FLUSH_ITERATIONS {
+            warn!(
+                "flushing IRQs for snapshot may be stalled after iteration {}, expected <= {}
+                  iterations",
+                flush_attempts, EXPECTED_MAX_IRQ_FLUSH_ITERATIONS
+            );
         }
-        info!("flushed IRQs in {} iterations", flush_attempts);
     }
-    let snapshot_writer = SnapshotWriter::new(snapshot_path, encrypt)?;
+    info!("flushed IRQs in {} iterations", flush_attempts);
+    Ok(())
+}
 
+/// Writes the state of the paravirtualized clock, the vCPUs and the irqchip to `snapshot_writer`.
+///
+/// The vCPUs must be suspended.
+fn snapshot_cpus(
+    snapshot_writer: &SnapshotWriter,
+    kick_vcpus: impl Fn(VcpuControl),
+    vcpu_size: usize,
+    snapshot_irqchip: impl Fn() -> anyhow::Result<AnySnapshot>,
+    suspended_pvclock_state: &Option<hypervisor::ClockState>,
+) -> anyhow::Result<()> {
     // Snapshot hypervisor's paravirtualized clock.
     snapshot_writer.write_fragment("pvclock", &AnySnapshot::to_any(suspended_pvclock_state)?)?;
 
@@ -2356,25 +2727,142 @@ fn do_snapshot(
         .write_fragment("irqchip", &irqchip_snap)
         .context("Failed to write irqchip state")?;
     info!("Snapshotted irqchip.");
+    Ok(())
+}
+
+/// Writes the state of the devices to `snapshot_writer`. The devices must be sleeping.
+fn snapshot_devices(
+    snapshot_writer: SnapshotWriter,
+    device_control_tube: &Tube,
+) -> anyhow::Result<()> {
+    info!("Devices snapshotting...");
+    device_control_tube
+        .send(&DeviceControlCommand::SnapshotDevices { snapshot_writer })
+        .context("send command to devices control socket")?;
+    let resp: VmResponse = device_control_tube
+        .recv()
+        .context("receive from devices control socket")?;
+    if !matches!(resp, VmResponse::Ok) {
+        bail!("unexpected SnapshotDevices response: {resp}");
+    }
+    info!("Devices snapshotted.");
+    Ok(())
+}
+
+/// Snapshot the VM to file at `snapshot_path`. With `archive`, the snapshot is written to a staging
+/// dir and packed into a single file archive at the end, compressed if `archive` is `Some(true)`.
+fn do_snapshot(
+    snapshot_path: PathBuf,
+    kick_vcpus: impl Fn(VcpuControl),
+    irq_handler_control: &Tube,
+    device_control_tube: &Tube,
+    vcpu_size: usize,
+    snapshot_irqchip: impl Fn() -> anyhow::Result<AnySnapshot>,
+    compress_memory: bool,
+    encrypt: bool,
+    incremental: bool,
+    archive: Option<bool>,
+    suspended_pvclock_state: &mut Option<hypervisor::ClockState>,
+    snapshot_chain: &mut Option<SnapshotChain>,
+    vm: &impl Vm,
+    pci_devices: impl Fn() -> BTreeMap<PciAddress, String>,
+) -> anyhow::Result<()> {
+    let snapshot_start = Instant::now();
+
+    let vcpu_guard = VcpuSuspendGuard::new(&kick_vcpus, vcpu_size)?;
+    let device_guard = DeviceSleepGuard::new(device_control_tube)?;
+
+    flush_irqs(irq_handler_control)?;
+    let snapshot_dir = if archive.is_some() {
+        if snapshot_path.exists() {
+            bail!(
+                "snapshot archive {} already exists",
+                snapshot_path.display()
+            );
+        }
+        let mut staging_dir = snapshot_path.clone().into_os_string();
+        staging_dir.push(".partial");
+        PathBuf::from(staging_dir)
+    } else {
+        snapshot_path.clone()
+    };
+    let snapshot_writer = SnapshotWriter::new(snapshot_dir.clone(), encrypt)?;
+    // Removes the staging dir whether the snapshot succeeds or not.
+    let _staging_dir = archive.map(|_| StagingDir(snapshot_dir.clone()));
+
+    snapshot_cpus(
+        &snapshot_writer,
+        &kick_vcpus,
+        vcpu_size,
+        snapshot_irqchip,
+        suspended_pvclock_state,
+    )?;
 
     // Snapshot memory
+    let mut page_hashes = None;
     {
         let mem_snap_start = Instant::now();
         // Use 64MB chunks when writing the memory snapshot (if encryption is used).
         const MEMORY_SNAP_ENCRYPTED_CHUNK_SIZE_BYTES: usize = 1024 * 1024 * 64;
-        // SAFETY:
-        // VM & devices are stopped.
-        let guest_memory_metadata = unsafe {
-            vm.get_memory()
-                .snapshot(
-                    &mut snapshot_writer.raw_fragment_with_chunk_size(
-                        "mem",
-                        MEMORY_SNAP_ENCRYPTED_CHUNK_SIZE_BYTES,
-                    )?,
-                    compress_memory,
-                )
-                .context("failed to snapshot memory")?
+        let mut mem_writer = snapshot_writer
+            .raw_fragment_with_chunk_size("mem", MEMORY_SNAP_ENCRYPTED_CHUNK_SIZE_BYTES)?;
+        // The chain is dropped if the snapshot fails, since its page hashes may be updated already.
+        let parent = if incremental {
+            snapshot_chain.take()
+        } else {
+            None
+        };
+        let guest_memory_metadata = if let Some(mut chain) = parent {
+            let dirty_bitmaps = get_dirty_bitmaps(vm)?;
+            // SAFETY:
+            // VM & devices are stopped.
+            let guest_memory_metadata = unsafe {
+                vm.get_memory()
+                    .snapshot_changed_pages(
+                        &mut mem_writer,
+                        &mut chain.page_hashes,
+                        &dirty_bitmaps,
+                        compress_memory,
+                    )
+                    .context("failed to snapshot changed memory")?
+            };
+            // Relative to the snapshot, so that the chain can be moved as a whole.
+            let snapshot_parent_dir = match snapshot_path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+            let snapshot_parent_dir = std::fs::canonicalize(snapshot_parent_dir)
+                .context("failed to resolve the snapshot path")?;
+            snapshot_writer.write_fragment(
+                "mem_parent",
+                &relative_path(&chain.parent, &snapshot_parent_dir),
+            )?;
+            page_hashes = Some(chain.page_hashes);
+            guest_memory_metadata
+        } else {
+            if incremental {
+                // Start a new chain with a full snapshot.
+                vm.set_guest_memory_dirty_log(true)
+                    .context("failed to enable the dirty log")?;
+                // Clear the pages written before.
+                get_dirty_bitmaps(vm)?;
+                // SAFETY:
+                // VM & devices are stopped.
+                page_hashes = Some(unsafe {
+                    vm.get_memory()
+                        .page_hashes()
+                        .context("failed to hash memory")?
+                });
+            }
+            // SAFETY:
+            // VM & devices are stopped.
+            unsafe {
+                vm.get_memory()
+                    .snapshot(&mut mem_writer, compress_memory)
+                    .context("failed to snapshot memory")?
+            }
         };
+        drop(mem_writer);
         snapshot_writer.write_fragment("mem_metadata", &guest_memory_metadata)?;
 
         let mem_snap_duration_ms = mem_snap_start.elapsed().as_millis();
@@ -2389,18 +2877,26 @@ fn do_snapshot(
             &metrics_events::RecordDetails {},
         );
     }
-    // Snapshot devices
-    info!("Devices snapshotting...");
-    device_control_tube
-        .send(&DeviceControlCommand::SnapshotDevices { snapshot_writer })
-        .context("send command to devices control socket")?;
-    let resp: VmResponse = device_control_tube
-        .recv()
-        .context("receive from devices control socket")?;
-    if !matches!(resp, VmResponse::Ok) {
-        bail!("unexpected SnapshotDevices response: {resp}");
+    snapshot_devices(snapshot_writer.clone(), device_control_tube)?;
+    snapshot_writer
+        .write_manifest(&VmManifest::new(vm, pci_devices()))
+        .context("failed to write the snapshot manifest")?;
+
+    // The VM only needs to be stopped while its state is written, not while it is packed.
+    drop(device_guard);
+    drop(vcpu_guard);
+    if let Some(compress) = archive {
+        snapshot::pack_archive(&snapshot_dir, &snapshot_path, compress)
+            .context("failed to pack the snapshot archive")?;
+    }
+
+    if let Some(page_hashes) = page_hashes {
+        *snapshot_chain = Some(SnapshotChain {
+            parent: std::fs::canonicalize(&snapshot_path)
+                .context("failed to resolve the snapshot path")?,
+            page_hashes,
+        });
     }
-    info!("Devices snapshotted.");
 
     let snap_duration_ms = snapshot_start.elapsed().as_millis();
     info!(
@@ -2416,7 +2912,9 @@ fn do_snapshot(
     Ok(())
 }
 
-/// Restore the VM to the snapshot at `restore_path`.
+/// Restore the VM to the snapshot at `restore_path`. The manifest of the snapshot, if any, is
+/// checked against the VM, which has the PCI devices listed by `pci_devices`, before any state is
+/// restored.
 ///
 /// Same as `VmRequest::execute` with a `VmRequest::Restore`. Exposed as a separate function
 /// because not all the `VmRequest::execute` arguments are available in the "cold restore" flow.
@@ -2427,17 +2925,70 @@ pub fn do_restore(
     irq_handler_control: &Tube,
     device_control_tube: &Tube,
     vcpu_size: usize,
-    mut restore_irqchip: impl FnMut(AnySnapshot) -> anyhow::Result<()>,
+    restore_irqchip: impl FnMut(AnySnapshot) -> anyhow::Result<()>,
     require_encrypted: bool,
     suspended_pvclock_state: &mut Option<hypervisor::ClockState>,
     vm: &impl Vm,
+    pci_devices: impl Fn() -> BTreeMap<PciAddress, String>,
+    #[cfg(feature = "swap")] lazy_restore: Option<&swap::SwapController>,
 ) -> anyhow::Result<()> {
     let restore_start = Instant::now();
     let _guard = VcpuSuspendGuard::new(&kick_vcpus, vcpu_size);
     let _devices_guard = DeviceSleepGuard::new(device_control_tube)?;
 
     let snapshot_reader = SnapshotReader::new(restore_path, require_encrypted)?;
+    match snapshot_reader.read_manifest::<VmManifest>()? {
+        Some(manifest) => VmManifest::new(vm, pci_devices()).check(&manifest.vm)?,
+        None => warn!("snapshot has no manifest, restoring it without checking it"),
+    }
+    restore_vm(
+        &snapshot_reader,
+        kick_vcpu,
+        irq_handler_control,
+        device_control_tube,
+        vcpu_size,
+        restore_irqchip,
+        suspended_pvclock_state,
+        vm,
+        Some(restore_path),
+        require_encrypted,
+        #[cfg(feature = "swap")]
+        lazy_restore,
+    )?;
 
+    let restore_duration_ms = restore_start.elapsed().as_millis();
+    info!(
+        "snapshot: completed restore in {}ms; mem size: {}",
+        restore_duration_ms,
+        vm.get_memory().memory_size(),
+    );
+
+    metrics::log_metric_with_details(
+        metrics::MetricEventType::SnapshotRestoreOverallLatency,
+        restore_duration_ms as i64,
+        &metrics_events::RecordDetails {},
+    );
+    Ok(())
+}
+
+/// Restores the state in `snapshot_reader` to the VM. The vCPUs must be suspended and the devices
+/// sleeping.
+///
+/// The guest memory is only restored if `memory_snapshot`, the path of the snapshot, is set,
+/// otherwise it must have been filled in some other way before.
+fn restore_vm(
+    snapshot_reader: &SnapshotReader,
+    kick_vcpu: impl Fn(VcpuControl, usize),
+    irq_handler_control: &Tube,
+    device_control_tube: &Tube,
+    vcpu_size: usize,
+    mut restore_irqchip: impl FnMut(AnySnapshot) -> anyhow::Result<()>,
+    suspended_pvclock_state: &mut Option<hypervisor::ClockState>,
+    vm: &impl Vm,
+    memory_snapshot: Option<&Path>,
+    require_encrypted: bool,
+    #[cfg(feature = "swap")] lazy_restore: Option<&swap::SwapController>,
+) -> anyhow::Result<()> {
     // Restore hypervisor's paravirtualized clock.
     *suspended_pvclock_state = snapshot_reader.read_fragment("pvclock")?;
 
@@ -2480,17 +3031,16 @@ pub fn do_restore(
     }
 
     // Restore Memory
-    {
+    if let Some(snapshot_path) = memory_snapshot {
         let mem_restore_start = Instant::now();
-        let guest_memory_metadata = snapshot_reader.read_fragment("mem_metadata")?;
-        // SAFETY:
-        // VM & devices are stopped.
-        unsafe {
-            vm.get_memory().restore(
-                guest_memory_metadata,
-                &mut snapshot_reader.raw_fragment("mem")?,
-            )?
-        };
+        #[cfg(feature = "swap")]
+        if let Some(swap_controller) = lazy_restore {
+            restore_memory_lazily(snapshot_reader, swap_controller, vm)?;
+        } else {
+            restore_memory_chain(snapshot_reader, snapshot_path, require_encrypted, vm)?;
+        }
+        #[cfg(not(feature = "swap"))]
+        restore_memory_chain(snapshot_reader, snapshot_path, require_encrypted, vm)?;
         let mem_restore_duration_ms = mem_restore_start.elapsed().as_millis();
         info!(
             "snapshot: memory restored {}MB in {}ms",
@@ -2531,22 +3081,136 @@ pub fn do_restore(
             );
         }
     }
+    Ok(())
+}
 
-    let restore_duration_ms = restore_start.elapsed().as_millis();
-    info!(
-        "snapshot: completed restore in {}ms; mem size: {}",
-        restore_duration_ms,
-        vm.get_memory().memory_size(),
-    );
+/// Maps the guest memory empty and lets the swap monitor process copy the pages from the snapshot
+/// on page faults, and the rest in background, so that the VM can run before the whole memory is
+/// read. The snapshot file must not change until the memory is fully restored.
+#[cfg(feature = "swap")]
+fn restore_memory_lazily(
+    snapshot_reader: &SnapshotReader,
+    swap_controller: &swap::SwapController,
+    vm: &impl Vm,
+) -> anyhow::Result<()> {
+    if snapshot_reader
+        .list_fragments()?
+        .iter()
+        .any(|f| f == "mem_parent")
+    {
+        bail!("incremental snapshots can't be restored lazily");
+    }
+    let data_ranges = vm
+        .get_memory()
+        .snapshot_data_ranges(snapshot_reader.read_fragment("mem_metadata")?)?;
+    let memory_file = snapshot_reader.raw_fragment_file("mem")?;
+    swap_controller.restore_lazily(memory_file, data_ranges)
+}
+
+/// Restores the guest memory from `snapshot_reader`, which reads the snapshot at `snapshot_path`.
+/// An incremental snapshot only holds the pages changed since its parent, so the memory is restored
+/// from the first, full snapshot of its chain and then from each incremental snapshot in order.
+fn restore_memory_chain(
+    snapshot_reader: &SnapshotReader,
+    snapshot_path: &Path,
+    require_encrypted: bool,
+    vm: &impl Vm,
+) -> anyhow::Result<()> {
+    let mut chain = vec![snapshot_reader.clone()];
+    let mut path = snapshot_path.to_path_buf();
+    let mut parents = Vec::new();
+    loop {
+        let reader = chain.last().unwrap();
+        if !reader.list_fragments()?.iter().any(|f| f == "mem_parent") {
+            break;
+        }
+        // Relative to the directory of the snapshot, or absolute in older snapshots.
+        let parent: PathBuf = reader.read_fragment("mem_parent")?;
+        let parent = path.parent().unwrap_or(Path::new("")).join(parent);
+        let parent = std::fs::canonicalize(&parent)
+            .with_context(|| format!("failed to find parent snapshot {}", parent.display()))?;
+        if parents.contains(&parent) {
+            bail!("snapshot chain loops at {}", parent.display());
+        }
+        let parent_reader = SnapshotReader::new(&parent, require_encrypted)
+            .with_context(|| format!("failed to open parent snapshot {}", parent.display()))?;
+        chain.push(parent_reader);
+        path = parent.clone();
+        parents.push(parent);
+    }
 
-    metrics::log_metric_with_details(
-        metrics::MetricEventType::SnapshotRestoreOverallLatency,
-        restore_duration_ms as i64,
-        &metrics_events::RecordDetails {},
-    );
+    let (base, increments) = chain.split_last().unwrap();
+    // SAFETY:
+    // VM & devices are stopped.
+    unsafe {
+        vm.get_memory().restore(
+            base.read_fragment("mem_metadata")?,
+            &mut base.raw_fragment("mem")?,
+        )?
+    };
+    for reader in increments.iter().rev() {
+        // SAFETY:
+        // VM & devices are stopped.
+        unsafe {
+            vm.get_memory().restore_changed_pages(
+                reader.read_fragment("mem_metadata")?,
+                &mut reader.raw_fragment("mem")?,
+            )?
+        };
+    }
     Ok(())
 }
 
+/// Returns the path of `path` relative to the directory `base`. Both must be absolute and
+/// canonical.
+fn relative_path(path: &Path, base: &Path) -> PathBuf {
+    let path: Vec<_> = path.components().collect();
+    let base: Vec<_> = base.components().collect();
+    let common = path.iter().zip(&base).take_while(|(a, b)| a == b).count();
+    let mut relative = PathBuf::new();
+    for _ in common..base.len() {
+        relative.push("..");
+    }
+    relative.extend(&path[common..]);
+    relative
+}
+
+/// Directory where the fragments of a snapshot archive are written before they are packed,
+/// removed when dropped.
+struct StagingDir(PathBuf);
+
+impl Drop for StagingDir {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_dir_all(&self.0) {
+            warn!("failed to remove {}: {}", self.0.display(), e);
+        }
+    }
+}
+
+/// The state of the incremental snapshots of a VM: the pages written by the vCPUs are tracked by
+/// the dirty log of the hypervisor from the first snapshot of the chain on.
+pub struct SnapshotChain {
+    /// Canonical path of the previous snapshot of the chain.
+    parent: PathBuf,
+    /// Hashes of the guest memory pages at the time of the previous snapshot.
+    page_hashes: PageHashes,
+}
+
+/// Returns a bitmap of the pages written by the vCPUs since the previous call for each region of
+/// guest memory, from the dirty log of the hypervisor.
+fn get_dirty_bitmaps(vm: &impl Vm) -> anyhow::Result<Vec<Vec<u8>>> {
+    let page_size = pagesize();
+    vm.get_memory()
+        .regions()
+        .map(|region| {
+            let mut bitmap = vec![0u8; (region.size / page_size).div_ceil(8)];
+            vm.get_dirty_log(region.index as MemSlot, &mut bitmap)
+                .context("failed to get the dirty log")?;
+            Ok(bitmap)
+        })
+        .collect()
+}
+
 pub type HypervisorKind = hypervisor::HypervisorKind;
 
 /// Indication of success or failure of a `VmRequest`.
@@ -2597,6 +3261,12 @@ pub enum VmResponse {
         hypervisor: HypervisorKind,
         vm_fd: SafeDescriptor,
     },
+    /// Results of listing the snapshots stored inside a disk image.
+    DiskInternalSnapshots(Vec<InternalSnapshot>),
+    /// The progress of a disk's block job.
+    DiskBlockJobStatus(BlockJobStatus),
+    /// The output history of a serial device, oldest first.
+    ConsoleHistory(Vec<u8>),
 }
 
 impl Display for VmResponse {
@@ -2650,6 +3320,9 @@ impl Display for VmResponse {
             VmDescriptor { hypervisor, vm_fd } => {
                 write!(f, "hypervisor: {:?}, vm_fd: {:?}", hypervisor, vm_fd)
             }
+            DiskInternalSnapshots(snapshots) => write!(f, "disk snapshots: {:?}", snapshots),
+            DiskBlockJobStatus(status) => write!(f, "disk job: {}", status),
+            ConsoleHistory(data) => write!(f, "console history: {} bytes", data.len()),
         }
     }
 }
@@ -2795,3 +3468,40 @@ pub fn virtio_iommu_request(
     };
     Ok(response)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relative_path_same_dir() {
+        assert_eq!(
+            relative_path(Path::new("/snapshots/vm/1"), Path::new("/snapshots/vm")),
+            PathBuf::from("1")
+        );
+    }
+
+    #[test]
+    fn relative_path_child_dir() {
+        assert_eq!(
+            relative_path(Path::new("/snapshots/vm/old/1"), Path::new("/snapshots/vm")),
+            PathBuf::from("old/1")
+        );
+    }
+
+    #[test]
+    fn relative_path_parent_dir() {
+        assert_eq!(
+            relative_path(Path::new("/snapshots/1"), Path::new("/snapshots/vm")),
+            PathBuf::from("../1")
+        );
+    }
+
+    #[test]
+    fn relative_path_sibling_dir() {
+        assert_eq!(
+            relative_path(Path::new("/snapshots/a/1"), Path::new("/snapshots/b/c")),
+            PathBuf::from("../../a/1")
+        );
+    }
+}
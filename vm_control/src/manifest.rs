@@ -0,0 +1,228 @@
+// Copyright 2025 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Description of a snapshotted VM, recorded in the manifest of its snapshot and checked against
+//! the VM restoring it, so that a snapshot taken by a different build or with a different set of
+//! devices is rejected before any state is restored.
+
+use std::collections::BTreeMap;
+use std::sync::OnceLock;
+
+use anyhow::bail;
+use hypervisor::Vm;
+use resources::PciAddress;
+use serde::Deserialize;
+use serde::Serialize;
+use vm_memory::GuestMemory;
+
+/// Bits of CPUID leaf 1 ECX that depend on the host OS rather than the CPU: OSXSAVE and the
+/// hypervisor bit.
+#[cfg(target_arch = "x86_64")]
+const CPUID_1_ECX_HOST_BITS: u32 = (1 << 27) | (1 << 31);
+
+static CROSVM_VERSION: OnceLock<String> = OnceLock::new();
+
+/// Sets the version of the crosvm build recorded in the manifests, as printed by `crosvm version`.
+/// Only the first call has an effect.
+pub fn set_crosvm_version(version: String) {
+    let _ = CROSVM_VERSION.set(version);
+}
+
+/// Description of the VM in the manifest of its snapshot.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VmManifest {
+    /// Version of the crosvm build, as printed by `crosvm version`.
+    pub crosvm_version: String,
+    /// Debug labels of the PCI devices, by address.
+    pub pci_devices: BTreeMap<PciAddress, String>,
+    /// Guest address and size of the guest memory regions.
+    pub memory_regions: Vec<(u64, u64)>,
+    /// CPUID registers of the host listing the CPU features, by leaf and register. Empty on
+    /// architectures other than x86_64.
+    pub cpu_features: BTreeMap<String, u32>,
+}
+
+impl VmManifest {
+    /// Describes the VM, which has the PCI devices `pci_devices`.
+    pub fn new(vm: &impl Vm, pci_devices: BTreeMap<PciAddress, String>) -> Self {
+        Self::with_memory(vm.get_memory(), pci_devices)
+    }
+
+    fn with_memory(mem: &GuestMemory, pci_devices: BTreeMap<PciAddress, String>) -> Self {
+        Self {
+            crosvm_version: crosvm_version(),
+            pci_devices,
+            memory_regions: mem
+                .regions()
+                .map(|region| (region.guest_addr.offset(), region.size as u64))
+                .collect(),
+            cpu_features: host_cpu_features(),
+        }
+    }
+
+    /// Checks that the snapshot described by `snapshot` can be restored to this VM, and lists all
+    /// the differences otherwise.
+    pub fn check(&self, snapshot: &VmManifest) -> anyhow::Result<()> {
+        let mut mismatches = Vec::new();
+        if snapshot.crosvm_version != self.crosvm_version {
+            mismatches.push(format!(
+                "taken by crosvm {}, this is crosvm {}",
+                snapshot.crosvm_version, self.crosvm_version
+            ));
+        }
+        for (address, label) in &snapshot.pci_devices {
+            match self.pci_devices.get(address) {
+                None => mismatches.push(format!("PCI device {address} ({label}) is missing")),
+                Some(vm_label) if vm_label != label => mismatches.push(format!(
+                    "PCI device {address} is {vm_label} instead of {label}"
+                )),
+                Some(_) => {}
+            }
+        }
+        for (address, label) in &self.pci_devices {
+            if !snapshot.pci_devices.contains_key(address) {
+                mismatches.push(format!(
+                    "PCI device {address} ({label}) is not in the snapshot"
+                ));
+            }
+        }
+        if snapshot.memory_regions != self.memory_regions {
+            mismatches.push(format!(
+                "guest memory regions are {}, the snapshot has {}",
+                format_regions(&self.memory_regions),
+                format_regions(&snapshot.memory_regions)
+            ));
+        }
+        for (register, bits) in &snapshot.cpu_features {
+            let missing = bits & !self.cpu_features.get(register).copied().unwrap_or(0);
+            if missing != 0 {
+                mismatches.push(format!(
+                    "CPU features missing on this host: CPUID {register} {missing:#x}"
+                ));
+            }
+        }
+        if !mismatches.is_empty() {
+            bail!(
+                "snapshot doesn't match this VM:\n  {}",
+                mismatches.join("\n  ")
+            );
+        }
+        Ok(())
+    }
+}
+
+fn format_regions(regions: &[(u64, u64)]) -> String {
+    regions
+        .iter()
+        .map(|(addr, size)| format!("{addr:#x}+{size:#x}"))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Version set by `set_crosvm_version`, or "UNKNOWN" like `crosvm version` without it.
+fn crosvm_version() -> String {
+    CROSVM_VERSION
+        .get()
+        .cloned()
+        .unwrap_or_else(|| "UNKNOWN".to_owned())
+}
+
+#[cfg(target_arch = "x86_64")]
+fn host_cpu_features() -> BTreeMap<String, u32> {
+    use std::arch::x86_64::__cpuid;
+    use std::arch::x86_64::__cpuid_count;
+
+    // SAFETY: cpuid has no side effects.
+    let max_leaf = unsafe { __cpuid(0) }.eax;
+    // SAFETY: cpuid has no side effects.
+    let leaf1 = unsafe { __cpuid(1) };
+    let mut features = BTreeMap::from([
+        ("1.ecx".to_owned(), leaf1.ecx & !CPUID_1_ECX_HOST_BITS),
+        ("1.edx".to_owned(), leaf1.edx),
+    ]);
+    if max_leaf >= 7 {
+        // SAFETY: cpuid has no side effects.
+        let leaf7 = unsafe { __cpuid_count(7, 0) };
+        features.insert("7.0.ebx".to_owned(), leaf7.ebx);
+        features.insert("7.0.ecx".to_owned(), leaf7.ecx);
+        features.insert("7.0.edx".to_owned(), leaf7.edx);
+    }
+    features
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn host_cpu_features() -> BTreeMap<String, u32> {
+    BTreeMap::new()
+}
+
+#[cfg(test)]
+mod tests {
+    use vm_memory::GuestAddress;
+
+    use super::*;
+
+    fn manifest() -> VmManifest {
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        VmManifest::with_memory(
+            &mem,
+            BTreeMap::from([
+                (
+                    PciAddress::new(0, 0, 1, 0).unwrap(),
+                    "pcivirtio-block".to_owned(),
+                ),
+                (
+                    PciAddress::new(0, 0, 2, 0).unwrap(),
+                    "pcivirtio-net".to_owned(),
+                ),
+            ]),
+        )
+    }
+
+    #[test]
+    fn check_same_vm() {
+        manifest().check(&manifest()).unwrap();
+    }
+
+    #[test]
+    fn check_mismatches() {
+        let vm = manifest();
+        let mut snapshot = manifest();
+        snapshot.crosvm_version = "0.0.1".to_owned();
+        snapshot.pci_devices.insert(
+            PciAddress::new(0, 0, 1, 0).unwrap(),
+            "pcivirtio-gpu".to_owned(),
+        );
+        snapshot
+            .pci_devices
+            .remove(&PciAddress::new(0, 0, 2, 0).unwrap());
+        snapshot.pci_devices.insert(
+            PciAddress::new(0, 0, 3, 0).unwrap(),
+            "pcivirtio-fs".to_owned(),
+        );
+        snapshot.memory_regions.push((0x100000, 0x1000));
+        snapshot.cpu_features.insert("test".to_owned(), 0x3);
+
+        let err = vm.check(&snapshot).unwrap_err().to_string();
+        for expected in [
+            "taken by crosvm 0.0.1",
+            "PCI device 0000:00:01.0 is pcivirtio-block instead of pcivirtio-gpu",
+            "PCI device 0000:00:03.0 (pcivirtio-fs) is missing",
+            "PCI device 0000:00:02.0 (pcivirtio-net) is not in the snapshot",
+            "guest memory regions are 0x0+0x10000, the snapshot has 0x0+0x10000, 0x100000+0x1000",
+            "CPU features missing on this host: CPUID test 0x3",
+        ] {
+            assert!(err.contains(expected), "{expected:?} not in {err:?}");
+        }
+    }
+
+    #[test]
+    fn check_fewer_cpu_features() {
+        let vm = manifest();
+        let mut snapshot = manifest();
+        for bits in snapshot.cpu_features.values_mut() {
+            *bits = 0;
+        }
+        vm.check(&snapshot).unwrap();
+    }
+}
@@ -0,0 +1,747 @@
+// Copyright 2025 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Pre-copy live migration of a VM to another crosvm process over a socket.
+//!
+//! The source copies the guest memory while the vCPUs and devices keep running, then the pages the
+//! guest wrote in the meantime, as reported by the dirty log of the hypervisor, until few enough
+//! are left. It then suspends the vCPUs, puts the devices to sleep and copies the last dirty pages,
+//! the pages whose hash changed since they were copied, which the devices wrote, and the snapshot
+//! fragments of the vCPUs, the irqchip and the devices, which the destination restores.
+//!
+//! The source skips the holes of its guest memory, so the destination zeroes its own first.
+//!
+//! The stream starts with `MAGIC`, the protocol version and the size of the guest memory, followed
+//! by records made of a kind, the size of the payload and the payload. Integers are little-endian.
+//! The destination answers the final `RECORD_DONE` with a single status byte.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fmt::Display;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Read;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::os::unix::net::UnixListener;
+use std::os::unix::net::UnixStream;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Instant;
+
+use anyhow::bail;
+use anyhow::Context;
+use base::info;
+use base::pagesize;
+use base::warn;
+use base::Tube;
+use hypervisor::Vm;
+use hypervisor::VmCap;
+use resources::PciAddress;
+use serde::Deserialize;
+use serde::Serialize;
+use snapshot::AnySnapshot;
+use snapshot::SnapshotReader;
+use snapshot::SnapshotWriter;
+use vm_memory::GuestAddress;
+use vm_memory::GuestMemory;
+use vm_memory::PageHashes;
+
+use crate::flush_irqs;
+use crate::get_dirty_bitmaps;
+use crate::restore_vm;
+use crate::snapshot_cpus;
+use crate::snapshot_devices;
+use crate::DeviceSleepGuard;
+use crate::VcpuControl;
+use crate::VcpuSuspendGuard;
+use crate::VmManifest;
+
+const MAGIC: &[u8; 8] = b"CROSVMLM";
+const VERSION: u32 = 1;
+
+// Guest address (u64) followed by the data.
+const RECORD_MEMORY: u32 = 1;
+// Size of the path (u32), path of the fragment relative to the snapshot root and data.
+const RECORD_FRAGMENT: u32 = 2;
+// No payload, the destination restores the VM and answers with a status byte.
+const RECORD_DONE: u32 = 3;
+
+const STATUS_OK: u8 = 0;
+const STATUS_FAILED: u8 = 1;
+
+// Maximum size of the data of a memory record.
+const MAX_MEMORY_RECORD_SIZE: usize = 1 << 20;
+// Maximum size of the path of a fragment.
+const MAX_FRAGMENT_PATH_SIZE: u32 = 4096;
+// The vCPUs are suspended once fewer pages than this were dirtied during a pre-copy pass, or after
+// `MAX_PRECOPY_PASSES` passes if the guest dirties its memory faster than it is copied.
+const MAX_FINAL_DIRTY_PAGES: usize = 256;
+const MAX_PRECOPY_PASSES: usize = 16;
+
+/// Address of the socket a VM is migrated through.
+///
+/// Parsed from an IP address and port, e.g. `192.168.0.2:5000`, or else from the path of a Unix
+/// domain socket.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MigrationAddress {
+    Unix(PathBuf),
+    Tcp(SocketAddr),
+}
+
+impl FromStr for MigrationAddress {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.parse() {
+            Ok(addr) => MigrationAddress::Tcp(addr),
+            Err(_) => MigrationAddress::Unix(PathBuf::from(s)),
+        })
+    }
+}
+
+impl Display for MigrationAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MigrationAddress::Unix(path) => write!(f, "{}", path.display()),
+            MigrationAddress::Tcp(addr) => write!(f, "{}", addr),
+        }
+    }
+}
+
+impl MigrationAddress {
+    fn connect(&self) -> io::Result<MigrationStream> {
+        Ok(match self {
+            MigrationAddress::Unix(path) => MigrationStream::Unix(UnixStream::connect(path)?),
+            MigrationAddress::Tcp(addr) => MigrationStream::Tcp(TcpStream::connect(addr)?),
+        })
+    }
+
+    /// Waits for a single connection on the address.
+    fn accept(&self) -> io::Result<MigrationStream> {
+        Ok(match self {
+            MigrationAddress::Unix(path) => {
+                let listener = UnixListener::bind(path)?;
+                let (stream, _) = listener.accept()?;
+                if let Err(e) = fs::remove_file(path) {
+                    warn!("failed to remove {}: {}", path.display(), e);
+                }
+                MigrationStream::Unix(stream)
+            }
+            MigrationAddress::Tcp(addr) => {
+                let (stream, _) = TcpListener::bind(addr)?.accept()?;
+                MigrationStream::Tcp(stream)
+            }
+        })
+    }
+}
+
+enum MigrationStream {
+    Unix(UnixStream),
+    Tcp(TcpStream),
+}
+
+impl Read for MigrationStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            MigrationStream::Unix(stream) => stream.read(buf),
+            MigrationStream::Tcp(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for MigrationStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            MigrationStream::Unix(stream) => stream.write(buf),
+            MigrationStream::Tcp(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            MigrationStream::Unix(stream) => stream.flush(),
+            MigrationStream::Tcp(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Temporary directory holding the snapshot fragments while they are transferred, removed when
+/// dropped.
+struct StateDir(tempfile::TempDir);
+
+impl StateDir {
+    fn new() -> anyhow::Result<StateDir> {
+        let dir = tempfile::Builder::new()
+            .prefix("crosvm-migration")
+            .tempdir()
+            .context("failed to create the migration state dir")?;
+        Ok(StateDir(dir))
+    }
+
+    /// Root of the snapshot, which doesn't exist until it is written.
+    fn snapshot_root(&self) -> PathBuf {
+        self.0.path().join("snapshot")
+    }
+}
+
+fn write_record(w: &mut impl Write, kind: u32, payload: &[&[u8]]) -> io::Result<()> {
+    let size: usize = payload.iter().map(|part| part.len()).sum();
+    w.write_all(&kind.to_le_bytes())?;
+    w.write_all(&(size as u64).to_le_bytes())?;
+    for part in payload {
+        w.write_all(part)?;
+    }
+    Ok(())
+}
+
+fn read_u32(r: &mut impl Read) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64(r: &mut impl Read) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+/// Sends `size` bytes of guest memory starting at `addr`, and records their hashes in `sent`.
+fn send_memory(
+    w: &mut impl Write,
+    mem: &GuestMemory,
+    addr: GuestAddress,
+    size: usize,
+    buf: &mut Vec<u8>,
+    sent: &mut PageHashes,
+) -> anyhow::Result<()> {
+    let mut offset = 0;
+    while offset < size {
+        let len = (size - offset).min(MAX_MEMORY_RECORD_SIZE);
+        let addr = addr.unchecked_add(offset as u64);
+        buf.resize(len, 0);
+        mem.read_exact_at_addr(buf, addr)
+            .with_context(|| format!("failed to read guest memory at {}", addr))?;
+        write_record(w, RECORD_MEMORY, &[&addr.offset().to_le_bytes(), &buf[..]])
+            .context("failed to send guest memory")?;
+        sent.update(mem, addr, buf);
+        offset += len;
+    }
+    Ok(())
+}
+
+/// Sends the pages written by the guest since the previous call and returns their number.
+fn send_dirty_pages(
+    w: &mut impl Write,
+    vm: &impl Vm,
+    buf: &mut Vec<u8>,
+    sent: &mut PageHashes,
+) -> anyhow::Result<usize> {
+    let mem = vm.get_memory();
+    let page_size = pagesize();
+    let mut dirty_pages = 0;
+    for (region, bitmap) in mem.regions().zip(get_dirty_bitmaps(vm)?) {
+        let pages = region.size / page_size;
+        let is_dirty = |page: usize| bitmap[page / 8] & (1 << (page % 8)) != 0;
+
+        // Send runs of consecutive dirty pages together.
+        let mut page = 0;
+        while page < pages {
+            if !is_dirty(page) {
+                page += 1;
+                continue;
+            }
+            let start = page;
+            while page < pages && is_dirty(page) {
+                page += 1;
+            }
+            send_memory(
+                w,
+                mem,
+                region.guest_addr.unchecked_add((start * page_size) as u64),
+                (page - start) * page_size,
+                buf,
+                sent,
+            )?;
+            dirty_pages += page - start;
+        }
+    }
+    Ok(dirty_pages)
+}
+
+/// Sends the pages whose contents differ from the hashes in `sent` and returns their number. The
+/// dirty log only has the writes of the vCPUs, so this finds the pages the devices wrote.
+///
+/// # Safety
+/// The vCPUs and the devices must be stopped.
+unsafe fn send_changed_pages(
+    w: &mut impl Write,
+    mem: &GuestMemory,
+    sent: &mut PageHashes,
+) -> anyhow::Result<usize> {
+    let mut changed = 0;
+    // SAFETY: guaranteed by the caller.
+    unsafe {
+        mem.for_each_changed_page(sent, |addr, page| {
+            write_record(w, RECORD_MEMORY, &[&addr.offset().to_le_bytes(), page])
+                .context("failed to send guest memory")?;
+            changed += 1;
+            Ok(())
+        })?
+    };
+    Ok(changed)
+}
+
+/// Sends the files below `root.join(dir)` as fragments.
+fn send_fragments(w: &mut impl Write, root: &Path, dir: &Path) -> anyhow::Result<()> {
+    for entry in fs::read_dir(root.join(dir)).context("failed to list snapshot fragments")? {
+        let entry = entry?;
+        let path = dir.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            send_fragments(w, root, &path)?;
+            continue;
+        }
+        let name = path
+            .to_str()
+            .context("snapshot fragment path is not UTF-8")?;
+        let data = fs::read(root.join(&path))
+            .with_context(|| format!("failed to read snapshot fragment {}", name))?;
+        write_record(
+            w,
+            RECORD_FRAGMENT,
+            &[&(name.len() as u32).to_le_bytes(), name.as_bytes(), &data],
+        )
+        .context("failed to send snapshot fragment")?;
+    }
+    Ok(())
+}
+
+/// Migrates the VM to the crosvm process listening on `destination`.
+///
+/// The devices keep running until the vCPUs are suspended. On success, the vCPUs stay suspended
+/// and the devices sleeping, since the VM now runs on the destination. On failure, the VM resumes.
+pub(crate) fn do_migrate(
+    destination: &MigrationAddress,
+    kick_vcpus: impl Fn(VcpuControl),
+    irq_handler_control: &Tube,
+    device_control_tube: &Tube,
+    vcpu_size: usize,
+    snapshot_irqchip: impl Fn() -> anyhow::Result<AnySnapshot>,
+    suspended_pvclock_state: &Option<hypervisor::ClockState>,
+    vm: &impl Vm,
+    pci_devices: impl Fn() -> BTreeMap<PciAddress, String>,
+) -> anyhow::Result<()> {
+    let migration_start = Instant::now();
+    if !vm.check_capability(VmCap::DirtyLog) {
+        bail!("the hypervisor cannot log the pages written by the guest");
+    }
+
+    let stream = destination
+        .connect()
+        .with_context(|| format!("failed to connect to {}", destination))?;
+    let mut w = BufWriter::new(stream);
+    w.write_all(MAGIC)?;
+    w.write_all(&VERSION.to_le_bytes())?;
+    w.write_all(&vm.get_memory().memory_size().to_le_bytes())?;
+
+    vm.set_guest_memory_dirty_log(true)
+        .context("failed to enable the dirty log")?;
+    let result = send_vm(
+        &mut w,
+        &kick_vcpus,
+        irq_handler_control,
+        device_control_tube,
+        vcpu_size,
+        snapshot_irqchip,
+        suspended_pvclock_state,
+        vm,
+        pci_devices,
+    );
+    if let Err(e) = vm.set_guest_memory_dirty_log(false) {
+        warn!("failed to disable the dirty log: {}", e);
+    }
+    let (vcpu_guard, device_guard) = result?;
+
+    // The VM runs on the destination now, so it must not resume here.
+    std::mem::forget(device_guard);
+    std::mem::forget(vcpu_guard);
+    info!(
+        "migration: completed in {}ms",
+        migration_start.elapsed().as_millis()
+    );
+    Ok(())
+}
+
+/// Sends the guest memory and the state of the VM, and returns the guards keeping the vCPUs
+/// suspended and the devices sleeping once the destination has restored them.
+fn send_vm<'a>(
+    w: &mut BufWriter<MigrationStream>,
+    kick_vcpus: &'a impl Fn(VcpuControl),
+    irq_handler_control: &Tube,
+    device_control_tube: &'a Tube,
+    vcpu_size: usize,
+    snapshot_irqchip: impl Fn() -> anyhow::Result<AnySnapshot>,
+    suspended_pvclock_state: &Option<hypervisor::ClockState>,
+    vm: &impl Vm,
+    pci_devices: impl Fn() -> BTreeMap<PciAddress, String>,
+) -> anyhow::Result<(VcpuSuspendGuard<'a>, DeviceSleepGuard<'a>)> {
+    let mut buf = Vec::new();
+    // The destination zeroes its memory, and the holes of the source are zero as well.
+    let mut sent = PageHashes::zeroed(vm.get_memory());
+    for (addr, size) in vm.get_memory().data_ranges()? {
+        send_memory(w, vm.get_memory(), addr, size, &mut buf, &mut sent)?;
+    }
+    for pass in 1..=MAX_PRECOPY_PASSES {
+        let dirty_pages = send_dirty_pages(w, vm, &mut buf, &mut sent)?;
+        info!(
+            "migration: pre-copy pass {} sent {} pages",
+            pass, dirty_pages
+        );
+        if dirty_pages < MAX_FINAL_DIRTY_PAGES {
+            break;
+        }
+    }
+
+    let vcpu_guard = VcpuSuspendGuard::new(kick_vcpus, vcpu_size)?;
+    let downtime_start = Instant::now();
+    let device_guard = DeviceSleepGuard::new(device_control_tube)?;
+    flush_irqs(irq_handler_control)?;
+    let dirty_pages = send_dirty_pages(w, vm, &mut buf, &mut sent)?;
+    info!("migration: sent the last {} dirty pages", dirty_pages);
+    // The writes of the devices to the guest memory are not in the dirty log.
+    let check_start = Instant::now();
+    // SAFETY:
+    // The vCPUs are suspended and the devices sleep.
+    let changed_pages = unsafe { send_changed_pages(w, vm.get_memory(), &mut sent)? };
+    info!(
+        "migration: sent {} pages written by the devices, found in {}ms",
+        changed_pages,
+        check_start.elapsed().as_millis()
+    );
+
+    let state_dir = StateDir::new()?;
+    let snapshot_writer = SnapshotWriter::new(state_dir.snapshot_root(), false)?;
+    snapshot_cpus(
+        &snapshot_writer,
+        kick_vcpus,
+        vcpu_size,
+        snapshot_irqchip,
+        suspended_pvclock_state,
+    )?;
+    snapshot_devices(snapshot_writer.clone(), device_control_tube)?;
+    snapshot_writer
+        .write_manifest(&VmManifest::new(vm, pci_devices()))
+        .context("failed to write the snapshot manifest")?;
+    send_fragments(w, &state_dir.snapshot_root(), Path::new(""))?;
+
+    write_record(w, RECORD_DONE, &[])?;
+    w.flush()?;
+    let mut status = [0u8];
+    w.get_mut()
+        .read_exact(&mut status)
+        .context("failed to receive the status of the destination")?;
+    if status[0] != STATUS_OK {
+        bail!("the destination failed to restore the VM");
+    }
+    info!(
+        "migration: VM stopped for {}ms",
+        downtime_start.elapsed().as_millis()
+    );
+    Ok((vcpu_guard, device_guard))
+}
+
+/// Receives a VM migrated with `VmRequest::Migrate` on `address` and restores it.
+///
+/// Same as `do_restore`, except that the snapshot comes from the source VM and must have a
+/// manifest.
+pub fn receive_migration(
+    address: &MigrationAddress,
+    kick_vcpus: impl Fn(VcpuControl),
+    kick_vcpu: impl Fn(VcpuControl, usize),
+    irq_handler_control: &Tube,
+    device_control_tube: &Tube,
+    vcpu_size: usize,
+    restore_irqchip: impl FnMut(AnySnapshot) -> anyhow::Result<()>,
+    suspended_pvclock_state: &mut Option<hypervisor::ClockState>,
+    vm: &impl Vm,
+    pci_devices: impl Fn() -> BTreeMap<PciAddress, String>,
+) -> anyhow::Result<()> {
+    let _vcpu_guard = VcpuSuspendGuard::new(&kick_vcpus, vcpu_size)?;
+    let _device_guard = DeviceSleepGuard::new(device_control_tube)?;
+
+    info!("migration: waiting for the source on {}", address);
+    let stream = address
+        .accept()
+        .with_context(|| format!("failed to accept a connection on {}", address))?;
+    let migration_start = Instant::now();
+    let mut r = BufReader::new(stream);
+    let mut magic = [0u8; 8];
+    r.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        bail!("the source is not a crosvm migration");
+    }
+    let version = read_u32(&mut r)?;
+    if version != VERSION {
+        bail!("unsupported migration version {}", version);
+    }
+    let memory_size = read_u64(&mut r)?;
+    if memory_size != vm.get_memory().memory_size() {
+        bail!(
+            "the source has {} bytes of memory but the VM has {}",
+            memory_size,
+            vm.get_memory().memory_size()
+        );
+    }
+
+    let state_dir = StateDir::new()?;
+    let result =
+        receive_records(&mut r, vm.get_memory(), &state_dir.snapshot_root()).and_then(|()| {
+            let snapshot_reader = SnapshotReader::new(&state_dir.snapshot_root(), false)?;
+            let manifest = snapshot_reader
+                .read_manifest::<VmManifest>()?
+                .context("the source sent no snapshot manifest")?;
+            VmManifest::new(vm, pci_devices()).check(&manifest.vm)?;
+            restore_vm(
+                &snapshot_reader,
+                kick_vcpu,
+                irq_handler_control,
+                device_control_tube,
+                vcpu_size,
+                restore_irqchip,
+                suspended_pvclock_state,
+                vm,
+                None,
+                false,
+                #[cfg(feature = "swap")]
+                None,
+            )
+        });
+    let status = if result.is_ok() {
+        STATUS_OK
+    } else {
+        STATUS_FAILED
+    };
+    let sent = r.get_mut().write_all(&[status]);
+    result?;
+    // The source resumes the VM unless it gets the status, so this one must not run without it.
+    sent.context("failed to send the status to the source")?;
+    info!(
+        "migration: received the VM in {}ms",
+        migration_start.elapsed().as_millis()
+    );
+    Ok(())
+}
+
+/// Writes the memory records to `mem` and the fragments below `snapshot_root` until `RECORD_DONE`.
+///
+/// `mem` is zeroed first, since it holds what the VM loaded at boot and the source doesn't send the
+/// pages that are holes in its memory.
+fn receive_records(
+    r: &mut impl Read,
+    mem: &GuestMemory,
+    snapshot_root: &Path,
+) -> anyhow::Result<()> {
+    mem.zero_all().context("failed to zero the guest memory")?;
+    let mut buf = Vec::new();
+    loop {
+        let kind = read_u32(r)?;
+        let size = read_u64(r)?;
+        match kind {
+            RECORD_MEMORY => {
+                let addr = GuestAddress(read_u64(r)?);
+                let len = size
+                    .checked_sub(8)
+                    .filter(|&len| len <= MAX_MEMORY_RECORD_SIZE as u64)
+                    .context("invalid memory record")?;
+                buf.resize(len as usize, 0);
+                r.read_exact(&mut buf)?;
+                mem.write_all_at_addr(&buf, addr)
+                    .with_context(|| format!("failed to write guest memory at {}", addr))?;
+            }
+            RECORD_FRAGMENT => {
+                let path_len = read_u32(r)?;
+                if path_len > MAX_FRAGMENT_PATH_SIZE {
+                    bail!("invalid snapshot fragment path");
+                }
+                let len = size
+                    .checked_sub(4 + path_len as u64)
+                    .context("invalid snapshot fragment record")?;
+                let mut path = vec![0u8; path_len as usize];
+                r.read_exact(&mut path)?;
+                let path = PathBuf::from(
+                    String::from_utf8(path).context("snapshot fragment path is not UTF-8")?,
+                );
+                // Only accept fragments below the snapshot root.
+                if !path
+                    .components()
+                    .all(|component| matches!(component, Component::Normal(_)))
+                {
+                    bail!("invalid snapshot fragment path {}", path.display());
+                }
+                let path = snapshot_root.join(path);
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                let mut file = File::create(&path)
+                    .with_context(|| format!("failed to create {}", path.display()))?;
+                if io::copy(&mut r.by_ref().take(len), &mut file)? != len {
+                    bail!("truncated snapshot fragment {}", path.display());
+                }
+            }
+            RECORD_DONE => return Ok(()),
+            _ => bail!("unknown migration record {}", kind),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_address() {
+        assert_eq!(
+            "127.0.0.1:5000".parse::<MigrationAddress>().unwrap(),
+            MigrationAddress::Tcp("127.0.0.1:5000".parse().unwrap())
+        );
+        assert_eq!(
+            "[::1]:5000".parse::<MigrationAddress>().unwrap(),
+            MigrationAddress::Tcp("[::1]:5000".parse().unwrap())
+        );
+        assert_eq!(
+            "/run/migration.sock".parse::<MigrationAddress>().unwrap(),
+            MigrationAddress::Unix(PathBuf::from("/run/migration.sock"))
+        );
+    }
+
+    #[test]
+    // Disabled for non-x86 because test infra uses qemu-user, which doesn't support MADV_REMOVE.
+    #[cfg(target_arch = "x86_64")]
+    fn receive_records_roundtrip() {
+        let src = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        src.write_obj_at_addr(0x1234u64, GuestAddress(0x2000))
+            .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let writer = SnapshotWriter::new(dir.path().join("src"), false).unwrap();
+        writer.write_fragment("pvclock", &1u32).unwrap();
+        writer
+            .add_namespace("vcpu")
+            .unwrap()
+            .write_fragment("0", &2u32)
+            .unwrap();
+
+        let mut stream = Vec::new();
+        let mut buf = Vec::new();
+        let mut sent = PageHashes::zeroed(&src);
+        send_memory(
+            &mut stream,
+            &src,
+            GuestAddress(0x2000),
+            0x1000,
+            &mut buf,
+            &mut sent,
+        )
+        .unwrap();
+        send_fragments(&mut stream, &dir.path().join("src"), Path::new("")).unwrap();
+        write_record(&mut stream, RECORD_DONE, &[]).unwrap();
+
+        // The destination starts with what it loaded at boot, which the source doesn't overwrite.
+        let dst = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        dst.write_obj_at_addr(0x5678u64, GuestAddress(0x8000))
+            .unwrap();
+        receive_records(&mut stream.as_slice(), &dst, &dir.path().join("dst")).unwrap();
+
+        let value: u64 = dst.read_obj_from_addr(GuestAddress(0x2000)).unwrap();
+        assert_eq!(value, 0x1234);
+        let value: u64 = dst.read_obj_from_addr(GuestAddress(0x8000)).unwrap();
+        assert_eq!(value, 0);
+        let reader = SnapshotReader::new(&dir.path().join("dst"), false).unwrap();
+        assert_eq!(reader.read_fragment::<u32>("pvclock").unwrap(), 1);
+        assert_eq!(
+            reader
+                .namespace("vcpu")
+                .unwrap()
+                .read_fragment::<u32>("0")
+                .unwrap(),
+            2
+        );
+    }
+
+    #[test]
+    // Disabled for non-x86 because test infra uses qemu-user, which doesn't support MADV_REMOVE.
+    #[cfg(target_arch = "x86_64")]
+    fn send_pages_written_by_devices() {
+        let page_size = pagesize();
+        let src = GuestMemory::new(&[
+            (GuestAddress(0), 4 * page_size as u64),
+            (GuestAddress(0x100000), 4 * page_size as u64),
+        ])
+        .unwrap();
+        src.write_obj_at_addr(1u64, GuestAddress(0)).unwrap();
+        let mut stream = Vec::new();
+        let mut buf = Vec::new();
+        let mut sent = PageHashes::zeroed(&src);
+        send_memory(
+            &mut stream,
+            &src,
+            GuestAddress(0),
+            page_size,
+            &mut buf,
+            &mut sent,
+        )
+        .unwrap();
+        // SAFETY:
+        // no vm is running
+        assert_eq!(
+            unsafe { send_changed_pages(&mut stream, &src, &mut sent) }.unwrap(),
+            0
+        );
+
+        // Writes that aren't in the dirty log, like the ones of a device.
+        src.write_obj_at_addr(2u64, GuestAddress(0)).unwrap();
+        let addr = GuestAddress(0x100000 + 2 * page_size as u64);
+        src.write_obj_at_addr(3u64, addr).unwrap();
+        // SAFETY:
+        // no vm is running
+        assert_eq!(
+            unsafe { send_changed_pages(&mut stream, &src, &mut sent) }.unwrap(),
+            2
+        );
+        write_record(&mut stream, RECORD_DONE, &[]).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let dst = GuestMemory::new(&[
+            (GuestAddress(0), 4 * page_size as u64),
+            (GuestAddress(0x100000), 4 * page_size as u64),
+        ])
+        .unwrap();
+        receive_records(&mut stream.as_slice(), &dst, &dir.path().join("dst")).unwrap();
+        assert_eq!(dst.read_obj_from_addr::<u64>(GuestAddress(0)).unwrap(), 2);
+        assert_eq!(dst.read_obj_from_addr::<u64>(addr).unwrap(), 3);
+    }
+
+    #[test]
+    // Disabled for non-x86 because test infra uses qemu-user, which doesn't support MADV_REMOVE.
+    #[cfg(target_arch = "x86_64")]
+    fn reject_fragment_outside_root() {
+        let name = "../escape";
+        let mut stream = Vec::new();
+        write_record(
+            &mut stream,
+            RECORD_FRAGMENT,
+            &[&(name.len() as u32).to_le_bytes(), name.as_bytes(), b"data"],
+        )
+        .unwrap();
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        assert!(receive_records(&mut stream.as_slice(), &mem, Path::new("/nonexistent")).is_err());
+    }
+}
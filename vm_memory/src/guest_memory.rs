@@ -7,6 +7,7 @@
 use std::convert::AsRef;
 use std::convert::TryFrom;
 use std::fs::File;
+use std::hash::Hasher;
 use std::io::Read;
 use std::io::Write;
 use std::marker::Send;
@@ -37,6 +38,7 @@ use serde::Serialize;
 use serde_keyvalue::FromKeyValues;
 use snapshot::AnySnapshot;
 use thiserror::Error;
+use twox_hash::XxHash64;
 use zerocopy::FromBytes;
 use zerocopy::Immutable;
 use zerocopy::IntoBytes;
@@ -268,6 +270,42 @@ impl MemoryRegion {
     fn contains(&self, addr: GuestAddress) -> bool {
         addr >= self.guest_base && addr < self.end()
     }
+
+    /// Calls `f` with the index and the contents of each page of the region. The pages in holes of
+    /// the backing object are passed as zeros instead of being read, which would allocate them.
+    ///
+    /// # Safety
+    /// Must have exclusive access to the guest memory for the duration of the
+    /// call (e.g. all vCPUs and devices must be stopped).
+    #[deny(unsafe_op_in_unsafe_fn)]
+    unsafe fn for_each_page(
+        &self,
+        mut f: impl FnMut(usize, &[u8]) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        let page_size = pagesize();
+        let zero_page = vec![0u8; page_size];
+        let data_ranges = self.find_data_ranges().context("find_data_ranges failed")?;
+        let mut data_ranges = data_ranges.iter().peekable();
+        for index in 0..self.mapping.size() / page_size {
+            let offset = index * page_size;
+            while data_ranges.next_if(|range| range.end <= offset).is_some() {}
+            if data_ranges
+                .peek()
+                .is_some_and(|range| range.start < offset + page_size)
+            {
+                let vslice = self.mapping.get_slice(offset, page_size)?;
+                // SAFETY:
+                // See `GuestMemory::snapshot`, the caller guarantees that nothing else accesses
+                // the guest memory.
+                f(index, unsafe {
+                    std::slice::from_raw_parts(vslice.as_ptr(), vslice.size())
+                })?;
+            } else {
+                f(index, &zero_page)?;
+            }
+        }
+        Ok(())
+    }
 }
 
 /// Tracks memory regions and where they are mapped in the guest, along with shm
@@ -956,6 +994,24 @@ impl GuestMemory {
             })
     }
 
+    /// Returns the guest address and size of the ranges of memory that might contain non-zero
+    /// data. Ranges that are holes in the backing objects read as zero and are skipped.
+    pub fn data_ranges(&self) -> anyhow::Result<Vec<(GuestAddress, usize)>> {
+        let mut ranges = Vec::new();
+        for region in self.regions.iter() {
+            let data_ranges = region
+                .find_data_ranges()
+                .context("find_data_ranges failed")?;
+            ranges.extend(data_ranges.into_iter().map(|range| {
+                (
+                    region.start().unchecked_add(range.start as u64),
+                    range.end - range.start,
+                )
+            }));
+        }
+        Ok(ranges)
+    }
+
     /// Copy all guest memory into `w`.
     ///
     /// # Safety
@@ -1031,6 +1087,252 @@ impl GuestMemory {
     /// `r` doesn't produce exactly as many bytes as needed.
     #[deny(unsafe_op_in_unsafe_fn)]
     pub unsafe fn restore<T: Read>(&self, metadata: AnySnapshot, r: &mut T) -> anyhow::Result<()> {
+        // SAFETY: guaranteed by the caller.
+        unsafe { self.restore_ranges(metadata, r, true) }
+    }
+
+    /// Computes the hashes of the pages of guest memory, for `snapshot_changed_pages` to find the
+    /// pages that change afterwards.
+    ///
+    /// # Safety
+    /// Must have exclusive access to the guest memory for the duration of the
+    /// call (e.g. all vCPUs and devices must be stopped).
+    #[deny(unsafe_op_in_unsafe_fn)]
+    pub unsafe fn page_hashes(&self) -> anyhow::Result<PageHashes> {
+        let mut regions = Vec::new();
+        for region in self.regions.iter() {
+            let mut hashes = Vec::with_capacity(region.mapping.size() / pagesize());
+            // SAFETY: guaranteed by the caller.
+            unsafe {
+                region.for_each_page(|_, page| {
+                    hashes.push(hash_page(page));
+                    Ok(())
+                })?
+            };
+            regions.push(hashes);
+        }
+        Ok(PageHashes { regions })
+    }
+
+    /// Calls `f` with the guest address and the contents of each page that changed since `hashes`
+    /// were computed, and updates `hashes` to the current contents. The pages in holes of the
+    /// backing objects aren't read.
+    ///
+    /// # Safety
+    /// Must have exclusive access to the guest memory for the duration of the
+    /// call (e.g. all vCPUs and devices must be stopped).
+    #[deny(unsafe_op_in_unsafe_fn)]
+    pub unsafe fn for_each_changed_page(
+        &self,
+        hashes: &mut PageHashes,
+        mut f: impl FnMut(GuestAddress, &[u8]) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        if hashes.regions.len() != self.regions.len() {
+            bail!("page hashes don't match the memory regions");
+        }
+        let page_size = pagesize();
+        for (region, hashes) in self.regions.iter().zip(hashes.regions.iter_mut()) {
+            if hashes.len() != region.mapping.size() / page_size {
+                bail!("page hashes don't match the memory regions");
+            }
+            // SAFETY: guaranteed by the caller.
+            unsafe {
+                region.for_each_page(|index, page| {
+                    let hash = hash_page(page);
+                    if hashes[index] != hash {
+                        hashes[index] = hash;
+                        f(
+                            region.start().unchecked_add((index * page_size) as u64),
+                            page,
+                        )?;
+                    }
+                    Ok(())
+                })?
+            };
+        }
+        Ok(())
+    }
+
+    /// Zeroes the whole guest memory, releasing the pages of the backing objects where possible.
+    pub fn zero_all(&self) -> anyhow::Result<()> {
+        for region in self.regions.iter() {
+            region.zero_range(0, region.mapping.size())?;
+        }
+        Ok(())
+    }
+
+    /// Copy the pages of guest memory that changed since `hashes` were computed into `w`, and
+    /// update `hashes` to the current contents.
+    ///
+    /// `dirty_bitmaps` holds a bitmap per region with a bit per page, like the dirty log of the
+    /// hypervisor. A page changed if its bit is set or if its hash differs: the dirty log only sees
+    /// the writes of the vCPUs, so the hashes catch the writes of the devices.
+    ///
+    /// # Safety
+    /// Must have exclusive access to the guest memory for the duration of the
+    /// call (e.g. all vCPUs and devices must be stopped).
+    ///
+    /// Returns a JSON object to pass to `restore_changed_pages` along with the bytes.
+    #[deny(unsafe_op_in_unsafe_fn)]
+    pub unsafe fn snapshot_changed_pages<T: Write>(
+        &self,
+        w: &mut T,
+        hashes: &mut PageHashes,
+        dirty_bitmaps: &[Vec<u8>],
+        compress: bool,
+    ) -> anyhow::Result<AnySnapshot> {
+        /// # Safety
+        /// Same as `snapshot_changed_pages`.
+        #[deny(unsafe_op_in_unsafe_fn)]
+        unsafe fn go(
+            this: &GuestMemory,
+            w: &mut impl Write,
+            hashes: &mut PageHashes,
+            dirty_bitmaps: &[Vec<u8>],
+        ) -> anyhow::Result<Vec<MemoryRegionSnapshotMetadata>> {
+            if hashes.regions.len() != this.regions.len()
+                || dirty_bitmaps.len() != this.regions.len()
+            {
+                bail!("page hashes or dirty bitmaps don't match the memory regions");
+            }
+            let page_size = pagesize();
+            let mut regions = Vec::new();
+            for ((region, hashes), bitmap) in this
+                .regions
+                .iter()
+                .zip(hashes.regions.iter_mut())
+                .zip(dirty_bitmaps)
+            {
+                if hashes.len() != region.mapping.size() / page_size {
+                    bail!("page hashes don't match the memory regions");
+                }
+                let mut data_ranges: Vec<std::ops::Range<usize>> = Vec::new();
+                // SAFETY: guaranteed by the caller.
+                unsafe {
+                    region.for_each_page(|index, page| {
+                        let dirty = bitmap
+                            .get(index / 8)
+                            .is_some_and(|byte| byte & (1 << (index % 8)) != 0);
+                        let hash = hash_page(page);
+                        if !dirty && hashes[index] == hash {
+                            return Ok(());
+                        }
+                        hashes[index] = hash;
+                        w.write_all(page)?;
+                        let offset = index * page_size;
+                        match data_ranges.last_mut() {
+                            Some(range) if range.end == offset => range.end += page_size,
+                            _ => data_ranges.push(offset..offset + page_size),
+                        }
+                        Ok(())
+                    })?
+                };
+                regions.push(MemoryRegionSnapshotMetadata {
+                    guest_base: region.guest_base.0,
+                    size: region.mapping.size(),
+                    data_ranges,
+                });
+            }
+            Ok(regions)
+        }
+
+        let regions = if compress {
+            let mut w = lz4_flex::frame::FrameEncoder::new(w);
+            // SAFETY: guaranteed by the caller.
+            let regions = unsafe { go(self, &mut w, hashes, dirty_bitmaps)? };
+            w.finish()?;
+            regions
+        } else {
+            // SAFETY: guaranteed by the caller.
+            unsafe { go(self, w, hashes, dirty_bitmaps)? }
+        };
+
+        AnySnapshot::to_any(MemorySnapshotMetadata {
+            regions,
+            compressed: compress,
+        })
+    }
+
+    /// Restore the pages written by `snapshot_changed_pages` using the bytes from `r`. The other
+    /// pages are left as they are, so the memory must have been restored from the snapshot the
+    /// hashes were computed for first.
+    ///
+    /// # Safety
+    /// Must have exclusive access to the guest memory for the duration of the
+    /// call (e.g. all vCPUs and devices must be stopped).
+    #[deny(unsafe_op_in_unsafe_fn)]
+    pub unsafe fn restore_changed_pages<T: Read>(
+        &self,
+        metadata: AnySnapshot,
+        r: &mut T,
+    ) -> anyhow::Result<()> {
+        // SAFETY: guaranteed by the caller.
+        unsafe { self.restore_ranges(metadata, r, false) }
+    }
+
+    /// Returns the ranges of guest memory stored in a snapshot taken by `snapshot`, in the order of
+    /// their bytes in the snapshot file. The rest of the guest memory is zero in the snapshot.
+    ///
+    /// This lets the pages be mapped from the snapshot file on demand instead of being restored, so
+    /// it returns an error if `metadata` doesn't match the configuration of the `GuestMemory` or if
+    /// the snapshot is compressed.
+    pub fn snapshot_data_ranges(
+        &self,
+        metadata: AnySnapshot,
+    ) -> anyhow::Result<Vec<(GuestAddress, usize)>> {
+        let metadata: MemorySnapshotMetadata = AnySnapshot::from_any(metadata)?;
+        if metadata.compressed {
+            bail!("compressed memory snapshots can't be mapped");
+        }
+        if self.regions.len() != metadata.regions.len() {
+            bail!(
+                "snapshot expected {} memory regions but VM has {}",
+                metadata.regions.len(),
+                self.regions.len()
+            );
+        }
+        let page_size = pagesize();
+        let mut ranges = Vec::new();
+        for (region, metadata) in self.regions.iter().zip(metadata.regions.iter()) {
+            if region.guest_base.0 != metadata.guest_base || region.mapping.size() != metadata.size
+            {
+                bail!("snapshot memory regions don't match VM memory regions");
+            }
+            let mut prev_end = 0;
+            for range in &metadata.data_ranges {
+                if range.start < prev_end
+                    || range.end < range.start
+                    || range.end > metadata.size
+                    || range.start % page_size != 0
+                    || range.end % page_size != 0
+                {
+                    bail!("invalid data range {:?}", range);
+                }
+                if range.end > range.start {
+                    ranges.push((
+                        region.guest_base.unchecked_add(range.start as u64),
+                        range.end - range.start,
+                    ));
+                }
+                prev_end = range.end;
+            }
+        }
+        Ok(ranges)
+    }
+
+    /// Restore the ranges of guest memory in `metadata` using the bytes from `r`, and zero the
+    /// rest if `zero_holes` is true.
+    ///
+    /// # Safety
+    /// Must have exclusive access to the guest memory for the duration of the
+    /// call (e.g. all vCPUs and devices must be stopped).
+    #[deny(unsafe_op_in_unsafe_fn)]
+    unsafe fn restore_ranges<T: Read>(
+        &self,
+        metadata: AnySnapshot,
+        r: &mut T,
+        zero_holes: bool,
+    ) -> anyhow::Result<()> {
         let metadata: MemorySnapshotMetadata = AnySnapshot::from_any(metadata)?;
 
         let mut r: Box<dyn Read> = if metadata.compressed {
@@ -1062,7 +1364,7 @@ impl GuestMemory {
                     .start
                     .checked_sub(prev_end)
                     .context("invalid data range")?;
-                if hole_size > 0 {
+                if zero_holes && hole_size > 0 {
                     region.zero_range(prev_end, hole_size)?;
                 }
                 let region_vslice = region
@@ -1083,7 +1385,7 @@ impl GuestMemory {
                 .size()
                 .checked_sub(prev_end)
                 .context("invalid data range")?;
-            if hole_size > 0 {
+            if zero_holes && hole_size > 0 {
                 region.zero_range(prev_end, hole_size)?;
             }
         }
@@ -1098,6 +1400,59 @@ impl GuestMemory {
     }
 }
 
+/// Hashes of the pages of guest memory, computed by `GuestMemory::page_hashes`.
+#[derive(Clone, Debug)]
+pub struct PageHashes {
+    regions: Vec<Vec<u64>>,
+}
+
+impl PageHashes {
+    /// Hashes of the pages of `mem` as if they were all zero, e.g. once zeroed by
+    /// `GuestMemory::zero_all`.
+    pub fn zeroed(mem: &GuestMemory) -> PageHashes {
+        let page_size = pagesize();
+        let zero_hash = hash_page(&vec![0u8; page_size]);
+        PageHashes {
+            regions: mem
+                .regions
+                .iter()
+                .map(|region| vec![zero_hash; region.mapping.size() / page_size])
+                .collect(),
+        }
+    }
+
+    /// Sets the hashes of the pages of `mem` starting at `addr` to the hashes of `data`, which
+    /// holds whole pages. The pages outside of `mem` are ignored.
+    pub fn update(&mut self, mem: &GuestMemory, addr: GuestAddress, data: &[u8]) {
+        let page_size = pagesize();
+        for (i, page) in data.chunks(page_size).enumerate() {
+            let Some(page_addr) = addr.checked_add((i * page_size) as u64) else {
+                break;
+            };
+            let Some((region, hashes)) = mem
+                .regions
+                .iter()
+                .zip(self.regions.iter_mut())
+                .find(|(region, _)| region.contains(page_addr))
+            else {
+                continue;
+            };
+            let index = page_addr.offset_from(region.start()) as usize / page_size;
+            if let Some(hash) = hashes.get_mut(index) {
+                *hash = hash_page(page);
+            }
+        }
+    }
+}
+
+/// Hashes the contents of a page of guest memory, to find out whether it changed without keeping a
+/// copy. Two different contents have the same hash with a probability of about 2^-64.
+fn hash_page(page: &[u8]) -> u64 {
+    let mut hasher = XxHash64::with_seed(0);
+    hasher.write(page);
+    hasher.finish()
+}
+
 #[derive(Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 struct MemorySnapshotMetadata {
     regions: Vec<MemoryRegionSnapshotMetadata>,
@@ -1309,6 +1664,26 @@ mod tests {
         }
     }
 
+    #[test]
+    #[cfg(unix)]
+    fn data_ranges() {
+        let gm = GuestMemory::new(&[
+            (GuestAddress(0x0), 0x10000),
+            (GuestAddress(0x10000), 0x10000),
+        ])
+        .unwrap();
+        gm.write_obj_at_addr(1u64, GuestAddress(0x3000)).unwrap();
+        gm.write_obj_at_addr(2u64, GuestAddress(0x1FFF8)).unwrap();
+
+        assert_eq!(
+            gm.data_ranges().unwrap(),
+            vec![
+                (GuestAddress(0x3000), 0x1000),
+                (GuestAddress(0x1F000), 0x1000)
+            ]
+        );
+    }
+
     #[test]
     // Disabled for non-x86 because test infra uses qemu-user, which doesn't support MADV_REMOVE.
     #[cfg(target_arch = "x86_64")]
@@ -1433,4 +1808,204 @@ mod tests {
             assert_eq!(gm2.read_obj_from_addr::<u64>(addr).unwrap(), value);
         }
     }
+
+    #[test]
+    // Disabled for non-x86 because test infra uses qemu-user, which doesn't support MADV_REMOVE.
+    #[cfg(target_arch = "x86_64")]
+    fn snapshot_restore_changed_pages() {
+        use std::io::Seek;
+
+        let page_size = pagesize();
+        let regions = &[
+            (GuestAddress(0x0), 4 * page_size as u64),
+            (GuestAddress(0x100000), 4 * page_size as u64),
+        ];
+        let gm = GuestMemory::new(regions).unwrap();
+        gm.write_obj_at_addr(1u64, GuestAddress(0x0)).unwrap();
+        gm.write_obj_at_addr(2u64, GuestAddress(0x100000)).unwrap();
+
+        let mut base = tempfile::tempfile().unwrap();
+        // SAFETY:
+        // no vm is running
+        let base_metadata = unsafe { gm.snapshot(&mut base, false).unwrap() };
+        // SAFETY:
+        // no vm is running
+        let mut hashes = unsafe { gm.page_hashes().unwrap() };
+
+        // Page 1 of the first region is in the dirty log but unchanged, pages 2 and 3 of the second
+        // region are changed without being in the dirty log.
+        gm.write_obj_at_addr(3u64, GuestAddress(0x100000 + 2 * page_size as u64))
+            .unwrap();
+        gm.write_obj_at_addr(4u64, GuestAddress(0x100000 + 3 * page_size as u64))
+            .unwrap();
+        let dirty_bitmaps = vec![vec![0b0010], vec![0]];
+
+        let mut changed = tempfile::tempfile().unwrap();
+        // SAFETY:
+        // no vm is running
+        let changed_metadata = unsafe {
+            gm.snapshot_changed_pages(&mut changed, &mut hashes, &dirty_bitmaps, true)
+                .unwrap()
+        };
+        let metadata: MemorySnapshotMetadata =
+            AnySnapshot::from_any(changed_metadata.clone()).unwrap();
+        assert_eq!(
+            metadata,
+            MemorySnapshotMetadata {
+                regions: vec![
+                    MemoryRegionSnapshotMetadata {
+                        guest_base: 0,
+                        size: 4 * page_size,
+                        data_ranges: vec![page_size..2 * page_size],
+                    },
+                    MemoryRegionSnapshotMetadata {
+                        guest_base: 0x100000,
+                        size: 4 * page_size,
+                        data_ranges: vec![2 * page_size..4 * page_size],
+                    },
+                ],
+                compressed: true,
+            }
+        );
+
+        // Nothing changed since.
+        let mut unchanged = Vec::new();
+        // SAFETY:
+        // no vm is running
+        unsafe {
+            gm.snapshot_changed_pages(&mut unchanged, &mut hashes, &[vec![], vec![]], false)
+                .unwrap()
+        };
+        assert!(unchanged.is_empty());
+
+        let gm2 = GuestMemory::new(regions).unwrap();
+        base.seek(std::io::SeekFrom::Start(0)).unwrap();
+        changed.seek(std::io::SeekFrom::Start(0)).unwrap();
+        // SAFETY:
+        // no vm is running
+        unsafe {
+            gm2.restore(base_metadata, &mut base).unwrap();
+            gm2.restore_changed_pages(changed_metadata, &mut changed)
+                .unwrap();
+        }
+        for (addr, value) in [
+            (0x0, 1u64),
+            (0x100000, 2u64),
+            (0x100000 + 2 * page_size as u64, 3u64),
+            (0x100000 + 3 * page_size as u64, 4u64),
+        ] {
+            assert_eq!(
+                gm2.read_obj_from_addr::<u64>(GuestAddress(addr)).unwrap(),
+                value
+            );
+        }
+    }
+
+    #[test]
+    // Disabled for non-x86 because test infra uses qemu-user, which doesn't support MADV_REMOVE.
+    #[cfg(target_arch = "x86_64")]
+    fn changed_pages_since_zeroed() {
+        let page_size = pagesize();
+        let regions = &[
+            (GuestAddress(0x0), 4 * page_size as u64),
+            (GuestAddress(0x100000), 4 * page_size as u64),
+        ];
+        let gm = GuestMemory::new(regions).unwrap();
+        gm.write_obj_at_addr(1u64, GuestAddress(0x0)).unwrap();
+        gm.zero_all().unwrap();
+        assert_eq!(gm.read_obj_from_addr::<u64>(GuestAddress(0x0)).unwrap(), 0);
+
+        let mut hashes = PageHashes::zeroed(&gm);
+        gm.write_obj_at_addr(2u64, GuestAddress(0x100000)).unwrap();
+        gm.write_obj_at_addr(3u64, GuestAddress(page_size as u64))
+            .unwrap();
+        // The page at 0x100000 is up to date in the hashes.
+        let mut page = vec![0u8; page_size];
+        gm.read_exact_at_addr(&mut page, GuestAddress(0x100000))
+            .unwrap();
+        hashes.update(&gm, GuestAddress(0x100000), &page);
+
+        let mut changed = Vec::new();
+        // SAFETY:
+        // no vm is running
+        unsafe {
+            gm.for_each_changed_page(&mut hashes, |addr, page| {
+                changed.push((addr, page[0]));
+                Ok(())
+            })
+            .unwrap()
+        };
+        assert_eq!(changed, vec![(GuestAddress(page_size as u64), 3)]);
+
+        changed.clear();
+        // SAFETY:
+        // no vm is running
+        unsafe {
+            gm.for_each_changed_page(&mut hashes, |addr, page| {
+                changed.push((addr, page[0]));
+                Ok(())
+            })
+            .unwrap()
+        };
+        assert!(changed.is_empty());
+    }
+
+    #[test]
+    fn snapshot_data_ranges() {
+        let page_size = pagesize();
+        let regions = &[
+            (GuestAddress(0x0), 4 * page_size as u64),
+            (GuestAddress(0x100000), 4 * page_size as u64),
+        ];
+        let gm = GuestMemory::new(regions).unwrap();
+        let metadata = |compressed, first: Vec<std::ops::Range<usize>>| {
+            AnySnapshot::to_any(MemorySnapshotMetadata {
+                regions: vec![
+                    MemoryRegionSnapshotMetadata {
+                        guest_base: 0,
+                        size: 4 * page_size,
+                        data_ranges: first,
+                    },
+                    MemoryRegionSnapshotMetadata {
+                        guest_base: 0x100000,
+                        size: 4 * page_size,
+                        data_ranges: vec![page_size..3 * page_size],
+                    },
+                ],
+                compressed,
+            })
+            .unwrap()
+        };
+
+        assert_eq!(
+            gm.snapshot_data_ranges(metadata(
+                false,
+                vec![0..page_size, 3 * page_size..3 * page_size]
+            ))
+            .unwrap(),
+            vec![
+                (GuestAddress(0x0), page_size),
+                (GuestAddress(0x100000 + page_size as u64), 2 * page_size),
+            ]
+        );
+        // Compressed.
+        assert!(gm
+            .snapshot_data_ranges(metadata(true, vec![0..page_size]))
+            .is_err());
+        // Out of order.
+        assert!(gm
+            .snapshot_data_ranges(metadata(
+                false,
+                vec![page_size..2 * page_size, 0..page_size]
+            ))
+            .is_err());
+        // Beyond the region.
+        assert!(gm
+            .snapshot_data_ranges(metadata(false, vec![0..5 * page_size]))
+            .is_err());
+        // Not page aligned.
+        assert!(gm
+            .snapshot_data_ranges(metadata(false, vec![0..1]))
+            .is_err());
+    }
 }